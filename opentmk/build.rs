@@ -6,4 +6,20 @@
 fn main() {
     // Allow a cfg of nightly to avoid using a feature, see main.rs.
     println!("cargo:rustc-check-cfg=cfg(nightly)");
+
+    // Compiled into the run manifest, see manifest.rs.
+    vergen::EmitBuilder::builder()
+        .git_describe(true, true, None)
+        .git_sha(false)
+        .emit()
+        .unwrap();
+
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|f| f.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=OPENTMK_FEATURES={}", features.join(","));
 }
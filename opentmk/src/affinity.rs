@@ -0,0 +1,139 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! VP selection and topology reporting.
+//!
+//! The harness may restrict the VPs multi-VP tests use by setting
+//! [`VP_SET_VARIABLE`] to a list of VP indexes and ranges such as
+//! `0,2-3`, so that runs on hosts with heterogeneous cores or constrained
+//! affinity are reproducible. [`write_vp_map`] reports the VP index to APIC
+//! ID mapping of the partition along with the selection.
+
+use alloc::collections::btree_set::BTreeSet;
+use alloc::vec::Vec;
+
+use serde::Serialize;
+use spin::Mutex;
+
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VtlPlatformTrait;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+use crate::tmkdefs::vp_slot;
+
+/// Name of the UEFI variable holding the VP selection.
+pub const VP_SET_VARIABLE: &str = "OpenTmkVpSet";
+/// Vendor GUID of [`VP_SET_VARIABLE`], shared with the scenario variable.
+pub const VP_SET_VARIABLE_VENDOR: uefi::Guid = crate::scenario::SCENARIO_VARIABLE_VENDOR;
+
+/// VPs selected by the harness, `None` to use every VP.
+static VP_SET: Mutex<Option<BTreeSet<u32>>> = Mutex::new(None);
+
+/// Parses a VP selection: comma separated VP indexes and inclusive
+/// `first-last` ranges. Indexes past [`crate::tmkdefs::MAX_VPS`], which no
+/// VP of the partition can have, fail with [`TmkError::InvalidVpIndex`].
+pub fn parse_vp_set(text: &str) -> TmkResult<BTreeSet<u32>> {
+    let mut set = BTreeSet::new();
+    for item in text.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (first, last) = match item.split_once('-') {
+            Some((first, last)) => (first.trim(), last.trim()),
+            None => (item, item),
+        };
+        let first: u32 = first.parse().map_err(|_| TmkError::InvalidParameter)?;
+        let last: u32 = last.parse().map_err(|_| TmkError::InvalidParameter)?;
+        if first > last {
+            return Err(TmkError::InvalidParameter);
+        }
+        vp_slot(last)?;
+        set.extend(first..=last);
+    }
+    if set.is_empty() {
+        return Err(TmkError::InvalidParameter);
+    }
+    Ok(set)
+}
+
+/// Records the VP selection found in [`VP_SET_VARIABLE`].
+pub(crate) fn set_vp_set(set: BTreeSet<u32>) {
+    *VP_SET.lock() = Some(set);
+}
+
+/// Returns the VPs, out of `vp_count`, that tests should use, in ascending
+/// order. VP 0 always runs the test driver and is part of the selection.
+pub fn selected_vps(vp_count: u32) -> Vec<u32> {
+    match &*VP_SET.lock() {
+        Some(set) => core::iter::once(0)
+            .chain(set.iter().copied().filter(|&vp| vp != 0 && vp < vp_count))
+            .collect(),
+        None => (0..vp_count).collect(),
+    }
+}
+
+#[derive(Serialize)]
+struct VpMapEntry {
+    vp_index: u32,
+    apic_id: Option<u32>,
+    selected: bool,
+}
+
+#[derive(Serialize)]
+struct VpMapRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    vp_count: u32,
+    vps: &'a [VpMapEntry],
+}
+
+/// Writes the `vp_map` record of every VP of the partition.
+pub fn write_vp_map<T>(ctx: &mut T) -> TmkResult<()>
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    let vp_count = ctx.get_vp_count()?;
+    let selected = selected_vps(vp_count);
+    let vps: Vec<VpMapEntry> = (0..vp_count)
+        .map(|vp_index| VpMapEntry {
+            vp_index,
+            apic_id: apic_id(ctx, vp_index),
+            selected: selected.contains(&vp_index),
+        })
+        .collect();
+    log::info!("VPs selected for tests: {:?}", selected);
+    crate::tmk_logger::write_record(&VpMapRecord {
+        record_type: "vp_map",
+        vp_count,
+        vps: &vps,
+    });
+    Ok(())
+}
+
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+fn apic_id<T: VtlPlatformTrait>(ctx: &mut T, vp_index: u32) -> Option<u32> {
+    ctx.get_vp_register_on_vp(
+        vp_index,
+        hvdef::HvX64RegisterName::InitialApicId.0,
+        hvdef::Vtl::Vtl0,
+    )
+    .ok()
+    .map(|id| id as u32)
+}
+
+#[cfg(target_arch = "aarch64")] // xtask-fmt allow-target-arch sys-crate
+fn apic_id<T: VtlPlatformTrait>(_ctx: &mut T, _vp_index: u32) -> Option<u32> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_vp_set() {
+        let set = parse_vp_set("0, 2-4,7").unwrap();
+        assert_eq!(set.into_iter().collect::<Vec<_>>(), [0, 2, 3, 4, 7]);
+        assert!(parse_vp_set("3-1").is_err());
+        assert!(parse_vp_set("a").is_err());
+        assert!(parse_vp_set("").is_err());
+        assert_eq!(parse_vp_set("0-4294967295"), Err(TmkError::InvalidVpIndex));
+    }
+}
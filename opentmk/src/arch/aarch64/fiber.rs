@@ -0,0 +1,98 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! aarch64 fiber context switch.
+//!
+//! A suspended context is its stack pointer: [`switch_stack`] stores the
+//! registers the AAPCS64 preserves across calls, including the link
+//! register, on the current stack before loading the stack pointer of the
+//! context it resumes. The compiler saves everything else around the call.
+
+use core::arch::global_asm;
+
+global_asm!(
+    ".global opentmk_fiber_switch",
+    "opentmk_fiber_switch:",
+    "sub sp, sp, #0xa0",
+    "stp x19, x20, [sp, #0x00]",
+    "stp x21, x22, [sp, #0x10]",
+    "stp x23, x24, [sp, #0x20]",
+    "stp x25, x26, [sp, #0x30]",
+    "stp x27, x28, [sp, #0x40]",
+    "stp x29, x30, [sp, #0x50]",
+    "stp d8, d9, [sp, #0x60]",
+    "stp d10, d11, [sp, #0x70]",
+    "stp d12, d13, [sp, #0x80]",
+    "stp d14, d15, [sp, #0x90]",
+    "mov x9, sp",
+    "str x9, [x0]",
+    "mov sp, x1",
+    "ldp x19, x20, [sp, #0x00]",
+    "ldp x21, x22, [sp, #0x10]",
+    "ldp x23, x24, [sp, #0x20]",
+    "ldp x25, x26, [sp, #0x30]",
+    "ldp x27, x28, [sp, #0x40]",
+    "ldp x29, x30, [sp, #0x50]",
+    "ldp d8, d9, [sp, #0x60]",
+    "ldp d10, d11, [sp, #0x70]",
+    "ldp d12, d13, [sp, #0x80]",
+    "ldp d14, d15, [sp, #0x90]",
+    "add sp, sp, #0xa0",
+    "ret",
+    // First resume of a context built by `prepare_stack`: x19 holds the
+    // argument and x20 the entry point.
+    ".global opentmk_fiber_start",
+    "opentmk_fiber_start:",
+    "mov x0, x19",
+    "blr x20",
+    "brk #0",
+);
+
+unsafe extern "C" {
+    fn opentmk_fiber_switch(from: *mut u64, to: u64);
+    fn opentmk_fiber_start();
+}
+
+extern "C" fn trampoline<F: FnOnce()>(f: *mut Option<F>) {
+    // SAFETY: `prepare_stack` callers keep the `Option<F>` alive and
+    // untouched until the closure is taken.
+    let f = unsafe { &mut *f };
+    (f.take().expect("closure already taken"))();
+    panic!("fiber entry returned");
+}
+
+/// Builds a context on `stack` that calls the closure in `f` when first
+/// switched to, and returns its stack pointer.
+///
+/// # Safety
+///
+/// `stack` and `f` must stay alive and otherwise unused until the context
+/// is no longer resumed. The closure must never return; it ends by
+/// switching to another context for good.
+pub unsafe fn prepare_stack<F: FnOnce()>(stack: &mut [u8], f: *mut Option<F>) -> u64 {
+    let top = (stack.as_mut_ptr() as u64 + stack.len() as u64) & !0xf;
+    // x19 through x30 then d8 through d15, with the entry in x20 and the
+    // start stub as the link register.
+    let mut frame = [0u64; 20];
+    frame[0] = f as u64;
+    frame[1] = trampoline::<F> as *const () as u64;
+    frame[11] = opentmk_fiber_start as *const () as u64;
+    let sp = top - size_of_val(&frame) as u64;
+    // SAFETY: the frame lies within `stack`, which the caller lends to the
+    // context.
+    unsafe { core::ptr::write(sp as *mut [u64; 20], frame) };
+    sp
+}
+
+/// Suspends the current context, storing its stack pointer in `from`, and
+/// resumes the context saved at stack pointer `to`.
+///
+/// # Safety
+///
+/// `to` must be the stack pointer of a suspended context, returned by
+/// [`prepare_stack`] or stored by a previous switch, whose stack is still
+/// alive. Each suspended context must be resumed at most once.
+pub unsafe fn switch_stack(from: *mut u64, to: u64) {
+    // SAFETY: guaranteed by the caller.
+    unsafe { opentmk_fiber_switch(from, to) }
+}
@@ -1,4 +1,9 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
+pub mod cycles;
+pub mod fiber;
 pub mod hypercall;
+pub mod regs;
+pub mod serial;
+pub mod stack;
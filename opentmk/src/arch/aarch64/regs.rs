@@ -0,0 +1,37 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! aarch64 system register snapshot, for diagnostics.
+
+use alloc::vec::Vec;
+use core::arch::asm;
+
+macro_rules! read_sysreg {
+    ($name:literal) => {{
+        let value: u64;
+        // SAFETY: reading this system register at EL1 has no side effects.
+        unsafe {
+            asm!(
+                concat!("mrs {}, ", $name),
+                out(reg) value,
+                options(nomem, nostack, preserves_flags),
+            )
+        };
+        value
+    }};
+}
+
+/// Reads the control state of the current processor as name and value
+/// pairs.
+pub fn snapshot() -> Vec<(&'static str, u64)> {
+    vec![
+        ("sp", super::stack::stack_pointer()),
+        ("currentel", read_sysreg!("CurrentEL")),
+        ("daif", read_sysreg!("DAIF")),
+        ("sctlr_el1", read_sysreg!("SCTLR_EL1")),
+        ("tcr_el1", read_sysreg!("TCR_EL1")),
+        ("ttbr0_el1", read_sysreg!("TTBR0_EL1")),
+        ("far_el1", read_sysreg!("FAR_EL1")),
+        ("esr_el1", read_sysreg!("ESR_EL1")),
+    ]
+}
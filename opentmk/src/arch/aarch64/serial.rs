@@ -0,0 +1,196 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Serial output for debugging, on a PL011 UART.
+//!
+//! The log uses the PL011 Hyper-V emulates unless configured otherwise: the
+//! harness may name a UART in [`SERIAL_VARIABLE`], else the ACPI SPCR or
+//! DBG2 table may describe one, see [`acpi::find_debug_uart`]. The UART is
+//! then selected with [`set_config`] and the logger re-initialized on it.
+
+use core::fmt;
+use core::hint::spin_loop;
+
+use spin::Mutex;
+
+use crate::devices::acpi;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// Name of the UEFI variable selecting the UART, see [`parse_config`].
+pub const SERIAL_VARIABLE: &str = "OpenTmkSerial";
+/// Vendor GUID of [`SERIAL_VARIABLE`], shared with the scenario variable.
+pub const SERIAL_VARIABLE_VENDOR: uefi::Guid = crate::scenario::SCENARIO_VARIABLE_VENDOR;
+
+/// Base of the first PL011 Hyper-V emulates.
+const PL011_HYPER_V_BASE: u64 = 0xeffe_c000;
+
+const REG_DR: u64 = 0x000;
+const REG_RSR_ECR: u64 = 0x004;
+const REG_FR: u64 = 0x018;
+const REG_IBRD: u64 = 0x024;
+const REG_FBRD: u64 = 0x028;
+const REG_LCR_H: u64 = 0x02c;
+const REG_CR: u64 = 0x030;
+const REG_IMSC: u64 = 0x038;
+const REG_ICR: u64 = 0x044;
+const REG_DMACR: u64 = 0x048;
+const REG_PCELL_ID0: u64 = 0xff0;
+
+const CR_RX_ENABLE: u32 = 0x200;
+const CR_TX_ENABLE: u32 = 0x100;
+const CR_UART_ENABLE: u32 = 1;
+const LCR_H_FIFO_EN: u32 = 0x10;
+const LCR_H_8BITS: u32 = 0x60;
+const FR_TX_FULL: u32 = 0x020;
+const FR_BUSY: u32 = 0x008;
+/// The PrimeCell ID of a PL011.
+const PL011_CELL_ID: u32 = 0xb105_f00d;
+
+/// The UART used.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SerialConfig {
+    /// Address of the registers of the PL011, identity mapped.
+    pub base: u64,
+}
+
+impl SerialConfig {
+    /// The PL011 Hyper-V emulates.
+    pub const DEFAULT: SerialConfig = SerialConfig {
+        base: PL011_HYPER_V_BASE,
+    };
+}
+
+static CONFIG: Mutex<SerialConfig> = Mutex::new(SerialConfig::DEFAULT);
+
+/// Returns the selected UART.
+pub fn config() -> SerialConfig {
+    *CONFIG.lock()
+}
+
+/// Selects the UART used from now on. The logger must be re-initialized on
+/// it, see [`crate::tmk_logger::select_serial`].
+pub(crate) fn set_config(config: SerialConfig) {
+    *CONFIG.lock() = config;
+}
+
+/// Parses a serial configuration, `pl011:<base>`.
+pub fn parse_config(text: &str) -> TmkResult<SerialConfig> {
+    let base = text
+        .trim()
+        .strip_prefix("pl011:")
+        .ok_or(TmkError::InvalidParameter)?;
+    let base = match base.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => base.parse(),
+    }
+    .map_err(|_| TmkError::InvalidParameter)?;
+    Ok(SerialConfig { base })
+}
+
+/// Returns the configuration of `uart`, `None` if it is not a memory mapped
+/// PL011.
+pub fn config_from_acpi(uart: &acpi::DebugUart) -> Option<SerialConfig> {
+    (uart.interface == acpi::UartInterface::Pl011 && uart.space == acpi::GAS_SPACE_SYSTEM_MEMORY)
+        .then_some(SerialConfig { base: uart.address })
+}
+
+/// A writer for a PL011 UART.
+pub struct Serial {
+    config: SerialConfig,
+    /// Whether the UART was found to be a PL011 and initialized.
+    supported: bool,
+}
+
+impl Serial {
+    /// Returns the UART of `config`, not yet initialized: writes are
+    /// dropped until [`Serial::init`] finds it.
+    pub const fn with_config(config: SerialConfig) -> Self {
+        Self {
+            config,
+            supported: false,
+        }
+    }
+
+    /// Switches to the UART of `config` and initializes it.
+    pub fn reconfigure(&mut self, config: SerialConfig) {
+        self.config = config;
+        self.init();
+    }
+
+    fn read_reg(&self, reg: u64) -> u32 {
+        // SAFETY: the base is that of a PL011, identity mapped.
+        unsafe { core::ptr::read_volatile((self.config.base + reg) as *const u32) }
+    }
+
+    fn write_reg(&self, reg: u64, value: u32) {
+        // SAFETY: the base is that of a PL011, identity mapped.
+        unsafe { core::ptr::write_volatile((self.config.base + reg) as *mut u32, value) }
+    }
+
+    fn cell_id(&self) -> u32 {
+        (0..4).rev().fold(0, |id, i| {
+            id << 8 | (self.read_reg(REG_PCELL_ID0 + i * 4) & 0xff)
+        })
+    }
+
+    fn poll_not_busy(&self) {
+        while self.read_reg(REG_FR) & FR_BUSY != 0 {
+            spin_loop();
+        }
+    }
+
+    /// Checks that the UART is a PL011, then resets it, drains its FIFOs
+    /// and enables it in polling mode.
+    pub fn init(&mut self) {
+        self.supported = self.cell_id() == PL011_CELL_ID;
+        if !self.supported {
+            return;
+        }
+        // Mask and clear the interrupts, disable DMA.
+        self.write_reg(REG_IMSC, 0x7ff);
+        self.write_reg(REG_ICR, 0x7ff);
+        self.write_reg(REG_DMACR, 0);
+
+        // Leave Rx and Tx enabled to drain the FIFOs, then disable the UART.
+        self.write_reg(REG_CR, CR_RX_ENABLE | CR_TX_ENABLE);
+        self.poll_not_busy();
+        self.write_reg(REG_CR, 0);
+
+        // The baud rate divisors, then the line control that latches them.
+        self.write_reg(REG_FBRD, 0x04);
+        self.write_reg(REG_IBRD, 0x27);
+        self.write_reg(REG_LCR_H, LCR_H_FIFO_EN | LCR_H_8BITS);
+        self.write_reg(REG_RSR_ECR, 0);
+
+        self.write_reg(REG_CR, CR_RX_ENABLE | CR_TX_ENABLE | CR_UART_ENABLE);
+        self.poll_not_busy();
+    }
+
+    /// Write `bytes` as is, for binary data.
+    pub fn write_bytes(&self, bytes: &[u8]) {
+        if !self.supported {
+            return;
+        }
+        for &b in bytes {
+            while self.read_reg(REG_FR) & FR_TX_FULL != 0 {
+                spin_loop();
+            }
+            self.write_reg(REG_DR, b.into());
+        }
+    }
+
+    /// Wait until every byte written so far has left the transmitter.
+    pub fn flush(&self) {
+        if self.supported {
+            self.poll_not_busy();
+        }
+    }
+}
+
+impl fmt::Write for Serial {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Minimal x2APIC driver.
+//!
+//! Only what tests need to raise interrupts themselves: enabling the local
+//! APIC in x2APIC mode, sending fixed IPIs and signaling end of interrupt.
+
+use core::arch::x86_64::__cpuid;
+
+use minimal_rt::arch::msr::read_msr;
+use minimal_rt::arch::msr::write_msr;
+
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+const X2APIC_ID: u32 = 0x802;
+const X2APIC_EOI: u32 = 0x80b;
+const X2APIC_SVR: u32 = 0x80f;
+const X2APIC_ICR: u32 = 0x830;
+const X2APIC_SELF_IPI: u32 = 0x83f;
+
+const SVR_SOFTWARE_ENABLE: u64 = 1 << 8;
+/// Vector reported for spurious interrupts.
+const SPURIOUS_VECTOR: u64 = 0xff;
+
+// CPUID.01h:ECX
+const CPUID_1_ECX_X2APIC: u32 = 1 << 21;
+
+/// Enable the local APIC of the current VP in x2APIC mode. A disabled APIC
+/// goes through xAPIC mode first, it can't switch to x2APIC mode directly.
+pub fn enable() -> TmkResult<()> {
+    // SAFETY: CPUID is always available on x86_64.
+    let leaf = unsafe { __cpuid(1) };
+    if leaf.ecx & CPUID_1_ECX_X2APIC == 0 {
+        return Err(TmkError::FeatureUnavailable);
+    }
+    // SAFETY: the APIC base MSR is architectural and x2APIC mode is
+    // supported per CPUID.
+    unsafe {
+        let base = read_msr(IA32_APIC_BASE);
+        if base & APIC_BASE_ENABLE == 0 {
+            write_msr(IA32_APIC_BASE, base | APIC_BASE_ENABLE);
+        }
+        if base & APIC_BASE_X2APIC_ENABLE == 0 {
+            write_msr(
+                IA32_APIC_BASE,
+                base | APIC_BASE_ENABLE | APIC_BASE_X2APIC_ENABLE,
+            );
+        }
+        let svr = read_msr(X2APIC_SVR);
+        write_msr(X2APIC_SVR, svr | SVR_SOFTWARE_ENABLE | SPURIOUS_VECTOR);
+    }
+    Ok(())
+}
+
+/// Returns the x2APIC ID of the current VP. Requires [`enable`].
+pub fn id() -> u32 {
+    // SAFETY: the x2APIC is enabled per the contract of this function.
+    unsafe { read_msr(X2APIC_ID) as u32 }
+}
+
+/// Send a fixed interrupt with `vector` to the current VP.
+pub fn self_ipi(vector: u8) {
+    // SAFETY: the self IPI register only raises an interrupt.
+    unsafe { write_msr(X2APIC_SELF_IPI, vector as u64) };
+}
+
+/// Send a fixed interrupt with `vector` to the VP with x2APIC ID `apic_id`.
+pub fn send_ipi(apic_id: u32, vector: u8) {
+    // Fixed delivery, physical destination, edge triggered.
+    let icr = (apic_id as u64) << 32 | vector as u64;
+    // SAFETY: writing the ICR only raises an interrupt.
+    unsafe { write_msr(X2APIC_ICR, icr) };
+}
+
+/// Signal the end of the interrupt being handled.
+pub fn eoi() {
+    // SAFETY: writing zero to the EOI register has no other effect.
+    unsafe { write_msr(X2APIC_EOI, 0) };
+}
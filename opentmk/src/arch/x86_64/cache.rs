@@ -0,0 +1,267 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! x86_64 cacheability control through the PAT and the MTRRs.
+//!
+//! [`set_page_cache_type`] maps a 4K page with a given memory type by
+//! pointing its page table entry at a PAT entry holding that type, after
+//! programming one with [`set_pat_entry`] if none does. The MTRRs are only
+//! read: [`Mtrrs::read`] returns the ranges the firmware set up so that tests
+//! can tell the effective type of a page, which combines both.
+//!
+//! The PAT is per processor; only the current VP is changed.
+
+use alloc::vec::Vec;
+use core::arch::asm;
+
+use minimal_rt::arch::msr::read_msr;
+use minimal_rt::arch::msr::write_msr;
+
+use super::paging;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+const IA32_MTRRCAP: u32 = 0xfe;
+const IA32_MTRR_PHYSBASE0: u32 = 0x200;
+const IA32_PAT: u32 = 0x277;
+const IA32_MTRR_DEF_TYPE: u32 = 0x2ff;
+
+const MTRR_DEF_TYPE_ENABLE: u64 = 1 << 11;
+const MTRR_PHYSMASK_VALID: u64 = 1 << 11;
+const MTRR_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// A memory type, as encoded in the PAT and the MTRRs.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MemoryType {
+    /// Uncacheable.
+    Uncacheable,
+    /// Write combining.
+    WriteCombining,
+    /// Write through.
+    WriteThrough,
+    /// Write protected.
+    WriteProtected,
+    /// Write back.
+    WriteBack,
+    /// Uncacheable, overridable by write combining MTRRs. PAT only.
+    UncachedMinus,
+}
+
+impl MemoryType {
+    /// The encoding of the type.
+    pub fn encoding(self) -> u8 {
+        match self {
+            MemoryType::Uncacheable => 0,
+            MemoryType::WriteCombining => 1,
+            MemoryType::WriteThrough => 4,
+            MemoryType::WriteProtected => 5,
+            MemoryType::WriteBack => 6,
+            MemoryType::UncachedMinus => 7,
+        }
+    }
+
+    /// The type encoded as `value`, if any.
+    pub fn from_encoding(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => MemoryType::Uncacheable,
+            1 => MemoryType::WriteCombining,
+            4 => MemoryType::WriteThrough,
+            5 => MemoryType::WriteProtected,
+            6 => MemoryType::WriteBack,
+            7 => MemoryType::UncachedMinus,
+            _ => return None,
+        })
+    }
+}
+
+/// Returns the raw value of the PAT of the current VP.
+pub fn read_pat() -> u64 {
+    // SAFETY: the PAT MSR is architectural on x86_64.
+    unsafe { read_msr(IA32_PAT) }
+}
+
+/// Returns the type held by PAT entry `index` of the current VP.
+pub fn pat_entry(index: u8) -> Option<MemoryType> {
+    MemoryType::from_encoding((read_pat() >> (index as u32 * 8)) as u8 & 0x7)
+}
+
+/// Write back and invalidate the caches, as required around cacheability
+/// changes.
+fn wbinvd() {
+    // SAFETY: WBINVD only writes back and invalidates caches.
+    unsafe { asm!("wbinvd", options(nostack, preserves_flags)) };
+}
+
+/// Sets PAT entry `index` of the current VP to `ty` and returns the
+/// previous type.
+///
+/// Pages already mapped through the entry change type with it.
+pub fn set_pat_entry(index: u8, ty: MemoryType) -> TmkResult<Option<MemoryType>> {
+    if index >= 8 {
+        return Err(TmkError::InvalidParameter);
+    }
+    let shift = index as u32 * 8;
+    let pat = read_pat();
+    let previous = MemoryType::from_encoding((pat >> shift) as u8 & 0x7);
+    let pat = (pat & !(0xff << shift)) | (ty.encoding() as u64) << shift;
+    wbinvd();
+    // SAFETY: every entry of the new value holds a valid memory type.
+    unsafe { write_msr(IA32_PAT, pat) };
+    x86_64::instructions::tlb::flush_all();
+    wbinvd();
+    Ok(previous)
+}
+
+/// Maps the 4K page containing `addr` with memory type `ty` and returns
+/// the PAT index it used before, to be restored with
+/// [`paging::set_page_pat_index`].
+///
+/// An entry already holding `ty` is used if there is one, otherwise `ty` is
+/// programmed into the last entry, which the power-on default leaves as UC.
+pub fn set_page_cache_type(addr: u64, ty: MemoryType) -> TmkResult<u8> {
+    let index = match (0..8).find(|&i| pat_entry(i) == Some(ty)) {
+        Some(index) => index,
+        None => {
+            set_pat_entry(7, ty)?;
+            7
+        }
+    };
+    wbinvd();
+    let previous = paging::set_page_pat_index(addr, index)?;
+    wbinvd();
+    Ok(previous)
+}
+
+/// A variable range MTRR.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MtrrRange {
+    /// The first address of the range.
+    pub base: u64,
+    /// The address mask; an address is in the range if it matches `base`
+    /// on the bits set.
+    pub mask: u64,
+    /// The encoded memory type.
+    pub ty: u8,
+}
+
+impl MtrrRange {
+    /// Returns whether `addr` is in the range.
+    pub fn contains(&self, addr: u64) -> bool {
+        addr & self.mask == self.base & self.mask
+    }
+}
+
+/// The MTRR configuration of the current VP, fixed ranges aside.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mtrrs {
+    /// Whether the MTRRs are enabled.
+    pub enabled: bool,
+    /// The encoded type of memory outside every range.
+    pub default_type: u8,
+    /// The valid variable ranges.
+    pub ranges: Vec<MtrrRange>,
+}
+
+impl Mtrrs {
+    /// Reads the MTRRs of the current VP.
+    pub fn read() -> Self {
+        // SAFETY: the MTRR MSRs are architectural on x86_64 and only the
+        // variable ranges MTRRCAP reports are read.
+        unsafe {
+            let count = read_msr(IA32_MTRRCAP) as u8 as u32;
+            let def_type = read_msr(IA32_MTRR_DEF_TYPE);
+            let ranges = (0..count)
+                .filter_map(|i| {
+                    let base = read_msr(IA32_MTRR_PHYSBASE0 + 2 * i);
+                    let mask = read_msr(IA32_MTRR_PHYSBASE0 + 2 * i + 1);
+                    (mask & MTRR_PHYSMASK_VALID != 0).then_some(MtrrRange {
+                        base: base & MTRR_ADDR_MASK,
+                        mask: mask & MTRR_ADDR_MASK,
+                        ty: base as u8,
+                    })
+                })
+                .collect();
+            Self {
+                enabled: def_type & MTRR_DEF_TYPE_ENABLE != 0,
+                default_type: def_type as u8,
+                ranges,
+            }
+        }
+    }
+
+    /// Returns the memory type the variable ranges give `addr`, combined
+    /// as the SDM describes for overlapping ranges, or `None` if the MTRRs
+    /// are disabled and memory is uncacheable.
+    pub fn type_of(&self, addr: u64) -> Option<MemoryType> {
+        if !self.enabled {
+            return None;
+        }
+        let mut types = self
+            .ranges
+            .iter()
+            .filter(|r| r.contains(addr))
+            .map(|r| r.ty);
+        let ty = match types.next() {
+            None => self.default_type,
+            Some(first) => types.fold(first, |ty, other| match (ty, other) {
+                (a, b) if a == b => a,
+                // UC wins, and WT wins over WB.
+                (0, _) | (_, 0) => 0,
+                (4, 6) | (6, 4) => 4,
+                // Undefined, treat as UC.
+                _ => 0,
+            }),
+        };
+        MemoryType::from_encoding(ty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mtrr_type_of() {
+        let mtrrs = Mtrrs {
+            enabled: true,
+            default_type: 6,
+            ranges: vec![
+                MtrrRange {
+                    base: 0xc000_0000,
+                    mask: 0x000f_ffff_c000_0000,
+                    ty: 0,
+                },
+                MtrrRange {
+                    base: 0x1_0000_0000,
+                    mask: 0x000f_ffff_0000_0000,
+                    ty: 4,
+                },
+                MtrrRange {
+                    base: 0x1_0000_0000,
+                    mask: 0x000f_ffff_8000_0000,
+                    ty: 6,
+                },
+            ],
+        };
+        assert_eq!(mtrrs.type_of(0x1000), Some(MemoryType::WriteBack));
+        assert_eq!(mtrrs.type_of(0xd000_0000), Some(MemoryType::Uncacheable));
+        assert_eq!(mtrrs.type_of(0x1_0000_1000), Some(MemoryType::WriteThrough));
+        assert_eq!(mtrrs.type_of(0x1_8000_0000), Some(MemoryType::WriteThrough));
+
+        let disabled = Mtrrs {
+            enabled: false,
+            ..mtrrs
+        };
+        assert_eq!(disabled.type_of(0x1000), None);
+    }
+
+    #[test]
+    fn test_encoding() {
+        for encoding in 0..8 {
+            if let Some(ty) = MemoryType::from_encoding(encoding) {
+                assert_eq!(ty.encoding(), encoding);
+            }
+        }
+        assert_eq!(MemoryType::from_encoding(2), None);
+    }
+}
@@ -0,0 +1,29 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! x86_64 cycle counter.
+
+use core::arch::x86_64::__cpuid;
+
+use hvdef::HvFeatures;
+use minimal_rt::arch::msr::read_msr;
+
+/// Reads the time stamp counter.
+pub fn read() -> u64 {
+    // SAFETY: RDTSC has no side effects and is available on all x86_64
+    // processors.
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Returns the TSC frequency in Hz as reported by the hypervisor, or `None`
+/// if the partition may not read the frequency MSRs.
+pub fn frequency() -> Option<u64> {
+    // SAFETY: CPUID is always available on x86_64.
+    let leaf = unsafe { __cpuid(hvdef::HV_CPUID_FUNCTION_MS_HV_FEATURES) };
+    let features = HvFeatures::from_cpuid([leaf.eax, leaf.ebx, leaf.ecx, leaf.edx]);
+    if !features.privileges().access_frequency_msrs() {
+        return None;
+    }
+    // SAFETY: the frequency MSRs are accessible per the privileges above.
+    Some(unsafe { read_msr(hvdef::HV_X64_MSR_TSC_FREQUENCY) })
+}
@@ -0,0 +1,327 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Debug registers.
+//!
+//! [`set_breakpoint`] and [`clear_breakpoint`] program the hardware
+//! breakpoints DR0-DR3 and their enables in DR7, checking the alignment
+//! the processor requires. The #DB handler calls [`on_debug`], which
+//! records the breakpoints that hit and the RIP for [`take_hit`];
+//! single-step traps are served by [`super::single_step`]. The raw
+//! registers are read with [`DebugRegisters::read`] and [`read_dr`], e.g.
+//! to compare what each VTL observes.
+
+use core::arch::asm;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use serde::Serialize;
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::platform::hyperv::ctx::HvTestCtx;
+use crate::tmkdefs::MAX_VPS;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// Number of hardware breakpoints.
+pub const BREAKPOINTS: usize = 4;
+/// Value of DR7 with every breakpoint disabled; bit 10 reads as one.
+pub const DR7_DISABLED: u64 = 1 << 10;
+/// DR6.B0-B3, the breakpoints that hit.
+const DR6_BREAKPOINTS: u64 = 0xf;
+
+/// What a breakpoint hits on, the DR7 R/W field.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum Condition {
+    /// Instruction execution.
+    Execute = 0,
+    /// Data writes.
+    Write = 1,
+    /// I/O reads and writes, with CR4.DE set.
+    Io = 2,
+    /// Data reads and writes.
+    ReadWrite = 3,
+}
+
+/// Size of the watched location, the DR7 LEN field.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum Length {
+    /// One byte, the only length of execution breakpoints.
+    Byte = 0,
+    /// Two bytes.
+    Word = 1,
+    /// Eight bytes.
+    Qword = 2,
+    /// Four bytes.
+    Dword = 3,
+}
+
+impl Length {
+    /// Size in bytes.
+    pub fn bytes(self) -> u64 {
+        match self {
+            Length::Byte => 1,
+            Length::Word => 2,
+            Length::Dword => 4,
+            Length::Qword => 8,
+        }
+    }
+}
+
+/// A hardware breakpoint.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Breakpoint {
+    /// Linear address watched.
+    pub address: u64,
+    /// What the breakpoint hits on.
+    pub condition: Condition,
+    /// Size of the watched location.
+    pub length: Length,
+}
+
+impl Breakpoint {
+    /// Breaks before executing the instruction at `address`.
+    pub fn execute(address: u64) -> Self {
+        Self {
+            address,
+            condition: Condition::Execute,
+            length: Length::Byte,
+        }
+    }
+
+    /// Breaks after a write to the `length` bytes at `address`.
+    pub fn write(address: u64, length: Length) -> Self {
+        Self {
+            address,
+            condition: Condition::Write,
+            length,
+        }
+    }
+
+    /// Checks the length and alignment the processor requires.
+    pub fn validate(&self) -> TmkResult<()> {
+        if self.condition == Condition::Execute && self.length != Length::Byte {
+            return Err(TmkError::InvalidParameter);
+        }
+        if self.address % self.length.bytes() != 0 {
+            return Err(TmkError::InvalidAlignment);
+        }
+        Ok(())
+    }
+}
+
+/// Returns `dr7` with breakpoint `slot` set to `breakpoint`, locally
+/// enabled, or disabled if `None`.
+pub fn dr7_with(dr7: u64, slot: usize, breakpoint: Option<&Breakpoint>) -> u64 {
+    let enable = 0b11 << (2 * slot);
+    let fields = 0b1111 << (16 + 4 * slot);
+    let dr7 = dr7 & !enable & !fields;
+    match breakpoint {
+        Some(bp) => {
+            let field = bp.condition as u64 | (bp.length as u64) << 2;
+            dr7 | 1 << (2 * slot) | field << (16 + 4 * slot)
+        }
+        None => dr7,
+    }
+}
+
+/// Reads DR`index`, one of DR0-DR3, DR6 or DR7.
+pub fn read_dr(index: u8) -> TmkResult<u64> {
+    let value: u64;
+    // SAFETY: reading a debug register has no side effects.
+    unsafe {
+        match index {
+            0 => asm!("mov {}, dr0", out(reg) value, options(nomem, nostack, preserves_flags)),
+            1 => asm!("mov {}, dr1", out(reg) value, options(nomem, nostack, preserves_flags)),
+            2 => asm!("mov {}, dr2", out(reg) value, options(nomem, nostack, preserves_flags)),
+            3 => asm!("mov {}, dr3", out(reg) value, options(nomem, nostack, preserves_flags)),
+            6 => asm!("mov {}, dr6", out(reg) value, options(nomem, nostack, preserves_flags)),
+            7 => asm!("mov {}, dr7", out(reg) value, options(nomem, nostack, preserves_flags)),
+            _ => return Err(TmkError::InvalidParameter),
+        }
+    }
+    Ok(value)
+}
+
+/// Writes `value` to DR`index`, one of DR0-DR3, DR6 or DR7.
+///
+/// # Safety
+/// Enabling a breakpoint on code or data the #DB path uses makes the
+/// handler fault recursively.
+pub unsafe fn write_dr(index: u8, value: u64) -> TmkResult<()> {
+    // SAFETY: guaranteed by the caller.
+    unsafe {
+        match index {
+            0 => asm!("mov dr0, {}", in(reg) value, options(nomem, nostack, preserves_flags)),
+            1 => asm!("mov dr1, {}", in(reg) value, options(nomem, nostack, preserves_flags)),
+            2 => asm!("mov dr2, {}", in(reg) value, options(nomem, nostack, preserves_flags)),
+            3 => asm!("mov dr3, {}", in(reg) value, options(nomem, nostack, preserves_flags)),
+            6 => asm!("mov dr6, {}", in(reg) value, options(nomem, nostack, preserves_flags)),
+            7 => asm!("mov dr7, {}", in(reg) value, options(nomem, nostack, preserves_flags)),
+            _ => return Err(TmkError::InvalidParameter),
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn read_dr6() -> u64 {
+    read_dr(6).unwrap()
+}
+
+pub(crate) fn write_dr6(value: u64) {
+    // SAFETY: DR6 only reports debug conditions.
+    unsafe { write_dr(6, value).unwrap() };
+}
+
+/// Sets hardware breakpoint `slot` to `breakpoint` on the current VP and
+/// enables it.
+pub fn set_breakpoint(slot: usize, breakpoint: Breakpoint) -> TmkResult<()> {
+    if slot >= BREAKPOINTS {
+        return Err(TmkError::InvalidParameter);
+    }
+    breakpoint.validate()?;
+    let dr7 = dr7_with(read_dr(7)?, slot, Some(&breakpoint));
+    // SAFETY: the breakpoint is disabled while its address changes, and
+    // the #DB handler records hits on it.
+    unsafe {
+        write_dr(7, dr7_with(read_dr(7)?, slot, None))?;
+        write_dr(slot as u8, breakpoint.address)?;
+        write_dr(7, dr7)
+    }
+}
+
+/// Disables hardware breakpoint `slot` on the current VP.
+pub fn clear_breakpoint(slot: usize) -> TmkResult<()> {
+    if slot >= BREAKPOINTS {
+        return Err(TmkError::InvalidParameter);
+    }
+    // SAFETY: disabling a breakpoint is always safe.
+    unsafe {
+        write_dr(7, dr7_with(read_dr(7)?, slot, None))?;
+        write_dr(slot as u8, 0)
+    }
+}
+
+/// The debug registers of a VP.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DebugRegisters {
+    /// DR0-DR3.
+    pub address: [u64; BREAKPOINTS],
+    /// DR6.
+    pub dr6: u64,
+    /// DR7.
+    pub dr7: u64,
+}
+
+impl DebugRegisters {
+    /// Reads the debug registers of the current VP.
+    pub fn read() -> Self {
+        Self {
+            address: [0, 1, 2, 3].map(|i| read_dr(i).unwrap()),
+            dr6: read_dr(6).unwrap(),
+            dr7: read_dr(7).unwrap(),
+        }
+    }
+}
+
+/// A #DB raised by hardware breakpoints.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct BreakpointHit {
+    /// The breakpoints that hit, bit n for DRn.
+    pub slots: u8,
+    /// The instruction pointer: the breakpoint address for execution
+    /// breakpoints, the instruction after the access for data ones.
+    pub rip: u64,
+}
+
+struct HitSlot {
+    valid: AtomicBool,
+    slots: AtomicU8,
+    rip: AtomicU64,
+}
+
+static HITS: [HitSlot; MAX_VPS] = [const {
+    HitSlot {
+        valid: AtomicBool::new(false),
+        slots: AtomicU8::new(0),
+        rip: AtomicU64::new(0),
+    }
+}; MAX_VPS];
+
+fn current() -> &'static HitSlot {
+    &HITS[HvTestCtx::get_vp_idx() as usize]
+}
+
+/// Returns the last breakpoint hit on the current VP not taken yet.
+pub fn take_hit() -> Option<BreakpointHit> {
+    let hit = current();
+    hit.valid
+        .swap(false, Ordering::Acquire)
+        .then(|| BreakpointHit {
+            slots: hit.slots.load(Ordering::Relaxed),
+            rip: hit.rip.load(Ordering::Relaxed),
+        })
+}
+
+/// Called by the #DB handler before any other processing. Returns true if
+/// hardware breakpoints hit, which have then been recorded and the handler
+/// should return.
+pub(crate) fn on_debug(stack_frame: &mut InterruptStackFrame) -> bool {
+    let dr6 = read_dr6();
+    let slots = (dr6 & DR6_BREAKPOINTS) as u8;
+    if slots == 0 {
+        return false;
+    }
+    // The processor never clears DR6 itself.
+    write_dr6(dr6 & !DR6_BREAKPOINTS);
+
+    let hit = current();
+    hit.slots.store(slots, Ordering::Relaxed);
+    hit.rip
+        .store(stack_frame.instruction_pointer.as_u64(), Ordering::Relaxed);
+    hit.valid.store(true, Ordering::Release);
+
+    // Execution breakpoints fault before the instruction, which would hit
+    // again on return without RF.
+    // SAFETY: RF only suppresses instruction breakpoints for the next
+    // instruction.
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            frame.cpu_flags.insert(RFlags::RESUME_FLAG);
+        });
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dr7_with() {
+        let bp = Breakpoint::write(0x1000, Length::Qword);
+        let dr7 = dr7_with(DR7_DISABLED, 1, Some(&bp));
+        assert_eq!(dr7, DR7_DISABLED | 1 << 2 | 0b1001 << 20);
+        let dr7 = dr7_with(dr7, 3, Some(&Breakpoint::execute(0x2000)));
+        assert_eq!(dr7 & (0b11 << 6 | 0b1111 << 28), 1 << 6);
+        assert_eq!(dr7_with(dr7_with(dr7, 1, None), 3, None), DR7_DISABLED);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Breakpoint::execute(0x1003).validate().is_ok());
+        assert!(Breakpoint::write(0x1004, Length::Dword).validate().is_ok());
+        assert_eq!(
+            Breakpoint::write(0x1004, Length::Qword).validate(),
+            Err(TmkError::InvalidAlignment)
+        );
+        let wide_execute = Breakpoint {
+            length: Length::Word,
+            ..Breakpoint::execute(0x1000)
+        };
+        assert_eq!(wide_execute.validate(), Err(TmkError::InvalidParameter));
+    }
+}
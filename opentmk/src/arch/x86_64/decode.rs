@@ -0,0 +1,305 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Minimal decoder for the instructions used to access MMIO.
+//!
+//! Only the `mov` and `movzx` forms compilers emit for volatile accesses
+//! are understood, which is what an intercepted MMIO access needs to be
+//! emulated: the access size, the direction, the register or immediate
+//! involved and the instruction length to skip. The memory operand itself
+//! is not evaluated, the intercept reports its address.
+
+use thiserror::Error;
+
+/// A general purpose register operand.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Gpr {
+    /// The register number, 0 for RAX through 15 for R15.
+    pub index: u8,
+    /// Whether the operand is the second byte of the register, i.e. AH,
+    /// CH, DH or BH.
+    pub high_byte: bool,
+}
+
+/// What a decoded instruction does with its memory operand.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MovKind {
+    /// Stores a register to memory.
+    StoreRegister(Gpr),
+    /// Stores an immediate to memory.
+    StoreImmediate(u64),
+    /// Loads memory into a register. With `zero_extend` the value is
+    /// zero extended to the register size, `movzx`.
+    Load {
+        /// The destination register.
+        dst: Gpr,
+        /// Size in bytes of the destination.
+        dst_size: u8,
+        /// Whether the value is zero extended.
+        zero_extend: bool,
+    },
+}
+
+/// A decoded memory access.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MovAccess {
+    /// Length of the instruction in bytes.
+    pub len: u8,
+    /// Size of the memory access in bytes.
+    pub size: u8,
+    /// What the instruction does with memory.
+    pub kind: MovKind,
+}
+
+/// Reasons an instruction cannot be decoded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Error)]
+pub enum DecodeError {
+    /// The instruction bytes end before the instruction does.
+    #[error("instruction is truncated")]
+    Truncated,
+    /// The opcode is not one of the supported memory accesses.
+    #[error("unsupported opcode {0:#x}")]
+    UnsupportedOpcode(u8),
+    /// The instruction does not access memory.
+    #[error("instruction has no memory operand")]
+    NoMemoryOperand,
+}
+
+struct Bytes<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Bytes<'_> {
+    fn next(&mut self) -> Result<u8, DecodeError> {
+        let b = *self.bytes.get(self.pos).ok_or(DecodeError::Truncated)?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn skip(&mut self, count: usize) -> Result<(), DecodeError> {
+        if self.pos + count > self.bytes.len() {
+            return Err(DecodeError::Truncated);
+        }
+        self.pos += count;
+        Ok(())
+    }
+
+    fn immediate(&mut self, size: usize) -> Result<u64, DecodeError> {
+        let mut value = 0;
+        for i in 0..size {
+            value |= (self.next()? as u64) << (8 * i);
+        }
+        Ok(value)
+    }
+}
+
+/// Skips the ModRM addressing bytes and returns the ModRM `reg` field.
+fn modrm(bytes: &mut Bytes<'_>, rex_r: bool) -> Result<u8, DecodeError> {
+    let modrm = bytes.next()?;
+    let mode = modrm >> 6;
+    let rm = modrm & 7;
+    if mode == 3 {
+        return Err(DecodeError::NoMemoryOperand);
+    }
+    let mut disp = match mode {
+        1 => 1,
+        2 => 4,
+        _ => 0,
+    };
+    if rm == 4 {
+        let sib = bytes.next()?;
+        if mode == 0 && sib & 7 == 5 {
+            disp = 4;
+        }
+    } else if mode == 0 && rm == 5 {
+        // RIP relative.
+        disp = 4;
+    }
+    bytes.skip(disp)?;
+    Ok(((modrm >> 3) & 7) | ((rex_r as u8) << 3))
+}
+
+fn gpr(index: u8, size: u8, rex: bool) -> Gpr {
+    if size == 1 && !rex && (4..8).contains(&index) {
+        Gpr {
+            index: index - 4,
+            high_byte: true,
+        }
+    } else {
+        Gpr {
+            index,
+            high_byte: false,
+        }
+    }
+}
+
+/// Decodes the memory access performed by the instruction in `bytes`.
+pub fn decode(bytes: &[u8]) -> Result<MovAccess, DecodeError> {
+    let mut bytes = Bytes { bytes, pos: 0 };
+    let mut operand_size_override = false;
+    let mut opcode = bytes.next()?;
+    // Legacy prefixes that do not change the meaning of a `mov`.
+    while matches!(
+        opcode,
+        0x66 | 0x67 | 0x2e | 0x3e | 0x26 | 0x64 | 0x65 | 0x36
+    ) {
+        operand_size_override |= opcode == 0x66;
+        opcode = bytes.next()?;
+    }
+    let rex = (0x40..0x50).contains(&opcode).then_some(opcode);
+    if rex.is_some() {
+        opcode = bytes.next()?;
+    }
+    let rex_w = rex.is_some_and(|r| r & 8 != 0);
+    let rex_r = rex.is_some_and(|r| r & 4 != 0);
+    let operand_size = if rex_w {
+        8
+    } else if operand_size_override {
+        2
+    } else {
+        4
+    };
+
+    let (size, kind) = match opcode {
+        0x88 | 0x89 | 0x8a | 0x8b => {
+            let size = if opcode & 1 == 0 { 1 } else { operand_size };
+            let reg = gpr(modrm(&mut bytes, rex_r)?, size, rex.is_some());
+            let kind = if opcode & 2 == 0 {
+                MovKind::StoreRegister(reg)
+            } else {
+                MovKind::Load {
+                    dst: reg,
+                    dst_size: size,
+                    zero_extend: false,
+                }
+            };
+            (size, kind)
+        }
+        0xc6 | 0xc7 => {
+            let size = if opcode == 0xc6 { 1 } else { operand_size };
+            modrm(&mut bytes, rex_r)?;
+            // Immediates are at most 32 bits and sign extended.
+            let imm_size = size.min(4) as usize;
+            let imm = bytes.immediate(imm_size)?;
+            let imm = if size == 8 {
+                imm as i32 as i64 as u64
+            } else {
+                imm
+            };
+            (size, MovKind::StoreImmediate(imm))
+        }
+        0x0f => {
+            let opcode = bytes.next()?;
+            let size = match opcode {
+                0xb6 => 1,
+                0xb7 => 2,
+                _ => return Err(DecodeError::UnsupportedOpcode(opcode)),
+            };
+            let reg = modrm(&mut bytes, rex_r)?;
+            let kind = MovKind::Load {
+                dst: gpr(reg, operand_size, rex.is_some()),
+                dst_size: operand_size,
+                zero_extend: true,
+            };
+            (size, kind)
+        }
+        _ => return Err(DecodeError::UnsupportedOpcode(opcode)),
+    };
+    Ok(MovAccess {
+        len: bytes.pos as u8,
+        size,
+        kind,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reg(index: u8) -> Gpr {
+        Gpr {
+            index,
+            high_byte: false,
+        }
+    }
+
+    #[test]
+    fn test_decode_mov() {
+        // mov dword ptr [rax], ecx
+        assert_eq!(
+            decode(&[0x89, 0x08]),
+            Ok(MovAccess {
+                len: 2,
+                size: 4,
+                kind: MovKind::StoreRegister(reg(1)),
+            })
+        );
+        // mov r9, qword ptr [rdx + 0x10]
+        assert_eq!(
+            decode(&[0x4c, 0x8b, 0x4a, 0x10]),
+            Ok(MovAccess {
+                len: 4,
+                size: 8,
+                kind: MovKind::Load {
+                    dst: reg(9),
+                    dst_size: 8,
+                    zero_extend: false
+                },
+            })
+        );
+        // mov byte ptr [rsp + 8], ah
+        assert_eq!(
+            decode(&[0x88, 0x64, 0x24, 0x08]),
+            Ok(MovAccess {
+                len: 4,
+                size: 1,
+                kind: MovKind::StoreRegister(Gpr {
+                    index: 0,
+                    high_byte: true
+                }),
+            })
+        );
+        // mov qword ptr [rip + 0x1000], -1
+        let access = decode(&[
+            0x48, 0xc7, 0x05, 0x00, 0x10, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff,
+        ]);
+        assert_eq!(
+            access,
+            Ok(MovAccess {
+                len: 11,
+                size: 8,
+                kind: MovKind::StoreImmediate(u64::MAX),
+            })
+        );
+        // movzx eax, word ptr [rcx]
+        assert_eq!(
+            decode(&[0x0f, 0xb7, 0x01]),
+            Ok(MovAccess {
+                len: 3,
+                size: 2,
+                kind: MovKind::Load {
+                    dst: reg(0),
+                    dst_size: 4,
+                    zero_extend: true
+                },
+            })
+        );
+        // mov word ptr [rax], 0x1234
+        assert_eq!(
+            decode(&[0x66, 0xc7, 0x00, 0x34, 0x12]),
+            Ok(MovAccess {
+                len: 5,
+                size: 2,
+                kind: MovKind::StoreImmediate(0x1234),
+            })
+        );
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(decode(&[0x89]), Err(DecodeError::Truncated));
+        assert_eq!(decode(&[0x89, 0xc8]), Err(DecodeError::NoMemoryOperand));
+        assert_eq!(decode(&[0x90]), Err(DecodeError::UnsupportedOpcode(0x90)));
+    }
+}
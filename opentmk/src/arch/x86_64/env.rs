@@ -0,0 +1,276 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A minimal long mode environment owned by the TMK.
+//!
+//! Until boot services are exited the TMK runs on the GDT, page tables and
+//! control register values the firmware left behind, which differ between
+//! firmware builds. Once they are, [`adopt_clean_state`] identity maps
+//! memory with page tables of its own, loads a flat GDT with a TSS
+//! providing the interrupt stacks, loads the TMK IDT with the double fault
+//! handler on its own stack, and programs CR0, CR4 and EFER to a known
+//! minimal configuration. Tests that must control the environment
+//! precisely, such as the SMEP and MBEC tests, check [`clean_env`] first.
+//!
+//! The environment is adopted once, on the VP exiting boot services, and
+//! kept for the rest of the run, so that every test after it sees the
+//! same one: VPs started afterwards inherit its page tables and GDT.
+
+use alloc::alloc::alloc_zeroed;
+use alloc::boxed::Box;
+use core::alloc::Layout;
+use core::arch::x86_64::__cpuid;
+
+use spin::Mutex;
+use x86_64::PhysAddr;
+use x86_64::VirtAddr;
+use x86_64::instructions::segmentation::CS;
+use x86_64::instructions::segmentation::DS;
+use x86_64::instructions::segmentation::ES;
+use x86_64::instructions::segmentation::SS;
+use x86_64::instructions::segmentation::Segment;
+use x86_64::instructions::tables::load_tss;
+use x86_64::registers::control::Cr0;
+use x86_64::registers::control::Cr0Flags;
+use x86_64::registers::control::Cr3;
+use x86_64::registers::control::Cr3Flags;
+use x86_64::registers::control::Cr4;
+use x86_64::registers::control::Cr4Flags;
+use x86_64::registers::model_specific::Efer;
+use x86_64::registers::model_specific::EferFlags;
+use x86_64::registers::segmentation::SegmentSelector;
+use x86_64::structures::gdt::Descriptor;
+use x86_64::structures::gdt::GlobalDescriptorTable;
+use x86_64::structures::paging::PageTable;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::structures::tss::TaskStateSegment;
+
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+const PAGE_SIZE: usize = 4096;
+const SIZE_2MB: u64 = 1 << 21;
+const SIZE_1GB: u64 = 1 << 30;
+/// Most memory identity mapped, what a single PML4 entry covers.
+const MAX_IDENTITY_MAP: u64 = 512 * SIZE_1GB;
+/// Size of each stack of the TSS.
+const TSS_STACK_SIZE: usize = 16 * 1024;
+/// Interrupt stack table slot the double fault handler runs on.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+// CPUID.80000001h:EDX
+const CPUID_80000001_EDX_PAGE_1GB: u32 = 1 << 26;
+
+/// The environment built by [`adopt_clean_state`].
+#[derive(Copy, Clone, Debug)]
+pub struct CleanEnv {
+    /// Physical address of the top level page table, the PML5 if 5-level
+    /// paging is on.
+    pub pml4: u64,
+    /// Bytes identity mapped from address 0.
+    pub mapped: u64,
+    /// Size of the pages of the identity map, 2MB or 1GB.
+    pub page_size: u64,
+    /// Selector of the flat 64-bit code segment.
+    pub code_selector: u16,
+    /// Selector of the flat data segment.
+    pub data_selector: u16,
+    /// Selector of the TSS.
+    pub tss_selector: u16,
+}
+
+static ENV: Mutex<Option<CleanEnv>> = Mutex::new(None);
+
+/// Allocates `size` zeroed, page aligned bytes, never freed.
+fn alloc_pages(size: usize) -> TmkResult<*mut u8> {
+    let layout =
+        Layout::from_size_align(size, PAGE_SIZE).map_err(|_| TmkError::AllocationFailed)?;
+    // SAFETY: the layout has a non-zero size. The memory is never freed, it
+    // backs the environment for the rest of the run.
+    let ptr = unsafe { alloc_zeroed(layout) };
+    if ptr.is_null() {
+        return Err(TmkError::AllocationFailed);
+    }
+    Ok(ptr)
+}
+
+/// Allocates an empty page table and returns it with its address.
+fn alloc_table() -> TmkResult<(&'static mut PageTable, PhysAddr)> {
+    let ptr = alloc_pages(PAGE_SIZE)?;
+    // SAFETY: the page is zeroed, which is an empty table, identity mapped
+    // and owned by nothing else.
+    let table = unsafe { &mut *ptr.cast::<PageTable>() };
+    Ok((table, PhysAddr::new(ptr as u64)))
+}
+
+/// Returns the top of a new stack of `size` bytes.
+fn alloc_stack(size: usize) -> TmkResult<VirtAddr> {
+    let base = alloc_pages(size)?;
+    Ok(VirtAddr::new(base as u64 + size as u64))
+}
+
+/// Number of physical address bits, which bounds the identity map.
+fn physical_address_bits() -> u32 {
+    // SAFETY: CPUID is always available on x86_64.
+    let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+    if max_extended < 0x8000_0008 {
+        return 36;
+    }
+    // SAFETY: the leaf is reported supported above.
+    unsafe { __cpuid(0x8000_0008) }.eax & 0xff
+}
+
+fn page_1gb_supported() -> bool {
+    // SAFETY: CPUID is always available on x86_64.
+    unsafe { __cpuid(0x8000_0001) }.edx & CPUID_80000001_EDX_PAGE_1GB != 0
+}
+
+/// Builds page tables identity mapping the first `size` bytes, writable
+/// and executable, with pages of `page_size`, and returns the address of
+/// the top level table. With `five_level`, a PML5 points at the PML4:
+/// 5-level paging can't be turned off while paging is on.
+///
+/// The memory types come from the MTRRs alone, the PAT entry of every page
+/// being write-back.
+fn build_identity_map(size: u64, page_size: u64, five_level: bool) -> TmkResult<PhysAddr> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let (pml4, pml4_addr) = alloc_table()?;
+    let (pdpt, pdpt_addr) = alloc_table()?;
+    pml4[0].set_addr(pdpt_addr, flags);
+    for gb in 0..size.div_ceil(SIZE_1GB) {
+        let base = gb * SIZE_1GB;
+        if page_size == SIZE_1GB {
+            pdpt[gb as usize].set_addr(PhysAddr::new(base), flags | PageTableFlags::HUGE_PAGE);
+            continue;
+        }
+        let (pd, pd_addr) = alloc_table()?;
+        for (i, entry) in pd.iter_mut().enumerate() {
+            entry.set_addr(
+                PhysAddr::new(base + i as u64 * SIZE_2MB),
+                flags | PageTableFlags::HUGE_PAGE,
+            );
+        }
+        pdpt[gb as usize].set_addr(pd_addr, flags);
+    }
+    if five_level {
+        let (pml5, pml5_addr) = alloc_table()?;
+        pml5[0].set_addr(pml4_addr, flags);
+        return Ok(pml5_addr);
+    }
+    Ok(pml4_addr)
+}
+
+/// Builds a GDT with flat 64-bit code and data segments and a TSS whose
+/// stacks serve interrupts from ring 3 and double faults.
+fn build_gdt() -> TmkResult<(&'static GlobalDescriptorTable, [SegmentSelector; 3])> {
+    let mut tss = TaskStateSegment::new();
+    tss.privilege_stack_table[0] = alloc_stack(TSS_STACK_SIZE)?;
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = alloc_stack(TSS_STACK_SIZE)?;
+    let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
+
+    let mut gdt = GlobalDescriptorTable::new();
+    let code = gdt.append(Descriptor::kernel_code_segment());
+    let data = gdt.append(Descriptor::kernel_data_segment());
+    let tss = gdt.append(Descriptor::tss_segment(tss));
+    Ok((Box::leak(Box::new(gdt)), [code, data, tss]))
+}
+
+/// Switches the current VP to an environment built by the TMK, see the
+/// module documentation, and returns it. Later calls return the
+/// environment already adopted.
+///
+/// Fails with [`TmkError::InvalidPartitionState`] while boot services are
+/// still running on the firmware's page tables.
+pub(crate) fn adopt_clean_state() -> TmkResult<CleanEnv> {
+    let mut adopted = ENV.lock();
+    if let Some(env) = *adopted {
+        return Ok(env);
+    }
+    #[cfg(target_os = "uefi")]
+    if !crate::uefi::alloc::ALLOCATOR.boot_services_exited() {
+        return Err(TmkError::InvalidPartitionState);
+    }
+
+    let mapped = (1u64 << physical_address_bits()).min(MAX_IDENTITY_MAP);
+    let page_size = if page_1gb_supported() {
+        SIZE_1GB
+    } else {
+        SIZE_2MB
+    };
+    // Kept as the firmware set them: 5-level paging and PCIDs can't be
+    // turned off while paging is on, and the compiled code may rely on the
+    // others.
+    let kept = Cr4::read()
+        & (Cr4Flags::OSXSAVE
+            | Cr4Flags::L5_PAGING
+            | Cr4Flags::PCID
+            | Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION
+            | Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION
+            | Cr4Flags::FSGSBASE);
+    let pml4 = build_identity_map(mapped, page_size, kept.contains(Cr4Flags::L5_PAGING))?;
+    let (gdt, [code, data, tss]) = build_gdt()?;
+
+    let pml4_frame = PhysFrame::from_start_address(pml4).map_err(|_| TmkError::InvalidAlignment)?;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        gdt.load();
+        // SAFETY: the selectors are those of the flat segments and the TSS
+        // of the GDT just loaded, which is never freed. The new page tables
+        // identity map all memory as the firmware's did, so the code, stack
+        // and heap stay where they are. NXE is only set once no table with
+        // execute-disable bits is in use, and CR0 and CR4 keep paging, PAE,
+        // the paging mode, PCIDs with PCID 0 in CR3, and the SSE and
+        // extended state the compiled code relies on.
+        unsafe {
+            CS::set_reg(code);
+            SS::set_reg(data);
+            DS::set_reg(data);
+            ES::set_reg(data);
+            load_tss(tss);
+            Cr3::write(pml4_frame, Cr3Flags::empty());
+            Efer::write(
+                EferFlags::LONG_MODE_ENABLE
+                    | EferFlags::LONG_MODE_ACTIVE
+                    | EferFlags::NO_EXECUTE_ENABLE,
+            );
+            Cr0::write(
+                Cr0Flags::PROTECTED_MODE_ENABLE
+                    | Cr0Flags::MONITOR_COPROCESSOR
+                    | Cr0Flags::EXTENSION_TYPE
+                    | Cr0Flags::NUMERIC_ERROR
+                    | Cr0Flags::WRITE_PROTECT
+                    | Cr0Flags::PAGING,
+            );
+            Cr4::write(
+                Cr4Flags::PHYSICAL_ADDRESS_EXTENSION
+                    | Cr4Flags::PAGE_GLOBAL
+                    | Cr4Flags::OSFXSR
+                    | Cr4Flags::OSXMMEXCPT_ENABLE
+                    | kept,
+            );
+        }
+    });
+    #[cfg(nightly)]
+    super::interrupt::init_with_double_fault_stack(DOUBLE_FAULT_IST_INDEX);
+
+    let env = CleanEnv {
+        pml4: pml4.as_u64(),
+        mapped,
+        page_size,
+        code_selector: code.0,
+        data_selector: data.0,
+        tss_selector: tss.0,
+    };
+    log::info!(
+        "adopted the TMK environment: {:#x} bytes identity mapped with {:#x} byte pages",
+        mapped,
+        page_size
+    );
+    *adopted = Some(env);
+    Ok(env)
+}
+
+/// Returns the environment adopted once boot services were exited, `None`
+/// before that or if adopting it failed.
+pub fn clean_env() -> Option<CleanEnv> {
+    *ENV.lock()
+}
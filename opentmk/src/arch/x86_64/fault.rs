@@ -0,0 +1,238 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Recoverable memory and MSR probes.
+//!
+//! [`probe_read`], [`probe_write`] and [`probe_exec`] touch an address from
+//! supervisor mode and report the page fault it raised instead of letting
+//! it reach the default handler. [`probe_rdmsr`] and [`probe_wrmsr`] do the
+//! same for the #GP raised by an MSR access. The page fault and #GP
+//! handlers call [`recover`], which records the fault and resumes execution
+//! at the probe fixup.
+
+use core::arch::global_asm;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use spin::Mutex;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::InterruptStackFrame;
+
+/// A fault raised by a probe.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CapturedFault {
+    /// The exception vector.
+    pub vector: u8,
+    /// The error code pushed by the processor.
+    pub error_code: u64,
+    /// The faulting linear address (CR2).
+    pub address: u64,
+    /// The instruction pointer at the time of the fault.
+    pub rip: u64,
+}
+
+/// Page fault error code bit set for instruction fetches.
+pub const PF_INSTRUCTION_FETCH: u64 = 1 << 4;
+/// Page fault error code bit set for writes.
+pub const PF_WRITE: u64 = 1 << 1;
+/// Page fault error code bit set for protection violations on present pages.
+pub const PF_PROTECTION: u64 = 1 << 0;
+
+#[repr(C)]
+struct ProbeResult {
+    value: u64,
+    faulted: u64,
+}
+
+global_asm! {
+    ".globl opentmk_probe_read",
+    "opentmk_probe_read:",
+    "xor edx, edx",
+    "xor eax, eax",
+    ".globl opentmk_probe_read_insn",
+    "opentmk_probe_read_insn:",
+    "mov al, [rdi]",
+    "ret",
+    ".globl opentmk_probe_write",
+    "opentmk_probe_write:",
+    "xor edx, edx",
+    "xor eax, eax",
+    ".globl opentmk_probe_write_insn",
+    "opentmk_probe_write_insn:",
+    "mov [rdi], sil",
+    "ret",
+    ".globl opentmk_probe_exec",
+    "opentmk_probe_exec:",
+    "xor edx, edx",
+    "xor eax, eax",
+    "call rdi",
+    "ret",
+    ".globl opentmk_probe_rdmsr",
+    "opentmk_probe_rdmsr:",
+    "mov ecx, edi",
+    "xor edx, edx",
+    "xor eax, eax",
+    ".globl opentmk_probe_rdmsr_insn",
+    "opentmk_probe_rdmsr_insn:",
+    "rdmsr",
+    "shl rdx, 32",
+    "or rax, rdx",
+    "xor edx, edx",
+    "ret",
+    ".globl opentmk_probe_wrmsr",
+    "opentmk_probe_wrmsr:",
+    "mov ecx, edi",
+    "mov rax, rsi",
+    "mov rdx, rsi",
+    "shr rdx, 32",
+    ".globl opentmk_probe_wrmsr_insn",
+    "opentmk_probe_wrmsr_insn:",
+    "wrmsr",
+    "xor edx, edx",
+    "xor eax, eax",
+    "ret",
+    ".globl opentmk_probe_fixup",
+    "opentmk_probe_fixup:",
+    "mov edx, 1",
+    "ret",
+}
+
+unsafe extern "sysv64" {
+    fn opentmk_probe_read(addr: u64) -> ProbeResult;
+    fn opentmk_probe_write(addr: u64, value: u8) -> ProbeResult;
+    fn opentmk_probe_exec(addr: u64) -> ProbeResult;
+    fn opentmk_probe_rdmsr(msr: u32) -> ProbeResult;
+    fn opentmk_probe_wrmsr(msr: u32, value: u64) -> ProbeResult;
+    static opentmk_probe_read_insn: u8;
+    static opentmk_probe_write_insn: u8;
+    static opentmk_probe_rdmsr_insn: u8;
+    static opentmk_probe_wrmsr_insn: u8;
+    static opentmk_probe_fixup: u8;
+}
+
+/// Target of the running [`probe_exec`], 0 when none is armed.
+static EXEC_TARGET: AtomicU64 = AtomicU64::new(0);
+/// Serializes probes, which share [`EXEC_TARGET`] and [`PENDING_FAULT`].
+static PROBE_LOCK: Mutex<()> = Mutex::new(());
+/// The fault recorded by the running probe.
+static PENDING_FAULT: Mutex<Option<CapturedFault>> = Mutex::new(None);
+
+fn is_probe_rip(rip: u64) -> bool {
+    let read = core::ptr::addr_of!(opentmk_probe_read_insn) as u64;
+    let write = core::ptr::addr_of!(opentmk_probe_write_insn) as u64;
+    let rdmsr = core::ptr::addr_of!(opentmk_probe_rdmsr_insn) as u64;
+    let wrmsr = core::ptr::addr_of!(opentmk_probe_wrmsr_insn) as u64;
+    let exec = EXEC_TARGET.load(Ordering::Acquire);
+    rip == read || rip == write || rip == rdmsr || rip == wrmsr || (exec != 0 && rip == exec)
+}
+
+/// Called by the exception handlers before any other processing. Returns
+/// true if the fault was raised by a probe; the frame has then been
+/// redirected to the probe fixup and the handler should return.
+pub(crate) fn recover(stack_frame: &mut InterruptStackFrame, vector: u8, error_code: u64) -> bool {
+    let rip = stack_frame.instruction_pointer.as_u64();
+    if !is_probe_rip(rip) {
+        return false;
+    }
+
+    *PENDING_FAULT.lock() = Some(CapturedFault {
+        vector,
+        error_code,
+        address: Cr2::read_raw(),
+        rip,
+    });
+
+    let fixup = x86_64::VirtAddr::new(core::ptr::addr_of!(opentmk_probe_fixup) as u64);
+    if rip == EXEC_TARGET.load(Ordering::Acquire) {
+        // The fetch faulted before the call target ran, so the return
+        // address pushed by `call` is still on the stack. Resume at the
+        // fixup as if the target had returned into it.
+        // SAFETY: popping the return address leaves the stack as it was
+        // in `opentmk_probe_exec` before the call.
+        unsafe {
+            stack_frame.as_mut().update(|frame| {
+                frame.stack_pointer += 8u64;
+                frame.instruction_pointer = fixup;
+            });
+        }
+    } else {
+        // SAFETY: the faulting instruction belongs to a probe, which
+        // returns from the fixup with the same stack.
+        unsafe {
+            stack_frame.as_mut().update(|frame| {
+                frame.instruction_pointer = fixup;
+            });
+        }
+    }
+    true
+}
+
+fn finish(result: ProbeResult) -> Result<u64, CapturedFault> {
+    let fault = PENDING_FAULT.lock().take();
+    if result.faulted != 0 {
+        let fault = fault.expect("probe faulted without a captured fault");
+        log::debug!("probe captured fault {:x?}", fault);
+        Err(fault)
+    } else {
+        Ok(result.value)
+    }
+}
+
+/// Read one byte at `addr` from supervisor mode.
+///
+/// The IDT must be installed with the default handlers so that page faults
+/// reach [`recover`].
+pub fn probe_read(addr: u64) -> Result<u8, CapturedFault> {
+    let _guard = PROBE_LOCK.lock();
+    // SAFETY: a fault on the access is recovered by the page fault handler.
+    let result = unsafe { opentmk_probe_read(addr) };
+    finish(result).map(|v| v as u8)
+}
+
+/// Write one byte at `addr` from supervisor mode.
+pub fn probe_write(addr: u64, value: u8) -> Result<(), CapturedFault> {
+    let _guard = PROBE_LOCK.lock();
+    // SAFETY: a fault on the access is recovered by the page fault handler.
+    let result = unsafe { opentmk_probe_write(addr, value) };
+    finish(result).map(|_| ())
+}
+
+/// Call `addr` from supervisor mode.
+///
+/// The memory at `addr` must start with a `ret` instruction (0xC3) so that
+/// the call returns if no fault is raised.
+pub fn probe_exec(addr: u64) -> Result<(), CapturedFault> {
+    let _guard = PROBE_LOCK.lock();
+    EXEC_TARGET.store(addr, Ordering::Release);
+    // SAFETY: the caller placed a `ret` at `addr`, and a fault on the fetch
+    // is recovered by the page fault handler.
+    let result = unsafe { opentmk_probe_exec(addr) };
+    EXEC_TARGET.store(0, Ordering::Release);
+    finish(result).map(|_| ())
+}
+
+/// Read `msr`, capturing the #GP raised if the MSR is not implemented.
+///
+/// The IDT must be installed with the default handlers so that #GP reaches
+/// [`recover`]. The captured fault's `address` is meaningless for #GP.
+pub fn probe_rdmsr(msr: u32) -> Result<u64, CapturedFault> {
+    let _guard = PROBE_LOCK.lock();
+    // SAFETY: a #GP on the access is recovered by the #GP handler. Reading
+    // an MSR has no side effects on the MSRs tests probe.
+    let result = unsafe { opentmk_probe_rdmsr(msr) };
+    finish(result)
+}
+
+/// Write `value` to `msr`, capturing the #GP raised if the MSR is not
+/// implemented or rejects the value.
+///
+/// # Safety
+///
+/// Writing the MSR must not compromise the execution environment.
+pub unsafe fn probe_wrmsr(msr: u32, value: u64) -> Result<(), CapturedFault> {
+    let _guard = PROBE_LOCK.lock();
+    // SAFETY: a #GP on the access is recovered by the #GP handler, and the
+    // caller guarantees the write is harmless.
+    let result = unsafe { opentmk_probe_wrmsr(msr, value) };
+    finish(result).map(|_| ())
+}
@@ -0,0 +1,169 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! x86_64 processor feature enabling.
+//!
+//! Tests exercising extended state or SMEP/SMAP call [`enable`] instead of
+//! programming CR4 and XCR0 by hand. Every feature is checked against CPUID
+//! before anything is written.
+
+use core::arch::x86_64::__cpuid;
+use core::arch::x86_64::__cpuid_count;
+use core::ops::BitOr;
+
+use x86_64::registers::control::Cr4;
+use x86_64::registers::control::Cr4Flags;
+use x86_64::registers::xcontrol::XCr0;
+use x86_64::registers::xcontrol::XCr0Flags;
+
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// A set of processor features.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Feature(u32);
+
+impl Feature {
+    /// No feature.
+    pub const NONE: Feature = Feature(0);
+    /// XSAVE and the x87/SSE state components.
+    pub const XSAVE: Feature = Feature(1 << 0);
+    /// AVX state. Implies [`Feature::XSAVE`].
+    pub const AVX: Feature = Feature(1 << 1);
+    /// Supervisor mode execution prevention.
+    pub const SMEP: Feature = Feature(1 << 2);
+    /// Supervisor mode access prevention.
+    pub const SMAP: Feature = Feature(1 << 3);
+
+    /// Returns true if all the features in `other` are part of `self`.
+    pub fn contains(self, other: Feature) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Feature {
+    type Output = Feature;
+
+    fn bitor(self, rhs: Feature) -> Feature {
+        Feature(self.0 | rhs.0)
+    }
+}
+
+// CPUID.01h:ECX
+const CPUID_1_ECX_XSAVE: u32 = 1 << 26;
+const CPUID_1_ECX_AVX: u32 = 1 << 28;
+// CPUID.(07h,0):EBX
+const CPUID_7_EBX_SMEP: u32 = 1 << 7;
+const CPUID_7_EBX_SMAP: u32 = 1 << 20;
+
+/// Returns the features supported by the processor.
+pub fn supported() -> Feature {
+    let mut features = Feature::NONE;
+    // SAFETY: CPUID is always available on x86_64.
+    let leaf1 = unsafe { __cpuid(0x1) };
+    // SAFETY: CPUID is always available on x86_64.
+    let max_leaf = unsafe { __cpuid(0x0) }.eax;
+
+    if leaf1.ecx & CPUID_1_ECX_XSAVE != 0 {
+        features = features | Feature::XSAVE;
+        // SAFETY: leaf 0xD is valid when XSAVE is supported.
+        let xsave = unsafe { __cpuid_count(0xd, 0) };
+        let xcr0_supported = XCr0Flags::from_bits_truncate(xsave.eax as u64);
+        if leaf1.ecx & CPUID_1_ECX_AVX != 0 && xcr0_supported.contains(XCr0Flags::AVX) {
+            features = features | Feature::AVX;
+        }
+    }
+
+    if max_leaf >= 0x7 {
+        // SAFETY: leaf 7 is within the supported range.
+        let leaf7 = unsafe { __cpuid_count(0x7, 0) };
+        if leaf7.ebx & CPUID_7_EBX_SMEP != 0 {
+            features = features | Feature::SMEP;
+        }
+        if leaf7.ebx & CPUID_7_EBX_SMAP != 0 {
+            features = features | Feature::SMAP;
+        }
+    }
+    features
+}
+
+/// Returns the features currently enabled on this processor.
+pub fn enabled() -> Feature {
+    let cr4 = Cr4::read();
+    let mut features = Feature::NONE;
+    if cr4.contains(Cr4Flags::OSXSAVE) {
+        features = features | Feature::XSAVE;
+        if XCr0::read().contains(XCr0Flags::AVX) {
+            features = features | Feature::AVX;
+        }
+    }
+    if cr4.contains(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION) {
+        features = features | Feature::SMEP;
+    }
+    if cr4.contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION) {
+        features = features | Feature::SMAP;
+    }
+    features
+}
+
+/// Enable `features` on the current processor.
+///
+/// Fails with [`TmkError::ProcessorFeatureNotSupported`] without changing
+/// anything if CPUID does not report one of the features. CR4 and XCR0 are
+/// per processor, so this must run on every VP that needs the features.
+pub fn enable(features: Feature) -> TmkResult<()> {
+    let supported = supported();
+    if !supported.contains(features) {
+        log::error!(
+            "requested features {:?} not supported, supported: {:?}",
+            features,
+            supported
+        );
+        return Err(TmkError::ProcessorFeatureNotSupported);
+    }
+
+    let mut cr4 = Cr4Flags::empty();
+    if features.contains(Feature::XSAVE) || features.contains(Feature::AVX) {
+        cr4 |= Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE | Cr4Flags::OSXSAVE;
+    }
+    if features.contains(Feature::SMEP) {
+        cr4 |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+    }
+    if features.contains(Feature::SMAP) {
+        cr4 |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+    }
+    // SAFETY: only features reported by CPUID are enabled.
+    unsafe { Cr4::update(|flags| *flags |= cr4) };
+
+    if features.contains(Feature::XSAVE) || features.contains(Feature::AVX) {
+        let mut xcr0 = XCr0::read() | XCr0Flags::X87 | XCr0Flags::SSE;
+        if features.contains(Feature::AVX) {
+            xcr0 |= XCr0Flags::AVX;
+        }
+        // SAFETY: CR4.OSXSAVE is set and the state components are supported.
+        unsafe { XCr0::write(xcr0) };
+    }
+
+    log::debug!("enabled features {:?}, now {:?}", features, enabled());
+    Ok(())
+}
+
+/// Disable SMEP and/or SMAP on the current processor.
+///
+/// XSAVE and AVX cannot be disabled once state may have been saved with
+/// them, so they are rejected.
+pub fn disable(features: Feature) -> TmkResult<()> {
+    if !(Feature::SMEP | Feature::SMAP).contains(features) {
+        return Err(TmkError::InvalidParameter);
+    }
+    let mut cr4 = Cr4Flags::empty();
+    if features.contains(Feature::SMEP) {
+        cr4 |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+    }
+    if features.contains(Feature::SMAP) {
+        cr4 |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+    }
+    // SAFETY: clearing SMEP/SMAP only relaxes supervisor protections.
+    unsafe { Cr4::update(|flags| flags.remove(cr4)) };
+    Ok(())
+}
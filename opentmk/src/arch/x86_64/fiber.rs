@@ -0,0 +1,92 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! x86_64 fiber context switch.
+//!
+//! A suspended context is its stack pointer: [`switch_stack`] pushes the
+//! registers the sysv64 ABI preserves across calls on the current stack,
+//! above its return address, before loading the stack pointer of the
+//! context it resumes. The compiler saves everything else around the call.
+
+use core::arch::global_asm;
+
+global_asm!(
+    ".global opentmk_fiber_switch",
+    "opentmk_fiber_switch:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    // First resume of a context built by `prepare_stack`: r12 holds the
+    // argument and r13 the entry point.
+    ".global opentmk_fiber_start",
+    "opentmk_fiber_start:",
+    "mov rdi, r12",
+    "call r13",
+    "ud2",
+);
+
+unsafe extern "sysv64" {
+    fn opentmk_fiber_switch(from: *mut u64, to: u64);
+    fn opentmk_fiber_start();
+}
+
+extern "sysv64" fn trampoline<F: FnOnce()>(f: *mut Option<F>) {
+    // SAFETY: `prepare_stack` callers keep the `Option<F>` alive and
+    // untouched until the closure is taken.
+    let f = unsafe { &mut *f };
+    (f.take().expect("closure already taken"))();
+    panic!("fiber entry returned");
+}
+
+/// Builds a context on `stack` that calls the closure in `f` when first
+/// switched to, and returns its stack pointer.
+///
+/// # Safety
+///
+/// `stack` and `f` must stay alive and otherwise unused until the context
+/// is no longer resumed. The closure must never return; it ends by
+/// switching to another context for good.
+pub unsafe fn prepare_stack<F: FnOnce()>(stack: &mut [u8], f: *mut Option<F>) -> u64 {
+    let top = (stack.as_mut_ptr() as u64 + stack.len() as u64) & !0xf;
+    // r15, r14, r13, r12, rbx, rbp, then the return address, laid out so
+    // that the entry is called with a 16 byte aligned stack.
+    let frame = [
+        0,
+        0,
+        trampoline::<F> as *const () as u64,
+        f as u64,
+        0,
+        0,
+        opentmk_fiber_start as *const () as u64,
+    ];
+    let sp = top - 16 - size_of_val(&frame) as u64;
+    // SAFETY: the frame lies within `stack`, which the caller lends to the
+    // context.
+    unsafe { core::ptr::write(sp as *mut [u64; 7], frame) };
+    sp
+}
+
+/// Suspends the current context, storing its stack pointer in `from`, and
+/// resumes the context saved at stack pointer `to`.
+///
+/// # Safety
+///
+/// `to` must be the stack pointer of a suspended context, returned by
+/// [`prepare_stack`] or stored by a previous switch, whose stack is still
+/// alive. Each suspended context must be resumed at most once.
+pub unsafe fn switch_stack(from: *mut u64, to: u64) {
+    // SAFETY: guaranteed by the caller.
+    unsafe { opentmk_fiber_switch(from, to) }
+}
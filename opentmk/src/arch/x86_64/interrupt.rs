@@ -4,6 +4,11 @@
 //! x86_64-specific interrupt handling implementation.
 //!
 
+use alloc::boxed::Box;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::Ordering;
+
 use lazy_static::lazy_static;
 use spin::Mutex;
 use x86_64::structures::idt::InterruptDescriptorTable;
@@ -11,6 +16,7 @@
 
 use super::interrupt_handler_register::register_interrupt_handler;
 use super::interrupt_handler_register::set_common_handler;
+use super::interrupt_watch;
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
@@ -22,10 +28,19 @@
 }
 
 static mut HANDLERS: [fn(); 256] = [no_op; 256];
+static REGISTERED: [AtomicBool; 256] = [const { AtomicBool::new(false) }; 256];
 static MUTEX: Mutex<()> = Mutex::new(());
+/// The IDT of the TMK environment, see [`init_with_double_fault_stack`],
+/// null until it is adopted.
+static CLEAN_IDT: AtomicPtr<InterruptDescriptorTable> = AtomicPtr::new(core::ptr::null_mut());
 fn no_op() {}
 
 fn common_handler(_stack_frame: InterruptStackFrame, interrupt: u8) {
+    let _irq = crate::platform::hyperv::irq_hvcall::enter_irq();
+    interrupt_watch::record(
+        interrupt,
+        REGISTERED[interrupt as usize].load(Ordering::Relaxed),
+    );
     // SAFETY: Handlers are initialized to no_op and only set via set_handler which is
     // protected by a mutex.
     unsafe {
@@ -40,13 +55,26 @@ pub fn set_handler(interrupt: u8, handler: fn()) {
     unsafe {
         HANDLERS[interrupt as usize] = handler;
     }
+    REGISTERED[interrupt as usize].store(true, Ordering::Relaxed);
+}
+
+/// Removes the handler of a specific interrupt number, which is then
+/// ignored again, as before [`set_handler`].
+pub fn clear_handler(interrupt: u8) {
+    let _lock = MUTEX.lock();
+    REGISTERED[interrupt as usize].store(false, Ordering::Relaxed);
+    // SAFETY: handlers is protected by a mutex.
+    unsafe {
+        HANDLERS[interrupt as usize] = no_op;
+    }
 }
 
 extern "x86-interrupt" fn handler_double_fault(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    log::error!(
+    crate::log_fmt_nostdalloc!(
+        log::Level::Error,
         "EXCEPTION:\n\tERROR_CODE: {}\n\tDOUBLE FAULT\n{:#?}",
         _error_code,
         stack_frame
@@ -58,7 +86,30 @@ extern "x86-interrupt" fn handler_double_fault(
 
 /// Initialize the IDT
 pub fn init() {
-    IDT.load();
+    let clean = CLEAN_IDT.load(Ordering::Acquire);
+    if clean.is_null() {
+        IDT.load();
+    } else {
+        // SAFETY: the IDT was leaked by `init_with_double_fault_stack` and
+        // is never freed or changed.
+        unsafe { &*clean }.load();
+    }
     set_common_handler(common_handler);
     x86_64::instructions::interrupts::enable();
 }
+
+/// Initialize the IDT with the double fault handler running on the
+/// interrupt stack `ist_index` of the TSS, for the TMK environment, see
+/// [`super::env`]. Later calls to [`init`] keep this IDT.
+pub(crate) fn init_with_double_fault_stack(ist_index: u16) {
+    let mut idt = IDT.clone();
+    // SAFETY: the caller loaded a TSS with a valid stack at `ist_index`,
+    // which is never freed.
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(handler_double_fault)
+            .set_stack_index(ist_index);
+    }
+    CLEAN_IDT.store(Box::leak(Box::new(idt)), Ordering::Release);
+    init();
+}
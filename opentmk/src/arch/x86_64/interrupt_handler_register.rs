@@ -13,7 +13,7 @@
 fn abstraction_handle(stack_frame: InterruptStackFrame, interrupt: u8) {
     // SAFETY: COMMON_HANDLER is only set via set_common_handler which is protected by a mutex.
     unsafe { (COMMON_HANDLER)(stack_frame, interrupt) };
-    log::debug!("Interrupt: {}", interrupt);
+    crate::log_fmt_nostdalloc!(log::Level::Debug, "Interrupt: {}", interrupt);
 }
 
 macro_rules! create_fn {
@@ -50,14 +50,41 @@ extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame) -> ! {
     };
 }
 
+macro_rules! create_gp_fn {
+    ($name:ident, $i: expr) => {
+        extern "x86-interrupt" fn $name(mut stack_frame: InterruptStackFrame, error_code: u64) {
+            if super::fault::recover(&mut stack_frame, $i, error_code) {
+                return;
+            }
+            abstraction_handle(stack_frame, $i);
+        }
+    };
+}
+
+macro_rules! create_debug_fn {
+    ($name:ident, $i: expr) => {
+        extern "x86-interrupt" fn $name(mut stack_frame: InterruptStackFrame) {
+            // A step and a breakpoint can be reported by the same #DB.
+            let stepped = super::single_step::on_debug(&mut stack_frame);
+            if super::debug::on_debug(&mut stack_frame) | stepped {
+                return;
+            }
+            abstraction_handle(stack_frame, $i);
+        }
+    };
+}
+
 static mut BACKUP_RSP: u64 = 0;
 
 macro_rules! create_page_fault_fn {
     ($name:ident, $i: expr) => {
         extern "x86-interrupt" fn $name(
-            stack_frame: InterruptStackFrame,
-            _error_code: PageFaultErrorCode,
+            mut stack_frame: InterruptStackFrame,
+            error_code: PageFaultErrorCode,
         ) {
+            if super::fault::recover(&mut stack_frame, $i, error_code.bits()) {
+                return;
+            }
             abstraction_handle(stack_frame, $i);
         }
     };
@@ -70,7 +97,11 @@ macro_rules! register_interrupt_handler {
 }
 
 fn common_handler(_stack_frame: InterruptStackFrame, interrupt: u8) {
-    log::info!("Default interrupt handler fired: {}", interrupt);
+    crate::log_fmt_nostdalloc!(
+        log::Level::Info,
+        "Default interrupt handler fired: {}",
+        interrupt
+    );
 }
 
 pub fn set_common_handler(handler: fn(InterruptStackFrame, u8)) {
@@ -339,7 +370,7 @@ pub fn register_interrupt_handler(idt: &mut InterruptDescriptorTable) {
 }
 
 create_fn!(handler_0, 0);
-create_fn!(handler_1, 1);
+create_debug_fn!(handler_1, 1);
 create_fn!(handler_2, 2);
 create_fn!(handler_3, 3);
 create_fn!(handler_4, 4);
@@ -351,7 +382,7 @@ pub fn register_interrupt_handler(idt: &mut InterruptDescriptorTable) {
 create_fn_create_with_errorcode!(handler_10, 10);
 create_fn_create_with_errorcode!(handler_11, 11);
 create_fn_create_with_errorcode!(handler_12, 12);
-create_fn_create_with_errorcode!(handler_13, 13);
+create_gp_fn!(handler_13, 13);
 create_page_fault_fn!(handler_14, 14);
 create_fn!(handler_15, 15);
 create_fn!(handler_16, 16);
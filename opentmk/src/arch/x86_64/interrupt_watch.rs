@@ -0,0 +1,211 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Per-vector interrupt accounting.
+//!
+//! The common interrupt handler calls [`record`] for every vector it
+//! dispatches. A vector firing more than the storm threshold within one
+//! window, or firing at all without a registered handler, is flagged: a
+//! warning is logged from the handler the first time, and
+//! [`write_record`] reports the flagged vectors as an `interrupt_anomaly`
+//! record. Mis-routed interrupts in device and intercept tests show up
+//! there instead of silently landing in the default handler.
+//!
+//! Windows are tracked per vector, not per VP, so the rate of a vector
+//! firing on several VPs at once is approximate.
+
+use alloc::vec::Vec;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use serde::Serialize;
+
+use super::cycles;
+
+const VECTORS: usize = 256;
+/// Interrupts of one vector within a window making a storm.
+const STORM_COUNT: u64 = 10_000;
+/// Window length, in TSC cycles.
+const WINDOW_CYCLES: u64 = 10_000_000;
+
+const STORM: u8 = 1 << 0;
+const UNREGISTERED: u8 = 1 << 1;
+
+struct VectorStats {
+    total: AtomicU64,
+    window_start: AtomicU64,
+    window_count: AtomicU64,
+    peak: AtomicU64,
+    anomalies: AtomicU8,
+}
+
+impl VectorStats {
+    const fn new() -> Self {
+        Self {
+            total: AtomicU64::new(0),
+            window_start: AtomicU64::new(0),
+            window_count: AtomicU64::new(0),
+            peak: AtomicU64::new(0),
+            anomalies: AtomicU8::new(0),
+        }
+    }
+
+    /// Accounts an interrupt at TSC value `now` and returns the anomalies
+    /// it raised for the first time.
+    fn account(&self, now: u64, registered: bool) -> u8 {
+        self.total.fetch_add(1, Ordering::Relaxed);
+
+        let start = self.window_start.load(Ordering::Relaxed);
+        let count = if now.wrapping_sub(start) > WINDOW_CYCLES {
+            self.window_start.store(now, Ordering::Relaxed);
+            self.window_count.store(1, Ordering::Relaxed);
+            1
+        } else {
+            self.window_count.fetch_add(1, Ordering::Relaxed) + 1
+        };
+        self.peak.fetch_max(count, Ordering::Relaxed);
+
+        let mut anomalies = 0;
+        if !registered {
+            anomalies |= UNREGISTERED;
+        }
+        if count > STORM_COUNT {
+            anomalies |= STORM;
+        }
+        anomalies & !self.anomalies.fetch_or(anomalies, Ordering::Relaxed)
+    }
+}
+
+static STATS: [VectorStats; VECTORS] = [const { VectorStats::new() }; VECTORS];
+
+/// Clears the counts and flags of every vector.
+pub fn reset() {
+    for stats in &STATS {
+        stats.total.store(0, Ordering::Relaxed);
+        stats.window_start.store(0, Ordering::Relaxed);
+        stats.window_count.store(0, Ordering::Relaxed);
+        stats.peak.store(0, Ordering::Relaxed);
+        stats.anomalies.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Accounts an interrupt on `vector`, `registered` telling whether a
+/// handler was set for it. Called from interrupt context.
+pub fn record(vector: u8, registered: bool) {
+    let new = STATS[vector as usize].account(cycles::read(), registered);
+    if new & UNREGISTERED != 0 {
+        crate::log_fmt_nostdalloc!(
+            log::Level::Warn,
+            "interrupt on vector {:#x} without a registered handler",
+            vector
+        );
+    }
+    if new & STORM != 0 {
+        crate::log_fmt_nostdalloc!(
+            log::Level::Warn,
+            "interrupt storm on vector {:#x}: more than {} interrupts in a window",
+            vector,
+            STORM_COUNT
+        );
+    }
+}
+
+/// The activity of a flagged vector.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct VectorActivity {
+    /// The vector.
+    pub vector: u8,
+    /// Interrupts since the last [`reset`].
+    pub total: u64,
+    /// Most interrupts seen within one window.
+    pub peak_per_window: u64,
+    /// Whether the vector fired above the storm threshold.
+    pub storm: bool,
+    /// Whether the vector fired without a registered handler.
+    pub unregistered: bool,
+}
+
+/// Returns the vectors flagged since the last [`reset`].
+pub fn anomalies() -> Vec<VectorActivity> {
+    STATS
+        .iter()
+        .enumerate()
+        .filter_map(|(vector, stats)| {
+            let anomalies = stats.anomalies.load(Ordering::Relaxed);
+            (anomalies != 0).then(|| VectorActivity {
+                vector: vector as u8,
+                total: stats.total.load(Ordering::Relaxed),
+                peak_per_window: stats.peak.load(Ordering::Relaxed),
+                storm: anomalies & STORM != 0,
+                unregistered: anomalies & UNREGISTERED != 0,
+            })
+        })
+        .collect()
+}
+
+#[derive(Serialize)]
+struct InterruptAnomalyRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    test: &'a str,
+    storm_count: u64,
+    window_cycles: u64,
+    vectors: &'a [VectorActivity],
+}
+
+/// Writes the `interrupt_anomaly` record for `test` if any vector was
+/// flagged since the last [`reset`].
+pub fn write_record(test: &str) {
+    let vectors = anomalies();
+    if vectors.is_empty() {
+        return;
+    }
+    for v in &vectors {
+        log::warn!(
+            "{}: vector {:#x} fired {} times, peak {} per window{}{}",
+            test,
+            v.vector,
+            v.total,
+            v.peak_per_window,
+            if v.storm { ", storm" } else { "" },
+            if v.unregistered { ", unregistered" } else { "" }
+        );
+    }
+    crate::tmk_logger::write_record(&InterruptAnomalyRecord {
+        record_type: "interrupt_anomaly",
+        test,
+        storm_count: STORM_COUNT,
+        window_cycles: WINDOW_CYCLES,
+        vectors: &vectors,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_account() {
+        let stats = VectorStats::new();
+        assert_eq!(stats.account(WINDOW_CYCLES + 1, false), UNREGISTERED);
+        assert_eq!(stats.account(WINDOW_CYCLES + 1, false), 0);
+
+        let stats = VectorStats::new();
+        let now = WINDOW_CYCLES + 1;
+        for _ in 0..STORM_COUNT {
+            assert_eq!(stats.account(now, true), 0);
+        }
+        assert_eq!(stats.account(now + WINDOW_CYCLES, true), STORM);
+        assert_eq!(stats.account(now + WINDOW_CYCLES, true), 0);
+        assert_eq!(stats.peak.load(Ordering::Relaxed), STORM_COUNT + 2);
+
+        // A new window starts the count over.
+        let stats = VectorStats::new();
+        for i in 0..=STORM_COUNT {
+            assert_eq!(stats.account((i + 1) * (WINDOW_CYCLES + 1), true), 0);
+        }
+        assert_eq!(stats.peak.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.total.load(Ordering::Relaxed), STORM_COUNT + 1);
+    }
+}
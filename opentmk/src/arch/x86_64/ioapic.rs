@@ -0,0 +1,197 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Minimal IOAPIC driver.
+//!
+//! The IOAPIC routes each global system interrupt (GSI) to a vector on a
+//! local APIC through its redirection table, which is reached through an
+//! index register and a data window in its MMIO page. Only physical
+//! destinations with 8-bit APIC IDs are supported.
+//!
+//! While the 8259 PIC is left unmasked, legacy device interrupts may also
+//! be delivered through it; [`mask_legacy_pic`] leaves the IOAPIC as their
+//! only path.
+
+use bitfield_struct::bitfield;
+use spin::Mutex;
+
+use super::io::inb;
+use super::io::outb;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// Address the IOAPIC is found at on PC platforms.
+pub const DEFAULT_BASE: u64 = 0xfec0_0000;
+
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+const REG_ID: u8 = 0x00;
+const REG_VERSION: u8 = 0x01;
+const REG_REDIRECTION_BASE: u8 = 0x10;
+
+const PIC1_DATA: u16 = 0x21;
+const PIC2_DATA: u16 = 0xa1;
+
+/// Fixed delivery mode.
+pub const DELIVERY_FIXED: u8 = 0;
+
+/// Serializes the index and data window pair.
+static WINDOW: Mutex<()> = Mutex::new(());
+
+/// An entry of the redirection table.
+#[bitfield(u64)]
+pub struct RedirectionEntry {
+    /// The vector delivered.
+    pub vector: u8,
+    /// The delivery mode, e.g. [`DELIVERY_FIXED`].
+    #[bits(3)]
+    pub delivery_mode: u8,
+    /// Whether `destination` is a logical rather than physical destination.
+    pub logical_destination: bool,
+    /// Whether an interrupt is waiting to be accepted. Read only.
+    pub delivery_pending: bool,
+    /// Whether the interrupt line is active low.
+    pub active_low: bool,
+    /// Whether a level triggered interrupt awaits its EOI. Read only.
+    pub remote_irr: bool,
+    /// Whether the interrupt is level rather than edge triggered.
+    pub level_triggered: bool,
+    /// Whether the interrupt is masked.
+    pub masked: bool,
+    #[bits(39)]
+    _reserved: u64,
+    /// The APIC ID of the destination.
+    pub destination: u8,
+}
+
+/// An IOAPIC, at an identity mapped address.
+pub struct IoApic {
+    base: u64,
+}
+
+impl IoApic {
+    /// Returns the IOAPIC at `base`, failing with
+    /// [`TmkError::FeatureUnavailable`] if none answers there.
+    pub fn new(base: u64) -> TmkResult<Self> {
+        let ioapic = Self { base };
+        if ioapic.read(REG_VERSION) == u32::MAX {
+            return Err(TmkError::FeatureUnavailable);
+        }
+        Ok(ioapic)
+    }
+
+    fn read(&self, reg: u8) -> u32 {
+        let _window = WINDOW.lock();
+        // SAFETY: the IOAPIC registers are identity mapped MMIO, and the
+        // window lock keeps the index from changing under the access.
+        unsafe {
+            ((self.base + IOREGSEL) as *mut u32).write_volatile(reg.into());
+            ((self.base + IOWIN) as *const u32).read_volatile()
+        }
+    }
+
+    fn write(&self, reg: u8, value: u32) {
+        let _window = WINDOW.lock();
+        // SAFETY: as for `read`.
+        unsafe {
+            ((self.base + IOREGSEL) as *mut u32).write_volatile(reg.into());
+            ((self.base + IOWIN) as *mut u32).write_volatile(value);
+        }
+    }
+
+    /// The IOAPIC ID.
+    pub fn id(&self) -> u8 {
+        (self.read(REG_ID) >> 24) as u8 & 0xf
+    }
+
+    /// The version of the IOAPIC.
+    pub fn version(&self) -> u8 {
+        self.read(REG_VERSION) as u8
+    }
+
+    /// The number of entries of the redirection table, one per GSI.
+    pub fn entries(&self) -> u32 {
+        (self.read(REG_VERSION) >> 16 & 0xff) + 1
+    }
+
+    fn check_gsi(&self, gsi: u32) -> TmkResult<u8> {
+        if gsi >= self.entries() {
+            return Err(TmkError::InvalidParameter);
+        }
+        u8::try_from(REG_REDIRECTION_BASE as u32 + 2 * gsi).map_err(|_| TmkError::InvalidParameter)
+    }
+
+    /// Reads the redirection entry of `gsi`.
+    pub fn redirection(&self, gsi: u32) -> TmkResult<RedirectionEntry> {
+        let reg = self.check_gsi(gsi)?;
+        let low = self.read(reg) as u64;
+        let high = self.read(reg + 1) as u64;
+        Ok(RedirectionEntry::from(high << 32 | low))
+    }
+
+    /// Writes the redirection entry of `gsi`. The entry is masked while
+    /// it is half written.
+    pub fn set_redirection(&self, gsi: u32, entry: RedirectionEntry) -> TmkResult<()> {
+        let reg = self.check_gsi(gsi)?;
+        let value = u64::from(entry);
+        let masked = u64::from(entry.with_masked(true));
+        self.write(reg, masked as u32);
+        self.write(reg + 1, (value >> 32) as u32);
+        self.write(reg, value as u32);
+        Ok(())
+    }
+
+    /// Routes `gsi`, an edge triggered active high interrupt, to `vector`
+    /// on the local APIC with ID `apic_id`, and unmasks it.
+    pub fn route(&self, gsi: u32, vector: u8, apic_id: u32) -> TmkResult<()> {
+        let destination = u8::try_from(apic_id).map_err(|_| TmkError::InvalidParameter)?;
+        self.set_redirection(
+            gsi,
+            RedirectionEntry::new()
+                .with_vector(vector)
+                .with_delivery_mode(DELIVERY_FIXED)
+                .with_destination(destination),
+        )
+    }
+
+    /// Masks or unmasks `gsi`, keeping its routing.
+    pub fn set_masked(&self, gsi: u32, masked: bool) -> TmkResult<()> {
+        let entry = self.redirection(gsi)?;
+        self.set_redirection(gsi, entry.with_masked(masked))
+    }
+}
+
+/// Masks every line of both 8259 PICs and returns their previous masks,
+/// to be restored with [`set_legacy_pic_masks`].
+pub fn mask_legacy_pic() -> [u8; 2] {
+    set_legacy_pic_masks([0xff; 2])
+}
+
+/// Sets the interrupt masks of the primary and secondary 8259 PICs and
+/// returns the previous ones.
+pub fn set_legacy_pic_masks(masks: [u8; 2]) -> [u8; 2] {
+    let previous = [inb(PIC1_DATA), inb(PIC2_DATA)];
+    outb(PIC1_DATA, masks[0]);
+    outb(PIC2_DATA, masks[1]);
+    previous
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redirection_entry() {
+        let entry = RedirectionEntry::new()
+            .with_vector(0x50)
+            .with_level_triggered(true)
+            .with_masked(true)
+            .with_destination(3);
+        assert_eq!(u64::from(entry), 0x0300_0000_0001_8050);
+        let entry = RedirectionEntry::from(0x0100_0000_0000_2031);
+        assert_eq!(entry.vector(), 0x31);
+        assert!(entry.active_low());
+        assert!(!entry.masked());
+        assert_eq!(entry.destination(), 1);
+    }
+}
@@ -1,12 +1,33 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
+pub mod apic;
+pub mod cache;
+pub mod cycles;
+#[cfg(nightly)]
+pub mod debug;
+pub mod decode;
+pub mod env;
+#[cfg(nightly)]
+pub mod fault;
+pub mod features;
+pub mod fiber;
 pub mod hypercall;
 #[cfg(nightly)]
 pub mod interrupt;
 #[cfg(nightly)]
 mod interrupt_handler_register;
-mod io;
+#[cfg(nightly)]
+pub mod interrupt_watch;
+pub(crate) mod io;
+pub mod ioapic;
+#[cfg(nightly)]
+pub mod msr_conformance;
+pub mod paging;
+pub mod regs;
 pub mod rtc;
 pub mod serial;
+#[cfg(nightly)]
+pub mod single_step;
+pub mod stack;
 pub mod tpm;
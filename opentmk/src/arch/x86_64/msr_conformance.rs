@@ -0,0 +1,173 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! MSR emulation conformance checks.
+//!
+//! [`check`] probes a table of MSRs, capturing the #GP raised by the ones
+//! the platform does not implement, and compares what each access did with
+//! the expectation in the table. The results are reported as an
+//! `msr_conformance` record.
+
+use alloc::vec::Vec;
+
+use serde::Serialize;
+
+use super::fault;
+
+/// What reading an MSR is expected to do.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExpectedRead {
+    /// Raise #GP.
+    Fault,
+    /// Return zero.
+    Zero,
+    /// Return a non-zero value.
+    NonZero,
+    /// Not raise #GP, whatever the value.
+    Any,
+}
+
+/// What writing an MSR is expected to do.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExpectedWrite {
+    /// Raise #GP.
+    Fault,
+    /// Accept the value.
+    Accepted,
+}
+
+/// An entry of an MSR expectation table.
+#[derive(Copy, Clone, Debug)]
+pub struct MsrExpectation {
+    /// The MSR.
+    pub msr: u32,
+    /// Name used in the report.
+    pub name: &'static str,
+    /// The expected read behavior.
+    pub read: ExpectedRead,
+    /// A value to write and the expected write behavior, if the MSR should
+    /// be written.
+    pub write: Option<(u64, ExpectedWrite)>,
+}
+
+/// What an MSR access did.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Observed {
+    /// The access raised #GP.
+    Fault,
+    /// The read returned zero, or the write was accepted.
+    Zero,
+    /// The read returned this non-zero value.
+    Value(u64),
+}
+
+/// The outcome of probing one MSR.
+#[derive(Clone, Debug, Serialize)]
+pub struct MsrResult {
+    /// The MSR.
+    pub msr: u32,
+    /// Name from the expectation table.
+    pub name: &'static str,
+    /// What the read did.
+    pub read: Observed,
+    /// What the write did, if the MSR was written.
+    pub write: Option<Observed>,
+    /// Whether both accesses matched the expectation.
+    pub conforms: bool,
+}
+
+fn read_conforms(expected: ExpectedRead, observed: Observed) -> bool {
+    matches!(
+        (expected, observed),
+        (ExpectedRead::Fault, Observed::Fault)
+            | (ExpectedRead::Zero, Observed::Zero)
+            | (ExpectedRead::NonZero, Observed::Value(_))
+            | (ExpectedRead::Any, Observed::Zero | Observed::Value(_))
+    )
+}
+
+fn write_conforms(expected: ExpectedWrite, observed: Observed) -> bool {
+    (expected == ExpectedWrite::Fault) == (observed == Observed::Fault)
+}
+
+/// Probe the MSRs of `table` and compare them with their expectations.
+///
+/// An accepted write is undone by writing back the value read before, if
+/// the read succeeded.
+///
+/// # Safety
+///
+/// The writes in `table`, and restoring the previous values, must not
+/// compromise the execution environment.
+pub unsafe fn check(table: &[MsrExpectation]) -> Vec<MsrResult> {
+    table
+        .iter()
+        .map(|entry| {
+            let read = match fault::probe_rdmsr(entry.msr) {
+                Ok(0) => Observed::Zero,
+                Ok(v) => Observed::Value(v),
+                Err(_) => Observed::Fault,
+            };
+            let write = entry.write.map(|(value, _)| {
+                // SAFETY: guaranteed by the caller.
+                match unsafe { fault::probe_wrmsr(entry.msr, value) } {
+                    Ok(()) => {
+                        let previous = match read {
+                            Observed::Zero => Some(0),
+                            Observed::Value(v) => Some(v),
+                            Observed::Fault => None,
+                        };
+                        if let Some(previous) = previous {
+                            // SAFETY: restoring the value read above.
+                            let _ = unsafe { fault::probe_wrmsr(entry.msr, previous) };
+                        }
+                        Observed::Zero
+                    }
+                    Err(_) => Observed::Fault,
+                }
+            });
+            let conforms = read_conforms(entry.read, read)
+                && match (entry.write, write) {
+                    (Some((_, expected)), Some(observed)) => write_conforms(expected, observed),
+                    _ => true,
+                };
+            if !conforms {
+                log::error!(
+                    "{} ({:#x}): read {:?} write {:?}, expected {:?} {:?}",
+                    entry.name,
+                    entry.msr,
+                    read,
+                    write,
+                    entry.read,
+                    entry.write
+                );
+            }
+            MsrResult {
+                msr: entry.msr,
+                name: entry.name,
+                read,
+                write,
+                conforms,
+            }
+        })
+        .collect()
+}
+
+#[derive(Serialize)]
+struct MsrConformanceRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    platform: &'a str,
+    results: &'a [MsrResult],
+}
+
+/// Writes the `msr_conformance` record for the results of [`check`]
+/// against the table of `platform`.
+pub fn write_report(platform: &str, results: &[MsrResult]) {
+    crate::tmk_logger::write_record(&MsrConformanceRecord {
+        record_type: "msr_conformance",
+        platform,
+        results,
+    });
+}
@@ -0,0 +1,329 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Minimal editing of the firmware-provided page tables.
+//!
+//! The TMK runs on the identity map built by UEFI, so physical addresses of
+//! page tables are directly usable as pointers. Large pages covering an
+//! edited address are split into 4K pages on demand.
+//!
+//! [`map_alias`] maps a guest physical page at a second virtual address with
+//! protections of its own, for tests of how translation and permissions
+//! interact; [`translate`] tells where an address is mapped.
+
+use alloc::alloc::alloc_zeroed;
+use core::alloc::Layout;
+
+use x86_64::PhysAddr;
+use x86_64::VirtAddr;
+use x86_64::registers::control::Cr0;
+use x86_64::registers::control::Cr0Flags;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::PageTable;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::structures::paging::PageTableIndex;
+use x86_64::structures::paging::page_table::PageTableEntry;
+
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+const PAGE_SIZE: u64 = 4096;
+/// The PAT bit of a large page entry, which reads as an address bit.
+const LARGE_PAGE_PAT: u64 = 1 << 12;
+
+/// Access attributes of a 4K page.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PageAccess {
+    /// Whether user mode (CPL 3) may access the page.
+    pub user: bool,
+    /// Whether instructions may be fetched from the page.
+    pub executable: bool,
+}
+
+fn table_at(addr: PhysAddr) -> &'static mut PageTable {
+    // SAFETY: the page tables are identity mapped and only edited with
+    // CR0.WP cleared, see `with_page_tables_writable`.
+    unsafe { &mut *(addr.as_u64() as *mut PageTable) }
+}
+
+/// Replace the large page mapped by `entry` with a table of 512 entries
+/// mapping the same memory with `child_size` pages, and the same memory
+/// type: the PAT bit moves from bit 12 to bit 7 in 4K entries.
+fn split_large_page(entry: &mut PageTableEntry, child_size: u64) -> TmkResult<()> {
+    let layout = Layout::from_size_align(PAGE_SIZE as usize, PAGE_SIZE as usize)
+        .map_err(|_| TmkError::AllocationFailed)?;
+    // SAFETY: the layout has a non-zero size. The table is never freed, it
+    // stays part of the page tables for the rest of the run.
+    let ptr = unsafe { alloc_zeroed(layout) };
+    if ptr.is_null() {
+        return Err(TmkError::AllocationFailed);
+    }
+
+    let pat = entry.addr().as_u64() & LARGE_PAGE_PAT;
+    let base = entry.addr().as_u64() & !LARGE_PAGE_PAT;
+    let mut flags = entry.flags();
+    // The children keep the PAT bit where their own format has it.
+    let child_pat = if child_size == PAGE_SIZE {
+        // In 4K entries bit 7 is the PAT bit rather than the page size bit.
+        flags.set(PageTableFlags::HUGE_PAGE, pat != 0);
+        0
+    } else {
+        pat
+    };
+    let table = table_at(PhysAddr::new(ptr as u64));
+    for (i, child) in table.iter_mut().enumerate() {
+        child.set_addr(
+            PhysAddr::new(base + i as u64 * child_size + child_pat),
+            flags,
+        );
+    }
+
+    let parent_flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | (entry.flags() & PageTableFlags::USER_ACCESSIBLE);
+    entry.set_addr(PhysAddr::new(ptr as u64), parent_flags);
+    log::debug!(
+        "split large page at {:#x} into {:#x} byte pages",
+        base,
+        child_size
+    );
+    Ok(())
+}
+
+fn with_page_tables_writable<R>(f: impl FnOnce() -> R) -> R {
+    let cr0 = Cr0::read();
+    // SAFETY: clearing WP only lets supervisor code write read-only pages,
+    // needed when the firmware maps its page tables read-only.
+    unsafe { Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT) };
+    let r = f();
+    // SAFETY: restoring the original CR0 value.
+    unsafe { Cr0::write(cr0) };
+    r
+}
+
+/// Walk to the leaf entry of the 4K page containing `addr`, splitting large
+/// pages on the way, apply `widen` to the flags of each upper level entry
+/// and `edit` to the leaf entry.
+fn edit_leaf<R>(
+    addr: u64,
+    widen: impl Fn(&mut PageTableFlags),
+    edit: impl FnOnce(&mut PageTableEntry) -> R,
+) -> TmkResult<R> {
+    let addr = VirtAddr::try_new(addr).map_err(|_| TmkError::InvalidParameter)?;
+    let (pml4, _) = Cr3::read();
+
+    let r = with_page_tables_writable(|| {
+        let mut table = table_at(pml4.start_address());
+        // Each level with the page size a split large page is replaced by.
+        let levels: [(PageTableIndex, u64); 3] = [
+            (addr.p4_index(), 0),
+            (addr.p3_index(), 1 << 21),
+            (addr.p2_index(), PAGE_SIZE),
+        ];
+        for (index, child_size) in levels {
+            let entry = &mut table[index];
+            if !entry.flags().contains(PageTableFlags::PRESENT) {
+                log::error!("address {:#x} is not mapped", addr.as_u64());
+                return Err(TmkError::InvalidParameter);
+            }
+            if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                split_large_page(entry, child_size)?;
+            }
+            let mut flags = entry.flags();
+            widen(&mut flags);
+            entry.set_flags(flags);
+            table = table_at(entry.addr());
+        }
+
+        let entry = &mut table[addr.p1_index()];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            log::error!("address {:#x} is not mapped", addr.as_u64());
+            return Err(TmkError::InvalidParameter);
+        }
+        Ok(edit(entry))
+    })?;
+
+    x86_64::instructions::tlb::flush_all();
+    Ok(r)
+}
+
+/// Change the access attributes of the 4K page containing `addr` and return
+/// the previous ones.
+///
+/// Upper level entries are widened as needed so that the leaf entry alone
+/// decides the access, which only relaxes protections of other pages up to
+/// what their own leaf entries allow.
+pub fn set_page_access(addr: u64, access: PageAccess) -> TmkResult<PageAccess> {
+    edit_leaf(
+        addr,
+        |flags| {
+            if access.user {
+                *flags |= PageTableFlags::USER_ACCESSIBLE;
+            }
+            if access.executable {
+                flags.remove(PageTableFlags::NO_EXECUTE);
+            }
+        },
+        |entry| {
+            let mut flags = entry.flags();
+            let previous = PageAccess {
+                user: flags.contains(PageTableFlags::USER_ACCESSIBLE),
+                executable: !flags.contains(PageTableFlags::NO_EXECUTE),
+            };
+            flags.set(PageTableFlags::USER_ACCESSIBLE, access.user);
+            flags.set(PageTableFlags::NO_EXECUTE, !access.executable);
+            entry.set_flags(flags);
+            previous
+        },
+    )
+}
+
+/// Make the 4K page containing `addr` writable or read-only and return
+/// whether it was writable before.
+///
+/// Upper level entries are widened as in [`set_page_access`].
+pub fn set_page_writable(addr: u64, writable: bool) -> TmkResult<bool> {
+    edit_leaf(
+        addr,
+        |flags| {
+            if writable {
+                *flags |= PageTableFlags::WRITABLE;
+            }
+        },
+        |entry| {
+            let mut flags = entry.flags();
+            let previous = flags.contains(PageTableFlags::WRITABLE);
+            flags.set(PageTableFlags::WRITABLE, writable);
+            entry.set_flags(flags);
+            previous
+        },
+    )
+}
+
+/// Select PAT entry `index` for the 4K page containing `addr` and return
+/// the index it used before.
+///
+/// The index is made of the PAT, PCD and PWT bits of the leaf entry, from
+/// most to least significant. Caches are not flushed.
+pub fn set_page_pat_index(addr: u64, index: u8) -> TmkResult<u8> {
+    if index >= 8 {
+        return Err(TmkError::InvalidParameter);
+    }
+    // In 4K entries the page size bit selects the upper half of the PAT.
+    let bits = [
+        PageTableFlags::WRITE_THROUGH,
+        PageTableFlags::NO_CACHE,
+        PageTableFlags::HUGE_PAGE,
+    ];
+    edit_leaf(
+        addr,
+        |_| {},
+        |entry| {
+            let mut flags = entry.flags();
+            let mut previous = 0;
+            for (i, bit) in bits.into_iter().enumerate() {
+                previous |= (flags.contains(bit) as u8) << i;
+                flags.set(bit, index & (1 << i) != 0);
+            }
+            entry.set_flags(flags);
+            previous
+        },
+    )
+}
+
+/// Walk the page tables to the guest physical address `addr` is mapped to,
+/// without changing them.
+pub fn translate(addr: u64) -> TmkResult<u64> {
+    let virt = VirtAddr::try_new(addr).map_err(|_| TmkError::InvalidParameter)?;
+    let (pml4, _) = Cr3::read();
+    let mut table = table_at(pml4.start_address());
+    // Each level with the size of the page a large page entry maps.
+    let levels: [(PageTableIndex, u64); 4] = [
+        (virt.p4_index(), 0),
+        (virt.p3_index(), 1 << 30),
+        (virt.p2_index(), 1 << 21),
+        (virt.p1_index(), PAGE_SIZE),
+    ];
+    for (index, size) in levels {
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return Err(TmkError::InvalidParameter);
+        }
+        if size == PAGE_SIZE || (size != 0 && entry.flags().contains(PageTableFlags::HUGE_PAGE)) {
+            // Bit 12 of a large page entry is its PAT bit, not an address bit.
+            return Ok((entry.addr().as_u64() & !(size - 1)) | (addr & (size - 1)));
+        }
+        table = table_at(entry.addr());
+    }
+    unreachable!()
+}
+
+/// A 4K page of virtual addresses remapped onto another guest physical page
+/// by [`map_alias`].
+#[derive(Debug)]
+pub struct Alias {
+    va: u64,
+    gpa: u64,
+    previous_addr: PhysAddr,
+    previous_flags: PageTableFlags,
+}
+
+impl Alias {
+    /// The virtual address of the alias.
+    pub fn va(&self) -> u64 {
+        self.va
+    }
+
+    /// The guest physical page the alias maps.
+    pub fn gpa(&self) -> u64 {
+        self.gpa
+    }
+
+    /// Make the alias writable or read-only and return whether it was
+    /// writable before. The other mappings of the page are unchanged.
+    pub fn set_writable(&self, writable: bool) -> TmkResult<bool> {
+        set_page_writable(self.va, writable)
+    }
+
+    /// Map the virtual page back to what it mapped before the alias.
+    pub fn remove(self) -> TmkResult<()> {
+        edit_leaf(
+            self.va,
+            |_| {},
+            |entry| entry.set_addr(self.previous_addr, self.previous_flags),
+        )
+    }
+}
+
+/// Map the 4K page of virtual addresses at `va` onto the guest physical page
+/// `gpa`, writable or read-only, so that the memory at `gpa` is reachable
+/// through two addresses with different protections.
+///
+/// `va` must already be mapped, e.g. by a page allocated for the purpose,
+/// whose memory stays unreachable through `va` until [`Alias::remove`]. The
+/// other attributes of the alias, such as caching, are those of `va`.
+pub fn map_alias(va: u64, gpa: u64, writable: bool) -> TmkResult<Alias> {
+    if va % PAGE_SIZE != 0 || gpa % PAGE_SIZE != 0 {
+        return Err(TmkError::InvalidAlignment);
+    }
+    edit_leaf(
+        va,
+        |flags| {
+            if writable {
+                *flags |= PageTableFlags::WRITABLE;
+            }
+        },
+        |entry| {
+            let alias = Alias {
+                va,
+                gpa,
+                previous_addr: entry.addr(),
+                previous_flags: entry.flags(),
+            };
+            let mut flags = entry.flags();
+            flags.set(PageTableFlags::WRITABLE, writable);
+            entry.set_addr(PhysAddr::new(gpa), flags);
+            alias
+        },
+    )
+}
@@ -0,0 +1,28 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! x86_64 control register snapshot, for diagnostics.
+
+use alloc::vec::Vec;
+
+use x86_64::registers::control::Cr0;
+use x86_64::registers::control::Cr2;
+use x86_64::registers::control::Cr3;
+use x86_64::registers::control::Cr4;
+use x86_64::registers::model_specific::Efer;
+use x86_64::registers::rflags;
+
+/// Reads the control state of the current processor as name and value
+/// pairs.
+pub fn snapshot() -> Vec<(&'static str, u64)> {
+    let (cr3_frame, cr3_flags) = Cr3::read_raw();
+    vec![
+        ("rsp", super::stack::stack_pointer()),
+        ("rflags", rflags::read_raw()),
+        ("cr0", Cr0::read_raw()),
+        ("cr2", Cr2::read_raw()),
+        ("cr3", cr3_frame.start_address().as_u64() | cr3_flags as u64),
+        ("cr4", Cr4::read_raw()),
+        ("efer", Efer::read_raw()),
+    ]
+}
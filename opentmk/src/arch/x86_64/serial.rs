@@ -1,16 +1,56 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
-//! Serial output for debugging.
+//! Serial output for debugging, and input for harness provided data.
+//!
+//! The log and the harness use COM2 unless the harness asks otherwise in
+//! [`SERIAL_VARIABLE`]: it may name a port, or [`ACPI_SERIAL`] for the one
+//! the ACPI SPCR or DBG2 table describes, see [`acpi::find_debug_uart`].
+//! The port is then selected with [`set_config`] and the logger
+//! re-initialized on it, so that the TMK follows the serial configuration
+//! of the VM without a rebuild.
 
+use alloc::string::String;
+use alloc::vec::Vec;
 use core::fmt;
 
 use spin::Mutex;
 
 use super::io;
+use crate::devices::acpi;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// Name of the UEFI variable selecting the serial port, see
+/// [`parse_config`].
+pub const SERIAL_VARIABLE: &str = "OpenTmkSerial";
+/// Vendor GUID of [`SERIAL_VARIABLE`], shared with the scenario variable.
+pub const SERIAL_VARIABLE_VENDOR: uefi::Guid = crate::scenario::SCENARIO_VARIABLE_VENDOR;
+/// Value of [`SERIAL_VARIABLE`] selecting the port the ACPI tables
+/// describe.
+pub const ACPI_SERIAL: &str = "acpi";
+
+/// Baud rate of a divisor of 1.
+const UART_CLOCK_BAUD: u32 = 115_200;
+const REG_DATA: u16 = 0;
+const REG_DIVISOR_LOW: u16 = 0;
+const REG_INTERRUPT_ENABLE: u16 = 1;
+const REG_DIVISOR_HIGH: u16 = 1;
+const REG_FIFO_CONTROL: u16 = 2;
+const REG_LINE_CONTROL: u16 = 3;
+const REG_MODEM_CONTROL: u16 = 4;
+const REG_LINE_STATUS: u16 = 5;
+/// Line control giving access to the divisor latch.
+const LCR_DLAB: u8 = 0x80;
+/// Line control of 8 data bits, no parity and one stop bit.
+const LCR_8N1: u8 = 0x03;
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_THR_EMPTY: u8 = 0x20;
+const LSR_TRANSMITTER_EMPTY: u8 = 0x40;
 
 /// Serial port addresses.
-/// These are the standard COM ports used in x86 systems.
+/// These are the standard COM ports used in x86 systems, or the base of a
+/// 16550 compatible UART elsewhere.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum SerialPort {
     /// COM1 serial port
@@ -21,20 +61,140 @@ pub enum SerialPort {
     COM3,
     /// COM4 serial port
     COM4,
+    /// A UART at another I/O port base.
+    Io(u16),
+    /// A memory mapped UART, identity mapped at `base`, with registers
+    /// `1 << shift` bytes apart and accessed with that width.
+    Mmio {
+        /// Address of the first register.
+        base: u64,
+        /// Log2 of the register stride, 0 or 2.
+        shift: u8,
+    },
 }
 
 impl SerialPort {
-    /// Convert the SerialPort enum to its u16 representation.
-    pub fn value(self) -> u16 {
+    /// Returns the I/O port base of the port, `None` if it is memory
+    /// mapped.
+    pub fn io_base(self) -> Option<u16> {
         match self {
-            SerialPort::COM1 => 0x3F8,
-            SerialPort::COM2 => 0x2F8,
-            SerialPort::COM3 => 0x3E8,
-            SerialPort::COM4 => 0x2E8,
+            SerialPort::COM1 => Some(0x3F8),
+            SerialPort::COM2 => Some(0x2F8),
+            SerialPort::COM3 => Some(0x3E8),
+            SerialPort::COM4 => Some(0x2E8),
+            SerialPort::Io(base) => Some(base),
+            SerialPort::Mmio { .. } => None,
         }
     }
 }
 
+/// The serial port used and how to program it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SerialConfig {
+    /// The port.
+    pub port: SerialPort,
+    /// Baud rate to program, `None` to keep the firmware's.
+    pub baud: Option<u32>,
+}
+
+impl SerialConfig {
+    /// COM2 as the firmware set it up.
+    pub const DEFAULT: SerialConfig = SerialConfig {
+        port: SerialPort::COM2,
+        baud: None,
+    };
+}
+
+static CONFIG: Mutex<SerialConfig> = Mutex::new(SerialConfig::DEFAULT);
+
+/// Returns the selected serial port.
+pub fn config() -> SerialConfig {
+    *CONFIG.lock()
+}
+
+/// Selects the serial port used from now on. The logger must be
+/// re-initialized on it, see [`crate::tmk_logger::select_serial`].
+pub(crate) fn set_config(config: SerialConfig) {
+    *CONFIG.lock() = config;
+}
+
+fn number(text: &str) -> TmkResult<u64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse(),
+    }
+    .map_err(|_| TmkError::InvalidParameter)
+}
+
+/// Parses a serial configuration, `<port> [baud]`, the port being one of
+/// `com1` to `com4`, `io:<base>`, `mmio:<base>` for byte wide registers or
+/// `mmio32:<base>` for 32-bit registers.
+pub fn parse_config(text: &str) -> TmkResult<SerialConfig> {
+    let mut words = text.split_whitespace();
+    let port = match words.next().ok_or(TmkError::InvalidParameter)? {
+        "com1" => SerialPort::COM1,
+        "com2" => SerialPort::COM2,
+        "com3" => SerialPort::COM3,
+        "com4" => SerialPort::COM4,
+        word => match word.split_once(':').ok_or(TmkError::InvalidParameter)? {
+            ("io", base) => SerialPort::Io(
+                u16::try_from(number(base)?).map_err(|_| TmkError::InvalidParameter)?,
+            ),
+            ("mmio", base) => SerialPort::Mmio {
+                base: number(base)?,
+                shift: 0,
+            },
+            ("mmio32", base) => SerialPort::Mmio {
+                base: number(base)?,
+                shift: 2,
+            },
+            _ => return Err(TmkError::InvalidParameter),
+        },
+    };
+    let baud = words
+        .next()
+        .map(|baud| {
+            u32::try_from(number(baud)?)
+                .ok()
+                .filter(|&baud| baud != 0 && UART_CLOCK_BAUD % baud == 0)
+                .ok_or(TmkError::InvalidParameter)
+        })
+        .transpose()?;
+    if words.next().is_some() {
+        return Err(TmkError::InvalidParameter);
+    }
+    Ok(SerialConfig { port, baud })
+}
+
+/// Returns the configuration of `uart`, `None` if it is not a 16550
+/// compatible UART.
+pub fn config_from_acpi(uart: &acpi::DebugUart) -> Option<SerialConfig> {
+    if uart.interface != acpi::UartInterface::Ns16550 {
+        return None;
+    }
+    let port = match uart.space {
+        acpi::GAS_SPACE_SYSTEM_IO => SerialPort::Io(u16::try_from(uart.address).ok()?),
+        acpi::GAS_SPACE_SYSTEM_MEMORY => SerialPort::Mmio {
+            base: uart.address,
+            shift: if uart.bit_width == 32 { 2 } else { 0 },
+        },
+        _ => return None,
+    };
+    let port = [
+        SerialPort::COM1,
+        SerialPort::COM2,
+        SerialPort::COM3,
+        SerialPort::COM4,
+    ]
+    .into_iter()
+    .find(|com| com.io_base().is_some() && com.io_base() == port.io_base())
+    .unwrap_or(port);
+    Some(SerialConfig {
+        port,
+        baud: uart.baud,
+    })
+}
+
 /// A trait to access io ports used by the serial device.
 pub trait IoAccess {
     /// Issue an in byte instruction.
@@ -68,35 +228,167 @@ unsafe fn outb(&self, port: u16, data: u8) {
 /// A writer for the UART COM Ports.
 pub struct Serial<T: IoAccess> {
     io: T,
-    serial_port: SerialPort,
+    config: SerialConfig,
     mutex: Mutex<()>,
 }
 
+impl Serial<InstrIoAccess> {
+    /// Returns the serial port selected by [`set_config`].
+    pub fn selected() -> Self {
+        Self::with_config(config(), InstrIoAccess)
+    }
+}
+
 impl<T: IoAccess> Serial<T> {
     /// Initialize the serial port.
     pub const fn new(serial_port: SerialPort, io: T) -> Self {
+        Self::with_config(
+            SerialConfig {
+                port: serial_port,
+                baud: None,
+            },
+            io,
+        )
+    }
+
+    /// Returns the serial port of `config`, not yet programmed.
+    pub const fn with_config(config: SerialConfig, io: T) -> Self {
         Self {
             io,
-            serial_port,
+            config,
             mutex: Mutex::new(()),
         }
     }
 
+    /// Switches to the port of `config` and initializes it.
+    pub fn reconfigure(&mut self, config: SerialConfig) {
+        self.config = config;
+        self.init();
+    }
+
+    /// Reads the register `reg`.
+    ///
+    /// # Safety
+    ///
+    /// The port must be a UART, identity mapped if memory mapped.
+    unsafe fn read_reg(&self, reg: u16) -> u8 {
+        match self.config.port {
+            SerialPort::Mmio { base, shift } => {
+                let addr = base + ((reg as u64) << shift);
+                // SAFETY: guaranteed by the caller.
+                unsafe {
+                    if shift == 2 {
+                        core::ptr::read_volatile(addr as *const u32) as u8
+                    } else {
+                        core::ptr::read_volatile(addr as *const u8)
+                    }
+                }
+            }
+            port => {
+                let base = port.io_base().unwrap();
+                // SAFETY: guaranteed by the caller.
+                unsafe { self.io.inb(base + reg) }
+            }
+        }
+    }
+
+    /// Writes `data` to the register `reg`.
+    ///
+    /// # Safety
+    ///
+    /// The port must be a UART, identity mapped if memory mapped, and
+    /// `data` a value the register accepts.
+    unsafe fn write_reg(&self, reg: u16, data: u8) {
+        match self.config.port {
+            SerialPort::Mmio { base, shift } => {
+                let addr = base + ((reg as u64) << shift);
+                // SAFETY: guaranteed by the caller.
+                unsafe {
+                    if shift == 2 {
+                        core::ptr::write_volatile(addr as *mut u32, data.into());
+                    } else {
+                        core::ptr::write_volatile(addr as *mut u8, data);
+                    }
+                }
+            }
+            port => {
+                let base = port.io_base().unwrap();
+                // SAFETY: guaranteed by the caller.
+                unsafe { self.io.outb(base + reg, data) }
+            }
+        }
+    }
+
     /// Initialize the serial port.
     pub fn init(&self) {
         // SAFETY: Initializing the serial port is safe.
         unsafe {
-            self.io.outb(self.serial_port.value() + 1, 0x00); // Disable all interrupts
-            self.io.outb(self.serial_port.value() + 2, 0xC7); // Enable FIFO, clear them, with 14-byte threshold
-            self.io.outb(self.serial_port.value() + 4, 0x0F);
+            self.write_reg(REG_INTERRUPT_ENABLE, 0x00); // Disable all interrupts
+            if let Some(baud) = self.config.baud {
+                let divisor = (UART_CLOCK_BAUD / baud) as u16;
+                self.write_reg(REG_LINE_CONTROL, LCR_DLAB);
+                self.write_reg(REG_DIVISOR_LOW, divisor as u8);
+                self.write_reg(REG_DIVISOR_HIGH, (divisor >> 8) as u8);
+                self.write_reg(REG_LINE_CONTROL, LCR_8N1);
+            }
+            self.write_reg(REG_FIFO_CONTROL, 0xC7); // Enable FIFO, clear them, with 14-byte threshold
+            self.write_reg(REG_MODEM_CONTROL, 0x0F);
         }
     }
 
+    /// Read a received byte, if one is pending.
+    pub fn try_read_byte(&self) -> Option<u8> {
+        // SAFETY: Reading from the serial device is safe.
+        unsafe {
+            if self.read_reg(REG_LINE_STATUS) & LSR_DATA_READY == 0 {
+                return None;
+            }
+            Some(self.read_reg(REG_DATA))
+        }
+    }
+
+    /// Read a line, without its line ending. Gives up after `max_polls`
+    /// polls without data.
+    pub fn read_line(&self, max_polls: u64) -> TmkResult<String> {
+        let mut line = Vec::new();
+        let mut idle = 0;
+        while idle < max_polls {
+            let Some(byte) = self.try_read_byte() else {
+                idle += 1;
+                core::hint::spin_loop();
+                continue;
+            };
+            idle = 0;
+            match byte {
+                b'\r' => {}
+                b'\n' => return String::from_utf8(line).map_err(|_| TmkError::InvalidParameter),
+                byte => line.push(byte),
+            }
+        }
+        Err(TmkError::Timeout)
+    }
+
+    /// Write `bytes` as is, without translating line endings, for binary
+    /// data.
+    pub fn write_bytes(&self, bytes: &[u8]) {
+        let _guard = self.mutex.lock();
+        for &b in bytes {
+            self.write_byte(b);
+        }
+    }
+
+    /// Wait until every byte written so far has left the transmitter.
+    pub fn flush(&self) {
+        let _guard = self.mutex.lock();
+        // SAFETY: Reading the line status of the serial device is safe.
+        unsafe { while self.read_reg(REG_LINE_STATUS) & LSR_TRANSMITTER_EMPTY == 0 {} }
+    }
+
     fn write_byte(&self, b: u8) {
         // SAFETY: Reading and writing text to the serial device is safe.
         unsafe {
-            while self.io.inb(self.serial_port.value() + 5) & 0x20 == 0 {}
-            self.io.outb(self.serial_port.value(), b);
+            while self.read_reg(REG_LINE_STATUS) & LSR_THR_EMPTY == 0 {}
+            self.write_reg(REG_DATA, b);
         }
     }
 }
@@ -113,3 +405,69 @@ fn write_str(&mut self, s: &str) -> fmt::Result {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        assert_eq!(
+            parse_config("com1"),
+            Ok(SerialConfig {
+                port: SerialPort::COM1,
+                baud: None,
+            })
+        );
+        assert_eq!(
+            parse_config(" io:0x2e0 57600 "),
+            Ok(SerialConfig {
+                port: SerialPort::Io(0x2e0),
+                baud: Some(57600),
+            })
+        );
+        assert_eq!(
+            parse_config("mmio32:0xfe030000").map(|config| config.port),
+            Ok(SerialPort::Mmio {
+                base: 0xfe03_0000,
+                shift: 2,
+            })
+        );
+        assert!(parse_config("").is_err());
+        assert!(parse_config("com5").is_err());
+        assert!(parse_config("io:0x10000").is_err());
+        assert!(parse_config("com1 1000").is_err());
+        assert!(parse_config("com1 9600 8n1").is_err());
+    }
+
+    #[test]
+    fn test_config_from_acpi() {
+        let mut uart = acpi::DebugUart {
+            interface: acpi::UartInterface::Ns16550,
+            space: acpi::GAS_SPACE_SYSTEM_IO,
+            bit_width: 8,
+            address: 0x3f8,
+            baud: Some(115200),
+        };
+        assert_eq!(
+            config_from_acpi(&uart),
+            Some(SerialConfig {
+                port: SerialPort::COM1,
+                baud: Some(115200),
+            })
+        );
+
+        uart.space = acpi::GAS_SPACE_SYSTEM_MEMORY;
+        uart.bit_width = 32;
+        assert_eq!(
+            config_from_acpi(&uart).map(|config| config.port),
+            Some(SerialPort::Mmio {
+                base: 0x3f8,
+                shift: 2,
+            })
+        );
+
+        uart.interface = acpi::UartInterface::Pl011;
+        assert_eq!(config_from_acpi(&uart), None);
+    }
+}
@@ -0,0 +1,120 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Single-stepping with the trap flag.
+//!
+//! [`enable`] sets RFLAGS.TF on the current VP, so that the processor
+//! raises a #DB after every instruction. The #DB handler calls
+//! [`on_debug`], which reports the RIP of the next instruction to the step
+//! callback of the VP; stepping goes on until the callback returns false or
+//! [`disable`] is called. [`run_stepped`] steps through a closure.
+//!
+//! Delivering the #DB clears TF, so neither the handler nor the callback,
+//! nor interrupts taken while stepping, are stepped themselves. A hypercall
+//! or an intercepted instruction is one step: the next RIP reported is
+//! where the VP resumed, which lets tests check intercept resume semantics
+//! instruction by instruction. The callback runs in interrupt context and
+//! must neither allocate nor log through the allocator.
+
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use spin::Mutex;
+use x86_64::registers::rflags;
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::debug::read_dr6;
+use super::debug::write_dr6;
+use crate::platform::hyperv::ctx::HvTestCtx;
+use crate::tmkdefs::MAX_VPS;
+
+/// DR6.BS, set by a single-step trap.
+const DR6_SINGLE_STEP: u64 = 1 << 14;
+
+/// Called with the RIP of each instruction about to run; stepping stops
+/// once it returns false.
+pub type StepFn = fn(rip: u64) -> bool;
+
+struct Stepper {
+    on_step: Mutex<Option<StepFn>>,
+    steps: AtomicU64,
+}
+
+static STEPPERS: [Stepper; MAX_VPS] = [const {
+    Stepper {
+        on_step: Mutex::new(None),
+        steps: AtomicU64::new(0),
+    }
+}; MAX_VPS];
+
+fn current() -> &'static Stepper {
+    &STEPPERS[HvTestCtx::get_vp_idx() as usize]
+}
+
+/// Starts stepping the current VP, reporting each instruction to
+/// `on_step`.
+pub fn enable(on_step: StepFn) {
+    let stepper = current();
+    *stepper.on_step.lock() = Some(on_step);
+    stepper.steps.store(0, Ordering::Relaxed);
+    // SAFETY: TF only makes the processor trap after each instruction,
+    // which the #DB handler serves.
+    unsafe { rflags::write(rflags::read() | RFlags::TRAP_FLAG) };
+}
+
+/// Stops stepping the current VP and returns the number of instructions
+/// stepped.
+pub fn disable() -> u64 {
+    // SAFETY: clearing TF stops the traps. The instruction clearing it is
+    // still stepped, before the callback is removed.
+    unsafe { rflags::write(rflags::read() - RFlags::TRAP_FLAG) };
+    let stepper = current();
+    *stepper.on_step.lock() = None;
+    stepper.steps.load(Ordering::Relaxed)
+}
+
+/// Returns the number of instructions stepped on the current VP since
+/// stepping was last enabled.
+pub fn steps() -> u64 {
+    current().steps.load(Ordering::Relaxed)
+}
+
+/// Runs `f` stepped, reporting each instruction to `on_step`, and returns
+/// its result with the number of instructions stepped, which include the
+/// few enabling and disabling the trap flag.
+pub fn run_stepped<R>(on_step: StepFn, f: impl FnOnce() -> R) -> (R, u64) {
+    enable(on_step);
+    let r = f();
+    let steps = disable();
+    (r, steps)
+}
+
+/// Called by the #DB handler before any other processing. Returns true if
+/// the exception was a single-step trap, which has then been reported and
+/// the handler should return.
+pub(crate) fn on_debug(stack_frame: &mut InterruptStackFrame) -> bool {
+    let dr6 = read_dr6();
+    if dr6 & DR6_SINGLE_STEP == 0 {
+        return false;
+    }
+    // The processor never clears DR6 itself.
+    write_dr6(dr6 & !DR6_SINGLE_STEP);
+
+    let stepper = current();
+    let on_step = *stepper.on_step.lock();
+    let keep = on_step.is_some_and(|on_step| {
+        stepper.steps.fetch_add(1, Ordering::Relaxed);
+        on_step(stack_frame.instruction_pointer.as_u64())
+    });
+    if !keep {
+        // SAFETY: clearing TF in the frame only stops the traps once the
+        // stepped code resumes.
+        unsafe {
+            stack_frame.as_mut().update(|frame| {
+                frame.cpu_flags.remove(RFlags::TRAP_FLAG);
+            });
+        }
+    }
+    true
+}
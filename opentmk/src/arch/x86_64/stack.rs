@@ -0,0 +1,58 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! x86_64 alternate stack support.
+
+use core::arch::asm;
+
+extern "sysv64" fn trampoline<F: FnOnce()>(f: *mut Option<F>) {
+    // SAFETY: `run_on_stack` passes a pointer to a live `Option<F>` that
+    // nothing else accesses while the closure runs.
+    let f = unsafe { &mut *f };
+    (f.take().expect("closure already taken"))();
+}
+
+/// Run `f` with RSP pointing to the top of `stack`, then switch back.
+///
+/// `stack` must be large enough for `f` and everything it calls, including
+/// interrupt handlers that run while it is active; there is no guard page.
+pub fn run_on_stack<F: FnOnce()>(stack: &mut [u8], f: F) {
+    let mut f = Some(f);
+    let top = (stack.as_mut_ptr() as u64 + stack.len() as u64) & !0xf;
+    // SAFETY: the stack is exclusively borrowed for the duration of the call
+    // and 16-byte aligned as the ABI requires. RSP is kept in the
+    // callee-saved r12 across the call and restored before returning.
+    unsafe {
+        asm!(
+            "mov r12, rsp",
+            "mov rsp, {top}",
+            "call {trampoline}",
+            "mov rsp, r12",
+            top = in(reg) top,
+            trampoline = sym trampoline::<F>,
+            in("rdi") &mut f as *mut Option<F>,
+            out("r12") _,
+            clobber_abi("sysv64"),
+        );
+    }
+}
+
+/// Returns the current stack pointer.
+#[inline(always)]
+pub fn stack_pointer() -> u64 {
+    let rsp: u64;
+    // SAFETY: reading RSP has no side effects.
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+    rsp
+}
+
+/// Returns the frame pointer, `rbp`. Frame pointers are kept in every
+/// build, so it points to the saved frame pointer of the caller, followed
+/// by the return address.
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let fp: u64;
+    // SAFETY: reading rbp has no side effects.
+    unsafe { asm!("mov {}, rbp", out(reg) fp, options(nomem, nostack, preserves_flags)) };
+    fp
+}
@@ -0,0 +1,478 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Minimal CBOR (RFC 8949) encoding of serde values.
+//!
+//! Backs the compact log format, see [`crate::tmk_logger::LogFormat`].
+//! Values keep the shape serde_json gives them, so that records have the
+//! same schema in both formats: structs and maps become maps keyed by field
+//! name, unit variants become strings and other variants single entry maps.
+//! Maps and sequences of unknown length, as produced by `#[serde(flatten)]`,
+//! use indefinite length encoding. Only encoding is provided.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use serde::Serialize;
+use serde::ser;
+
+const UNSIGNED: u8 = 0;
+const NEGATIVE: u8 = 1;
+const BYTES: u8 = 2;
+const TEXT: u8 = 3;
+const ARRAY: u8 = 4;
+const MAP: u8 = 5;
+/// Additional information of an indefinite length item.
+const INDEFINITE: u8 = 31;
+const FALSE: u8 = 0xf4;
+const TRUE: u8 = 0xf5;
+const NULL: u8 = 0xf6;
+const FLOAT64: u8 = 0xfb;
+const BREAK: u8 = 0xff;
+
+/// The value could not be encoded, e.g. a 128-bit integer.
+#[derive(Debug)]
+pub struct Error;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("value cannot be encoded as CBOR")
+    }
+}
+
+impl ser::StdError for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(_msg: T) -> Self {
+        Error
+    }
+}
+
+/// Encodes `value` as CBOR.
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, Error> {
+    let mut encoder = Encoder { out: Vec::new() };
+    value.serialize(&mut encoder)?;
+    Ok(encoder.out)
+}
+
+struct Encoder {
+    out: Vec<u8>,
+}
+
+impl Encoder {
+    /// Writes the head of an item of type `major` with argument `value`.
+    fn head(&mut self, major: u8, value: u64) {
+        let major = major << 5;
+        if value < 24 {
+            self.out.push(major | value as u8);
+        } else if let Ok(value) = u8::try_from(value) {
+            self.out.extend_from_slice(&[major | 24, value]);
+        } else if let Ok(value) = u16::try_from(value) {
+            self.out.push(major | 25);
+            self.out.extend_from_slice(&value.to_be_bytes());
+        } else if let Ok(value) = u32::try_from(value) {
+            self.out.push(major | 26);
+            self.out.extend_from_slice(&value.to_be_bytes());
+        } else {
+            self.out.push(major | 27);
+            self.out.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+
+    fn text(&mut self, s: &str) {
+        self.head(TEXT, s.len() as u64);
+        self.out.extend_from_slice(s.as_bytes());
+    }
+
+    /// Starts an array or map of `len` items, or of indefinite length.
+    fn open(&mut self, major: u8, len: Option<usize>) -> Compound<'_> {
+        match len {
+            Some(len) => self.head(major, len as u64),
+            None => self.out.push(major << 5 | INDEFINITE),
+        }
+        Compound {
+            encoder: self,
+            indefinite: len.is_none(),
+        }
+    }
+
+    /// Starts the single entry map of a variant carrying data.
+    fn variant(&mut self, variant: &str) {
+        self.head(MAP, 1);
+        self.text(variant);
+    }
+}
+
+struct Compound<'a> {
+    encoder: &'a mut Encoder,
+    indefinite: bool,
+}
+
+impl Compound<'_> {
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut *self.encoder)
+    }
+
+    fn field<T: Serialize + ?Sized>(&mut self, key: &str, value: &T) -> Result<(), Error> {
+        self.encoder.text(key);
+        value.serialize(&mut *self.encoder)
+    }
+
+    fn close(self) -> Result<(), Error> {
+        if self.indefinite {
+            self.encoder.out.push(BREAK);
+        }
+        Ok(())
+    }
+}
+
+impl<'a> ser::Serializer for &'a mut Encoder {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Compound<'a>;
+    type SerializeTuple = Compound<'a>;
+    type SerializeTupleStruct = Compound<'a>;
+    type SerializeTupleVariant = Compound<'a>;
+    type SerializeMap = Compound<'a>;
+    type SerializeStruct = Compound<'a>;
+    type SerializeStructVariant = Compound<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        self.out.push(if v { TRUE } else { FALSE });
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), Error> {
+        if v < 0 {
+            // A negative integer n is encoded as -1 - n.
+            self.head(NEGATIVE, !v as u64);
+        } else {
+            self.head(UNSIGNED, v as u64);
+        }
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), Error> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), Error> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), Error> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), Error> {
+        self.head(UNSIGNED, v);
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), Error> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), Error> {
+        self.out.push(FLOAT64);
+        self.out.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        self.text(v.encode_utf8(&mut [0; 4]));
+        Ok(())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.text(v);
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        self.head(BYTES, v.len() as u64);
+        self.out.extend_from_slice(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        self.out.push(NULL);
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<(), Error> {
+        self.text(variant);
+        Ok(())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.variant(variant);
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Compound<'a>, Error> {
+        Ok(self.open(ARRAY, len))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Compound<'a>, Error> {
+        Ok(self.open(ARRAY, Some(len)))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Compound<'a>, Error> {
+        Ok(self.open(ARRAY, Some(len)))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Compound<'a>, Error> {
+        self.variant(variant);
+        Ok(self.open(ARRAY, Some(len)))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Compound<'a>, Error> {
+        Ok(self.open(MAP, len))
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Compound<'a>, Error> {
+        Ok(self.open(MAP, Some(len)))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Compound<'a>, Error> {
+        self.variant(variant);
+        Ok(self.open(MAP, Some(len)))
+    }
+}
+
+impl ser::SerializeSeq for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.close()
+    }
+}
+
+impl ser::SerializeTuple for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.close()
+    }
+}
+
+impl ser::SerializeTupleStruct for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.close()
+    }
+}
+
+impl ser::SerializeTupleVariant for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.close()
+    }
+}
+
+impl ser::SerializeMap for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.element(key)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.close()
+    }
+}
+
+impl ser::SerializeStruct for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.close()
+    }
+}
+
+impl ser::SerializeStructVariant for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn test_rfc8949_examples() {
+        assert_eq!(to_vec(&0u8).unwrap(), [0x00]);
+        assert_eq!(to_vec(&23u8).unwrap(), [0x17]);
+        assert_eq!(to_vec(&24u8).unwrap(), [0x18, 0x18]);
+        assert_eq!(to_vec(&1000u32).unwrap(), [0x19, 0x03, 0xe8]);
+        assert_eq!(to_vec(&1000000u64).unwrap(), [0x1a, 0x00, 0x0f, 0x42, 0x40]);
+        assert_eq!(to_vec(&-1i32).unwrap(), [0x20]);
+        assert_eq!(to_vec(&-1000i64).unwrap(), [0x39, 0x03, 0xe7]);
+        assert_eq!(
+            to_vec(&1.1f64).unwrap(),
+            [0xfb, 0x3f, 0xf1, 0x99, 0x99, 0x99, 0x99, 0x99, 0x9a]
+        );
+        assert_eq!(to_vec(&None::<u8>).unwrap(), [0xf6]);
+        assert_eq!(to_vec("IETF").unwrap(), [0x64, 0x49, 0x45, 0x54, 0x46]);
+        assert_eq!(to_vec(&[1u8, 2, 3]).unwrap(), [0x83, 0x01, 0x02, 0x03]);
+        let map = BTreeMap::from([("a", 1u8), ("b", 2)]);
+        assert_eq!(
+            to_vec(&map).unwrap(),
+            [0xa2, 0x61, 0x61, 0x01, 0x61, 0x62, 0x02]
+        );
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "snake_case")]
+    enum Kind {
+        Unit,
+        Newtype(u8),
+    }
+
+    #[derive(Serialize)]
+    struct Inner {
+        b: bool,
+    }
+
+    #[derive(Serialize)]
+    struct Record {
+        #[serde(rename = "type")]
+        record_type: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        skipped: Option<u8>,
+        kinds: (Kind, Kind),
+        #[serde(flatten)]
+        inner: Inner,
+    }
+
+    #[test]
+    fn test_record_shape() {
+        let record = Record {
+            record_type: "x",
+            skipped: None,
+            kinds: (Kind::Unit, Kind::Newtype(1)),
+            inner: Inner { b: true },
+        };
+        // Flattening makes serde serialize a map of unknown length.
+        let mut expected = vec![0xbf, 0x64];
+        expected.extend_from_slice(b"type");
+        expected.extend_from_slice(&[0x61, b'x', 0x65]);
+        expected.extend_from_slice(b"kinds");
+        expected.extend_from_slice(&[0x82, 0x64]);
+        expected.extend_from_slice(b"unit");
+        expected.extend_from_slice(&[0xa1, 0x67]);
+        expected.extend_from_slice(b"newtype");
+        expected.extend_from_slice(&[0x01, 0x61, b'b', 0xf5, 0xff]);
+        assert_eq!(to_vec(&record).unwrap(), expected);
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Delay injection for race hunting.
+//!
+//! With the `chaos` feature, [`delay`] spins for a pseudo-random number of
+//! cycles at the points VTL switches and command dequeues call it, widening
+//! the windows intermittent cross-VTL races need. The delays are derived
+//! from the seed, the VP and the number of delays that VP injected so far,
+//! so a run is reproduced by reusing the seed logged at startup, which the
+//! harness may set through the `OpenTmkChaos` UEFI variable as
+//! `seed[,max_cycles]`.
+//! Without the feature [`delay`] compiles to nothing.
+
+/// Points delays are injected at.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChaosPoint {
+    /// Before calling into the higher VTL.
+    BeforeSwitchToHigh,
+    /// After returning from the higher VTL.
+    AfterSwitchToHigh,
+    /// Before returning to the lower VTL.
+    BeforeSwitchToLow,
+    /// After the lower VTL called back in.
+    AfterSwitchToLow,
+    /// Before taking the command queue lock.
+    BeforeDequeue,
+    /// After a command was dequeued, before it runs.
+    AfterDequeue,
+}
+
+#[cfg(feature = "chaos")]
+pub use imp::*;
+
+/// Injects no delay, the `chaos` feature is disabled.
+#[cfg(not(feature = "chaos"))]
+#[inline(always)]
+pub fn delay(_vp_index: u32, _point: ChaosPoint) {}
+
+#[cfg(feature = "chaos")]
+mod imp {
+    use core::sync::atomic::AtomicU64;
+    use core::sync::atomic::Ordering;
+
+    use super::ChaosPoint;
+    use crate::tmkdefs::MAX_VPS;
+    use crate::tmkdefs::TmkError;
+    use crate::tmkdefs::TmkResult;
+
+    /// Name of the UEFI variable holding the chaos configuration.
+    pub const CHAOS_VARIABLE: &str = "OpenTmkChaos";
+    /// Vendor GUID of [`CHAOS_VARIABLE`], shared with the scenario variable.
+    pub const CHAOS_VARIABLE_VENDOR: uefi::Guid = crate::scenario::SCENARIO_VARIABLE_VENDOR;
+
+    /// Longest delay injected by default, in cycles.
+    const DEFAULT_MAX_DELAY_CYCLES: u64 = 100_000;
+
+    static SEED: AtomicU64 = AtomicU64::new(0);
+    static MAX_DELAY_CYCLES: AtomicU64 = AtomicU64::new(DEFAULT_MAX_DELAY_CYCLES);
+    static DELAYS: [AtomicU64; MAX_VPS] = [const { AtomicU64::new(0) }; MAX_VPS];
+
+    /// Parses a chaos configuration: a seed, optionally followed by a comma
+    /// and the longest delay in cycles. Both are decimal or `0x` prefixed
+    /// hexadecimal.
+    pub fn parse_config(text: &str) -> TmkResult<(u64, Option<u64>)> {
+        fn number(text: &str) -> TmkResult<u64> {
+            let text = text.trim();
+            match text.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16),
+                None => text.parse(),
+            }
+            .map_err(|_| TmkError::InvalidParameter)
+        }
+        match text.split_once(',') {
+            Some((seed, max)) => Ok((number(seed)?, Some(number(max)?))),
+            None => Ok((number(text)?, None)),
+        }
+    }
+
+    /// Sets the seed and, if given, the longest delay. Must be called
+    /// before other VPs start.
+    pub fn configure(seed: u64, max_delay_cycles: Option<u64>) {
+        SEED.store(seed, Ordering::Relaxed);
+        if let Some(max) = max_delay_cycles {
+            MAX_DELAY_CYCLES.store(max, Ordering::Relaxed);
+        }
+        for count in &DELAYS {
+            count.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Logs the configuration so that the run can be reproduced.
+    pub fn log_config() {
+        log::warn!(
+            "chaos enabled: seed {:#x}, delays up to {} cycles",
+            SEED.load(Ordering::Relaxed),
+            MAX_DELAY_CYCLES.load(Ordering::Relaxed)
+        );
+    }
+
+    /// SplitMix64 finalizer.
+    fn mix(mut x: u64) -> u64 {
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        x ^ (x >> 31)
+    }
+
+    /// Returns the length of delay `count` of VP `vp_index` at `point`.
+    pub fn delay_cycles(seed: u64, vp_index: u32, count: u64, point: ChaosPoint, max: u64) -> u64 {
+        if max == 0 {
+            return 0;
+        }
+        let x = mix(seed ^ mix((vp_index as u64) << 32 | point as u64) ^ mix(count));
+        x % max.saturating_add(1)
+    }
+
+    /// Spins for a seeded pseudo-random number of cycles.
+    pub fn delay(vp_index: u32, point: ChaosPoint) {
+        let slot = &DELAYS[vp_index as usize];
+        let count = slot.fetch_add(1, Ordering::Relaxed);
+        let cycles = delay_cycles(
+            SEED.load(Ordering::Relaxed),
+            vp_index,
+            count,
+            point,
+            MAX_DELAY_CYCLES.load(Ordering::Relaxed),
+        );
+        let start = crate::arch::cycles::read();
+        while crate::arch::cycles::read().wrapping_sub(start) < cycles {
+            core::hint::spin_loop();
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_parse_config() {
+            assert_eq!(parse_config("42").unwrap(), (42, None));
+            assert_eq!(parse_config("0x2a, 1000").unwrap(), (42, Some(1000)));
+            assert!(parse_config("seed").is_err());
+        }
+
+        #[test]
+        fn test_delay_cycles() {
+            let a = delay_cycles(1, 2, 3, ChaosPoint::AfterDequeue, 1000);
+            assert_eq!(a, delay_cycles(1, 2, 3, ChaosPoint::AfterDequeue, 1000));
+            assert!(a <= 1000);
+            assert_eq!(delay_cycles(1, 2, 3, ChaosPoint::AfterDequeue, 0), 0);
+        }
+    }
+}
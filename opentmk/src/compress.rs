@@ -0,0 +1,213 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Minimal LZ4 block compression of large log payloads.
+//!
+//! Memory dumps and large frames cross the emulated UART a byte at a time,
+//! and mostly hold zeroed or repeated data. When the harness takes
+//! compression during the format negotiation, see
+//! [`crate::tmk_logger::COMPRESSION_OPTION`], they are sent as LZ4 blocks
+//! (the block format of the LZ4 specification, without the frame format
+//! around it) tagged with the [`Compression`] used, so that any LZ4 block
+//! decoder restores them. Only compression is provided, greedy and with a
+//! small hash table: the point is to cut the transfer time, not the ratio.
+
+use alloc::vec::Vec;
+
+use serde::Serialize;
+
+/// Shortest match LZ4 encodes.
+const MIN_MATCH: usize = 4;
+/// Bytes at the end of a block that are always literals.
+const LAST_LITERALS: usize = 5;
+/// Bytes at the end of a block no match may start in.
+const MF_LIMIT: usize = 12;
+/// Furthest back a match may point.
+const MAX_OFFSET: usize = u16::MAX as usize;
+/// Log2 of the entries of the hash table of recent positions.
+const HASH_LOG: u32 = 12;
+
+/// How a payload is compressed. The tag goes in the header of compressed
+/// frames and in the records carrying compressed data.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// Sent as is.
+    None,
+    /// An LZ4 block.
+    Lz4,
+}
+
+impl Compression {
+    /// The tag of the algorithm in frame headers.
+    pub fn tag(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+        }
+    }
+}
+
+/// Compresses `input` with `compression`. Returns `None` if that would not
+/// make it smaller, in which case it is sent as is.
+pub fn compress(compression: Compression, input: &[u8]) -> Option<Vec<u8>> {
+    let compressed = match compression {
+        Compression::None => return None,
+        Compression::Lz4 => lz4_compress(input),
+    };
+    (compressed.len() < input.len()).then_some(compressed)
+}
+
+fn read_u32(input: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(input[pos..pos + 4].try_into().unwrap())
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+}
+
+/// Appends the part of a length that does not fit in its token nibble.
+fn push_length(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+/// Appends a sequence: `literals`, then the match of `len` bytes `offset`
+/// bytes back, if any.
+fn push_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let literal_len = literals.len();
+    let match_len = matched.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push(((literal_len.min(15) as u8) << 4) | match_len.min(15) as u8);
+    if literal_len >= 15 {
+        push_length(out, literal_len - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = matched {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_len >= 15 {
+            push_length(out, match_len - 15);
+        }
+    }
+}
+
+/// Compresses `input` into an LZ4 block.
+fn lz4_compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2 + 16);
+    // Positions plus one, zero for none.
+    let mut table = vec![0u32; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut pos = 0;
+    let match_limit = input.len().saturating_sub(MF_LIMIT);
+    let end_limit = input.len().saturating_sub(LAST_LITERALS);
+    while pos < match_limit {
+        let sequence = read_u32(input, pos);
+        let slot = &mut table[hash(sequence)];
+        let candidate = *slot as usize;
+        *slot = (pos + 1) as u32;
+        if let Some(candidate) = candidate.checked_sub(1)
+            && pos - candidate <= MAX_OFFSET
+            && read_u32(input, candidate) == sequence
+        {
+            let mut len = MIN_MATCH;
+            while pos + len < end_limit && input[candidate + len] == input[pos + len] {
+                len += 1;
+            }
+            push_sequence(&mut out, &input[anchor..pos], Some((pos - candidate, len)));
+            pos += len;
+            anchor = pos;
+        } else {
+            pos += 1;
+        }
+    }
+    push_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_length(input: &mut &[u8]) -> Option<usize> {
+        let mut len = 0;
+        loop {
+            let (&byte, rest) = input.split_first()?;
+            *input = rest;
+            len += byte as usize;
+            if byte != 255 {
+                return Some(len);
+            }
+        }
+    }
+
+    /// Decodes an LZ4 block, as the host does.
+    fn lz4_decompress(mut input: &[u8]) -> Option<Vec<u8>> {
+        let mut out = Vec::new();
+        loop {
+            let (&token, rest) = input.split_first()?;
+            input = rest;
+            let mut literal_len = (token >> 4) as usize;
+            if literal_len == 15 {
+                literal_len += read_length(&mut input)?;
+            }
+            out.extend_from_slice(input.get(..literal_len)?);
+            input = &input[literal_len..];
+            if input.is_empty() {
+                return Some(out);
+            }
+            let offset = u16::from_le_bytes(input.get(..2)?.try_into().unwrap()) as usize;
+            input = &input[2..];
+            if offset == 0 || offset > out.len() {
+                return None;
+            }
+            let mut match_len = (token & 0xf) as usize;
+            if match_len == 15 {
+                match_len += read_length(&mut input)?;
+            }
+            let start = out.len() - offset;
+            for i in 0..match_len + MIN_MATCH {
+                out.push(out[start + i]);
+            }
+        }
+    }
+
+    fn round_trip(input: &[u8]) -> Vec<u8> {
+        let compressed = lz4_compress(input);
+        assert_eq!(lz4_decompress(&compressed).as_deref(), Some(input));
+        compressed
+    }
+
+    #[test]
+    fn test_lz4_round_trip() {
+        round_trip(&[]);
+        round_trip(b"short");
+        round_trip(b"abcdabcdabcdabcdabcdabcd");
+
+        let mut seed = 0x2545_f491_u32;
+        let noise: Vec<u8> = (0..10_000)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                seed as u8
+            })
+            .collect();
+        round_trip(&noise);
+
+        let mut page = vec![0u8; 4096];
+        page[100..108].copy_from_slice(&0xdead_beef_u64.to_le_bytes());
+        let pages: Vec<u8> = page.iter().cycle().take(256 * 1024).copied().collect();
+        let compressed = round_trip(&pages);
+        assert!(compressed.len() < pages.len() / 100);
+    }
+
+    #[test]
+    fn test_compress() {
+        assert_eq!(compress(Compression::None, &[0; 4096]), None);
+        assert_eq!(compress(Compression::Lz4, b"0123456789abcdef"), None);
+        let compressed = compress(Compression::Lz4, &[0; 4096]).unwrap();
+        assert_eq!(lz4_decompress(&compressed), Some(vec![0; 4096]));
+    }
+}
@@ -9,10 +9,23 @@
 //!
 
 use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::marker::PhantomData;
 use core::ops::Range;
 
+use hvdef::HvMapGpaFlags;
+use hvdef::HvPartitionPrivilege;
+use hvdef::HvRegisterVsmVpSecureVtlConfig;
+use hvdef::HvVtlEntryReason;
 use hvdef::Vtl;
+use nostd_spin_channel::Channel;
+use nostd_spin_channel::Receiver;
 
+use crate::platform::hyperv::descriptor_table::DescriptorTable;
+use crate::platform::hyperv::privileges::Privilege;
+use crate::platform::hyperv::vtl_access::AccessCheck;
+use crate::tmkdefs::TmkError;
 use crate::tmkdefs::TmkResult;
 
 #[cfg(nightly)]
@@ -39,11 +52,107 @@ pub trait InterruptPlatformTrait {
     ///   fires.
     fn set_interrupt_idx(&mut self, interrupt_idx: u8, handler: fn()) -> TmkResult<()>;
 
+    /// Removes the handler of an interrupt vector set with
+    /// [`Self::set_interrupt_idx`], so that the vector is ignored again.
+    fn clear_interrupt_idx(&mut self, interrupt_idx: u8) -> TmkResult<()>;
+
     /// Finalises platform specific interrupt setup (enables the table,
     /// unmasks lines, etc.).
     fn setup_interrupt_handler(&mut self) -> TmkResult<()>;
 }
 
+/// Trait for platforms that can overlay guest physical pages with other
+/// pages.
+pub trait GpaOverlayPlatformTrait {
+    /// Overlays the pages starting at page `target_gpn` with the pages
+    /// `source_gpns`, one target page per source page.
+    fn map_gpa_pages(&mut self, target_gpn: u64, source_gpns: &[u64]) -> TmkResult<()>;
+
+    /// Removes `page_count` overlay pages starting at page `target_gpn`,
+    /// uncovering the memory that was there before.
+    fn unmap_gpa_pages(&mut self, target_gpn: u64, page_count: usize) -> TmkResult<()>;
+}
+
+/// Trait for platforms that can signal SynIC event flags.
+pub trait SynicEventPlatformTrait {
+    /// Sets event flag `flag` of `sint` on VP `vp_index` in `vtl`, without
+    /// going through a connection. Returns whether the flag was newly set.
+    fn signal_event_direct(
+        &mut self,
+        vp_index: u32,
+        vtl: Vtl,
+        sint: u8,
+        flag: u16,
+    ) -> TmkResult<bool>;
+
+    /// Sets event flag `flag` on the port of `connection_id`.
+    fn signal_event(&mut self, connection_id: u32, flag: u16) -> TmkResult<()>;
+
+    /// Posts a message of `message_type` carrying `payload` to the port of
+    /// `connection_id`.
+    fn post_message(
+        &mut self,
+        connection_id: u32,
+        message_type: u32,
+        payload: &[u8],
+    ) -> TmkResult<()>;
+
+    /// Posts a message of `message_type` to the port of `connection_id`,
+    /// claiming a zeroed payload of `payload_size` bytes even if it does
+    /// not fit a message slot, for the hypervisor to refuse.
+    fn post_message_unchecked(
+        &mut self,
+        connection_id: u32,
+        message_type: u32,
+        payload_size: u32,
+    ) -> TmkResult<()>;
+}
+
+/// Trait for platforms that implement the extended hypercall namespace.
+pub trait ExtendedHypercallPlatformTrait {
+    /// Returns the mask of extended hypercalls the host implements, as
+    /// reported by `HvExtCallQueryCapabilities`.
+    fn query_extended_capabilities(&mut self) -> TmkResult<u64>;
+}
+
+/// Trait for platforms that grant privileges to the partition.
+pub trait PrivilegePlatformTrait {
+    /// Returns the privileges of the partition, as advertised by the
+    /// hypervisor unless overridden with [`Self::set_privileges`].
+    fn get_privileges(&mut self) -> TmkResult<HvPartitionPrivilege>;
+
+    /// Overrides the privileges returned by [`Self::get_privileges`] for the
+    /// whole partition, `None` restores the advertised ones. This does not
+    /// change what the hypervisor enforces.
+    fn set_privileges(&mut self, privileges: Option<HvPartitionPrivilege>);
+
+    /// Makes a hypercall guarded by `privilege`, with arguments that leave
+    /// the partition unchanged, and returns its result.
+    fn try_privileged_hypercall(&mut self, privilege: Privilege) -> TmkResult<()>;
+}
+
+/// Trait for platforms where a higher VTL can set fine grained protections
+/// for lower VTLs and query them.
+pub trait VtlAccessPlatformTrait {
+    /// Sets the access lower VTLs have to the pages of `range`, as enforced
+    /// by `vtl`, to `flags`.
+    fn set_vtl_protection_mask(
+        &mut self,
+        range: Range<u64>,
+        vtl: Vtl,
+        flags: HvMapGpaFlags,
+    ) -> TmkResult<()>;
+
+    /// Asks the hypervisor whether the lower VTL `vtl` may access each page
+    /// of `gpns` with `access`.
+    fn check_vtl_access(
+        &mut self,
+        gpns: &[u64],
+        vtl: Vtl,
+        access: HvMapGpaFlags,
+    ) -> TmkResult<Vec<AccessCheck>>;
+}
+
 /// Trait for platforms that support reading and writing to Model Specific Registers (MSRs).
 pub trait MsrPlatformTrait {
     /// Reads the content of `msr`.
@@ -87,12 +196,41 @@ pub trait VirtualProcessorPlatformTrait<T>
     /// `VpExecToken`.
     fn queue_command_vp(&mut self, cmd: VpExecToken<T>) -> TmkResult<()>;
 
+    /// Queues `cmds` to run on `vp_index` in `vtl` back-to-back, in order.
+    /// Commands queued for the VP by others while the batch is queued or
+    /// runs execute before or after it, never between its commands.
+    fn queue_batch(
+        &mut self,
+        vp_index: u32,
+        vtl: Vtl,
+        cmds: Vec<Box<dyn FnOnce(&mut T) + Send>>,
+    ) -> TmkResult<()>;
+
     /// Synchronously executes `cmd` on its target VP.
     fn start_on_vp(&mut self, cmd: VpExecToken<T>) -> TmkResult<()>;
 
     /// Starts the target VP (if required) and executes `cmd` with a
     /// platform provided default VTL context.
     fn start_running_vp_with_default_context(&mut self, cmd: VpExecToken<T>) -> TmkResult<()>;
+
+    /// Enables or disables per-VP crash isolation. While enabled, a fault
+    /// on any VP other than the BSP parks that VP instead of ending the run.
+    fn set_crash_isolation(&mut self, enabled: bool);
+
+    /// Returns the VPs that faulted and have not been restarted yet.
+    fn get_faulted_vps(&self) -> TmkResult<Vec<u32>>;
+
+    /// Restarts a faulted VP in `vtl` with a fresh executor context,
+    /// dropping the commands still queued for it.
+    fn restart_vp(&mut self, vp_index: u32, vtl: Vtl) -> TmkResult<()>;
+
+    /// Sets the VTL the command loop of every VP returns to once its queue
+    /// is empty, `None` to stay in the VTL of the last command.
+    ///
+    /// With [`Vtl::Vtl1`], the reverse topology, VTL1 is the long-running
+    /// orchestrator on each VP and VTL0 stays idle, only entered to run
+    /// the commands queued for it.
+    fn set_idle_vtl(&mut self, vtl: Option<Vtl>) -> TmkResult<()>;
 }
 
 /// Trait for platforms that support Virtual Trust Levels (VTLs).
@@ -100,6 +238,9 @@ pub trait VtlPlatformTrait {
     /// Applies VTL protection to the supplied physical address range.
     fn apply_vtl_protection_for_memory(&mut self, range: Range<u64>, vtl: Vtl) -> TmkResult<()>;
 
+    /// Removes the VTL protection from the supplied physical address range.
+    fn remove_vtl_protection_for_memory(&mut self, range: Range<u64>, vtl: Vtl) -> TmkResult<()>;
+
     /// Enables the given `vtl` on `vp_index` with a default context.
     fn enable_vp_vtl_with_default_context(&mut self, vp_index: u32, vtl: Vtl) -> TmkResult<()>;
 
@@ -129,6 +270,90 @@ fn set_vp_register_with_vtl(
 
     /// Gets the state of a register on a VP in a specific VTL.
     fn get_vp_register_with_vtl(&mut self, register_index: u32, vtl: Vtl) -> TmkResult<u64>;
+
+    /// Sets the state of a register of the VP `vp_index` in a specific VTL.
+    /// A higher VTL can set the registers of a lower one.
+    fn set_vp_register_on_vp(
+        &mut self,
+        vp_index: u32,
+        register_index: u32,
+        value: u64,
+        vtl: Vtl,
+    ) -> TmkResult<()>;
+
+    /// Gets the state of a register of the VP `vp_index` in a specific VTL.
+    fn get_vp_register_on_vp(
+        &mut self,
+        vp_index: u32,
+        register_index: u32,
+        vtl: Vtl,
+    ) -> TmkResult<u64>;
+
+    /// Reads the descriptor table register `register_index`, GDTR or IDTR,
+    /// of the VP `vp_index` in a specific VTL.
+    fn get_vp_descriptor_table(
+        &mut self,
+        vp_index: u32,
+        register_index: u32,
+        vtl: Vtl,
+    ) -> TmkResult<DescriptorTable>;
+
+    /// Writes the descriptor table register `register_index`, GDTR or IDTR,
+    /// of the VP `vp_index` in a specific VTL. A higher VTL can set the
+    /// registers of a lower one.
+    fn set_vp_descriptor_table(
+        &mut self,
+        vp_index: u32,
+        register_index: u32,
+        table: DescriptorTable,
+        vtl: Vtl,
+    ) -> TmkResult<()>;
+
+    /// Reads the secure configuration the current VTL applies to the lower
+    /// `target_vtl` on the current VP.
+    fn get_vp_secure_config(
+        &mut self,
+        target_vtl: Vtl,
+    ) -> TmkResult<HvRegisterVsmVpSecureVtlConfig>;
+
+    /// Writes the secure configuration the current VTL applies to the lower
+    /// `target_vtl` on the current VP. Fails if the value does not read back
+    /// unchanged.
+    fn set_vp_secure_config(
+        &mut self,
+        target_vtl: Vtl,
+        config: HvRegisterVsmVpSecureVtlConfig,
+    ) -> TmkResult<()>;
+
+    /// Returns why VTL1 was last entered on `vp_index` as it resumed from
+    /// a VTL return: a VTL call, an interrupt or an intercept. Fails with
+    /// [`TmkError::NoData`] until VTL1 resumed there once.
+    fn get_vtl_entry_reason(&self, vp_index: u32) -> TmkResult<HvVtlEntryReason>;
+}
+
+/// The value returned by a command, type-erased while it crosses the
+/// command queue.
+pub type CommandOutput = Box<dyn Any + Send>;
+
+/// The receiving end of the value returned by a command queued with
+/// [`VpExecToken::command_with_result`].
+pub struct CommandResult<R> {
+    rx: Receiver<CommandOutput>,
+    _result: PhantomData<fn() -> R>,
+}
+
+impl<R: 'static> CommandResult<R> {
+    /// Waits for the command to return and returns its value.
+    ///
+    /// Fails if the command was dropped before running, e.g. by a VP
+    /// restart.
+    pub fn recv(self) -> TmkResult<R> {
+        let output = self.rx.recv().map_err(|_| TmkError::OperationFailed)?;
+        output
+            .downcast::<R>()
+            .map(|r| *r)
+            .map_err(|_| TmkError::OperationFailed)
+    }
 }
 
 /// A token that describes a command to be executed on a specific VP and VTL.
@@ -136,6 +361,7 @@ pub struct VpExecToken<T> {
     vp_index: u32,
     vtl: Vtl,
     cmd: Option<Box<dyn FnOnce(&mut T)>>,
+    returns: bool,
 }
 
 impl<T> VpExecToken<T> {
@@ -145,6 +371,7 @@ pub fn new(vp_index: u32, vtl: Vtl) -> Self {
             vp_index,
             vtl,
             cmd: None,
+            returns: false,
         }
     }
 
@@ -157,6 +384,40 @@ pub fn command(mut self, cmd: impl FnOnce(&mut T) + 'static + Send) -> Self {
         self
     }
 
+    /// Stores a closure `cmd` whose return value is delivered to the issuer
+    /// through the returned [`CommandResult`].
+    ///
+    /// When the command targets the VP of the issuer in another VTL, the
+    /// executor returns to the VTL of the issuer once `cmd` returns and no
+    /// other command is queued for it, so `cmd` must not switch VTLs
+    /// itself.
+    pub fn command_with_result<R>(
+        mut self,
+        cmd: impl FnOnce(&mut T) -> R + 'static + Send,
+    ) -> (Self, CommandResult<R>)
+    where
+        R: Send + 'static,
+    {
+        let (tx, rx) = Channel::<CommandOutput>::new().split();
+        self.cmd = Some(Box::new(move |ctx: &mut T| {
+            _ = tx.send(Box::new(cmd(ctx)));
+        }));
+        self.returns = true;
+        (
+            self,
+            CommandResult {
+                rx,
+                _result: PhantomData,
+            },
+        )
+    }
+
+    /// Returns whether the command returns a value to its issuer, see
+    /// [`Self::command_with_result`].
+    pub fn returns_result(&self) -> bool {
+        self.returns
+    }
+
     /// Extracts the tuple `(vp_index, vtl, cmd)` consuming `self`.
     pub fn get(mut self) -> (u32, Vtl, Option<Box<dyn FnOnce(&mut T)>>) {
         let cmd = self.cmd.take();
@@ -0,0 +1,327 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Lookup of the ACPI tables.
+//!
+//! The tables are reached from the ACPI 2.0 RSDP, which the firmware points
+//! at in its configuration table, through the XSDT. They are only looked up
+//! at boot, while the configuration table is at hand.
+//!
+//! Besides the lookup, the module parses the SPCR and DBG2 tables, which
+//! describe the UART meant for the console and debugging, so that the
+//! logger can find its serial port on any platform, see
+//! [`find_debug_uart`].
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const XSDT_SIGNATURE: &[u8; 4] = b"XSDT";
+const RSDP_XSDT_OFFSET: u64 = 24;
+const HEADER_LENGTH_OFFSET: u64 = 4;
+/// Size of the header common to all tables.
+pub const HEADER_SIZE: usize = 36;
+const SPCR_SIGNATURE: &[u8; 4] = b"SPCR";
+const SPCR_INTERFACE_TYPE_OFFSET: usize = 36;
+const SPCR_BASE_ADDRESS_OFFSET: usize = 40;
+const SPCR_BAUD_RATE_OFFSET: usize = 58;
+const DBG2_SIGNATURE: &[u8; 4] = b"DBG2";
+const DBG2_DEVICE_INFO_OFFSET: usize = 36;
+const DBG2_DEVICE_COUNT_OFFSET: usize = 40;
+const DEVICE_LENGTH_OFFSET: usize = 1;
+const DEVICE_ADDRESS_COUNT_OFFSET: usize = 3;
+const DEVICE_PORT_TYPE_OFFSET: usize = 12;
+const DEVICE_PORT_SUBTYPE_OFFSET: usize = 14;
+const DEVICE_BASE_ADDRESS_OFFSET: usize = 18;
+/// Port type of the serial devices of DBG2.
+const DBG2_PORT_TYPE_SERIAL: u16 = 0x8000;
+/// Size of a generic address structure.
+const GAS_SIZE: usize = 12;
+const GAS_BIT_WIDTH_OFFSET: usize = 1;
+const GAS_ADDRESS_OFFSET: usize = 4;
+/// Address space of memory mapped registers.
+pub const GAS_SPACE_SYSTEM_MEMORY: u8 = 0;
+/// Address space of I/O ports.
+pub const GAS_SPACE_SYSTEM_IO: u8 = 1;
+
+/// Reads the `N` bytes at `addr`.
+///
+/// # Safety
+///
+/// The `N` bytes at `addr` must be identity mapped and readable.
+unsafe fn read<const N: usize>(addr: u64) -> [u8; N] {
+    // SAFETY: guaranteed by the caller.
+    unsafe { (addr as *const [u8; N]).read_unaligned() }
+}
+
+/// Reads the little endian `u32` at `addr`.
+///
+/// # Safety
+///
+/// As for [`read`].
+unsafe fn read_u32(addr: u64) -> u32 {
+    // SAFETY: guaranteed by the caller.
+    u32::from_le_bytes(unsafe { read(addr) })
+}
+
+/// Reads the little endian `u64` at `addr`.
+///
+/// # Safety
+///
+/// As for [`read`].
+unsafe fn read_u64(addr: u64) -> u64 {
+    // SAFETY: guaranteed by the caller.
+    u64::from_le_bytes(unsafe { read(addr) })
+}
+
+/// Returns the little endian `u16` at `offset` of `table`, zero if the table
+/// is too short to hold it.
+pub fn u16_at(table: &[u8], offset: usize) -> u16 {
+    table
+        .get(offset..offset + 2)
+        .map_or(0, |bytes| u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Returns the little endian `u32` at `offset` of `table`, zero if the table
+/// is too short to hold it.
+pub fn u32_at(table: &[u8], offset: usize) -> u32 {
+    table
+        .get(offset..offset + 4)
+        .map_or(0, |bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Returns the little endian `u64` at `offset` of `table`, zero if the table
+/// is too short to hold it.
+pub fn u64_at(table: &[u8], offset: usize) -> u64 {
+    table
+        .get(offset..offset + 8)
+        .map_or(0, |bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Returns the table with `signature` listed in the XSDT of the RSDP at
+/// `rsdp`, header included.
+///
+/// # Safety
+///
+/// `rsdp` must be the address of the RSDP, with it and the tables it
+/// refers to identity mapped and left unchanged.
+pub unsafe fn find_table(rsdp: u64, signature: &[u8; 4]) -> Option<&'static [u8]> {
+    // SAFETY: the caller guarantees the RSDP, the XSDT and the tables it
+    // lists are mapped; each is only read past its signature once that
+    // matched.
+    unsafe {
+        if read::<8>(rsdp) != *RSDP_SIGNATURE {
+            log::warn!("no RSDP at {:#x}", rsdp);
+            return None;
+        }
+        let xsdt = read_u64(rsdp + RSDP_XSDT_OFFSET);
+        if read::<4>(xsdt) != *XSDT_SIGNATURE {
+            log::warn!("no XSDT at {:#x}", xsdt);
+            return None;
+        }
+        let length = read_u32(xsdt + HEADER_LENGTH_OFFSET) as u64;
+        let table = (HEADER_SIZE as u64..length)
+            .step_by(8)
+            .map(|offset| read_u64(xsdt + offset))
+            .find(|&table| read::<4>(table) == *signature)?;
+        let length = read_u32(table + HEADER_LENGTH_OFFSET) as usize;
+        // SAFETY: the caller guarantees the table is mapped and unchanged,
+        // and its header gives its length.
+        Some(core::slice::from_raw_parts(table as *const u8, length))
+    }
+}
+
+/// Register interface of a UART, from the serial port subtypes shared by
+/// SPCR and DBG2.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UartInterface {
+    /// A 16550 compatible UART.
+    Ns16550,
+    /// An ARM PL011 UART, or the SBSA generic UART subset of it.
+    Pl011,
+}
+
+impl UartInterface {
+    fn from_subtype(subtype: u16) -> Option<Self> {
+        match subtype {
+            // Full 16550, 16550 subset, and 16550 described by the generic
+            // address structure.
+            0x00 | 0x01 | 0x12 => Some(UartInterface::Ns16550),
+            // PL011, SBSA generic UART (32-bit and 2.x).
+            0x03 | 0x0d | 0x0e => Some(UartInterface::Pl011),
+            _ => None,
+        }
+    }
+}
+
+/// A UART described by SPCR or DBG2.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DebugUart {
+    /// The register interface.
+    pub interface: UartInterface,
+    /// The address space of the registers, [`GAS_SPACE_SYSTEM_MEMORY`] or
+    /// [`GAS_SPACE_SYSTEM_IO`].
+    pub space: u8,
+    /// The width of the registers in bits, 0 if unspecified.
+    pub bit_width: u8,
+    /// The address of the first register.
+    pub address: u64,
+    /// The baud rate the UART is set to, `None` to keep it as is.
+    pub baud: Option<u32>,
+}
+
+impl DebugUart {
+    fn new(interface: UartInterface, gas: &[u8], baud: Option<u32>) -> Option<Self> {
+        let gas = gas.get(..GAS_SIZE)?;
+        let uart = DebugUart {
+            interface,
+            space: gas[0],
+            bit_width: gas[GAS_BIT_WIDTH_OFFSET],
+            address: u64_at(gas, GAS_ADDRESS_OFFSET),
+            baud,
+        };
+        (uart.address != 0).then_some(uart)
+    }
+}
+
+/// Parses `spcr`, the SPCR table, header included.
+pub fn parse_spcr(spcr: &[u8]) -> Option<DebugUart> {
+    let interface = UartInterface::from_subtype((*spcr.get(SPCR_INTERFACE_TYPE_OFFSET)?).into())?;
+    let baud = match spcr.get(SPCR_BAUD_RATE_OFFSET)? {
+        3 => Some(9600),
+        4 => Some(19200),
+        6 => Some(57600),
+        7 => Some(115200),
+        _ => None,
+    };
+    DebugUart::new(interface, spcr.get(SPCR_BASE_ADDRESS_OFFSET..)?, baud)
+}
+
+/// Parses `dbg2`, the DBG2 table, header included, and returns its first
+/// serial device with a known interface.
+pub fn parse_dbg2(dbg2: &[u8]) -> Option<DebugUart> {
+    let mut offset = u32_at(dbg2, DBG2_DEVICE_INFO_OFFSET) as usize;
+    for _ in 0..u32_at(dbg2, DBG2_DEVICE_COUNT_OFFSET) {
+        let device = dbg2.get(offset..)?;
+        let length = u16_at(device, DEVICE_LENGTH_OFFSET) as usize;
+        let device = device.get(..length).filter(|_| length != 0)?;
+        offset += length;
+        if u16_at(device, DEVICE_PORT_TYPE_OFFSET) != DBG2_PORT_TYPE_SERIAL
+            || *device.get(DEVICE_ADDRESS_COUNT_OFFSET)? == 0
+        {
+            continue;
+        }
+        let Some(interface) =
+            UartInterface::from_subtype(u16_at(device, DEVICE_PORT_SUBTYPE_OFFSET))
+        else {
+            continue;
+        };
+        let gas = device.get(u16_at(device, DEVICE_BASE_ADDRESS_OFFSET) as usize..)?;
+        if let Some(uart) = DebugUart::new(interface, gas, None) {
+            return Some(uart);
+        }
+    }
+    None
+}
+
+/// Returns the console UART SPCR describes or, failing that, the first
+/// debug UART of DBG2, reached from the RSDP at `rsdp`.
+///
+/// # Safety
+///
+/// `rsdp` must be the address of the RSDP, with it and the tables it
+/// refers to identity mapped and left unchanged.
+pub unsafe fn find_debug_uart(rsdp: u64) -> Option<DebugUart> {
+    // SAFETY: guaranteed by the caller.
+    let spcr = unsafe { find_table(rsdp, SPCR_SIGNATURE) };
+    if let Some(uart) = spcr.and_then(parse_spcr) {
+        return Some(uart);
+    }
+    // SAFETY: guaranteed by the caller.
+    let dbg2 = unsafe { find_table(rsdp, DBG2_SIGNATURE) };
+    let uart = dbg2.and_then(parse_dbg2);
+    if uart.is_none() {
+        log::info!("neither SPCR nor DBG2 describe a known UART");
+    }
+    uart
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gas(space: u8, bit_width: u8, address: u64) -> [u8; GAS_SIZE] {
+        let mut gas = [0; GAS_SIZE];
+        gas[0] = space;
+        gas[GAS_BIT_WIDTH_OFFSET] = bit_width;
+        gas[GAS_ADDRESS_OFFSET..].copy_from_slice(&address.to_le_bytes());
+        gas
+    }
+
+    #[test]
+    fn test_parse_spcr() {
+        let mut spcr = [0u8; 80];
+        spcr[SPCR_BASE_ADDRESS_OFFSET..][..GAS_SIZE].copy_from_slice(&gas(
+            GAS_SPACE_SYSTEM_IO,
+            8,
+            0x3f8,
+        ));
+        spcr[SPCR_BAUD_RATE_OFFSET] = 7;
+        assert_eq!(
+            parse_spcr(&spcr),
+            Some(DebugUart {
+                interface: UartInterface::Ns16550,
+                space: GAS_SPACE_SYSTEM_IO,
+                bit_width: 8,
+                address: 0x3f8,
+                baud: Some(115200),
+            })
+        );
+
+        spcr[SPCR_INTERFACE_TYPE_OFFSET] = 0x03;
+        spcr[SPCR_BAUD_RATE_OFFSET] = 0;
+        let uart = parse_spcr(&spcr).unwrap();
+        assert_eq!(uart.interface, UartInterface::Pl011);
+        assert_eq!(uart.baud, None);
+
+        // A MAX311xE SPI UART.
+        spcr[SPCR_INTERFACE_TYPE_OFFSET] = 0x02;
+        assert_eq!(parse_spcr(&spcr), None);
+        assert_eq!(parse_spcr(&spcr[..40]), None);
+    }
+
+    #[test]
+    fn test_parse_dbg2() {
+        const DEVICE_SIZE: usize = 22 + GAS_SIZE + 4;
+
+        fn device(port_type: u16, subtype: u16, address: u64) -> [u8; DEVICE_SIZE] {
+            let mut device = [0u8; DEVICE_SIZE];
+            device[DEVICE_LENGTH_OFFSET..][..2]
+                .copy_from_slice(&(DEVICE_SIZE as u16).to_le_bytes());
+            device[DEVICE_ADDRESS_COUNT_OFFSET] = 1;
+            device[DEVICE_PORT_TYPE_OFFSET..][..2].copy_from_slice(&port_type.to_le_bytes());
+            device[DEVICE_PORT_SUBTYPE_OFFSET..][..2].copy_from_slice(&subtype.to_le_bytes());
+            device[DEVICE_BASE_ADDRESS_OFFSET..][..2].copy_from_slice(&22u16.to_le_bytes());
+            device[22..][..GAS_SIZE].copy_from_slice(&gas(GAS_SPACE_SYSTEM_MEMORY, 32, address));
+            device
+        }
+
+        let mut dbg2 = alloc::vec![0u8; 44];
+        dbg2[DBG2_DEVICE_INFO_OFFSET..][..4].copy_from_slice(&44u32.to_le_bytes());
+        dbg2[DBG2_DEVICE_COUNT_OFFSET..][..4].copy_from_slice(&3u32.to_le_bytes());
+        // A network device, then a serial port of an unknown kind.
+        dbg2.extend_from_slice(&device(0x8002, 0x0003, 0x1000));
+        dbg2.extend_from_slice(&device(DBG2_PORT_TYPE_SERIAL, 0x0005, 0x2000));
+        dbg2.extend_from_slice(&device(DBG2_PORT_TYPE_SERIAL, 0x0003, 0xeffe_c000));
+        assert_eq!(
+            parse_dbg2(&dbg2),
+            Some(DebugUart {
+                interface: UartInterface::Pl011,
+                space: GAS_SPACE_SYSTEM_MEMORY,
+                bit_width: 32,
+                address: 0xeffe_c000,
+                baud: None,
+            })
+        );
+
+        dbg2.truncate(44 + 2 * DEVICE_SIZE);
+        assert_eq!(parse_dbg2(&dbg2), None);
+    }
+}
@@ -0,0 +1,358 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Minimal guest side of the Dynamic Memory (DM) VMBus protocol.
+//!
+//! The client is deterministic on purpose: hot-add requests are answered
+//! according to a fixed [`HotAddPolicy`] and balloon requests are satisfied
+//! from the TMK heap, so the host side of the protocol can be exercised
+//! without a full guest OS.
+
+pub mod protocol;
+
+use alloc::alloc::alloc_zeroed;
+use alloc::alloc::dealloc;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+
+use hvdef::HV_PAGE_SIZE;
+use protocol::DmHeader;
+use protocol::PageRange;
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+use crate::devices::vmbus::Channel;
+use crate::devices::vmbus::VmbusClient;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// Ring size, in pages per direction, used for the DM channel.
+const DM_RING_PAGES: usize = 4;
+
+/// How hot-add requests are answered.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HotAddPolicy {
+    /// Accept every page of the request.
+    AcceptAll,
+    /// Accept at most the given number of pages per request.
+    AcceptUpTo(u32),
+    /// Accept no pages.
+    Reject,
+}
+
+/// A request received from the host.
+#[derive(Clone, Debug)]
+pub enum DmRequest {
+    /// The host offers new memory to the guest.
+    HotAdd {
+        /// Transaction ID to answer with.
+        trans_id: u32,
+        /// The offered range.
+        range: PageRange,
+    },
+    /// The host asks the guest to give back `num_pages` pages.
+    Balloon {
+        /// Transaction ID to answer with.
+        trans_id: u32,
+        /// Number of pages requested.
+        num_pages: u32,
+    },
+    /// The host returns previously ballooned pages.
+    Unballoon {
+        /// Transaction ID to answer with.
+        trans_id: u32,
+        /// The returned ranges.
+        ranges: Vec<PageRange>,
+    },
+    /// Any other message, identified by its type.
+    Other(u16),
+}
+
+/// A page range handed back to the host through a balloon request.
+struct BalloonedRange {
+    base: *mut u8,
+    layout: Layout,
+}
+
+/// An open DM channel.
+pub struct DmClient {
+    vmbus: VmbusClient,
+    channel: Channel,
+    version: u32,
+    trans_id: u32,
+    policy: HotAddPolicy,
+    hot_added: Vec<PageRange>,
+    ballooned: Vec<BalloonedRange>,
+}
+
+impl DmClient {
+    /// Open the DM channel offered on `vmbus`.
+    pub fn open(mut vmbus: VmbusClient, policy: HotAddPolicy) -> TmkResult<Self> {
+        let offer = vmbus
+            .find_offer(protocol::DM_INTERFACE_ID)
+            .ok_or(TmkError::NotFound)?;
+        let channel = vmbus.open_channel(&offer, DM_RING_PAGES)?;
+        Ok(DmClient {
+            vmbus,
+            channel,
+            version: 0,
+            trans_id: 0,
+            policy,
+            hot_added: Vec::new(),
+            ballooned: Vec::new(),
+        })
+    }
+
+    /// The negotiated protocol version, 0 before [`DmClient::negotiate`].
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// The ranges accepted through hot-add so far.
+    pub fn hot_added(&self) -> &[PageRange] {
+        &self.hot_added
+    }
+
+    /// Negotiate the newest protocol version supported by the host.
+    pub fn negotiate(&mut self) -> TmkResult<u32> {
+        for (i, version) in protocol::SUPPORTED_VERSIONS.into_iter().enumerate() {
+            let request = protocol::VersionRequest {
+                header: self.header::<protocol::VersionRequest>(protocol::MESSAGE_VERSION_REQUEST),
+                version,
+                is_last_attempt: (i == protocol::SUPPORTED_VERSIONS.len() - 1) as u32,
+            };
+            self.send(&request)?;
+            let response: protocol::VersionResponse =
+                self.wait_for(protocol::MESSAGE_VERSION_RESPONSE)?;
+            if response.is_accepted != 0 {
+                log::info!("dm version {:#x} accepted", version);
+                self.version = version;
+                return Ok(version);
+            }
+        }
+        log::error!("no common dm version with host");
+        Err(TmkError::FeatureUnavailable)
+    }
+
+    /// Report the guest capabilities to the host.
+    pub fn report_capabilities(
+        &mut self,
+        capabilities: protocol::Capabilities,
+        min_page_count: u64,
+        max_page_number: u64,
+    ) -> TmkResult<()> {
+        let report = protocol::CapabilitiesReport {
+            header: self
+                .header::<protocol::CapabilitiesReport>(protocol::MESSAGE_CAPABILITIES_REPORT),
+            capabilities,
+            min_page_count,
+            max_page_number,
+        };
+        self.send(&report)?;
+        let response: protocol::CapabilitiesResponse =
+            self.wait_for(protocol::MESSAGE_CAPABILITIES_RESPONSE)?;
+        if response.is_accepted == 0 {
+            log::error!("dm capabilities rejected by host");
+            return Err(TmkError::OperationDenied);
+        }
+        Ok(())
+    }
+
+    /// Report the current memory status, which the host uses to size its
+    /// requests.
+    pub fn report_status(&mut self, num_avail: u64, num_committed: u64) -> TmkResult<()> {
+        let report = protocol::StatusReport {
+            header: self.header::<protocol::StatusReport>(protocol::MESSAGE_STATUS_REPORT),
+            num_avail,
+            num_committed,
+            page_file_size: 0,
+            zero_free: 0,
+            page_file_writes: 0,
+            io_diff: 0,
+        };
+        self.send(&report)
+    }
+
+    /// Wait for the next request from the host.
+    pub fn next_request(&mut self) -> TmkResult<DmRequest> {
+        loop {
+            let packet = self.vmbus.recv(&mut self.channel)?;
+            if packet.descriptor.packet_type
+                != crate::devices::vmbus::protocol::PACKET_TYPE_DATA_IN_BAND
+            {
+                continue;
+            }
+            let data = packet.data.as_slice();
+            let (header, _) =
+                DmHeader::read_from_prefix(data).map_err(|_| TmkError::InvalidParameter)?;
+            let trans_id = header.trans_id;
+            let request = match header.message_type {
+                protocol::MESSAGE_HOT_ADD_REQUEST => {
+                    let (request, _) = protocol::HotAddRequest::read_from_prefix(data)
+                        .map_err(|_| TmkError::InvalidParameter)?;
+                    DmRequest::HotAdd {
+                        trans_id,
+                        range: request.range,
+                    }
+                }
+                protocol::MESSAGE_BALLOON_REQUEST => {
+                    let (request, _) = protocol::BalloonRequest::read_from_prefix(data)
+                        .map_err(|_| TmkError::InvalidParameter)?;
+                    DmRequest::Balloon {
+                        trans_id,
+                        num_pages: request.num_pages,
+                    }
+                }
+                protocol::MESSAGE_UNBALLOON_REQUEST => {
+                    let (request, rest) = protocol::UnballoonRequest::read_from_prefix(data)
+                        .map_err(|_| TmkError::InvalidParameter)?;
+                    let count = request.range_count as usize;
+                    if rest.len() < count * size_of::<PageRange>() {
+                        return Err(TmkError::InvalidParameter);
+                    }
+                    let ranges = rest
+                        .chunks_exact(size_of::<PageRange>())
+                        .take(count)
+                        .filter_map(|chunk| PageRange::read_from_bytes(chunk).ok())
+                        .collect();
+                    DmRequest::Unballoon { trans_id, ranges }
+                }
+                message_type => DmRequest::Other(message_type),
+            };
+            log::debug!("dm request: {:?}", request);
+            return Ok(request);
+        }
+    }
+
+    /// Answer a hot-add request according to the policy, returning the number
+    /// of pages accepted.
+    pub fn ack_hot_add(&mut self, trans_id: u32, range: PageRange) -> TmkResult<u32> {
+        let page_count = match self.policy {
+            HotAddPolicy::AcceptAll => range.page_count(),
+            HotAddPolicy::AcceptUpTo(max) => range.page_count().min(max),
+            HotAddPolicy::Reject => 0,
+        };
+        if page_count != 0 {
+            self.hot_added.push(range.with_page_count(page_count));
+        }
+        let response = protocol::HotAddResponse {
+            header: DmHeader {
+                trans_id,
+                ..self.header::<protocol::HotAddResponse>(protocol::MESSAGE_HOT_ADD_RESPONSE)
+            },
+            page_count,
+            result: protocol::HOT_ADD_RESULT_SUCCESS,
+        };
+        self.send(&response)?;
+        log::info!(
+            "acked hot-add of {} pages at {:#x}",
+            page_count,
+            range.start_page()
+        );
+        Ok(page_count)
+    }
+
+    /// Give `num_pages` pages from the heap back to the host.
+    ///
+    /// If the heap cannot provide them the response carries no ranges.
+    pub fn ack_balloon(&mut self, trans_id: u32, num_pages: u32) -> TmkResult<()> {
+        let mut ranges = Vec::new();
+        let page_size = HV_PAGE_SIZE as usize;
+        if let Ok(layout) = Layout::from_size_align(num_pages as usize * page_size, page_size) {
+            if num_pages != 0 {
+                // SAFETY: the layout has a non-zero size.
+                let base = unsafe { alloc_zeroed(layout) };
+                if !base.is_null() {
+                    ranges.push(
+                        PageRange::new()
+                            .with_start_page(base as u64 / HV_PAGE_SIZE)
+                            .with_page_count(num_pages),
+                    );
+                    self.ballooned.push(BalloonedRange { base, layout });
+                }
+            }
+        }
+
+        let response = protocol::BalloonResponse {
+            header: DmHeader {
+                trans_id,
+                size: (size_of::<protocol::BalloonResponse>()
+                    + ranges.len() * size_of::<PageRange>()) as u16,
+                message_type: protocol::MESSAGE_BALLOON_RESPONSE,
+            },
+            reserved: 0,
+            range_count: protocol::RangeCount::new().with_range_count(ranges.len() as u32),
+        };
+        let mut payload = Vec::new();
+        payload.extend_from_slice(response.as_bytes());
+        payload.extend_from_slice(ranges.as_bytes());
+        self.vmbus.send(&mut self.channel, &payload, false)?;
+        log::info!("ballooned {} ranges for {} pages", ranges.len(), num_pages);
+        Ok(())
+    }
+
+    /// Take back pages previously given to the host through a balloon
+    /// request.
+    pub fn ack_unballoon(&mut self, trans_id: u32, ranges: &[PageRange]) -> TmkResult<()> {
+        for range in ranges {
+            let base = (range.start_page() * HV_PAGE_SIZE) as *mut u8;
+            if let Some(index) = self.ballooned.iter().position(|b| b.base == base) {
+                let ballooned = self.ballooned.swap_remove(index);
+                // SAFETY: the range was allocated by `ack_balloon` with this layout.
+                unsafe { dealloc(ballooned.base, ballooned.layout) };
+            } else {
+                log::warn!("unballoon of unknown range at {:#x}", range.start_page());
+            }
+        }
+        let response = protocol::UnballoonResponse {
+            header: DmHeader {
+                trans_id,
+                ..self.header::<protocol::UnballoonResponse>(protocol::MESSAGE_UNBALLOON_RESPONSE)
+            },
+        };
+        self.send(&response)
+    }
+
+    /// Close the channel and hand back the VMBus connection.
+    pub fn close(self) -> TmkResult<VmbusClient> {
+        let mut vmbus = self.vmbus;
+        vmbus.close_channel(self.channel)?;
+        Ok(vmbus)
+    }
+
+    fn header<T>(&mut self, message_type: u16) -> DmHeader {
+        self.trans_id += 1;
+        DmHeader {
+            message_type,
+            size: size_of::<T>() as u16,
+            trans_id: self.trans_id,
+        }
+    }
+
+    fn send<T: IntoBytes + Immutable>(&mut self, message: &T) -> TmkResult<()> {
+        self.vmbus
+            .send(&mut self.channel, message.as_bytes(), false)
+            .map(|_| ())
+    }
+
+    /// Wait for a message of `message_type`, dropping everything else.
+    fn wait_for<T: FromBytes + KnownLayout + Immutable>(
+        &mut self,
+        message_type: u16,
+    ) -> TmkResult<T> {
+        loop {
+            let packet = self.vmbus.recv(&mut self.channel)?;
+            let (header, _) =
+                DmHeader::read_from_prefix(&packet.data).map_err(|_| TmkError::InvalidParameter)?;
+            if header.message_type != message_type {
+                log::debug!("ignoring dm message {}", header.message_type);
+                continue;
+            }
+            let (message, _) =
+                T::read_from_prefix(&packet.data).map_err(|_| TmkError::InvalidParameter)?;
+            return Ok(message);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Dynamic Memory (DM) VMBus protocol messages.
+//!
+//! Only the messages exchanged by a guest that reports capabilities, acks
+//! hot-add requests and services balloon requests are defined.
+
+#![expect(missing_docs)]
+
+use bitfield_struct::bitfield;
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+/// Interface ID of the Dynamic Memory VMBus device.
+pub const DM_INTERFACE_ID: uefi::Guid = uefi::guid!("525074dc-8985-46e2-8057-a307dc18a502");
+
+pub const fn make_version(major: u16, minor: u16) -> u32 {
+    ((major as u32) << 16) | (minor as u32)
+}
+
+pub const VERSION_WIN7: u32 = make_version(0, 3);
+pub const VERSION_WIN8: u32 = make_version(1, 0);
+pub const VERSION_WIN10: u32 = make_version(2, 0);
+
+/// Versions requested during negotiation, newest first.
+pub const SUPPORTED_VERSIONS: [u32; 3] = [VERSION_WIN10, VERSION_WIN8, VERSION_WIN7];
+
+pub const MESSAGE_ERROR: u16 = 0;
+pub const MESSAGE_VERSION_REQUEST: u16 = 1;
+pub const MESSAGE_VERSION_RESPONSE: u16 = 2;
+pub const MESSAGE_CAPABILITIES_REPORT: u16 = 3;
+pub const MESSAGE_CAPABILITIES_RESPONSE: u16 = 4;
+pub const MESSAGE_STATUS_REPORT: u16 = 5;
+pub const MESSAGE_BALLOON_REQUEST: u16 = 6;
+pub const MESSAGE_BALLOON_RESPONSE: u16 = 7;
+pub const MESSAGE_UNBALLOON_REQUEST: u16 = 8;
+pub const MESSAGE_UNBALLOON_RESPONSE: u16 = 9;
+pub const MESSAGE_HOT_ADD_REQUEST: u16 = 10;
+pub const MESSAGE_HOT_ADD_RESPONSE: u16 = 11;
+
+/// `HotAddResponse::result` value reporting success.
+pub const HOT_ADD_RESULT_SUCCESS: u32 = 1;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct DmHeader {
+    pub message_type: u16,
+    /// Size of the whole message, including this header.
+    pub size: u16,
+    pub trans_id: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct VersionRequest {
+    pub header: DmHeader,
+    pub version: u32,
+    pub is_last_attempt: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct VersionResponse {
+    pub header: DmHeader,
+    pub is_accepted: u8,
+}
+
+#[bitfield(u64)]
+#[derive(IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct Capabilities {
+    pub balloon: bool,
+    pub hot_add: bool,
+    /// Required alignment of hot-added ranges, as a power of two in MB.
+    #[bits(4)]
+    pub hot_add_alignment: u8,
+    #[bits(58)]
+    _reserved: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct CapabilitiesReport {
+    pub header: DmHeader,
+    pub capabilities: Capabilities,
+    pub min_page_count: u64,
+    pub max_page_number: u64,
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct CapabilitiesResponse {
+    pub header: DmHeader,
+    pub is_accepted: u8,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct StatusReport {
+    pub header: DmHeader,
+    pub num_avail: u64,
+    pub num_committed: u64,
+    pub page_file_size: u64,
+    pub zero_free: u64,
+    pub page_file_writes: u32,
+    pub io_diff: u32,
+}
+
+/// A range of guest pages.
+#[bitfield(u64)]
+#[derive(IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct PageRange {
+    #[bits(40)]
+    pub start_page: u64,
+    #[bits(24)]
+    pub page_count: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct BalloonRequest {
+    pub header: DmHeader,
+    pub num_pages: u32,
+    pub reserved: u32,
+}
+
+#[bitfield(u32)]
+#[derive(IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct RangeCount {
+    pub more_pages: bool,
+    #[bits(31)]
+    pub range_count: u32,
+}
+
+/// Followed by `range_count` page ranges.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct BalloonResponse {
+    pub header: DmHeader,
+    pub reserved: u64,
+    pub range_count: RangeCount,
+}
+
+/// Followed by `range_count` page ranges.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct UnballoonRequest {
+    pub header: DmHeader,
+    pub more_pages: u32,
+    pub range_count: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct UnballoonResponse {
+    pub header: DmHeader,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct HotAddRequest {
+    pub header: DmHeader,
+    pub range: PageRange,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct HotAddResponse {
+    pub header: DmHeader,
+    pub page_count: u32,
+    pub result: u32,
+}
@@ -0,0 +1,265 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Guest side of the heartbeat, shutdown and KVP integration components.
+//!
+//! The host drives all IC traffic: it negotiates versions and then sends
+//! requests, which the guest answers on the same transaction ID. The
+//! heartbeat and negotiation are answered automatically, a shutdown request
+//! is acknowledged and handed to the caller. KVP is only a stub that
+//! negotiates and fails every request, so the host does not wait for a
+//! daemon that will never answer.
+
+pub mod protocol;
+
+use alloc::vec::Vec;
+
+use protocol::Header;
+use protocol::PipeHeader;
+use protocol::Version;
+use zerocopy::FromBytes;
+use zerocopy::IntoBytes;
+
+use crate::devices::vmbus::Channel;
+use crate::devices::vmbus::VmbusClient;
+use crate::devices::vmbus::protocol::PACKET_TYPE_DATA_IN_BAND;
+use crate::devices::vmbus::ring::Packet;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// Ring size, in pages per direction, used for IC channels.
+const IC_RING_PAGES: usize = 2;
+/// Offset of the IC specific message in a packet.
+const MESSAGE_OFFSET: usize = size_of::<PipeHeader>() + size_of::<Header>();
+
+/// An integration component.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IcService {
+    /// Answers the host's heartbeats.
+    Heartbeat,
+    /// Accepts shutdown requests.
+    Shutdown,
+    /// Negotiates and rejects every key-value pair exchange.
+    Kvp,
+}
+
+impl IcService {
+    fn interface_id(self) -> uefi::Guid {
+        match self {
+            IcService::Heartbeat => protocol::HEARTBEAT_INTERFACE_ID,
+            IcService::Shutdown => protocol::SHUTDOWN_INTERFACE_ID,
+            IcService::Kvp => protocol::KVP_INTERFACE_ID,
+        }
+    }
+
+    fn versions(self) -> &'static [Version] {
+        match self {
+            IcService::Heartbeat => &protocol::HEARTBEAT_VERSIONS,
+            IcService::Shutdown => &protocol::SHUTDOWN_VERSIONS,
+            IcService::Kvp => &protocol::KVP_VERSIONS,
+        }
+    }
+}
+
+/// Something the host asked for that the caller has to act on.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IcEvent {
+    /// The host requested a shutdown, which has been acknowledged.
+    Shutdown {
+        /// Whether a restart rather than a power off was requested.
+        restart: bool,
+        /// Whether the host asked to force the operation.
+        force: bool,
+        /// The reason code given by the host.
+        reason_code: u32,
+    },
+}
+
+struct IcChannel {
+    service: IcService,
+    channel: Channel,
+    heartbeats: u64,
+}
+
+/// Open IC channels on one VMBus connection.
+pub struct IcClient {
+    vmbus: VmbusClient,
+    channels: Vec<IcChannel>,
+}
+
+impl IcClient {
+    /// Open a channel for each of `services`.
+    pub fn open(mut vmbus: VmbusClient, services: &[IcService]) -> TmkResult<Self> {
+        let mut channels = Vec::new();
+        for &service in services {
+            let offer = vmbus
+                .find_offer(service.interface_id())
+                .ok_or(TmkError::NotFound)?;
+            let channel = vmbus.open_channel(&offer, IC_RING_PAGES)?;
+            channels.push(IcChannel {
+                service,
+                channel,
+                heartbeats: 0,
+            });
+        }
+        Ok(IcClient { vmbus, channels })
+    }
+
+    /// Number of heartbeats answered so far.
+    pub fn heartbeats(&self) -> u64 {
+        self.channels.iter().map(|c| c.heartbeats).sum()
+    }
+
+    /// Handle the pending messages of all channels, returning the first
+    /// event for the caller.
+    pub fn poll(&mut self) -> TmkResult<Option<IcEvent>> {
+        for index in 0..self.channels.len() {
+            let ic = &mut self.channels[index];
+            while let Some(packet) = self.vmbus.try_recv(&mut ic.channel)? {
+                if let Some(event) = Self::handle(&mut self.vmbus, ic, packet)? {
+                    return Ok(Some(event));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Handle messages until the host requests a shutdown, or until
+    /// `max_polls` polls found nothing to do.
+    pub fn wait_for_shutdown(&mut self, max_polls: u64) -> TmkResult<IcEvent> {
+        for _ in 0..max_polls {
+            if let Some(event) = self.poll()? {
+                return Ok(event);
+            }
+            core::hint::spin_loop();
+        }
+        Err(TmkError::Timeout)
+    }
+
+    /// Close the channels and hand back the VMBus connection.
+    pub fn close(self) -> TmkResult<VmbusClient> {
+        let mut vmbus = self.vmbus;
+        for ic in self.channels {
+            vmbus.close_channel(ic.channel)?;
+        }
+        Ok(vmbus)
+    }
+
+    fn handle(
+        vmbus: &mut VmbusClient,
+        ic: &mut IcChannel,
+        packet: Packet,
+    ) -> TmkResult<Option<IcEvent>> {
+        if packet.descriptor.packet_type != PACKET_TYPE_DATA_IN_BAND {
+            return Ok(None);
+        }
+        let mut data = packet.data;
+        let (_, rest) =
+            PipeHeader::read_from_prefix(&data).map_err(|_| TmkError::InvalidParameter)?;
+        let (mut header, _) =
+            Header::read_from_prefix(rest).map_err(|_| TmkError::InvalidParameter)?;
+
+        let mut event = None;
+        let mut status = protocol::STATUS_SUCCESS;
+        match header.message_type {
+            protocol::MESSAGE_TYPE_NEGOTIATE => {
+                let message = negotiate(&data[MESSAGE_OFFSET..], ic.service.versions())?;
+                data.truncate(MESSAGE_OFFSET);
+                data.extend_from_slice(&message);
+                header.message_size = message.len() as u16;
+            }
+            protocol::MESSAGE_TYPE_HEARTBEAT if ic.service == IcService::Heartbeat => {
+                let message = data
+                    .get_mut(MESSAGE_OFFSET..)
+                    .ok_or(TmkError::InvalidParameter)?;
+                let (mut heartbeat, _) = protocol::HeartbeatMessage::read_from_prefix(message)
+                    .map_err(|_| TmkError::InvalidParameter)?;
+                heartbeat.sequence_number += 1;
+                message[..size_of::<protocol::HeartbeatMessage>()]
+                    .copy_from_slice(heartbeat.as_bytes());
+                ic.heartbeats += 1;
+            }
+            protocol::MESSAGE_TYPE_SHUTDOWN if ic.service == IcService::Shutdown => {
+                let (shutdown, _) = data
+                    .get(MESSAGE_OFFSET..)
+                    .and_then(|m| protocol::ShutdownMessage::read_from_prefix(m).ok())
+                    .ok_or(TmkError::InvalidParameter)?;
+                log::info!("shutdown requested: {:?}", shutdown);
+                event = Some(IcEvent::Shutdown {
+                    restart: shutdown.flags & protocol::SHUTDOWN_FLAG_RESTART != 0,
+                    force: shutdown.flags & protocol::SHUTDOWN_FLAG_FORCE != 0,
+                    reason_code: shutdown.reason_code,
+                });
+            }
+            message_type => {
+                log::debug!("failing ic message {} on {:?}", message_type, ic.service);
+                status = protocol::STATUS_FAIL;
+            }
+        }
+
+        header.status = status;
+        header.flags = protocol::FLAG_TRANSACTION | protocol::FLAG_RESPONSE;
+        data[size_of::<PipeHeader>()..MESSAGE_OFFSET].copy_from_slice(header.as_bytes());
+        let pipe = PipeHeader {
+            flags: 0,
+            message_size: (data.len() - size_of::<PipeHeader>()) as u32,
+        };
+        data[..size_of::<PipeHeader>()].copy_from_slice(pipe.as_bytes());
+        vmbus.reply(&mut ic.channel, packet.descriptor.transaction_id, &data)?;
+        Ok(event)
+    }
+}
+
+/// Build the response to a negotiate message: the newest framework and
+/// message versions offered by the host that are also in `versions`, or no
+/// versions at all if there is none.
+fn negotiate(message: &[u8], versions: &[Version]) -> TmkResult<Vec<u8>> {
+    let (request, rest) = protocol::NegotiateMessage::read_from_prefix(message)
+        .map_err(|_| TmkError::InvalidParameter)?;
+    let framework_count = request.framework_version_count as usize;
+    let message_count = request.message_version_count as usize;
+    let offered = rest
+        .chunks_exact(size_of::<Version>())
+        .take(framework_count + message_count)
+        .filter_map(|v| Version::read_from_bytes(v).ok())
+        .collect::<Vec<_>>();
+    if offered.len() != framework_count + message_count {
+        return Err(TmkError::InvalidParameter);
+    }
+    let (framework_offered, message_offered) = offered.split_at(framework_count);
+
+    let newest = |offered: &[Version], supported: &[Version]| {
+        supported.iter().copied().find(|v| offered.contains(v))
+    };
+    let framework = newest(framework_offered, &protocol::FRAMEWORK_VERSIONS);
+    let message = newest(message_offered, versions);
+
+    let mut response = Vec::new();
+    match (framework, message) {
+        (Some(framework), Some(message)) => {
+            log::info!(
+                "ic negotiated framework {:?}, message {:?}",
+                framework,
+                message
+            );
+            let header = protocol::NegotiateMessage {
+                framework_version_count: 1,
+                message_version_count: 1,
+                reserved: 0,
+            };
+            response.extend_from_slice(header.as_bytes());
+            response.extend_from_slice(framework.as_bytes());
+            response.extend_from_slice(message.as_bytes());
+        }
+        _ => {
+            log::error!("no common ic version with host");
+            let header = protocol::NegotiateMessage {
+                framework_version_count: 0,
+                message_version_count: 0,
+                reserved: 0,
+            };
+            response.extend_from_slice(header.as_bytes());
+        }
+    }
+    Ok(response)
+}
@@ -0,0 +1,112 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Integration component (IC) VMBus protocol messages.
+//!
+//! All ICs share the framing: a pipe header, an IC message header, then the
+//! message of the IC. Only what the heartbeat, shutdown and KVP ICs need is
+//! defined.
+
+#![expect(missing_docs)]
+
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+/// Interface ID of the heartbeat IC.
+pub const HEARTBEAT_INTERFACE_ID: uefi::Guid = uefi::guid!("57164f39-9115-4e78-ab55-382f3bd5422d");
+/// Interface ID of the shutdown IC.
+pub const SHUTDOWN_INTERFACE_ID: uefi::Guid = uefi::guid!("0e0b6031-5213-4934-818b-38d90ced39db");
+/// Interface ID of the key-value pair exchange IC.
+pub const KVP_INTERFACE_ID: uefi::Guid = uefi::guid!("a9a0f4e7-5a45-4d96-b827-8a841e8c03e6");
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct Version {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl Version {
+    pub const fn new(major: u16, minor: u16) -> Self {
+        Self { major, minor }
+    }
+}
+
+/// Framework versions accepted during negotiation, newest first.
+pub const FRAMEWORK_VERSIONS: [Version; 2] = [Version::new(3, 0), Version::new(1, 0)];
+pub const HEARTBEAT_VERSIONS: [Version; 2] = [Version::new(3, 0), Version::new(1, 0)];
+pub const SHUTDOWN_VERSIONS: [Version; 4] = [
+    Version::new(3, 2),
+    Version::new(3, 1),
+    Version::new(3, 0),
+    Version::new(1, 0),
+];
+pub const KVP_VERSIONS: [Version; 3] = [Version::new(5, 0), Version::new(4, 0), Version::new(3, 0)];
+
+pub const MESSAGE_TYPE_NEGOTIATE: u16 = 0;
+pub const MESSAGE_TYPE_HEARTBEAT: u16 = 1;
+pub const MESSAGE_TYPE_KVP_EXCHANGE: u16 = 2;
+pub const MESSAGE_TYPE_SHUTDOWN: u16 = 3;
+
+pub const FLAG_TRANSACTION: u8 = 1;
+pub const FLAG_REQUEST: u8 = 2;
+pub const FLAG_RESPONSE: u8 = 4;
+
+pub const STATUS_SUCCESS: u32 = 0;
+/// HRESULT E_FAIL.
+pub const STATUS_FAIL: u32 = 0x80004005;
+
+/// `ShutdownMessage::flags` values requesting a restart rather than a
+/// power off.
+pub const SHUTDOWN_FLAG_RESTART: u32 = 2;
+/// `ShutdownMessage::flags` value forcing the operation.
+pub const SHUTDOWN_FLAG_FORCE: u32 = 1;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct PipeHeader {
+    pub flags: u32,
+    pub message_size: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct Header {
+    pub framework_version: Version,
+    pub message_type: u16,
+    pub message_version: Version,
+    /// Size of the message following this header.
+    pub message_size: u16,
+    pub status: u32,
+    pub transaction_id: u8,
+    pub flags: u8,
+    pub reserved: [u8; 2],
+}
+
+/// Followed by `framework_version_count` framework versions and
+/// `message_version_count` message versions.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct NegotiateMessage {
+    pub framework_version_count: u16,
+    pub message_version_count: u16,
+    pub reserved: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct HeartbeatMessage {
+    pub sequence_number: u64,
+    pub reserved: [u32; 8],
+}
+
+/// Followed by a UTF-16 message to display.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct ShutdownMessage {
+    pub reason_code: u32,
+    pub timeout_seconds: u32,
+    pub flags: u32,
+}
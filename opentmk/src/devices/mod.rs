@@ -3,4 +3,25 @@
 
 //! Device modules for OpenTMK.
 //! This module includes implementations for various virtual devices used in OpenTMK.
+pub mod acpi;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod dynamic_memory;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod ic;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod netvsc;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod pit;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod pm_timer;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod storvsc;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod synthhid;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod synthvid;
 pub mod tpm;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod vmbus;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod vpci;
@@ -0,0 +1,452 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Minimal guest side of the synthetic network (netvsc) VMBus protocol.
+//!
+//! The client brings up the NVSP channel with its receive and send buffers
+//! and exchanges RNDIS control messages over it, which is enough to check
+//! the control path of the synthetic NIC without a network stack. Data
+//! packets are not supported.
+
+pub mod protocol;
+
+use alloc::alloc::alloc_zeroed;
+use alloc::alloc::dealloc;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+
+use hvdef::HV_PAGE_SIZE;
+use protocol::RndisHeader;
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+use crate::devices::vmbus::Channel;
+use crate::devices::vmbus::GpadlBuffer;
+use crate::devices::vmbus::VmbusClient;
+use crate::devices::vmbus::protocol::PACKET_TYPE_COMPLETION;
+use crate::devices::vmbus::protocol::PACKET_TYPE_DATA_USING_TRANSFER_PAGES;
+use crate::devices::vmbus::protocol::TransferPageHeader;
+use crate::devices::vmbus::protocol::TransferPageRange;
+use crate::devices::vmbus::ring::Packet;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// Ring size, in pages per direction, used for the network channel.
+const NETVSC_RING_PAGES: usize = 16;
+/// Size of the receive buffer, in pages.
+const RECEIVE_BUFFER_PAGES: usize = 128;
+/// Size of the send buffer, in pages.
+const SEND_BUFFER_PAGES: usize = 16;
+/// IDs the buffers are registered with.
+const RECEIVE_BUFFER_ID: u16 = 0xcafe;
+const SEND_BUFFER_ID: u16 = 0;
+/// MTU reported to the host, including the Ethernet header.
+const MTU: u32 = 1514;
+/// Largest RNDIS message the guest accepts.
+const RNDIS_MAX_TRANSFER_SIZE: u32 = 0x4000;
+
+/// The result of RNDIS initialization.
+#[derive(Copy, Clone, Debug)]
+pub struct RndisDevice {
+    /// The RNDIS version of the device.
+    pub major_version: u32,
+    /// The RNDIS minor version of the device.
+    pub minor_version: u32,
+    /// The medium of the device, 0 for 802.3.
+    pub medium: u32,
+    /// Largest message the device accepts.
+    pub max_transfer_size: u32,
+}
+
+/// An open synthetic network channel.
+pub struct NetvscClient {
+    vmbus: VmbusClient,
+    channel: Channel,
+    version: u32,
+    receive_buffer: Option<GpadlBuffer>,
+    send_buffer: Option<GpadlBuffer>,
+    next_request_id: u32,
+}
+
+impl NetvscClient {
+    /// Open the first synthetic network channel offered on `vmbus`.
+    pub fn open(mut vmbus: VmbusClient) -> TmkResult<Self> {
+        let offer = vmbus
+            .find_offer(protocol::NETVSC_INTERFACE_ID)
+            .ok_or(TmkError::NotFound)?;
+        let channel = vmbus.open_channel(&offer, NETVSC_RING_PAGES)?;
+        Ok(NetvscClient {
+            vmbus,
+            channel,
+            version: 0,
+            receive_buffer: None,
+            send_buffer: None,
+            next_request_id: 1,
+        })
+    }
+
+    /// The negotiated NVSP version, 0 before [`NetvscClient::initialize`].
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Negotiate the newest NVSP version supported by the host, report the
+    /// NDIS version and configuration, and register the receive and send
+    /// buffers.
+    pub fn initialize(&mut self) -> TmkResult<u32> {
+        let mut accepted = None;
+        for version in protocol::SUPPORTED_VERSIONS {
+            let request = protocol::MessageInit {
+                min_protocol_version: version,
+                max_protocol_version: version,
+            };
+            let response: protocol::MessageInitComplete = self.request(
+                protocol::MESSAGE_TYPE_INIT,
+                &request,
+                protocol::MESSAGE_TYPE_INIT_COMPLETE,
+            )?;
+            if response.status == protocol::STATUS_SUCCESS {
+                accepted = Some(version);
+                break;
+            }
+            log::debug!("nvsp version {:#x} rejected: {}", version, response.status);
+        }
+        let Some(version) = accepted else {
+            log::error!("no common nvsp version with host");
+            return Err(TmkError::FeatureUnavailable);
+        };
+        log::info!("nvsp version {:#x} accepted", version);
+        self.version = version;
+
+        if version >= protocol::VERSION_2 {
+            self.post(
+                protocol::MESSAGE2_TYPE_SEND_NDIS_CONFIG,
+                &protocol::Message2SendNdisConfig {
+                    mtu: MTU,
+                    reserved: 0,
+                    capabilities: 0,
+                },
+            )?;
+        }
+        self.post(
+            protocol::MESSAGE1_TYPE_SEND_NDIS_VERSION,
+            &protocol::Message1SendNdisVersion {
+                ndis_major_version: protocol::NDIS_MAJOR_VERSION,
+                ndis_minor_version: protocol::NDIS_MINOR_VERSION,
+            },
+        )?;
+
+        let buffer = self
+            .vmbus
+            .create_buffer(&self.channel, RECEIVE_BUFFER_PAGES)?;
+        let request = protocol::Message1SendBuffer {
+            gpadl_handle: buffer.gpadl_id,
+            id: RECEIVE_BUFFER_ID,
+            reserved: 0,
+        };
+        self.receive_buffer = Some(buffer);
+        let response: protocol::Message1SendReceiveBufferComplete = self.request(
+            protocol::MESSAGE1_TYPE_SEND_RECEIVE_BUFFER,
+            &request,
+            protocol::MESSAGE1_TYPE_SEND_RECEIVE_BUFFER_COMPLETE,
+        )?;
+        if response.status != protocol::STATUS_SUCCESS {
+            log::error!("receive buffer rejected: {}", response.status);
+            return Err(TmkError::OperationFailed);
+        }
+
+        let buffer = self.vmbus.create_buffer(&self.channel, SEND_BUFFER_PAGES)?;
+        let request = protocol::Message1SendBuffer {
+            gpadl_handle: buffer.gpadl_id,
+            id: SEND_BUFFER_ID,
+            reserved: 0,
+        };
+        self.send_buffer = Some(buffer);
+        let response: protocol::Message1SendSendBufferComplete = self.request(
+            protocol::MESSAGE1_TYPE_SEND_SEND_BUFFER,
+            &request,
+            protocol::MESSAGE1_TYPE_SEND_SEND_BUFFER_COMPLETE,
+        )?;
+        if response.status != protocol::STATUS_SUCCESS {
+            log::error!("send buffer rejected: {}", response.status);
+            return Err(TmkError::OperationFailed);
+        }
+        Ok(version)
+    }
+
+    /// Send RNDIS INITIALIZE.
+    pub fn rndis_initialize(&mut self) -> TmkResult<RndisDevice> {
+        let request = protocol::RndisInitializeRequest {
+            request_id: self.next_request_id(),
+            major_version: protocol::RNDIS_MAJOR_VERSION,
+            minor_version: protocol::RNDIS_MINOR_VERSION,
+            max_transfer_size: RNDIS_MAX_TRANSFER_SIZE,
+        };
+        let response = self.rndis_request(
+            protocol::RNDIS_INITIALIZE_MSG,
+            request.request_id,
+            request.as_bytes(),
+        )?;
+        let (complete, _) = protocol::RndisInitializeComplete::read_from_prefix(&response)
+            .map_err(|_| TmkError::InvalidParameter)?;
+        if complete.status != protocol::RNDIS_STATUS_SUCCESS {
+            log::error!("rndis initialize failed: {:#x}", complete.status);
+            return Err(TmkError::OperationFailed);
+        }
+        Ok(RndisDevice {
+            major_version: complete.major_version,
+            minor_version: complete.minor_version,
+            medium: complete.medium,
+            max_transfer_size: complete.max_transfer_size,
+        })
+    }
+
+    /// Query `oid` with RNDIS QUERY and return the information buffer.
+    pub fn query_oid(&mut self, oid: u32) -> TmkResult<Vec<u8>> {
+        let request = protocol::RndisQueryRequest {
+            request_id: self.next_request_id(),
+            oid,
+            information_buffer_length: 0,
+            information_buffer_offset: 0,
+            device_vc_handle: 0,
+        };
+        let response = self.rndis_request(
+            protocol::RNDIS_QUERY_MSG,
+            request.request_id,
+            request.as_bytes(),
+        )?;
+        let (complete, _) = protocol::RndisQueryComplete::read_from_prefix(&response)
+            .map_err(|_| TmkError::InvalidParameter)?;
+        if complete.status != protocol::RNDIS_STATUS_SUCCESS {
+            log::error!("rndis query of {:#x} failed: {:#x}", oid, complete.status);
+            return Err(TmkError::OperationFailed);
+        }
+        let start = complete.information_buffer_offset as usize;
+        let end = start + complete.information_buffer_length as usize;
+        response
+            .get(start..end)
+            .map(|info| info.to_vec())
+            .ok_or(TmkError::InvalidParameter)
+    }
+
+    /// Query the current MAC address of the NIC.
+    pub fn mac_address(&mut self) -> TmkResult<[u8; 6]> {
+        let info = self.query_oid(protocol::OID_802_3_CURRENT_ADDRESS)?;
+        info.get(..6)
+            .and_then(|mac| mac.try_into().ok())
+            .ok_or(TmkError::InvalidParameter)
+    }
+
+    /// Returns whether the host reports the link as connected.
+    pub fn link_up(&mut self) -> TmkResult<bool> {
+        let info = self.query_oid(protocol::OID_GEN_MEDIA_CONNECT_STATUS)?;
+        let (state, _) = u32::read_from_prefix(&info).map_err(|_| TmkError::InvalidParameter)?;
+        Ok(state == protocol::MEDIA_STATE_CONNECTED)
+    }
+
+    /// Revoke the buffers, close the channel and hand back the VMBus
+    /// connection.
+    pub fn close(mut self) -> TmkResult<VmbusClient> {
+        if self.receive_buffer.is_some() {
+            self.post(
+                protocol::MESSAGE1_TYPE_REVOKE_RECEIVE_BUFFER,
+                &protocol::Message1RevokeBuffer {
+                    id: RECEIVE_BUFFER_ID,
+                },
+            )?;
+        }
+        if self.send_buffer.is_some() {
+            self.post(
+                protocol::MESSAGE1_TYPE_REVOKE_SEND_BUFFER,
+                &protocol::Message1RevokeBuffer { id: SEND_BUFFER_ID },
+            )?;
+        }
+        let channel_id = self.channel.offer.channel_id;
+        let mut vmbus = self.vmbus;
+        vmbus.close_channel(self.channel)?;
+        for buffer in [self.receive_buffer, self.send_buffer]
+            .into_iter()
+            .flatten()
+        {
+            vmbus.teardown_gpadl(channel_id, buffer.gpadl_id)?;
+        }
+        Ok(vmbus)
+    }
+
+    fn next_request_id(&mut self) -> u32 {
+        let id = self.next_request_id;
+        self.next_request_id += 1;
+        id
+    }
+
+    fn message<T: IntoBytes + Immutable>(message_type: u32, body: &T) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(protocol::MESSAGE_SIZE);
+        payload.extend_from_slice(protocol::MessageHeader { message_type }.as_bytes());
+        payload.extend_from_slice(body.as_bytes());
+        payload.resize(payload.len().max(protocol::MESSAGE_SIZE), 0);
+        payload
+    }
+
+    /// Send an NVSP message that needs no response.
+    fn post<T: IntoBytes + Immutable>(&mut self, message_type: u32, body: &T) -> TmkResult<()> {
+        let payload = Self::message(message_type, body);
+        self.vmbus
+            .send(&mut self.channel, &payload, false)
+            .map(|_| ())
+    }
+
+    /// Send an NVSP message and wait for the response of type
+    /// `response_type` carried by its completion.
+    fn request<T: IntoBytes + Immutable, R: FromBytes + KnownLayout + Immutable>(
+        &mut self,
+        message_type: u32,
+        body: &T,
+        response_type: u32,
+    ) -> TmkResult<R> {
+        let payload = Self::message(message_type, body);
+        let transaction_id = self.vmbus.send(&mut self.channel, &payload, true)?;
+        loop {
+            let packet = self.vmbus.recv(&mut self.channel)?;
+            if packet.descriptor.packet_type != PACKET_TYPE_COMPLETION
+                || packet.descriptor.transaction_id != transaction_id
+            {
+                self.handle_packet(&packet)?;
+                continue;
+            }
+            let (header, rest) = protocol::MessageHeader::read_from_prefix(&packet.data)
+                .map_err(|_| TmkError::InvalidParameter)?;
+            if header.message_type != response_type {
+                log::error!(
+                    "unexpected nvsp response {} to {}",
+                    header.message_type,
+                    message_type
+                );
+                return Err(TmkError::InvalidParameter);
+            }
+            let (response, _) =
+                R::read_from_prefix(rest).map_err(|_| TmkError::InvalidParameter)?;
+            return Ok(response);
+        }
+    }
+
+    /// Send an RNDIS control message and return the body of its
+    /// completion, starting at the request ID.
+    fn rndis_request(
+        &mut self,
+        message_type: u32,
+        request_id: u32,
+        body: &[u8],
+    ) -> TmkResult<Vec<u8>> {
+        let header = RndisHeader {
+            message_type,
+            message_length: (size_of::<RndisHeader>() + body.len()) as u32,
+        };
+        let len = header.message_length as usize;
+        let page_size = HV_PAGE_SIZE as usize;
+        let layout = Layout::from_size_align(len.next_multiple_of(page_size), page_size)
+            .map_err(|_| TmkError::InvalidParameter)?;
+        // SAFETY: the layout has a non-zero size.
+        let buffer = unsafe { alloc_zeroed(layout) };
+        if buffer.is_null() {
+            return Err(TmkError::AllocationFailed);
+        }
+        // SAFETY: the buffer holds at least `len` bytes.
+        let message = unsafe { core::slice::from_raw_parts_mut(buffer, len) };
+        message[..size_of::<RndisHeader>()].copy_from_slice(header.as_bytes());
+        message[size_of::<RndisHeader>()..].copy_from_slice(body);
+
+        let nvsp = Self::message(
+            protocol::MESSAGE1_TYPE_SEND_RNDIS_PACKET,
+            &protocol::Message1SendRndisPacket {
+                channel_type: protocol::RNDIS_CHANNEL_CONTROL,
+                send_buffer_section_index: protocol::NO_SEND_BUFFER_SECTION,
+                send_buffer_section_size: 0,
+            },
+        );
+        let transaction_id =
+            self.vmbus
+                .send_gpa_direct(&mut self.channel, buffer as u64, len, &nvsp, true)?;
+
+        // The response may arrive before or after the host completes the
+        // request; the buffer is only released once both happened.
+        let mut sent = false;
+        let mut response = None;
+        while !sent || response.is_none() {
+            let packet = self.vmbus.recv(&mut self.channel)?;
+            if packet.descriptor.packet_type == PACKET_TYPE_COMPLETION
+                && packet.descriptor.transaction_id == transaction_id
+            {
+                sent = true;
+                continue;
+            }
+            for message in self.handle_packet(&packet)? {
+                let (header, rest) = RndisHeader::read_from_prefix(&message)
+                    .map_err(|_| TmkError::InvalidParameter)?;
+                if header.message_type == message_type | protocol::RNDIS_COMPLETION
+                    && u32::read_from_prefix(rest).is_ok_and(|(id, _)| id == request_id)
+                {
+                    response = Some(rest.to_vec());
+                } else {
+                    log::debug!("ignoring rndis message {:#x}", header.message_type);
+                }
+            }
+        }
+        // SAFETY: the buffer was allocated above with this layout and the
+        // host completed the request.
+        unsafe { dealloc(buffer, layout) };
+        Ok(response.unwrap())
+    }
+
+    /// Handle a packet that is not an awaited completion, returning the
+    /// RNDIS messages it carried in the receive buffer.
+    fn handle_packet(&mut self, packet: &Packet) -> TmkResult<Vec<Vec<u8>>> {
+        if packet.descriptor.packet_type != PACKET_TYPE_DATA_USING_TRANSFER_PAGES {
+            log::debug!("ignoring netvsc packet {:?}", packet.descriptor);
+            return Ok(Vec::new());
+        }
+        let receive_buffer = self.receive_buffer.as_ref().ok_or(TmkError::Inactive)?;
+        let (header, ranges) = TransferPageHeader::read_from_prefix(&packet.extension)
+            .map_err(|_| TmkError::InvalidParameter)?;
+        let ranges = ranges
+            .chunks_exact(size_of::<TransferPageRange>())
+            .take(header.range_count as usize)
+            .filter_map(|r| TransferPageRange::read_from_bytes(r).ok())
+            .collect::<Vec<_>>();
+        if ranges.len() != header.range_count as usize {
+            return Err(TmkError::InvalidParameter);
+        }
+        let mut messages = Vec::new();
+        for range in &ranges {
+            let start = range.byte_offset as usize;
+            let end = start + range.byte_count as usize;
+            if header.transfer_page_set_id != RECEIVE_BUFFER_ID || end > receive_buffer.len {
+                return Err(TmkError::InvalidParameter);
+            }
+            // SAFETY: the range lies within the receive buffer, which the
+            // host does not reuse until the packet is completed.
+            let message =
+                unsafe { core::slice::from_raw_parts(receive_buffer.base.add(start), end - start) };
+            if RndisHeader::read_from_prefix(message)
+                .is_ok_and(|(h, _)| h.message_type == protocol::RNDIS_INDICATE_STATUS_MSG)
+            {
+                log::info!("rndis status indication");
+                continue;
+            }
+            messages.push(message.to_vec());
+        }
+        let completion = Self::message(
+            protocol::MESSAGE1_TYPE_SEND_RNDIS_PACKET_COMPLETE,
+            &protocol::Message1SendRndisPacketComplete {
+                status: protocol::STATUS_SUCCESS,
+            },
+        );
+        self.vmbus.complete(
+            &mut self.channel,
+            packet.descriptor.transaction_id,
+            &completion,
+        )?;
+        Ok(messages)
+    }
+}
@@ -0,0 +1,209 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Synthetic network (netvsc) VMBus protocol messages.
+//!
+//! Covers the NVSP messages needed to bring up the channel and the RNDIS
+//! control messages carried over it, no data path.
+
+#![expect(missing_docs)]
+
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+/// Interface ID of the synthetic network VMBus device.
+pub const NETVSC_INTERFACE_ID: uefi::Guid = uefi::guid!("f8615163-df3e-46c5-913f-f2d2f965ed0e");
+
+pub const VERSION_2: u32 = 0x30002;
+pub const VERSION_4: u32 = 0x40000;
+pub const VERSION_5: u32 = 0x50000;
+pub const VERSION_6: u32 = 0x60000;
+pub const VERSION_61: u32 = 0x60001;
+
+/// Versions requested during negotiation, newest first.
+pub const SUPPORTED_VERSIONS: [u32; 5] = [VERSION_61, VERSION_6, VERSION_5, VERSION_4, VERSION_2];
+
+/// Size NVSP messages are padded to, as the host expects from guests.
+pub const MESSAGE_SIZE: usize = 40;
+
+pub const MESSAGE_TYPE_INIT: u32 = 1;
+pub const MESSAGE_TYPE_INIT_COMPLETE: u32 = 2;
+pub const MESSAGE1_TYPE_SEND_NDIS_VERSION: u32 = 100;
+pub const MESSAGE1_TYPE_SEND_RECEIVE_BUFFER: u32 = 101;
+pub const MESSAGE1_TYPE_SEND_RECEIVE_BUFFER_COMPLETE: u32 = 102;
+pub const MESSAGE1_TYPE_REVOKE_RECEIVE_BUFFER: u32 = 103;
+pub const MESSAGE1_TYPE_SEND_SEND_BUFFER: u32 = 104;
+pub const MESSAGE1_TYPE_SEND_SEND_BUFFER_COMPLETE: u32 = 105;
+pub const MESSAGE1_TYPE_REVOKE_SEND_BUFFER: u32 = 106;
+pub const MESSAGE1_TYPE_SEND_RNDIS_PACKET: u32 = 107;
+pub const MESSAGE1_TYPE_SEND_RNDIS_PACKET_COMPLETE: u32 = 108;
+pub const MESSAGE2_TYPE_SEND_NDIS_CONFIG: u32 = 125;
+
+pub const STATUS_SUCCESS: u32 = 1;
+
+pub const NDIS_MAJOR_VERSION: u32 = 6;
+pub const NDIS_MINOR_VERSION: u32 = 30;
+
+/// `Message1SendRndisPacket::channel_type` for control messages.
+pub const RNDIS_CHANNEL_CONTROL: u32 = 1;
+/// `Message1SendRndisPacket::send_buffer_section_index` when the message is
+/// not in the send buffer.
+pub const NO_SEND_BUFFER_SECTION: u32 = 0xffffffff;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct MessageHeader {
+    pub message_type: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct MessageInit {
+    pub min_protocol_version: u32,
+    pub max_protocol_version: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct MessageInitComplete {
+    pub deprecated: u32,
+    pub maximum_mdl_chain_length: u32,
+    pub status: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct Message2SendNdisConfig {
+    pub mtu: u32,
+    pub reserved: u32,
+    pub capabilities: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct Message1SendNdisVersion {
+    pub ndis_major_version: u32,
+    pub ndis_minor_version: u32,
+}
+
+/// Used for both the receive and the send buffer.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct Message1SendBuffer {
+    pub gpadl_handle: u32,
+    pub id: u16,
+    pub reserved: u16,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct Message1SendReceiveBufferComplete {
+    pub status: u32,
+    pub num_sections: u32,
+    pub offset: u32,
+    pub sub_allocation_size: u32,
+    pub num_sub_allocations: u32,
+    pub end_offset: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct Message1SendSendBufferComplete {
+    pub status: u32,
+    pub section_size: u32,
+}
+
+/// Used for both the receive and the send buffer.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct Message1RevokeBuffer {
+    pub id: u16,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct Message1SendRndisPacket {
+    pub channel_type: u32,
+    pub send_buffer_section_index: u32,
+    pub send_buffer_section_size: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct Message1SendRndisPacketComplete {
+    pub status: u32,
+}
+
+pub const RNDIS_MAJOR_VERSION: u32 = 1;
+pub const RNDIS_MINOR_VERSION: u32 = 0;
+
+pub const RNDIS_INITIALIZE_MSG: u32 = 0x00000002;
+pub const RNDIS_QUERY_MSG: u32 = 0x00000004;
+pub const RNDIS_INDICATE_STATUS_MSG: u32 = 0x00000007;
+/// Set in the message type of completions.
+pub const RNDIS_COMPLETION: u32 = 0x80000000;
+
+pub const RNDIS_STATUS_SUCCESS: u32 = 0;
+
+pub const OID_GEN_MEDIA_CONNECT_STATUS: u32 = 0x00010114;
+pub const OID_802_3_PERMANENT_ADDRESS: u32 = 0x01010101;
+pub const OID_802_3_CURRENT_ADDRESS: u32 = 0x01010102;
+
+/// `OID_GEN_MEDIA_CONNECT_STATUS` value of a connected link.
+pub const MEDIA_STATE_CONNECTED: u32 = 0;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct RndisHeader {
+    pub message_type: u32,
+    /// Size of the whole message, including this header.
+    pub message_length: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct RndisInitializeRequest {
+    pub request_id: u32,
+    pub major_version: u32,
+    pub minor_version: u32,
+    pub max_transfer_size: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct RndisInitializeComplete {
+    pub request_id: u32,
+    pub status: u32,
+    pub major_version: u32,
+    pub minor_version: u32,
+    pub device_flags: u32,
+    pub medium: u32,
+    pub max_packets_per_message: u32,
+    pub max_transfer_size: u32,
+    pub packet_alignment_factor: u32,
+    pub af_list_offset: u32,
+    pub af_list_size: u32,
+}
+
+/// The information buffer offset is relative to `request_id`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct RndisQueryRequest {
+    pub request_id: u32,
+    pub oid: u32,
+    pub information_buffer_length: u32,
+    pub information_buffer_offset: u32,
+    pub device_vc_handle: u32,
+}
+
+/// The information buffer offset is relative to `request_id`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct RndisQueryComplete {
+    pub request_id: u32,
+    pub status: u32,
+    pub information_buffer_length: u32,
+    pub information_buffer_offset: u32,
+}
@@ -0,0 +1,140 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Intel 8254 programmable interval timer.
+//!
+//! The counters are programmed through the command port and read back by
+//! latching their count or status, which keeps a read from racing with the
+//! counter decrementing. Every access is an I/O port exit handled by the
+//! VMM's legacy timer emulation.
+
+use bitfield_struct::bitfield;
+
+use crate::arch::io::inb;
+use crate::arch::io::outb;
+
+/// Input clock of the counters, in Hz.
+pub const FREQUENCY_HZ: u64 = 1_193_182;
+
+const CHANNEL_PORT_BASE: u16 = 0x40;
+const COMMAND_PORT: u16 = 0x43;
+const CHANNELS: u8 = 3;
+/// Access mode of a counter loaded and read low byte then high byte.
+const ACCESS_LOW_HIGH: u8 = 0b11;
+/// Read-back command, latching the status but not the count of the
+/// counters selected by bits 1 to 3.
+const READ_BACK_STATUS: u8 = 0b1110_0000;
+
+/// Operating mode of a counter.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Mode {
+    /// Counts down once, raising OUT at terminal count.
+    InterruptOnTerminalCount = 0,
+    /// One-shot retriggered by the gate.
+    HardwareOneShot = 1,
+    /// Pulses OUT low once per period.
+    RateGenerator = 2,
+    /// Toggles OUT every half period, counting down by two.
+    SquareWave = 3,
+    /// Pulses OUT low once at terminal count.
+    SoftwareStrobe = 4,
+    /// Strobe triggered by the gate.
+    HardwareStrobe = 5,
+}
+
+impl Mode {
+    /// How much the count decrements per input clock.
+    pub fn step(self) -> u64 {
+        match self {
+            Mode::SquareWave => 2,
+            _ => 1,
+        }
+    }
+}
+
+/// Status of a counter, as latched by a read-back command.
+#[bitfield(u8)]
+pub struct Status {
+    /// Whether the counter counts in BCD.
+    pub bcd: bool,
+    /// The operating mode.
+    #[bits(3)]
+    pub mode: u8,
+    /// How the count is accessed.
+    #[bits(2)]
+    pub access: u8,
+    /// Whether the last count written was not loaded into the counter yet.
+    pub null_count: bool,
+    /// The state of the OUT pin.
+    pub output: bool,
+}
+
+fn channel_port(channel: u8) -> u16 {
+    assert!(channel < CHANNELS, "invalid PIT channel {}", channel);
+    CHANNEL_PORT_BASE + channel as u16
+}
+
+/// Programs `channel` to count in binary in `mode`, from `reload`. A
+/// reload of zero stands for 65536.
+pub fn program(channel: u8, mode: Mode, reload: u16) {
+    let port = channel_port(channel);
+    outb(
+        COMMAND_PORT,
+        channel << 6 | ACCESS_LOW_HIGH << 4 | (mode as u8) << 1,
+    );
+    let [low, high] = reload.to_le_bytes();
+    outb(port, low);
+    outb(port, high);
+}
+
+/// Latches and reads the count of `channel`, which must have been
+/// programmed with [`program`].
+pub fn read_count(channel: u8) -> u16 {
+    let port = channel_port(channel);
+    // The counter latch command is a control word with access mode zero.
+    outb(COMMAND_PORT, channel << 6);
+    let low = inb(port);
+    let high = inb(port);
+    u16::from_le_bytes([low, high])
+}
+
+/// Latches and reads the status of `channel`.
+pub fn read_status(channel: u8) -> Status {
+    let port = channel_port(channel);
+    outb(COMMAND_PORT, READ_BACK_STATUS | 1 << (channel + 1));
+    Status::from(inb(port))
+}
+
+/// Returns the input clocks between two reads of a counter running in a
+/// periodic mode from `reload`, assuming the count went down by less than
+/// `reload` in between.
+pub fn ticks_between(previous: u16, current: u16, reload: u16, mode: Mode) -> u64 {
+    let period = if reload == 0 { 1 << 16 } else { reload as u64 };
+    let counted = (previous as u64 + period - current as u64) % period;
+    counted / mode.step()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ticks_between() {
+        assert_eq!(ticks_between(100, 40, 1000, Mode::RateGenerator), 60);
+        assert_eq!(ticks_between(40, 900, 1000, Mode::RateGenerator), 140);
+        assert_eq!(ticks_between(100, 40, 1000, Mode::SquareWave), 30);
+        assert_eq!(ticks_between(10, 65530, 0, Mode::RateGenerator), 16);
+        assert_eq!(ticks_between(5, 5, 1000, Mode::RateGenerator), 0);
+    }
+
+    #[test]
+    fn test_status() {
+        let status = Status::from(0b1011_0110);
+        assert!(status.output());
+        assert!(!status.null_count());
+        assert_eq!(status.access(), ACCESS_LOW_HIGH);
+        assert_eq!(status.mode(), Mode::SquareWave as u8);
+        assert!(!status.bcd());
+    }
+}
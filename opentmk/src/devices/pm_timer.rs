@@ -0,0 +1,120 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! ACPI power management timer.
+//!
+//! A free running counter in the PM register block at 3.579545 MHz, 24 or
+//! 32 bits wide. Its I/O port and width come from the FADT, which
+//! [`locate`] finds through [`acpi::find_table`].
+
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::Ordering;
+
+use crate::arch::io::inl;
+use crate::devices::acpi;
+
+/// Frequency of the timer, in Hz.
+pub const FREQUENCY_HZ: u64 = 3_579_545;
+
+const FADT_SIGNATURE: &[u8; 4] = b"FACP";
+const FADT_PM_TMR_BLK_OFFSET: usize = 76;
+const FADT_FLAGS_OFFSET: usize = 112;
+/// Address of the 64-bit timer block, a generic address structure.
+const FADT_X_PM_TMR_BLK_OFFSET: usize = 208;
+const GAS_ADDRESS_OFFSET: usize = 4;
+/// FADT flag of a 32-bit timer.
+const TMR_VAL_EXT: u32 = 1 << 8;
+
+/// The port of the located timer, with [`WIDE`] set if it is 32 bits
+/// wide, zero if none was located.
+static TIMER: AtomicU32 = AtomicU32::new(0);
+const WIDE: u32 = 1 << 16;
+
+/// The timer of the platform.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PmTimer {
+    /// The I/O port of the timer.
+    pub port: u16,
+    /// Whether the counter is 32 bits wide rather than 24.
+    pub wide: bool,
+}
+
+impl PmTimer {
+    /// Reads the counter.
+    pub fn read(&self) -> u32 {
+        inl(self.port) & self.mask()
+    }
+
+    fn mask(&self) -> u32 {
+        if self.wide { u32::MAX } else { 0xff_ffff }
+    }
+
+    /// Returns the ticks between two reads, assuming the counter wrapped
+    /// at most once in between.
+    pub fn ticks_between(&self, start: u32, end: u32) -> u32 {
+        end.wrapping_sub(start) & self.mask()
+    }
+}
+
+/// Returns the timer located by [`locate`], if any.
+pub fn get() -> Option<PmTimer> {
+    let timer = TIMER.load(Ordering::Relaxed);
+    (timer != 0).then_some(PmTimer {
+        port: timer as u16,
+        wide: timer & WIDE != 0,
+    })
+}
+
+/// Locates the timer through the FADT reached from the ACPI 2.0 RSDP at
+/// `rsdp`, and remembers it for [`get`].
+///
+/// # Safety
+///
+/// `rsdp` must be the address of the RSDP, with it and the tables it
+/// refers to identity mapped.
+pub unsafe fn locate(rsdp: u64) -> Option<PmTimer> {
+    // SAFETY: guaranteed by the caller.
+    let Some(fadt) = (unsafe { acpi::find_table(rsdp, FADT_SIGNATURE) }) else {
+        log::warn!("no FADT");
+        return None;
+    };
+    let mut port = acpi::u32_at(fadt, FADT_PM_TMR_BLK_OFFSET) as u64;
+    if port == 0 && fadt.get(FADT_X_PM_TMR_BLK_OFFSET) == Some(&acpi::GAS_SPACE_SYSTEM_IO) {
+        port = acpi::u64_at(fadt, FADT_X_PM_TMR_BLK_OFFSET + GAS_ADDRESS_OFFSET);
+    }
+    let port = u16::try_from(port).ok().filter(|&port| port != 0)?;
+    let timer = PmTimer {
+        port,
+        wide: acpi::u32_at(fadt, FADT_FLAGS_OFFSET) & TMR_VAL_EXT != 0,
+    };
+    log::info!(
+        "PM timer at port {:#x}, {} bits",
+        timer.port,
+        if timer.wide { 32 } else { 24 }
+    );
+    TIMER.store(
+        port as u32 | if timer.wide { WIDE } else { 0 },
+        Ordering::Relaxed,
+    );
+    Some(timer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ticks_between() {
+        let narrow = PmTimer {
+            port: 0x408,
+            wide: false,
+        };
+        assert_eq!(narrow.ticks_between(10, 30), 20);
+        assert_eq!(narrow.ticks_between(0xff_fff0, 0x10), 0x20);
+        let wide = PmTimer {
+            port: 0x408,
+            wide: true,
+        };
+        assert_eq!(wide.ticks_between(0xffff_fff0, 0x10), 0x20);
+    }
+}
@@ -0,0 +1,257 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Minimal guest side of the synthetic SCSI (storvsc) VMBus protocol.
+//!
+//! The client initializes the channel and executes one SCSI request at a
+//! time, with the data buffer described to the host by GPA direct packets.
+//! It is meant to check the storage data path end to end, not to drive a
+//! disk efficiently.
+
+pub mod protocol;
+
+use alloc::alloc::alloc_zeroed;
+use alloc::alloc::dealloc;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+
+use hvdef::HV_PAGE_SIZE;
+use protocol::PacketHeader;
+use protocol::ScsiRequest;
+use zerocopy::FromBytes;
+use zerocopy::FromZeros;
+use zerocopy::IntoBytes;
+
+use crate::devices::vmbus::Channel;
+use crate::devices::vmbus::VmbusClient;
+use crate::devices::vmbus::protocol::PACKET_TYPE_COMPLETION;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// Ring size, in pages per direction, used for the SCSI channel.
+const STORVSC_RING_PAGES: usize = 8;
+
+/// Location of a LUN on the synthetic SCSI controller.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ScsiAddress {
+    /// The SCSI path.
+    pub path_id: u8,
+    /// The SCSI target.
+    pub target_id: u8,
+    /// The logical unit.
+    pub lun: u8,
+}
+
+/// An open synthetic SCSI channel.
+pub struct StorvscClient {
+    vmbus: VmbusClient,
+    channel: Channel,
+    version: u16,
+    max_transfer_bytes: u32,
+}
+
+impl StorvscClient {
+    /// Open the first synthetic SCSI channel offered on `vmbus`.
+    pub fn open(mut vmbus: VmbusClient) -> TmkResult<Self> {
+        let offer = vmbus
+            .find_offer(protocol::SCSI_INTERFACE_ID)
+            .ok_or(TmkError::NotFound)?;
+        let channel = vmbus.open_channel(&offer, STORVSC_RING_PAGES)?;
+        Ok(StorvscClient {
+            vmbus,
+            channel,
+            version: 0,
+            max_transfer_bytes: 0,
+        })
+    }
+
+    /// The negotiated protocol version, 0 before
+    /// [`StorvscClient::initialize`].
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// Run the initialization sequence: negotiate the newest protocol
+    /// version supported by the host and query the channel properties.
+    pub fn initialize(&mut self) -> TmkResult<u16> {
+        self.execute(protocol::OPERATION_BEGIN_INITIALIZATION, &[])?;
+
+        let mut accepted = None;
+        for version in protocol::SUPPORTED_VERSIONS {
+            let request = protocol::ProtocolVersion {
+                major_minor: version,
+                reserved: 0,
+            };
+            let (status, _) = self.transact(
+                protocol::OPERATION_QUERY_PROTOCOL_VERSION,
+                request.as_bytes(),
+            )?;
+            if status == protocol::STATUS_SUCCESS {
+                accepted = Some(version);
+                break;
+            }
+            log::debug!("storvsc version {:#x} rejected: {:#x}", version, status);
+        }
+        let Some(version) = accepted else {
+            log::error!("no common storvsc version with host");
+            return Err(TmkError::FeatureUnavailable);
+        };
+        log::info!("storvsc version {:#x} accepted", version);
+        self.version = version;
+
+        let data = self.execute(protocol::OPERATION_QUERY_PROPERTIES, &[])?;
+        let (properties, _) = protocol::ChannelProperties::read_from_prefix(&data)
+            .map_err(|_| TmkError::InvalidParameter)?;
+        self.max_transfer_bytes = properties.max_transfer_bytes;
+
+        self.execute(protocol::OPERATION_END_INITIALIZATION, &[])?;
+        Ok(version)
+    }
+
+    /// Send a standard INQUIRY to `address`.
+    pub fn inquiry(&mut self, address: ScsiAddress) -> TmkResult<protocol::InquiryData> {
+        let len = size_of::<protocol::InquiryData>();
+        let cdb = [protocol::SCSI_OP_INQUIRY, 0, 0, 0, len as u8, 0];
+        let data = self.execute_srb(address, &cdb, len)?;
+        let (inquiry, _) = protocol::InquiryData::read_from_prefix(&data)
+            .map_err(|_| TmkError::InvalidParameter)?;
+        Ok(inquiry)
+    }
+
+    /// Read `block_count` blocks of `block_size` bytes starting at `lba`
+    /// with READ(16).
+    pub fn read16(
+        &mut self,
+        address: ScsiAddress,
+        lba: u64,
+        block_count: u32,
+        block_size: usize,
+    ) -> TmkResult<Vec<u8>> {
+        let len = block_count as usize * block_size;
+        if self.max_transfer_bytes != 0 && len > self.max_transfer_bytes as usize {
+            return Err(TmkError::InvalidParameter);
+        }
+        let mut cdb = [0u8; 16];
+        cdb[0] = protocol::SCSI_OP_READ16;
+        cdb[2..10].copy_from_slice(&lba.to_be_bytes());
+        cdb[10..14].copy_from_slice(&block_count.to_be_bytes());
+        self.execute_srb(address, &cdb, len)
+    }
+
+    /// Close the channel and hand back the VMBus connection.
+    pub fn close(self) -> TmkResult<VmbusClient> {
+        let mut vmbus = self.vmbus;
+        vmbus.close_channel(self.channel)?;
+        Ok(vmbus)
+    }
+
+    /// Execute `cdb` reading `len` bytes from the device into a page aligned
+    /// bounce buffer.
+    fn execute_srb(&mut self, address: ScsiAddress, cdb: &[u8], len: usize) -> TmkResult<Vec<u8>> {
+        if cdb.len() > protocol::CDB_SIZE || len == 0 {
+            return Err(TmkError::InvalidParameter);
+        }
+        let mut request = ScsiRequest {
+            length: size_of::<ScsiRequest>() as u16,
+            path_id: address.path_id,
+            target_id: address.target_id,
+            lun: address.lun,
+            cdb_length: cdb.len() as u8,
+            sense_info_ex_length: protocol::SENSE_BUFFER_SIZE as u8,
+            data_in: protocol::DATA_IN,
+            data_transfer_length: len as u32,
+            ..FromZeros::new_zeroed()
+        };
+        request.payload[..cdb.len()].copy_from_slice(cdb);
+
+        let page_size = HV_PAGE_SIZE as usize;
+        let layout = Layout::from_size_align(len.next_multiple_of(page_size), page_size)
+            .map_err(|_| TmkError::InvalidParameter)?;
+        // SAFETY: the layout has a non-zero size.
+        let buffer = unsafe { alloc_zeroed(layout) };
+        if buffer.is_null() {
+            return Err(TmkError::AllocationFailed);
+        }
+
+        let mut payload = Vec::new();
+        payload.extend_from_slice(self.header(protocol::OPERATION_EXECUTE_SRB).as_bytes());
+        payload.extend_from_slice(request.as_bytes());
+        let completion = self
+            .vmbus
+            .send_gpa_direct(&mut self.channel, buffer as u64, len, &payload, true)
+            .and_then(|transaction_id| self.wait_completion(transaction_id));
+        // Without a completion the host may still own the buffer, so it is
+        // leaked.
+        let (status, data) = completion?;
+        // SAFETY: the buffer holds `len` bytes and the host completed the
+        // request, so it no longer accesses it.
+        let mut buffer_data = unsafe { core::slice::from_raw_parts(buffer, len) }.to_vec();
+        // SAFETY: the buffer was allocated above with this layout.
+        unsafe { dealloc(buffer, layout) };
+
+        if status != protocol::STATUS_SUCCESS {
+            log::error!("storvsc srb {:#x} failed: {:#x}", cdb[0], status);
+            return Err(TmkError::OperationFailed);
+        }
+        let (response, _) =
+            ScsiRequest::read_from_prefix(&data).map_err(|_| TmkError::InvalidParameter)?;
+        if response.srb_status & protocol::SRB_STATUS_MASK != protocol::SRB_STATUS_SUCCESS
+            || response.scsi_status != 0
+        {
+            log::error!(
+                "scsi command {:#x} failed: srb {:#x} scsi {:#x} sense {:x?}",
+                cdb[0],
+                response.srb_status,
+                response.scsi_status,
+                response.payload
+            );
+            return Err(TmkError::OperationFailed);
+        }
+        buffer_data.truncate(response.data_transfer_length as usize);
+        Ok(buffer_data)
+    }
+
+    /// Run a control operation, failing unless the host reports success.
+    fn execute(&mut self, operation: u32, payload: &[u8]) -> TmkResult<Vec<u8>> {
+        let (status, data) = self.transact(operation, payload)?;
+        if status != protocol::STATUS_SUCCESS {
+            log::error!("storvsc operation {} failed: {:#x}", operation, status);
+            return Err(TmkError::OperationFailed);
+        }
+        Ok(data)
+    }
+
+    /// Send an in-band operation and return the status and payload of its
+    /// completion.
+    fn transact(&mut self, operation: u32, payload: &[u8]) -> TmkResult<(u32, Vec<u8>)> {
+        let mut packet = Vec::new();
+        packet.extend_from_slice(self.header(operation).as_bytes());
+        packet.extend_from_slice(payload);
+        let transaction_id = self.vmbus.send(&mut self.channel, &packet, true)?;
+        self.wait_completion(transaction_id)
+    }
+
+    fn header(&self, operation: u32) -> PacketHeader {
+        PacketHeader {
+            operation,
+            flags: protocol::FLAG_REQUEST_COMPLETED,
+            status: 0,
+        }
+    }
+
+    /// Wait for the completion of `transaction_id`, dropping everything else.
+    fn wait_completion(&mut self, transaction_id: u64) -> TmkResult<(u32, Vec<u8>)> {
+        loop {
+            let packet = self.vmbus.recv(&mut self.channel)?;
+            if packet.descriptor.packet_type != PACKET_TYPE_COMPLETION
+                || packet.descriptor.transaction_id != transaction_id
+            {
+                log::debug!("ignoring storvsc packet {:?}", packet.descriptor);
+                continue;
+            }
+            let (header, rest) = PacketHeader::read_from_prefix(&packet.data)
+                .map_err(|_| TmkError::InvalidParameter)?;
+            return Ok((header.status, rest.to_vec()));
+        }
+    }
+}
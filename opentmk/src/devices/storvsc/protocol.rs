@@ -0,0 +1,120 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Synthetic SCSI (storvsc) VMBus protocol messages.
+//!
+//! Only the messages needed to initialize the channel and execute SCSI
+//! requests against a single LUN are defined.
+
+#![expect(missing_docs)]
+
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+/// Interface ID of the synthetic SCSI controller VMBus device.
+pub const SCSI_INTERFACE_ID: uefi::Guid = uefi::guid!("ba6163d9-04a1-4d29-b605-72e2ffb1dc7f");
+
+pub const VERSION_WIN8: u16 = 0x0501;
+pub const VERSION_BLUE: u16 = 0x0600;
+pub const VERSION_THRESHOLD: u16 = 0x0602;
+
+/// Versions requested during negotiation, newest first. All of them use the
+/// full size [`ScsiRequest`].
+pub const SUPPORTED_VERSIONS: [u16; 3] = [VERSION_THRESHOLD, VERSION_BLUE, VERSION_WIN8];
+
+pub const OPERATION_COMPLETE_IO: u32 = 1;
+pub const OPERATION_EXECUTE_SRB: u32 = 3;
+pub const OPERATION_BEGIN_INITIALIZATION: u32 = 7;
+pub const OPERATION_END_INITIALIZATION: u32 = 8;
+pub const OPERATION_QUERY_PROTOCOL_VERSION: u32 = 9;
+pub const OPERATION_QUERY_PROPERTIES: u32 = 10;
+pub const OPERATION_ENUMERATE_BUS: u32 = 11;
+
+pub const FLAG_REQUEST_COMPLETED: u32 = 1;
+
+pub const STATUS_SUCCESS: u32 = 0;
+/// NTSTATUS returned for a rejected protocol version.
+pub const STATUS_REVISION_MISMATCH: u32 = 0xc0000059;
+
+pub const SRB_STATUS_SUCCESS: u8 = 0x01;
+/// Mask of the status bits in `ScsiRequest::srb_status`.
+pub const SRB_STATUS_MASK: u8 = 0x3f;
+
+/// `ScsiRequest::data_in` value for transfers from the device.
+pub const DATA_IN: u8 = 1;
+
+pub const CDB_SIZE: usize = 16;
+pub const SENSE_BUFFER_SIZE: usize = 0x14;
+
+pub const SCSI_OP_INQUIRY: u8 = 0x12;
+pub const SCSI_OP_READ16: u8 = 0x88;
+
+/// Header of every storvsc packet.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct PacketHeader {
+    pub operation: u32,
+    pub flags: u32,
+    pub status: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct ProtocolVersion {
+    pub major_minor: u16,
+    pub reserved: u16,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct ChannelProperties {
+    pub reserved: u32,
+    pub maximum_sub_channel_count: u16,
+    pub reserved2: u16,
+    pub flags: u32,
+    pub max_transfer_bytes: u32,
+    pub reserved3: [u32; 2],
+}
+
+/// A SCSI request block, in its Windows 8 and later layout.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct ScsiRequest {
+    pub length: u16,
+    pub srb_status: u8,
+    pub scsi_status: u8,
+    pub port_number: u8,
+    pub path_id: u8,
+    pub target_id: u8,
+    pub lun: u8,
+    pub cdb_length: u8,
+    pub sense_info_ex_length: u8,
+    pub data_in: u8,
+    pub properties: u8,
+    pub data_transfer_length: u32,
+    /// The CDB on the way in, the sense data on the way out.
+    pub payload: [u8; SENSE_BUFFER_SIZE],
+    pub reserve: u16,
+    pub queue_tag: u8,
+    pub queue_action: u8,
+    pub srb_flags: u32,
+    pub time_out_value: u32,
+    pub queue_sort_key: u32,
+}
+
+/// Standard INQUIRY data, up to the product revision.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct InquiryData {
+    pub peripheral: u8,
+    pub removable: u8,
+    pub version: u8,
+    pub response_format: u8,
+    pub additional_length: u8,
+    pub flags: [u8; 3],
+    pub vendor_id: [u8; 8],
+    pub product_id: [u8; 16],
+    pub product_revision: [u8; 4],
+}
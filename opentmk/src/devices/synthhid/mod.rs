@@ -0,0 +1,167 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Minimal guest side of the synthetic HID (mouse) VMBus protocol.
+//!
+//! The client negotiates the protocol version and retrieves the device
+//! attributes and HID report descriptor the host announces after it.
+//! Input reports are dropped.
+
+pub mod protocol;
+
+use alloc::vec::Vec;
+
+use protocol::MessageHeader;
+use protocol::PipeHeader;
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+
+use crate::devices::vmbus::Channel;
+use crate::devices::vmbus::VmbusClient;
+use crate::devices::vmbus::protocol::PACKET_TYPE_DATA_IN_BAND;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// Ring size, in pages per direction, used for the HID channel.
+const SYNTHHID_RING_PAGES: usize = 4;
+
+/// The device as announced by the host.
+#[derive(Clone, Debug)]
+pub struct HidDevice {
+    /// USB style vendor ID.
+    pub vendor_id: u16,
+    /// USB style product ID.
+    pub product_id: u16,
+    /// Device version.
+    pub version: u16,
+    /// The HID descriptor.
+    pub descriptor: protocol::HidDescriptor,
+    /// The HID report descriptor.
+    pub report_descriptor: Vec<u8>,
+}
+
+/// An open synthetic HID channel.
+pub struct SynthHidClient {
+    vmbus: VmbusClient,
+    channel: Channel,
+    version: u32,
+}
+
+impl SynthHidClient {
+    /// Open the synthetic mouse channel offered on `vmbus`.
+    pub fn open(mut vmbus: VmbusClient) -> TmkResult<Self> {
+        let offer = vmbus
+            .find_offer(protocol::SYNTHHID_INTERFACE_ID)
+            .ok_or(TmkError::NotFound)?;
+        let channel = vmbus.open_channel(&offer, SYNTHHID_RING_PAGES)?;
+        Ok(SynthHidClient {
+            vmbus,
+            channel,
+            version: 0,
+        })
+    }
+
+    /// The negotiated protocol version, 0 before
+    /// [`SynthHidClient::negotiate`].
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Request the only protocol version there is.
+    pub fn negotiate(&mut self) -> TmkResult<u32> {
+        let version = protocol::VERSION_WIN7;
+        let request = protocol::ProtocolRequest {
+            header: MessageHeader {
+                message_type: protocol::MESSAGE_PROTOCOL_REQUEST,
+                size: size_of::<u32>() as u32,
+            },
+            version,
+        };
+        self.send(&request)?;
+        let data = self.wait_for(protocol::MESSAGE_PROTOCOL_RESPONSE)?;
+        let (response, _) = protocol::ProtocolResponse::read_from_prefix(&data)
+            .map_err(|_| TmkError::InvalidParameter)?;
+        if response.approved == 0 {
+            log::error!("synthhid version {:#x} rejected", version);
+            return Err(TmkError::FeatureUnavailable);
+        }
+        log::info!("synthhid version {:#x} accepted", version);
+        self.version = version;
+        Ok(version)
+    }
+
+    /// Wait for the device info the host sends after negotiation and
+    /// acknowledge it.
+    pub fn device_info(&mut self) -> TmkResult<HidDevice> {
+        let data = self.wait_for(protocol::MESSAGE_INITIAL_DEVICE_INFO)?;
+        let (info, rest) = protocol::InitialDeviceInfo::read_from_prefix(&data)
+            .map_err(|_| TmkError::InvalidParameter)?;
+        let descriptor = info.descriptor;
+        let report_descriptor = rest
+            .get(..descriptor.class_descriptor_length as usize)
+            .ok_or(TmkError::InvalidParameter)?
+            .to_vec();
+
+        let ack = protocol::InitialDeviceInfoAck {
+            header: MessageHeader {
+                message_type: protocol::MESSAGE_INITIAL_DEVICE_INFO_ACK,
+                size: 1,
+            },
+            reserved: 0,
+        };
+        self.send(&ack)?;
+
+        let attributes = info.attributes;
+        Ok(HidDevice {
+            vendor_id: attributes.vendor_id,
+            product_id: attributes.product_id,
+            version: attributes.version,
+            descriptor,
+            report_descriptor,
+        })
+    }
+
+    /// Close the channel and hand back the VMBus connection.
+    pub fn close(self) -> TmkResult<VmbusClient> {
+        let mut vmbus = self.vmbus;
+        vmbus.close_channel(self.channel)?;
+        Ok(vmbus)
+    }
+
+    fn send<T: IntoBytes + Immutable>(&mut self, message: &T) -> TmkResult<()> {
+        let pipe = PipeHeader {
+            message_type: protocol::PIPE_MESSAGE_DATA,
+            size: size_of::<T>() as u32,
+        };
+        let mut payload = Vec::new();
+        payload.extend_from_slice(pipe.as_bytes());
+        payload.extend_from_slice(message.as_bytes());
+        self.vmbus
+            .send(&mut self.channel, &payload, true)
+            .map(|_| ())
+    }
+
+    /// Wait for a message of `message_type` and return it without the pipe
+    /// header, dropping everything else.
+    fn wait_for(&mut self, message_type: u32) -> TmkResult<Vec<u8>> {
+        loop {
+            let packet = self.vmbus.recv(&mut self.channel)?;
+            if packet.descriptor.packet_type != PACKET_TYPE_DATA_IN_BAND {
+                continue;
+            }
+            let (pipe, rest) = PipeHeader::read_from_prefix(&packet.data)
+                .map_err(|_| TmkError::InvalidParameter)?;
+            let message = rest
+                .get(..pipe.size as usize)
+                .ok_or(TmkError::InvalidParameter)?;
+            let (header, _) =
+                MessageHeader::read_from_prefix(message).map_err(|_| TmkError::InvalidParameter)?;
+            if header.message_type != message_type {
+                log::debug!("ignoring synthhid message {}", header.message_type);
+                continue;
+            }
+            return Ok(message.to_vec());
+        }
+    }
+}
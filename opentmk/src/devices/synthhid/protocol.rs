@@ -0,0 +1,106 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Synthetic HID (mouse) VMBus protocol messages.
+//!
+//! Every message is wrapped in a pipe header and starts with a synthhid
+//! header. The device info message is packed and followed by the HID
+//! report descriptor.
+
+#![expect(missing_docs)]
+
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+/// Interface ID of the synthetic mouse VMBus device.
+pub const SYNTHHID_INTERFACE_ID: uefi::Guid = uefi::guid!("cfa8b69e-5b4a-4cc0-b98b-8ba1a1f3f95a");
+
+pub const fn make_version(major: u16, minor: u16) -> u32 {
+    ((major as u32) << 16) | (minor as u32)
+}
+
+pub const VERSION_WIN7: u32 = make_version(2, 0);
+
+pub const PIPE_MESSAGE_DATA: u32 = 1;
+
+pub const MESSAGE_PROTOCOL_REQUEST: u32 = 0;
+pub const MESSAGE_PROTOCOL_RESPONSE: u32 = 1;
+pub const MESSAGE_INITIAL_DEVICE_INFO: u32 = 2;
+pub const MESSAGE_INITIAL_DEVICE_INFO_ACK: u32 = 3;
+pub const MESSAGE_INPUT_REPORT: u32 = 4;
+
+/// `HidDescriptor::descriptor_type` of a HID descriptor.
+pub const HID_DESCRIPTOR_TYPE: u8 = 0x21;
+/// `HidDescriptor::class_descriptor_type` of a report descriptor.
+pub const REPORT_DESCRIPTOR_TYPE: u8 = 0x22;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct PipeHeader {
+    pub message_type: u32,
+    /// Size of the data following this header.
+    pub size: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct MessageHeader {
+    pub message_type: u32,
+    /// Size of the message following this header.
+    pub size: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct ProtocolRequest {
+    pub header: MessageHeader,
+    pub version: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct ProtocolResponse {
+    pub header: MessageHeader,
+    pub version: u32,
+    pub approved: u8,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct HidDeviceAttributes {
+    pub size: u32,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub version: u16,
+    pub reserved: [u16; 11],
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct HidDescriptor {
+    pub length: u8,
+    pub descriptor_type: u8,
+    pub hid_version: u16,
+    pub country: u8,
+    pub num_descriptors: u8,
+    pub class_descriptor_type: u8,
+    pub class_descriptor_length: u16,
+}
+
+/// Followed by the report descriptor.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct InitialDeviceInfo {
+    pub header: MessageHeader,
+    pub attributes: HidDeviceAttributes,
+    pub descriptor: HidDescriptor,
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct InitialDeviceInfoAck {
+    pub header: MessageHeader,
+    pub reserved: u8,
+}
@@ -0,0 +1,274 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Minimal guest side of the synthetic video (synthvid) VMBus protocol.
+//!
+//! The client negotiates the protocol version, places the VRAM, sets a
+//! single 32 bpp mode and reports dirty rectangles, enough to get pixels
+//! written by a test onto the host's screen.
+//!
+//! Where the VRAM goes is up to the harness, which knows the MMIO gaps of
+//! the VM: it names a free GPA in [`VRAM_VARIABLE`], and the channel offer
+//! gives the size the host set aside for it.
+
+pub mod protocol;
+
+use alloc::vec::Vec;
+
+use protocol::MessageHeader;
+use protocol::PipeHeader;
+use spin::Mutex;
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+use crate::devices::vmbus::Channel;
+use crate::devices::vmbus::VmbusClient;
+use crate::devices::vmbus::protocol::PACKET_TYPE_DATA_IN_BAND;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// Ring size, in pages per direction, used for the video channel.
+const SYNTHVID_RING_PAGES: usize = 4;
+/// Bits per pixel of the mode set by [`SynthVidClient::set_mode`].
+const DEPTH_BITS: u8 = 32;
+
+/// Name of the UEFI variable holding the GPA of the VRAM.
+pub const VRAM_VARIABLE: &str = "OpenTmkSynthvidVram";
+/// Vendor GUID of [`VRAM_VARIABLE`], shared with the scenario variable.
+pub const VRAM_VARIABLE_VENDOR: uefi::Guid = crate::scenario::SCENARIO_VARIABLE_VENDOR;
+
+/// GPA of the VRAM named by the harness, if any.
+static VRAM_GPA: Mutex<Option<u64>> = Mutex::new(None);
+
+/// Records the GPA found in [`VRAM_VARIABLE`].
+pub(crate) fn set_vram_gpa(gpa: u64) {
+    *VRAM_GPA.lock() = Some(gpa);
+}
+
+/// Returns the GPA the harness set aside for the VRAM, `None` if it named
+/// none.
+pub fn vram_gpa() -> Option<u64> {
+    *VRAM_GPA.lock()
+}
+
+/// The current video mode.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Mode {
+    /// Width in pixels.
+    pub width: u32,
+    /// Height in pixels.
+    pub height: u32,
+    /// Bytes per scan line.
+    pub pitch: u32,
+}
+
+/// An open synthetic video channel.
+pub struct SynthVidClient {
+    vmbus: VmbusClient,
+    channel: Channel,
+    version: u32,
+    vram: Option<(u64, usize)>,
+    mode: Option<Mode>,
+    user_context: u64,
+}
+
+impl SynthVidClient {
+    /// Open the synthetic video channel offered on `vmbus`.
+    pub fn open(mut vmbus: VmbusClient) -> TmkResult<Self> {
+        let offer = vmbus
+            .find_offer(protocol::SYNTHVID_INTERFACE_ID)
+            .ok_or(TmkError::NotFound)?;
+        let channel = vmbus.open_channel(&offer, SYNTHVID_RING_PAGES)?;
+        Ok(SynthVidClient {
+            vmbus,
+            channel,
+            version: 0,
+            vram: None,
+            mode: None,
+            user_context: 0,
+        })
+    }
+
+    /// Size of the VRAM the host set aside for the channel, from its offer.
+    pub fn vram_size(&self) -> usize {
+        usize::from(self.channel.offer.mmio_megabytes) << 20
+    }
+
+    /// The negotiated protocol version, 0 before
+    /// [`SynthVidClient::negotiate`].
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// The mode set by [`SynthVidClient::set_mode`], if any.
+    pub fn mode(&self) -> Option<Mode> {
+        self.mode
+    }
+
+    /// Negotiate the newest protocol version supported by the host.
+    pub fn negotiate(&mut self) -> TmkResult<u32> {
+        for version in protocol::SUPPORTED_VERSIONS {
+            let request = protocol::VersionRequest {
+                header: header::<protocol::VersionRequest>(protocol::MESSAGE_VERSION_REQUEST),
+                version,
+            };
+            self.send(&request)?;
+            let response: protocol::VersionResponse =
+                self.wait_for(protocol::MESSAGE_VERSION_RESPONSE)?;
+            if response.is_accepted != 0 {
+                log::info!("synthvid version {:#x} accepted", version);
+                self.version = version;
+                return Ok(version);
+            }
+            log::debug!("synthvid version {:#x} rejected", version);
+        }
+        log::error!("no common synthvid version with host");
+        Err(TmkError::FeatureUnavailable)
+    }
+
+    /// Ask the host to back the `len` bytes of MMIO space at `gpa` with
+    /// VRAM.
+    pub fn set_vram_location(&mut self, gpa: u64, len: usize) -> TmkResult<()> {
+        self.user_context += 1;
+        let user_context = self.user_context;
+        let request = protocol::VramLocation {
+            header: header::<protocol::VramLocation>(protocol::MESSAGE_VRAM_LOCATION),
+            user_context,
+            is_vram_gpa_specified: 1,
+            vram_gpa: gpa,
+        };
+        self.send(&request)?;
+        let ack: protocol::VramLocationAck = self.wait_for(protocol::MESSAGE_VRAM_LOCATION_ACK)?;
+        if { ack.user_context } != user_context {
+            log::error!("vram location ack for the wrong request");
+            return Err(TmkError::InvalidParameter);
+        }
+        log::info!("synthvid vram at {:#x}", gpa);
+        self.vram = Some((gpa, len));
+        Ok(())
+    }
+
+    /// Set a `width` x `height` mode at 32 bits per pixel, scanning out from
+    /// the start of the VRAM.
+    pub fn set_mode(&mut self, width: u32, height: u32) -> TmkResult<Mode> {
+        let (_, len) = self.vram.ok_or(TmkError::Inactive)?;
+        let pitch = width * (DEPTH_BITS as u32 / 8);
+        if pitch as usize * height as usize > len {
+            return Err(TmkError::InsufficientBuffer);
+        }
+        self.user_context += 1;
+        let user_context = self.user_context;
+        let request = protocol::SituationUpdate {
+            header: header::<protocol::SituationUpdate>(protocol::MESSAGE_SITUATION_UPDATE),
+            user_context,
+            video_output_count: 1,
+            video_output: protocol::VideoOutputSituation {
+                active: 1,
+                vram_offset: 0,
+                depth_bits: DEPTH_BITS,
+                width_pixels: width,
+                height_pixels: height,
+                pitch_bytes: pitch,
+            },
+        };
+        self.send(&request)?;
+        let ack: protocol::SituationUpdateAck =
+            self.wait_for(protocol::MESSAGE_SITUATION_UPDATE_ACK)?;
+        if { ack.user_context } != user_context {
+            log::error!("situation update ack for the wrong request");
+            return Err(TmkError::InvalidParameter);
+        }
+        let mode = Mode {
+            width,
+            height,
+            pitch,
+        };
+        log::info!("synthvid mode {}x{}", width, height);
+        self.mode = Some(mode);
+        Ok(mode)
+    }
+
+    /// Write the 32-bit `color` of the pixel at `x`, `y`.
+    pub fn put_pixel(&mut self, x: u32, y: u32, color: u32) -> TmkResult<()> {
+        let (gpa, _) = self.vram.ok_or(TmkError::Inactive)?;
+        let mode = self.mode.ok_or(TmkError::Inactive)?;
+        if x >= mode.width || y >= mode.height {
+            return Err(TmkError::InvalidParameter);
+        }
+        let ptr = (gpa + y as u64 * mode.pitch as u64 + x as u64 * 4) as *mut u32;
+        // SAFETY: the pixel lies within the VRAM placed by
+        // `set_vram_location`, which is identity mapped.
+        unsafe { ptr.write_volatile(color) };
+        Ok(())
+    }
+
+    /// Tell the host the whole screen changed.
+    pub fn mark_dirty(&mut self) -> TmkResult<()> {
+        let mode = self.mode.ok_or(TmkError::Inactive)?;
+        let dirt = protocol::Dirt {
+            header: header::<protocol::Dirt>(protocol::MESSAGE_DIRT),
+            video_output: 0,
+            dirt_count: 1,
+            rect: protocol::Rect {
+                x1: 0,
+                y1: 0,
+                x2: mode.width as i32,
+                y2: mode.height as i32,
+            },
+        };
+        self.send(&dirt)
+    }
+
+    /// Close the channel and hand back the VMBus connection.
+    pub fn close(self) -> TmkResult<VmbusClient> {
+        let mut vmbus = self.vmbus;
+        vmbus.close_channel(self.channel)?;
+        Ok(vmbus)
+    }
+
+    fn send<T: IntoBytes + Immutable>(&mut self, message: &T) -> TmkResult<()> {
+        let pipe = PipeHeader {
+            message_type: protocol::PIPE_MESSAGE_DATA,
+            size: size_of::<T>() as u32,
+        };
+        let mut payload = Vec::new();
+        payload.extend_from_slice(pipe.as_bytes());
+        payload.extend_from_slice(message.as_bytes());
+        self.vmbus
+            .send(&mut self.channel, &payload, false)
+            .map(|_| ())
+    }
+
+    /// Wait for a message of `message_type`, dropping everything else.
+    fn wait_for<T: FromBytes + KnownLayout + Immutable>(
+        &mut self,
+        message_type: u32,
+    ) -> TmkResult<T> {
+        loop {
+            let packet = self.vmbus.recv(&mut self.channel)?;
+            if packet.descriptor.packet_type != PACKET_TYPE_DATA_IN_BAND {
+                continue;
+            }
+            let (_, message) = PipeHeader::read_from_prefix(&packet.data)
+                .map_err(|_| TmkError::InvalidParameter)?;
+            let (header, _) =
+                MessageHeader::read_from_prefix(message).map_err(|_| TmkError::InvalidParameter)?;
+            if header.message_type != message_type {
+                log::debug!("ignoring synthvid message {}", header.message_type);
+                continue;
+            }
+            let (message, _) =
+                T::read_from_prefix(message).map_err(|_| TmkError::InvalidParameter)?;
+            return Ok(message);
+        }
+    }
+}
+
+fn header<T>(message_type: u32) -> MessageHeader {
+    MessageHeader {
+        message_type,
+        size: size_of::<T>() as u32,
+    }
+}
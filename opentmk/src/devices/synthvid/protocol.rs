@@ -0,0 +1,135 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Synthetic video (synthvid) VMBus protocol messages.
+//!
+//! Every message is wrapped in a pipe header and starts with a synthvid
+//! header. Most messages are packed.
+
+#![expect(missing_docs)]
+
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+/// Interface ID of the synthetic video VMBus device.
+pub const SYNTHVID_INTERFACE_ID: uefi::Guid = uefi::guid!("da0a7802-e377-4aac-8e77-0558eb1073f8");
+
+/// Synthvid versions carry the major version in the low word.
+pub const fn make_version(major: u16, minor: u16) -> u32 {
+    ((minor as u32) << 16) | (major as u32)
+}
+
+pub const VERSION_WIN7: u32 = make_version(3, 0);
+pub const VERSION_WIN8: u32 = make_version(3, 2);
+pub const VERSION_WIN10: u32 = make_version(3, 5);
+
+/// Versions requested during negotiation, newest first.
+pub const SUPPORTED_VERSIONS: [u32; 3] = [VERSION_WIN10, VERSION_WIN8, VERSION_WIN7];
+
+pub const PIPE_MESSAGE_DATA: u32 = 1;
+
+pub const MESSAGE_VERSION_REQUEST: u32 = 1;
+pub const MESSAGE_VERSION_RESPONSE: u32 = 2;
+pub const MESSAGE_VRAM_LOCATION: u32 = 3;
+pub const MESSAGE_VRAM_LOCATION_ACK: u32 = 4;
+pub const MESSAGE_SITUATION_UPDATE: u32 = 5;
+pub const MESSAGE_SITUATION_UPDATE_ACK: u32 = 6;
+pub const MESSAGE_FEATURE_CHANGE: u32 = 9;
+pub const MESSAGE_DIRT: u32 = 10;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct PipeHeader {
+    pub message_type: u32,
+    /// Size of the data following this header.
+    pub size: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct MessageHeader {
+    pub message_type: u32,
+    /// Size of the whole message, including this header.
+    pub size: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct VersionRequest {
+    pub header: MessageHeader,
+    pub version: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct VersionResponse {
+    pub header: MessageHeader,
+    pub version: u32,
+    pub is_accepted: u8,
+    pub max_video_outputs: u8,
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct VramLocation {
+    pub header: MessageHeader,
+    pub user_context: u64,
+    pub is_vram_gpa_specified: u8,
+    pub vram_gpa: u64,
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct VramLocationAck {
+    pub header: MessageHeader,
+    pub user_context: u64,
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct VideoOutputSituation {
+    pub active: u8,
+    pub vram_offset: u32,
+    pub depth_bits: u8,
+    pub width_pixels: u32,
+    pub height_pixels: u32,
+    pub pitch_bytes: u32,
+}
+
+/// Only a single video output is supported.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct SituationUpdate {
+    pub header: MessageHeader,
+    pub user_context: u64,
+    pub video_output_count: u8,
+    pub video_output: VideoOutputSituation,
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct SituationUpdateAck {
+    pub header: MessageHeader,
+    pub user_context: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct Rect {
+    pub x1: i32,
+    pub y1: i32,
+    pub x2: i32,
+    pub y2: i32,
+}
+
+/// Only a single rectangle is supported.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct Dirt {
+    pub header: MessageHeader,
+    pub video_output: u8,
+    pub dirt_count: u8,
+    pub rect: Rect,
+}
@@ -0,0 +1,184 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! GPA direct lists.
+//!
+//! A GPADL describes guest memory shared with the host as a list of ranges,
+//! each a [`GpaRange`] followed by the page numbers it covers. On the wire
+//! the ranges are a stream of 64-bit words sent in a `GpadlHeader` message
+//! and as many `GpadlBody` messages as needed. [`Gpadl`] builds the ranges
+//! and [`GpadlMessages`] splits them into messages; the messages are plain
+//! data so that negative tests can corrupt them before sending them with
+//! [`super::VmbusClient::send_gpadl_messages`].
+
+use alloc::vec::Vec;
+
+use hvdef::HV_PAGE_SIZE;
+use zerocopy::FromBytes;
+use zerocopy::IntoBytes;
+
+use super::protocol;
+use super::protocol::GpaRange;
+use super::protocol::MessageHeader;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// Range words that fit into a `GpadlHeader` message.
+pub const HEADER_WORDS: usize = (hvdef::HV_MESSAGE_PAYLOAD_SIZE
+    - size_of::<MessageHeader>()
+    - size_of::<protocol::GpadlHeader>())
+    / size_of::<u64>();
+/// Range words that fit into a `GpadlBody` message.
+pub const BODY_WORDS: usize = (hvdef::HV_MESSAGE_PAYLOAD_SIZE
+    - size_of::<MessageHeader>()
+    - size_of::<protocol::GpadlBody>())
+    / size_of::<u64>();
+
+/// A GPA direct list under construction.
+#[derive(Clone, Debug, Default)]
+pub struct Gpadl {
+    ranges: Vec<(GpaRange, Vec<u64>)>,
+}
+
+impl Gpadl {
+    /// An empty list.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a range covering the `len` bytes at `gpa`, which need not be
+    /// page aligned.
+    pub fn range(mut self, gpa: u64, len: usize) -> Self {
+        let first = gpa / HV_PAGE_SIZE;
+        let last = (gpa + len.max(1) as u64 - 1) / HV_PAGE_SIZE;
+        let range = GpaRange {
+            len: len as u32,
+            offset: (gpa % HV_PAGE_SIZE) as u32,
+        };
+        self.ranges.push((range, (first..=last).collect()));
+        self
+    }
+
+    /// Adds a range covering the whole pages `gpns`, in order.
+    pub fn pages(mut self, gpns: &[u64]) -> Self {
+        let range = GpaRange {
+            len: (gpns.len() * HV_PAGE_SIZE as usize) as u32,
+            offset: 0,
+        };
+        self.ranges.push((range, gpns.to_vec()));
+        self
+    }
+
+    /// Adds `range` with the page numbers `gpns` as given, consistent or
+    /// not.
+    pub fn raw_range(mut self, range: GpaRange, gpns: &[u64]) -> Self {
+        self.ranges.push((range, gpns.to_vec()));
+        self
+    }
+
+    /// Number of ranges.
+    pub fn range_count(&self) -> usize {
+        self.ranges.len()
+    }
+
+    /// The ranges as the stream of words sent to the host.
+    pub fn to_words(&self) -> Vec<u64> {
+        let mut words = Vec::new();
+        for (range, gpns) in &self.ranges {
+            words.push(u64::read_from_bytes(range.as_bytes()).unwrap());
+            words.extend_from_slice(gpns);
+        }
+        words
+    }
+}
+
+/// The messages creating a GPADL.
+#[derive(Clone, Debug)]
+pub struct GpadlMessages {
+    /// The `GpadlHeader` message.
+    pub header: protocol::GpadlHeader,
+    /// The range words sent with the header.
+    pub header_words: Vec<u64>,
+    /// The range words of each `GpadlBody` message.
+    pub bodies: Vec<Vec<u64>>,
+}
+
+impl GpadlMessages {
+    /// Splits `gpadl` into the messages creating it as `gpadl_id` on
+    /// `channel_id`. Fails with [`TmkError::InvalidParameter`] if its range
+    /// words or ranges are too many for the 16-bit fields of the header.
+    pub fn new(channel_id: u32, gpadl_id: u32, gpadl: &Gpadl) -> TmkResult<Self> {
+        let words = gpadl.to_words();
+        let len = u16::try_from(words.len() * size_of::<u64>())
+            .map_err(|_| TmkError::InvalidParameter)?;
+        let count = u16::try_from(gpadl.range_count()).map_err(|_| TmkError::InvalidParameter)?;
+        let (first, rest) = words.split_at(words.len().min(HEADER_WORDS));
+        Ok(Self {
+            header: protocol::GpadlHeader {
+                channel_id,
+                gpadl_id,
+                len,
+                count,
+            },
+            header_words: first.to_vec(),
+            bodies: rest.chunks(BODY_WORDS).map(|c| c.to_vec()).collect(),
+        })
+    }
+
+    /// The payload of the `GpadlHeader` message, after the message header.
+    pub fn header_payload(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(self.header.as_bytes());
+        payload.extend_from_slice(self.header_words.as_bytes());
+        payload
+    }
+
+    /// The payload of body message `index`, after the message header.
+    pub fn body_payload(&self, index: usize) -> Vec<u8> {
+        let body = protocol::GpadlBody {
+            rsvd: 0,
+            gpadl_id: self.header.gpadl_id,
+        };
+        let mut payload = Vec::new();
+        payload.extend_from_slice(body.as_bytes());
+        payload.extend_from_slice(self.bodies[index].as_bytes());
+        payload
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        let gpns = (0..100).collect::<Vec<u64>>();
+        let gpadl = Gpadl::new().pages(&gpns).range(0x1ff0, 0x20);
+        let words = gpadl.to_words();
+        // Two range words, 100 pages and the two pages straddled by the
+        // second range.
+        assert_eq!(words.len(), 104);
+        assert_eq!(&words[102..], &[1, 2]);
+
+        let messages = GpadlMessages::new(3, 7, &gpadl).unwrap();
+        assert_eq!(messages.header.len as usize, 104 * 8);
+        assert_eq!(messages.header.count, 2);
+        assert_eq!(messages.header_words.len(), HEADER_WORDS);
+        let sent =
+            messages.header_words.len() + messages.bodies.iter().map(Vec::len).sum::<usize>();
+        assert_eq!(sent, words.len());
+        assert!(messages.bodies.iter().all(|b| b.len() <= BODY_WORDS));
+    }
+
+    #[test]
+    fn test_too_long() {
+        // 8192 range words are 64KiB, one byte more than the header holds.
+        let gpns = (0..8191).collect::<Vec<u64>>();
+        let gpadl = Gpadl::new().pages(&gpns);
+        assert_eq!(gpadl.to_words().len(), 8192);
+        assert_eq!(
+            GpadlMessages::new(3, 7, &gpadl).map(|_| ()),
+            Err(TmkError::InvalidParameter)
+        );
+    }
+}
@@ -0,0 +1,575 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Minimal polled VMBus client.
+//!
+//! The client runs on the BSP in VTL0 and picks up channel management
+//! messages by polling the SynIC message slot of [`protocol::VMBUS_SINT`], so
+//! it does not depend on any interrupt infrastructure. It only supports what
+//! device test clients need: offers, GPADLs, opening/closing channels,
+//! in-band and GPA direct packets and completions.
+
+pub mod gpadl;
+pub mod protocol;
+pub mod ring;
+
+use alloc::alloc::alloc_zeroed;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+
+use hvdef::HV_PAGE_SIZE;
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+use crate::platform::hyperv::arch::hypercall::HvCall;
+use crate::platform::hyperv::synic::Synic;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+use gpadl::Gpadl;
+use gpadl::GpadlMessages;
+use protocol::MessageHeader;
+use protocol::OfferChannel;
+use ring::Packet;
+use ring::Ring;
+
+/// Number of polling iterations before a wait for the host gives up.
+const POLL_LIMIT: u64 = 100_000_000;
+/// Channel management messages kept for a later wait while waiting for
+/// another; the oldest is dropped past it.
+const MAX_PENDING_MESSAGES: usize = 64;
+
+/// An open channel and its ring buffers.
+pub struct Channel {
+    /// The offer the channel was opened from.
+    pub offer: OfferChannel,
+    gpadl_id: u32,
+    outgoing: Ring,
+    incoming: Ring,
+    next_transaction_id: u64,
+}
+
+/// Guest memory shared with the host through a GPADL.
+pub struct GpadlBuffer {
+    /// The GPADL ID to hand to the device.
+    pub gpadl_id: u32,
+    /// The identity mapped start of the buffer.
+    pub base: *mut u8,
+    /// Length of the buffer in bytes.
+    pub len: usize,
+}
+
+/// A connection to the VMBus server of the host.
+pub struct VmbusClient {
+    hvcall: HvCall,
+    synic: Synic,
+    message_connection_id: u32,
+    version: u32,
+    offers: Vec<OfferChannel>,
+    next_gpadl_id: u32,
+    /// Channel and GPADL IDs of the GPADLs created and not torn down.
+    gpadls: Vec<(u32, u32)>,
+    /// Channel management messages received while waiting for another,
+    /// oldest first.
+    pending: VecDeque<(u32, Vec<u8>)>,
+}
+
+impl VmbusClient {
+    /// Enable the SynIC on the current VP, negotiate a protocol version with
+    /// the host and collect the channel offers.
+    pub fn connect() -> TmkResult<Self> {
+        let mut hvcall = HvCall::new();
+        hvcall.initialize();
+        let synic = Synic::enable()?;
+        synic.configure_sint(protocol::VMBUS_SINT, 0, true)?;
+
+        let mut client = VmbusClient {
+            hvcall,
+            synic,
+            message_connection_id: protocol::VMBUS_MESSAGE_CONNECTION_ID,
+            version: 0,
+            offers: Vec::new(),
+            next_gpadl_id: 1,
+            gpadls: Vec::new(),
+            pending: VecDeque::new(),
+        };
+
+        for version in protocol::SUPPORTED_VERSIONS {
+            client.message_connection_id = if version >= protocol::VERSION_WIN10 {
+                protocol::VMBUS_MESSAGE_CONNECTION_ID_4
+            } else {
+                protocol::VMBUS_MESSAGE_CONNECTION_ID
+            };
+            let request = protocol::InitiateContact {
+                version_requested: version,
+                target_message_vp: 0,
+                interrupt_page_or_target_info: protocol::VMBUS_SINT as u64,
+                parent_to_child_monitor_page_gpa: 0,
+                child_to_parent_monitor_page_gpa: 0,
+            };
+            client.post(protocol::INITIATE_CONTACT, &request)?;
+            let response: protocol::VersionResponse =
+                client.wait_for(protocol::VERSION_RESPONSE, |_| true)?;
+            if response.version_supported != 0 {
+                client.version = version;
+                break;
+            }
+            log::debug!("vmbus version {:#x} not supported by host", version);
+        }
+        if client.version == 0 {
+            log::error!("no common vmbus version with host");
+            return Err(TmkError::FeatureUnavailable);
+        }
+        log::info!("vmbus connected, version: {:#x}", client.version);
+
+        client.post_header(protocol::REQUEST_OFFERS)?;
+        loop {
+            let (message_type, payload) = client.wait_message()?;
+            match message_type {
+                protocol::OFFER_CHANNEL => {
+                    let offer = OfferChannel::read_from_prefix(&payload)
+                        .map_err(|_| TmkError::InvalidParameter)?
+                        .0;
+                    log::debug!(
+                        "vmbus offer: channel {}, interface {}",
+                        offer.channel_id,
+                        offer.interface_id()
+                    );
+                    client.offers.push(offer);
+                }
+                protocol::ALL_OFFERS_DELIVERED => break,
+                _ => log::debug!("ignoring vmbus message {}", message_type),
+            }
+        }
+
+        Ok(client)
+    }
+
+    /// The negotiated protocol version.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// All the offers received from the host.
+    pub fn offers(&self) -> &[OfferChannel] {
+        &self.offers
+    }
+
+    /// Find the first offer for the given interface.
+    pub fn find_offer(&self, interface_id: uefi::Guid) -> Option<OfferChannel> {
+        self.offers
+            .iter()
+            .find(|offer| offer.interface_id() == interface_id)
+            .copied()
+    }
+
+    /// Open the channel described by `offer` with `ring_pages` pages for each
+    /// direction, including the control page.
+    pub fn open_channel(&mut self, offer: &OfferChannel, ring_pages: usize) -> TmkResult<Channel> {
+        if ring_pages < 2 {
+            return Err(TmkError::InvalidParameter);
+        }
+        let page_size = HV_PAGE_SIZE as usize;
+        let layout = Layout::from_size_align(2 * ring_pages * page_size, page_size)
+            .map_err(|_| TmkError::AllocationFailed)?;
+        // SAFETY: the layout has a non-zero size.
+        let base = unsafe { alloc_zeroed(layout) };
+        if base.is_null() {
+            return Err(TmkError::AllocationFailed);
+        }
+
+        // The ring memory is handed to the host for the rest of the run, so
+        // it is never freed.
+        let gpns = (0..2 * ring_pages)
+            .map(|i| (base as u64 + (i * page_size) as u64) / HV_PAGE_SIZE)
+            .collect::<Vec<_>>();
+        let gpadl_id = self.create_gpadl(offer.channel_id, &Gpadl::new().pages(&gpns))?;
+
+        let request = protocol::OpenChannel {
+            channel_id: offer.channel_id,
+            open_id: offer.channel_id,
+            ring_buffer_gpadl_id: gpadl_id,
+            target_vp: 0,
+            downstream_ring_buffer_page_offset: ring_pages as u32,
+            user_data: [0; 120],
+        };
+        self.post(protocol::OPEN_CHANNEL, &request)?;
+        let result: protocol::OpenResult = self.wait_for(protocol::OPEN_CHANNEL_RESULT, |r| {
+            r.channel_id == offer.channel_id
+        })?;
+        if result.status != protocol::STATUS_SUCCESS as u32 {
+            log::error!(
+                "failed to open channel {}: {:#x}",
+                offer.channel_id,
+                result.status
+            );
+            return Err(TmkError::OperationFailed);
+        }
+        log::info!("opened vmbus channel {}", offer.channel_id);
+
+        Ok(Channel {
+            offer: *offer,
+            gpadl_id,
+            // SAFETY: both halves are page aligned and never freed.
+            outgoing: unsafe { Ring::new(base, ring_pages) },
+            // SAFETY: both halves are page aligned and never freed.
+            incoming: unsafe { Ring::new(base.add(ring_pages * page_size), ring_pages) },
+            next_transaction_id: 1,
+        })
+    }
+
+    /// Close the channel and tear down its ring buffer GPADL.
+    ///
+    /// GPADLs created with [`VmbusClient::create_buffer`] must be torn down
+    /// separately.
+    pub fn close_channel(&mut self, channel: Channel) -> TmkResult<()> {
+        let channel_id = channel.offer.channel_id;
+        self.post(
+            protocol::CLOSE_CHANNEL,
+            &protocol::CloseChannel { channel_id },
+        )?;
+        self.teardown_gpadl(channel_id, channel.gpadl_id)?;
+        log::info!("closed vmbus channel {}", channel_id);
+        Ok(())
+    }
+
+    /// Allocate `page_count` zeroed pages and share them with the host
+    /// through a GPADL on `channel`, e.g. for the receive buffer of a
+    /// network channel.
+    ///
+    /// Like the rings, the memory is never freed.
+    pub fn create_buffer(
+        &mut self,
+        channel: &Channel,
+        page_count: usize,
+    ) -> TmkResult<GpadlBuffer> {
+        let page_size = HV_PAGE_SIZE as usize;
+        let layout = Layout::from_size_align(page_count * page_size, page_size)
+            .map_err(|_| TmkError::AllocationFailed)?;
+        if layout.size() == 0 {
+            return Err(TmkError::InvalidParameter);
+        }
+        // SAFETY: the layout has a non-zero size.
+        let base = unsafe { alloc_zeroed(layout) };
+        if base.is_null() {
+            return Err(TmkError::AllocationFailed);
+        }
+        let gpns = (0..page_count)
+            .map(|i| (base as u64 + (i * page_size) as u64) / HV_PAGE_SIZE)
+            .collect::<Vec<_>>();
+        let gpadl_id = self.create_gpadl(channel.offer.channel_id, &Gpadl::new().pages(&gpns))?;
+        Ok(GpadlBuffer {
+            gpadl_id,
+            base,
+            len: layout.size(),
+        })
+    }
+
+    /// Tear down a GPADL created on `channel_id`.
+    pub fn teardown_gpadl(&mut self, channel_id: u32, gpadl_id: u32) -> TmkResult<()> {
+        self.post(
+            protocol::GPADL_TEARDOWN,
+            &protocol::GpadlTeardown {
+                channel_id,
+                gpadl_id,
+            },
+        )?;
+        let _: protocol::GpadlTorndown =
+            self.wait_for(protocol::GPADL_TORNDOWN, |r| r.gpadl_id == gpadl_id)?;
+        self.gpadls
+            .retain(|&(c, g)| (c, g) != (channel_id, gpadl_id));
+        Ok(())
+    }
+
+    /// The channel and GPADL IDs of the GPADLs created and not torn down
+    /// yet.
+    pub fn live_gpadls(&self) -> &[(u32, u32)] {
+        &self.gpadls
+    }
+
+    /// Create `gpadl` on `channel_id` and return its ID.
+    pub fn create_gpadl(&mut self, channel_id: u32, gpadl: &Gpadl) -> TmkResult<u32> {
+        let gpadl_id = self.allocate_gpadl_id();
+        self.send_gpadl_messages(&GpadlMessages::new(channel_id, gpadl_id, gpadl)?)?;
+        let status = self.wait_gpadl_created(gpadl_id)?;
+        if status != protocol::STATUS_SUCCESS {
+            log::error!("failed to create gpadl {}: {:#x}", gpadl_id, status);
+            return Err(TmkError::OperationFailed);
+        }
+        Ok(gpadl_id)
+    }
+
+    /// Reserve a GPADL ID, for building [`GpadlMessages`] by hand.
+    pub fn allocate_gpadl_id(&mut self) -> u32 {
+        let gpadl_id = self.next_gpadl_id;
+        self.next_gpadl_id += 1;
+        gpadl_id
+    }
+
+    /// Send the messages creating a GPADL without waiting for the host to
+    /// answer, see [`Self::wait_gpadl_created`].
+    pub fn send_gpadl_messages(&mut self, messages: &GpadlMessages) -> TmkResult<()> {
+        self.post_raw(protocol::GPADL_HEADER, &messages.header_payload())?;
+        for index in 0..messages.bodies.len() {
+            self.post_raw(protocol::GPADL_BODY, &messages.body_payload(index))?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the host to answer the creation of `gpadl_id` and return
+    /// the status it reported. A GPADL created successfully is tracked
+    /// until it is torn down.
+    pub fn wait_gpadl_created(&mut self, gpadl_id: u32) -> TmkResult<i32> {
+        let created: protocol::GpadlCreated =
+            self.wait_for(protocol::GPADL_CREATED, |r| r.gpadl_id == gpadl_id)?;
+        if created.status == protocol::STATUS_SUCCESS {
+            self.gpadls.push((created.channel_id, gpadl_id));
+        }
+        Ok(created.status)
+    }
+
+    /// Send an in-band packet on `channel`, returning its transaction ID.
+    pub fn send(
+        &mut self,
+        channel: &mut Channel,
+        payload: &[u8],
+        completion_requested: bool,
+    ) -> TmkResult<u64> {
+        self.send_packet(
+            channel,
+            protocol::PACKET_TYPE_DATA_IN_BAND,
+            &[],
+            payload,
+            completion_requested,
+        )
+    }
+
+    /// Send a packet on `channel` that describes the `len` bytes at guest
+    /// physical address `gpa` by their page numbers, returning its
+    /// transaction ID.
+    ///
+    /// The host accesses the buffer directly, it must stay valid until the
+    /// packet is completed.
+    pub fn send_gpa_direct(
+        &mut self,
+        channel: &mut Channel,
+        gpa: u64,
+        len: usize,
+        payload: &[u8],
+        completion_requested: bool,
+    ) -> TmkResult<u64> {
+        if len == 0 {
+            return Err(TmkError::InvalidParameter);
+        }
+        let offset = gpa % HV_PAGE_SIZE;
+        let first = gpa / HV_PAGE_SIZE;
+        let last = (gpa + len as u64 - 1) / HV_PAGE_SIZE;
+        let header = protocol::GpaDirectHeader {
+            reserved: 0,
+            range_count: 1,
+        };
+        let range = protocol::GpaRange {
+            len: len as u32,
+            offset: offset as u32,
+        };
+        let mut extension = Vec::new();
+        extension.extend_from_slice(header.as_bytes());
+        extension.extend_from_slice(range.as_bytes());
+        for gpn in first..=last {
+            extension.extend_from_slice(gpn.as_bytes());
+        }
+        self.send_packet(
+            channel,
+            protocol::PACKET_TYPE_DATA_USING_GPA_DIRECT,
+            &extension,
+            payload,
+            completion_requested,
+        )
+    }
+
+    /// Send a completion for the packet `transaction_id` received on
+    /// `channel`.
+    pub fn complete(
+        &mut self,
+        channel: &mut Channel,
+        transaction_id: u64,
+        payload: &[u8],
+    ) -> TmkResult<()> {
+        self.write_packet(
+            channel,
+            protocol::PACKET_TYPE_COMPLETION,
+            0,
+            transaction_id,
+            &[],
+            payload,
+        )
+    }
+
+    /// Answer the in-band packet `transaction_id` received on `channel` with
+    /// an in-band packet carrying the same transaction ID, as the
+    /// integration components expect.
+    pub fn reply(
+        &mut self,
+        channel: &mut Channel,
+        transaction_id: u64,
+        payload: &[u8],
+    ) -> TmkResult<()> {
+        self.write_packet(
+            channel,
+            protocol::PACKET_TYPE_DATA_IN_BAND,
+            0,
+            transaction_id,
+            &[],
+            payload,
+        )
+    }
+
+    fn send_packet(
+        &mut self,
+        channel: &mut Channel,
+        packet_type: u16,
+        extension: &[u8],
+        payload: &[u8],
+        completion_requested: bool,
+    ) -> TmkResult<u64> {
+        let transaction_id = channel.next_transaction_id;
+        channel.next_transaction_id += 1;
+        let flags = if completion_requested {
+            protocol::PACKET_FLAG_COMPLETION_REQUESTED
+        } else {
+            0
+        };
+        self.write_packet(
+            channel,
+            packet_type,
+            flags,
+            transaction_id,
+            extension,
+            payload,
+        )?;
+        Ok(transaction_id)
+    }
+
+    fn write_packet(
+        &mut self,
+        channel: &mut Channel,
+        packet_type: u16,
+        flags: u16,
+        transaction_id: u64,
+        extension: &[u8],
+        payload: &[u8],
+    ) -> TmkResult<()> {
+        let signal =
+            channel
+                .outgoing
+                .write(packet_type, flags, transaction_id, extension, payload)?;
+        if signal {
+            self.hvcall
+                .signal_event(channel.offer.connection_id, 0)
+                .map_err(TmkError::from)?;
+        }
+        Ok(())
+    }
+
+    /// Return the next packet on `channel` if one is pending.
+    pub fn try_recv(&mut self, channel: &mut Channel) -> TmkResult<Option<Packet>> {
+        channel.incoming.read()
+    }
+
+    /// Wait for the next packet on `channel`.
+    pub fn recv(&mut self, channel: &mut Channel) -> TmkResult<Packet> {
+        for _ in 0..POLL_LIMIT {
+            if let Some(packet) = channel.incoming.read()? {
+                return Ok(packet);
+            }
+            core::hint::spin_loop();
+        }
+        log::error!(
+            "timed out waiting for a packet on channel {}",
+            channel.offer.channel_id
+        );
+        Err(TmkError::Timeout)
+    }
+
+    fn post<T: IntoBytes + Immutable>(&mut self, message_type: u32, message: &T) -> TmkResult<()> {
+        self.post_raw(message_type, message.as_bytes())
+    }
+
+    fn post_header(&mut self, message_type: u32) -> TmkResult<()> {
+        self.post_raw(message_type, &[])
+    }
+
+    fn post_raw(&mut self, message_type: u32, body: &[u8]) -> TmkResult<()> {
+        let mut payload = Vec::with_capacity(size_of::<MessageHeader>() + body.len());
+        payload.extend_from_slice(MessageHeader::new(message_type).as_bytes());
+        payload.extend_from_slice(body);
+        self.hvcall
+            .post_message(
+                self.message_connection_id,
+                protocol::VMBUS_POST_MESSAGE_TYPE,
+                &payload,
+            )
+            .map_err(TmkError::from)
+    }
+
+    /// Wait for the next channel management message, kept or new, and
+    /// return its type and the payload following the message header.
+    fn wait_message(&mut self) -> TmkResult<(u32, Vec<u8>)> {
+        match self.pending.pop_front() {
+            Some(message) => Ok(message),
+            None => self.next_message(),
+        }
+    }
+
+    /// Wait for a new channel management message from the SINT.
+    fn next_message(&mut self) -> TmkResult<(u32, Vec<u8>)> {
+        for _ in 0..POLL_LIMIT {
+            if let Some(message) = self.synic.poll_message(protocol::VMBUS_SINT) {
+                let payload = message.payload();
+                let (header, body) = MessageHeader::read_from_prefix(payload)
+                    .map_err(|_| TmkError::InvalidParameter)?;
+                return Ok((header.message_type, body.to_vec()));
+            }
+            core::hint::spin_loop();
+        }
+        log::error!("timed out waiting for a vmbus message");
+        Err(TmkError::Timeout)
+    }
+
+    /// Wait for a message of `message_type` accepted by `filter`, kept or
+    /// new, keeping everything else for later waits.
+    fn wait_for<T: FromBytes + KnownLayout + Immutable>(
+        &mut self,
+        message_type: u32,
+        filter: impl Fn(&T) -> bool,
+    ) -> TmkResult<T> {
+        let accepted = |typ: u32, payload: &[u8]| -> TmkResult<Option<T>> {
+            if typ != message_type {
+                return Ok(None);
+            }
+            let (message, _) =
+                T::read_from_prefix(payload).map_err(|_| TmkError::InvalidParameter)?;
+            Ok(filter(&message).then_some(message))
+        };
+        for i in 0..self.pending.len() {
+            let (typ, payload) = &self.pending[i];
+            if let Some(message) = accepted(*typ, payload)? {
+                self.pending.remove(i);
+                return Ok(message);
+            }
+        }
+        loop {
+            let (typ, payload) = self.next_message()?;
+            if let Some(message) = accepted(typ, &payload)? {
+                return Ok(message);
+            }
+            if self.pending.len() == MAX_PENDING_MESSAGES {
+                let (dropped, _) = self.pending.pop_front().unwrap();
+                log::warn!("dropping vmbus message {}, too many kept", dropped);
+            }
+            self.pending.push_back((typ, payload));
+        }
+    }
+}
@@ -0,0 +1,244 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! VMBus channel management messages, as used by the guest side.
+
+//! NOTE: this is a hand-rolled subset of `vmbus_core::protocol`, which needs
+//! `std`. Only the messages required by a polled guest client are defined.
+
+#![expect(missing_docs)]
+
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+/// The SINT used by the host to deliver channel management messages.
+pub const VMBUS_SINT: u8 = 2;
+/// Connection ID for channel management messages before Win10.
+pub const VMBUS_MESSAGE_CONNECTION_ID: u32 = 1;
+/// Connection ID for channel management messages starting with Win10.
+pub const VMBUS_MESSAGE_CONNECTION_ID_4: u32 = 4;
+/// Message type passed to `HvCallPostMessage` for channel management messages.
+pub const VMBUS_POST_MESSAGE_TYPE: u32 = 1;
+
+pub const STATUS_SUCCESS: i32 = 0;
+
+pub const fn make_version(major: u16, minor: u16) -> u32 {
+    ((major as u32) << 16) | (minor as u32)
+}
+
+pub const VERSION_WIN8_1: u32 = make_version(3, 0);
+pub const VERSION_WIN10: u32 = make_version(4, 0);
+pub const VERSION_WIN10_RS5: u32 = make_version(5, 2);
+
+/// Versions requested during `InitiateContact`, newest first.
+pub const SUPPORTED_VERSIONS: [u32; 3] = [VERSION_WIN10_RS5, VERSION_WIN10, VERSION_WIN8_1];
+
+pub const OFFER_CHANNEL: u32 = 1;
+pub const RESCIND_CHANNEL_OFFER: u32 = 2;
+pub const REQUEST_OFFERS: u32 = 3;
+pub const ALL_OFFERS_DELIVERED: u32 = 4;
+pub const OPEN_CHANNEL: u32 = 5;
+pub const OPEN_CHANNEL_RESULT: u32 = 6;
+pub const CLOSE_CHANNEL: u32 = 7;
+pub const GPADL_HEADER: u32 = 8;
+pub const GPADL_BODY: u32 = 9;
+pub const GPADL_CREATED: u32 = 10;
+pub const GPADL_TEARDOWN: u32 = 11;
+pub const GPADL_TORNDOWN: u32 = 12;
+pub const INITIATE_CONTACT: u32 = 14;
+pub const VERSION_RESPONSE: u32 = 15;
+pub const UNLOAD: u32 = 16;
+pub const UNLOAD_COMPLETE: u32 = 17;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct MessageHeader {
+    pub message_type: u32,
+    pub padding: u32,
+}
+
+impl MessageHeader {
+    pub const fn new(message_type: u32) -> Self {
+        Self {
+            message_type,
+            padding: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct InitiateContact {
+    pub version_requested: u32,
+    pub target_message_vp: u32,
+    pub interrupt_page_or_target_info: u64,
+    pub parent_to_child_monitor_page_gpa: u64,
+    pub child_to_parent_monitor_page_gpa: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct VersionResponse {
+    pub version_supported: u8,
+    pub connection_state: u8,
+    pub padding: u16,
+    pub selected_version_or_connection_id: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct OfferChannel {
+    pub interface_id: [u8; 16],
+    pub instance_id: [u8; 16],
+    pub rsvd: [u32; 4],
+    pub flags: u16,
+    pub mmio_megabytes: u16,
+    pub user_defined: [u8; 120],
+    pub subchannel_index: u16,
+    pub mmio_megabytes_optional: u16,
+    pub channel_id: u32,
+    pub monitor_id: u8,
+    pub monitor_allocated: u8,
+    pub is_dedicated: u16,
+    pub connection_id: u32,
+}
+
+impl OfferChannel {
+    /// The interface GUID of the offered device.
+    pub fn interface_id(&self) -> uefi::Guid {
+        uefi::Guid::from_bytes(self.interface_id)
+    }
+
+    /// The instance GUID of the offered device.
+    pub fn instance_id(&self) -> uefi::Guid {
+        uefi::Guid::from_bytes(self.instance_id)
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct RescindChannelOffer {
+    pub channel_id: u32,
+}
+
+/// Followed by `count` GPA ranges totalling `len` bytes.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct GpadlHeader {
+    pub channel_id: u32,
+    pub gpadl_id: u32,
+    pub len: u16,
+    pub count: u16,
+}
+
+/// Describes one GPA range inside a GPADL, followed by its page numbers.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct GpaRange {
+    pub len: u32,
+    pub offset: u32,
+}
+
+/// Followed by the page numbers that did not fit into the `GpadlHeader`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct GpadlBody {
+    pub rsvd: u32,
+    pub gpadl_id: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct GpadlCreated {
+    pub channel_id: u32,
+    pub gpadl_id: u32,
+    pub status: i32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct OpenChannel {
+    pub channel_id: u32,
+    pub open_id: u32,
+    pub ring_buffer_gpadl_id: u32,
+    pub target_vp: u32,
+    pub downstream_ring_buffer_page_offset: u32,
+    pub user_data: [u8; 120],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct OpenResult {
+    pub channel_id: u32,
+    pub open_id: u32,
+    pub status: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct CloseChannel {
+    pub channel_id: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct GpadlTeardown {
+    pub channel_id: u32,
+    pub gpadl_id: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct GpadlTorndown {
+    pub gpadl_id: u32,
+}
+
+/// Size in bytes of the header of every VMBus packet.
+pub const PACKET_DESCRIPTOR_SIZE: usize = size_of::<PacketDescriptor>();
+
+pub const PACKET_TYPE_DATA_IN_BAND: u16 = 6;
+pub const PACKET_TYPE_DATA_USING_TRANSFER_PAGES: u16 = 7;
+pub const PACKET_TYPE_DATA_USING_GPA_DIRECT: u16 = 9;
+pub const PACKET_TYPE_COMPLETION: u16 = 11;
+
+pub const PACKET_FLAG_COMPLETION_REQUESTED: u16 = 1;
+
+/// The descriptor at the start of every packet in a channel ring buffer.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct PacketDescriptor {
+    pub packet_type: u16,
+    pub data_offset8: u16,
+    pub length8: u16,
+    pub flags: u16,
+    pub transaction_id: u64,
+}
+
+/// Header following the descriptor of a GPA direct packet, itself followed
+/// by `range_count` [`GpaRange`]s and their page numbers.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct GpaDirectHeader {
+    pub reserved: u32,
+    pub range_count: u32,
+}
+
+/// Header following the descriptor of a transfer page packet, itself
+/// followed by `range_count` [`TransferPageRange`]s.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct TransferPageHeader {
+    pub transfer_page_set_id: u16,
+    pub sender_owns_set: u8,
+    pub reserved: u8,
+    pub range_count: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct TransferPageRange {
+    pub byte_count: u32,
+    pub byte_offset: u32,
+}
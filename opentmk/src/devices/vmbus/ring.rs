@@ -0,0 +1,181 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Guest side of the VMBus channel ring buffers.
+//!
+//! Each ring is a control page followed by the data pages. The control page
+//! starts with the write index, the read index and the interrupt mask.
+
+use alloc::vec::Vec;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::Ordering;
+
+use zerocopy::FromBytes;
+use zerocopy::IntoBytes;
+
+use super::protocol::PACKET_DESCRIPTOR_SIZE;
+use super::protocol::PacketDescriptor;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+const PAGE_SIZE: usize = 4096;
+const FOOTER_SIZE: usize = size_of::<u64>();
+
+/// A packet read from the incoming ring.
+pub struct Packet {
+    /// The packet descriptor.
+    pub descriptor: PacketDescriptor,
+    /// The bytes between the descriptor and the payload, e.g. the transfer
+    /// page ranges of a transfer page packet.
+    pub extension: Vec<u8>,
+    /// The packet payload, without the descriptor and the extension.
+    pub data: Vec<u8>,
+}
+
+/// One direction of a channel ring buffer.
+pub struct Ring {
+    control: *mut AtomicU32,
+    data: *mut u8,
+    data_len: usize,
+}
+
+impl Ring {
+    /// Wrap `page_count` pages starting at `base`, the first one being the
+    /// control page.
+    ///
+    /// # Safety
+    ///
+    /// `base` must point to `page_count` page-aligned pages that stay valid
+    /// for the lifetime of the ring.
+    pub unsafe fn new(base: *mut u8, page_count: usize) -> Self {
+        Ring {
+            control: base.cast(),
+            // SAFETY: guaranteed by the caller.
+            data: unsafe { base.add(PAGE_SIZE) },
+            data_len: (page_count - 1) * PAGE_SIZE,
+        }
+    }
+
+    fn control(&self, index: usize) -> &AtomicU32 {
+        // SAFETY: the control page is valid and suitably aligned for u32 access.
+        unsafe { &*self.control.add(index) }
+    }
+
+    fn write_index(&self) -> &AtomicU32 {
+        self.control(0)
+    }
+
+    fn read_index(&self) -> &AtomicU32 {
+        self.control(1)
+    }
+
+    fn interrupt_mask(&self) -> &AtomicU32 {
+        self.control(2)
+    }
+
+    fn copy_in(&self, offset: usize, bytes: &[u8]) -> usize {
+        let mut offset = offset;
+        for &b in bytes {
+            // SAFETY: `offset` always stays within the data pages.
+            unsafe { self.data.add(offset).write_volatile(b) };
+            offset = (offset + 1) % self.data_len;
+        }
+        offset
+    }
+
+    fn copy_out(&self, offset: usize, bytes: &mut [u8]) -> usize {
+        let mut offset = offset;
+        for b in bytes {
+            // SAFETY: `offset` always stays within the data pages.
+            *b = unsafe { self.data.add(offset).read_volatile() };
+            offset = (offset + 1) % self.data_len;
+        }
+        offset
+    }
+
+    /// Write a packet to the ring.
+    ///
+    /// `extension` is placed between the descriptor and the payload, e.g. the
+    /// page ranges of a GPA direct packet, and must be a multiple of 8 bytes.
+    /// Returns whether the host needs to be signaled.
+    pub fn write(
+        &self,
+        packet_type: u16,
+        flags: u16,
+        transaction_id: u64,
+        extension: &[u8],
+        payload: &[u8],
+    ) -> TmkResult<bool> {
+        if extension.len() % 8 != 0 {
+            return Err(TmkError::InvalidParameter);
+        }
+        let data_offset = PACKET_DESCRIPTOR_SIZE + extension.len();
+        let packet_len = data_offset + payload.len().next_multiple_of(8);
+        let total_len = packet_len + FOOTER_SIZE;
+
+        let write = self.write_index().load(Ordering::Acquire) as usize;
+        let read = self.read_index().load(Ordering::Acquire) as usize;
+        let used = (write + self.data_len - read) % self.data_len;
+        // The ring is never filled completely, otherwise full and empty
+        // would be indistinguishable.
+        if total_len >= self.data_len - used {
+            return Err(TmkError::InsufficientBuffer);
+        }
+
+        let descriptor = PacketDescriptor {
+            packet_type,
+            data_offset8: (data_offset / 8) as u16,
+            length8: (packet_len / 8) as u16,
+            flags,
+            transaction_id,
+        };
+        let mut offset = self.copy_in(write, descriptor.as_bytes());
+        offset = self.copy_in(offset, extension);
+        offset = self.copy_in(offset, payload);
+        let padding = [0u8; 8];
+        offset = self.copy_in(offset, &padding[..packet_len - data_offset - payload.len()]);
+        offset = self.copy_in(offset, &((write as u64) << 32).to_le_bytes());
+
+        self.write_index().store(offset as u32, Ordering::Release);
+        Ok(write == read && self.interrupt_mask().load(Ordering::Acquire) == 0)
+    }
+
+    /// Read the next packet from the ring, if any.
+    pub fn read(&self) -> TmkResult<Option<Packet>> {
+        let write = self.write_index().load(Ordering::Acquire) as usize;
+        let read = self.read_index().load(Ordering::Acquire) as usize;
+        if write == read {
+            return Ok(None);
+        }
+
+        let mut header = [0u8; PACKET_DESCRIPTOR_SIZE];
+        self.copy_out(read, &mut header);
+        let descriptor =
+            PacketDescriptor::read_from_bytes(&header).map_err(|_| TmkError::InvalidParameter)?;
+        let packet_len = descriptor.length8 as usize * 8;
+        let data_offset = descriptor.data_offset8 as usize * 8;
+        if data_offset < PACKET_DESCRIPTOR_SIZE
+            || data_offset > packet_len
+            || packet_len + FOOTER_SIZE > self.data_len
+        {
+            log::error!("malformed packet in ring: {:?}", descriptor);
+            return Err(TmkError::InvalidParameter);
+        }
+
+        let mut extension = vec![0u8; data_offset - PACKET_DESCRIPTOR_SIZE];
+        let offset = self.copy_out(
+            (read + PACKET_DESCRIPTOR_SIZE) % self.data_len,
+            &mut extension,
+        );
+        let mut data = vec![0u8; packet_len - data_offset];
+        self.copy_out(offset, &mut data);
+
+        let next = (read + packet_len + FOOTER_SIZE) % self.data_len;
+        self.read_index().store(next as u32, Ordering::Release);
+        Ok(Some(Packet {
+            descriptor,
+            extension,
+            data,
+        }))
+    }
+}
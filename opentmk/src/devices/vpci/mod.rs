@@ -0,0 +1,353 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Minimal guest side of the Virtual PCI (VPCI) VMBus protocol.
+//!
+//! The client enumerates the devices assigned to the VM, reads and writes
+//! their config space and assigns their memory BARs, which is enough to
+//! exercise the VPCI path of the host without a guest OS PCI stack.
+//!
+//! Config space is not carried over the channel itself: the host maps it
+//! into a two page MMIO window whose address the guest picks when moving
+//! the bus to D0. The window and the BAR addresses must lie in MMIO space
+//! that the caller knows to be free, such as the range the harness names
+//! in [`MMIO_VARIABLE`].
+
+pub mod protocol;
+
+use alloc::vec::Vec;
+
+use protocol::DeviceDescription;
+use protocol::DeviceDescription2;
+use protocol::PnpId;
+use protocol::SlotNumber;
+use spin::Mutex;
+use zerocopy::FromBytes;
+use zerocopy::FromZeros;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+
+use crate::devices::vmbus::Channel;
+use crate::devices::vmbus::VmbusClient;
+use crate::devices::vmbus::protocol::PACKET_TYPE_COMPLETION;
+use crate::devices::vmbus::protocol::PACKET_TYPE_DATA_IN_BAND;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// Ring size, in pages per direction, used for the VPCI channel.
+const VPCI_RING_PAGES: usize = 4;
+
+/// Name of the UEFI variable holding the GPA of free MMIO space for the
+/// config window and the BARs.
+pub const MMIO_VARIABLE: &str = "OpenTmkVpciMmio";
+/// Vendor GUID of [`MMIO_VARIABLE`], shared with the scenario variable.
+pub const MMIO_VARIABLE_VENDOR: uefi::Guid = crate::scenario::SCENARIO_VARIABLE_VENDOR;
+
+/// GPA of the free MMIO space named by the harness, if any.
+static MMIO_GPA: Mutex<Option<u64>> = Mutex::new(None);
+
+/// Records the GPA found in [`MMIO_VARIABLE`].
+pub(crate) fn set_mmio_gpa(gpa: u64) {
+    *MMIO_GPA.lock() = Some(gpa);
+}
+
+/// Returns the GPA of the MMIO space the harness set aside for VPCI,
+/// `None` if it named none.
+pub fn mmio_gpa() -> Option<u64> {
+    *MMIO_GPA.lock()
+}
+
+/// Config space offset of the command register.
+const CFG_COMMAND: u16 = 0x4;
+/// Config space offset of the first BAR.
+const CFG_BAR0: u16 = 0x10;
+/// Command register bit enabling memory space decoding.
+const COMMAND_MEMORY_ENABLE: u32 = 1 << 1;
+
+/// A device reported by the host.
+#[derive(Copy, Clone, Debug)]
+pub struct VpciDevice {
+    /// The slot used to address the device.
+    pub slot: SlotNumber,
+    /// The PCI IDs of the device.
+    pub pnp_id: PnpId,
+    /// The serial number assigned by the host.
+    pub serial_num: u32,
+}
+
+/// A memory BAR assigned by [`VpciClient::map_bars`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Bar {
+    /// Index of the (first) BAR register.
+    pub index: u8,
+    /// The assigned guest physical address.
+    pub address: u64,
+    /// Size of the BAR in bytes.
+    pub size: u64,
+    /// Whether the BAR spans two registers.
+    pub is_64bit: bool,
+}
+
+/// An open VPCI channel.
+pub struct VpciClient {
+    vmbus: VmbusClient,
+    channel: Channel,
+    version: u32,
+    config_window: u64,
+    devices: Vec<VpciDevice>,
+}
+
+impl VpciClient {
+    /// Open the first VPCI channel offered on `vmbus`.
+    ///
+    /// `config_window` is the guest physical address of the two free MMIO
+    /// pages the host maps the config space of the selected slot into.
+    pub fn open(mut vmbus: VmbusClient, config_window: u64) -> TmkResult<Self> {
+        if config_window % hvdef::HV_PAGE_SIZE != 0 {
+            return Err(TmkError::InvalidParameter);
+        }
+        let offer = vmbus
+            .find_offer(protocol::VPCI_INTERFACE_ID)
+            .ok_or(TmkError::NotFound)?;
+        let channel = vmbus.open_channel(&offer, VPCI_RING_PAGES)?;
+        Ok(VpciClient {
+            vmbus,
+            channel,
+            version: 0,
+            config_window,
+            devices: Vec::new(),
+        })
+    }
+
+    /// The negotiated protocol version, 0 before [`VpciClient::negotiate`].
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// The devices reported by the host so far.
+    pub fn devices(&self) -> &[VpciDevice] {
+        &self.devices
+    }
+
+    /// Negotiate the newest protocol version supported by the host.
+    pub fn negotiate(&mut self) -> TmkResult<u32> {
+        for version in protocol::SUPPORTED_VERSIONS {
+            let request = protocol::QueryProtocolVersion {
+                message_type: protocol::MESSAGE_QUERY_PROTOCOL_VERSION,
+                protocol_version: version,
+            };
+            let reply = self.transact(request.as_bytes())?;
+            let (reply, _) = protocol::QueryProtocolVersionReply::read_from_prefix(&reply)
+                .map_err(|_| TmkError::InvalidParameter)?;
+            if reply.status == protocol::STATUS_SUCCESS {
+                log::info!("vpci version {:#x} accepted", version);
+                self.version = version;
+                return Ok(version);
+            }
+            log::debug!("vpci version {:#x} rejected: {:#x}", version, reply.status);
+        }
+        log::error!("no common vpci version with host");
+        Err(TmkError::FeatureUnavailable)
+    }
+
+    /// Move the bus to D0, which maps the config window and makes the host
+    /// report the assigned devices.
+    pub fn enter_d0(&mut self) -> TmkResult<&[VpciDevice]> {
+        let request = protocol::FdoD0Entry {
+            message_type: protocol::MESSAGE_FDO_D0_ENTRY,
+            padding: 0,
+            mmio_start: self.config_window,
+        };
+        let reply = self.transact(request.as_bytes())?;
+        let status = u32::read_from_prefix(&reply)
+            .map_err(|_| TmkError::InvalidParameter)?
+            .0;
+        if status != protocol::STATUS_SUCCESS {
+            log::error!("vpci fdo d0 entry failed: {:#x}", status);
+            return Err(TmkError::OperationFailed);
+        }
+        // The device list may trail the completion.
+        if self.devices.is_empty() {
+            let packet = self.vmbus.recv(&mut self.channel)?;
+            self.handle_packet(&packet.data)?;
+        }
+        Ok(&self.devices)
+    }
+
+    /// Read the config space dword at `offset` of `slot`.
+    pub fn read_config(&mut self, slot: SlotNumber, offset: u16) -> u32 {
+        let ptr = self.select_slot(slot, offset);
+        // SAFETY: the config window was handed to the host in `enter_d0`
+        // and is identity mapped.
+        unsafe { ptr.read_volatile() }
+    }
+
+    /// Write the config space dword at `offset` of `slot`.
+    pub fn write_config(&mut self, slot: SlotNumber, offset: u16, value: u32) {
+        let ptr = self.select_slot(slot, offset);
+        // SAFETY: the config window was handed to the host in `enter_d0`
+        // and is identity mapped.
+        unsafe { ptr.write_volatile(value) };
+    }
+
+    /// Assign the memory BARs of `slot` from `mmio_base` upwards, each
+    /// naturally aligned, and enable memory decoding.
+    pub fn map_bars(&mut self, slot: SlotNumber, mmio_base: u64) -> TmkResult<Vec<Bar>> {
+        let request = protocol::QueryResourceRequirements {
+            message_type: protocol::MESSAGE_CURRENT_RESOURCE_REQUIREMENTS,
+            slot,
+        };
+        let reply = self.transact(request.as_bytes())?;
+        let (reply, _) = protocol::QueryResourceRequirementsReply::read_from_prefix(&reply)
+            .map_err(|_| TmkError::InvalidParameter)?;
+        if reply.status != protocol::STATUS_SUCCESS {
+            log::error!("vpci resource query failed: {:#x}", reply.status);
+            return Err(TmkError::OperationFailed);
+        }
+
+        let mut bars = Vec::new();
+        let mut next = mmio_base;
+        let mut i = 0;
+        while i < reply.bars.len() {
+            let mask = reply.bars[i];
+            // I/O BARs and unimplemented BARs are skipped.
+            if mask & 1 != 0 || mask == 0 {
+                i += 1;
+                continue;
+            }
+            let is_64bit = (mask >> 1) & 0x3 == 0x2;
+            let mask = if is_64bit {
+                let upper = *reply.bars.get(i + 1).ok_or(TmkError::InvalidParameter)?;
+                (upper as u64) << 32 | (mask & !0xf) as u64
+            } else {
+                (mask & !0xf) as u64 | 0xffff_ffff_0000_0000
+            };
+            let size = (!mask).wrapping_add(1);
+            let address = next.next_multiple_of(size);
+            next = address + size;
+
+            let offset = CFG_BAR0 + 4 * i as u16;
+            self.write_config(slot, offset, address as u32);
+            if is_64bit {
+                self.write_config(slot, offset + 4, (address >> 32) as u32);
+            }
+            bars.push(Bar {
+                index: i as u8,
+                address,
+                size,
+                is_64bit,
+            });
+            i += if is_64bit { 2 } else { 1 };
+        }
+
+        let request = protocol::DeviceTranslate {
+            message_type: protocol::MESSAGE_ASSIGNED_RESOURCES,
+            slot,
+            ..FromZeros::new_zeroed()
+        };
+        let mut payload = Vec::new();
+        payload.extend_from_slice(request.as_bytes());
+        payload.extend_from_slice(&[0; protocol::MSI_RESOURCE3_SIZE]);
+        let reply = self.transact(&payload)?;
+        let status = u32::read_from_prefix(&reply)
+            .map_err(|_| TmkError::InvalidParameter)?
+            .0;
+        if status != protocol::STATUS_SUCCESS {
+            log::error!("vpci assigned resources failed: {:#x}", status);
+            return Err(TmkError::OperationFailed);
+        }
+
+        let command = self.read_config(slot, CFG_COMMAND);
+        self.write_config(slot, CFG_COMMAND, command | COMMAND_MEMORY_ENABLE);
+        log::info!(
+            "mapped {} bars for vpci slot {:#x}",
+            bars.len(),
+            u32::from(slot)
+        );
+        Ok(bars)
+    }
+
+    /// Close the channel and hand back the VMBus connection.
+    pub fn close(self) -> TmkResult<VmbusClient> {
+        let mut vmbus = self.vmbus;
+        vmbus.close_channel(self.channel)?;
+        Ok(vmbus)
+    }
+
+    fn select_slot(&mut self, slot: SlotNumber, offset: u16) -> *mut u32 {
+        let select = (self.config_window + protocol::MMIO_PAGE_SLOT_NUMBER) as *mut u32;
+        // SAFETY: the config window was handed to the host in `enter_d0`
+        // and is identity mapped.
+        unsafe { select.write_volatile(slot.into()) };
+        (self.config_window + protocol::MMIO_PAGE_CONFIG_SPACE + (offset & 0xffc) as u64)
+            as *mut u32
+    }
+
+    /// Send `payload` with a completion requested and return the completion
+    /// data, handling bus relations that arrive in the meantime.
+    fn transact(&mut self, payload: &[u8]) -> TmkResult<Vec<u8>> {
+        let transaction_id = self.vmbus.send(&mut self.channel, payload, true)?;
+        loop {
+            let packet = self.vmbus.recv(&mut self.channel)?;
+            match packet.descriptor.packet_type {
+                PACKET_TYPE_COMPLETION if packet.descriptor.transaction_id == transaction_id => {
+                    return Ok(packet.data);
+                }
+                PACKET_TYPE_DATA_IN_BAND => self.handle_packet(&packet.data)?,
+                _ => log::debug!("ignoring vpci packet {:?}", packet.descriptor),
+            }
+        }
+    }
+
+    fn handle_packet(&mut self, data: &[u8]) -> TmkResult<()> {
+        let (relations, rest) = protocol::QueryBusRelations::read_from_prefix(data)
+            .map_err(|_| TmkError::InvalidParameter)?;
+        let count = relations.device_count as usize;
+        match relations.message_type {
+            protocol::MESSAGE_BUS_RELATIONS => {
+                self.devices = parse_devices::<DeviceDescription>(rest, count)?
+                    .into_iter()
+                    .map(|d| VpciDevice {
+                        slot: d.slot,
+                        pnp_id: d.pnp_id,
+                        serial_num: d.serial_num,
+                    })
+                    .collect();
+            }
+            protocol::MESSAGE_BUS_RELATIONS2 => {
+                self.devices = parse_devices::<DeviceDescription2>(rest, count)?
+                    .into_iter()
+                    .map(|d| VpciDevice {
+                        slot: d.slot,
+                        pnp_id: d.pnp_id,
+                        serial_num: d.serial_num,
+                    })
+                    .collect();
+            }
+            message_type => {
+                log::debug!("ignoring vpci message {:#x}", message_type);
+                return Ok(());
+            }
+        }
+        for device in &self.devices {
+            log::info!(
+                "vpci device at slot {:#x}: {:04x}:{:04x}",
+                u32::from(device.slot),
+                device.pnp_id.vendor_id,
+                device.pnp_id.device_id
+            );
+        }
+        Ok(())
+    }
+}
+
+fn parse_devices<T: FromBytes + Immutable>(data: &[u8], count: usize) -> TmkResult<Vec<T>> {
+    if data.len() < count * size_of::<T>() {
+        return Err(TmkError::InvalidParameter);
+    }
+    Ok(data
+        .chunks_exact(size_of::<T>())
+        .take(count)
+        .filter_map(|chunk| T::read_from_bytes(chunk).ok())
+        .collect())
+}
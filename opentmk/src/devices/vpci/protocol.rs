@@ -0,0 +1,157 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Virtual PCI (VPCI) VMBus protocol messages, as used by the guest side.
+
+//! NOTE: this is a hand-rolled subset of `vpci_protocol`, which needs `std`.
+//! Only the messages required to enumerate devices and assign their BARs
+//! are defined.
+
+#![expect(missing_docs)]
+
+use bitfield_struct::bitfield;
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+/// Interface ID of the VPCI VMBus device.
+pub const VPCI_INTERFACE_ID: uefi::Guid = uefi::guid!("44c4f61d-4444-4400-9d52-802e27ede19f");
+
+/// Offset in the config window of the register selecting the slot.
+pub const MMIO_PAGE_SLOT_NUMBER: u64 = 0;
+/// Offset in the config window of the selected slot's config space.
+pub const MMIO_PAGE_CONFIG_SPACE: u64 = 0x1000;
+/// Size of the config window passed in `FdoD0Entry`.
+pub const MMIO_WINDOW_SIZE: u64 = 0x2000;
+
+pub const MESSAGE_BUS_RELATIONS: u32 = 0x42490000;
+pub const MESSAGE_CURRENT_RESOURCE_REQUIREMENTS: u32 = 0x42490005;
+pub const MESSAGE_FDO_D0_ENTRY: u32 = 0x42490007;
+pub const MESSAGE_EJECT: u32 = 0x4249000b;
+pub const MESSAGE_ASSIGNED_RESOURCES: u32 = 0x42490010;
+pub const MESSAGE_QUERY_PROTOCOL_VERSION: u32 = 0x42490013;
+pub const MESSAGE_BUS_RELATIONS2: u32 = 0x42490019;
+
+pub const VERSION_WIN10: u32 = 0x00010001;
+pub const VERSION_RS1: u32 = 0x00010002;
+pub const VERSION_VB: u32 = 0x00010003;
+
+/// Versions requested during negotiation, newest first.
+pub const SUPPORTED_VERSIONS: [u32; 3] = [VERSION_VB, VERSION_RS1, VERSION_WIN10];
+
+pub const STATUS_SUCCESS: u32 = 0;
+
+/// Size of the MSI resource that follows `DeviceTranslate`, which Hyper-V
+/// expects even when no interrupts are requested.
+pub const MSI_RESOURCE3_SIZE: usize = 80;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct QueryProtocolVersion {
+    pub message_type: u32,
+    pub protocol_version: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct QueryProtocolVersionReply {
+    pub status: u32,
+    pub protocol_version: u32,
+}
+
+#[bitfield(u32)]
+#[derive(IntoBytes, FromBytes, Immutable, KnownLayout, PartialEq, Eq)]
+pub struct SlotNumber {
+    #[bits(5)]
+    pub device: u8,
+    #[bits(3)]
+    pub function: u8,
+    #[bits(24)]
+    _reserved: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct PnpId {
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub revision_id: u8,
+    pub prog_if: u8,
+    pub sub_class: u8,
+    pub base_class: u8,
+    pub sub_vendor_id: u16,
+    pub sub_system_id: u16,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct DeviceDescription {
+    pub pnp_id: PnpId,
+    pub slot: SlotNumber,
+    pub serial_num: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct DeviceDescription2 {
+    pub pnp_id: PnpId,
+    pub slot: SlotNumber,
+    pub serial_num: u32,
+    pub flags: u32,
+    pub numa_node: u16,
+    pub rsvd: u16,
+}
+
+/// Followed by `device_count` `DeviceDescription`s, or
+/// `DeviceDescription2`s for `MESSAGE_BUS_RELATIONS2`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct QueryBusRelations {
+    pub message_type: u32,
+    pub device_count: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct FdoD0Entry {
+    pub message_type: u32,
+    pub padding: u32,
+    pub mmio_start: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct QueryResourceRequirements {
+    pub message_type: u32,
+    pub slot: SlotNumber,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct QueryResourceRequirementsReply {
+    pub status: u32,
+    pub bars: [u32; 6],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct PartialResourceDescriptor {
+    pub resource_type: u8,
+    pub share_disposition: u8,
+    pub flags: u16,
+    pub address: [u32; 2],
+    pub adjusted_len: u32,
+    pub padding: u32,
+}
+
+/// Followed by `msi_resource_count` MSI resources.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct DeviceTranslate {
+    pub message_type: u32,
+    pub slot: SlotNumber,
+    pub mmio_resources: [PartialResourceDescriptor; 6],
+    pub msi_resource_count: u32,
+    pub reserved: u32,
+}
@@ -0,0 +1,265 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Cooperative fibers to interleave test activities on one VP.
+//!
+//! A [`Scheduler`] runs closures on their own stacks, round robin, until
+//! they all return. Fibers are never preempted: each one gives the VP back
+//! through its [`Yielder`], either unconditionally with
+//! [`Yielder::yield_now`], while waiting with [`Yielder::wait_until`], or
+//! once its time slice is used up with [`Yielder::checkpoint`]. This lets a
+//! single VP poll a mailbox while also counting timer ticks, say, instead
+//! of dedicating a VP to each background activity.
+//!
+//! Fibers only switch on the VP running [`Scheduler::run`]. Interrupt
+//! handlers run on the stack of whichever fiber was interrupted, so fiber
+//! stacks must leave room for them.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use crate::arch::cycles;
+use crate::arch::fiber::prepare_stack;
+use crate::arch::fiber::switch_stack;
+
+/// Default size of a fiber stack.
+pub const DEFAULT_STACK_SIZE: usize = 256 * 1024;
+/// Default time slice, in cycles.
+pub const DEFAULT_TIME_SLICE: u64 = 1_000_000;
+
+/// State shared between a fiber and the scheduler running it.
+struct Switch {
+    /// Stack pointer of the scheduler while the fiber runs.
+    scheduler_sp: u64,
+    /// Stack pointer of the fiber while it is suspended.
+    fiber_sp: u64,
+    finished: bool,
+    slice: u64,
+    resumed_at: u64,
+}
+
+/// Handle through which a fiber gives the VP back to its scheduler.
+pub struct Yielder {
+    switch: *mut Switch,
+}
+
+impl Yielder {
+    /// Suspends the fiber until the scheduler resumes it, after the other
+    /// fibers had their turn.
+    pub fn yield_now(&self) {
+        // SAFETY: the switch state lives as long as the fiber, which is only
+        // running while the scheduler is suspended at `scheduler_sp`.
+        unsafe {
+            switch_stack(
+                &raw mut (*self.switch).fiber_sp,
+                (*self.switch).scheduler_sp,
+            )
+        }
+    }
+
+    /// Returns whether the fiber used up its time slice since it was last
+    /// resumed.
+    pub fn slice_expired(&self) -> bool {
+        // SAFETY: the switch state lives as long as the fiber and the
+        // scheduler does not touch it while the fiber runs.
+        let switch = unsafe { &*self.switch };
+        cycles::read().wrapping_sub(switch.resumed_at) >= switch.slice
+    }
+
+    /// Yields if the fiber used up its time slice. Long running loops call
+    /// this on every iteration.
+    pub fn checkpoint(&self) {
+        if self.slice_expired() {
+            self.yield_now();
+        }
+    }
+
+    /// Yields until `cond` holds.
+    pub fn wait_until(&self, mut cond: impl FnMut() -> bool) {
+        while !cond() {
+            self.yield_now();
+        }
+    }
+}
+
+struct Fiber<'a> {
+    name: &'static str,
+    /// Owned, freed on drop. Boxed so that its address, held by the
+    /// fiber's [`Yielder`], is stable.
+    switch: *mut Switch,
+    /// Kept alive while the fiber may run.
+    _stack: Vec<u8>,
+    /// The entry point, taken on the first resume.
+    _entry: Box<Option<Box<dyn FnOnce() + 'a>>>,
+    resumes: u64,
+}
+
+impl Drop for Fiber<'_> {
+    fn drop(&mut self) {
+        // SAFETY: the pointer comes from `Box::into_raw` in
+        // `Scheduler::spawn` and the fiber no longer runs.
+        drop(unsafe { Box::from_raw(self.switch) });
+    }
+}
+
+/// How a fiber ran.
+#[derive(Clone, Debug)]
+pub struct FiberSummary {
+    /// The name the fiber was spawned with.
+    pub name: &'static str,
+    /// Number of times the fiber was resumed, including the first.
+    pub resumes: u64,
+}
+
+/// Runs fibers round robin on the current VP.
+pub struct Scheduler<'a> {
+    fibers: Vec<Fiber<'a>>,
+    stack_size: usize,
+    slice: u64,
+}
+
+impl Default for Scheduler<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Scheduler<'a> {
+    /// A scheduler with no fibers, [`DEFAULT_STACK_SIZE`] stacks and a
+    /// [`DEFAULT_TIME_SLICE`] time slice.
+    pub fn new() -> Self {
+        Self {
+            fibers: Vec::new(),
+            stack_size: DEFAULT_STACK_SIZE,
+            slice: DEFAULT_TIME_SLICE,
+        }
+    }
+
+    /// Use `size` byte stacks for the fibers spawned from now on.
+    pub fn with_stack_size(mut self, size: usize) -> Self {
+        self.stack_size = size;
+        self
+    }
+
+    /// Let fibers run for `cycles` before [`Yielder::checkpoint`] yields.
+    pub fn with_time_slice(mut self, cycles: u64) -> Self {
+        self.slice = cycles;
+        self
+    }
+
+    /// Adds a fiber running `f`. It starts when [`Self::run`] is called.
+    pub fn spawn(&mut self, name: &'static str, f: impl FnOnce(&Yielder) + 'a) {
+        let switch = Box::into_raw(Box::new(Switch {
+            scheduler_sp: 0,
+            fiber_sp: 0,
+            finished: false,
+            slice: self.slice,
+            resumed_at: 0,
+        }));
+        let yielder = Yielder { switch };
+        let entry: Box<dyn FnOnce() + 'a> = Box::new(move || {
+            f(&yielder);
+            // SAFETY: the fiber is running, so the scheduler does not touch
+            // the switch state.
+            unsafe { (*yielder.switch).finished = true };
+            // Finished fibers are never resumed.
+            yielder.yield_now();
+            unreachable!("finished fiber resumed");
+        });
+        let mut entry = Box::new(Some(entry));
+        let mut stack = vec![0; self.stack_size];
+        // SAFETY: the stack and the entry are kept alive by the fiber, the
+        // entry never returns.
+        let sp = unsafe { prepare_stack(&mut stack, &raw mut *entry) };
+        // SAFETY: the switch state was just allocated and nothing else
+        // accesses it yet.
+        unsafe { (*switch).fiber_sp = sp };
+        self.fibers.push(Fiber {
+            name,
+            switch,
+            _stack: stack,
+            _entry: entry,
+            resumes: 0,
+        });
+    }
+
+    /// Runs the fibers round robin until they have all returned.
+    pub fn run(mut self) -> Vec<FiberSummary> {
+        loop {
+            let mut running = false;
+            for fiber in &mut self.fibers {
+                let switch = fiber.switch;
+                // SAFETY: the fiber is suspended, so the scheduler owns the
+                // switch state until it switches to the fiber, which only
+                // switches back to the stack pointer stored here.
+                unsafe {
+                    if (*switch).finished {
+                        continue;
+                    }
+                    (*switch).resumed_at = cycles::read();
+                    switch_stack(&raw mut (*switch).scheduler_sp, (*switch).fiber_sp);
+                }
+                running = true;
+                fiber.resumes += 1;
+            }
+            if !running {
+                break;
+            }
+        }
+        self.fibers
+            .iter()
+            .map(|f| FiberSummary {
+                name: f.name,
+                resumes: f.resumes,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::rc::Rc;
+    use core::cell::RefCell;
+
+    use super::*;
+
+    #[test]
+    fn test_round_robin() {
+        let order = Rc::new(RefCell::new(Vec::new()));
+        let mut scheduler = Scheduler::new().with_stack_size(64 * 1024);
+        for id in 0..2u32 {
+            let order = order.clone();
+            scheduler.spawn("worker", move |y| {
+                for i in 0..3 {
+                    order.borrow_mut().push((id, i));
+                    y.yield_now();
+                }
+            });
+        }
+        let summary = scheduler.run();
+        assert_eq!(
+            *order.borrow(),
+            [(0, 0), (1, 0), (0, 1), (1, 1), (0, 2), (1, 2)]
+        );
+        assert!(summary.iter().all(|s| s.resumes == 4));
+    }
+
+    #[test]
+    fn test_wait_until() {
+        let flag = RefCell::new(false);
+        let seen = RefCell::new(false);
+        let mut scheduler = Scheduler::new()
+            .with_stack_size(64 * 1024)
+            .with_time_slice(0);
+        scheduler.spawn("waiter", |y| {
+            y.wait_until(|| *flag.borrow());
+            *seen.borrow_mut() = true;
+        });
+        scheduler.spawn("setter", |y| {
+            y.checkpoint();
+            *flag.borrow_mut() = true;
+        });
+        scheduler.run();
+        assert!(*seen.borrow());
+    }
+}
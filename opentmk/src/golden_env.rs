@@ -0,0 +1,484 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Golden environment: drift of the host configuration.
+//!
+//! A run on a host whose configuration drifted, e.g. a processor feature
+//! masked or an MSR no longer emulated, fails tests in ways that say little
+//! about the cause. The harness may keep the CPUID leaves and MSRs of a
+//! known good run as a baseline and hand it to the TMK in
+//! [`GOLDEN_ENV_VARIABLE`]. Once boot services are exited, before the tests
+//! running without them, [`check`] reads the same leaves and MSRs on the
+//! BSP and writes an `environment_diff` record with what differs, so that
+//! the drift surfaces ahead of the failures it causes.
+//!
+//! The baseline is a `;` or newline separated list of entries, `#` starting
+//! a comment:
+//!
+//! ```text
+//! cpuid <leaf> <subleaf> <eax> <ebx> <ecx> <edx>
+//! msr <index> <value>
+//! ```
+//!
+//! Numbers are decimal or `0x` prefixed hexadecimal. A register or MSR
+//! value of `*` matches anything, e.g. for fields holding the APIC ID, and
+//! an MSR value of `fault` expects reads to raise #GP.
+//!
+//! Every run also writes an `environment` record with the live values, of
+//! the baseline entries or of a default set of leaves and MSRs without one.
+//! Its `baseline` field holds them in the format above, which the harness
+//! stores as is to make a run the baseline of the next ones.
+
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+use alloc::string::String;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::fmt;
+
+use serde::Serialize;
+use spin::Mutex;
+
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// Name of the UEFI variable holding the baseline.
+pub const GOLDEN_ENV_VARIABLE: &str = "OpenTmkGoldenEnv";
+/// Vendor GUID of [`GOLDEN_ENV_VARIABLE`], shared with the scenario
+/// variable.
+pub const GOLDEN_ENV_VARIABLE_VENDOR: uefi::Guid = crate::scenario::SCENARIO_VARIABLE_VENDOR;
+
+/// Names of the CPUID registers, in the order of [`CpuidEntry::registers`].
+const CPUID_REGISTERS: [&str; 4] = ["eax", "ebx", "ecx", "edx"];
+
+/// CPUID leaves and subleaves captured without a baseline.
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+const DEFAULT_CPUID: &[(u32, u32)] = &[
+    (0x0, 0),
+    (0x1, 0),
+    (0x7, 0),
+    (0xd, 0),
+    (0x4000_0000, 0),
+    (0x4000_0001, 0),
+    (0x4000_0003, 0),
+    (0x4000_0004, 0),
+    (0x8000_0000, 0),
+    (0x8000_0001, 0),
+    (0x8000_0008, 0),
+];
+
+/// MSRs captured without a baseline: MTRRCAP, PAT, MTRR_DEF_TYPE, EFER and
+/// the Hyper-V VP index.
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+const DEFAULT_MSRS: &[u32] = &[0xfe, 0x277, 0x2ff, 0xc000_0080, 0x4000_0002];
+
+/// A CPUID leaf.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct CpuidEntry {
+    /// The leaf, EAX.
+    pub leaf: u32,
+    /// The subleaf, ECX.
+    pub subleaf: u32,
+    /// EAX, EBX, ECX and EDX, `None` for any value.
+    pub registers: [Option<u32>; 4],
+}
+
+/// What reading an MSR does.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MsrValue {
+    /// Returns the value.
+    Value(u64),
+    /// Raises #GP.
+    Fault,
+    /// Anything, in a baseline.
+    Any,
+}
+
+/// An MSR.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct MsrEntry {
+    /// The index of the MSR.
+    pub msr: u32,
+    /// What reading it does.
+    pub value: MsrValue,
+}
+
+/// CPUID leaves and MSRs, of a baseline or as read on the BSP.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Environment {
+    /// The CPUID leaves.
+    pub cpuid: Vec<CpuidEntry>,
+    /// The MSRs.
+    pub msrs: Vec<MsrEntry>,
+}
+
+fn parse_number(text: &str) -> TmkResult<u64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse(),
+    }
+    .map_err(|_| TmkError::InvalidParameter)
+}
+
+fn parse_u32(text: &str) -> TmkResult<u32> {
+    parse_number(text)?
+        .try_into()
+        .map_err(|_| TmkError::InvalidParameter)
+}
+
+/// Parses a baseline in the format of the module documentation.
+pub fn parse_baseline(text: &str) -> TmkResult<Environment> {
+    let mut env = Environment::default();
+    let entries = text
+        .split([';', '\n'])
+        .map(|entry| entry.split('#').next().unwrap().trim())
+        .filter(|entry| !entry.is_empty());
+    for entry in entries {
+        let words: Vec<&str> = entry.split_whitespace().collect();
+        match words[..] {
+            ["cpuid", leaf, subleaf, eax, ebx, ecx, edx] => {
+                let mut registers = [None; 4];
+                for (register, text) in registers.iter_mut().zip([eax, ebx, ecx, edx]) {
+                    if text != "*" {
+                        *register = Some(parse_u32(text)?);
+                    }
+                }
+                env.cpuid.push(CpuidEntry {
+                    leaf: parse_u32(leaf)?,
+                    subleaf: parse_u32(subleaf)?,
+                    registers,
+                });
+            }
+            ["msr", msr, value] => env.msrs.push(MsrEntry {
+                msr: parse_u32(msr)?,
+                value: match value {
+                    "*" => MsrValue::Any,
+                    "fault" => MsrValue::Fault,
+                    value => MsrValue::Value(parse_number(value)?),
+                },
+            }),
+            _ => return Err(TmkError::InvalidParameter),
+        }
+    }
+    Ok(env)
+}
+
+/// Writes the environment as a baseline, one entry per line.
+impl fmt::Display for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in &self.cpuid {
+            write!(f, "cpuid {:#x} {:#x}", entry.leaf, entry.subleaf)?;
+            for register in entry.registers {
+                match register {
+                    Some(value) => write!(f, " {:#x}", value)?,
+                    None => f.write_str(" *")?,
+                }
+            }
+            f.write_str("\n")?;
+        }
+        for entry in &self.msrs {
+            match entry.value {
+                MsrValue::Value(value) => writeln!(f, "msr {:#x} {:#x}", entry.msr, value)?,
+                MsrValue::Fault => writeln!(f, "msr {:#x} fault", entry.msr)?,
+                MsrValue::Any => writeln!(f, "msr {:#x} *", entry.msr)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A difference between the baseline and the live environment.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Drift {
+    /// A CPUID register.
+    Cpuid {
+        /// The leaf.
+        leaf: u32,
+        /// The subleaf.
+        subleaf: u32,
+        /// The register, e.g. `ecx`.
+        register: &'static str,
+        /// The value of the baseline.
+        expected: u32,
+        /// The live value.
+        actual: u32,
+    },
+    /// An MSR.
+    Msr {
+        /// The index of the MSR.
+        msr: u32,
+        /// What the baseline reads.
+        expected: MsrValue,
+        /// What reading it live does.
+        actual: MsrValue,
+    },
+}
+
+/// What [`diff`] found.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct EnvironmentDiff {
+    /// Entries of the baseline found in the live environment.
+    pub checked: usize,
+    /// Entries of the baseline missing from the live environment, e.g. MSRs
+    /// on builds that cannot probe them.
+    pub unchecked: usize,
+    /// What differs.
+    pub drifts: Vec<Drift>,
+}
+
+/// Compares the `live` environment with the `baseline`, entry by entry.
+pub fn diff(baseline: &Environment, live: &Environment) -> EnvironmentDiff {
+    let mut result = EnvironmentDiff::default();
+    for expected in &baseline.cpuid {
+        let Some(actual) = live
+            .cpuid
+            .iter()
+            .find(|e| e.leaf == expected.leaf && e.subleaf == expected.subleaf)
+        else {
+            result.unchecked += 1;
+            continue;
+        };
+        result.checked += 1;
+        for ((name, expected_value), actual_value) in CPUID_REGISTERS
+            .into_iter()
+            .zip(expected.registers)
+            .zip(actual.registers)
+        {
+            if let (Some(expected_value), Some(actual_value)) = (expected_value, actual_value)
+                && expected_value != actual_value
+            {
+                result.drifts.push(Drift::Cpuid {
+                    leaf: expected.leaf,
+                    subleaf: expected.subleaf,
+                    register: name,
+                    expected: expected_value,
+                    actual: actual_value,
+                });
+            }
+        }
+    }
+    for expected in &baseline.msrs {
+        let Some(actual) = live.msrs.iter().find(|e| e.msr == expected.msr) else {
+            result.unchecked += 1;
+            continue;
+        };
+        result.checked += 1;
+        let matches = matches!(expected.value, MsrValue::Any)
+            || matches!(actual.value, MsrValue::Any)
+            || expected.value == actual.value;
+        if !matches {
+            result.drifts.push(Drift::Msr {
+                msr: expected.msr,
+                expected: expected.value,
+                actual: actual.value,
+            });
+        }
+    }
+    result
+}
+
+static BASELINE: Mutex<Option<Environment>> = Mutex::new(None);
+
+/// Records the baseline the harness provided.
+pub(crate) fn set_baseline(baseline: Environment) {
+    log::info!(
+        "golden environment: {} CPUID leaves, {} MSRs",
+        baseline.cpuid.len(),
+        baseline.msrs.len()
+    );
+    *BASELINE.lock() = Some(baseline);
+}
+
+/// Returns the baseline the harness provided, if any.
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub fn baseline() -> Option<Environment> {
+    BASELINE.lock().clone()
+}
+
+/// Reads the CPUID leaves and MSRs of `entries` on the current VP. MSRs
+/// are only read on builds with #GP recovery, see
+/// [`crate::arch::fault::probe_rdmsr`], and left out otherwise.
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub fn capture(entries: &Environment) -> Environment {
+    let cpuid = entries
+        .cpuid
+        .iter()
+        .map(|entry| {
+            // SAFETY: CPUID is always available on x86_64, and returns the
+            // data of the highest leaf for leaves out of range.
+            let r = unsafe { core::arch::x86_64::__cpuid_count(entry.leaf, entry.subleaf) };
+            CpuidEntry {
+                leaf: entry.leaf,
+                subleaf: entry.subleaf,
+                registers: [Some(r.eax), Some(r.ebx), Some(r.ecx), Some(r.edx)],
+            }
+        })
+        .collect();
+    #[cfg(nightly)]
+    let msrs = entries
+        .msrs
+        .iter()
+        .map(|entry| MsrEntry {
+            msr: entry.msr,
+            value: match crate::arch::fault::probe_rdmsr(entry.msr) {
+                Ok(value) => MsrValue::Value(value),
+                Err(_) => MsrValue::Fault,
+            },
+        })
+        .collect();
+    #[cfg(not(nightly))]
+    let msrs = Vec::new();
+    Environment { cpuid, msrs }
+}
+
+/// The entries captured without a baseline.
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+fn default_entries() -> Environment {
+    Environment {
+        cpuid: DEFAULT_CPUID
+            .iter()
+            .map(|&(leaf, subleaf)| CpuidEntry {
+                leaf,
+                subleaf,
+                registers: [None; 4],
+            })
+            .collect(),
+        msrs: DEFAULT_MSRS
+            .iter()
+            .map(|&msr| MsrEntry {
+                msr,
+                value: MsrValue::Any,
+            })
+            .collect(),
+    }
+}
+
+#[derive(Serialize)]
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+struct EnvironmentRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    #[serde(flatten)]
+    environment: &'a Environment,
+    baseline: String,
+}
+
+#[derive(Serialize)]
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+struct EnvironmentDiffRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    #[serde(flatten)]
+    diff: &'a EnvironmentDiff,
+}
+
+/// Captures the live environment of the current VP, writes it in an
+/// `environment` record and, if the harness provided a baseline, writes
+/// how it differs in an `environment_diff` record and returns it.
+///
+/// MSRs are read with #GP recovery, so the interrupt handlers must be set
+/// up on builds that have it.
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub fn check() -> Option<EnvironmentDiff> {
+    let baseline = baseline();
+    let live = capture(baseline.as_ref().unwrap_or(&default_entries()));
+    crate::tmk_logger::write_record(&EnvironmentRecord {
+        record_type: "environment",
+        environment: &live,
+        baseline: live.to_string(),
+    });
+    let baseline = baseline?;
+    let diff = diff(&baseline, &live);
+    for drift in &diff.drifts {
+        log::warn!("environment drift: {:?}", drift);
+    }
+    crate::tmk_logger::write_record(&EnvironmentDiffRecord {
+        record_type: "environment_diff",
+        diff: &diff,
+    });
+    Some(diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASELINE: &str = "cpuid 0x1 0 0x806f8 * 0xfeda3203 0x178bfbff # leaf 1\n\
+        cpuid 0x40000000 0x0 0x4000000b 0x7263694d 0x666f736f 0x76482074;\
+        msr 0x277 0x7040600070406\n\
+        msr 0x10a fault\n\
+        msr 0xfe *\n";
+
+    #[test]
+    fn test_parse_baseline() {
+        let env = parse_baseline(BASELINE).unwrap();
+        assert_eq!(env.cpuid.len(), 2);
+        assert_eq!(
+            env.cpuid[0],
+            CpuidEntry {
+                leaf: 1,
+                subleaf: 0,
+                registers: [Some(0x806f8), None, Some(0xfeda3203), Some(0x178bfbff)],
+            }
+        );
+        assert_eq!(
+            env.msrs,
+            [
+                MsrEntry {
+                    msr: 0x277,
+                    value: MsrValue::Value(0x7040600070406),
+                },
+                MsrEntry {
+                    msr: 0x10a,
+                    value: MsrValue::Fault,
+                },
+                MsrEntry {
+                    msr: 0xfe,
+                    value: MsrValue::Any,
+                },
+            ]
+        );
+        assert_eq!(parse_baseline(&env.to_string()), Ok(env));
+
+        assert_eq!(parse_baseline(""), Ok(Environment::default()));
+        assert!(parse_baseline("cpuid 0x1 0 1 2 3").is_err());
+        assert!(parse_baseline("msr 0x1b maybe").is_err());
+        assert!(parse_baseline("cpuid 0x1 0 1 2 3 0x100000000").is_err());
+        assert!(parse_baseline("cr4 0x1").is_err());
+    }
+
+    #[test]
+    fn test_diff() {
+        let baseline = parse_baseline(BASELINE).unwrap();
+        let live = parse_baseline(
+            "cpuid 0x1 0 0x806f8 0x800 0xdeda3203 0x178bfbff\n\
+             msr 0x277 0x7040600070406\n\
+             msr 0x10a 0x0\n\
+             msr 0xfe 0x508",
+        )
+        .unwrap();
+        let diff = diff(&baseline, &live);
+        assert_eq!(diff.checked, 4);
+        assert_eq!(diff.unchecked, 1);
+        assert_eq!(
+            diff.drifts,
+            [
+                Drift::Cpuid {
+                    leaf: 1,
+                    subleaf: 0,
+                    register: "ecx",
+                    expected: 0xfeda3203,
+                    actual: 0xdeda3203,
+                },
+                Drift::Msr {
+                    msr: 0x10a,
+                    expected: MsrValue::Fault,
+                    actual: MsrValue::Value(0),
+                },
+            ]
+        );
+        assert!(super::diff(&live, &live).drifts.is_empty());
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Handshake with the host-side test framework.
+//!
+//! Early in the run the TMK writes a `handshake` record stating the schema
+//! version of its records, the commands it accepts on the serial port and
+//! the tests built into the image, so that the harness can adapt its
+//! expectations to the binary instead of assuming a fixed layout. The
+//! harness answers with a line
+//!
+//! ```text
+//! handshake <schema version> [capability...]
+//! ```
+//!
+//! naming the schema version it speaks and the capabilities it supports,
+//! e.g. `host_action`. Before answering, the harness may send other
+//! commands to configure the run, such as [`crate::soak::SOAK_COMMAND`] or
+//! [`crate::tmk_logger::LOG_LEVELS_COMMAND`].
+//! Tests needing a capability of the host check it with
+//! [`host_supports`] and skip when the harness answered without it. A
+//! harness that does not answer predates the handshake, and what it
+//! supports is unknown.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use serde::Serialize;
+use spin::Mutex;
+
+/// Version of the schema of the records, bumped on incompatible changes.
+pub const SCHEMA_VERSION: u32 = 1;
+/// Command the harness answers the handshake with.
+pub const HANDSHAKE_COMMAND: &str = "handshake";
+/// Capability of a harness performing host actions, see
+/// [`crate::host_action`].
+pub const HOST_ACTION_CAPABILITY: &str = "host_action";
+/// Capability of a harness holding barriers between the VMs it booted, see
+/// [`crate::rendezvous`].
+pub const RENDEZVOUS_CAPABILITY: &str = "rendezvous";
+/// Capability of a harness asking to keep UEFI boot services up for the
+/// tests needing them, see
+/// [`crate::tests::registry::Registry::keep_boot_services`].
+pub const BOOT_SERVICES_CAPABILITY: &str = "boot_services";
+/// Capability of a harness following the TMK across a warm reset it asks
+/// for, see [`crate::uefi::warm_reset`].
+pub const WARM_RESET_CAPABILITY: &str = "warm_reset";
+
+/// Commands the TMK accepts from the harness.
+pub const COMMANDS: &[&str] = &[
+    HANDSHAKE_COMMAND,
+    crate::tmk_logger::LOG_FORMAT_COMMAND,
+    crate::tmk_logger::RETRANSMIT_COMMAND,
+    crate::tmk_logger::LOG_LEVELS_COMMAND,
+    crate::host_action::DONE_COMMAND,
+    crate::host_action::FAILED_COMMAND,
+    crate::soak::SOAK_COMMAND,
+];
+
+/// The harness' answer to the handshake.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HostHandshake {
+    /// The schema version the harness speaks.
+    pub schema_version: u32,
+    /// The capabilities the harness supports.
+    pub capabilities: Vec<String>,
+}
+
+static HOST: Mutex<Option<HostHandshake>> = Mutex::new(None);
+
+/// Parses the harness' answer to the handshake.
+pub fn parse_reply(line: &str) -> Option<HostHandshake> {
+    let mut words = line.split_whitespace();
+    if words.next()? != HANDSHAKE_COMMAND {
+        return None;
+    }
+    let schema_version = words.next()?.parse().ok()?;
+    Some(HostHandshake {
+        schema_version,
+        capabilities: words.map(String::from).collect(),
+    })
+}
+
+/// Returns the harness' answer to the handshake, `None` if it did not
+/// answer.
+pub fn host() -> Option<HostHandshake> {
+    HOST.lock().clone()
+}
+
+/// Returns whether the harness announced `capability`, `None` if it did
+/// not answer the handshake.
+pub fn host_supports(capability: &str) -> Option<bool> {
+    HOST.lock()
+        .as_ref()
+        .map(|host| host.capabilities.iter().any(|c| c == capability))
+}
+
+#[derive(Serialize)]
+struct HandshakeRecord {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    schema_version: u32,
+    commands: &'static [&'static str],
+    tests: Vec<&'static str>,
+}
+
+/// Writes the `handshake` record.
+pub fn write_record() {
+    crate::tmk_logger::write_record(&HandshakeRecord {
+        record_type: "handshake",
+        schema_version: SCHEMA_VERSION,
+        commands: COMMANDS,
+        tests: crate::tests::SELECTED_TESTS
+            .split(',')
+            .filter(|name| !name.is_empty())
+            .collect(),
+    });
+}
+
+/// Writes the `handshake` record and waits for the harness' answer. Gives
+/// up after `max_polls` polls of the serial port without data.
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub fn negotiate(max_polls: u64) {
+    use crate::arch::serial::Serial;
+
+    write_record();
+    let serial = Serial::selected();
+    let reply = loop {
+        let Ok(line) = serial.read_line(max_polls) else {
+            break None;
+        };
+        if crate::tmk_logger::handle_host_line(&line) {
+            continue;
+        }
+        if let Some((crate::soak::SOAK_COMMAND, text)) = line.trim().split_once(' ') {
+            match crate::soak::parse_config(text) {
+                Ok(config) => crate::soak::set_config(config),
+                Err(_) => log::error!("ignoring invalid soak configuration {:?}", text),
+            }
+            continue;
+        }
+        match parse_reply(&line) {
+            Some(reply) => break Some(reply),
+            None => log::warn!("ignoring serial line {:?}", line),
+        }
+    };
+    match &reply {
+        Some(host) => {
+            log::info!(
+                "host speaks schema version {}, capabilities: {}",
+                host.schema_version,
+                host.capabilities.join(",")
+            );
+            if host.schema_version != SCHEMA_VERSION {
+                log::warn!(
+                    "host schema version {} differs from {}",
+                    host.schema_version,
+                    SCHEMA_VERSION
+                );
+            }
+        }
+        None => log::info!("no handshake from the host"),
+    }
+    *HOST.lock() = reply;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_reply() {
+        assert_eq!(
+            parse_reply("handshake 1 host_action  results_file"),
+            Some(HostHandshake {
+                schema_version: 1,
+                capabilities: vec!["host_action".into(), "results_file".into()],
+            })
+        );
+        assert_eq!(
+            parse_reply("handshake 2"),
+            Some(HostHandshake {
+                schema_version: 2,
+                capabilities: Vec::new(),
+            })
+        );
+        assert_eq!(parse_reply("handshake"), None);
+        assert_eq!(parse_reply("handshake x"), None);
+        assert_eq!(parse_reply("log_format json"), None);
+    }
+
+    #[test]
+    fn test_handshake_record() {
+        let output = crate::tmk_logger::capture_output(write_record);
+        let lines = crate::tmk_logger::json_lines(&output);
+        let record = lines
+            .iter()
+            .find(|line| line["type"] == "handshake")
+            .unwrap();
+        assert_eq!(record["schema_version"], SCHEMA_VERSION);
+        assert_eq!(record["commands"], serde_json::json!(COMMANDS));
+        assert!(record["tests"].is_array());
+    }
+}
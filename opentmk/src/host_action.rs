@@ -0,0 +1,150 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Steps the harness performs in the middle of a test.
+//!
+//! Some scenarios need the host to act on the VM while a test runs, e.g. to
+//! save and restore it or to hot-add memory. The test emits a
+//! `host_action_request` record naming the action and carrying an ID, then
+//! waits on the serial port for the harness to answer with a line
+//!
+//! ```text
+//! host_action_done <id>
+//! host_action_failed <id> [reason]
+//! ```
+//!
+//! Log retransmission requests are served while waiting. Other lines are
+//! logged and ignored, so that replies to earlier requests that timed out do
+//! not confuse later ones.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use serde::Serialize;
+
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+use crate::tmkdefs::TmkError;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+use crate::tmkdefs::TmkResult;
+
+/// Command the harness sends once an action completed.
+pub const DONE_COMMAND: &str = "host_action_done";
+/// Command the harness sends if it could not perform an action.
+pub const FAILED_COMMAND: &str = "host_action_failed";
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The harness' answer to a request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reply {
+    /// The action with the ID completed.
+    Done(u64),
+    /// The action with the ID failed, for the given reason.
+    Failed(u64, String),
+}
+
+impl Reply {
+    /// The ID of the request answered.
+    pub fn id(&self) -> u64 {
+        match *self {
+            Reply::Done(id) | Reply::Failed(id, _) => id,
+        }
+    }
+}
+
+/// Parses a line received from the harness, `None` if it is not a reply.
+pub fn parse_reply(line: &str) -> Option<Reply> {
+    let mut words = line.split_whitespace();
+    let command = words.next()?;
+    let id = words.next()?.parse().ok()?;
+    match command {
+        DONE_COMMAND => words.next().is_none().then_some(Reply::Done(id)),
+        FAILED_COMMAND => {
+            let reason = words.collect::<Vec<_>>().join(" ");
+            Some(Reply::Failed(id, reason))
+        }
+        _ => None,
+    }
+}
+
+#[derive(Serialize)]
+struct HostActionRequestRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    id: u64,
+    test: Option<&'a str>,
+    action: &'a str,
+    detail: &'a str,
+}
+
+/// Asks the harness to perform `action`, with action specific `detail`, and
+/// returns the ID of the request.
+pub fn request(action: &str, detail: &str) -> u64 {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    log::info!("requesting host action {} ({}): {}", id, action, detail);
+    crate::tmk_logger::write_record(&HostActionRequestRecord {
+        record_type: "host_action_request",
+        id,
+        test: crate::tests::registry::current_test(),
+        action,
+        detail,
+    });
+    id
+}
+
+/// Waits for the harness to answer request `id`. Gives up after
+/// `max_polls` polls of the serial port without data.
+///
+/// Fails with [`TmkError::OperationFailed`] if the harness reports the
+/// action failed.
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub fn wait_done(id: u64, max_polls: u64) -> TmkResult<()> {
+    use crate::arch::serial::Serial;
+
+    let serial = Serial::selected();
+    loop {
+        let line = serial.read_line(max_polls)?;
+        if crate::tmk_logger::handle_host_line(&line) {
+            continue;
+        }
+        match parse_reply(&line) {
+            Some(Reply::Done(done)) if done == id => return Ok(()),
+            Some(Reply::Failed(failed, reason)) if failed == id => {
+                log::error!("host action {} failed: {}", id, reason);
+                return Err(TmkError::OperationFailed);
+            }
+            Some(reply) => log::warn!("ignoring reply to host action {}", reply.id()),
+            None => log::warn!("ignoring serial line {:?}", line),
+        }
+    }
+}
+
+/// Requests `action` and waits for the harness to perform it.
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub fn perform(action: &str, detail: &str, max_polls: u64) -> TmkResult<()> {
+    let id = request(action, detail);
+    wait_done(id, max_polls)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_reply() {
+        assert_eq!(parse_reply("host_action_done 3"), Some(Reply::Done(3)));
+        assert_eq!(
+            parse_reply(" host_action_failed 4 vm is  off "),
+            Some(Reply::Failed(4, "vm is off".into()))
+        );
+        assert_eq!(
+            parse_reply("host_action_failed 5"),
+            Some(Reply::Failed(5, String::new()))
+        );
+        assert_eq!(parse_reply("host_action_done x"), None);
+        assert_eq!(parse_reply("host_action_done 3 extra"), None);
+        assert_eq!(parse_reply("end"), None);
+    }
+}
@@ -0,0 +1,165 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Latency sample aggregation and reporting.
+//!
+//! Samples are recorded in TSC cycles and reported as a `latency` record,
+//! converted to nanoseconds when the TSC frequency is known, so that the
+//! host side can track latency distributions across runs.
+
+use alloc::vec::Vec;
+
+use serde::Serialize;
+
+/// Number of power of two buckets in the reported distribution.
+const BUCKETS: usize = 64;
+
+/// A set of latency samples, in cycles.
+pub struct LatencyStats {
+    samples: Vec<u64>,
+}
+
+/// Summary of a [`LatencyStats`], in the unit named by `unit`.
+#[derive(Clone, Debug, Serialize)]
+pub struct LatencySummary {
+    /// `ns` if the samples were converted, `cycles` otherwise.
+    pub unit: &'static str,
+    /// Number of samples.
+    pub count: usize,
+    /// Smallest sample.
+    pub min: u64,
+    /// Largest sample.
+    pub max: u64,
+    /// Average of the samples.
+    pub mean: u64,
+    /// Median.
+    pub p50: u64,
+    /// 99th percentile.
+    pub p99: u64,
+    /// Sample counts per power of two: entry `i` counts the samples in
+    /// `[2^i, 2^(i+1))`, entry 0 also counts zero. Trailing empty buckets
+    /// are omitted.
+    pub histogram: Vec<u64>,
+}
+
+impl LatencyStats {
+    /// Create an empty set with room for `capacity` samples.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            samples: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Record a sample in cycles.
+    pub fn record(&mut self, cycles: u64) {
+        self.samples.push(cycles);
+    }
+
+    /// Number of recorded samples.
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Returns true if no sample was recorded.
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Summarize the samples, converting them to nanoseconds if the TSC
+    /// frequency `tsc_hz` is known.
+    pub fn summary(&self, tsc_hz: Option<u64>) -> LatencySummary {
+        let convert = |cycles: u64| match tsc_hz {
+            Some(hz) if hz != 0 => (cycles as u128 * 1_000_000_000 / hz as u128) as u64,
+            _ => cycles,
+        };
+        let mut sorted: Vec<u64> = self.samples.iter().map(|&c| convert(c)).collect();
+        sorted.sort_unstable();
+
+        let percentile = |p: usize| {
+            if sorted.is_empty() {
+                0
+            } else {
+                sorted[(sorted.len() - 1) * p / 100]
+            }
+        };
+
+        let mut histogram = vec![0u64; BUCKETS];
+        for &v in &sorted {
+            histogram[v.checked_ilog2().unwrap_or(0) as usize] += 1;
+        }
+        let used = histogram.iter().rposition(|&n| n != 0).map_or(0, |i| i + 1);
+        histogram.truncate(used);
+
+        LatencySummary {
+            unit: if tsc_hz.is_some_and(|hz| hz != 0) {
+                "ns"
+            } else {
+                "cycles"
+            },
+            count: sorted.len(),
+            min: sorted.first().copied().unwrap_or(0),
+            max: sorted.last().copied().unwrap_or(0),
+            mean: if sorted.is_empty() {
+                0
+            } else {
+                (sorted.iter().map(|&v| v as u128).sum::<u128>() / sorted.len() as u128) as u64
+            },
+            p50: percentile(50),
+            p99: percentile(99),
+            histogram,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct LatencyRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    name: &'a str,
+    summary: &'a LatencySummary,
+}
+
+/// Writes the `latency` record for the measurement `name`.
+pub fn write_summary(name: &str, summary: &LatencySummary) {
+    log::info!(
+        "{}: {} samples, min {} p50 {} p99 {} max {} {}",
+        name,
+        summary.count,
+        summary.min,
+        summary.p50,
+        summary.p99,
+        summary.max,
+        summary.unit
+    );
+    crate::tmk_logger::write_record(&LatencyRecord {
+        record_type: "latency",
+        name,
+        summary,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let mut stats = LatencyStats::with_capacity(100);
+        for i in 1..=100 {
+            stats.record(i * 2);
+        }
+        let summary = stats.summary(Some(2_000_000_000));
+        assert_eq!(summary.unit, "ns");
+        assert_eq!(summary.count, 100);
+        assert_eq!(summary.min, 1);
+        assert_eq!(summary.max, 100);
+        assert_eq!(summary.p50, 50);
+        assert_eq!(summary.p99, 99);
+        assert_eq!(summary.histogram, [1, 2, 4, 8, 16, 32, 37]);
+
+        let summary = LatencyStats::with_capacity(0).summary(None);
+        assert_eq!(summary.unit, "cycles");
+        assert_eq!(summary.count, 0);
+        assert!(summary.histogram.is_empty());
+    }
+}
@@ -15,13 +15,31 @@ fn main() {}
 #[macro_use]
 extern crate alloc;
 
+pub mod affinity;
 pub mod arch;
+pub mod cbor;
+pub mod chaos;
+pub mod compress;
 pub mod context;
 pub mod devices;
+pub mod fiber;
+pub mod golden_env;
+pub mod handshake;
+pub mod host_action;
+pub mod latency;
+pub mod manifest;
+#[cfg(target_os = "uefi")]
+pub mod memstress;
 pub mod platform;
+pub mod progress;
+pub mod rendezvous;
+pub mod scenario;
+pub mod soak;
+pub mod sync;
 pub mod tests;
 pub mod tmk_assert;
 pub mod tmk_logger;
 pub mod tmkdefs;
 #[cfg(target_os = "uefi")]
 mod uefi;
+pub mod vtl_fuzz;
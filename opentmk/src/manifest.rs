@@ -0,0 +1,110 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Run manifest.
+//!
+//! The first record of a run describes the binary that produced it and the
+//! partition it ran in, so that log archives are self-describing and the
+//! harness can reject output from a binary other than the one it built.
+
+use serde::Serialize;
+
+/// Describes an OpenTMK binary and the partition it runs in.
+#[derive(Clone, Debug, Serialize)]
+pub struct RunManifest {
+    /// Version of the crate.
+    pub version: &'static str,
+    /// `git describe` of the tree the binary was built from, if known.
+    pub git_describe: Option<&'static str>,
+    /// Enabled cargo features, comma separated.
+    pub features: &'static str,
+    /// Tests built into the binary, comma separated.
+    pub tests: &'static str,
+    /// Target architecture.
+    pub target_arch: &'static str,
+    /// Isolation type of the partition: `none`, `vbs`, `snp`, `tdx` or
+    /// `unknown`.
+    pub isolation_type: &'static str,
+    /// Whether a paravisor is present.
+    pub paravisor_present: bool,
+    /// Whether the hypervisor runs nested in a Hyper-V partition, see
+    /// [`crate::platform::is_nested`].
+    pub nested: bool,
+}
+
+impl RunManifest {
+    /// Describe this binary and the current partition.
+    pub fn current() -> Self {
+        let (isolation_type, paravisor_present) = isolation();
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            git_describe: option_env!("VERGEN_GIT_DESCRIBE"),
+            features: env!("OPENTMK_FEATURES"),
+            tests: crate::tests::SELECTED_TESTS,
+            target_arch: TARGET_ARCH,
+            isolation_type,
+            paravisor_present,
+            nested: crate::platform::is_nested(),
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+const TARGET_ARCH: &str = "x86_64";
+#[cfg(target_arch = "aarch64")] // xtask-fmt allow-target-arch sys-crate
+const TARGET_ARCH: &str = "aarch64";
+
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+fn isolation() -> (&'static str, bool) {
+    use hvdef::HvIsolationConfiguration;
+    use hvdef::HvPartitionIsolationType;
+
+    // SAFETY: CPUID is always available on x86_64.
+    let leaf = unsafe {
+        core::arch::x86_64::__cpuid(hvdef::HV_CPUID_FUNCTION_MS_HV_ISOLATION_CONFIGURATION)
+    };
+    let config = HvIsolationConfiguration::from(
+        leaf.eax as u128
+            | (leaf.ebx as u128) << 32
+            | (leaf.ecx as u128) << 64
+            | (leaf.edx as u128) << 96,
+    );
+    let isolation_type = match HvPartitionIsolationType(config.isolation_type()) {
+        HvPartitionIsolationType::NONE => "none",
+        HvPartitionIsolationType::VBS => "vbs",
+        HvPartitionIsolationType::SNP => "snp",
+        HvPartitionIsolationType::TDX => "tdx",
+        _ => "unknown",
+    };
+    (isolation_type, config.paravisor_present())
+}
+
+#[cfg(target_arch = "aarch64")] // xtask-fmt allow-target-arch sys-crate
+fn isolation() -> (&'static str, bool) {
+    // Isolated partitions are not supported on aarch64.
+    ("none", false)
+}
+
+#[derive(Serialize)]
+struct RunHeaderRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    manifest: &'a RunManifest,
+}
+
+/// Writes the `run_header` record for this binary.
+pub fn write_run_header() {
+    let manifest = RunManifest::current();
+    log::info!(
+        "opentmk {} ({}) {} isolation {}{}",
+        manifest.version,
+        manifest.git_describe.unwrap_or("unknown revision"),
+        manifest.target_arch,
+        manifest.isolation_type,
+        if manifest.nested { " nested" } else { "" }
+    );
+    crate::tmk_logger::write_record(&RunHeaderRecord {
+        record_type: "run_header",
+        manifest: &manifest,
+    });
+}
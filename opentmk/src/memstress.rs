@@ -0,0 +1,163 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Memory pressure simulation for allocator stress tests.
+//!
+//! [`consume`] grabs heap memory until the requested share of the capped heap
+//! is in use, in the spirit of a balloon driver inflating, and [`release`]
+//! gives it back. Every step is reported as a `memstress` JSON record so the
+//! host can follow the pressure level alongside the test results, and large
+//! allocations also report `progress` records, see [`crate::progress`].
+
+use alloc::alloc::dealloc;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+
+use serde::Serialize;
+use spin::Mutex;
+
+use crate::progress::Progress;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+use crate::uefi::alloc::ALLOCATOR;
+use crate::uefi::alloc::HeapStats;
+
+const PAGE_SIZE: usize = 4096;
+/// Largest block requested from the heap in one go.
+const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+/// A progress record is emitted every time usage crosses this many percent.
+const PROGRESS_STEP_PERCENT: usize = 10;
+/// Bytes from which consuming memory reports `progress` records.
+const PROGRESS_BYTES: u64 = 64 * 1024 * 1024;
+
+/// A block of heap memory held by the stress module.
+struct Block {
+    addr: usize,
+    layout: Layout,
+}
+
+static HELD: Mutex<Vec<Block>> = Mutex::new(Vec::new());
+
+#[derive(Serialize)]
+struct MemStressRecord {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    event: &'static str,
+    target_percent: usize,
+    held_bytes: usize,
+    heap_used: usize,
+    heap_size: usize,
+}
+
+fn heap_stats() -> TmkResult<HeapStats> {
+    ALLOCATOR.heap_stats().ok_or(TmkError::Inactive)
+}
+
+fn held_bytes(held: &[Block]) -> usize {
+    held.iter().map(|b| b.layout.size()).sum()
+}
+
+fn record(event: &'static str, target_percent: usize, held: &[Block]) {
+    let (heap_used, heap_size) = ALLOCATOR
+        .heap_stats()
+        .map_or((0, 0), |stats| (stats.used, stats.size));
+    crate::tmk_logger::write_record(&MemStressRecord {
+        record_type: "memstress",
+        event,
+        target_percent,
+        held_bytes: held_bytes(held),
+        heap_used,
+        heap_size,
+    });
+}
+
+fn used_percent(stats: &HeapStats) -> usize {
+    stats.used * 100 / stats.size
+}
+
+/// Allocate heap memory until `percent` of the capped heap is in use.
+///
+/// Memory is taken in blocks of up to 1MB, falling back to smaller blocks as
+/// the heap fragments, and every page is touched so it is really backed.
+/// Returns the number of bytes consumed by this call. If usage is already at
+/// or above `percent` nothing is allocated.
+pub fn consume(percent: usize) -> TmkResult<usize> {
+    if percent > 100 {
+        return Err(TmkError::InvalidParameter);
+    }
+    let mut stats = heap_stats()?;
+    let target = stats.size / 100 * percent;
+    let mut held = HELD.lock();
+    record("consume_start", percent, &held);
+
+    let mut consumed = 0;
+    let mut chunk = MAX_CHUNK_SIZE;
+    let mut next_progress = used_percent(&stats) + PROGRESS_STEP_PERCENT;
+    let mut progress = Progress::new(
+        "memstress_consume",
+        target.saturating_sub(stats.used) as u64,
+        PROGRESS_BYTES,
+    );
+    while stats.used < target {
+        let size = chunk.min((target - stats.used).next_multiple_of(PAGE_SIZE));
+        let layout =
+            Layout::from_size_align(size, PAGE_SIZE).map_err(|_| TmkError::AllocationFailed)?;
+        // SAFETY: the layout has a non-zero size.
+        let ptr = unsafe { alloc::alloc::alloc(layout) };
+        if ptr.is_null() {
+            if chunk == PAGE_SIZE {
+                log::warn!("memstress: heap exhausted before reaching {}%", percent);
+                break;
+            }
+            chunk /= 2;
+            continue;
+        }
+        for offset in (0..size).step_by(PAGE_SIZE) {
+            // SAFETY: the block was just allocated with `size` bytes.
+            unsafe { ptr.add(offset).write_volatile(0xa5) };
+        }
+        held.push(Block {
+            addr: ptr as usize,
+            layout,
+        });
+        consumed += size;
+        progress.advance(size as u64);
+
+        stats = heap_stats()?;
+        if used_percent(&stats) >= next_progress {
+            record("consume_progress", percent, &held);
+            next_progress += PROGRESS_STEP_PERCENT;
+        }
+    }
+
+    record("consume_end", percent, &held);
+    Ok(consumed)
+}
+
+/// Free up to `bytes` of the memory taken by [`consume`], most recent
+/// blocks first. Returns the number of bytes released.
+pub fn release(bytes: usize) -> usize {
+    let mut held = HELD.lock();
+    let mut released = 0;
+    while released < bytes {
+        let Some(block) = held.pop() else {
+            break;
+        };
+        // SAFETY: the block was allocated by `consume` with this layout.
+        unsafe { dealloc(block.addr as *mut u8, block.layout) };
+        released += block.layout.size();
+    }
+    record("release", 0, &held);
+    released
+}
+
+/// Free all the memory taken by [`consume`]. Returns the number of bytes
+/// released.
+pub fn release_all() -> usize {
+    release(usize::MAX)
+}
+
+/// Number of bytes currently held by the stress module.
+pub fn held() -> usize {
+    held_bytes(&HELD.lock())
+}
@@ -4,17 +4,29 @@
 //! Platform-specific context implementations for AArch64 Hyper-V.
 //!
 
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::ops::Range;
 
+use crate::chaos;
+use crate::chaos::ChaosPoint;
 use crate::context::VirtualProcessorPlatformTrait;
 use crate::context::VpExecToken;
 use crate::context::VtlPlatformTrait;
+use crate::platform::hyperv::arch::hypercall::HvCall;
 use crate::platform::hyperv::ctx::HvTestCtx;
+use crate::platform::hyperv::ctx::get_faulted_vps;
+use crate::platform::hyperv::ctx::set_active_vtl;
+use crate::platform::hyperv::ctx::set_crash_isolation;
+use crate::platform::hyperv::ctx::set_idle_vtl;
 use crate::platform::hyperv::ctx::vtl_transform;
+use crate::platform::hyperv::descriptor_table::DescriptorTable;
 use crate::tmkdefs::TmkError;
 use crate::tmkdefs::TmkResult;
 use hvdef::AlignedU128;
 use hvdef::HvRegisterValue;
+use hvdef::HvRegisterVsmVpSecureVtlConfig;
+use hvdef::HvVtlEntryReason;
 use hvdef::Vtl;
 use hvdef::hypercall::HvInputVtl;
 use hvdef::hypercall::InitialVpContextArm64;
@@ -45,6 +57,15 @@ fn queue_command_vp(&mut self, _cmd: VpExecToken<HvTestCtx>) -> TmkResult<()> {
         unimplemented!();
     }
 
+    fn queue_batch(
+        &mut self,
+        _vp_index: u32,
+        _vtl: Vtl,
+        _cmds: Vec<Box<dyn FnOnce(&mut HvTestCtx) + Send>>,
+    ) -> TmkResult<()> {
+        Err(TmkError::FeatureUnavailable)
+    }
+
     fn start_on_vp(&mut self, _cmd: VpExecToken<HvTestCtx>) -> TmkResult<()> {
         unimplemented!();
     }
@@ -83,6 +104,26 @@ fn get_register_vtl(&mut self, reg: u32, vtl: Vtl) -> TmkResult<u128> {
             .as_u128();
         Ok(val)
     }
+
+    fn set_crash_isolation(&mut self, enabled: bool) {
+        set_crash_isolation(enabled);
+    }
+
+    fn get_faulted_vps(&self) -> TmkResult<Vec<u32>> {
+        Ok(get_faulted_vps())
+    }
+
+    fn restart_vp(&mut self, _vp_index: u32, _vtl: Vtl) -> TmkResult<()> {
+        Err(TmkError::FeatureUnavailable)
+    }
+
+    fn set_idle_vtl(&mut self, vtl: Option<Vtl>) -> TmkResult<()> {
+        if vtl.is_some_and(|vtl| vtl >= Vtl::Vtl2) {
+            return Err(TmkError::InvalidParameter);
+        }
+        set_idle_vtl(vtl);
+        Ok(())
+    }
 }
 
 impl VtlPlatformTrait for HvTestCtx {
@@ -94,6 +135,13 @@ fn apply_vtl_protection_for_memory(&mut self, range: Range<u64>, vtl: Vtl) -> Tm
         Ok(())
     }
 
+    /// Give every VTL full access to the supplied GPA range again.
+    fn remove_vtl_protection_for_memory(&mut self, range: Range<u64>, vtl: Vtl) -> TmkResult<()> {
+        self.hvcall
+            .remove_vtl_protections(MemoryRange::new(range), vtl)?;
+        Ok(())
+    }
+
     /// Enable the specified VTL on a VP and seed it with a default
     /// context captured from the current execution environment.
     fn enable_vp_vtl_with_default_context(&mut self, vp_index: u32, vtl: Vtl) -> TmkResult<()> {
@@ -124,10 +172,24 @@ fn setup_vtl_protection(&mut self) -> TmkResult<()> {
 
     /// Switch execution from the current (low) VTL to the next higher
     /// one (`vtl_call`).
-    fn switch_to_high_vtl(&mut self) {}
+    #[inline(never)]
+    fn switch_to_high_vtl(&mut self) {
+        chaos::delay(self.my_vp_idx, ChaosPoint::BeforeSwitchToHigh);
+        set_active_vtl(self.my_vp_idx, Vtl::Vtl1);
+        HvCall::vtl_call();
+        set_active_vtl(self.my_vp_idx, self.my_vtl);
+        chaos::delay(self.my_vp_idx, ChaosPoint::AfterSwitchToHigh);
+    }
 
     /// Return from a high VTL back to the low VTL (`vtl_return`).
-    fn switch_to_low_vtl(&mut self) {}
+    #[inline(never)]
+    fn switch_to_low_vtl(&mut self) {
+        chaos::delay(self.my_vp_idx, ChaosPoint::BeforeSwitchToLow);
+        set_active_vtl(self.my_vp_idx, Vtl::Vtl0);
+        HvCall::vtl_return();
+        set_active_vtl(self.my_vp_idx, self.my_vtl);
+        chaos::delay(self.my_vp_idx, ChaosPoint::AfterSwitchToLow);
+    }
 
     fn set_vp_register_with_vtl(
         &mut self,
@@ -150,6 +212,77 @@ fn get_vp_register_with_vtl(&mut self, register_index: u32, vtl: Vtl) -> TmkResu
             .map(|v| v.as_u64())
             .map_err(|e| e.into())
     }
+
+    fn set_vp_register_on_vp(
+        &mut self,
+        vp_index: u32,
+        register_index: u32,
+        value: u64,
+        vtl: Vtl,
+    ) -> TmkResult<()> {
+        let vtl = vtl_transform(vtl);
+        let reg_value = HvRegisterValue(AlignedU128::from(value));
+        self.hvcall
+            .set_vp_register(
+                vp_index,
+                hvdef::HvRegisterName(register_index),
+                reg_value,
+                Some(vtl),
+            )
+            .map_err(|e| e.into())
+    }
+
+    fn get_vp_register_on_vp(
+        &mut self,
+        vp_index: u32,
+        register_index: u32,
+        vtl: Vtl,
+    ) -> TmkResult<u64> {
+        let vtl = vtl_transform(vtl);
+        self.hvcall
+            .get_vp_register(vp_index, hvdef::HvRegisterName(register_index), Some(vtl))
+            .map(|v| v.as_u64())
+            .map_err(|e| e.into())
+    }
+
+    fn get_vp_descriptor_table(
+        &mut self,
+        _vp_index: u32,
+        _register_index: u32,
+        _vtl: Vtl,
+    ) -> TmkResult<DescriptorTable> {
+        Err(TmkError::FeatureUnavailable)
+    }
+
+    fn set_vp_descriptor_table(
+        &mut self,
+        _vp_index: u32,
+        _register_index: u32,
+        _table: DescriptorTable,
+        _vtl: Vtl,
+    ) -> TmkResult<()> {
+        Err(TmkError::FeatureUnavailable)
+    }
+
+    fn get_vp_secure_config(
+        &mut self,
+        target_vtl: Vtl,
+    ) -> TmkResult<HvRegisterVsmVpSecureVtlConfig> {
+        Ok(self.hvcall.get_vsm_vp_secure_config(target_vtl)?)
+    }
+
+    fn set_vp_secure_config(
+        &mut self,
+        target_vtl: Vtl,
+        config: HvRegisterVsmVpSecureVtlConfig,
+    ) -> TmkResult<()> {
+        self.hvcall.set_vsm_vp_secure_config(target_vtl, config)?;
+        Ok(())
+    }
+
+    fn get_vtl_entry_reason(&self, _vp_index: u32) -> TmkResult<HvVtlEntryReason> {
+        Err(TmkError::FeatureUnavailable)
+    }
 }
 
 impl HvTestCtx {
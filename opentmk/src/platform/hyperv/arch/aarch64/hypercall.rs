@@ -1,12 +1,60 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
+//! aarch64 hypercalls of [`HvCall`].
+//!
+//! There is no hypercall page on aarch64: hypercalls trap with `hvc #1`, the
+//! Hyper-V calling convention, with the control word in `x0` and the status
+//! returned there. VTL call and VTL return go through the same instruction.
+
 use hvdef::Vtl;
 use hvdef::hypercall::InitialVpContextArm64;
 use zerocopy::IntoBytes;
 
 use crate::platform::hyperv::arch::hypercall::HvCall;
 
+/// Input of the VtlReturn hypercall asking for the fast return, which does
+/// not restore the low VTL's volatile registers from the VP assist page.
+const VTL_RETURN_FAST: u64 = 1;
+
+// avoiding inline for debuggability in release builds.
+#[inline(never)]
+/// Invokes a hypercall switching VTL, VtlCall or VtlReturn, with `input` in
+/// `x1`.
+///
+/// The general purpose registers are shared between VTLs, so the other VTL
+/// may change any of them before execution comes back here. The caller-saved
+/// ones are declared clobbered; `x18` to `x30` are saved on the stack, which
+/// is private to each VTL, and restored after the switch.
+///
+///  # Safety
+///  The caller must ensure that the hypercall is invoked in a context where it is safe to do so.
+unsafe fn invoke_hypercall_vtl(control: hvdef::hypercall::Control, input: u64) {
+    // SAFETY: the caller guarantees the safety of this operation.
+    unsafe {
+        core::arch::asm! {
+            "stp x18, x19, [sp, #-16]!",
+            "stp x20, x21, [sp, #-16]!",
+            "stp x22, x23, [sp, #-16]!",
+            "stp x24, x25, [sp, #-16]!",
+            "stp x26, x27, [sp, #-16]!",
+            "stp x28, x29, [sp, #-16]!",
+            "str x30, [sp, #-16]!",
+            "hvc #1",
+            "ldr x30, [sp], #16",
+            "ldp x28, x29, [sp], #16",
+            "ldp x26, x27, [sp], #16",
+            "ldp x24, x25, [sp], #16",
+            "ldp x22, x23, [sp], #16",
+            "ldp x20, x21, [sp], #16",
+            "ldp x18, x19, [sp], #16",
+            inout("x0") u64::from(control) => _,
+            inout("x1") input => _,
+            clobber_abi("C"),
+        }
+    }
+}
+
 impl HvCall {
     /// Starts a virtual processor (VP) with the specified VTL and context on aarch64.
     pub fn start_virtual_processor(
@@ -55,13 +103,31 @@ pub fn enable_vp_vtl(
         output.result()
     }
 
-    /// Placeholder for VTL call on aarch64.
-    pub fn vtl_call() {
-        unimplemented!();
+    // avoiding inline for debuggability in release builds.
+    #[inline(never)]
+    /// Invokes the VtlCall hypercall.
+    pub(crate) fn vtl_call() {
+        let control: hvdef::hypercall::Control = hvdef::hypercall::Control::new()
+            .with_code(hvdef::HypercallCode::HvCallVtlCall.0)
+            .with_rep_count(0);
+        // SAFETY: This is safe because we are calling a hypercall with a valid control structure.
+        unsafe { invoke_hypercall_vtl(control, 0) };
     }
 
-    /// Placeholder for VTL return on aarch64.
-    pub fn vtl_return() {
-        unimplemented!();
+    // avoiding inline for debuggability in release builds.
+    #[inline(never)]
+    /// Invokes the VtlReturn hypercall, taking the fast return: the
+    /// registers the low VTL relies on are restored by
+    /// [`invoke_hypercall_vtl`] itself.
+    ///
+    /// The hypervisor rejects a VtlReturn whose control has any bit set
+    /// besides the call code, so the fast return is asked for in the input
+    /// rather than with the fast bit of the control.
+    pub(crate) fn vtl_return() {
+        let control: hvdef::hypercall::Control = hvdef::hypercall::Control::new()
+            .with_code(hvdef::HypercallCode::HvCallVtlReturn.0)
+            .with_rep_count(0);
+        // SAFETY: This is safe because we are calling a hypercall with a valid control structure.
+        unsafe { invoke_hypercall_vtl(control, VTL_RETURN_FAST) };
     }
 }
@@ -0,0 +1,104 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Typed builder for hypercall input pages.
+//!
+//! Rep hypercalls take a fixed header followed by a list of rep elements,
+//! as many as fit in the rest of the input page. [`HvInput`] writes the
+//! header and the elements at the right offsets and keeps count of them;
+//! a header that cannot fit the page fails to build, and
+//! [`HvInput::MAX_REPS`] tells how many elements a call takes at most.
+
+use core::marker::PhantomData;
+use core::mem::size_of;
+
+use hvdef::HV_PAGE_SIZE;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+
+use super::hypercall::HvcallPage;
+
+const PAGE_SIZE: usize = HV_PAGE_SIZE as usize;
+
+/// The input page of a hypercall taking a header `H` followed by rep
+/// elements `R`.
+pub(crate) struct HvInput<'a, H, R> {
+    page: &'a mut HvcallPage,
+    reps: usize,
+    _types: PhantomData<(H, R)>,
+}
+
+impl<'a, H, R> HvInput<'a, H, R>
+where
+    H: IntoBytes + Immutable,
+    R: IntoBytes + Immutable,
+{
+    /// Size of the header; building the input of a header larger than the
+    /// page fails to compile.
+    pub const HEADER_SIZE: usize = {
+        assert!(
+            size_of::<H>() <= PAGE_SIZE,
+            "hypercall header larger than a page"
+        );
+        size_of::<H>()
+    };
+
+    /// Most rep elements that fit after the header.
+    pub const MAX_REPS: usize = {
+        assert!(size_of::<R>() != 0, "rep elements must have a size");
+        (PAGE_SIZE - Self::HEADER_SIZE) / size_of::<R>()
+    };
+
+    /// Starts the input in `page` with `header`, and no rep elements.
+    pub fn new(page: &'a mut HvcallPage, header: &H) -> Self {
+        page.buffer[..Self::HEADER_SIZE].copy_from_slice(header.as_bytes());
+        Self {
+            page,
+            reps: 0,
+            _types: PhantomData,
+        }
+    }
+
+    /// Appends as many of `reps` as still fit and returns how many that
+    /// was.
+    pub fn extend(&mut self, reps: &[R]) -> usize {
+        let count = reps.len().min(Self::MAX_REPS - self.reps);
+        let start = Self::HEADER_SIZE + self.reps * size_of::<R>();
+        let bytes = reps[..count].as_bytes();
+        self.page.buffer[start..start + bytes.len()].copy_from_slice(bytes);
+        self.reps += count;
+        count
+    }
+
+    /// Appends `rep`, returning false if the page is full.
+    pub fn push(&mut self, rep: &R) -> bool {
+        self.extend(core::slice::from_ref(rep)) == 1
+    }
+
+    /// Number of rep elements written, the rep count of the call.
+    pub fn reps(&self) -> usize {
+        self.reps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reps_fill_the_page() {
+        type Input<'a> = HvInput<'a, [u8; 24], u64>;
+        assert_eq!(Input::MAX_REPS, 509);
+
+        let mut page = HvcallPage::new();
+        let mut input = Input::new(&mut page, &[0xaa; 24]);
+        let reps: alloc::vec::Vec<u64> = (0..600).collect();
+        assert_eq!(input.extend(&reps[..500]), 500);
+        assert_eq!(input.extend(&reps[500..]), 9);
+        assert!(!input.push(&0));
+        assert_eq!(input.reps(), 509);
+        assert_eq!(page.buffer[23], 0xaa);
+        assert_eq!(page.buffer[24..32], 0u64.to_le_bytes());
+        assert_eq!(page.buffer[PAGE_SIZE - 8..], 508u64.to_le_bytes());
+    }
+}
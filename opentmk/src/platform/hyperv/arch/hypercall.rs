@@ -6,13 +6,17 @@
 // UNSAFETY: This module contains unsafe code to perform low-level operations such as invoking hypercalls
 #![expect(unsafe_code)]
 
+use alloc::vec::Vec;
 use core::mem::size_of;
+use core::sync::atomic::AtomicBool;
 use core::sync::atomic::AtomicU16;
+use core::sync::atomic::AtomicU64;
 use core::sync::atomic::Ordering;
 
 use hvdef::HV_PAGE_SIZE;
 use hvdef::HvRegisterValue;
 use hvdef::HvRegisterVsmPartitionConfig;
+use hvdef::HvRegisterVsmVpSecureVtlConfig;
 use hvdef::HvX64RegisterName;
 use hvdef::Vtl;
 use hvdef::hypercall::EnablePartitionVtlFlags;
@@ -22,6 +26,15 @@
 use zerocopy::FromBytes;
 use zerocopy::IntoBytes;
 
+use crate::platform::hyperv::arch::hv_input::HvInput;
+use crate::platform::hyperv::descriptor_table::DescriptorTable;
+use crate::platform::hyperv::retry;
+use crate::progress::Progress;
+
+/// Pages, 64MB, from which changing or checking VTL protections reports
+/// progress.
+const PROGRESS_PAGES: u64 = 16384;
+
 /// Page-aligned, page-sized buffer for use with hypercalls
 #[repr(C, align(4096))]
 pub(crate) struct HvcallPage {
@@ -48,9 +61,60 @@ fn address(&self) -> u64 {
 pub struct HvCall {
     pub(crate) input_page: HvcallPage,
     pub(crate) output_page: HvcallPage,
+    /// Set for the per-VP instances reserved for interrupt context.
+    pub(crate) irq_instance: bool,
 }
 
 static HV_PAGE_INIT_STATUS: AtomicU16 = AtomicU16::new(0);
+static PARANOID_MODE: AtomicBool = AtomicBool::new(false);
+static PARANOID_VIOLATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Returns whether an [`HvCall`] initialized the hypercall interface.
+pub(crate) fn is_initialized() -> bool {
+    HV_PAGE_INIT_STATUS.load(Ordering::Acquire) != 0
+}
+
+/// Byte the output page is filled with before each hypercall in paranoid mode.
+const OUTPUT_POISON: u8 = 0xcd;
+
+/// Number of output bytes the TLFS documents for `code`, or `None` if the
+/// hypercall is not known to this module or its output would not fit in
+/// the output page.
+fn documented_output_size(code: hvdef::HypercallCode, rep_count: Option<usize>) -> Option<usize> {
+    let reps = rep_count.unwrap_or_default();
+    let size = match code {
+        hvdef::HypercallCode::HvCallGetVpRegisters => {
+            reps.checked_mul(size_of::<HvRegisterValue>())
+        }
+        hvdef::HypercallCode::HvCallCheckSparseGpaPageVtlAccess => {
+            reps.checked_mul(size_of::<hvdef::hypercall::CheckSparseGpaPageVtlAccessOutput>())
+        }
+        hvdef::HypercallCode::HvCallSetVpRegisters
+        | hvdef::HypercallCode::HvCallModifyVtlProtectionMask
+        | hvdef::HypercallCode::HvCallEnablePartitionVtl
+        | hvdef::HypercallCode::HvCallEnableVpVtl
+        | hvdef::HypercallCode::HvCallStartVirtualProcessor
+        | hvdef::HypercallCode::HvCallPostMessage
+        | hvdef::HypercallCode::HvCallSignalEvent
+        | hvdef::HypercallCode::HvCallMapGpaPages
+        | hvdef::HypercallCode::HvCallUnmapGpaPages => Some(0),
+        hvdef::HypercallCode::HvCallSignalEventDirect => {
+            Some(size_of::<hvdef::hypercall::SignalEventDirectOutput>())
+        }
+        hvdef::HypercallCode::HvExtCallQueryCapabilities => Some(size_of::<u64>()),
+        _ => None,
+    };
+    size.filter(|&size| size <= HV_PAGE_SIZE as usize)
+}
+
+/// Name of the register holding the VP secure configuration of `vtl`.
+fn vsm_vp_secure_config_name(vtl: Vtl) -> hvdef::HvRegisterName {
+    match vtl {
+        Vtl::Vtl0 => hvdef::HvAllArchRegisterName::VsmVpSecureConfigVtl0.into(),
+        Vtl::Vtl1 => hvdef::HvAllArchRegisterName::VsmVpSecureConfigVtl1.into(),
+        Vtl::Vtl2 => hvdef::HvAllArchRegisterName::VsmVpSecureConfigVtl2.into(),
+    }
+}
 
 impl HvCall {
     /// Hypercall to apply vtl protections (NO ACCESS) to the pages from address start to end
@@ -59,46 +123,186 @@ pub fn apply_vtl_protections(
         range: MemoryRange,
         vtl: Vtl,
     ) -> Result<(), hvdef::HvError> {
-        const HEADER_SIZE: usize = size_of::<hvdef::hypercall::ModifyVtlProtectionMask>();
-        const MAX_INPUT_ELEMENTS: usize = (HV_PAGE_SIZE as usize - HEADER_SIZE) / size_of::<u64>();
+        self.modify_vtl_protection_mask_op(
+            "apply_vtl_protections",
+            range,
+            vtl,
+            hvdef::HV_MAP_GPA_PERMISSIONS_NONE,
+        )
+    }
+
+    /// Hypercall to remove the vtl protections from the pages from address
+    /// start to end, giving full access back.
+    pub fn remove_vtl_protections(
+        &mut self,
+        range: MemoryRange,
+        vtl: Vtl,
+    ) -> Result<(), hvdef::HvError> {
+        self.modify_vtl_protection_mask_op(
+            "remove_vtl_protections",
+            range,
+            vtl,
+            hvdef::HV_MAP_GPA_PERMISSIONS_ALL,
+        )
+    }
 
+    /// Hypercall to set the vtl protection mask of the pages from address
+    /// start to end to `map_flags`, in as many rep calls as needed.
+    pub fn modify_vtl_protection_mask(
+        &mut self,
+        range: MemoryRange,
+        vtl: Vtl,
+        map_flags: hvdef::HvMapGpaFlags,
+    ) -> Result<(), hvdef::HvError> {
+        self.modify_vtl_protection_mask_op("modify_vtl_protection_mask", range, vtl, map_flags)
+    }
+
+    /// [`Self::modify_vtl_protection_mask`], reporting progress as `op`.
+    fn modify_vtl_protection_mask_op(
+        &mut self,
+        op: &'static str,
+        range: MemoryRange,
+        vtl: Vtl,
+        map_flags: hvdef::HvMapGpaFlags,
+    ) -> Result<(), hvdef::HvError> {
         let header = hvdef::hypercall::ModifyVtlProtectionMask {
             partition_id: hvdef::HV_PARTITION_ID_SELF,
-            map_flags: hvdef::HV_MAP_GPA_PERMISSIONS_NONE,
+            map_flags,
             target_vtl: HvInputVtl::new()
                 .with_target_vtl_value(vtl.into())
                 .with_use_target_vtl(true),
             reserved: [0; 3],
         };
 
+        let mut progress = Progress::new(op, range.page_count_4k(), PROGRESS_PAGES);
         let mut current_page = range.start_4k_gpn();
         while current_page < range.end_4k_gpn() {
-            let remaining_pages = range.end_4k_gpn() - current_page;
-            let count = remaining_pages.min(MAX_INPUT_ELEMENTS as u64);
-
-            let _ = header.write_to_prefix(self.input_page().buffer.as_mut_slice());
-
-            let mut input_offset = HEADER_SIZE;
-            for i in 0..count {
-                let page_num = current_page + i;
-                let _ = page_num.write_to_prefix(&mut self.input_page().buffer[input_offset..]);
-                input_offset += size_of::<u64>();
+            let mut input = HvInput::new(self.input_page(), &header);
+            let mut page_num = current_page;
+            while page_num < range.end_4k_gpn() && input.push(&page_num) {
+                page_num += 1;
             }
+            let count = input.reps();
 
             let output = self.dispatch_hvcall(
                 hvdef::HypercallCode::HvCallModifyVtlProtectionMask,
-                Some(count as usize),
+                Some(count),
             );
 
             output.result()?;
 
-            current_page += count;
+            current_page += count as u64;
+            progress.advance(count as u64);
         }
 
         Ok(())
     }
 
-    /// Makes a hypercall.
+    /// Hypercall asking the hypervisor whether `vtl` may access the pages
+    /// `gpns` with `desired_access`, returning one result per page.
+    ///
+    /// The hypervisor may stop at a page whose check fails; the remaining
+    /// pages are checked with further calls.
+    pub fn check_sparse_gpa_page_vtl_access(
+        &mut self,
+        gpns: &[u64],
+        vtl: Vtl,
+        desired_access: hvdef::HvMapGpaFlags,
+    ) -> Result<Vec<hvdef::hypercall::CheckSparseGpaPageVtlAccessOutput>, hvdef::HvError> {
+        let header = hvdef::hypercall::CheckSparseGpaPageVtlAccess {
+            partition_id: hvdef::HV_PARTITION_ID_SELF,
+            target_vtl: HvInputVtl::new()
+                .with_target_vtl_value(vtl.into())
+                .with_use_target_vtl(true),
+            desired_access: u32::from(desired_access) as u8,
+            reserved0: 0,
+            reserved1: 0,
+        };
+
+        let mut progress = Progress::new("check_vtl_access", gpns.len() as u64, PROGRESS_PAGES);
+        let mut results = Vec::with_capacity(gpns.len());
+        let mut remaining = gpns;
+        while !remaining.is_empty() {
+            let count = HvInput::new(self.input_page(), &header).extend(remaining);
+
+            let output = self.dispatch_hvcall(
+                hvdef::HypercallCode::HvCallCheckSparseGpaPageVtlAccess,
+                Some(count),
+            );
+            output.result()?;
+
+            let processed = output.elements_processed().min(count);
+            if processed == 0 {
+                return Err(hvdef::HvError::InvalidHypercallInput);
+            }
+            for i in 0..processed {
+                let offset = i * size_of::<u64>();
+                let (result, _) =
+                    hvdef::hypercall::CheckSparseGpaPageVtlAccessOutput::read_from_prefix(
+                        &self.output_page().buffer[offset..],
+                    )
+                    .unwrap();
+                results.push(result);
+            }
+            remaining = &remaining[processed..];
+            progress.advance(processed as u64);
+        }
+
+        Ok(results)
+    }
+
+    /// Hypercall to overlay the pages starting at page `target_gpn` with
+    /// the pages `source_gpns`, one target page per source page.
+    ///
+    /// Only permitted for partitions with the MapGpaPages privilege, which
+    /// guests normally lack; expect `AccessDenied` otherwise.
+    pub fn map_gpa_pages(
+        &mut self,
+        target_gpn: u64,
+        source_gpns: &[u64],
+        flags: hvdef::HvMapGpaFlags,
+    ) -> Result<(), hvdef::HvError> {
+        let mut mapped = 0;
+        while mapped < source_gpns.len() {
+            let header = hvdef::hypercall::MapGpaPages {
+                target_partition_id: hvdef::HV_PARTITION_ID_SELF,
+                target_gpa_base: target_gpn + mapped as u64,
+                map_flags: flags,
+                padding: 0,
+            };
+
+            let count = HvInput::new(self.input_page(), &header).extend(&source_gpns[mapped..]);
+
+            let output = self.dispatch_hvcall(hvdef::HypercallCode::HvCallMapGpaPages, Some(count));
+            output.result()?;
+            mapped += count;
+        }
+
+        Ok(())
+    }
+
+    /// Hypercall to remove `page_count` overlay pages starting at page
+    /// `target_gpn`, making the underlying guest memory visible again.
+    pub fn unmap_gpa_pages(
+        &mut self,
+        target_gpn: u64,
+        page_count: usize,
+    ) -> Result<(), hvdef::HvError> {
+        let header = hvdef::hypercall::UnmapGpaPages {
+            target_partition_id: hvdef::HV_PARTITION_ID_SELF,
+            target_gpa_base: target_gpn,
+            unmap_flags: 0,
+            padding: 0,
+        };
+
+        let _ = header.write_to_prefix(self.input_page().buffer.as_mut_slice());
+
+        let output =
+            self.dispatch_hvcall(hvdef::HypercallCode::HvCallUnmapGpaPages, Some(page_count));
+        output.result()
+    }
+
+    /// Makes a hypercall, retrying it as its [`retry`] policy says.
     /// rep_count is Some for rep hypercalls
     pub(crate) fn dispatch_hvcall(
         &mut self,
@@ -109,16 +313,83 @@ pub(crate) fn dispatch_hvcall(
             .with_code(code.0)
             .with_rep_count(rep_count.unwrap_or_default());
 
-        // SAFETY: Invoking hypercall per TLFS spec
-        unsafe {
-            invoke_hypercall(
-                control,
-                self.input_page().address(),
-                self.output_page().address(),
-            )
+        crate::platform::hyperv::irq_hvcall::check_dispatch(self);
+
+        let paranoid = PARANOID_MODE.load(Ordering::Relaxed);
+        let mut attempt = 0;
+        loop {
+            if paranoid {
+                self.output_page().buffer.fill(OUTPUT_POISON);
+            }
+
+            let start = crate::arch::cycles::read();
+            // SAFETY: Invoking hypercall per TLFS spec
+            let output = unsafe {
+                invoke_hypercall(
+                    control,
+                    self.input_page().address(),
+                    self.output_page().address(),
+                )
+            };
+            let cycles = crate::arch::cycles::read().wrapping_sub(start);
+            crate::platform::hyperv::trace::record(
+                code.0,
+                rep_count.unwrap_or_default(),
+                output.call_status().0,
+                cycles,
+            );
+
+            if paranoid {
+                self.check_output_page(code, rep_count);
+            }
+
+            let Some(delay) = retry::retry_delay(code.0, attempt, output) else {
+                return output;
+            };
+            attempt += 1;
+            let wait_start = crate::arch::cycles::read();
+            while crate::arch::cycles::read().wrapping_sub(wait_start) < delay {
+                core::hint::spin_loop();
+            }
         }
     }
 
+    /// Checks that the hypercall left the output page untouched beyond its
+    /// documented output, then zeroes the untouched part so that stale
+    /// poison never reaches a caller.
+    fn check_output_page(&mut self, code: hvdef::HypercallCode, rep_count: Option<usize>) {
+        let Some(size) = documented_output_size(code, rep_count) else {
+            log::debug!("no documented output size for {:?}, skipping check", code);
+            return;
+        };
+        let tail = &mut self.output_page().buffer[size..];
+        if let Some(offset) = tail.iter().position(|&b| b != OUTPUT_POISON) {
+            PARANOID_VIOLATIONS.fetch_add(1, Ordering::Relaxed);
+            log::error!(
+                "{:?} wrote output page offset {:#x}, beyond its documented {:#x} bytes",
+                code,
+                size + offset,
+                size
+            );
+        }
+        tail.fill(0);
+    }
+
+    /// Enables or disables paranoid mode for all hypercalls.
+    ///
+    /// In paranoid mode the output page is poisoned before every hypercall
+    /// and checked afterwards for writes beyond the documented output size,
+    /// to catch information leaks from the hypervisor or paravisor.
+    pub fn set_paranoid_mode(enabled: bool) {
+        PARANOID_MODE.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Returns the number of hypercalls caught writing beyond their
+    /// documented output since boot.
+    pub fn paranoid_violations() -> u64 {
+        PARANOID_VIOLATIONS.load(Ordering::Relaxed)
+    }
+
     /// Enables a VTL for the specified partition.
     pub fn enable_partition_vtl(
         &mut self,
@@ -166,17 +437,24 @@ pub fn get_register(
         name: hvdef::HvRegisterName,
         vtl: Option<HvInputVtl>,
     ) -> Result<HvRegisterValue, hvdef::HvError> {
-        const HEADER_SIZE: usize = size_of::<hvdef::hypercall::GetSetVpRegisters>();
+        self.get_vp_register(hvdef::HV_VP_INDEX_SELF, name, vtl)
+    }
 
+    /// Hypercall for getting a register value of the given VP.
+    pub fn get_vp_register(
+        &mut self,
+        vp_index: u32,
+        name: hvdef::HvRegisterName,
+        vtl: Option<HvInputVtl>,
+    ) -> Result<HvRegisterValue, hvdef::HvError> {
         let header = hvdef::hypercall::GetSetVpRegisters {
             partition_id: hvdef::HV_PARTITION_ID_SELF,
-            vp_index: hvdef::HV_VP_INDEX_SELF,
+            vp_index,
             target_vtl: vtl.unwrap_or(HvInputVtl::CURRENT_VTL),
             rsvd: [0; 3],
         };
 
-        let _ = header.write_to_prefix(self.input_page().buffer.as_mut_slice());
-        let _ = name.write_to_prefix(&mut self.input_page().buffer[HEADER_SIZE..]);
+        HvInput::new(self.input_page(), &header).push(&name);
 
         let output = self.dispatch_hvcall(hvdef::HypercallCode::HvCallGetVpRegisters, Some(1));
         output.result()?;
@@ -185,6 +463,183 @@ pub fn get_register(
         Ok(value.0)
     }
 
+    /// Reads the descriptor table register `name`, GDTR or IDTR, of the
+    /// given VP.
+    pub fn get_vp_descriptor_table(
+        &mut self,
+        vp_index: u32,
+        name: hvdef::HvRegisterName,
+        vtl: Option<HvInputVtl>,
+    ) -> Result<DescriptorTable, hvdef::HvError> {
+        Ok(self.get_vp_register(vp_index, name, vtl)?.into())
+    }
+
+    /// Writes the descriptor table register `name`, GDTR or IDTR, of the
+    /// given VP.
+    pub fn set_vp_descriptor_table(
+        &mut self,
+        vp_index: u32,
+        name: hvdef::HvRegisterName,
+        table: DescriptorTable,
+        vtl: Option<HvInputVtl>,
+    ) -> Result<(), hvdef::HvError> {
+        self.set_vp_register(vp_index, name, table.into(), vtl)
+    }
+
+    /// Reads the secure configuration the current VTL applies to the lower
+    /// VTL `target_vtl` on this VP.
+    pub fn get_vsm_vp_secure_config(
+        &mut self,
+        target_vtl: Vtl,
+    ) -> Result<HvRegisterVsmVpSecureVtlConfig, hvdef::HvError> {
+        let value = self.get_register(vsm_vp_secure_config_name(target_vtl), None)?;
+        Ok(HvRegisterVsmVpSecureVtlConfig::from(value.as_u64()))
+    }
+
+    /// Writes the secure configuration the current VTL applies to the lower
+    /// VTL `target_vtl` on this VP, and reads it back to check that the
+    /// hypervisor accepted every bit.
+    pub fn set_vsm_vp_secure_config(
+        &mut self,
+        target_vtl: Vtl,
+        config: HvRegisterVsmVpSecureVtlConfig,
+    ) -> Result<(), hvdef::HvError> {
+        let name = vsm_vp_secure_config_name(target_vtl);
+        self.set_register(name, u64::from(config).into(), None)?;
+        let readback = self.get_vsm_vp_secure_config(target_vtl)?;
+        if u64::from(readback) != u64::from(config) {
+            log::error!(
+                "secure config for {:?} read back as {:?}, expected {:?}",
+                target_vtl,
+                readback,
+                config
+            );
+            return Err(hvdef::HvError::InvalidRegisterValue);
+        }
+        Ok(())
+    }
+
+    /// Hypercall to post a message to the given connection.
+    pub fn post_message(
+        &mut self,
+        connection_id: u32,
+        message_type: u32,
+        payload: &[u8],
+    ) -> Result<(), hvdef::HvError> {
+        let mut input = hvdef::hypercall::PostMessage {
+            connection_id,
+            padding: 0,
+            message_type,
+            payload_size: payload.len() as u32,
+            payload: [0; hvdef::HV_MESSAGE_PAYLOAD_SIZE],
+        };
+        input
+            .payload
+            .get_mut(..payload.len())
+            .ok_or(hvdef::HvError::InvalidParameter)?
+            .copy_from_slice(payload);
+
+        let _ = input.write_to_prefix(self.input_page().buffer.as_mut_slice());
+
+        let output = self.dispatch_hvcall(hvdef::HypercallCode::HvCallPostMessage, None);
+        output.result()
+    }
+
+    /// Hypercall to post a message to the given connection, with a zeroed
+    /// payload of `payload_size` bytes. The size is passed to the
+    /// hypervisor as is, even past [`hvdef::HV_MESSAGE_PAYLOAD_SIZE`], to
+    /// check that it refuses it.
+    pub fn post_message_unchecked(
+        &mut self,
+        connection_id: u32,
+        message_type: u32,
+        payload_size: u32,
+    ) -> Result<(), hvdef::HvError> {
+        let input = hvdef::hypercall::PostMessage {
+            connection_id,
+            padding: 0,
+            message_type,
+            payload_size,
+            payload: [0; hvdef::HV_MESSAGE_PAYLOAD_SIZE],
+        };
+
+        let _ = input.write_to_prefix(self.input_page().buffer.as_mut_slice());
+
+        let output = self.dispatch_hvcall(hvdef::HypercallCode::HvCallPostMessage, None);
+        output.result()
+    }
+
+    /// Hypercall to signal an event flag on the given connection.
+    pub fn signal_event(
+        &mut self,
+        connection_id: u32,
+        flag_number: u16,
+    ) -> Result<(), hvdef::HvError> {
+        let input = hvdef::hypercall::SignalEvent {
+            connection_id,
+            flag_number,
+            rsvd: 0,
+        };
+
+        let _ = input.write_to_prefix(self.input_page().buffer.as_mut_slice());
+
+        let output = self.dispatch_hvcall(hvdef::HypercallCode::HvCallSignalEvent, None);
+        output.result()
+    }
+
+    /// Hypercall to signal event flag `flag_number` of `sint` on VP
+    /// `vp_index` in `vtl` directly, without a connection.
+    ///
+    /// Returns whether the flag was newly set, i.e. was clear before.
+    pub fn signal_event_direct(
+        &mut self,
+        vp_index: u32,
+        vtl: Vtl,
+        sint: u8,
+        flag_number: u16,
+    ) -> Result<bool, hvdef::HvError> {
+        let input = hvdef::hypercall::SignalEventDirect {
+            target_partition: hvdef::HV_PARTITION_ID_SELF,
+            target_vp: vp_index,
+            target_vtl: vtl.into(),
+            target_sint: sint,
+            flag_number,
+        };
+
+        let _ = input.write_to_prefix(self.input_page().buffer.as_mut_slice());
+
+        let output = self.dispatch_hvcall(hvdef::HypercallCode::HvCallSignalEventDirect, None);
+        output.result()?;
+        let output =
+            hvdef::hypercall::SignalEventDirectOutput::read_from_prefix(&self.output_page().buffer)
+                .unwrap()
+                .0;
+        Ok(output.newly_signaled != 0)
+    }
+
+    /// Queries the extended hypercalls the host implements. Each set bit `n`
+    /// of the returned mask stands for the extended hypercall `0x8002 + n`.
+    pub fn query_extended_capabilities(&mut self) -> Result<u64, hvdef::HvError> {
+        let output = self.dispatch_hvcall(hvdef::HypercallCode::HvExtCallQueryCapabilities, None);
+        output.result()?;
+        let (capabilities, _) = u64::read_from_prefix(&self.output_page().buffer).unwrap();
+        Ok(capabilities)
+    }
+
+    /// Tells the hypervisor that the current VP has spun `spin_count` times
+    /// waiting for a lock, so that it may run the VP holding it instead.
+    ///
+    /// The call is fast and needs neither page of an instance, so that spin
+    /// locks can issue it anywhere once [`is_initialized`] holds.
+    pub fn notify_long_spin_wait(spin_count: u32) -> Result<(), hvdef::HvError> {
+        let control = hvdef::hypercall::Control::new()
+            .with_code(hvdef::HypercallCode::HvCallNotifyLongSpinWait.0)
+            .with_fast(true);
+        // SAFETY: a fast hypercall with a single input word and no output.
+        let output = unsafe { invoke_hypercall(control, spin_count.into(), 0) };
+        output.result()
+    }
+
     /// Initializes the hypercall interface.
     pub fn initialize(&mut self) {
         let guest_os_id = hvdef::hypercall::HvGuestOsMicrosoft::new().with_os_id(1);
@@ -206,6 +661,7 @@ pub const fn new() -> Self {
         HvCall {
             input_page: HvcallPage::new(),
             output_page: HvcallPage::new(),
+            irq_instance: false,
         }
     }
 
@@ -221,24 +677,31 @@ pub fn set_register(
         value: HvRegisterValue,
         vtl: Option<HvInputVtl>,
     ) -> Result<(), hvdef::HvError> {
-        const HEADER_SIZE: usize = size_of::<hvdef::hypercall::GetSetVpRegisters>();
+        self.set_vp_register(hvdef::HV_VP_INDEX_SELF, name, value, vtl)
+    }
 
+    /// Hypercall for setting a register of the given VP to a value.
+    pub fn set_vp_register(
+        &mut self,
+        vp_index: u32,
+        name: hvdef::HvRegisterName,
+        value: HvRegisterValue,
+        vtl: Option<HvInputVtl>,
+    ) -> Result<(), hvdef::HvError> {
         let header = hvdef::hypercall::GetSetVpRegisters {
             partition_id: hvdef::HV_PARTITION_ID_SELF,
-            vp_index: hvdef::HV_VP_INDEX_SELF,
+            vp_index,
             target_vtl: vtl.unwrap_or(HvInputVtl::CURRENT_VTL),
             rsvd: [0; 3],
         };
 
-        let _ = header.write_to_prefix(self.input_page().buffer.as_mut_slice());
-
         let reg = hvdef::hypercall::HvRegisterAssoc {
             name,
             pad: Default::default(),
             value,
         };
 
-        let _ = reg.write_to_prefix(&mut self.input_page().buffer[HEADER_SIZE..]);
+        HvInput::new(self.input_page(), &header).push(&reg);
 
         let output = self.dispatch_hvcall(hvdef::HypercallCode::HvCallSetVpRegisters, Some(1));
 
@@ -3,6 +3,7 @@
 
 //! Hyper-V platform architecture-specific modules.
 
+pub(crate) mod hv_input;
 pub mod hypercall;
 
 cfg_if::cfg_if!(
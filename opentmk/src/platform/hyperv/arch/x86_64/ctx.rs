@@ -5,14 +5,25 @@
 
 use alloc::alloc::alloc;
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::alloc::Layout;
 use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::arch::x86_64::__rdtscp;
 use core::ops::Range;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
 #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
 use hvdef::hypercall::InitialVpContextX64;
 
 use hvdef::AlignedU128;
+use hvdef::HvAllArchRegisterName;
+use hvdef::HvFeatures;
+use hvdef::HvInternalActivityRegister;
+use hvdef::HvPartitionPrivilege;
 use hvdef::HvRegisterValue;
+use hvdef::HvRegisterVsmVpSecureVtlConfig;
+use hvdef::HvVtlEntryReason;
 use hvdef::HvX64RegisterName;
 use hvdef::Vtl;
 use hvdef::hypercall::HvInputVtl;
@@ -20,9 +31,12 @@
 use minimal_rt::arch::msr::read_msr;
 use minimal_rt::arch::msr::write_msr;
 
+use crate::chaos;
+use crate::chaos::ChaosPoint;
 #[cfg(nightly)]
 use crate::context::InterruptPlatformTrait;
 use crate::context::MsrPlatformTrait;
+use crate::context::PrivilegePlatformTrait;
 #[cfg(nightly)]
 use crate::context::SecureInterceptPlatformTrait;
 use crate::context::VirtualProcessorPlatformTrait;
@@ -30,11 +44,43 @@
 use crate::context::VtlPlatformTrait;
 use crate::platform::hyperv::arch::hypercall::HvCall;
 use crate::platform::hyperv::ctx::HvTestCtx;
+use crate::platform::hyperv::ctx::QueuedCommand;
+use crate::platform::hyperv::ctx::active_vtl;
 use crate::platform::hyperv::ctx::cmdt;
+use crate::platform::hyperv::ctx::get_faulted_vp_set;
+use crate::platform::hyperv::ctx::get_faulted_vps;
 use crate::platform::hyperv::ctx::get_vp_set;
+use crate::platform::hyperv::ctx::push_command;
+use crate::platform::hyperv::ctx::resync_command_queue;
+use crate::platform::hyperv::ctx::set_active_vtl;
+use crate::platform::hyperv::ctx::set_crash_isolation;
+use crate::platform::hyperv::ctx::set_idle_vtl;
 use crate::platform::hyperv::ctx::vtl_transform;
+use crate::platform::hyperv::descriptor_table::DescriptorTable;
+use crate::platform::hyperv::privileges;
+use crate::platform::hyperv::privileges::Privilege;
+use crate::platform::hyperv::queue_stats;
+use crate::platform::hyperv::stack_usage;
+use crate::platform::hyperv::vcpu::VpContext;
+use crate::platform::hyperv::vp_assist;
 use crate::tmkdefs::TmkError;
 use crate::tmkdefs::TmkResult;
+use crate::tmkdefs::vp_slot;
+
+/// The MSR RDTSCP returns, where each VP keeps its index plus one, see
+/// [`HvTestCtx::cache_vp_idx`].
+const IA32_TSC_AUX: u32 = 0xc000_0103;
+// CPUID.80000001h:EDX
+const CPUID_80000001_EDX_RDTSCP: u32 = 1 << 27;
+
+/// Number of polls of a parked VP before it is considered still running.
+const HALT_POLL_LIMIT: u32 = 1_000_000;
+/// The interrupt flag in RFLAGS.
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// Set once a VP cached its index in [`IA32_TSC_AUX`], which means RDTSCP
+/// is supported.
+static VP_INDEX_CACHED: AtomicBool = AtomicBool::new(false);
 
 #[cfg(nightly)]
 impl SecureInterceptPlatformTrait for HvTestCtx {
@@ -46,6 +92,9 @@ fn setup_secure_intercept(&mut self, interrupt_idx: u8) -> TmkResult<()> {
 
         // SAFETY: the pointer is managed carefully and is not deallocated until the end of the test.
         let ptr = unsafe { alloc(layout) };
+        if ptr.is_null() {
+            return Err(TmkError::AllocationFailed);
+        }
         let gpn = (ptr as u64) >> 12;
         let reg = (gpn << 12) | 0x1;
 
@@ -75,6 +124,11 @@ fn set_interrupt_idx(&mut self, interrupt_idx: u8, handler: fn()) -> TmkResult<(
         Ok(())
     }
 
+    fn clear_interrupt_idx(&mut self, interrupt_idx: u8) -> TmkResult<()> {
+        crate::arch::interrupt::clear_handler(interrupt_idx);
+        Ok(())
+    }
+
     /// Initialise the minimal in-guest interrupt infrastructure
     fn setup_interrupt_handler(&mut self) -> TmkResult<()> {
         crate::arch::interrupt::init();
@@ -98,6 +152,47 @@ unsafe fn write_msr(&mut self, msr: u32, value: u64) -> TmkResult<()> {
     }
 }
 
+impl PrivilegePlatformTrait for HvTestCtx {
+    /// Read the privileges from the hypervisor features CPUID leaf.
+    fn get_privileges(&mut self) -> TmkResult<HvPartitionPrivilege> {
+        if let Some(privileges) = privileges::overridden() {
+            return Ok(privileges);
+        }
+        // SAFETY: CPUID is always available on x86_64.
+        let leaf = unsafe { __cpuid(hvdef::HV_CPUID_FUNCTION_MS_HV_FEATURES) };
+        Ok(HvFeatures::from_cpuid([leaf.eax, leaf.ebx, leaf.ecx, leaf.edx]).privileges())
+    }
+
+    fn set_privileges(&mut self, privileges: Option<HvPartitionPrivilege>) {
+        privileges::set_override(privileges);
+    }
+
+    /// Connection 0 is never valid and the current VP is already started,
+    /// so the calls that could change state fail past the privilege check.
+    fn try_privileged_hypercall(&mut self, privilege: Privilege) -> TmkResult<()> {
+        match privilege {
+            Privilege::PostMessages => self.hvcall.post_message(0, 1, &[])?,
+            Privilege::SignalEvents => self.hvcall.signal_event(0, 0)?,
+            Privilege::AccessVpRegisters => {
+                self.hvcall
+                    .get_register(HvAllArchRegisterName::VpIndex.into(), None)?;
+            }
+            Privilege::AccessVsm => {
+                self.hvcall
+                    .get_register(HvAllArchRegisterName::VsmCapabilities.into(), None)?;
+            }
+            Privilege::EnableExtendedHypercalls => {
+                self.hvcall.query_extended_capabilities()?;
+            }
+            Privilege::StartVirtualProcessor => {
+                self.hvcall
+                    .start_virtual_processor(self.my_vp_idx, self.my_vtl, None)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 impl VirtualProcessorPlatformTrait<HvTestCtx> for HvTestCtx {
     /// Fetch the content of the specified architectural register from
     /// the current VTL for the executing VP.
@@ -126,13 +221,32 @@ fn get_vp_count(&self) -> TmkResult<u32> {
     /// by the busy-loop running in `exec_handler`. No scheduling happens
     /// here – we simply enqueue.
     fn queue_command_vp(&mut self, cmd: VpExecToken<HvTestCtx>) -> TmkResult<()> {
-        let (vp_index, vtl, cmd) = cmd.get();
-        let cmd = cmd.ok_or(TmkError::QueueCommandFailed)?;
-        cmdt()
-            .lock()
-            .get_mut(&vp_index)
-            .unwrap()
-            .push_back((cmd, vtl));
+        let (vp_index, cmd) = self
+            .queued_command(cmd)
+            .ok_or(TmkError::QueueCommandFailed)?;
+        push_command(vp_index, cmd)
+    }
+
+    /// Push the whole batch onto the per-VP linked-list under a single
+    /// lock. The executor pops one command at a time and everyone else
+    /// only pushes to the back, so the batch stays contiguous.
+    fn queue_batch(
+        &mut self,
+        vp_index: u32,
+        vtl: Vtl,
+        cmds: Vec<Box<dyn FnOnce(&mut HvTestCtx) + Send>>,
+    ) -> TmkResult<()> {
+        if vtl >= Vtl::Vtl2 {
+            return Err(TmkError::InvalidParameter);
+        }
+        let mut cmdt = cmdt().lock();
+        let Some(queue) = cmdt.get_mut(&vp_index) else {
+            queue_stats::count_rejected(cmds.len());
+            return Err(TmkError::InvalidVpIndex);
+        };
+        for cmd in cmds {
+            queue.push_back(QueuedCommand::new(cmd, vtl));
+        }
         Ok(())
     }
 
@@ -147,8 +261,10 @@ fn queue_command_vp(&mut self, cmd: VpExecToken<HvTestCtx>) -> TmkResult<()> {
     /// in short every VP acts as an executor engine and
     /// spins in `exec_handler` waiting for work.
     fn start_on_vp(&mut self, cmd: VpExecToken<HvTestCtx>) -> TmkResult<()> {
-        let (vp_index, vtl, cmd) = cmd.get();
-        let cmd = cmd.ok_or(TmkError::InvalidParameter)?;
+        let (vp_index, cmd) = self.queued_command(cmd).ok_or(TmkError::InvalidParameter)?;
+        // The per-VP tables have no slot for it.
+        vp_slot(vp_index)?;
+        let vtl = cmd.vtl();
         if vtl >= Vtl::Vtl2 {
             return Err(TmkError::InvalidParameter);
         }
@@ -160,55 +276,59 @@ fn start_on_vp(&mut self, cmd: VpExecToken<HvTestCtx>) -> TmkResult<()> {
                 let vp_context = self.get_default_context(Vtl::Vtl1)?;
                 self.hvcall.enable_vp_vtl(0, Vtl::Vtl1, Some(vp_context))?;
 
-                cmdt().lock().get_mut(&vp_index).unwrap().push_back((
-                    Box::new(move |ctx| {
-                        ctx.switch_to_low_vtl();
-                    }),
-                    Vtl::Vtl1,
-                ));
+                push_command(
+                    vp_index,
+                    QueuedCommand::new(
+                        Box::new(move |ctx| {
+                            ctx.switch_to_low_vtl();
+                        }),
+                        Vtl::Vtl1,
+                    ),
+                )?;
                 self.switch_to_high_vtl();
                 get_vp_set().lock().insert(vp_index);
             } else {
                 let (tx, rx) = nostd_spin_channel::Channel::<TmkResult<()>>::new().split();
                 let self_vp_idx = self.my_vp_idx;
-                cmdt().lock().get_mut(&self_vp_idx).unwrap().push_back((
-                    Box::new(move |ctx| {
-                        log::debug!("starting VP{} in VTL1 of vp{}", vp_index, self_vp_idx);
-                        let r = ctx.enable_vp_vtl_with_default_context(vp_index, Vtl::Vtl1);
-                        if r.is_err() {
-                            log::error!("failed to enable VTL1 for VP{}: {:?}", vp_index, r);
-                            let _ = tx.send(r);
-                            return;
-                        }
-                        log::debug!("successfully enabled VTL1 for VP{}", vp_index);
-                        let r = ctx.start_running_vp_with_default_context(VpExecToken::new(
-                            vp_index,
-                            Vtl::Vtl0,
-                        ));
-                        if r.is_err() {
-                            log::error!("failed to start VP{}: {:?}", vp_index, r);
+                push_command(
+                    self_vp_idx,
+                    QueuedCommand::new(
+                        Box::new(move |ctx| {
+                            log::debug!("starting VP{} in VTL1 of vp{}", vp_index, self_vp_idx);
+                            let r: TmkResult<()> = (|| {
+                                ctx.enable_vp_vtl_with_default_context(vp_index, Vtl::Vtl1)
+                                    .inspect_err(|e| {
+                                        log::error!(
+                                            "failed to enable VTL1 for VP{}: {:?}",
+                                            vp_index,
+                                            e
+                                        )
+                                    })?;
+                                log::debug!("successfully enabled VTL1 for VP{}", vp_index);
+                                ctx.start_running_vp_with_default_context(VpExecToken::new(
+                                    vp_index,
+                                    Vtl::Vtl0,
+                                ))
+                                .inspect_err(|e| {
+                                    log::error!("failed to start VP{}: {:?}", vp_index, e)
+                                })?;
+                                log::debug!("successfully started VP{}", vp_index);
+                                Ok(())
+                            })();
                             let _ = tx.send(r);
-                            return;
-                        }
-                        log::debug!("successfully started VP{}", vp_index);
-                        let _ = tx.send(Ok(()));
-                        ctx.switch_to_low_vtl();
-                    }),
-                    Vtl::Vtl1,
-                ));
+                            // Return to the issuer even on failure, which
+                            // waits for the result in VTL0.
+                            ctx.switch_to_low_vtl();
+                        }),
+                        Vtl::Vtl1,
+                    ),
+                )?;
                 self.switch_to_high_vtl();
-                let rx = rx.recv();
-                if let Ok(r) = rx {
-                    r?;
-                }
+                rx.recv().map_err(|_| TmkError::StartVpFailed)??;
                 get_vp_set().lock().insert(vp_index);
             }
         }
-        cmdt()
-            .lock()
-            .get_mut(&vp_index)
-            .unwrap()
-            .push_back((cmd, vtl));
+        push_command(vp_index, cmd)?;
 
         if vp_index == self.my_vp_idx && self.my_vtl != vtl {
             if vtl == Vtl::Vtl0 {
@@ -255,6 +375,82 @@ fn get_register_vtl(&mut self, reg: u32, vtl: Vtl) -> TmkResult<u128> {
             .as_u128();
         Ok(val)
     }
+
+    fn set_crash_isolation(&mut self, enabled: bool) {
+        set_crash_isolation(enabled);
+    }
+
+    fn get_faulted_vps(&self) -> TmkResult<Vec<u32>> {
+        Ok(get_faulted_vps())
+    }
+
+    /// Bring a faulted VP back into the pool of executors.
+    ///
+    /// Must be called from the BSP. Commands still queued for the VP are
+    /// dropped, then the VP is restarted in `vtl` on a fresh stack running
+    /// `exec_handler`. If the hypervisor refuses `StartVirtualProcessor`
+    /// because the VP is still started, the parked VP is redirected to the
+    /// new context through its RIP/RSP registers instead, once it is halted
+    /// with interrupts disabled in `vtl`; otherwise the restart is refused
+    /// with [`TmkError::InvalidVpState`] and the VP stays faulted.
+    fn restart_vp(&mut self, vp_index: u32, vtl: Vtl) -> TmkResult<()> {
+        if self.my_vp_idx != 0 || vp_index == 0 {
+            return Err(TmkError::InvalidVpIndex);
+        }
+        if !get_faulted_vp_set().lock().contains(&vp_index) {
+            log::warn!("VP{} is not faulted, not restarting it", vp_index);
+            return Err(TmkError::InvalidVpState);
+        }
+
+        let dropped = resync_command_queue(vp_index);
+        log::info!(
+            "restarting VP{} in {:?}, dropped {} queued commands",
+            vp_index,
+            vtl,
+            dropped
+        );
+
+        let vp_ctx = self.get_default_context(vtl)?;
+        match self
+            .hvcall
+            .start_virtual_processor(vp_index, vtl, Some(vp_ctx))
+        {
+            Ok(()) => {}
+            Err(hvdef::HvError::InvalidVpState) => {
+                if active_vtl(vp_index)? != vtl || !self.wait_for_halt(vp_index, vtl)? {
+                    log::warn!("VP{} is still running, not redirecting it", vp_index);
+                    return Err(TmkError::InvalidVpState);
+                }
+                log::debug!("VP{} is still started, redirecting it", vp_index);
+                let vtl = Some(vtl_transform(vtl));
+                // The VP cannot leave the halt while its registers change;
+                // clearing its activity state last wakes it in the new
+                // context.
+                for (name, value) in [
+                    (HvX64RegisterName::Rsp, vp_ctx.rsp),
+                    (HvX64RegisterName::Rflags, vp_ctx.rflags),
+                    (HvX64RegisterName::Rip, vp_ctx.rip),
+                    (HvX64RegisterName::InternalActivityState, 0),
+                ] {
+                    self.hvcall
+                        .set_vp_register(vp_index, name.into(), value.into(), vtl)?;
+                }
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        get_faulted_vp_set().lock().remove(&vp_index);
+        get_vp_set().lock().insert(vp_index);
+        Ok(())
+    }
+
+    fn set_idle_vtl(&mut self, vtl: Option<Vtl>) -> TmkResult<()> {
+        if vtl.is_some_and(|vtl| vtl >= Vtl::Vtl2) {
+            return Err(TmkError::InvalidParameter);
+        }
+        set_idle_vtl(vtl);
+        Ok(())
+    }
 }
 
 impl VtlPlatformTrait for HvTestCtx {
@@ -266,6 +462,13 @@ fn apply_vtl_protection_for_memory(&mut self, range: Range<u64>, vtl: Vtl) -> Tm
         Ok(())
     }
 
+    /// Give every VTL full access to the supplied GPA range again.
+    fn remove_vtl_protection_for_memory(&mut self, range: Range<u64>, vtl: Vtl) -> TmkResult<()> {
+        self.hvcall
+            .remove_vtl_protections(MemoryRange::new(range), vtl)?;
+        Ok(())
+    }
+
     /// Enable the specified VTL on a VP and seed it with a default
     /// context captured from the current execution environment.
     fn enable_vp_vtl_with_default_context(&mut self, vp_index: u32, vtl: Vtl) -> TmkResult<()> {
@@ -280,7 +483,16 @@ fn get_current_vtl(&self) -> TmkResult<Vtl> {
     }
 
     /// Enable VTL support for the entire partition.
+    ///
+    /// Fails with [`TmkError::FeatureUnavailable`] when nested, where the
+    /// VSM tests are not adapted to VTL switches and protections going
+    /// through the hypervisor of the level below, so that they are blocked
+    /// instead.
     fn setup_partition_vtl(&mut self, vtl: Vtl) -> TmkResult<()> {
+        if crate::platform::is_nested() {
+            crate::platform::nested::note_adapted("VSM tests blocked");
+            return Err(TmkError::FeatureUnavailable);
+        }
         self.hvcall
             .enable_partition_vtl(hvdef::HV_PARTITION_ID_SELF, vtl)?;
         log::info!("enabled vtl protections for the partition.");
@@ -298,6 +510,8 @@ fn setup_vtl_protection(&mut self) -> TmkResult<()> {
     /// one (`vtl_call`).
     #[inline(never)]
     fn switch_to_high_vtl(&mut self) {
+        chaos::delay(self.my_vp_idx, ChaosPoint::BeforeSwitchToHigh);
+        set_active_vtl(self.my_vp_idx, Vtl::Vtl1);
         // SAFETY: we are calling a valid function that switches to high VTL. With valid instructions
         // to save restore register states.
         unsafe {
@@ -337,11 +551,15 @@ fn switch_to_high_vtl(&mut self) {
                 call_address = sym HvCall::vtl_call,
             );
         }
+        set_active_vtl(self.my_vp_idx, self.my_vtl);
+        chaos::delay(self.my_vp_idx, ChaosPoint::AfterSwitchToHigh);
     }
 
     /// Return from a high VTL back to the low VTL (`vtl_return`).
     #[inline(never)]
     fn switch_to_low_vtl(&mut self) {
+        chaos::delay(self.my_vp_idx, ChaosPoint::BeforeSwitchToLow);
+        set_active_vtl(self.my_vp_idx, Vtl::Vtl0);
         // SAFETY: we are calling a valid function that switches to low VTL. With valid instructions
         // to save restore register states.
         unsafe {
@@ -381,6 +599,9 @@ fn switch_to_low_vtl(&mut self) {
                 call_address = sym HvCall::vtl_return,
             );
         }
+        set_active_vtl(self.my_vp_idx, self.my_vtl);
+        vp_assist::record_entry(self.my_vp_idx);
+        chaos::delay(self.my_vp_idx, ChaosPoint::AfterSwitchToLow);
     }
 
     // Set the state of a virtual processor (VP) with the specified VTL.
@@ -405,16 +626,128 @@ fn get_vp_register_with_vtl(&mut self, register_index: u32, vtl: Vtl) -> TmkResu
             .map(|v| v.as_u64())
             .map_err(|e| e.into())
     }
+
+    fn set_vp_register_on_vp(
+        &mut self,
+        vp_index: u32,
+        register_index: u32,
+        value: u64,
+        vtl: Vtl,
+    ) -> TmkResult<()> {
+        let vtl = vtl_transform(vtl);
+        let reg_value = HvRegisterValue(AlignedU128::from(value));
+        self.hvcall
+            .set_vp_register(
+                vp_index,
+                hvdef::HvRegisterName(register_index),
+                reg_value,
+                Some(vtl),
+            )
+            .map_err(|e| e.into())
+    }
+
+    fn get_vp_register_on_vp(
+        &mut self,
+        vp_index: u32,
+        register_index: u32,
+        vtl: Vtl,
+    ) -> TmkResult<u64> {
+        let vtl = vtl_transform(vtl);
+        self.hvcall
+            .get_vp_register(vp_index, hvdef::HvRegisterName(register_index), Some(vtl))
+            .map(|v| v.as_u64())
+            .map_err(|e| e.into())
+    }
+
+    fn get_vp_descriptor_table(
+        &mut self,
+        vp_index: u32,
+        register_index: u32,
+        vtl: Vtl,
+    ) -> TmkResult<DescriptorTable> {
+        let vtl = vtl_transform(vtl);
+        Ok(self.hvcall.get_vp_descriptor_table(
+            vp_index,
+            hvdef::HvRegisterName(register_index),
+            Some(vtl),
+        )?)
+    }
+
+    fn set_vp_descriptor_table(
+        &mut self,
+        vp_index: u32,
+        register_index: u32,
+        table: DescriptorTable,
+        vtl: Vtl,
+    ) -> TmkResult<()> {
+        let vtl = vtl_transform(vtl);
+        self.hvcall.set_vp_descriptor_table(
+            vp_index,
+            hvdef::HvRegisterName(register_index),
+            table,
+            Some(vtl),
+        )?;
+        Ok(())
+    }
+
+    fn get_vp_secure_config(
+        &mut self,
+        target_vtl: Vtl,
+    ) -> TmkResult<HvRegisterVsmVpSecureVtlConfig> {
+        Ok(self.hvcall.get_vsm_vp_secure_config(target_vtl)?)
+    }
+
+    fn set_vp_secure_config(
+        &mut self,
+        target_vtl: Vtl,
+        config: HvRegisterVsmVpSecureVtlConfig,
+    ) -> TmkResult<()> {
+        self.hvcall.set_vsm_vp_secure_config(target_vtl, config)?;
+        Ok(())
+    }
+
+    /// Returns the reason recorded from the VTL1 VP assist page of the VP
+    /// as VTL1 last resumed there.
+    fn get_vtl_entry_reason(&self, vp_index: u32) -> TmkResult<HvVtlEntryReason> {
+        vp_assist::last_entry_reason(vp_index).ok_or(TmkError::NoData)
+    }
 }
 
 impl HvTestCtx {
     /// Return the index of the VP that is currently executing this code.
+    ///
+    /// CPUID traps to the hypervisor, too slow for the allocator and the
+    /// log to run on every call: once the VP cached its index, see
+    /// [`Self::cache_vp_idx`], it is read back with RDTSCP instead.
     pub(crate) fn get_vp_idx() -> u32 {
+        if VP_INDEX_CACHED.load(Ordering::Relaxed) {
+            let mut aux = 0;
+            // SAFETY: RDTSCP is supported, a VP cached its index.
+            unsafe { __rdtscp(&mut aux) };
+            // Zero on a VP, or in a VTL, that did not cache its index yet.
+            if aux != 0 {
+                return aux - 1;
+            }
+        }
         // SAFETY: we are executing a valid CPUID instruction.
         let result = unsafe { core::arch::x86_64::__cpuid(0x1) };
         (result.ebx >> 24) & 0xFF
     }
 
+    /// Keeps `vp_index`, the index of the current VP, for
+    /// [`Self::get_vp_idx`] in IA32_TSC_AUX of the current VTL. Does
+    /// nothing without RDTSCP.
+    pub(crate) fn cache_vp_idx(vp_index: u32) {
+        // SAFETY: CPUID is always available on x86_64.
+        let rdtscp = unsafe { __cpuid(0x8000_0001) }.edx & CPUID_80000001_EDX_RDTSCP != 0;
+        if !rdtscp {
+            return;
+        }
+        // SAFETY: IA32_TSC_AUX exists with RDTSCP and is only read by it.
+        unsafe { write_msr(IA32_TSC_AUX, vp_index as u64 + 1) };
+        VP_INDEX_CACHED.store(true, Ordering::Relaxed);
+    }
+
     /// Capture the current VP context, patch the entry point and stack
     /// so that the new VP starts in `exec_handler`.
     pub(crate) fn get_default_context(
@@ -429,28 +762,53 @@ pub(crate) fn get_default_context(
         self.exec_fn_with_current_context(handler)
     }
 
+    /// Wait for VP `vp_index` to halt in `vtl` with interrupts disabled,
+    /// as a parked VP does, returning whether it did.
+    fn wait_for_halt(&mut self, vp_index: u32, vtl: Vtl) -> TmkResult<bool> {
+        let vtl = Some(vtl_transform(vtl));
+        for _ in 0..HALT_POLL_LIMIT {
+            let activity = HvInternalActivityRegister::from(
+                self.hvcall
+                    .get_vp_register(
+                        vp_index,
+                        HvX64RegisterName::InternalActivityState.into(),
+                        vtl,
+                    )?
+                    .as_u64(),
+            );
+            let rflags = self
+                .hvcall
+                .get_vp_register(vp_index, HvX64RegisterName::Rflags.into(), vtl)?
+                .as_u64();
+            if activity.halt_suspend() && rflags & RFLAGS_IF == 0 {
+                return Ok(true);
+            }
+            core::hint::spin_loop();
+        }
+        Ok(false)
+    }
+
     /// Helper to return an arbitrary function with a captured VP context
     /// that can later be used to start a new VP/VTL instance.
     fn exec_fn_with_current_context(
         &mut self,
         func: fn(),
     ) -> Result<InitialVpContextX64, TmkError> {
-        let mut vp_context: InitialVpContextX64 = self
-            .hvcall
-            .get_current_vtl_vp_context()
-            .expect("Failed to get current VTL context");
-        let stack_layout = Layout::from_size_align(1024 * 1024, 16)
-            .expect("Failed to create layout for stack allocation");
+        let mut vp_context = VpContext::from_raw(self.hvcall.get_current_vtl_vp_context()?);
+        let stack_layout = Layout::from_size_align(stack_usage::VP_STACK_SIZE, 16)
+            .map_err(|_| TmkError::AllocationFailed)?;
         // SAFETY: the pointer is managed carefully and is not deallocated until the end of the test.
         let allocated_stack_ptr = unsafe { alloc(stack_layout) };
         if allocated_stack_ptr.is_null() {
             return Err(TmkError::AllocationFailed);
         }
         let stack_size = stack_layout.size();
+        // SAFETY: the stack was just allocated with `stack_layout` and no VP
+        // runs on it yet.
+        unsafe { stack_usage::paint(allocated_stack_ptr, stack_size) };
         let stack_top = allocated_stack_ptr as u64 + stack_size as u64;
-        let fn_address = func as usize as u64;
-        vp_context.rip = fn_address;
-        vp_context.rsp = stack_top;
-        Ok(vp_context)
+        vp_context.set_entry_point(func as usize as u64);
+        vp_context.set_stack_pointer(stack_top);
+        Ok(vp_context.into_raw())
     }
 }
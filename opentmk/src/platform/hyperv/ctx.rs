@@ -9,21 +9,73 @@
 use alloc::collections::btree_map::BTreeMap;
 use alloc::collections::btree_set::BTreeSet;
 use alloc::collections::linked_list::LinkedList;
+use alloc::vec::Vec;
 use core::fmt::Display;
+use core::ops::Range;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering;
 
+use hvdef::HvMapGpaFlags;
 use hvdef::Vtl;
 use hvdef::hypercall::HvInputVtl;
+use memory_range::MemoryRange;
 use spin::Mutex;
 
+use crate::chaos;
+use crate::chaos::ChaosPoint;
+use crate::context::ExtendedHypercallPlatformTrait;
+use crate::context::GpaOverlayPlatformTrait;
+use crate::context::SynicEventPlatformTrait;
 use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlAccessPlatformTrait;
 use crate::context::VtlPlatformTrait;
 use crate::platform::hyperv::arch::hypercall::HvCall;
+use crate::platform::hyperv::queue_stats;
+use crate::platform::hyperv::stack_usage;
+use crate::platform::hyperv::vtl_access::AccessCheck;
+use crate::tmkdefs::MAX_VPS;
 use crate::tmkdefs::TmkError;
 use crate::tmkdefs::TmkResult;
+use crate::tmkdefs::vp_slot;
 
-type CommandTable = BTreeMap<u32, LinkedList<(Box<dyn FnOnce(&mut HvTestCtx) + 'static>, Vtl)>>;
+/// A command waiting in the queue of a VP.
+pub(crate) struct QueuedCommand {
+    cmd: Box<dyn FnOnce(&mut HvTestCtx) + 'static>,
+    vtl: Vtl,
+    /// The VTL to go back to once the command ran and the queue is empty,
+    /// for a command returning a value to an issuer on the same VP.
+    return_to: Option<Vtl>,
+}
+
+impl QueuedCommand {
+    pub(crate) fn new(cmd: Box<dyn FnOnce(&mut HvTestCtx) + 'static>, vtl: Vtl) -> Self {
+        Self {
+            cmd,
+            vtl,
+            return_to: None,
+        }
+    }
+
+    /// The VTL the command runs in.
+    pub(crate) fn vtl(&self) -> Vtl {
+        self.vtl
+    }
+}
+
+type CommandTable = BTreeMap<u32, LinkedList<QueuedCommand>>;
 static mut CMD: Mutex<CommandTable> = Mutex::new(BTreeMap::new());
 static VP_SET: Mutex<BTreeSet<u32>> = Mutex::new(BTreeSet::new());
+static FAULTED_VP_SET: Mutex<BTreeSet<u32>> = Mutex::new(BTreeSet::new());
+static CRASH_ISOLATION: AtomicBool = AtomicBool::new(false);
+/// The VTL each VP last entered, see [`active_vtl`].
+static ACTIVE_VTL: [AtomicU8; MAX_VPS] = [const { AtomicU8::new(0) }; MAX_VPS];
+/// The VTL the command loops idle in, see [`set_idle_vtl`].
+static IDLE_VTL: AtomicU8 = AtomicU8::new(NO_IDLE_VTL);
+/// Value of [`IDLE_VTL`] for command loops staying in the VTL of the last
+/// command.
+const NO_IDLE_VTL: u8 = u8::MAX;
 
 #[expect(static_mut_refs)]
 pub(crate) fn cmdt() -> &'static Mutex<CommandTable> {
@@ -36,6 +88,91 @@ pub(crate) fn get_vp_set() -> &'static Mutex<BTreeSet<u32>> {
     &VP_SET
 }
 
+/// Returns whether VP `vp_index` was started through the test context, and
+/// runs a command loop.
+pub fn vp_started(vp_index: u32) -> bool {
+    get_vp_set().lock().contains(&vp_index)
+}
+
+pub(crate) fn get_faulted_vp_set() -> &'static Mutex<BTreeSet<u32>> {
+    &FAULTED_VP_SET
+}
+
+/// Records that `vp` is about to run, or now runs, in `vtl`.
+pub(crate) fn set_active_vtl(vp: u32, vtl: Vtl) {
+    ACTIVE_VTL[vp as usize].store(vtl as u8, Ordering::Release);
+}
+
+/// Returns the VTL `vp` runs in, as tracked across the VTL switches made
+/// through the test context. Cheap enough for the allocator to use. Fails
+/// with [`TmkError::InvalidVpIndex`] for a VP past [`MAX_VPS`].
+pub fn active_vtl(vp: u32) -> TmkResult<Vtl> {
+    Ok(match ACTIVE_VTL[vp_slot(vp)?].load(Ordering::Acquire) {
+        0 => Vtl::Vtl0,
+        1 => Vtl::Vtl1,
+        _ => Vtl::Vtl2,
+    })
+}
+
+/// Sets the VTL the command loops go back to once their queue is empty.
+pub(crate) fn set_idle_vtl(vtl: Option<Vtl>) {
+    IDLE_VTL.store(vtl.map_or(NO_IDLE_VTL, |vtl| vtl as u8), Ordering::Release);
+}
+
+/// Returns the VTL the command loops go back to once their queue is
+/// empty, `None` if they stay in the VTL of the last command.
+pub fn idle_vtl() -> Option<Vtl> {
+    match IDLE_VTL.load(Ordering::Acquire) {
+        0 => Some(Vtl::Vtl0),
+        1 => Some(Vtl::Vtl1),
+        _ => None,
+    }
+}
+
+pub(crate) fn set_crash_isolation(enabled: bool) {
+    CRASH_ISOLATION.store(enabled, Ordering::Release);
+}
+
+pub(crate) fn get_faulted_vps() -> Vec<u32> {
+    get_faulted_vp_set().lock().iter().copied().collect()
+}
+
+/// Record that the current VP faulted.
+///
+/// Returns the index of the VP if it should be parked until it is restarted,
+/// or `None` if the fault should end the test run.
+pub(crate) fn park_faulted_vp() -> Option<u32> {
+    let vp_index = HvTestCtx::get_vp_idx();
+    if vp_index == 0 || !CRASH_ISOLATION.load(Ordering::Acquire) {
+        return None;
+    }
+    get_faulted_vp_set().lock().insert(vp_index);
+    Some(vp_index)
+}
+
+/// Drop all the commands still queued for `vp_index`, returning how many
+/// were dropped.
+pub(crate) fn resync_command_queue(vp_index: u32) -> usize {
+    let dropped = cmdt()
+        .lock()
+        .get_mut(&vp_index)
+        .map_or(0, |queue| core::mem::take(queue).len());
+    queue_stats::count_dropped(dropped);
+    dropped
+}
+
+/// Pushes `cmd` to the back of the queue of `vp_index`, counting it as
+/// rejected if the VP has no queue.
+pub(crate) fn push_command(vp_index: u32, cmd: QueuedCommand) -> TmkResult<()> {
+    let mut cmdt = cmdt().lock();
+    let Some(queue) = cmdt.get_mut(&vp_index) else {
+        queue_stats::count_rejected(1);
+        return Err(TmkError::InvalidVpIndex);
+    };
+    queue.push_back(cmd);
+    Ok(())
+}
+
 fn register_command_queue(vp_index: u32) {
     log::trace!("registering command queue for vp: {}", vp_index);
     if cmdt().lock().get(&vp_index).is_none() {
@@ -102,9 +239,44 @@ pub fn init(&mut self, vtl: Vtl) -> TmkResult<()> {
         }
         self.my_vtl = vtl;
         self.my_vp_idx = Self::get_vp_idx();
+        vp_slot(self.my_vp_idx)?;
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        Self::cache_vp_idx(self.my_vp_idx);
+        set_active_vtl(self.my_vp_idx, vtl);
+        super::irq_hvcall::prepare(self.my_vp_idx);
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        if vtl == Vtl::Vtl1
+            && let Err(e) = super::vp_assist::enable(self.my_vp_idx)
+        {
+            log::warn!(
+                "no VP assist page for VTL1 on vp {}: {:?}",
+                self.my_vp_idx,
+                e
+            );
+        }
         Ok(())
     }
 
+    /// Turns `token` into the entry to queue for its target VP, returned
+    /// with the index of that VP. A command returning a value to a caller
+    /// on the same VP brings the VP back to the VTL of the caller.
+    pub(crate) fn queued_command(
+        &self,
+        token: VpExecToken<HvTestCtx>,
+    ) -> Option<(u32, QueuedCommand)> {
+        let returns = token.returns_result();
+        let (vp_index, vtl, cmd) = token.get();
+        let return_to = (returns && vp_index == self.my_vp_idx).then_some(self.my_vtl);
+        Some((
+            vp_index,
+            QueuedCommand {
+                cmd: cmd?,
+                vtl,
+                return_to,
+            },
+        ))
+    }
+
     pub(crate) fn secure_exec_handler() {
         HvTestCtx::exec_handler(Vtl::Vtl1);
     }
@@ -115,43 +287,172 @@ pub(crate) fn general_exec_handler() {
 
     /// Busy-loop executor that runs on every VP.  
     /// Extracts commands from the per-VP queue and executes them in the
-    /// appropriate VTL, switching VTLs when necessary.
+    /// appropriate VTL, switching VTLs when necessary. With the queue
+    /// empty, it goes back to the idle VTL if one is set and the VP runs
+    /// the command loop there, see [`set_idle_vtl`].
     fn exec_handler(vtl: Vtl) {
         let mut ctx = HvTestCtx::new();
         ctx.init(vtl).expect("error: failed to init on a VP");
+        let stack = stack_usage::current();
+        let mut commands = 0;
         loop {
             let mut vtl: Option<Vtl> = None;
-            let mut cmd: Option<Box<dyn FnOnce(&mut HvTestCtx) + 'static>> = None;
+            let mut cmd: Option<QueuedCommand> = None;
 
+            chaos::delay(ctx.my_vp_idx, ChaosPoint::BeforeDequeue);
             {
                 let mut cmdt = cmdt().lock();
                 let d = cmdt.get_mut(&ctx.my_vp_idx);
                 if let Some(d) = d {
                     if !d.is_empty() {
-                        let (_c, v) = d.front().unwrap();
-                        if *v == ctx.my_vtl {
-                            let (c, _v) = d.pop_front().unwrap();
-                            cmd = Some(c);
+                        let front = d.front().unwrap();
+                        if front.vtl == ctx.my_vtl {
+                            cmd = d.pop_front();
+                            queue_stats::count_dequeued();
                         } else {
-                            vtl = Some(*v);
+                            vtl = Some(front.vtl);
+                            queue_stats::count_bounced();
                         }
+                    } else {
+                        vtl = ctx.idle_switch();
                     }
                 }
             }
 
             if let Some(vtl) = vtl {
-                if vtl == Vtl::Vtl0 {
-                    ctx.switch_to_low_vtl();
-                } else {
-                    ctx.switch_to_high_vtl();
-                }
+                ctx.switch_to_vtl(vtl);
             }
 
             if let Some(cmd) = cmd {
-                cmd(&mut ctx);
+                chaos::delay(ctx.my_vp_idx, ChaosPoint::AfterDequeue);
+                (cmd.cmd)(&mut ctx);
+                commands += 1;
+                stack_usage::write_command_complete(
+                    ctx.my_vp_idx,
+                    ctx.my_vtl,
+                    commands,
+                    stack.as_ref(),
+                );
+                if let Some(return_to) = cmd.return_to.filter(|&v| v != ctx.my_vtl) {
+                    let idle = cmdt()
+                        .lock()
+                        .get(&ctx.my_vp_idx)
+                        .is_none_or(|d| d.is_empty());
+                    if idle {
+                        ctx.switch_to_vtl(return_to);
+                    }
+                }
             }
         }
     }
+
+    /// Returns the idle VTL if the command loop of this VP should go there.
+    /// VP0 runs the tests rather than a command loop in VTL0, and VTL1 only
+    /// runs one on the VPs it was enabled on.
+    fn idle_switch(&self) -> Option<Vtl> {
+        let vtl = idle_vtl().filter(|&vtl| vtl != self.my_vtl)?;
+        let has_loop = match vtl {
+            Vtl::Vtl0 => self.my_vp_idx != 0,
+            _ => get_vp_set().lock().contains(&self.my_vp_idx),
+        };
+        has_loop.then_some(vtl)
+    }
+
+    fn switch_to_vtl(&mut self, vtl: Vtl) {
+        if vtl == Vtl::Vtl0 {
+            self.switch_to_low_vtl();
+        } else {
+            self.switch_to_high_vtl();
+        }
+    }
+}
+
+impl GpaOverlayPlatformTrait for HvTestCtx {
+    /// Map the overlay with full access through `HvCallMapGpaPages`.
+    fn map_gpa_pages(&mut self, target_gpn: u64, source_gpns: &[u64]) -> TmkResult<()> {
+        self.hvcall
+            .map_gpa_pages(target_gpn, source_gpns, hvdef::HV_MAP_GPA_PERMISSIONS_ALL)?;
+        Ok(())
+    }
+
+    fn unmap_gpa_pages(&mut self, target_gpn: u64, page_count: usize) -> TmkResult<()> {
+        self.hvcall.unmap_gpa_pages(target_gpn, page_count)?;
+        Ok(())
+    }
+}
+
+impl SynicEventPlatformTrait for HvTestCtx {
+    fn signal_event_direct(
+        &mut self,
+        vp_index: u32,
+        vtl: Vtl,
+        sint: u8,
+        flag: u16,
+    ) -> TmkResult<bool> {
+        Ok(self.hvcall.signal_event_direct(vp_index, vtl, sint, flag)?)
+    }
+
+    fn signal_event(&mut self, connection_id: u32, flag: u16) -> TmkResult<()> {
+        self.hvcall.signal_event(connection_id, flag)?;
+        Ok(())
+    }
+
+    fn post_message(
+        &mut self,
+        connection_id: u32,
+        message_type: u32,
+        payload: &[u8],
+    ) -> TmkResult<()> {
+        self.hvcall
+            .post_message(connection_id, message_type, payload)?;
+        Ok(())
+    }
+
+    fn post_message_unchecked(
+        &mut self,
+        connection_id: u32,
+        message_type: u32,
+        payload_size: u32,
+    ) -> TmkResult<()> {
+        self.hvcall
+            .post_message_unchecked(connection_id, message_type, payload_size)?;
+        Ok(())
+    }
+}
+
+impl ExtendedHypercallPlatformTrait for HvTestCtx {
+    fn query_extended_capabilities(&mut self) -> TmkResult<u64> {
+        Ok(self.hvcall.query_extended_capabilities()?)
+    }
+}
+
+impl VtlAccessPlatformTrait for HvTestCtx {
+    fn set_vtl_protection_mask(
+        &mut self,
+        range: Range<u64>,
+        vtl: Vtl,
+        flags: HvMapGpaFlags,
+    ) -> TmkResult<()> {
+        self.hvcall
+            .modify_vtl_protection_mask(MemoryRange::new(range), vtl, flags)?;
+        Ok(())
+    }
+
+    fn check_vtl_access(
+        &mut self,
+        gpns: &[u64],
+        vtl: Vtl,
+        access: HvMapGpaFlags,
+    ) -> TmkResult<Vec<AccessCheck>> {
+        let outputs = self
+            .hvcall
+            .check_sparse_gpa_page_vtl_access(gpns, vtl, access)?;
+        Ok(gpns
+            .iter()
+            .zip(outputs)
+            .map(|(&gpn, output)| AccessCheck::new(gpn, output))
+            .collect())
+    }
 }
 
 impl From<hvdef::HvError> for TmkError {
@@ -0,0 +1,157 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Typed view of the GDTR and IDTR.
+//!
+//! The hypervisor reports descriptor table registers as an
+//! [`HvX64TableRegister`] packed in a register value; [`DescriptorTable`]
+//! is the base and limit pair tests work with, along with the checks that a
+//! table a VP loaded is one the processor can use.
+
+use hvdef::HvRegisterValue;
+use hvdef::HvX64TableRegister;
+use serde::Serialize;
+
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// Size of a GDT entry. System descriptors take two.
+pub const GDT_ENTRY_SIZE: u32 = 8;
+/// Size of a long mode IDT entry.
+pub const IDT_ENTRY_SIZE: u32 = 16;
+/// Number of interrupt vectors.
+pub const IDT_MAX_ENTRIES: u32 = 256;
+/// Bits of a canonical linear address, sign extended above.
+const LINEAR_ADDRESS_BITS: u32 = 48;
+
+/// A descriptor table register, GDTR or IDTR.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DescriptorTable {
+    /// Linear address of the table.
+    pub base: u64,
+    /// Offset of the last byte of the table, one less than its size.
+    pub limit: u16,
+}
+
+impl DescriptorTable {
+    /// Returns the table at `base` holding `entries` entries of
+    /// `entry_size` bytes, failing if they do not fit a 16-bit limit.
+    pub fn with_entries(base: u64, entries: u32, entry_size: u32) -> TmkResult<Self> {
+        let size = entries
+            .checked_mul(entry_size)
+            .filter(|&size| size != 0)
+            .ok_or(TmkError::InvalidParameter)?;
+        let limit = u16::try_from(size - 1).map_err(|_| TmkError::InvalidParameter)?;
+        Ok(Self { base, limit })
+    }
+
+    /// Size of the table in bytes.
+    pub fn size(&self) -> u32 {
+        self.limit as u32 + 1
+    }
+
+    /// Number of whole entries of `entry_size` bytes in the table.
+    pub fn entries(&self, entry_size: u32) -> u32 {
+        self.size() / entry_size
+    }
+
+    /// Checks that the base is canonical, that the table holds a whole
+    /// number of entries of `entry_size` bytes, and no more than
+    /// `max_entries` if given.
+    pub fn validate(&self, entry_size: u32, max_entries: Option<u32>) -> TmkResult<()> {
+        let shift = 64 - LINEAR_ADDRESS_BITS;
+        if ((self.base << shift) as i64 >> shift) as u64 != self.base {
+            return Err(TmkError::InvalidParameter);
+        }
+        if self.size() % entry_size != 0 {
+            return Err(TmkError::InvalidParameter);
+        }
+        if max_entries.is_some_and(|max| self.entries(entry_size) > max) {
+            return Err(TmkError::InvalidParameter);
+        }
+        Ok(())
+    }
+
+    /// Checks the table as a GDT.
+    pub fn validate_gdt(&self) -> TmkResult<()> {
+        self.validate(GDT_ENTRY_SIZE, None)
+    }
+
+    /// Checks the table as a long mode IDT.
+    pub fn validate_idt(&self) -> TmkResult<()> {
+        self.validate(IDT_ENTRY_SIZE, Some(IDT_MAX_ENTRIES))
+    }
+}
+
+impl From<HvX64TableRegister> for DescriptorTable {
+    fn from(value: HvX64TableRegister) -> Self {
+        Self {
+            base: value.base,
+            limit: value.limit,
+        }
+    }
+}
+
+impl From<DescriptorTable> for HvX64TableRegister {
+    fn from(value: DescriptorTable) -> Self {
+        Self {
+            pad: [0; 3],
+            limit: value.limit,
+            base: value.base,
+        }
+    }
+}
+
+impl From<HvRegisterValue> for DescriptorTable {
+    fn from(value: HvRegisterValue) -> Self {
+        value.as_table().into()
+    }
+}
+
+impl From<DescriptorTable> for HvRegisterValue {
+    fn from(value: DescriptorTable) -> Self {
+        HvX64TableRegister::from(value).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_value() {
+        let table = DescriptorTable {
+            base: 0xffff_8000_0001_0000,
+            limit: 0xfff,
+        };
+        let value = HvRegisterValue::from(table);
+        assert_eq!(value.as_table().limit, 0xfff);
+        assert_eq!(DescriptorTable::from(value), table);
+    }
+
+    #[test]
+    fn test_validate() {
+        let idt = DescriptorTable::with_entries(0x1000, IDT_MAX_ENTRIES, IDT_ENTRY_SIZE).unwrap();
+        assert_eq!(idt.limit, 0xfff);
+        assert!(idt.validate_idt().is_ok());
+        let short = DescriptorTable {
+            limit: idt.limit - 8,
+            ..idt
+        };
+        assert!(short.validate_idt().is_err());
+        assert!(short.validate_gdt().is_ok());
+        let non_canonical = DescriptorTable {
+            base: 0x0000_8000_0000_0000,
+            ..idt
+        };
+        assert!(non_canonical.validate_idt().is_err());
+        let large = DescriptorTable {
+            limit: u16::MAX,
+            ..idt
+        };
+        assert!(large.validate_idt().is_err());
+        assert!(DescriptorTable::with_entries(0, 8192, GDT_ENTRY_SIZE).is_ok());
+        assert!(DescriptorTable::with_entries(0, 8193, GDT_ENTRY_SIZE).is_err());
+        assert!(DescriptorTable::with_entries(0, 0, GDT_ENTRY_SIZE).is_err());
+    }
+}
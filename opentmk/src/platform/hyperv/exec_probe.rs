@@ -0,0 +1,178 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Code pages whose execution VTL1 controls.
+//!
+//! A [`CodePage`] holds a small function returning [`MAGIC`] in a page of
+//! its own, mapped executable in the guest page tables, so that whether
+//! VTL0 may run it only depends on the execute permission VTL1 grants the
+//! page. When VTL0 calls it without that permission, the hypervisor
+//! raises a memory intercept to VTL1 on the instruction fetch.
+//! [`handle_intercept`] serves it by emulating the `ret` of the function
+//! with a return value of zero, so VTL0 resumes after the call and sees
+//! the function did not run.
+//!
+//! [`handle_intercept`] runs in interrupt context and only touches atomics
+//! and the interrupt context [`HvCall`](super::arch::hypercall::HvCall).
+
+use alloc::alloc::alloc;
+use core::alloc::Layout;
+use core::ops::Range;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use hvdef::HV_PAGE_SIZE;
+use hvdef::HvInterceptAccessType;
+use hvdef::HvMessageType;
+use hvdef::HvX64MemoryInterceptMessage;
+use hvdef::HvX64RegisterName;
+use hvdef::Vtl;
+
+use super::ctx::vtl_transform;
+use super::irq_hvcall::with_irq_hvcall;
+use super::synic;
+use crate::arch::paging;
+use crate::arch::paging::PageAccess;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// The value the function of a [`CodePage`] returns when it runs.
+pub const MAGIC: u32 = 0x7e57_c0de;
+const MOV_EAX: u8 = 0xb8;
+const RET: u8 = 0xc3;
+const INT3: u8 = 0xcc;
+/// Offset of the `ret` ending the function.
+const RET_OFFSET: u64 = 5;
+
+static INTERCEPTED: AtomicU64 = AtomicU64::new(0);
+static UNHANDLED: AtomicU64 = AtomicU64::new(0);
+
+/// A page holding `mov eax, MAGIC; ret`, the rest filled with `int3`.
+pub struct CodePage {
+    addr: u64,
+}
+
+impl CodePage {
+    /// Allocates the page, writes the function and makes the page
+    /// executable in the guest page tables.
+    ///
+    /// The page is never freed: VTL protections applied to it may outlive
+    /// the test.
+    pub fn new() -> TmkResult<Self> {
+        let layout = Layout::from_size_align(HV_PAGE_SIZE as usize, HV_PAGE_SIZE as usize)
+            .map_err(|_| TmkError::AllocationFailed)?;
+        // SAFETY: the layout has a non-zero size.
+        let ptr = unsafe { alloc(layout) };
+        if ptr.is_null() {
+            return Err(TmkError::AllocationFailed);
+        }
+        // SAFETY: the page was just allocated and nothing else refers to it.
+        let page = unsafe { core::slice::from_raw_parts_mut(ptr, HV_PAGE_SIZE as usize) };
+        page.fill(INT3);
+        page[0] = MOV_EAX;
+        page[1..5].copy_from_slice(&MAGIC.to_le_bytes());
+        page[RET_OFFSET as usize] = RET;
+
+        paging::set_page_access(
+            ptr as u64,
+            PageAccess {
+                user: false,
+                executable: true,
+            },
+        )?;
+        Ok(Self { addr: ptr as u64 })
+    }
+
+    /// The address of the function.
+    pub fn address(&self) -> u64 {
+        self.addr
+    }
+
+    /// The address of the `ret` ending the function, a target for
+    /// `fault::probe_exec`.
+    pub fn ret_address(&self) -> u64 {
+        self.addr + RET_OFFSET
+    }
+
+    /// The guest physical addresses of the page.
+    pub fn range(&self) -> Range<u64> {
+        self.addr..self.addr + HV_PAGE_SIZE
+    }
+
+    /// Calls the function, see [`call`].
+    pub fn call(&self) -> u32 {
+        // SAFETY: the page holds the function.
+        unsafe { call(self.addr) }
+    }
+}
+
+/// Calls the function of a [`CodePage`] at `addr` and returns its return
+/// value, [`MAGIC`] if it ran and zero if its execution was intercepted
+/// and served by [`handle_intercept`].
+///
+/// # Safety
+///
+/// `addr` must be the address of a [`CodePage`] function.
+pub unsafe fn call(addr: u64) -> u32 {
+    // SAFETY: the function only clobbers EAX, and if its execution is
+    // intercepted VTL1 returns from it.
+    let f: extern "sysv64" fn() -> u32 = unsafe { core::mem::transmute(addr as usize) };
+    f()
+}
+
+/// Returns the number of execute intercepts served and the number of
+/// intercepts that could not be, since the last [`reset`].
+pub fn stats() -> (u64, u64) {
+    (
+        INTERCEPTED.load(Ordering::Relaxed),
+        UNHANDLED.load(Ordering::Relaxed),
+    )
+}
+
+/// Resets the counters.
+pub fn reset() {
+    INTERCEPTED.store(0, Ordering::Relaxed);
+    UNHANDLED.store(0, Ordering::Relaxed);
+}
+
+/// Returns to the caller of the intercepted function, with a return value
+/// of zero.
+fn emulate_return(message: &HvX64MemoryInterceptMessage) -> TmkResult<()> {
+    if message.header.intercept_access_type != HvInterceptAccessType::EXECUTE {
+        return Err(TmkError::InvalidParameter);
+    }
+    let vtl0 = Some(vtl_transform(Vtl::Vtl0));
+    with_irq_hvcall(|hvcall| -> TmkResult<()> {
+        let rsp = hvcall
+            .get_register(HvX64RegisterName::Rsp.into(), vtl0)?
+            .as_u64();
+        // SAFETY: the VTL0 stack is identity mapped and its top holds the
+        // return address pushed by the call.
+        let return_address = unsafe { (rsp as *const u64).read_volatile() };
+        hvcall.set_register(HvX64RegisterName::Rax.into(), 0u64.into(), vtl0)?;
+        hvcall.set_register(HvX64RegisterName::Rsp.into(), (rsp + 8).into(), vtl0)?;
+        hvcall.set_register(HvX64RegisterName::Rip.into(), return_address.into(), vtl0)?;
+        Ok(())
+    })?
+}
+
+/// Secure intercept handler serving VTL0 calls to code pages it may not
+/// execute. Install it on the secure intercept vector of the VTL1 side.
+pub fn handle_intercept() {
+    let Some(message) = synic::poll_current_message(hvdef::HV_SYNIC_INTERCEPTION_SINT_INDEX) else {
+        return;
+    };
+    if message.header.typ != HvMessageType::HvMessageTypeGpaIntercept {
+        UNHANDLED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    match emulate_return(message.as_message::<HvX64MemoryInterceptMessage>()) {
+        Ok(()) => {
+            INTERCEPTED.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(e) => {
+            UNHANDLED.fetch_add(1, Ordering::Relaxed);
+            crate::log_fmt_nostdalloc!(log::Level::Error, "execute intercept failed: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,127 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Extended hypercall availability.
+//!
+//! Extended hypercalls (codes `0x8001` and up) are implemented by the host,
+//! not the hypervisor, and are only reachable with the
+//! `EnableExtendedHypercalls` privilege. [`probe`] finds out which of them
+//! the host implements so that tests depending on one can skip with a clear
+//! reason instead of failing on an unexpected status.
+
+use alloc::vec::Vec;
+
+use serde::Serialize;
+
+use crate::context::ExtendedHypercallPlatformTrait;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// Code of the first extended hypercall of the capability mask.
+const FIRST_MASKED_CALL: u16 = 0x8002;
+
+/// Extended hypercalls defined by the TLFS, in capability mask order.
+pub const KNOWN_EXTENDED_CALLS: &[(u16, &str)] = &[
+    (0x8002, "HvExtCallGetBootZeroedMemory"),
+    (0x8003, "HvExtCallMemoryHeatHint"),
+    (0x8004, "HvExtCallEpfSetup"),
+    (0x8005, "HvExtCallSchedulerAssistSetup"),
+    (0x8006, "HvExtCallMemoryHeatHintAsync"),
+];
+
+/// Extended hypercalls available to the partition.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ExtendedCapabilities {
+    /// Whether `HvExtCallQueryCapabilities` itself is available.
+    pub available: bool,
+    /// Mask returned by `HvExtCallQueryCapabilities`, 0 if unavailable.
+    pub mask: u64,
+}
+
+impl ExtendedCapabilities {
+    /// Returns whether the host implements the extended hypercall `code`.
+    pub fn supports(&self, code: u16) -> bool {
+        match code {
+            0x8001 => self.available,
+            FIRST_MASKED_CALL..=0x8041 => self.mask & 1 << (code - FIRST_MASKED_CALL) != 0,
+            _ => false,
+        }
+    }
+}
+
+/// Queries the extended hypercalls the host implements.
+///
+/// A partition without the privilege, or a host that does not implement
+/// the extended namespace, is reported as unavailable rather than as an
+/// error.
+pub fn probe<T: ExtendedHypercallPlatformTrait>(ctx: &mut T) -> TmkResult<ExtendedCapabilities> {
+    match ctx.query_extended_capabilities() {
+        Ok(mask) => Ok(ExtendedCapabilities {
+            available: true,
+            mask,
+        }),
+        Err(TmkError::AccessDenied | TmkError::InvalidHypercallCode) => Ok(ExtendedCapabilities {
+            available: false,
+            mask: 0,
+        }),
+        Err(e) => Err(e),
+    }
+}
+
+#[derive(Serialize)]
+struct ExtendedCall {
+    code: u16,
+    name: &'static str,
+    supported: bool,
+}
+
+#[derive(Serialize)]
+struct ExtendedHypercallsRecord {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    available: bool,
+    mask: u64,
+    calls: Vec<ExtendedCall>,
+}
+
+/// Writes the `extended_hypercalls` record describing `caps`.
+pub fn write_record(caps: &ExtendedCapabilities) {
+    let calls = KNOWN_EXTENDED_CALLS
+        .iter()
+        .map(|&(code, name)| ExtendedCall {
+            code,
+            name,
+            supported: caps.supports(code),
+        })
+        .collect();
+    log::info!(
+        "extended hypercalls available: {}, mask {:#x}",
+        caps.available,
+        caps.mask
+    );
+    crate::tmk_logger::write_record(&ExtendedHypercallsRecord {
+        record_type: "extended_hypercalls",
+        available: caps.available,
+        mask: caps.mask,
+        calls,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_supports() {
+        let caps = ExtendedCapabilities {
+            available: true,
+            mask: 0b101,
+        };
+        assert!(caps.supports(0x8001));
+        assert!(caps.supports(0x8002));
+        assert!(!caps.supports(0x8003));
+        assert!(caps.supports(0x8004));
+        assert!(!caps.supports(0x8042));
+        assert!(!caps.supports(0x0001));
+    }
+}
@@ -0,0 +1,209 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Per-VP run time, as accounted by the hypervisor.
+//!
+//! With the `AccessVpRunTimeReg` privilege, the hypervisor exposes the time
+//! each VP has spent running in the `VpRuntime` register, and the
+//! partition reference time in `TimeRefCount`, both in 100ns units.
+//! [`sample`] reads both for a set of VPs; comparing two samples with
+//! [`runtime_deltas`] gives the share of the interval each VP ran, which
+//! lets scheduling tests report VP run time and flag VPs starved during a
+//! long operation.
+//!
+//! The run time of a VP covers all of its VTLs. [`VtlRuntime`] attributes
+//! it to the VTL running between the points a test switches VTLs, to catch
+//! one VTL starving another on the same VP.
+
+use alloc::vec::Vec;
+
+use hvdef::HvAllArchRegisterName;
+use hvdef::Vtl;
+use serde::Serialize;
+
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VtlPlatformTrait;
+use crate::tmkdefs::TmkResult;
+
+/// The run time of a VP at a point in time.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RuntimeSample {
+    /// The VP sampled.
+    pub vp: u32,
+    /// The time the VP has run, in 100ns units.
+    pub runtime: u64,
+    /// The partition reference time when the VP was sampled, in 100ns
+    /// units.
+    pub reference: u64,
+}
+
+/// The run time of a VP between two samples.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RuntimeDelta {
+    /// The VP.
+    pub vp: u32,
+    /// The time the VP ran, in 100ns units.
+    pub runtime: u64,
+    /// The reference time elapsed, in 100ns units.
+    pub elapsed: u64,
+    /// The share of the elapsed time the VP ran, in thousandths.
+    pub share_permille: u64,
+}
+
+fn read<T>(ctx: &mut T, vp: u32, vtl: Vtl, register: HvAllArchRegisterName) -> TmkResult<u64>
+where
+    T: VtlPlatformTrait,
+{
+    ctx.get_vp_register_on_vp(vp, register.0, vtl)
+}
+
+/// Reads the run time of each VP in `vps`.
+///
+/// Fails if the hypervisor does not expose the run time to the partition,
+/// or the registers of other VPs to the caller.
+pub fn sample<T>(ctx: &mut T, vps: impl IntoIterator<Item = u32>) -> TmkResult<Vec<RuntimeSample>>
+where
+    T: VtlPlatformTrait,
+{
+    let vtl = ctx.get_current_vtl()?;
+    vps.into_iter()
+        .map(|vp| {
+            Ok(RuntimeSample {
+                vp,
+                runtime: read(ctx, vp, vtl, HvAllArchRegisterName::VpRuntime)?,
+                reference: read(ctx, vp, vtl, HvAllArchRegisterName::TimeRefCount)?,
+            })
+        })
+        .collect()
+}
+
+/// Returns the run time of each VP sampled in both `before` and `after`.
+pub fn runtime_deltas(before: &[RuntimeSample], after: &[RuntimeSample]) -> Vec<RuntimeDelta> {
+    after
+        .iter()
+        .filter_map(|a| {
+            let b = before.iter().find(|b| b.vp == a.vp)?;
+            let runtime = a.runtime.saturating_sub(b.runtime);
+            let elapsed = a.reference.saturating_sub(b.reference);
+            Some(RuntimeDelta {
+                vp: a.vp,
+                runtime,
+                elapsed,
+                share_permille: if elapsed == 0 {
+                    0
+                } else {
+                    (runtime as u128 * 1000 / elapsed as u128) as u64
+                },
+            })
+        })
+        .collect()
+}
+
+/// Returns the VPs of `deltas` that ran less than `min_permille`
+/// thousandths of the elapsed time.
+pub fn starved(deltas: &[RuntimeDelta], min_permille: u64) -> Vec<u32> {
+    deltas
+        .iter()
+        .filter(|d| d.share_permille < min_permille)
+        .map(|d| d.vp)
+        .collect()
+}
+
+/// Run time of the current VP split by VTL.
+///
+/// Call [`VtlRuntime::switch`] whenever the VP leaves a VTL, from the VTL
+/// it leaves: the run time since the previous call is charged to it.
+pub struct VtlRuntime {
+    vp: u32,
+    last: u64,
+    per_vtl: [u64; 3],
+}
+
+impl VtlRuntime {
+    /// Starts accounting the run time of the current VP.
+    pub fn start<T>(ctx: &mut T) -> TmkResult<Self>
+    where
+        T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+    {
+        let vp = ctx.get_current_vp()?;
+        let vtl = ctx.get_current_vtl()?;
+        Ok(Self {
+            vp,
+            last: read(ctx, vp, vtl, HvAllArchRegisterName::VpRuntime)?,
+            per_vtl: [0; 3],
+        })
+    }
+
+    /// Charges the run time since the previous call to the current VTL.
+    pub fn switch<T>(&mut self, ctx: &mut T) -> TmkResult<()>
+    where
+        T: VtlPlatformTrait,
+    {
+        let vtl = ctx.get_current_vtl()?;
+        let now = read(ctx, self.vp, vtl, HvAllArchRegisterName::VpRuntime)?;
+        self.per_vtl[vtl as usize] += now.saturating_sub(self.last);
+        self.last = now;
+        Ok(())
+    }
+
+    /// The run time charged to each VTL, in 100ns units, indexed by VTL.
+    pub fn per_vtl(&self) -> [u64; 3] {
+        self.per_vtl
+    }
+}
+
+#[derive(Serialize)]
+struct VpRuntimeRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    name: &'a str,
+    deltas: &'a [RuntimeDelta],
+}
+
+/// Writes the `vp_runtime` record of `deltas` for the operation `name`.
+pub fn write_record(name: &str, deltas: &[RuntimeDelta]) {
+    for d in deltas {
+        log::info!(
+            "{}: VP{} ran {} of {} (x100ns), {} permille",
+            name,
+            d.vp,
+            d.runtime,
+            d.elapsed,
+            d.share_permille
+        );
+    }
+    crate::tmk_logger::write_record(&VpRuntimeRecord {
+        record_type: "vp_runtime",
+        name,
+        deltas,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runtime_deltas() {
+        let sample = |vp, runtime, reference| RuntimeSample {
+            vp,
+            runtime,
+            reference,
+        };
+        let before = [sample(0, 100, 1000), sample(1, 50, 1000)];
+        let after = [
+            sample(0, 1100, 3000),
+            sample(1, 60, 3000),
+            sample(2, 10, 3000),
+        ];
+        let deltas = runtime_deltas(&before, &after);
+        assert_eq!(
+            deltas
+                .iter()
+                .map(|d| (d.vp, d.runtime, d.elapsed, d.share_permille))
+                .collect::<Vec<_>>(),
+            [(0, 1000, 2000, 500), (1, 10, 2000, 5)]
+        );
+        assert_eq!(starved(&deltas, 100), [1]);
+    }
+}
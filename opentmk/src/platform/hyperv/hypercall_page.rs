@@ -0,0 +1,197 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Contents of the hypercall page.
+//!
+//! Once the hypercall interface is enabled, the hypervisor overlays the page
+//! named by the hypercall MSR with code issuing hypercalls through the
+//! instruction of the processor vendor, `vmcall` on Intel and `vmmcall` on
+//! AMD. Per the TLFS the page starts with the hypercall entry itself, a
+//! hypercall instruction followed by `ret`. With VSM, the page also holds
+//! the VTL call and return entries, at the offsets reported by
+//! `HvRegisterVsmCodePageOffsets`, which set up their hypercall code before
+//! the same sequence. The rest of the page is unspecified.
+//!
+//! The page belongs to the hypervisor: guest writes must not change it.
+
+use alloc::vec::Vec;
+use core::ptr::addr_of;
+
+use hvdef::HV_PAGE_SIZE;
+use hvdef::HvRegisterVsmCodePageOffsets;
+use minimal_rt::arch::hypercall::HYPERCALL_PAGE;
+use serde::Serialize;
+
+/// `vmcall`, the Intel hypercall instruction.
+pub const VMCALL: [u8; 3] = [0x0f, 0x01, 0xc1];
+/// `vmmcall`, the AMD hypercall instruction.
+pub const VMMCALL: [u8; 3] = [0x0f, 0x01, 0xd9];
+const RET: u8 = 0xc3;
+/// Bytes of a VTL entry searched for its hypercall instruction, enough for
+/// the register setup before it.
+const ENTRY_WINDOW: usize = 32;
+/// Bytes of each entry included in the record.
+const RECORDED_BYTES: usize = 16;
+
+/// Returns the address of the hypercall page.
+pub fn address() -> u64 {
+    addr_of!(HYPERCALL_PAGE) as u64
+}
+
+/// Reads the hypercall page.
+pub fn read() -> Vec<u8> {
+    let page = address() as *const u8;
+    (0..HV_PAGE_SIZE as usize)
+        // SAFETY: the hypercall page is mapped for the whole run.
+        .map(|i| unsafe { page.add(i).read_volatile() })
+        .collect()
+}
+
+/// Returns the hypercall instruction of the processor vendor.
+pub fn expected_instruction() -> [u8; 3] {
+    // SAFETY: CPUID leaf 0 is always available.
+    let vendor = unsafe { core::arch::x86_64::__cpuid(0) };
+    // "AuthenticAMD" and "HygonGenuine" split over EBX, EDX and ECX.
+    if (vendor.ebx, vendor.edx, vendor.ecx) == (0x6874_7541, 0x6974_6e65, 0x444d_4163)
+        || (vendor.ebx, vendor.edx, vendor.ecx) == (0x6f67_7948, 0x6e65_476e, 0x656e_6975)
+    {
+        VMMCALL
+    } else {
+        VMCALL
+    }
+}
+
+/// Returns the offset in `code` of the first `instruction` followed by a
+/// `ret`.
+pub fn find_hypercall(code: &[u8], instruction: [u8; 3]) -> Option<usize> {
+    code.windows(4)
+        .position(|w| w[..3] == instruction && w[3] == RET)
+}
+
+/// The check of an entry of the page.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct EntryCheck {
+    /// The entry, `hypercall`, `vtl_call` or `vtl_return`.
+    pub name: &'static str,
+    /// The offset of the entry in the page.
+    pub offset: usize,
+    /// The offset of the hypercall instruction from the entry, if found.
+    pub call_at: Option<usize>,
+    /// Whether the entry matches the TLFS.
+    pub valid: bool,
+    /// The first bytes of the entry.
+    pub bytes: Vec<u8>,
+}
+
+fn check_entry(
+    page: &[u8],
+    name: &'static str,
+    offset: usize,
+    instruction: [u8; 3],
+    at_start: bool,
+) -> EntryCheck {
+    let code = page.get(offset..).unwrap_or_default();
+    let call_at = find_hypercall(&code[..code.len().min(ENTRY_WINDOW)], instruction);
+    EntryCheck {
+        name,
+        offset,
+        call_at,
+        valid: if at_start {
+            call_at == Some(0)
+        } else {
+            call_at.is_some()
+        },
+        bytes: code[..code.len().min(RECORDED_BYTES)].to_vec(),
+    }
+}
+
+/// Checks the entries of `page` against the hypercall `instruction`. The
+/// VTL entries are checked if `vsm_offsets` is given.
+pub fn verify(
+    page: &[u8],
+    instruction: [u8; 3],
+    vsm_offsets: Option<HvRegisterVsmCodePageOffsets>,
+) -> Vec<EntryCheck> {
+    let mut entries = Vec::from([check_entry(page, "hypercall", 0, instruction, true)]);
+    if let Some(offsets) = vsm_offsets {
+        entries.push(check_entry(
+            page,
+            "vtl_call",
+            offsets.call_offset().into(),
+            instruction,
+            false,
+        ));
+        entries.push(check_entry(
+            page,
+            "vtl_return",
+            offsets.return_offset().into(),
+            instruction,
+            false,
+        ));
+    }
+    entries
+}
+
+/// A fault raised by writing the page.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct WriteFault {
+    /// The exception vector.
+    pub vector: u8,
+    /// The error code pushed by the processor.
+    pub error_code: u64,
+}
+
+#[derive(Serialize)]
+struct HypercallPageRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    address: u64,
+    entries: &'a [EntryCheck],
+    write_fault: Option<WriteFault>,
+    unchanged: bool,
+}
+
+/// Writes the `hypercall_page` record: the entry checks, the fault raised
+/// by the write attempt and whether the page survived it.
+pub fn write_record(entries: &[EntryCheck], write_fault: Option<WriteFault>, unchanged: bool) {
+    for entry in entries.iter().filter(|e| !e.valid) {
+        log::info!("invalid hypercall page entry: {:x?}", entry);
+    }
+    crate::tmk_logger::write_record(&HypercallPageRecord {
+        record_type: "hypercall_page",
+        address: address(),
+        entries,
+        write_fault,
+        unchanged,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let mut page = vec![0xcc; HV_PAGE_SIZE as usize];
+        page[..4].copy_from_slice(&[0x0f, 0x01, 0xc1, 0xc3]);
+        // A VTL call entry loading its hypercall code first.
+        page[0x10..0x1a]
+            .copy_from_slice(&[0xb8, 0x11, 0x00, 0x00, 0x00, 0x0f, 0x01, 0xc1, 0xc3, 0xcc]);
+        let offsets = HvRegisterVsmCodePageOffsets::new()
+            .with_call_offset(0x10)
+            .with_return_offset(0x20);
+        let entries = verify(&page, VMCALL, Some(offsets));
+        assert_eq!(
+            entries
+                .iter()
+                .map(|e| (e.name, e.call_at, e.valid))
+                .collect::<Vec<_>>(),
+            [
+                ("hypercall", Some(0), true),
+                ("vtl_call", Some(5), true),
+                ("vtl_return", None, false),
+            ]
+        );
+        assert!(!verify(&page, VMMCALL, None)[0].valid);
+    }
+}
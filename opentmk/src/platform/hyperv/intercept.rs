@@ -0,0 +1,180 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Capture of the VTL0 state interrupted by a secure intercept.
+//!
+//! When a secure intercept fires, VTL0 is frozen where it was interrupted.
+//! A VTL1 intercept handler calls [`capture_vtl0_context`] to read the VTL0
+//! RIP and RSP of its VP through `HvCallGetVpRegisters`; the capture is kept
+//! per VP so that, once the handler returned, test code can fetch it with
+//! [`take_captured`] and report it with [`write_intercept_record`]. Handlers
+//! run in interrupt context, so capturing neither allocates nor logs.
+//!
+//! Once it served the intercept, the handler picks where VTL0 resumes with
+//! [`resume_vtl0`]: past the intercepted instruction, as when emulating it,
+//! or at a recovery stub. Left alone, VTL0 executes the intercepted
+//! instruction again.
+
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use hvdef::HvX64InterceptMessageHeader;
+use hvdef::HvX64RegisterName;
+use hvdef::Vtl;
+use serde::Serialize;
+
+use super::ctx::HvTestCtx;
+use super::ctx::vtl_transform;
+use super::irq_hvcall::with_irq_hvcall;
+use crate::tmkdefs::MAX_VPS;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+use crate::tmkdefs::vp_slot;
+
+/// VTL0 state at the moment an intercept fired.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct InterceptedContext {
+    /// The vector the intercept was delivered on.
+    pub vector: u8,
+    /// The VTL0 instruction pointer.
+    pub rip: u64,
+    /// The VTL0 stack pointer.
+    pub rsp: u64,
+}
+
+struct CaptureSlot {
+    valid: AtomicBool,
+    vector: AtomicU8,
+    rip: AtomicU64,
+    rsp: AtomicU64,
+}
+
+static CAPTURES: [CaptureSlot; MAX_VPS] = [const {
+    CaptureSlot {
+        valid: AtomicBool::new(false),
+        vector: AtomicU8::new(0),
+        rip: AtomicU64::new(0),
+        rsp: AtomicU64::new(0),
+    }
+}; MAX_VPS];
+
+/// Reads the VTL0 RIP and RSP of the current VP and keeps them for
+/// [`take_captured`]. Must be called from the VTL1 handler of the intercept
+/// delivered on `vector`, before VTL0 resumes.
+pub fn capture_vtl0_context(vector: u8) -> TmkResult<InterceptedContext> {
+    let vtl0 = Some(vtl_transform(Vtl::Vtl0));
+    let (rip, rsp) = with_irq_hvcall(|hvcall| {
+        let rip = hvcall.get_register(HvX64RegisterName::Rip.into(), vtl0)?;
+        let rsp = hvcall.get_register(HvX64RegisterName::Rsp.into(), vtl0)?;
+        Ok::<_, hvdef::HvError>((rip.as_u64(), rsp.as_u64()))
+    })??;
+
+    let slot = &CAPTURES[HvTestCtx::get_vp_idx() as usize];
+    slot.vector.store(vector, Ordering::Relaxed);
+    slot.rip.store(rip, Ordering::Relaxed);
+    slot.rsp.store(rsp, Ordering::Relaxed);
+    slot.valid.store(true, Ordering::Release);
+    Ok(InterceptedContext { vector, rip, rsp })
+}
+
+/// Returns the last capture of `vp_index` not taken yet, `None` for a VP
+/// past [`MAX_VPS`] too.
+pub fn take_captured(vp_index: u32) -> Option<InterceptedContext> {
+    let slot = &CAPTURES[vp_slot(vp_index).ok()?];
+    slot.valid
+        .swap(false, Ordering::Acquire)
+        .then(|| InterceptedContext {
+            vector: slot.vector.load(Ordering::Relaxed),
+            rip: slot.rip.load(Ordering::Relaxed),
+            rsp: slot.rsp.load(Ordering::Relaxed),
+        })
+}
+
+/// Where VTL0 resumes after an intercept.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Resume {
+    /// Past the intercepted instruction, whose length the intercept message
+    /// reports.
+    Next,
+    /// The given number of bytes past the start of the intercepted
+    /// instruction, for intercepts that do not report its length, such as
+    /// memory intercepts, once the instruction was decoded.
+    Skip(u8),
+    /// At the given address, e.g. of a recovery stub. The stub runs on the
+    /// stack and with the registers of the intercepted code.
+    At(u64),
+}
+
+impl Resume {
+    /// Returns the RIP VTL0 resumes at after the intercept `header`
+    /// describes.
+    ///
+    /// Fails with [`TmkError::NoData`] for [`Resume::Next`] if the
+    /// intercept does not report the instruction length.
+    pub fn target(self, header: &HvX64InterceptMessageHeader) -> TmkResult<u64> {
+        match self {
+            Resume::Next => match header.instruction_len() {
+                0 => Err(TmkError::NoData),
+                len => Ok(header.rip.wrapping_add(len.into())),
+            },
+            Resume::Skip(len) => Ok(header.rip.wrapping_add(len.into())),
+            Resume::At(address) => Ok(address),
+        }
+    }
+}
+
+/// Sets the VTL0 RIP of the current VP as `resume` says, relative to the
+/// intercepted instruction `header` describes, and returns it. Must be
+/// called from the VTL1 handler of the intercept, before VTL0 resumes.
+pub fn resume_vtl0(header: &HvX64InterceptMessageHeader, resume: Resume) -> TmkResult<u64> {
+    let rip = resume.target(header)?;
+    let vtl0 = Some(vtl_transform(Vtl::Vtl0));
+    with_irq_hvcall(|hvcall| {
+        hvcall.set_register(HvX64RegisterName::Rip.into(), rip.into(), vtl0)
+    })??;
+    Ok(rip)
+}
+
+#[derive(Serialize)]
+struct InterceptRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    vp_index: u32,
+    context: &'a InterceptedContext,
+}
+
+/// Writes the `secure_intercept` record of an intercept on `vp_index`.
+pub fn write_intercept_record(vp_index: u32, context: &InterceptedContext) {
+    log::info!(
+        "intercept {:#x} on vp {} interrupted VTL0 at rip {:#x} rsp {:#x}",
+        context.vector,
+        vp_index,
+        context.rip,
+        context.rsp
+    );
+    crate::tmk_logger::write_record(&InterceptRecord {
+        record_type: "secure_intercept",
+        vp_index,
+        context,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use zerocopy::FromZeros;
+
+    use super::*;
+
+    #[test]
+    fn test_resume_target() {
+        let mut header = HvX64InterceptMessageHeader::new_zeroed();
+        header.rip = 0x1000;
+        assert_eq!(Resume::Next.target(&header), Err(TmkError::NoData));
+        header.instruction_length_and_cr8 = 0x52;
+        assert_eq!(Resume::Next.target(&header), Ok(0x1002));
+        assert_eq!(Resume::Skip(7).target(&header), Ok(0x1007));
+        assert_eq!(Resume::At(0x2000).target(&header), Ok(0x2000));
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Hypercalls from interrupt context.
+//!
+//! An interrupt can arrive while the interrupted code is halfway through
+//! filling the input page of its [`HvCall`]. Handlers that issue hypercalls
+//! must therefore not touch that instance, nor build a new context whose
+//! pages alias per-VP state. Each VP instead gets a dedicated [`HvCall`]
+//! reserved for interrupt context, reached through [`with_irq_hvcall`].
+//!
+//! Unsafe nesting is detected and reported rather than silently corrupting
+//! an in-flight hypercall: using a regular [`HvCall`] from interrupt context
+//! or re-entering the interrupt instance is counted in
+//! [`nesting_violations`].
+
+use alloc::boxed::Box;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use super::arch::hypercall::HvCall;
+use super::ctx::HvTestCtx;
+use crate::tmkdefs::MAX_VPS;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+struct VpIrqState {
+    /// Interrupt nesting depth of the VP.
+    depth: AtomicU32,
+    /// The interrupt context instance, null until [`prepare`] ran.
+    hvcall: AtomicPtr<HvCall>,
+    /// Set while the interrupt context instance is in use.
+    busy: AtomicBool,
+}
+
+static IRQ_STATE: [VpIrqState; MAX_VPS] = [const {
+    VpIrqState {
+        depth: AtomicU32::new(0),
+        hvcall: AtomicPtr::new(core::ptr::null_mut()),
+        busy: AtomicBool::new(false),
+    }
+}; MAX_VPS];
+
+/// Number of VPs currently in interrupt context, so that the common case
+/// needs no VP index lookup.
+static IRQ_ACTIVE: AtomicU32 = AtomicU32::new(0);
+static NESTING_VIOLATIONS: AtomicU64 = AtomicU64::new(0);
+
+fn current_state() -> &'static VpIrqState {
+    &IRQ_STATE[HvTestCtx::get_vp_idx() as usize]
+}
+
+fn report_violation(what: &str) {
+    NESTING_VIOLATIONS.fetch_add(1, Ordering::Relaxed);
+    // Reported from interrupt context.
+    crate::log_fmt_nostdalloc!(log::Level::Error, "unsafe hypercall nesting: {}", what);
+}
+
+/// Allocate the interrupt context [`HvCall`] for `vp_index`.
+///
+/// Must run outside interrupt context, the allocator is not re-entrant.
+/// Calling it again for the same VP is a no-op. Only the VP itself may call
+/// this.
+pub(crate) fn prepare(vp_index: u32) {
+    let state = &IRQ_STATE[vp_index as usize];
+    if !state.hvcall.load(Ordering::Acquire).is_null() {
+        return;
+    }
+    let mut hvcall = Box::new(HvCall::new());
+    hvcall.irq_instance = true;
+    // The instance is never freed: dropping an HvCall tears down the
+    // hypercall interface once the last instance goes away.
+    state.hvcall.store(Box::leak(hvcall), Ordering::Release);
+}
+
+/// Marks the current VP as running an interrupt handler until dropped.
+pub(crate) struct IrqContextGuard(&'static VpIrqState);
+
+/// Enter interrupt context on the current VP.
+pub(crate) fn enter_irq() -> IrqContextGuard {
+    let state = current_state();
+    state.depth.fetch_add(1, Ordering::AcqRel);
+    IRQ_ACTIVE.fetch_add(1, Ordering::AcqRel);
+    IrqContextGuard(state)
+}
+
+impl Drop for IrqContextGuard {
+    fn drop(&mut self) {
+        IRQ_ACTIVE.fetch_sub(1, Ordering::AcqRel);
+        self.0.depth.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Returns true if the current VP is running an interrupt handler.
+pub fn in_irq_context() -> bool {
+    IRQ_ACTIVE.load(Ordering::Acquire) != 0 && current_state().depth.load(Ordering::Acquire) != 0
+}
+
+/// Called for every hypercall. Reports hypercalls issued from interrupt
+/// context through an instance that may be in use by the interrupted code.
+pub(crate) fn check_dispatch(hvcall: &HvCall) {
+    if !hvcall.irq_instance && in_irq_context() {
+        report_violation("regular HvCall used from interrupt context");
+    }
+}
+
+/// Run `f` with the interrupt context [`HvCall`] of the current VP.
+///
+/// Fails with [`TmkError::Inactive`] if the VP's context was never
+/// initialized, and with [`TmkError::OperationDenied`] if the instance is
+/// already in use, i.e. a nested interrupt handler or a nested call. The
+/// latter is also reported as a nesting violation.
+pub fn with_irq_hvcall<R>(f: impl FnOnce(&mut HvCall) -> R) -> TmkResult<R> {
+    let state = current_state();
+    let ptr = state.hvcall.load(Ordering::Acquire);
+    if ptr.is_null() {
+        return Err(TmkError::Inactive);
+    }
+    if state.busy.swap(true, Ordering::AcqRel) {
+        report_violation("interrupt context HvCall re-entered");
+        return Err(TmkError::OperationDenied);
+    }
+    // SAFETY: the instance belongs to this VP and `busy` guarantees there
+    // is no other live reference to it.
+    let r = f(unsafe { &mut *ptr });
+    state.busy.store(false, Ordering::Release);
+    Ok(r)
+}
+
+/// Returns the number of unsafe nestings detected since boot.
+pub fn nesting_violations() -> u64 {
+    NESTING_VIOLATIONS.load(Ordering::Relaxed)
+}
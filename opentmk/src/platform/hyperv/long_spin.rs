@@ -0,0 +1,92 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Long spin wait hints.
+//!
+//! Hyper-V recommends, in the enlightenment information leaf, how many
+//! times a guest should spin on a lock before telling it so with
+//! HvCallNotifyLongSpinWait: the VP holding the lock may have been
+//! descheduled, and the hint lets the hypervisor run it. The spin locks of
+//! [`crate::sync`] call [`spun`] from their slow path, as an enlightened
+//! guest's would.
+
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use super::arch::hypercall;
+use super::arch::hypercall::HvCall;
+
+/// Threshold meaning the hypervisor does not want the hint.
+const NEVER_NOTIFY: u32 = u32::MAX;
+/// Value of [`THRESHOLD`] before the leaf was read.
+const UNKNOWN: u32 = 0;
+
+/// The recommended spin count, read once.
+static THRESHOLD: AtomicU32 = AtomicU32::new(UNKNOWN);
+static ENABLED: AtomicBool = AtomicBool::new(true);
+static NOTIFICATIONS: AtomicU64 = AtomicU64::new(0);
+static FAILURES: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the number of spins after which the hypervisor wants to be
+/// notified, `None` if it does not want the hint.
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub fn threshold() -> Option<u32> {
+    let mut threshold = THRESHOLD.load(Ordering::Relaxed);
+    if threshold == UNKNOWN {
+        // SAFETY: CPUID is always available on x86_64.
+        let leaf = unsafe {
+            core::arch::x86_64::__cpuid(hvdef::HV_CPUID_FUNCTION_MS_HV_ENLIGHTENMENT_INFORMATION)
+        };
+        threshold =
+            hvdef::HvEnlightenmentInformation::from_cpuid([leaf.eax, leaf.ebx, leaf.ecx, leaf.edx])
+                .long_spin_wait_count();
+        // A zero count would notify on every spin; take it as no hint.
+        if threshold == 0 {
+            threshold = NEVER_NOTIFY;
+        }
+        THRESHOLD.store(threshold, Ordering::Relaxed);
+    }
+    (threshold != NEVER_NOTIFY).then_some(threshold)
+}
+
+/// Returns the number of spins after which the hypervisor wants to be
+/// notified. The recommendation is not read on aarch64.
+#[cfg(target_arch = "aarch64")] // xtask-fmt allow-target-arch sys-crate
+pub fn threshold() -> Option<u32> {
+    None
+}
+
+/// Enables or disables the hints, e.g. to measure their effect.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Called by spin loops with the number of spins since they started or
+/// last notified. Notifies the hypervisor once `spins` reaches the
+/// threshold, and returns whether it did, in which case the caller starts
+/// counting again.
+pub fn spun(spins: u32) -> bool {
+    if !ENABLED.load(Ordering::Relaxed) || !hypercall::is_initialized() {
+        return false;
+    }
+    if threshold().is_none_or(|threshold| spins < threshold) {
+        return false;
+    }
+    match HvCall::notify_long_spin_wait(spins) {
+        Ok(()) => NOTIFICATIONS.fetch_add(1, Ordering::Relaxed),
+        Err(_) => FAILURES.fetch_add(1, Ordering::Relaxed),
+    };
+    true
+}
+
+/// Returns the number of hints given since boot.
+pub fn notifications() -> u64 {
+    NOTIFICATIONS.load(Ordering::Relaxed)
+}
+
+/// Returns the number of hints the hypervisor failed since boot.
+pub fn failures() -> u64 {
+    FAILURES.load(Ordering::Relaxed)
+}
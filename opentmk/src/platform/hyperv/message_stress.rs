@@ -0,0 +1,277 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! SynIC message-pending stress driver.
+//!
+//! A SINT has a single message slot: while it is occupied, further messages
+//! for the SINT are queued by the hypervisor, which sets `message_pending`
+//! in the header of the message in the slot and only delivers the next one
+//! after the slot is released and end-of-message is signaled. [`run`]
+//! floods a SINT by arming every synthetic timer to expire at nearly the
+//! same time, round after round, and consumes the messages with a delay
+//! before each EOM so that the queue builds up. Every expiration must be
+//! delivered exactly once; [`MessageStats`] counts what was seen.
+//!
+//! Timers are the message source because `HvCallPostMessage` needs a port
+//! the host created for the partition, which a test cannot rely on.
+
+use alloc::vec::Vec;
+
+use hvdef::HvMessageType;
+use hvdef::TimerMessagePayload;
+use serde::Serialize;
+use zerocopy::FromBytes;
+
+use super::synic;
+use super::synic::Synic;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// Reference time, in 100ns units, between arming the timers of a round and
+/// the first expiration.
+const ARM_LEAD: u64 = 10_000;
+/// Reference time allowed for a round past its last expiration before the
+/// missing messages are counted as lost.
+const ROUND_TIMEOUT: u64 = 10_000_000;
+
+/// How a SINT is flooded.
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct StressConfig {
+    /// The SINT the messages are delivered to.
+    pub sint: u8,
+    /// Number of rounds.
+    pub rounds: u32,
+    /// Number of synthetic timers armed each round, up to
+    /// [`hvdef::NUM_TIMERS`].
+    pub timers: u8,
+    /// Reference time between the expirations of a round.
+    pub spacing: u64,
+    /// Reference time waited before each end-of-message.
+    pub eom_delay: u64,
+}
+
+/// What a stress run observed.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct MessageStats {
+    /// Expirations armed.
+    pub expected: u64,
+    /// Expirations delivered once.
+    pub delivered: u64,
+    /// Messages found with `message_pending` set.
+    pub pending: u64,
+    /// End-of-message signals sent.
+    pub eoms: u64,
+    /// Messages received right after an end-of-message signal, which each
+    /// should have released.
+    pub released: u64,
+    /// Rounds whose first message did not have `message_pending` set
+    /// although more expirations were queued behind it.
+    pub pending_missing: u64,
+    /// Expirations delivered more than once.
+    pub duplicates: u64,
+    /// Expirations never delivered.
+    pub lost: u64,
+    /// Messages matching no armed expiration.
+    pub unexpected: u64,
+}
+
+impl MessageStats {
+    /// Returns whether every expiration was delivered exactly once and the
+    /// queued messages were announced by `message_pending`.
+    pub fn consistent(&self) -> bool {
+        self.delivered == self.expected
+            && self.duplicates == 0
+            && self.lost == 0
+            && self.unexpected == 0
+            && self.pending_missing == 0
+    }
+}
+
+/// How a received expiration relates to the armed ones.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    /// First delivery of an armed expiration.
+    New,
+    /// An armed expiration delivered before.
+    Duplicate,
+    /// Not an armed expiration.
+    Unexpected,
+}
+
+/// The expirations armed in a round and whether they were delivered.
+#[derive(Default)]
+pub struct Round {
+    armed: Vec<(u32, u64, bool)>,
+}
+
+impl Round {
+    /// Records that `timer` was armed to expire at `expiration`.
+    pub fn arm(&mut self, timer: u32, expiration: u64) {
+        self.armed.push((timer, expiration, false));
+    }
+
+    /// Records the delivery of the expiration of `timer` at `expiration`.
+    pub fn receive(&mut self, timer: u32, expiration: u64) -> Delivery {
+        match self
+            .armed
+            .iter_mut()
+            .find(|(t, e, _)| *t == timer && *e == expiration)
+        {
+            None => Delivery::Unexpected,
+            Some((_, _, true)) => Delivery::Duplicate,
+            Some((_, _, seen)) => {
+                *seen = true;
+                Delivery::New
+            }
+        }
+    }
+
+    /// Returns the number of armed expirations not delivered yet.
+    pub fn outstanding(&self) -> u64 {
+        self.armed.iter().filter(|(_, _, seen)| !seen).count() as u64
+    }
+}
+
+fn wait_until(time: u64) {
+    while synic::reference_time() < time {
+        core::hint::spin_loop();
+    }
+}
+
+/// Floods `config.sint` of `synic` with timer messages as described in the
+/// module documentation.
+///
+/// The SINT must be configured for polling. The timers are disarmed when the
+/// run ends.
+pub fn run(synic: &Synic, config: &StressConfig) -> TmkResult<MessageStats> {
+    if config.timers == 0 || config.timers as usize > hvdef::NUM_TIMERS {
+        return Err(TmkError::InvalidParameter);
+    }
+    // Drop what a previous user of the SINT left behind.
+    while synic.poll_message(config.sint).is_some() {}
+
+    let mut stats = MessageStats::default();
+    let result = (0..config.rounds).try_for_each(|_| round(synic, config, &mut stats));
+    for timer in 0..config.timers {
+        synic.disarm_timer(timer)?;
+    }
+    result.map(|()| stats)
+}
+
+fn round(synic: &Synic, config: &StressConfig, stats: &mut MessageStats) -> TmkResult<()> {
+    let mut round = Round::default();
+    let first = synic::reference_time() + ARM_LEAD;
+    for timer in 0..config.timers {
+        let expiration = first + timer as u64 * config.spacing;
+        synic.arm_timer(timer, config.sint, expiration)?;
+        round.arm(timer as u32, expiration);
+    }
+    stats.expected += config.timers as u64;
+
+    // Leave the first message in its slot until every timer expired, so
+    // that the others are queued behind it.
+    let last = first + (config.timers as u64 - 1) * config.spacing;
+    wait_until(last + config.eom_delay);
+
+    let deadline = last + ROUND_TIMEOUT;
+    let mut first_message = true;
+    let mut after_eom = false;
+    while round.outstanding() != 0 && synic::reference_time() < deadline {
+        let Some(message) = synic.take_message(config.sint) else {
+            core::hint::spin_loop();
+            continue;
+        };
+        if after_eom {
+            stats.released += 1;
+            after_eom = false;
+        }
+        let pending = message.header.flags.message_pending();
+        if first_message && config.timers > 1 && !pending {
+            stats.pending_missing += 1;
+        }
+        first_message = false;
+
+        if message.header.typ == HvMessageType::HvMessageTypeTimerExpired {
+            let (payload, _) = TimerMessagePayload::read_from_prefix(&message.payload_buffer)
+                .map_err(|_| TmkError::InvalidParameter)?;
+            match round.receive(payload.timer_index, payload.expiration_time) {
+                Delivery::New => stats.delivered += 1,
+                Delivery::Duplicate => stats.duplicates += 1,
+                Delivery::Unexpected => stats.unexpected += 1,
+            }
+        } else {
+            stats.unexpected += 1;
+        }
+
+        if pending {
+            stats.pending += 1;
+            wait_until(synic::reference_time() + config.eom_delay);
+            synic::end_of_message();
+            stats.eoms += 1;
+            after_eom = true;
+        }
+    }
+    stats.lost += round.outstanding();
+
+    // Catch duplicates delivered right after the round completed.
+    wait_until(synic::reference_time() + config.eom_delay);
+    while let Some(message) = synic.poll_message(config.sint) {
+        if after_eom {
+            stats.released += 1;
+            after_eom = false;
+        }
+        if message.header.typ != HvMessageType::HvMessageTypeTimerExpired {
+            stats.unexpected += 1;
+            continue;
+        }
+        let (payload, _) = TimerMessagePayload::read_from_prefix(&message.payload_buffer)
+            .map_err(|_| TmkError::InvalidParameter)?;
+        match round.receive(payload.timer_index, payload.expiration_time) {
+            Delivery::New => {
+                // Late rather than lost.
+                stats.delivered += 1;
+                stats.lost -= 1;
+            }
+            Delivery::Duplicate => stats.duplicates += 1,
+            Delivery::Unexpected => stats.unexpected += 1,
+        }
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct MessageStressRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    config: &'a StressConfig,
+    stats: &'a MessageStats,
+}
+
+/// Writes the `synic_message_stress` record of a run.
+pub fn write_record(config: &StressConfig, stats: &MessageStats) {
+    log::info!("synic message stress {:?}: {:?}", config, stats);
+    crate::tmk_logger::write_record(&MessageStressRecord {
+        record_type: "synic_message_stress",
+        config,
+        stats,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round() {
+        let mut round = Round::default();
+        round.arm(0, 100);
+        round.arm(1, 110);
+        assert_eq!(round.outstanding(), 2);
+        assert_eq!(round.receive(1, 110), Delivery::New);
+        assert_eq!(round.receive(1, 110), Delivery::Duplicate);
+        assert_eq!(round.receive(0, 110), Delivery::Unexpected);
+        assert_eq!(round.outstanding(), 1);
+        assert_eq!(round.receive(0, 100), Delivery::New);
+        assert_eq!(round.outstanding(), 0);
+    }
+}
@@ -0,0 +1,262 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Device MMIO emulated by VTL1 for VTL0.
+//!
+//! A test declares an [`MmioRegion`], a register map with read and write
+//! callbacks, installs it with [`install`], VTL1 protects the backing pages
+//! and routes the secure intercept vector to [`handle_intercept`]. Each
+//! VTL0 access to the region then takes the path a paravisor relies on:
+//! the GPA intercept message is decoded, the access is served by the
+//! register map, the VTL0 destination register and RIP are updated, and
+//! VTL0 resumes after the instruction once VTL1 returns to it.
+//!
+//! [`handle_intercept`] runs in interrupt context. It only touches the
+//! region, atomics and the interrupt context
+//! [`HvCall`](super::arch::hypercall::HvCall), so VTL0 must not hold the
+//! region through [`install`] or [`uninstall`] while accessing it.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use hvdef::HvMessageType;
+use hvdef::HvX64MemoryInterceptMessage;
+use hvdef::HvX64RegisterName;
+use hvdef::Vtl;
+use spin::Mutex;
+
+use super::ctx::vtl_transform;
+use super::intercept;
+use super::intercept::Resume;
+use super::irq_hvcall::with_irq_hvcall;
+use super::synic;
+use crate::arch::decode;
+use crate::arch::decode::Gpr;
+use crate::arch::decode::MovAccess;
+use crate::arch::decode::MovKind;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// Called on a read of a register with its current value, returns the
+/// value VTL0 reads.
+pub type ReadFn = fn(value: &mut u64) -> u64;
+/// Called on a write of a register with its current value and the value
+/// VTL0 writes.
+pub type WriteFn = fn(value: &mut u64, written: u64);
+
+fn plain_read(value: &mut u64) -> u64 {
+    *value
+}
+
+fn plain_write(value: &mut u64, written: u64) {
+    *value = written;
+}
+
+/// A device register.
+pub struct MmioRegister {
+    offset: u64,
+    value: u64,
+    read: ReadFn,
+    write: WriteFn,
+}
+
+impl MmioRegister {
+    /// A register at `offset` that reads back what was last written,
+    /// initially `value`.
+    pub fn new(offset: u64, value: u64) -> Self {
+        Self {
+            offset,
+            value,
+            read: plain_read,
+            write: plain_write,
+        }
+    }
+
+    /// Serve reads with `read`.
+    pub fn on_read(mut self, read: ReadFn) -> Self {
+        self.read = read;
+        self
+    }
+
+    /// Serve writes with `write`.
+    pub fn on_write(mut self, write: WriteFn) -> Self {
+        self.write = write;
+        self
+    }
+
+    /// The current value of the register.
+    pub fn value(&self) -> u64 {
+        self.value
+    }
+}
+
+/// A region of guest physical addresses whose accesses VTL1 emulates.
+pub struct MmioRegion {
+    range: Range<u64>,
+    registers: Vec<MmioRegister>,
+}
+
+impl MmioRegion {
+    /// A region covering `range`, with no registers. Accesses to offsets
+    /// without a register read as zero and ignore writes.
+    pub fn new(range: Range<u64>) -> Self {
+        Self {
+            range,
+            registers: Vec::new(),
+        }
+    }
+
+    /// Adds `register` to the map.
+    pub fn register(mut self, register: MmioRegister) -> Self {
+        self.registers.push(register);
+        self
+    }
+
+    /// The register at `offset`, if any.
+    pub fn get(&self, offset: u64) -> Option<&MmioRegister> {
+        self.registers.iter().find(|r| r.offset == offset)
+    }
+
+    fn read(&mut self, offset: u64) -> u64 {
+        self.registers
+            .iter_mut()
+            .find(|r| r.offset == offset)
+            .map_or(0, |r| (r.read)(&mut r.value))
+    }
+
+    fn write(&mut self, offset: u64, value: u64) {
+        if let Some(r) = self.registers.iter_mut().find(|r| r.offset == offset) {
+            (r.write)(&mut r.value, value);
+        }
+    }
+}
+
+static REGION: Mutex<Option<MmioRegion>> = Mutex::new(None);
+static EMULATED: AtomicU64 = AtomicU64::new(0);
+static UNHANDLED: AtomicU64 = AtomicU64::new(0);
+
+/// Installs `region`, replacing any previous one, and resets the counters.
+pub fn install(region: MmioRegion) {
+    *REGION.lock() = Some(region);
+    EMULATED.store(0, Ordering::Relaxed);
+    UNHANDLED.store(0, Ordering::Relaxed);
+}
+
+/// Removes the installed region and returns it.
+pub fn uninstall() -> Option<MmioRegion> {
+    REGION.lock().take()
+}
+
+/// Returns the number of accesses emulated and the number of intercepts
+/// that could not be, since the region was installed.
+pub fn stats() -> (u64, u64) {
+    (
+        EMULATED.load(Ordering::Relaxed),
+        UNHANDLED.load(Ordering::Relaxed),
+    )
+}
+
+fn gpr_name(gpr: Gpr) -> hvdef::HvRegisterName {
+    HvX64RegisterName(HvX64RegisterName::Rax.0 + gpr.index as u32).into()
+}
+
+/// Mask of the low `size` bytes.
+pub(crate) fn size_mask(size: u8) -> u64 {
+    match size {
+        8 => u64::MAX,
+        size => (1 << (size * 8)) - 1,
+    }
+}
+
+/// Completes the VTL0 `mov` intercepted as `message`: `serve` is called
+/// with the GPA, the decoded access and, for stores, the value stored, and
+/// returns the value a load reads. The VTL0 destination register and RIP
+/// are then updated so that VTL0 resumes after the instruction.
+pub(crate) fn emulate_mov(
+    message: &HvX64MemoryInterceptMessage,
+    serve: impl FnOnce(u64, &MovAccess, Option<u64>) -> TmkResult<u64>,
+) -> TmkResult<MovAccess> {
+    let count = (message.instruction_byte_count as usize).min(message.instruction_bytes.len());
+    let access = decode::decode(&message.instruction_bytes[..count])
+        .map_err(|_| TmkError::InvalidParameter)?;
+    let gpa = message.guest_physical_address;
+    let vtl0 = Some(vtl_transform(Vtl::Vtl0));
+    let mask = size_mask(access.size);
+
+    with_irq_hvcall(|hvcall| -> TmkResult<()> {
+        match access.kind {
+            MovKind::StoreImmediate(imm) => {
+                serve(gpa, &access, Some(imm & mask))?;
+            }
+            MovKind::StoreRegister(src) => {
+                let value = hvcall.get_register(gpr_name(src), vtl0)?.as_u64();
+                let value = if src.high_byte { value >> 8 } else { value };
+                serve(gpa, &access, Some(value & mask))?;
+            }
+            MovKind::Load {
+                dst,
+                dst_size,
+                zero_extend,
+            } => {
+                let value = serve(gpa, &access, None)? & mask;
+                let old = hvcall.get_register(gpr_name(dst), vtl0)?.as_u64();
+                let new = match (dst.high_byte, dst_size) {
+                    (true, _) => (old & !0xff00) | (value << 8),
+                    // 32-bit destinations clear the upper half.
+                    (false, 4 | 8) => value,
+                    (false, _) if zero_extend => (old & !size_mask(dst_size)) | value,
+                    (false, _) => (old & !mask) | value,
+                };
+                hvcall.set_register(gpr_name(dst), new.into(), vtl0)?;
+            }
+        }
+        Ok(())
+    })??;
+    intercept::resume_vtl0(&message.header, Resume::Skip(access.len))?;
+    Ok(access)
+}
+
+/// Emulates the access described by `message`.
+fn emulate(message: &HvX64MemoryInterceptMessage) -> TmkResult<()> {
+    let mut region = REGION.lock();
+    let region = region.as_mut().ok_or(TmkError::Inactive)?;
+    emulate_mov(message, |gpa, _, stored| {
+        if !region.range.contains(&gpa) {
+            return Err(TmkError::InvalidParameter);
+        }
+        let offset = gpa - region.range.start;
+        match stored {
+            Some(value) => {
+                region.write(offset, value);
+                Ok(0)
+            }
+            None => Ok(region.read(offset)),
+        }
+    })?;
+    Ok(())
+}
+
+/// Secure intercept handler serving VTL0 accesses to the installed region.
+/// Install it on the secure intercept vector of the VTL1 side.
+pub fn handle_intercept() {
+    let Some(message) = synic::poll_current_message(hvdef::HV_SYNIC_INTERCEPTION_SINT_INDEX) else {
+        return;
+    };
+    if message.header.typ != HvMessageType::HvMessageTypeGpaIntercept
+        && message.header.typ != HvMessageType::HvMessageTypeUnmappedGpa
+    {
+        UNHANDLED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    match emulate(message.as_message::<HvX64MemoryInterceptMessage>()) {
+        Ok(()) => {
+            EMULATED.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(e) => {
+            UNHANDLED.fetch_add(1, Ordering::Relaxed);
+            crate::log_fmt_nostdalloc!(log::Level::Error, "MMIO emulation failed: {:?}", e);
+        }
+    }
+}
@@ -8,3 +8,35 @@
 
 pub mod arch;
 pub mod ctx;
+pub mod descriptor_table;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod exec_probe;
+pub mod extended;
+pub mod hv_processor;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hypercall_page;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod intercept;
+pub mod irq_hvcall;
+pub mod long_spin;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod message_stress;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod mmio_stub;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod pending_event;
+pub mod privileges;
+pub mod queue_stats;
+pub mod retry;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod save_restore;
+pub(crate) mod stack_usage;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod synic;
+pub mod trace;
+pub mod vcpu;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod vp_assist;
+pub mod vtl_access;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod watch;
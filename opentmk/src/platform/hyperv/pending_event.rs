@@ -0,0 +1,99 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Pending interruptions of another VP or VTL.
+//!
+//! The `PendingInterruption` register holds the event the hypervisor
+//! delivers to a VP the next time it runs, as a VMM would inject it: an
+//! external interrupt, an NMI or an exception. A higher VTL writes it
+//! through `HvCallSetVpRegisters` to inject or withdraw an event in the
+//! state of a suspended lower VTL, which is what interrupt virtualization
+//! in a paravisor relies on beyond plain IPIs.
+
+use hvdef::HvX64PendingInterruptionRegister;
+use hvdef::HvX64PendingInterruptionType;
+use hvdef::HvX64RegisterName;
+use hvdef::Vtl;
+
+use crate::context::VtlPlatformTrait;
+use crate::tmkdefs::TmkResult;
+
+/// An external interrupt on `vector`.
+pub fn interrupt(vector: u8) -> HvX64PendingInterruptionRegister {
+    HvX64PendingInterruptionRegister::new()
+        .with_interruption_pending(true)
+        .with_interruption_type(HvX64PendingInterruptionType::HV_X64_PENDING_INTERRUPT.0)
+        .with_interruption_vector(vector.into())
+}
+
+/// A non-maskable interrupt.
+pub fn nmi() -> HvX64PendingInterruptionRegister {
+    HvX64PendingInterruptionRegister::new()
+        .with_interruption_pending(true)
+        .with_interruption_type(HvX64PendingInterruptionType::HV_X64_PENDING_NMI.0)
+        .with_interruption_vector(2)
+}
+
+/// A hardware exception on `vector`, with `error_code` pushed if given.
+pub fn exception(vector: u8, error_code: Option<u32>) -> HvX64PendingInterruptionRegister {
+    HvX64PendingInterruptionRegister::new()
+        .with_interruption_pending(true)
+        .with_interruption_type(HvX64PendingInterruptionType::HV_X64_PENDING_EXCEPTION.0)
+        .with_interruption_vector(vector.into())
+        .with_deliver_error_code(error_code.is_some())
+        .with_error_code(error_code.unwrap_or(0))
+}
+
+/// Reads the pending interruption of `vtl` on `vp_index`.
+pub fn read<T: VtlPlatformTrait>(
+    ctx: &mut T,
+    vp_index: u32,
+    vtl: Vtl,
+) -> TmkResult<HvX64PendingInterruptionRegister> {
+    ctx.get_vp_register_on_vp(vp_index, HvX64RegisterName::PendingInterruption.0, vtl)
+        .map(HvX64PendingInterruptionRegister::from)
+}
+
+/// Makes `event` the pending interruption of `vtl` on `vp_index`, to be
+/// delivered when that VTL next runs. Only a higher VTL may do so, while
+/// `vtl` is not running on the VP.
+pub fn inject<T: VtlPlatformTrait>(
+    ctx: &mut T,
+    vp_index: u32,
+    vtl: Vtl,
+    event: HvX64PendingInterruptionRegister,
+) -> TmkResult<()> {
+    ctx.set_vp_register_on_vp(
+        vp_index,
+        HvX64RegisterName::PendingInterruption.0,
+        event.into(),
+        vtl,
+    )
+}
+
+/// Withdraws the pending interruption of `vtl` on `vp_index`, if any.
+pub fn clear<T: VtlPlatformTrait>(ctx: &mut T, vp_index: u32, vtl: Vtl) -> TmkResult<()> {
+    inject(ctx, vp_index, vtl, HvX64PendingInterruptionRegister::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events() {
+        let event = interrupt(0x40);
+        assert!(event.interruption_pending());
+        assert_eq!(
+            (event.interruption_type(), event.interruption_vector()),
+            (0, 0x40)
+        );
+
+        let event = exception(13, Some(0x10));
+        assert_eq!(event.interruption_type(), 3);
+        assert!(event.deliver_error_code());
+        assert_eq!(event.error_code(), 0x10);
+        assert!(!exception(0, None).deliver_error_code());
+        assert_eq!(nmi().interruption_type(), 2);
+    }
+}
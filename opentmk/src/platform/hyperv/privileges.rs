@@ -0,0 +1,203 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Partition privileges, cross-checked against hypercall behavior.
+//!
+//! The hypervisor advertises the privileges of the partition in the
+//! features leaf, CPUID `0x40000003` on x86_64, and returns
+//! `HV_STATUS_ACCESS_DENIED` from hypercalls the partition is not allowed
+//! to make. [`check`] tries one harmless hypercall guarded by each
+//! [`Privilege`] and compares the outcome with the advertised flag, and
+//! [`write_record`] reports the result as a `privilege_matrix` record.
+//!
+//! The privileges can be overridden for the whole partition with
+//! [`PrivilegePlatformTrait::set_privileges`] to exercise the paths of tests
+//! that skip without a privilege. The hypervisor keeps enforcing the
+//! privileges it granted.
+
+use alloc::vec::Vec;
+
+use hvdef::HvPartitionPrivilege;
+use serde::Serialize;
+use spin::Mutex;
+
+use crate::context::PrivilegePlatformTrait;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// A privilege guarding hypercalls opentmk can try without side effects.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Privilege {
+    /// `PostMessages`, for `HvCallPostMessage`.
+    PostMessages,
+    /// `SignalEvents`, for `HvCallSignalEvent`.
+    SignalEvents,
+    /// `AccessVpRegisters`, for `HvCallGetVpRegisters`.
+    AccessVpRegisters,
+    /// `AccessVsm`, for the VSM registers.
+    AccessVsm,
+    /// `EnableExtendedHypercalls`, for `HvExtCallQueryCapabilities`.
+    EnableExtendedHypercalls,
+    /// `StartVirtualProcessor`, for `HvCallStartVirtualProcessor`.
+    StartVirtualProcessor,
+}
+
+impl Privilege {
+    /// Every privilege that can be tried.
+    pub const ALL: &[Privilege] = &[
+        Privilege::PostMessages,
+        Privilege::SignalEvents,
+        Privilege::AccessVpRegisters,
+        Privilege::AccessVsm,
+        Privilege::EnableExtendedHypercalls,
+        Privilege::StartVirtualProcessor,
+    ];
+
+    /// The TLFS name of the privilege.
+    pub fn name(self) -> &'static str {
+        match self {
+            Privilege::PostMessages => "PostMessages",
+            Privilege::SignalEvents => "SignalEvents",
+            Privilege::AccessVpRegisters => "AccessVpRegisters",
+            Privilege::AccessVsm => "AccessVsm",
+            Privilege::EnableExtendedHypercalls => "EnableExtendedHypercalls",
+            Privilege::StartVirtualProcessor => "StartVirtualProcessor",
+        }
+    }
+
+    /// Returns whether the privilege is set in `privileges`.
+    pub fn is_set(self, privileges: HvPartitionPrivilege) -> bool {
+        match self {
+            Privilege::PostMessages => privileges.post_messages(),
+            Privilege::SignalEvents => privileges.signal_events(),
+            Privilege::AccessVpRegisters => privileges.access_vp_registers(),
+            Privilege::AccessVsm => privileges.access_vsm(),
+            Privilege::EnableExtendedHypercalls => privileges.enable_extended_hypercalls(),
+            Privilege::StartVirtualProcessor => privileges.start_virtual_processor(),
+        }
+    }
+}
+
+/// How the hypervisor handled a hypercall guarded by a privilege.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Observed {
+    /// The call got past the privilege check, whatever its status.
+    Granted,
+    /// The call failed with `HV_STATUS_ACCESS_DENIED`.
+    Denied,
+    /// The call failed with `HV_STATUS_INVALID_HYPERCALL_CODE`.
+    Unimplemented,
+}
+
+impl Observed {
+    /// Classifies the result of a privileged hypercall.
+    pub fn from_result(result: &TmkResult<()>) -> Self {
+        match result {
+            Err(TmkError::AccessDenied) => Observed::Denied,
+            Err(TmkError::InvalidHypercallCode) => Observed::Unimplemented,
+            _ => Observed::Granted,
+        }
+    }
+}
+
+/// An advertised privilege and how the hypervisor behaved.
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct PrivilegeCheck {
+    /// The privilege name.
+    pub privilege: &'static str,
+    /// Whether the privilege is advertised.
+    pub reported: bool,
+    /// What happened when trying a hypercall it guards.
+    pub observed: Observed,
+}
+
+impl PrivilegeCheck {
+    /// Returns whether the hypercall was let through exactly when the
+    /// privilege is advertised.
+    pub fn consistent(&self) -> bool {
+        self.reported == (self.observed == Observed::Granted)
+    }
+}
+
+static OVERRIDE: Mutex<Option<HvPartitionPrivilege>> = Mutex::new(None);
+
+/// Returns the privileges set with
+/// [`PrivilegePlatformTrait::set_privileges`], if any.
+#[cfg_attr(target_arch = "aarch64", expect(dead_code))] // xtask-fmt allow-target-arch sys-crate
+pub(crate) fn overridden() -> Option<HvPartitionPrivilege> {
+    *OVERRIDE.lock()
+}
+
+/// Overrides the privileges of the partition, `None` restores the
+/// advertised ones.
+#[cfg_attr(target_arch = "aarch64", expect(dead_code))] // xtask-fmt allow-target-arch sys-crate
+pub(crate) fn set_override(privileges: Option<HvPartitionPrivilege>) {
+    *OVERRIDE.lock() = privileges;
+}
+
+/// Tries a hypercall guarded by each privilege and compares the outcome
+/// with the privileges returned by
+/// [`PrivilegePlatformTrait::get_privileges`].
+pub fn check<T: PrivilegePlatformTrait>(ctx: &mut T) -> TmkResult<Vec<PrivilegeCheck>> {
+    let privileges = ctx.get_privileges()?;
+    Ok(Privilege::ALL
+        .iter()
+        .map(|&privilege| {
+            let result = ctx.try_privileged_hypercall(privilege);
+            log::debug!("{} hypercall returned {:?}", privilege.name(), result);
+            PrivilegeCheck {
+                privilege: privilege.name(),
+                reported: privilege.is_set(privileges),
+                observed: Observed::from_result(&result),
+            }
+        })
+        .collect())
+}
+
+#[derive(Serialize)]
+struct PrivilegeMatrixRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    privileges: u64,
+    checks: &'a [PrivilegeCheck],
+}
+
+/// Writes the `privilege_matrix` record of `checks`, made against
+/// `privileges`.
+pub fn write_record(privileges: HvPartitionPrivilege, checks: &[PrivilegeCheck]) {
+    for check in checks {
+        log::info!(
+            "{}: reported {}, observed {:?}",
+            check.privilege,
+            check.reported,
+            check.observed
+        );
+    }
+    crate::tmk_logger::write_record(&PrivilegeMatrixRecord {
+        record_type: "privilege_matrix",
+        privileges: privileges.into(),
+        checks,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_consistent() {
+        let privileges = HvPartitionPrivilege::new().with_post_messages(true);
+        let check = |privilege: Privilege, result: TmkResult<()>| PrivilegeCheck {
+            privilege: privilege.name(),
+            reported: privilege.is_set(privileges),
+            observed: Observed::from_result(&result),
+        };
+
+        assert!(check(Privilege::PostMessages, Err(TmkError::InvalidParameter)).consistent());
+        assert!(!check(Privilege::PostMessages, Err(TmkError::AccessDenied)).consistent());
+        assert!(check(Privilege::SignalEvents, Err(TmkError::AccessDenied)).consistent());
+        assert!(check(Privilege::AccessVsm, Err(TmkError::InvalidHypercallCode)).consistent());
+        assert!(!check(Privilege::AccessVpRegisters, Ok(())).consistent());
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Counters of the per-VP command queues.
+//!
+//! A command that never runs leaves no trace by itself: a test waiting on
+//! its result hangs, and one that does not wait passes without the work
+//! being done. The queues count the commands the command loops take, the
+//! times a loop finds the command at the head of its queue meant for the
+//! other VTL and switches to it, and the commands lost, either refused when
+//! queued or thrown away with the rest of a queue. The counters cover the
+//! whole run: the registry reports what each test added in a
+//! `command_queue` record, the results file the totals, and tests compare
+//! [`stats`] before and after the work they queue.
+//!
+//! The counters only use atomics, so they are updated from the command
+//! loops without taking the queue lock again.
+
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use serde::Serialize;
+
+/// Commands the command loops dequeued to run.
+static DEQUEUED: AtomicU64 = AtomicU64::new(0);
+/// Switches of a command loop to the VTL of the command at its head.
+static BOUNCED: AtomicU64 = AtomicU64::new(0);
+/// Queued commands thrown away without running.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+/// Commands refused when queued.
+static REJECTED: AtomicU64 = AtomicU64::new(0);
+
+/// The counters of the command queues.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct QueueStats {
+    /// Commands the command loops dequeued to run.
+    pub dequeued: u64,
+    /// Times a command loop found the command at the head of its queue
+    /// meant for the other VTL, and switched to it.
+    pub bounced: u64,
+    /// Queued commands thrown away without running, e.g. when a faulted VP
+    /// is restarted.
+    pub dropped: u64,
+    /// Commands refused when queued, e.g. for a VP without a queue.
+    pub rejected: u64,
+}
+
+impl QueueStats {
+    /// Returns what the counters added since `earlier` was taken.
+    pub fn since(&self, earlier: &QueueStats) -> QueueStats {
+        QueueStats {
+            dequeued: self.dequeued.saturating_sub(earlier.dequeued),
+            bounced: self.bounced.saturating_sub(earlier.bounced),
+            dropped: self.dropped.saturating_sub(earlier.dropped),
+            rejected: self.rejected.saturating_sub(earlier.rejected),
+        }
+    }
+
+    /// Returns the number of commands lost, dropped or rejected.
+    pub fn lost(&self) -> u64 {
+        self.dropped + self.rejected
+    }
+}
+
+/// Returns the counters since the start of the run.
+pub fn stats() -> QueueStats {
+    QueueStats {
+        dequeued: DEQUEUED.load(Ordering::Relaxed),
+        bounced: BOUNCED.load(Ordering::Relaxed),
+        dropped: DROPPED.load(Ordering::Relaxed),
+        rejected: REJECTED.load(Ordering::Relaxed),
+    }
+}
+
+/// Counts a command dequeued to run.
+pub(crate) fn count_dequeued() {
+    DEQUEUED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a switch to the VTL of the command at the head of a queue.
+pub(crate) fn count_bounced() {
+    BOUNCED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts `commands` queued commands thrown away.
+pub(crate) fn count_dropped(commands: usize) {
+    DROPPED.fetch_add(commands as u64, Ordering::Relaxed);
+}
+
+/// Counts `commands` commands refused when queued.
+pub(crate) fn count_rejected(commands: usize) {
+    REJECTED.fetch_add(commands as u64, Ordering::Relaxed);
+}
+
+#[derive(Serialize)]
+struct CommandQueueRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    test: &'a str,
+    #[serde(flatten)]
+    stats: QueueStats,
+}
+
+/// Writes the `command_queue` record of `test`, with what the counters
+/// added while it ran, and warns if commands were lost.
+pub fn write_record(test: &str, stats: QueueStats) {
+    if stats.lost() != 0 {
+        log::warn!(
+            "{} lost {} queued commands: {} dropped, {} rejected",
+            test,
+            stats.lost(),
+            stats.dropped,
+            stats.rejected
+        );
+    }
+    crate::tmk_logger::write_record(&CommandQueueRecord {
+        record_type: "command_queue",
+        test,
+        stats,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_since() {
+        let earlier = QueueStats {
+            dequeued: 10,
+            bounced: 2,
+            dropped: 0,
+            rejected: 1,
+        };
+        let now = QueueStats {
+            dequeued: 15,
+            bounced: 3,
+            dropped: 4,
+            rejected: 1,
+        };
+        let added = now.since(&earlier);
+        assert_eq!(
+            added,
+            QueueStats {
+                dequeued: 5,
+                bounced: 1,
+                dropped: 4,
+                rejected: 0,
+            }
+        );
+        assert_eq!(added.lost(), 4);
+        assert_eq!(earlier.since(&now), QueueStats::default());
+    }
+}
@@ -0,0 +1,226 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Retries of hypercalls failing with transient statuses.
+//!
+//! Under load the hypervisor may fail a hypercall with a status such as
+//! `InsufficientMemory` or `Timeout` that says nothing about the request
+//! itself. A test can opt into bounded retries with exponential backoff for
+//! specific hypercall codes with [`set_policy`]; every attempt goes through
+//! [`HvCall`](super::arch::hypercall::HvCall) and shows up in the hypercall
+//! trace. Conformance tests that need the raw behavior turn retries off with
+//! [`set_enabled`]. Policies are cleared before each test.
+//!
+//! The policy table only uses atomics, so it is consulted from interrupt
+//! context as well.
+
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use hvdef::HvError;
+use hvdef::HypercallCode;
+use hvdef::hypercall::HypercallOutput;
+
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// Number of hypercall codes that can have a policy.
+const POLICY_SLOTS: usize = 32;
+
+/// How a hypercall is retried.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt.
+    pub max_retries: u32,
+    /// Cycles waited before the first retry, doubled for each further one.
+    pub initial_backoff: u64,
+    /// Upper bound of the wait before a retry, in cycles.
+    pub max_backoff: u64,
+}
+
+impl RetryPolicy {
+    /// Up to `max_retries` retries, waiting from 10K up to 10M cycles.
+    pub const fn new(max_retries: u32) -> Self {
+        Self {
+            max_retries,
+            initial_backoff: 10_000,
+            max_backoff: 10_000_000,
+        }
+    }
+
+    /// Wait `initial` cycles before the first retry and at most `max`
+    /// before any.
+    pub const fn with_backoff(mut self, initial: u64, max: u64) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// The wait before retry `attempt`, counted from zero.
+    pub fn backoff(&self, attempt: u32) -> u64 {
+        self.initial_backoff
+            .saturating_mul(1u64.checked_shl(attempt).unwrap_or(u64::MAX))
+            .min(self.max_backoff)
+    }
+}
+
+/// A policy. `code` holds the hypercall code plus one so that zero marks a
+/// free slot; a slot is never freed, a zero `max_retries` disables it.
+struct PolicySlot {
+    code: AtomicU32,
+    max_retries: AtomicU32,
+    initial_backoff: AtomicU64,
+    max_backoff: AtomicU64,
+}
+
+static POLICIES: [PolicySlot; POLICY_SLOTS] = [const {
+    PolicySlot {
+        code: AtomicU32::new(0),
+        max_retries: AtomicU32::new(0),
+        initial_backoff: AtomicU64::new(0),
+        max_backoff: AtomicU64::new(0),
+    }
+}; POLICY_SLOTS];
+static ENABLED: AtomicBool = AtomicBool::new(true);
+static RETRIES: AtomicU64 = AtomicU64::new(0);
+
+/// Returns whether `error` may go away by itself when retried.
+pub fn is_transient(error: HvError) -> bool {
+    matches!(
+        error,
+        HvError::InsufficientMemory
+            | HvError::InsufficientBuffers
+            | HvError::InsufficientBuffer
+            | HvError::InsufficientRootMemory
+            | HvError::NoResources
+            | HvError::Timeout
+    )
+}
+
+fn slot(code: u16, claim: bool) -> Option<&'static PolicySlot> {
+    let key = code as u32 + 1;
+    POLICIES.iter().find_map(|slot| {
+        let existing = if claim {
+            match slot
+                .code
+                .compare_exchange(0, key, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => key,
+                Err(existing) => existing,
+            }
+        } else {
+            slot.code.load(Ordering::Acquire)
+        };
+        (existing == key).then_some(slot)
+    })
+}
+
+/// Sets the retry policy of `code`, `None` to stop retrying it.
+pub fn set_policy(code: HypercallCode, policy: Option<RetryPolicy>) -> TmkResult<()> {
+    let Some(policy) = policy else {
+        if let Some(slot) = slot(code.0, false) {
+            slot.max_retries.store(0, Ordering::Release);
+        }
+        return Ok(());
+    };
+    let slot = slot(code.0, true).ok_or(TmkError::AllocationFailed)?;
+    slot.initial_backoff
+        .store(policy.initial_backoff, Ordering::Relaxed);
+    slot.max_backoff
+        .store(policy.max_backoff, Ordering::Relaxed);
+    slot.max_retries
+        .store(policy.max_retries, Ordering::Release);
+    Ok(())
+}
+
+/// Returns the retry policy of `code`, if any.
+pub fn policy(code: HypercallCode) -> Option<RetryPolicy> {
+    let slot = slot(code.0, false)?;
+    let max_retries = slot.max_retries.load(Ordering::Acquire);
+    (max_retries != 0).then(|| RetryPolicy {
+        max_retries,
+        initial_backoff: slot.initial_backoff.load(Ordering::Relaxed),
+        max_backoff: slot.max_backoff.load(Ordering::Relaxed),
+    })
+}
+
+/// Enables or disables retries for every hypercall, without changing the
+/// policies.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Release);
+}
+
+/// Returns the number of retries made since the last [`reset`].
+pub fn retries() -> u64 {
+    RETRIES.load(Ordering::Relaxed)
+}
+
+/// Clears the policies and the retry count and enables retries.
+pub fn reset() {
+    for slot in &POLICIES {
+        slot.max_retries.store(0, Ordering::Release);
+    }
+    RETRIES.store(0, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Returns the cycles to wait before retrying the hypercall `code` that
+/// returned `output` on attempt `attempt`, counted from zero, or `None` if
+/// it must not be retried. Rep hypercalls that made progress are left to
+/// their caller.
+pub(crate) fn retry_delay(code: u16, attempt: u32, output: HypercallOutput) -> Option<u64> {
+    let error = output.result().err()?;
+    if !ENABLED.load(Ordering::Acquire) || !is_transient(error) || output.elements_processed() != 0
+    {
+        return None;
+    }
+    let policy = policy(HypercallCode(code))?;
+    if attempt >= policy.max_retries {
+        return None;
+    }
+    RETRIES.fetch_add(1, Ordering::Relaxed);
+    Some(policy.backoff(attempt))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failed(error: HvError) -> HypercallOutput {
+        HypercallOutput::from(error)
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        reset();
+        let code = HypercallCode::HvCallPostMessage;
+        assert_eq!(
+            retry_delay(code.0, 0, failed(HvError::InsufficientMemory)),
+            None
+        );
+
+        set_policy(code, Some(RetryPolicy::new(3).with_backoff(100, 300))).unwrap();
+        let delays: Vec<_> = (0..4)
+            .map(|attempt| retry_delay(code.0, attempt, failed(HvError::Timeout)))
+            .collect();
+        assert_eq!(delays, [Some(100), Some(200), Some(300), None]);
+        assert_eq!(retries(), 3);
+
+        assert_eq!(
+            retry_delay(code.0, 0, failed(HvError::InvalidParameter)),
+            None
+        );
+        assert_eq!(retry_delay(code.0, 0, HypercallOutput::SUCCESS), None);
+        let partial = failed(HvError::Timeout).with_elements_processed(1);
+        assert_eq!(retry_delay(code.0, 0, partial), None);
+
+        set_enabled(false);
+        assert_eq!(retry_delay(code.0, 0, failed(HvError::Timeout)), None);
+        set_enabled(true);
+        set_policy(code, None).unwrap();
+        assert_eq!(retry_delay(code.0, 0, failed(HvError::Timeout)), None);
+        reset();
+    }
+}
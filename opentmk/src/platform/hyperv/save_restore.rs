@@ -0,0 +1,212 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Guest visible state checked across a VM save and restore.
+//!
+//! [`GuestState::capture`] reads the counters, control registers and
+//! synthetic MSRs of the current VP. Taken before and after the harness
+//! saved and restored the VM through [`crate::host_action`], [`compare`]
+//! reports what did not carry over: counters must not go backwards and the
+//! rest must be unchanged.
+//!
+//! Synthetic timers are left out, as they change state when they expire;
+//! tests check a timer pending across the restore through its message
+//! instead.
+
+use alloc::vec::Vec;
+
+use minimal_rt::arch::msr::read_msr;
+use serde::Serialize;
+
+use super::synic;
+use crate::arch::cycles;
+use crate::arch::regs;
+
+/// Registers of [`regs::snapshot`] that hold configuration rather than
+/// transient state.
+const STABLE_REGISTERS: [&str; 4] = ["cr0", "cr3", "cr4", "efer"];
+
+const IA32_PAT: u32 = 0x277;
+
+/// MSRs that must be unchanged by a restore.
+const STABLE_MSRS: [(&str, u32); 7] = [
+    ("guest_os_id", hvdef::HV_X64_MSR_GUEST_OS_ID),
+    ("hypercall", hvdef::HV_X64_MSR_HYPERCALL),
+    ("vp_index", hvdef::HV_X64_MSR_VP_INDEX),
+    ("scontrol", hvdef::HV_X64_MSR_SCONTROL),
+    ("simp", hvdef::HV_X64_MSR_SIMP),
+    ("siefp", hvdef::HV_X64_MSR_SIEFP),
+    ("pat", IA32_PAT),
+];
+
+const SINT_NAMES: [&str; hvdef::NUM_SINTS] = [
+    "sint0", "sint1", "sint2", "sint3", "sint4", "sint5", "sint6", "sint7", "sint8", "sint9",
+    "sint10", "sint11", "sint12", "sint13", "sint14", "sint15",
+];
+
+/// A named value.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Value {
+    /// The name of the register or MSR.
+    pub name: &'static str,
+    /// The value.
+    pub value: u64,
+}
+
+/// The state of the current VP.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct GuestState {
+    /// The partition reference time, in 100ns units.
+    pub reference_time: u64,
+    /// The time stamp counter.
+    pub tsc: u64,
+    /// The configuration registers.
+    pub registers: Vec<Value>,
+    /// The MSRs, SINTs included.
+    pub msrs: Vec<Value>,
+}
+
+impl GuestState {
+    /// Reads the state of the current VP.
+    pub fn capture() -> Self {
+        let registers = regs::snapshot()
+            .into_iter()
+            .filter(|(name, _)| STABLE_REGISTERS.contains(name))
+            .map(|(name, value)| Value { name, value })
+            .collect();
+        let sints = SINT_NAMES.into_iter().zip(hvdef::HV_X64_MSR_SINT0..);
+        let msrs = STABLE_MSRS
+            .into_iter()
+            .chain(sints)
+            .map(|(name, msr)| Value {
+                name,
+                // SAFETY: the synthetic MSRs are accessible to a Hyper-V guest
+                // and the PAT is architectural.
+                value: unsafe { read_msr(msr) },
+            })
+            .collect();
+        Self {
+            reference_time: synic::reference_time(),
+            tsc: cycles::read(),
+            registers,
+            msrs,
+        }
+    }
+}
+
+/// A piece of state that did not carry over.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Discontinuity {
+    /// The counter went backwards.
+    CounterBackwards {
+        name: &'static str,
+        before: u64,
+        after: u64,
+    },
+    /// The register or MSR changed.
+    Changed {
+        name: &'static str,
+        before: u64,
+        after: u64,
+    },
+}
+
+fn compare_values(before: &[Value], after: &[Value], out: &mut Vec<Discontinuity>) {
+    for (b, a) in before.iter().zip(after) {
+        if b.value != a.value {
+            out.push(Discontinuity::Changed {
+                name: b.name,
+                before: b.value,
+                after: a.value,
+            });
+        }
+    }
+}
+
+/// Returns what did not carry over from `before` to `after`.
+pub fn compare(before: &GuestState, after: &GuestState) -> Vec<Discontinuity> {
+    let mut out = Vec::new();
+    for (name, b, a) in [
+        (
+            "reference_time",
+            before.reference_time,
+            after.reference_time,
+        ),
+        ("tsc", before.tsc, after.tsc),
+    ] {
+        if a < b {
+            out.push(Discontinuity::CounterBackwards {
+                name,
+                before: b,
+                after: a,
+            });
+        }
+    }
+    compare_values(&before.registers, &after.registers, &mut out);
+    compare_values(&before.msrs, &after.msrs, &mut out);
+    out
+}
+
+#[derive(Serialize)]
+struct SaveRestoreRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    before: &'a GuestState,
+    after: &'a GuestState,
+    discontinuities: &'a [Discontinuity],
+}
+
+/// Writes the `save_restore_state` record of a save and restore.
+pub fn write_record(before: &GuestState, after: &GuestState, discontinuities: &[Discontinuity]) {
+    for d in discontinuities {
+        log::info!("not carried over: {:?}", d);
+    }
+    crate::tmk_logger::write_record(&SaveRestoreRecord {
+        record_type: "save_restore_state",
+        before,
+        after,
+        discontinuities,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(reference_time: u64, tsc: u64, cr0: u64, sint: u64) -> GuestState {
+        GuestState {
+            reference_time,
+            tsc,
+            registers: vec![Value {
+                name: "cr0",
+                value: cr0,
+            }],
+            msrs: vec![Value {
+                name: "sint0",
+                value: sint,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_compare() {
+        let before = state(100, 1000, 0x11, 0x10000);
+        assert_eq!(compare(&before, &state(200, 2000, 0x11, 0x10000)), []);
+        assert_eq!(
+            compare(&before, &state(50, 2000, 0x11, 0x10031)),
+            [
+                Discontinuity::CounterBackwards {
+                    name: "reference_time",
+                    before: 100,
+                    after: 50
+                },
+                Discontinuity::Changed {
+                    name: "sint0",
+                    before: 0x10000,
+                    after: 0x10031
+                },
+            ]
+        );
+    }
+}
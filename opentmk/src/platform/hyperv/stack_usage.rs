@@ -0,0 +1,112 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Stack usage instrumentation of the VP command stacks.
+//!
+//! Every stack a VP starts on is filled with [`CANARY`] when it is
+//! allocated. After each command the executor scans the stack from its
+//! bottom for the first overwritten word, which gives the deepest the stack
+//! has grown so far, and reports it in the `command_complete` record. The
+//! high-water mark is cumulative over the commands run on a stack.
+
+// VP stacks are only allocated on x86_64, since aarch support is not complete
+#![cfg_attr(target_arch = "aarch64", expect(dead_code))] // xtask-fmt allow-target-arch sys-crate
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use hvdef::Vtl;
+use serde::Serialize;
+use spin::Mutex;
+
+/// Size of the stack each VP and VTL starts on.
+pub(crate) const VP_STACK_SIZE: usize = 1024 * 1024;
+/// Pattern the unused part of a stack holds.
+const CANARY: u64 = 0x5354_4143_4b43_414e;
+
+/// Stacks filled with the canary.
+static STACKS: Mutex<Vec<Range<u64>>> = Mutex::new(Vec::new());
+
+/// Fills the freshly allocated stack at `base` with the canary and tracks
+/// it.
+///
+/// # Safety
+/// `base` must point to `size` writable bytes, aligned to 8, that nothing
+/// else uses yet.
+pub(crate) unsafe fn paint(base: *mut u8, size: usize) {
+    let words = base.cast::<u64>();
+    for i in 0..size / 8 {
+        // SAFETY: the caller guarantees the whole stack is writable.
+        unsafe { words.add(i).write_volatile(CANARY) };
+    }
+    STACKS.lock().push(base as u64..base as u64 + size as u64);
+}
+
+/// Returns the tracked stack the current stack pointer is in.
+pub(crate) fn current() -> Option<Range<u64>> {
+    let sp = crate::arch::stack::stack_pointer();
+    STACKS
+        .lock()
+        .iter()
+        .find(|stack| stack.contains(&sp))
+        .cloned()
+}
+
+/// Returns how many bytes of `stack` have been used. Must only be called
+/// by the VP running on `stack`.
+pub(crate) fn high_water(stack: &Range<u64>) -> u64 {
+    let words = stack.start as *const u64;
+    let count = ((stack.end - stack.start) / 8) as usize;
+    let mut untouched = 0;
+    while untouched < count {
+        // SAFETY: tracked stacks are never freed, and only the VP running on
+        // `stack` scans it, so nothing writes the stack during the scan.
+        if unsafe { words.add(untouched).read_volatile() } != CANARY {
+            break;
+        }
+        untouched += 1;
+    }
+    stack.end - stack.start - untouched as u64 * 8
+}
+
+#[derive(Serialize)]
+struct CommandCompleteRecord {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    vp_index: u32,
+    vtl: u8,
+    /// Number of commands run by this VP in this VTL, this one included.
+    command: u64,
+    /// Size of the stack the command ran on, `None` if it is not tracked.
+    stack_size: Option<u64>,
+    /// Deepest use of the stack so far, in bytes.
+    stack_high_water: Option<u64>,
+}
+
+/// Writes the `command_complete` record of a command that ran on `stack`.
+pub(crate) fn write_command_complete(
+    vp_index: u32,
+    vtl: Vtl,
+    command: u64,
+    stack: Option<&Range<u64>>,
+) {
+    let high_water = stack.map(high_water);
+    let stack_size = stack.map(|stack| stack.end - stack.start);
+    if let (Some(used), Some(size)) = (high_water, stack_size) {
+        log::debug!(
+            "vp {} {:?} command {} used {} of {} stack bytes",
+            vp_index,
+            vtl,
+            command,
+            used,
+            size
+        );
+    }
+    crate::tmk_logger::write_record(&CommandCompleteRecord {
+        record_type: "command_complete",
+        vp_index,
+        vtl: vtl.into(),
+        command,
+        stack_size,
+        stack_high_water: high_water,
+    });
+}
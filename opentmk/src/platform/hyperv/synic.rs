@@ -0,0 +1,460 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Minimal SynIC support for message based device clients.
+//!
+//! Messages are picked up by polling the SIMP slot of a SINT, so no interrupt
+//! infrastructure is required to use this module. Synthetic timers can be
+//! armed to deliver their expiration messages to a SINT, which gives tests a
+//! message source that needs no port set up by the host.
+//!
+//! Tests that use many SINTs reconfigure them all with
+//! [`Synic::configure_all`], restoring the previous configuration from the
+//! [`SintSnapshot`] it returns, and hand out event flags with
+//! [`EventConnections`], which keeps count of the flags in use so that
+//! running out of them is an error rather than two users sharing a flag.
+
+use alloc::alloc::alloc_zeroed;
+use alloc::alloc::dealloc;
+use core::alloc::Layout;
+use core::arch::x86_64::__cpuid;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use hvdef::HV_PAGE_SIZE;
+use hvdef::HvFeatures;
+use hvdef::HvMessage;
+use hvdef::HvMessageType;
+use hvdef::HvSynicScontrol;
+use hvdef::HvSynicSimpSiefp;
+use hvdef::HvSynicSint;
+use hvdef::HvSynicStimerConfig;
+use hvdef::Vtl;
+use minimal_rt::arch::msr::read_msr;
+use minimal_rt::arch::msr::write_msr;
+
+use crate::context::SynicEventPlatformTrait;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// Event flags of each SINT in the event flag page.
+pub const EVENT_FLAGS_PER_SINT: usize = 2048;
+/// Lowest vector a SINT may be routed to.
+const MIN_SINT_VECTOR: u8 = 0x10;
+
+/// SynIC features advertised to the partition.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SynicCapabilities {
+    /// The SynIC version from `HV_X64_MSR_SVERSION`, 0 if the SynIC MSRs
+    /// are not accessible. The hypervisor advertises no SINT count: every
+    /// version has [`hvdef::NUM_SINTS`].
+    pub version: u32,
+    /// Whether SINTs can be configured in polling mode.
+    pub polling: bool,
+    /// Whether synthetic timers can deliver in direct mode, bypassing the
+    /// SINTs.
+    pub direct_mode: bool,
+    /// Whether SINTs can be proxied. The TLFS ties this to the
+    /// CpuManagement privilege rather than a dedicated feature bit.
+    pub proxy: bool,
+    /// Whether the synthetic timers and the reference counter they count
+    /// against are accessible.
+    pub timers: bool,
+}
+
+/// Query the SynIC capabilities from the Hyper-V CPUID leaves and MSRs.
+pub fn capabilities() -> SynicCapabilities {
+    // SAFETY: CPUID is always available on x86_64.
+    let leaf = unsafe { __cpuid(hvdef::HV_CPUID_FUNCTION_MS_HV_FEATURES) };
+    let features = HvFeatures::from_cpuid([leaf.eax, leaf.ebx, leaf.ecx, leaf.edx]);
+    let privileges = features.privileges();
+
+    if !privileges.access_synic_msrs() {
+        return SynicCapabilities {
+            version: 0,
+            polling: false,
+            direct_mode: false,
+            proxy: false,
+            timers: false,
+        };
+    }
+
+    // SAFETY: the SynIC MSRs are accessible per the privileges above.
+    let version = unsafe { read_msr(hvdef::HV_X64_MSR_SVERSION) } as u32;
+    SynicCapabilities {
+        version,
+        polling: features.sint_polling_mode_available(),
+        direct_mode: features.direct_synthetic_timers(),
+        proxy: privileges.cpu_management(),
+        timers: privileges.access_synthetic_timer_msrs()
+            && privileges.access_partition_reference_counter(),
+    }
+}
+
+/// Returns the partition reference time, in 100ns units.
+pub fn reference_time() -> u64 {
+    // SAFETY: reading the reference counter MSR has no side effects.
+    unsafe { read_msr(hvdef::HV_X64_MSR_TIME_REF_COUNT) }
+}
+
+/// Signal end-of-message, so that the hypervisor delivers the next message
+/// queued for a SINT whose slot was released.
+pub fn end_of_message() {
+    // SAFETY: writing the EOM MSR only triggers message redelivery.
+    unsafe { write_msr(hvdef::HV_X64_MSR_EOM, 0) };
+}
+
+/// Copy out and release the message in the slot for `sint` of the SIMP
+/// page at `simp`, without signaling end-of-message.
+fn take_slot(simp: *mut HvMessage, sint: u8) -> Option<HvMessage> {
+    if sint as usize >= hvdef::NUM_SINTS {
+        return None;
+    }
+    // SAFETY: the SIMP page holds one message slot per SINT.
+    let slot = unsafe { simp.add(sint as usize) };
+    // SAFETY: the slot is written by the hypervisor, so read it volatile.
+    let message = unsafe { core::ptr::read_volatile(slot) };
+    if message.header.typ == HvMessageType::HvMessageTypeNone {
+        return None;
+    }
+
+    // SAFETY: releasing the slot back to the hypervisor.
+    unsafe {
+        core::ptr::write_volatile(
+            addr_of_mut!((*slot).header.typ),
+            HvMessageType::HvMessageTypeNone,
+        );
+    }
+    Some(message)
+}
+
+/// Copy out and release the message pending in the slot for `sint` of the
+/// SIMP page at `simp`.
+fn poll_slot(simp: *mut HvMessage, sint: u8) -> Option<HvMessage> {
+    let message = take_slot(simp, sint)?;
+    if message.header.flags.message_pending() {
+        end_of_message();
+    }
+    Some(message)
+}
+
+/// Copy out and release the message pending for `sint` in the SIMP page of
+/// the current VP and VTL, which only needs the SIMP to be enabled, e.g.
+/// by `setup_secure_intercept`.
+///
+/// Returns `None` if the slot is empty or the SIMP is disabled.
+pub fn poll_current_message(sint: u8) -> Option<HvMessage> {
+    // SAFETY: reading the SIMP MSR of the current VP.
+    let simp = HvSynicSimpSiefp::from(unsafe { read_msr(hvdef::HV_X64_MSR_SIMP) });
+    if !simp.enabled() {
+        return None;
+    }
+    poll_slot((simp.base_gpn() * HV_PAGE_SIZE) as *mut HvMessage, sint)
+}
+
+/// The SynIC message and event flag pages of the current VP.
+pub struct Synic {
+    simp: *mut HvMessage,
+    siefp: *mut u8,
+}
+
+impl Synic {
+    /// Allocate the SIMP and SIEFP pages for the current VP and enable the
+    /// SynIC.
+    ///
+    /// The pages are never freed, the hypervisor keeps writing to them for
+    /// as long as the SynIC stays enabled.
+    pub fn enable() -> TmkResult<Self> {
+        let layout = Layout::from_size_align(HV_PAGE_SIZE as usize, HV_PAGE_SIZE as usize)
+            .map_err(|_| TmkError::AllocationFailed)?;
+        // SAFETY: the layout has a non-zero size.
+        let simp = unsafe { alloc_zeroed(layout) };
+        // SAFETY: the layout has a non-zero size.
+        let siefp = unsafe { alloc_zeroed(layout) };
+        if simp.is_null() || siefp.is_null() {
+            for page in [simp, siefp] {
+                if !page.is_null() {
+                    // SAFETY: the page was allocated above with `layout`.
+                    unsafe { dealloc(page, layout) };
+                }
+            }
+            return Err(TmkError::AllocationFailed);
+        }
+
+        let simp_reg = HvSynicSimpSiefp::new()
+            .with_enabled(true)
+            .with_base_gpn(simp as u64 / HV_PAGE_SIZE);
+        let siefp_reg = HvSynicSimpSiefp::new()
+            .with_enabled(true)
+            .with_base_gpn(siefp as u64 / HV_PAGE_SIZE);
+
+        // SAFETY: the SynIC MSRs are programmed with pages owned by this VP.
+        unsafe {
+            write_msr(hvdef::HV_X64_MSR_SIMP, simp_reg.into());
+            write_msr(hvdef::HV_X64_MSR_SIEFP, siefp_reg.into());
+            write_msr(
+                hvdef::HV_X64_MSR_SCONTROL,
+                HvSynicScontrol::new().with_enabled(true).into(),
+            );
+        }
+        log::debug!("enabled synic, simp: {:p}, siefp: {:p}", simp, siefp);
+
+        Ok(Synic {
+            simp: simp.cast(),
+            siefp,
+        })
+    }
+
+    /// Returns the SynIC of the current VP and VTL if it was enabled, e.g.
+    /// by an earlier [`Synic::enable`] on the same VP and VTL.
+    pub fn current() -> Option<Self> {
+        // SAFETY: reading the SynIC MSRs of the current VP.
+        let (simp, siefp) = unsafe {
+            (
+                HvSynicSimpSiefp::from(read_msr(hvdef::HV_X64_MSR_SIMP)),
+                HvSynicSimpSiefp::from(read_msr(hvdef::HV_X64_MSR_SIEFP)),
+            )
+        };
+        if !simp.enabled() || !siefp.enabled() {
+            return None;
+        }
+        Some(Synic {
+            simp: (simp.base_gpn() * HV_PAGE_SIZE) as *mut HvMessage,
+            siefp: (siefp.base_gpn() * HV_PAGE_SIZE) as *mut u8,
+        })
+    }
+
+    /// Unmask `sint` and route it to `vector`.
+    ///
+    /// With `polling` set the hypervisor does not raise an interrupt for new
+    /// messages; they are picked up with [`Synic::poll_message`]. Fails with
+    /// [`TmkError::FeatureUnavailable`] if polling mode is not advertised.
+    pub fn configure_sint(&self, sint: u8, vector: u8, polling: bool) -> TmkResult<()> {
+        if sint as usize >= hvdef::NUM_SINTS {
+            return Err(TmkError::InvalidParameter);
+        }
+        if polling && !capabilities().polling {
+            return Err(TmkError::FeatureUnavailable);
+        }
+        let value = HvSynicSint::new()
+            .with_vector(vector)
+            .with_masked(false)
+            .with_auto_eoi(true)
+            .with_polling(polling);
+        // SAFETY: writing a valid SINT configuration for the current VP.
+        unsafe { write_msr(hvdef::HV_X64_MSR_SINT0 + sint as u32, value.into()) };
+        Ok(())
+    }
+
+    /// Unmask every SINT, routing SINT `n` to vector
+    /// `vector_base + n`, and return the configuration they had before.
+    pub fn configure_all(&self, vector_base: u8, polling: bool) -> TmkResult<SintSnapshot> {
+        if vector_base < MIN_SINT_VECTOR || vector_base as usize + hvdef::NUM_SINTS > 256 {
+            return Err(TmkError::InvalidParameter);
+        }
+        let snapshot = self.snapshot_sints();
+        for sint in 0..hvdef::NUM_SINTS as u8 {
+            if let Err(e) = self.configure_sint(sint, vector_base + sint, polling) {
+                self.restore_sints(&snapshot);
+                return Err(e);
+            }
+        }
+        Ok(snapshot)
+    }
+
+    /// Save the configuration of every SINT.
+    pub fn snapshot_sints(&self) -> SintSnapshot {
+        // SAFETY: reading the SINT MSRs of the current VP.
+        SintSnapshot(core::array::from_fn(|sint| unsafe {
+            read_msr(hvdef::HV_X64_MSR_SINT0 + sint as u32)
+        }))
+    }
+
+    /// Restore the configuration of every SINT saved in `snapshot`.
+    pub fn restore_sints(&self, snapshot: &SintSnapshot) {
+        for (sint, &value) in snapshot.0.iter().enumerate() {
+            // SAFETY: writing back a configuration read from the same MSR.
+            unsafe { write_msr(hvdef::HV_X64_MSR_SINT0 + sint as u32, value) };
+        }
+    }
+
+    /// Read back the configuration of `sint`.
+    pub fn sint(&self, sint: u8) -> TmkResult<HvSynicSint> {
+        if sint as usize >= hvdef::NUM_SINTS {
+            return Err(TmkError::InvalidParameter);
+        }
+        // SAFETY: reading a SINT MSR of the current VP.
+        let value = unsafe { read_msr(hvdef::HV_X64_MSR_SINT0 + sint as u32) };
+        Ok(value.into())
+    }
+
+    /// Copy out and release the message pending in the slot for `sint`.
+    ///
+    /// Returns `None` if the slot is empty.
+    pub fn poll_message(&self, sint: u8) -> Option<HvMessage> {
+        poll_slot(self.simp, sint)
+    }
+
+    /// Copy out and release the message in the slot for `sint`, leaving
+    /// end-of-message to the caller.
+    ///
+    /// If the message has `message_pending` set, the next message is only
+    /// delivered after [`end_of_message`].
+    pub fn take_message(&self, sint: u8) -> Option<HvMessage> {
+        take_slot(self.simp, sint)
+    }
+
+    /// Arm synthetic timer `timer` to deliver one expiration message to
+    /// `sint` at reference time `expiration`.
+    pub fn arm_timer(&self, timer: u8, sint: u8, expiration: u64) -> TmkResult<()> {
+        let caps = capabilities();
+        if !caps.timers {
+            return Err(TmkError::FeatureUnavailable);
+        }
+        if timer as usize >= hvdef::NUM_TIMERS || sint as usize >= hvdef::NUM_SINTS {
+            return Err(TmkError::InvalidParameter);
+        }
+        let config = HvSynicStimerConfig::new()
+            .with_enabled(true)
+            .with_sint(sint);
+        let msr = hvdef::HV_X64_MSR_STIMER0_CONFIG + 2 * timer as u32;
+        // SAFETY: programming a synthetic timer of the current VP, the count
+        // is written last as it arms the timer.
+        unsafe {
+            write_msr(msr, config.into());
+            write_msr(msr + 1, expiration);
+        }
+        Ok(())
+    }
+
+    /// Disarm synthetic timer `timer`.
+    pub fn disarm_timer(&self, timer: u8) -> TmkResult<()> {
+        if timer as usize >= hvdef::NUM_TIMERS {
+            return Err(TmkError::InvalidParameter);
+        }
+        // SAFETY: disabling a synthetic timer of the current VP.
+        unsafe { write_msr(hvdef::HV_X64_MSR_STIMER0_CONFIG + 2 * timer as u32, 0) };
+        Ok(())
+    }
+
+    /// Address of the event flag page.
+    pub fn event_flags_page(&self) -> *mut u8 {
+        self.siefp
+    }
+
+    /// Clear event flag `flag` of `sint` and return whether it was set.
+    pub fn take_event_flag(&self, sint: u8, flag: u16) -> bool {
+        if sint as usize >= hvdef::NUM_SINTS || flag as usize >= EVENT_FLAGS_PER_SINT {
+            return false;
+        }
+        let offset = sint as usize * EVENT_FLAGS_PER_SINT / 8 + flag as usize / 64 * 8;
+        // SAFETY: the SIEFP page holds the flags of every SINT, and the
+        // hypervisor sets them with atomic operations too.
+        let word = unsafe { &*self.siefp.add(offset).cast::<AtomicU64>() };
+        let bit = 1 << (flag % 64);
+        word.fetch_and(!bit, Ordering::AcqRel) & bit != 0
+    }
+}
+
+/// The configuration of every SINT of a VP, see [`Synic::configure_all`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SintSnapshot([u64; hvdef::NUM_SINTS]);
+
+/// An event flag of a SINT of a VP and VTL, handed out by
+/// [`EventConnections`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct EventConnection {
+    /// The VP the flag belongs to.
+    pub vp_index: u32,
+    /// The VTL the flag belongs to.
+    pub vtl: Vtl,
+    /// The SINT of the flag.
+    pub sint: u8,
+    /// The flag number within the SINT.
+    pub flag: u16,
+}
+
+impl EventConnection {
+    /// Sets the flag with `HvCallSignalEventDirect`. Returns whether it was
+    /// newly set.
+    pub fn signal<T: SynicEventPlatformTrait>(&self, ctx: &mut T) -> TmkResult<bool> {
+        ctx.signal_event_direct(self.vp_index, self.vtl, self.sint, self.flag)
+    }
+}
+
+/// Bookkeeping of the event flags handed out on the SINTs of a VP and VTL.
+///
+/// Ports and connections cannot be created from inside the partition, so a
+/// connection here is an event flag signaled directly. Flags are handed out
+/// in order and given back all at once with [`EventConnections::release_all`].
+pub struct EventConnections {
+    vp_index: u32,
+    vtl: Vtl,
+    next: [u16; hvdef::NUM_SINTS],
+}
+
+impl EventConnections {
+    /// No flag of `vp_index` in `vtl` handed out yet.
+    pub fn new(vp_index: u32, vtl: Vtl) -> Self {
+        Self {
+            vp_index,
+            vtl,
+            next: [0; hvdef::NUM_SINTS],
+        }
+    }
+
+    /// Hands out the next free flag of `sint`. Fails with
+    /// [`TmkError::InsufficientBuffers`] once every flag of the SINT is in
+    /// use.
+    pub fn connect(&mut self, sint: u8) -> TmkResult<EventConnection> {
+        let next = self
+            .next
+            .get_mut(sint as usize)
+            .ok_or(TmkError::InvalidParameter)?;
+        if *next as usize >= EVENT_FLAGS_PER_SINT {
+            return Err(TmkError::InsufficientBuffers);
+        }
+        let flag = *next;
+        *next += 1;
+        Ok(EventConnection {
+            vp_index: self.vp_index,
+            vtl: self.vtl,
+            sint,
+            flag,
+        })
+    }
+
+    /// Number of flags of `sint` handed out.
+    pub fn connected(&self, sint: u8) -> usize {
+        self.next.get(sint as usize).map_or(0, |&n| n as usize)
+    }
+
+    /// Gives every flag back.
+    pub fn release_all(&mut self) {
+        self.next = [0; hvdef::NUM_SINTS];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connections_run_out() {
+        let mut connections = EventConnections::new(1, Vtl::Vtl0);
+        for flag in 0..EVENT_FLAGS_PER_SINT {
+            let c = connections.connect(3).unwrap();
+            assert_eq!((c.vp_index, c.sint, c.flag as usize), (1, 3, flag));
+        }
+        assert_eq!(connections.connect(3), Err(TmkError::InsufficientBuffers));
+        assert_eq!(connections.connect(4).map(|c| c.flag), Ok(0));
+        assert_eq!(
+            connections.connect(hvdef::NUM_SINTS as u8),
+            Err(TmkError::InvalidParameter)
+        );
+        assert_eq!(connections.connected(3), EVENT_FLAGS_PER_SINT);
+        connections.release_all();
+        assert_eq!(connections.connected(3), 0);
+    }
+}
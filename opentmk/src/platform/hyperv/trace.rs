@@ -0,0 +1,186 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Hypercall trace ring and per-code timing statistics.
+//!
+//! Every hypercall issued through [`HvCall`](super::arch::hypercall::HvCall)
+//! is recorded here. Recording only uses atomics so it is safe from any VP
+//! and from interrupt context; concurrent readers may observe an entry that
+//! is being overwritten, which is acceptable for diagnostics.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+use serde::Serialize;
+
+/// Number of hypercalls kept in the trace ring.
+const RING_SIZE: usize = 256;
+/// Number of distinct hypercall codes tracked by the statistics table.
+const STATS_SIZE: usize = 64;
+
+/// A traced hypercall.
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct TraceEntry {
+    /// The hypercall code.
+    pub code: u16,
+    /// The rep count, saturated to `u16::MAX`.
+    pub rep_count: u16,
+    /// The hypercall status.
+    pub result: u16,
+    /// Cycles spent in the hypercall.
+    pub cycles: u64,
+}
+
+struct RingSlot {
+    header: AtomicU64,
+    cycles: AtomicU64,
+}
+
+/// Per-code aggregates. `code` holds the hypercall code plus one so that
+/// zero marks a free slot.
+struct StatsSlot {
+    code: AtomicU32,
+    count: AtomicU64,
+    total_cycles: AtomicU64,
+}
+
+static RING: [RingSlot; RING_SIZE] = [const {
+    RingSlot {
+        header: AtomicU64::new(0),
+        cycles: AtomicU64::new(0),
+    }
+}; RING_SIZE];
+static RING_HEAD: AtomicUsize = AtomicUsize::new(0);
+
+static STATS: [StatsSlot; STATS_SIZE] = [const {
+    StatsSlot {
+        code: AtomicU32::new(0),
+        count: AtomicU64::new(0),
+        total_cycles: AtomicU64::new(0),
+    }
+}; STATS_SIZE];
+
+fn stats_slot(code: u16) -> Option<&'static StatsSlot> {
+    let key = code as u32 + 1;
+    let start = code as usize % STATS_SIZE;
+    for i in 0..STATS_SIZE {
+        let slot = &STATS[(start + i) % STATS_SIZE];
+        match slot
+            .code
+            .compare_exchange(0, key, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => return Some(slot),
+            Err(existing) if existing == key => return Some(slot),
+            Err(_) => {}
+        }
+    }
+    None
+}
+
+/// Records a completed hypercall.
+pub(crate) fn record(code: u16, rep_count: usize, result: u16, cycles: u64) {
+    let rep_count = rep_count.min(u16::MAX as usize) as u64;
+    let index = RING_HEAD.fetch_add(1, Ordering::Relaxed) % RING_SIZE;
+    let slot = &RING[index];
+    slot.header.store(
+        code as u64 | rep_count << 16 | (result as u64) << 32 | 1 << 63,
+        Ordering::Relaxed,
+    );
+    slot.cycles.store(cycles, Ordering::Release);
+
+    if let Some(stats) = stats_slot(code) {
+        stats.count.fetch_add(1, Ordering::Relaxed);
+        stats.total_cycles.fetch_add(cycles, Ordering::Relaxed);
+    }
+}
+
+/// Returns the traced hypercalls, oldest first.
+pub fn recent() -> Vec<TraceEntry> {
+    let head = RING_HEAD.load(Ordering::Acquire);
+    let count = head.min(RING_SIZE);
+    (head - count..head)
+        .filter_map(|i| {
+            let slot = &RING[i % RING_SIZE];
+            let cycles = slot.cycles.load(Ordering::Acquire);
+            let header = slot.header.load(Ordering::Relaxed);
+            (header & 1 << 63 != 0).then_some(TraceEntry {
+                code: header as u16,
+                rep_count: (header >> 16) as u16,
+                result: (header >> 32) as u16,
+                cycles,
+            })
+        })
+        .collect()
+}
+
+/// Aggregated statistics for one hypercall code.
+#[derive(Clone, Debug, Serialize)]
+pub struct CodeStats {
+    /// The hypercall code.
+    pub code: u16,
+    /// The hypercall name, as known to `hvdef`.
+    pub name: String,
+    /// Number of calls.
+    pub count: u64,
+    /// Total cycles spent in the calls.
+    pub total_cycles: u64,
+}
+
+/// Returns the per-code statistics, most expensive codes first.
+pub fn stats() -> Vec<CodeStats> {
+    let mut stats: Vec<CodeStats> = STATS
+        .iter()
+        .filter_map(|slot| {
+            let key = slot.code.load(Ordering::Acquire);
+            let count = slot.count.load(Ordering::Relaxed);
+            (key != 0 && count != 0).then(|| {
+                let code = (key - 1) as u16;
+                CodeStats {
+                    code,
+                    name: format!("{:?}", hvdef::HypercallCode(code)),
+                    count,
+                    total_cycles: slot.total_cycles.load(Ordering::Relaxed),
+                }
+            })
+        })
+        .collect();
+    stats.sort_by(|a, b| b.total_cycles.cmp(&a.total_cycles));
+    stats
+}
+
+/// Clears the trace ring and the statistics. Must not race with
+/// hypercalls on other VPs.
+pub fn reset() {
+    for slot in &RING {
+        slot.header.store(0, Ordering::Relaxed);
+        slot.cycles.store(0, Ordering::Relaxed);
+    }
+    RING_HEAD.store(0, Ordering::Release);
+    for slot in &STATS {
+        slot.count.store(0, Ordering::Relaxed);
+        slot.total_cycles.store(0, Ordering::Relaxed);
+    }
+}
+
+#[derive(Serialize)]
+struct TestEndRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    test: &'a str,
+    hypercalls: Vec<CodeStats>,
+}
+
+/// Writes the `test_end` record for `test`, carrying the hypercall
+/// statistics gathered while it ran.
+pub fn write_test_end(test: &str) {
+    crate::tmk_logger::write_record(&TestEndRecord {
+        record_type: "test_end",
+        test,
+        hypercalls: stats(),
+    });
+}
@@ -0,0 +1,215 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Arch-neutral access to the context of a VP.
+//!
+//! Test logic bouncing VPs between VTLs points them at an entry point, gives
+//! them a stack and arguments, and reads where they faulted. [`VpContext`]
+//! does the first two on the initial context a VP or VTL is started with,
+//! [`VirtualProcessor`] all of them on the registers of a running VP in a
+//! VTL, so that the same test code drives x86_64 and aarch64 VPs.
+
+#[cfg(target_arch = "aarch64")] // xtask-fmt allow-target-arch sys-crate
+use hvdef::HvArm64RegisterName;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+use hvdef::HvX64RegisterName;
+use hvdef::Vtl;
+#[cfg(target_arch = "aarch64")] // xtask-fmt allow-target-arch sys-crate
+use hvdef::hypercall::InitialVpContextArm64;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+use hvdef::hypercall::InitialVpContextX64;
+use serde::Serialize;
+
+use crate::context::VtlPlatformTrait;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// The initial context of the architecture.
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub type RawContext = InitialVpContextX64;
+/// The initial context of the architecture.
+#[cfg(target_arch = "aarch64")] // xtask-fmt allow-target-arch sys-crate
+pub type RawContext = InitialVpContextArm64;
+
+/// The instruction pointer.
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub const PC_REGISTER: u32 = HvX64RegisterName::Rip.0;
+/// The stack pointer.
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub const SP_REGISTER: u32 = HvX64RegisterName::Rsp.0;
+/// The argument registers, in order. UEFI code follows the Microsoft x64
+/// calling convention.
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub const ARG_REGISTERS: &[u32] = &[
+    HvX64RegisterName::Rcx.0,
+    HvX64RegisterName::Rdx.0,
+    HvX64RegisterName::R8.0,
+    HvX64RegisterName::R9.0,
+];
+
+/// The instruction pointer.
+#[cfg(target_arch = "aarch64")] // xtask-fmt allow-target-arch sys-crate
+pub const PC_REGISTER: u32 = HvArm64RegisterName::XPc.0;
+/// The stack pointer of the exception level the VP runs at.
+#[cfg(target_arch = "aarch64")] // xtask-fmt allow-target-arch sys-crate
+pub const SP_REGISTER: u32 = HvArm64RegisterName::XSp.0;
+/// The argument registers, in order.
+#[cfg(target_arch = "aarch64")] // xtask-fmt allow-target-arch sys-crate
+pub const ARG_REGISTERS: &[u32] = &[
+    HvArm64RegisterName::X0.0,
+    HvArm64RegisterName::X1.0,
+    HvArm64RegisterName::X2.0,
+    HvArm64RegisterName::X3.0,
+    HvArm64RegisterName::X4.0,
+    HvArm64RegisterName::X5.0,
+    HvArm64RegisterName::X6.0,
+    HvArm64RegisterName::X7.0,
+];
+
+/// The initial context of a VP or VTL.
+#[derive(Copy, Clone)]
+pub struct VpContext(RawContext);
+
+impl VpContext {
+    /// Wraps the initial context of the architecture.
+    pub fn from_raw(raw: RawContext) -> Self {
+        Self(raw)
+    }
+
+    /// Returns the initial context of the architecture.
+    pub fn into_raw(self) -> RawContext {
+        self.0
+    }
+
+    /// The address the VP starts executing at.
+    pub fn entry_point(&self) -> u64 {
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        return self.0.rip;
+        #[cfg(target_arch = "aarch64")] // xtask-fmt allow-target-arch sys-crate
+        return self.0.pc;
+    }
+
+    /// Sets the address the VP starts executing at.
+    pub fn set_entry_point(&mut self, address: u64) {
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        {
+            self.0.rip = address;
+        }
+        #[cfg(target_arch = "aarch64")] // xtask-fmt allow-target-arch sys-crate
+        {
+            self.0.pc = address;
+        }
+    }
+
+    /// The stack pointer the VP starts with.
+    pub fn stack_pointer(&self) -> u64 {
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        return self.0.rsp;
+        #[cfg(target_arch = "aarch64")] // xtask-fmt allow-target-arch sys-crate
+        return self.0.sp_elh;
+    }
+
+    /// Sets the stack pointer the VP starts with, the top of its stack.
+    pub fn set_stack_pointer(&mut self, top: u64) {
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        {
+            self.0.rsp = top;
+        }
+        #[cfg(target_arch = "aarch64")] // xtask-fmt allow-target-arch sys-crate
+        {
+            self.0.sp_elh = top;
+        }
+    }
+}
+
+/// Where a VP last faulted, as its registers tell.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FaultInfo {
+    /// The instruction that faulted: RIP as the exception left it on
+    /// x86_64, ELR_EL1 on aarch64.
+    pub pc: u64,
+    /// The address accessed: CR2 on x86_64, FAR_EL1 on aarch64.
+    pub address: u64,
+    /// ESR_EL1 on aarch64; x86_64 keeps the error code on the stack only.
+    pub syndrome: Option<u64>,
+}
+
+/// The registers of VP `vp_index` in `vtl`, read and written through the
+/// hypervisor. A higher VTL can reach the registers of a lower one.
+///
+/// The general purpose registers, and so the argument registers, are
+/// shared between the VTLs of a VP, as are CR2 and FAR_EL1 which
+/// [`Self::fault_info`] reads: from VTL1, those of VTL0 on the same VP are
+/// the ones VTL1 itself runs with. They are only meaningful on another VP,
+/// halted or not started yet, whose registers nothing else uses.
+pub struct VirtualProcessor<'a, T> {
+    ctx: &'a mut T,
+    vp_index: u32,
+    vtl: Vtl,
+}
+
+impl<'a, T: VtlPlatformTrait> VirtualProcessor<'a, T> {
+    /// Accesses the registers of VP `vp_index` in `vtl`.
+    pub fn new(ctx: &'a mut T, vp_index: u32, vtl: Vtl) -> Self {
+        Self { ctx, vp_index, vtl }
+    }
+
+    fn get(&mut self, register: u32) -> TmkResult<u64> {
+        self.ctx
+            .get_vp_register_on_vp(self.vp_index, register, self.vtl)
+    }
+
+    fn set(&mut self, register: u32, value: u64) -> TmkResult<()> {
+        self.ctx
+            .set_vp_register_on_vp(self.vp_index, register, value, self.vtl)
+    }
+
+    /// Returns the address the VP executes next.
+    pub fn entry_point(&mut self) -> TmkResult<u64> {
+        self.get(PC_REGISTER)
+    }
+
+    /// Makes the VP execute `address` next.
+    pub fn set_entry_point(&mut self, address: u64) -> TmkResult<()> {
+        self.set(PC_REGISTER, address)
+    }
+
+    /// Returns the stack pointer of the VP.
+    pub fn stack_pointer(&mut self) -> TmkResult<u64> {
+        self.get(SP_REGISTER)
+    }
+
+    /// Points the VP at a stack whose top is `top`.
+    pub fn set_stack(&mut self, top: u64) -> TmkResult<()> {
+        self.set(SP_REGISTER, top)
+    }
+
+    /// Loads `args` in the argument registers of the VP, in order. Fails
+    /// with [`TmkError::InvalidParameter`] if there are more arguments than
+    /// registers.
+    pub fn set_args(&mut self, args: &[u64]) -> TmkResult<()> {
+        if args.len() > ARG_REGISTERS.len() {
+            return Err(TmkError::InvalidParameter);
+        }
+        ARG_REGISTERS
+            .iter()
+            .zip(args)
+            .try_for_each(|(&register, &value)| self.set(register, value))
+    }
+
+    /// Returns where the VP last faulted.
+    pub fn fault_info(&mut self) -> TmkResult<FaultInfo> {
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        return Ok(FaultInfo {
+            pc: self.get(PC_REGISTER)?,
+            address: self.get(HvX64RegisterName::Cr2.0)?,
+            syndrome: None,
+        });
+        #[cfg(target_arch = "aarch64")] // xtask-fmt allow-target-arch sys-crate
+        return Ok(FaultInfo {
+            pc: self.get(HvArm64RegisterName::ElrEl1.0)?,
+            address: self.get(HvArm64RegisterName::FarEl1.0)?,
+            syndrome: Some(self.get(HvArm64RegisterName::EsrEl1.0)?),
+        });
+    }
+}
@@ -0,0 +1,91 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! VTL1 VP assist pages and VTL entry reasons.
+//!
+//! Each time a VTL is entered, the hypervisor writes why, a VTL call, an
+//! interrupt or an intercept, to the VTL control area of the VP assist page
+//! of that VTL. [`enable`] gives VTL1 an assist page on each VP as the VP
+//! first runs in VTL1, and [`record_entry`] notes the reason every time
+//! VTL1 resumes from a VTL return, for tests to check through
+//! `VtlPlatformTrait::get_vtl_entry_reason`. The first entry of a VP into
+//! VTL1 precedes its assist page, so no reason is known for it.
+
+use alloc::alloc::alloc_zeroed;
+use core::alloc::Layout;
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::Ordering;
+
+use hvdef::HV_PAGE_SIZE;
+use hvdef::HvRegisterVpAssistPage;
+use hvdef::HvVpAssistPage;
+use hvdef::HvVtlEntryReason;
+use minimal_rt::arch::msr::read_msr;
+use minimal_rt::arch::msr::write_msr;
+
+use crate::tmkdefs::MAX_VPS;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+use crate::tmkdefs::vp_slot;
+
+/// Entry reason of a VP that VTL1 was not resumed on yet.
+const NO_REASON: u32 = u32::MAX;
+
+/// The VTL1 assist page of each VP, null until [`enable`] ran on it.
+static PAGES: [AtomicPtr<HvVpAssistPage>; MAX_VPS] =
+    [const { AtomicPtr::new(core::ptr::null_mut()) }; MAX_VPS];
+static ENTRY_REASONS: [AtomicU32; MAX_VPS] = [const { AtomicU32::new(NO_REASON) }; MAX_VPS];
+
+/// Enables the VP assist page of the current VTL, which must be VTL1, on
+/// `vp_index`, the current VP. A page the firmware or an earlier run left
+/// enabled is kept.
+pub(crate) fn enable(vp_index: u32) -> TmkResult<()> {
+    let slot = &PAGES[vp_slot(vp_index)?];
+    if !slot.load(Ordering::Acquire).is_null() {
+        return Ok(());
+    }
+    // SAFETY: the VP assist page MSR is always present under Hyper-V.
+    let current =
+        HvRegisterVpAssistPage::from(unsafe { read_msr(hvdef::HV_X64_MSR_VP_ASSIST_PAGE) });
+    let page = if current.enabled() {
+        (current.gpa_page_number() * HV_PAGE_SIZE) as *mut HvVpAssistPage
+    } else {
+        let layout = Layout::from_size_align(HV_PAGE_SIZE as usize, HV_PAGE_SIZE as usize)
+            .map_err(|_| TmkError::AllocationFailed)?;
+        // SAFETY: the layout has a non-zero size.
+        let page = unsafe { alloc_zeroed(layout) };
+        if page.is_null() {
+            return Err(TmkError::AllocationFailed);
+        }
+        let reg = HvRegisterVpAssistPage::new()
+            .with_enabled(true)
+            .with_gpa_page_number(page as u64 / HV_PAGE_SIZE);
+        // SAFETY: the page is identity mapped and never freed, so the
+        // hypervisor may keep writing to it.
+        unsafe { write_msr(hvdef::HV_X64_MSR_VP_ASSIST_PAGE, reg.into()) };
+        page.cast()
+    };
+    slot.store(page, Ordering::Release);
+    Ok(())
+}
+
+/// Records why VTL1 was just entered on `vp_index`, the current VP. Called
+/// as VTL1 resumes from a VTL return, before anything else runs in it.
+pub(crate) fn record_entry(vp_index: u32) {
+    let page = PAGES[vp_index as usize].load(Ordering::Acquire);
+    if page.is_null() {
+        return;
+    }
+    // SAFETY: the page was enabled by `enable` and stays mapped; the field
+    // is written by the hypervisor, hence the volatile read.
+    let reason = unsafe { core::ptr::addr_of!((*page).vtl_control.entry_reason).read_volatile() };
+    ENTRY_REASONS[vp_index as usize].store(reason.0, Ordering::Release);
+}
+
+/// Returns why VTL1 was last entered on `vp_index`, `None` if no entry was
+/// recorded yet or the VP is past [`MAX_VPS`].
+pub fn last_entry_reason(vp_index: u32) -> Option<HvVtlEntryReason> {
+    let reason = ENTRY_REASONS[vp_slot(vp_index).ok()?].load(Ordering::Acquire);
+    (reason != NO_REASON).then_some(HvVtlEntryReason(reason))
+}
@@ -0,0 +1,143 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! The hypervisor's view of VTL protections.
+//!
+//! A higher VTL sets the protections of lower VTLs with
+//! `HvCallModifyVtlProtectionMask` and can ask the hypervisor how they apply
+//! to given pages with `HvCallCheckSparseGpaPageVtlAccess`, both through
+//! [`crate::context::VtlAccessPlatformTrait`]. [`AccessComparison`] puts
+//! the protections a test applied, what the hypervisor reports and what the
+//! lower VTL observed when accessing the page side by side, so that the
+//! three can be checked against each other.
+
+use hvdef::HvMapGpaFlags;
+use hvdef::hypercall::CheckGpaPageVtlAccessResultCode;
+use hvdef::hypercall::CheckSparseGpaPageVtlAccessOutput;
+use serde::Serialize;
+
+/// An access to a page.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    /// A data read.
+    Read,
+    /// A data write.
+    Write,
+    /// An instruction fetch from supervisor mode.
+    Execute,
+}
+
+impl Access {
+    /// Every data access, in order.
+    pub const ALL: [Access; 2] = [Access::Read, Access::Write];
+
+    /// The protection flags granting the access.
+    pub fn flags(self) -> HvMapGpaFlags {
+        match self {
+            Access::Read => HvMapGpaFlags::new().with_readable(true),
+            Access::Write => HvMapGpaFlags::new().with_writable(true),
+            Access::Execute => HvMapGpaFlags::new().with_kernel_executable(true),
+        }
+    }
+
+    /// Whether `flags` grant the access.
+    pub fn is_granted(self, flags: HvMapGpaFlags) -> bool {
+        u32::from(flags) & u32::from(self.flags()) != 0
+    }
+}
+
+/// The hypervisor's answer for one page.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct AccessCheck {
+    /// The page checked.
+    pub gpn: u64,
+    /// Whether the access is allowed.
+    pub allowed: bool,
+    /// The denied access flags, if the access is not allowed.
+    pub denied_access: u8,
+    /// The VTL the access would be intercepted by, if it is not allowed.
+    pub intercepting_vtl: u8,
+}
+
+impl AccessCheck {
+    /// Interprets the hypervisor output for `gpn`.
+    pub fn new(gpn: u64, output: CheckSparseGpaPageVtlAccessOutput) -> Self {
+        Self {
+            gpn,
+            allowed: output.result_code() as u32 == CheckGpaPageVtlAccessResultCode::SUCCESS.0,
+            denied_access: output.denied_access(),
+            intercepting_vtl: output.intercepting_vtl() as u8,
+        }
+    }
+}
+
+/// An access to a page as applied, reported and observed.
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct AccessComparison {
+    /// The page accessed.
+    pub gpn: u64,
+    /// The access.
+    pub access: Access,
+    /// Whether the protections the test applied grant it.
+    pub applied: bool,
+    /// Whether the hypervisor reports it as allowed.
+    pub reported: bool,
+    /// Whether the access went through without an intercept.
+    pub observed: bool,
+}
+
+impl AccessComparison {
+    /// Returns whether the three views agree.
+    pub fn consistent(&self) -> bool {
+        self.applied == self.reported && self.reported == self.observed
+    }
+}
+
+#[derive(Serialize)]
+struct VtlAccessMatrixRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    comparisons: &'a [AccessComparison],
+}
+
+/// Writes the `vtl_access_matrix` record of `comparisons`.
+pub fn write_record(comparisons: &[AccessComparison]) {
+    for c in comparisons {
+        log::info!(
+            "gpn {:#x} {:?}: applied {}, reported {}, observed {}",
+            c.gpn,
+            c.access,
+            c.applied,
+            c.reported,
+            c.observed
+        );
+    }
+    crate::tmk_logger::write_record(&VtlAccessMatrixRecord {
+        record_type: "vtl_access_matrix",
+        comparisons,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_access_check() {
+        let read_only = HvMapGpaFlags::new().with_readable(true);
+        assert!(Access::Read.is_granted(read_only));
+        assert!(!Access::Write.is_granted(read_only));
+        assert!(Access::Write.is_granted(hvdef::HV_MAP_GPA_PERMISSIONS_ALL));
+        assert!(!Access::Execute.is_granted(read_only.with_user_executable(true)));
+
+        let denied = CheckSparseGpaPageVtlAccessOutput::new()
+            .with_result_code(CheckGpaPageVtlAccessResultCode::MEMORY_INTERCEPT.0 as u8)
+            .with_denied_access(2)
+            .with_intercepting_vtl(1);
+        let check = AccessCheck::new(7, denied);
+        assert!(!check.allowed);
+        assert_eq!((check.denied_access, check.intercepting_vtl), (2, 1));
+        assert!(AccessCheck::new(7, CheckSparseGpaPageVtlAccessOutput::new()).allowed);
+    }
+}
@@ -0,0 +1,297 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Watchpoints on guest memory, built on VTL protections.
+//!
+//! [`watch`] has VTL1 take VTL0 access away from the pages of a range, so
+//! that every VTL0 access to them raises a secure intercept. The VTL1
+//! handler, [`handle_intercept`], records the accesses that touch the range
+//! with the VTL0 RIP that made them and calls the `on_access` hook, giving
+//! tests gdb-style watchpoints, e.g. on the stack slots of VTL0 that get
+//! corrupted.
+//!
+//! Accesses are then stepped by emulation rather than with the trap flag:
+//! VTL1 makes the load or store on behalf of VTL0 and moves VTL0 past the
+//! instruction, as [`super::mmio_stub`] does, so the pages stay armed for
+//! the next access, from any VP, without a window where they are open.
+//! Instructions the `mov` decoder does not understand cannot be stepped
+//! that way; their page is given back to VTL0 so that it makes progress,
+//! and counted in [`WatchStats::disarmed`].
+//!
+//! As with [`super::mmio_stub`], VTL1 must return to VTL0 after each
+//! intercept, and [`handle_intercept`] runs in interrupt context: the
+//! `on_access` hook must neither allocate nor log through the allocator.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use hvdef::HV_PAGE_SIZE;
+use hvdef::HvMessageType;
+use hvdef::HvX64MemoryInterceptMessage;
+use hvdef::Vtl;
+use memory_range::MemoryRange;
+use serde::Serialize;
+use spin::Mutex;
+
+use super::ctx::HvTestCtx;
+use super::irq_hvcall::with_irq_hvcall;
+use super::mmio_stub::emulate_mov;
+use super::synic;
+use crate::context::VtlPlatformTrait;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// Accesses kept per watch; later ones are only counted.
+const MAX_HITS: usize = 1024;
+
+/// A VTL0 access to a watched range.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct WatchHit {
+    /// The VP that made the access.
+    pub vp_index: u32,
+    /// Guest physical address of the access.
+    pub gpa: u64,
+    /// Size of the access in bytes.
+    pub size: u8,
+    /// Whether the access was a store.
+    pub write: bool,
+    /// The VTL0 instruction pointer of the access.
+    pub rip: u64,
+}
+
+/// Called from the intercept handler on each access to the range.
+pub type OnAccess = fn(&WatchHit);
+
+/// Counters of the current watch.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct WatchStats {
+    /// Accesses to the range, recorded or not.
+    pub hits: u64,
+    /// Accesses to the range not recorded, past [`MAX_HITS`].
+    pub dropped: u64,
+    /// Accesses to the watched pages outside the range, stepped silently.
+    pub passed: u64,
+    /// Pages given back to VTL0 as an access to them could not be stepped.
+    pub disarmed: u64,
+}
+
+struct Watch {
+    range: Range<u64>,
+    pages: Range<u64>,
+    on_access: OnAccess,
+    hits: Vec<WatchHit>,
+}
+
+static WATCH: Mutex<Option<Watch>> = Mutex::new(None);
+static HITS: AtomicU64 = AtomicU64::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static PASSED: AtomicU64 = AtomicU64::new(0);
+static DISARMED: AtomicU64 = AtomicU64::new(0);
+
+fn page_range(range: &Range<u64>) -> Range<u64> {
+    range.start & !(HV_PAGE_SIZE - 1)..range.end.next_multiple_of(HV_PAGE_SIZE)
+}
+
+/// Watches VTL0 accesses to `range`, calling `on_access` on each. Must run
+/// in VTL1 once VTL protections are enabled, with [`handle_intercept`] on
+/// the secure intercept vector. Fails with [`TmkError::ObjectInUse`] if a
+/// range is watched already.
+pub fn watch<T: VtlPlatformTrait>(
+    ctx: &mut T,
+    range: Range<u64>,
+    on_access: OnAccess,
+) -> TmkResult<()> {
+    if range.is_empty() {
+        return Err(TmkError::InvalidParameter);
+    }
+    let pages = page_range(&range);
+    {
+        let mut watch = WATCH.lock();
+        if watch.is_some() {
+            return Err(TmkError::ObjectInUse);
+        }
+        *watch = Some(Watch {
+            range,
+            pages: pages.clone(),
+            on_access,
+            hits: Vec::with_capacity(MAX_HITS),
+        });
+    }
+    for counter in [&HITS, &DROPPED, &PASSED, &DISARMED] {
+        counter.store(0, Ordering::Relaxed);
+    }
+    let r = ctx.apply_vtl_protection_for_memory(pages, Vtl::Vtl1);
+    if r.is_err() {
+        *WATCH.lock() = None;
+    }
+    r
+}
+
+/// Stops the watch, giving VTL0 access to the pages back, and returns the
+/// accesses recorded. Must run in VTL1.
+pub fn unwatch<T: VtlPlatformTrait>(ctx: &mut T) -> TmkResult<Vec<WatchHit>> {
+    let watch = WATCH.lock().take().ok_or(TmkError::Inactive)?;
+    ctx.remove_vtl_protection_for_memory(watch.pages, Vtl::Vtl1)?;
+    Ok(watch.hits)
+}
+
+/// Returns the counters of the current or last watch.
+pub fn stats() -> WatchStats {
+    WatchStats {
+        hits: HITS.load(Ordering::Relaxed),
+        dropped: DROPPED.load(Ordering::Relaxed),
+        passed: PASSED.load(Ordering::Relaxed),
+        disarmed: DISARMED.load(Ordering::Relaxed),
+    }
+}
+
+/// Makes the access of `size` bytes at `gpa` for VTL0, storing `stored`
+/// if given, and returns the value read otherwise.
+///
+/// # Safety
+/// `gpa` must be identity mapped memory of the TMK.
+unsafe fn step(gpa: u64, size: u8, stored: Option<u64>) -> u64 {
+    let ptr = gpa as *mut u8;
+    let mut bytes = [0u8; 8];
+    if let Some(value) = stored {
+        bytes = value.to_le_bytes();
+    }
+    // The access may be unaligned, so it is made a byte at a time.
+    for (i, byte) in bytes.iter_mut().enumerate().take(size as usize) {
+        // SAFETY: guaranteed by the caller.
+        unsafe {
+            match stored {
+                Some(_) => ptr.add(i).write_volatile(*byte),
+                None => *byte = ptr.add(i).read_volatile(),
+            }
+        }
+    }
+    if stored.is_some() {
+        0
+    } else {
+        u64::from_le_bytes(bytes)
+    }
+}
+
+/// Gives VTL0 back the page of `gpa`.
+fn disarm(gpa: u64) {
+    let page = gpa & !(HV_PAGE_SIZE - 1);
+    let r = with_irq_hvcall(|hvcall| {
+        hvcall.remove_vtl_protections(MemoryRange::new(page..page + HV_PAGE_SIZE), Vtl::Vtl1)
+    });
+    DISARMED.fetch_add(1, Ordering::Relaxed);
+    crate::log_fmt_nostdalloc!(
+        log::Level::Warn,
+        "watch: access at {:#x} cannot be stepped, page disarmed: {:?}",
+        gpa,
+        r
+    );
+}
+
+/// Secure intercept handler of the watch. Install it on the secure
+/// intercept vector of the VTL1 side.
+pub fn handle_intercept() {
+    let Some(message) = synic::poll_current_message(hvdef::HV_SYNIC_INTERCEPTION_SINT_INDEX) else {
+        return;
+    };
+    if message.header.typ != HvMessageType::HvMessageTypeGpaIntercept {
+        return;
+    }
+    let message = message.as_message::<HvX64MemoryInterceptMessage>();
+    let gpa = message.guest_physical_address;
+    let rip = message.header.rip;
+
+    let mut hit = None;
+    let on_access = {
+        let mut watch = WATCH.lock();
+        let Some(watch) = watch.as_mut().filter(|w| w.pages.contains(&gpa)) else {
+            return;
+        };
+        let range = watch.range.clone();
+        let r = emulate_mov(message, |gpa, access, stored| {
+            if gpa < range.end && gpa + access.size as u64 > range.start {
+                hit = Some(WatchHit {
+                    vp_index: HvTestCtx::get_vp_idx(),
+                    gpa,
+                    size: access.size,
+                    write: stored.is_some(),
+                    rip,
+                });
+            }
+            // SAFETY: the watched pages are memory of the TMK, which is
+            // identity mapped.
+            Ok(unsafe { step(gpa, access.size, stored) })
+        });
+        if r.is_err() {
+            disarm(gpa);
+            return;
+        }
+        let Some(hit) = &hit else {
+            PASSED.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+        HITS.fetch_add(1, Ordering::Relaxed);
+        if watch.hits.len() < watch.hits.capacity() {
+            watch.hits.push(*hit);
+        } else {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+        watch.on_access
+    };
+    if let Some(hit) = &hit {
+        on_access(hit);
+    }
+}
+
+#[derive(Serialize)]
+struct WatchRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    #[serde(flatten)]
+    hit: &'a WatchHit,
+}
+
+/// Writes a `watch_hit` record for each of `hits`.
+pub fn write_hit_records(hits: &[WatchHit]) {
+    for hit in hits {
+        log::info!(
+            "watch: VP{} {} {} bytes at {:#x} from rip {:#x}",
+            hit.vp_index,
+            if hit.write { "wrote" } else { "read" },
+            hit.size,
+            hit.gpa,
+            hit.rip
+        );
+        crate::tmk_logger::write_record(&WatchRecord {
+            record_type: "watch_hit",
+            hit,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_range() {
+        assert_eq!(page_range(&(0x1008..0x1010)), 0x1000..0x2000);
+        assert_eq!(page_range(&(0x1ff8..0x2008)), 0x1000..0x3000);
+        assert_eq!(page_range(&(0x2000..0x3000)), 0x2000..0x3000);
+    }
+
+    #[test]
+    fn test_step() {
+        let mut buffer = [0u8; 16];
+        let gpa = buffer.as_mut_ptr() as u64;
+        // SAFETY: the accesses are within the buffer.
+        unsafe {
+            assert_eq!(step(gpa + 3, 4, Some(0x1122_3344)), 0);
+            assert_eq!(step(gpa + 3, 2, None), 0x3344);
+            assert_eq!(step(gpa + 1, 8, None), 0x1122_3344_0000);
+        }
+        assert_eq!(buffer[3..7], [0x44, 0x33, 0x22, 0x11]);
+    }
+}
@@ -4,3 +4,6 @@
 //! Platform-specific modules for OpenTMK.
 
 pub mod hyperv;
+pub mod nested;
+
+pub use nested::is_nested;
@@ -0,0 +1,86 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Nested virtualization detection.
+//!
+//! Under Hyper-V on Hyper-V, or OpenVMM nested in a Hyper-V partition, the
+//! hypervisor the TMK talks to is itself a guest: its timers jitter with
+//! the scheduling of the level below, and intercepts and VTL protection
+//! changes go through two levels of page tables. Tests check
+//! [`is_nested`] to widen their timing tolerances or shrink their work,
+//! and report each such change with [`note_adapted`] so that results from
+//! nested runs can be told apart.
+
+use serde::Serialize;
+
+/// Factor timing tolerances are widened by under nested virtualization.
+pub const TOLERANCE_FACTOR: u64 = 4;
+
+/// Returns true if the hypervisor reports running nested in a Hyper-V
+/// partition.
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub fn is_nested() -> bool {
+    enlightenments().nested()
+}
+
+/// Returns true if the hypervisor recommends relaxed timing checks, which
+/// it does when nested or when the partition is heavily overcommitted.
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub fn relaxed_timing() -> bool {
+    enlightenments().use_relaxed_timing()
+}
+
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+fn enlightenments() -> hvdef::HvEnlightenmentInformation {
+    // SAFETY: CPUID is always available on x86_64.
+    let leaf = unsafe {
+        core::arch::x86_64::__cpuid(hvdef::HV_CPUID_FUNCTION_MS_HV_ENLIGHTENMENT_INFORMATION)
+    };
+    hvdef::HvEnlightenmentInformation::from_cpuid([leaf.eax, leaf.ebx, leaf.ecx, leaf.edx])
+}
+
+/// Returns true if the hypervisor reports running nested. Nesting is not
+/// reported on aarch64.
+#[cfg(target_arch = "aarch64")] // xtask-fmt allow-target-arch sys-crate
+pub fn is_nested() -> bool {
+    false
+}
+
+/// Returns true if the hypervisor recommends relaxed timing checks. Not
+/// reported on aarch64.
+#[cfg(target_arch = "aarch64")] // xtask-fmt allow-target-arch sys-crate
+pub fn relaxed_timing() -> bool {
+    false
+}
+
+/// Returns the factor to widen timing tolerances by, [`TOLERANCE_FACTOR`]
+/// if nested or asked to relax timing checks, in which case `adaptation`
+/// is noted, and 1 otherwise.
+pub fn tolerance_factor(adaptation: &str) -> u64 {
+    if is_nested() || relaxed_timing() {
+        note_adapted(adaptation);
+        TOLERANCE_FACTOR
+    } else {
+        1
+    }
+}
+
+#[derive(Serialize)]
+struct NestedAdaptedRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    test: Option<&'static str>,
+    adaptation: &'a str,
+}
+
+/// Writes a `nested_adapted` record noting that the running test changed
+/// what it checks or does, as `adaptation` says, because it runs nested.
+pub fn note_adapted(adaptation: &str) {
+    let test = crate::tests::registry::current_test();
+    log::info!("adapted for nested virtualization: {}", adaptation);
+    crate::tmk_logger::write_record(&NestedAdaptedRecord {
+        record_type: "nested_adapted",
+        test,
+        adaptation,
+    });
+}
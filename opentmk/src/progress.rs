@@ -0,0 +1,141 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Progress records of long-running operations.
+//!
+//! Chunked operations, such as changing the VTL protections of a large
+//! range, report how far along they are with records like
+//!
+//! ```text
+//! {"type":"progress","op":"apply_vtl_protections","done":4096,"total":65536}
+//! ```
+//!
+//! so that the harness can tell a slow operation from a hung one and show
+//! progress for multi-GB operations. A [`Progress`] writes a record when the
+//! operation starts, each time another sixteenth of it is done, and when it
+//! completes. Operations shorter than the threshold they are created with
+//! write nothing, so that the many small calls of a test do not flood the
+//! log.
+
+use serde::Serialize;
+
+/// Records written over the course of an operation, besides the first.
+const STEPS: u64 = 16;
+
+#[derive(Serialize)]
+struct ProgressRecord {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    op: &'static str,
+    done: u64,
+    total: u64,
+}
+
+/// Reports the progress of an operation `total` units long, in whatever
+/// unit the operation counts, e.g. pages or bytes.
+pub struct Progress {
+    op: &'static str,
+    total: u64,
+    done: u64,
+    step: u64,
+    next: u64,
+    enabled: bool,
+}
+
+impl Progress {
+    /// Starts reporting on `op`, if it is at least `threshold` units long.
+    pub fn new(op: &'static str, total: u64, threshold: u64) -> Self {
+        let step = total.div_ceil(STEPS).max(1);
+        let progress = Self {
+            op,
+            total,
+            done: 0,
+            step,
+            next: step.min(total),
+            enabled: total > 0 && total >= threshold,
+        };
+        progress.write();
+        progress
+    }
+
+    /// Records that `units` more units are done.
+    pub fn advance(&mut self, units: u64) {
+        self.done = self.done.saturating_add(units).min(self.total);
+        if self.done < self.next {
+            return;
+        }
+        while self.next <= self.done && self.next < self.total {
+            self.next = (self.next + self.step).min(self.total);
+        }
+        if self.next <= self.done {
+            // Done; no record after this one.
+            self.next = u64::MAX;
+        }
+        self.write();
+    }
+
+    /// Units done so far.
+    pub fn done(&self) -> u64 {
+        self.done
+    }
+
+    fn write(&self) {
+        if self.enabled {
+            crate::tmk_logger::write_record(&ProgressRecord {
+                record_type: "progress",
+                op: self.op,
+                done: self.done,
+                total: self.total,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reported(f: impl FnOnce()) -> Vec<(u64, u64)> {
+        let output = crate::tmk_logger::capture_output(f);
+        crate::tmk_logger::json_lines(&output)
+            .iter()
+            .filter(|line| line["type"] == "progress" && line["op"] == "test_op")
+            .map(|line| {
+                (
+                    line["done"].as_u64().unwrap(),
+                    line["total"].as_u64().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_progress_records() {
+        let records = reported(|| {
+            let mut progress = Progress::new("test_op", 100, 50);
+            for _ in 0..10 {
+                progress.advance(10);
+            }
+            progress.advance(10);
+        });
+        let done: Vec<u64> = records.iter().map(|&(done, _)| done).collect();
+        assert_eq!(done, [0, 10, 20, 30, 40, 50, 60, 70, 80, 90, 100]);
+        assert!(records.iter().all(|&(_, total)| total == 100));
+
+        let records = reported(|| {
+            let mut progress = Progress::new("test_op", 1000, 50);
+            progress.advance(1000);
+        });
+        assert_eq!(records, [(0, 1000), (1000, 1000)]);
+    }
+
+    #[test]
+    fn test_progress_below_threshold() {
+        let records = reported(|| {
+            let mut progress = Progress::new("test_op", 10, 50);
+            progress.advance(10);
+            assert_eq!(progress.done(), 10);
+        });
+        assert!(records.is_empty());
+    }
+}
@@ -0,0 +1,114 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Named barriers between the TMK VMs of a scenario.
+//!
+//! Some scenarios boot several TMK VMs side by side, e.g. to check that what
+//! one VM does is not visible to another. Their tests walk through phases in
+//! lockstep by meeting at named barriers: each VM reaching a barrier asks
+//! the harness for the `rendezvous` host action, with the detail
+//!
+//! ```text
+//! <name> <parties>
+//! ```
+//!
+//! and the harness answers every VM once `parties` of them asked for the
+//! barrier `name`, so that no test needs its own host logic. A harness that
+//! gives up on a barrier, e.g. because a VM crashed, fails the action.
+//! Harnesses that coordinate VMs announce
+//! [`crate::handshake::RENDEZVOUS_CAPABILITY`].
+//!
+//! Names are the VM's own: VMs running the same test reach the same names
+//! in the same order, which [`Phases`] helps with.
+
+use alloc::format;
+use alloc::string::String;
+
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+use crate::tmkdefs::TmkError;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+use crate::tmkdefs::TmkResult;
+
+/// Host action a VM reaching a barrier requests.
+pub const RENDEZVOUS_ACTION: &str = "rendezvous";
+/// Longest barrier name.
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Returns whether `name` can name a barrier: printable ASCII without
+/// spaces, at most [`MAX_NAME_LEN`] bytes.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_NAME_LEN && name.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// Returns the detail of the request for barrier `name`.
+pub fn detail(name: &str, parties: u32) -> String {
+    format!("{} {}", name, parties)
+}
+
+/// Waits at barrier `name` until `parties` VMs, this one included, reached
+/// it. Gives up after `max_polls` polls of the serial port without data.
+///
+/// Fails with [`TmkError::InvalidParameter`] if the name is not valid or
+/// fewer than two parties are expected, and with
+/// [`TmkError::OperationFailed`] if the harness gave up on the barrier.
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub fn barrier(name: &str, parties: u32, max_polls: u64) -> TmkResult<()> {
+    if !valid_name(name) || parties < 2 {
+        return Err(TmkError::InvalidParameter);
+    }
+    crate::host_action::perform(RENDEZVOUS_ACTION, &detail(name, parties), max_polls)
+}
+
+/// Successive barriers of a test, named `<scope>.<phase>`.
+pub struct Phases {
+    scope: &'static str,
+    parties: u32,
+    next: u32,
+}
+
+impl Phases {
+    /// Starts the phases of `scope`, shared by `parties` VMs.
+    pub fn new(scope: &'static str, parties: u32) -> Self {
+        Self {
+            scope,
+            parties,
+            next: 0,
+        }
+    }
+
+    /// Returns the name of the next barrier, and moves past it.
+    pub fn next_name(&mut self) -> String {
+        let name = format!("{}.{}", self.scope, self.next);
+        self.next += 1;
+        name
+    }
+
+    /// Waits for the other VMs at the next barrier.
+    #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+    pub fn advance(&mut self, max_polls: u64) -> TmkResult<()> {
+        let name = self.next_name();
+        barrier(&name, self.parties, max_polls)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_name() {
+        assert!(valid_name("hv_isolation.0"));
+        assert!(!valid_name(""));
+        assert!(!valid_name("two words"));
+        assert!(!valid_name("é"));
+        assert!(!valid_name(&"a".repeat(MAX_NAME_LEN + 1)));
+    }
+
+    #[test]
+    fn test_phases() {
+        let mut phases = Phases::new("scope", 2);
+        assert_eq!(phases.next_name(), "scope.0");
+        assert_eq!(phases.next_name(), "scope.1");
+        assert_eq!(detail("scope.1", 2), "scope.1 2");
+    }
+}
@@ -0,0 +1,274 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Data-driven scenarios.
+//!
+//! A scenario is a short text listing steps such as starting VPs, applying
+//! VTL protections and checking the resulting accesses, so new variations
+//! of the multi-VP tests can be tried without rebuilding the image. See
+//! [`parse::Step`] for the steps.
+//!
+//! Steps run on the BSP in the VTL selected by the last `switch_vtl` step.
+//! A step prefixed with `@<vp>:<vtl>` runs on that VP and VTL instead, and
+//! the interpreter waits for it to finish. Buffers are allocated by the
+//! interpreter and may be used from any VP.
+//!
+//! The scenario is provided by the harness either in a UEFI variable, read
+//! while boot services are still available, or over the serial port.
+
+pub mod parse;
+
+use alloc::alloc::alloc_zeroed;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::ops::Range;
+
+use hvdef::Vtl;
+use nostd_spin_channel::Channel;
+use spin::Mutex;
+
+use crate::context::InterruptPlatformTrait;
+use crate::context::SecureInterceptPlatformTrait;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+use parse::Line;
+use parse::Step;
+
+/// Name of the UEFI variable holding a scenario.
+pub const SCENARIO_VARIABLE: &str = "OpenTmkScenario";
+/// Vendor GUID of [`SCENARIO_VARIABLE`].
+pub const SCENARIO_VARIABLE_VENDOR: uefi::Guid =
+    uefi::guid!("0d7c6a7e-93b1-4c55-a0f3-6f1de3b2c4a9");
+/// Line ending a scenario sent over the serial port.
+pub const SERIAL_END_MARKER: &str = "end";
+
+const PAGE_SIZE: usize = 4096;
+
+/// Scenario text read from [`SCENARIO_VARIABLE`] during init.
+static VARIABLE_SCENARIO: Mutex<Option<String>> = Mutex::new(None);
+
+/// Records the scenario found in [`SCENARIO_VARIABLE`].
+pub(crate) fn set_variable_scenario(text: String) {
+    *VARIABLE_SCENARIO.lock() = Some(text);
+}
+
+/// Returns the scenario read from [`SCENARIO_VARIABLE`], if the harness set
+/// one.
+pub fn take_variable_scenario() -> Option<String> {
+    VARIABLE_SCENARIO.lock().take()
+}
+
+/// Reads a scenario from the serial port, up to a line holding
+/// [`SERIAL_END_MARKER`]. Gives up after `max_polls` polls without data.
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub fn read_serial_scenario(max_polls: u64) -> TmkResult<String> {
+    use crate::arch::serial::Serial;
+
+    let serial = Serial::selected();
+    let mut text = String::new();
+    loop {
+        let line = serial.read_line(max_polls)?;
+        if line.trim() == SERIAL_END_MARKER {
+            return Ok(text);
+        }
+        text.push_str(&line);
+        text.push('\n');
+    }
+}
+
+/// A failed step.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StepFailure {
+    /// The line number of the step.
+    pub line: usize,
+    /// The step.
+    pub step: Step,
+    /// Why it failed.
+    pub error: TmkError,
+}
+
+#[derive(Clone)]
+struct Buffer {
+    name: String,
+    range: Range<u64>,
+}
+
+/// Platform capabilities the interpreter needs.
+pub trait ScenarioPlatform:
+    InterruptPlatformTrait
+    + SecureInterceptPlatformTrait
+    + VtlPlatformTrait
+    + VirtualProcessorPlatformTrait<Self>
+    + Sized
+    + 'static
+{
+}
+
+impl<T> ScenarioPlatform for T where
+    T: InterruptPlatformTrait
+        + SecureInterceptPlatformTrait
+        + VtlPlatformTrait
+        + VirtualProcessorPlatformTrait<T>
+        + 'static
+{
+}
+
+/// Run `lines` on `ctx`, stopping at the first failing step.
+pub fn run<T: ScenarioPlatform>(ctx: &mut T, lines: &[Line]) -> Result<(), StepFailure> {
+    let mut buffers: Vec<Buffer> = Vec::new();
+    let mut vtl = Vtl::Vtl0;
+    for line in lines {
+        log::info!("scenario line {}: {:?}", line.number, line.step);
+        let fail = |error| StepFailure {
+            line: line.number,
+            step: line.step.clone(),
+            error,
+        };
+        match &line.step {
+            Step::Alloc { name, pages } => {
+                let range = alloc_buffer(*pages).map_err(fail)?;
+                buffers.retain(|b| b.name != *name);
+                buffers.push(Buffer {
+                    name: name.clone(),
+                    range,
+                });
+            }
+            Step::SwitchVtl(target) => vtl = *target,
+            step => {
+                let current_vp = ctx.get_current_vp().map_err(fail)?;
+                let (vp, target_vtl) = line.target.unwrap_or((current_vp, vtl));
+                let step = step.clone();
+                // The interpreter itself runs on the BSP in VTL0.
+                if vp == current_vp && target_vtl == Vtl::Vtl0 {
+                    execute(ctx, &step, &buffers).map_err(fail)?;
+                } else {
+                    dispatch(ctx, vp, target_vtl, step, buffers.clone()).map_err(fail)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Run `step` on `vp` in `vtl` and wait for its result.
+fn dispatch<T: ScenarioPlatform>(
+    ctx: &mut T,
+    vp: u32,
+    vtl: Vtl,
+    step: Step,
+    buffers: Vec<Buffer>,
+) -> TmkResult<()> {
+    let (tx, rx) = Channel::<TmkResult<()>>::new().split();
+    let current_vp = ctx.get_current_vp()?;
+    ctx.start_on_vp(VpExecToken::new(vp, vtl).command(move |ctx: &mut T| {
+        let r = execute(ctx, &step, &buffers);
+        let _ = tx.send(r);
+        // The BSP only enters VTL1 for the step and has to be handed back
+        // to the interpreter.
+        if vp == current_vp && vtl == Vtl::Vtl1 {
+            ctx.switch_to_low_vtl();
+        }
+    }))?;
+    rx.recv().map_err(|_| TmkError::OperationFailed)?
+}
+
+fn execute<T: ScenarioPlatform>(ctx: &mut T, step: &Step, buffers: &[Buffer]) -> TmkResult<()> {
+    let buffer = |name: &str| {
+        buffers
+            .iter()
+            .find(|b| b.name == name)
+            .map(|b| b.range.clone())
+            .ok_or(TmkError::NotFound)
+    };
+    match step {
+        Step::VpCount(count) => {
+            if ctx.get_vp_count()? < *count {
+                return Err(TmkError::FeatureUnavailable);
+            }
+        }
+        Step::StartVp(vp) => {
+            let (tx, rx) = Channel::new().split();
+            ctx.start_on_vp(VpExecToken::new(*vp, Vtl::Vtl0).command(move |_: &mut T| {
+                let _ = tx.send(());
+            }))?;
+            rx.recv().map_err(|_| TmkError::StartVpFailed)?;
+        }
+        Step::SetupVtl(vtl) => ctx.setup_partition_vtl(*vtl)?,
+        Step::EnableProtection => ctx.setup_vtl_protection()?,
+        Step::Intercept(vector) => {
+            ctx.setup_interrupt_handler()?;
+            ctx.setup_secure_intercept(*vector)?;
+        }
+        Step::Fill { name, value } => {
+            let range = buffer(name)?;
+            for addr in range {
+                // SAFETY: the buffer was allocated by the interpreter and is
+                // never freed.
+                unsafe { (addr as *mut u8).write_volatile(*value) };
+            }
+        }
+        Step::Protect { name, vtl } => ctx.apply_vtl_protection_for_memory(buffer(name)?, *vtl)?,
+        Step::Expect { name, value, equal } => {
+            let range = buffer(name)?;
+            // SAFETY: the buffer was allocated by the interpreter and is
+            // never freed.
+            let actual = unsafe { (range.start as *const u8).read_volatile() };
+            if (actual == *value) != *equal {
+                log::error!(
+                    "read {:#x}, expected {}{:#x}",
+                    actual,
+                    if *equal { "" } else { "not " },
+                    value
+                );
+                return Err(TmkError::OperationFailed);
+            }
+        }
+        Step::ExpectFault { name, access } => {
+            let range = buffer(name)?;
+            expect_fault(range.start, *access)?;
+        }
+        Step::Alloc { .. } | Step::SwitchVtl(_) => unreachable!("handled by the interpreter"),
+    }
+    Ok(())
+}
+
+#[cfg(all(nightly, target_arch = "x86_64"))] // xtask-fmt allow-target-arch sys-crate
+fn expect_fault(addr: u64, access: parse::Access) -> TmkResult<()> {
+    use crate::arch::fault;
+
+    let faulted = match access {
+        parse::Access::Read => fault::probe_read(addr).is_err(),
+        parse::Access::Write => fault::probe_write(addr, 0).is_err(),
+        parse::Access::Execute => fault::probe_exec(addr).is_err(),
+    };
+    if !faulted {
+        log::error!("{:?} of {:#x} did not fault", access, addr);
+        return Err(TmkError::OperationFailed);
+    }
+    Ok(())
+}
+
+#[cfg(not(all(nightly, target_arch = "x86_64")))] // xtask-fmt allow-target-arch sys-crate
+fn expect_fault(_addr: u64, _access: parse::Access) -> TmkResult<()> {
+    log::error!("fault probes are not available in this build");
+    Err(TmkError::FeatureUnavailable)
+}
+
+fn alloc_buffer(pages: usize) -> TmkResult<Range<u64>> {
+    let layout = Layout::from_size_align(pages * PAGE_SIZE, PAGE_SIZE)
+        .map_err(|_| TmkError::InvalidParameter)?;
+    if layout.size() == 0 {
+        return Err(TmkError::InvalidParameter);
+    }
+    // SAFETY: the layout has a non-zero size. Scenario buffers may be
+    // protected for the rest of the run, so they are never freed.
+    let ptr = unsafe { alloc_zeroed(layout) };
+    if ptr.is_null() {
+        return Err(TmkError::AllocationFailed);
+    }
+    Ok(ptr as u64..ptr as u64 + layout.size() as u64)
+}
@@ -0,0 +1,250 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Parser for the scenario text format.
+
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+use hvdef::Vtl;
+
+/// A memory access checked by [`Step::ExpectFault`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    /// A read of the first byte.
+    Read,
+    /// A write of the first byte.
+    Write,
+    /// An instruction fetch from the first byte.
+    Execute,
+}
+
+/// A single scenario step.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Step {
+    /// `vp_count <n>`: fail unless the partition has at least `n` VPs.
+    VpCount(u32),
+    /// `start_vp <vp>`: bring up a VP, with VTL1 enabled on it.
+    StartVp(u32),
+    /// `setup_vtl <vtl>`: enable a VTL for the partition.
+    SetupVtl(Vtl),
+    /// `enable_protection`: enable VTL memory protections.
+    EnableProtection,
+    /// `intercept <vector>`: install the interrupt handlers and route secure
+    /// intercepts to `vector`.
+    Intercept(u8),
+    /// `alloc <buffer> <pages>`: allocate a zeroed, page aligned buffer.
+    Alloc {
+        /// Name the buffer is referred to by.
+        name: String,
+        /// Size in pages.
+        pages: usize,
+    },
+    /// `fill <buffer> <byte>`: write `value` to every byte of a buffer.
+    Fill {
+        /// The buffer.
+        name: String,
+        /// The value to write.
+        value: u8,
+    },
+    /// `protect <buffer> <vtl>`: make a buffer accessible to `vtl` only.
+    Protect {
+        /// The buffer.
+        name: String,
+        /// The VTL keeping access.
+        vtl: Vtl,
+    },
+    /// `switch_vtl <vtl>`: run the following steps in `vtl`.
+    SwitchVtl(Vtl),
+    /// `expect <buffer> <byte>` or `expect_not <buffer> <byte>`: check the
+    /// first byte of a buffer.
+    Expect {
+        /// The buffer.
+        name: String,
+        /// The value compared against.
+        value: u8,
+        /// Whether the byte must equal `value` or differ from it.
+        equal: bool,
+    },
+    /// `expect_fault <read|write|exec> <buffer>`: check that accessing a
+    /// buffer raises an exception. Needs the handlers installed by
+    /// `intercept` and a nightly build.
+    ExpectFault {
+        /// The buffer.
+        name: String,
+        /// The access attempted.
+        access: Access,
+    },
+}
+
+/// A step and where it runs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Line {
+    /// The line number in the source, starting at 1.
+    pub number: usize,
+    /// The VP and VTL given with an `@<vp>:<vtl>` prefix, if any.
+    pub target: Option<(u32, Vtl)>,
+    /// The step.
+    pub step: Step,
+}
+
+/// A parse failure.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    /// The line number in the source, starting at 1.
+    pub line: usize,
+    /// What is wrong with the line.
+    pub message: &'static str,
+}
+
+/// Parse a scenario. Steps are separated by newlines or `;` and `#` starts
+/// a comment.
+pub fn parse(text: &str) -> Result<Vec<Line>, ParseError> {
+    let mut lines = Vec::new();
+    for (index, source_line) in text.lines().enumerate() {
+        let number = index + 1;
+        let source_line = source_line.split('#').next().unwrap_or("");
+        for statement in source_line.split(';') {
+            let mut words = statement.split_whitespace().peekable();
+            if words.peek().is_none() {
+                continue;
+            }
+            let error = |message| ParseError {
+                line: number,
+                message,
+            };
+            let target = match words.peek() {
+                Some(word) if word.starts_with('@') => {
+                    let target = parse_target(&word[1..]).ok_or(error("invalid target"))?;
+                    words.next();
+                    Some(target)
+                }
+                _ => None,
+            };
+            let step = parse_step(&mut words).map_err(error)?;
+            if words.next().is_some() {
+                return Err(error("trailing arguments"));
+            }
+            lines.push(Line {
+                number,
+                target,
+                step,
+            });
+        }
+    }
+    Ok(lines)
+}
+
+fn parse_step<'a>(words: &mut impl Iterator<Item = &'a str>) -> Result<Step, &'static str> {
+    let mut arg = |what| words.next().ok_or(what);
+    let step = match arg("missing step")? {
+        "vp_count" => Step::VpCount(number(arg("missing vp count")?)?),
+        "start_vp" => Step::StartVp(number(arg("missing vp")?)?),
+        "setup_vtl" => Step::SetupVtl(vtl(arg("missing vtl")?)?),
+        "enable_protection" => Step::EnableProtection,
+        "intercept" => Step::Intercept(number(arg("missing vector")?)?),
+        "alloc" => Step::Alloc {
+            name: arg("missing buffer")?.to_string(),
+            pages: number(arg("missing page count")?)?,
+        },
+        "fill" => Step::Fill {
+            name: arg("missing buffer")?.to_string(),
+            value: number(arg("missing value")?)?,
+        },
+        "protect" => Step::Protect {
+            name: arg("missing buffer")?.to_string(),
+            vtl: vtl(arg("missing vtl")?)?,
+        },
+        "switch_vtl" => Step::SwitchVtl(vtl(arg("missing vtl")?)?),
+        step @ ("expect" | "expect_not") => Step::Expect {
+            name: arg("missing buffer")?.to_string(),
+            value: number(arg("missing value")?)?,
+            equal: step == "expect",
+        },
+        "expect_fault" => {
+            let access = match arg("missing access")? {
+                "read" => Access::Read,
+                "write" => Access::Write,
+                "exec" => Access::Execute,
+                _ => return Err("invalid access"),
+            };
+            Step::ExpectFault {
+                name: arg("missing buffer")?.to_string(),
+                access,
+            }
+        }
+        _ => return Err("unknown step"),
+    };
+    Ok(step)
+}
+
+fn number<N: TryFrom<u64>>(word: &str) -> Result<N, &'static str> {
+    let value = match word.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => word.parse(),
+    };
+    value
+        .ok()
+        .and_then(|v| N::try_from(v).ok())
+        .ok_or("invalid number")
+}
+
+fn vtl(word: &str) -> Result<Vtl, &'static str> {
+    match word.strip_prefix("vtl").unwrap_or(word) {
+        "0" => Ok(Vtl::Vtl0),
+        "1" => Ok(Vtl::Vtl1),
+        _ => Err("invalid vtl"),
+    }
+}
+
+fn parse_target(word: &str) -> Option<(u32, Vtl)> {
+    let (vp, target_vtl) = word.split_once(':')?;
+    Some((number(vp).ok()?, vtl(target_vtl).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_steps_and_targets() {
+        let lines = parse(
+            "setup_vtl 1 # comment\n\
+             alloc buf 0x10; @2:vtl1 fill buf 0xa2\n\
+             \n\
+             expect_fault write buf",
+        )
+        .unwrap();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0].step, Step::SetupVtl(Vtl::Vtl1));
+        assert_eq!(
+            lines[1].step,
+            Step::Alloc {
+                name: "buf".to_string(),
+                pages: 16
+            }
+        );
+        assert_eq!(lines[2].number, 2);
+        assert_eq!(lines[2].target, Some((2, Vtl::Vtl1)));
+        assert_eq!(lines[3].number, 4);
+        assert_eq!(
+            lines[3].step,
+            Step::ExpectFault {
+                name: "buf".to_string(),
+                access: Access::Write
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |text| parse(text).unwrap_err();
+        assert_eq!(error("start_vp 1\nfly away").line, 2);
+        assert_eq!(error("fly away").message, "unknown step");
+        assert_eq!(error("fill buf 256").message, "invalid number");
+        assert_eq!(error("setup_vtl 2").message, "invalid vtl");
+        assert_eq!(error("@1 start_vp 1").message, "invalid target");
+        assert_eq!(error("enable_protection now").message, "trailing arguments");
+    }
+}
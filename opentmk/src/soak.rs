@@ -0,0 +1,331 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Soak runs.
+//!
+//! The harness may ask for the tests to run repeatedly, to shake out
+//! intermittent failures, by setting [`SOAK_VARIABLE`] or by sending
+//! [`SOAK_COMMAND`] followed by the configuration before answering the
+//! handshake. The configuration is a `;` separated list of settings:
+//!
+//! ```text
+//! iterations=<count>
+//! duration=<seconds>
+//! tests=<test>,<test>...
+//! ```
+//!
+//! Either a number of iterations or a duration must be given; the run stops
+//! at whichever is reached first if both are. `tests` restricts the run to
+//! the listed tests; tests whose prerequisites are provided by tests left
+//! out are reported as skipped.
+//!
+//! Each iteration is reported in a `soak_iteration` record. The
+//! `soak_summary` record aggregates the outcomes of every test and flags as
+//! flaky the tests whose outcome changed between iterations. As a failed
+//! assertion ends the run, the failing iteration is the last one; the
+//! summary is still written, from [`abort`].
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::Ordering;
+
+use hvdef::HvAllArchRegisterName;
+use serde::Serialize;
+use spin::Mutex;
+
+use crate::context::VtlPlatformTrait;
+use crate::tests::registry::Registry;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+use crate::tmkdefs::TmkStatus;
+
+/// Name of the UEFI variable holding the soak configuration.
+pub const SOAK_VARIABLE: &str = "OpenTmkSoak";
+/// Vendor GUID of [`SOAK_VARIABLE`], shared with the scenario variable.
+pub const SOAK_VARIABLE_VENDOR: uefi::Guid = crate::scenario::SCENARIO_VARIABLE_VENDOR;
+/// Command the harness sends the soak configuration with over the serial
+/// port.
+pub const SOAK_COMMAND: &str = "soak";
+
+/// Partition reference time units per second.
+const REFERENCE_HZ: u64 = 10_000_000;
+
+/// How many times the tests run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SoakConfig {
+    /// Iterations to run, if limited.
+    pub iterations: Option<u32>,
+    /// Seconds to run for, if limited.
+    pub duration: Option<u64>,
+    /// Tests to run, `None` for all.
+    pub tests: Option<Vec<String>>,
+}
+
+/// Parses a soak configuration.
+pub fn parse_config(text: &str) -> TmkResult<SoakConfig> {
+    let mut config = SoakConfig {
+        iterations: None,
+        duration: None,
+        tests: None,
+    };
+    for setting in text.split(';').map(str::trim).filter(|s| !s.is_empty()) {
+        let (key, value) = setting.split_once('=').ok_or(TmkError::InvalidParameter)?;
+        let value = value.trim();
+        match key.trim() {
+            "iterations" => {
+                config.iterations = Some(value.parse().map_err(|_| TmkError::InvalidParameter)?)
+            }
+            "duration" => {
+                config.duration = Some(value.parse().map_err(|_| TmkError::InvalidParameter)?)
+            }
+            "tests" => {
+                config.tests = Some(
+                    value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|s| !s.is_empty())
+                        .map(String::from)
+                        .collect(),
+                )
+            }
+            _ => return Err(TmkError::InvalidParameter),
+        }
+    }
+    if config.iterations.is_none() && config.duration.is_none() {
+        return Err(TmkError::InvalidParameter);
+    }
+    Ok(config)
+}
+
+static CONFIG: Mutex<Option<SoakConfig>> = Mutex::new(None);
+/// Whether a soak run is in progress, for [`abort`].
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static ITERATIONS: AtomicU32 = AtomicU32::new(0);
+static OUTCOMES: Mutex<BTreeMap<&'static str, TestOutcomes>> = Mutex::new(BTreeMap::new());
+
+/// Records the soak configuration the harness asked for.
+pub(crate) fn set_config(config: SoakConfig) {
+    log::info!("soak run configured: {:?}", config);
+    *CONFIG.lock() = Some(config);
+}
+
+/// Returns the soak configuration, `None` for a single run.
+pub fn config() -> Option<SoakConfig> {
+    CONFIG.lock().clone()
+}
+
+/// The outcomes of a test over the iterations.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct TestOutcomes {
+    /// Iterations the test passed.
+    pub passed: u32,
+    /// Iterations the test failed.
+    pub failed: u32,
+    /// Iterations the test was skipped.
+    pub skipped: u32,
+    /// Iterations the test failed as expected.
+    pub expected_failures: u32,
+    /// Iterations the test was blocked.
+    pub blocked: u32,
+}
+
+impl TestOutcomes {
+    fn record(&mut self, status: TmkStatus) {
+        match status {
+            TmkStatus::Passed => self.passed += 1,
+            TmkStatus::Failed => self.failed += 1,
+            TmkStatus::Skipped => self.skipped += 1,
+            TmkStatus::ExpectedFailure => self.expected_failures += 1,
+            TmkStatus::Blocked => self.blocked += 1,
+        }
+    }
+
+    /// Whether the outcome of the test changed between iterations.
+    pub fn flaky(&self) -> bool {
+        [
+            self.passed,
+            self.failed,
+            self.skipped,
+            self.expected_failures,
+            self.blocked,
+        ]
+        .iter()
+        .filter(|&&n| n != 0)
+        .count()
+            > 1
+    }
+}
+
+#[derive(Serialize)]
+struct OutcomeEntry {
+    test: &'static str,
+    status: TmkStatus,
+}
+
+#[derive(Serialize)]
+struct SoakIterationRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    iteration: u32,
+    outcomes: &'a [OutcomeEntry],
+}
+
+#[derive(Serialize)]
+struct SoakTestEntry {
+    test: &'static str,
+    #[serde(flatten)]
+    outcomes: TestOutcomes,
+    flaky: bool,
+}
+
+#[derive(Serialize)]
+struct SoakSummaryRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    iterations: u32,
+    completed: bool,
+    tests: &'a [SoakTestEntry],
+    flaky: Vec<&'static str>,
+}
+
+fn write_summary(completed: bool) {
+    let tests: Vec<_> = OUTCOMES
+        .lock()
+        .iter()
+        .map(|(&test, &outcomes)| SoakTestEntry {
+            test,
+            outcomes,
+            flaky: outcomes.flaky(),
+        })
+        .collect();
+    let flaky: Vec<_> = tests.iter().filter(|t| t.flaky).map(|t| t.test).collect();
+    let iterations = ITERATIONS.load(Ordering::Relaxed);
+    if flaky.is_empty() {
+        log::info!("soak run: {} iterations, no flaky test", iterations);
+    } else {
+        log::warn!("soak run: {} iterations, flaky: {:?}", iterations, flaky);
+    }
+    crate::tmk_logger::write_record(&SoakSummaryRecord {
+        record_type: "soak_summary",
+        iterations,
+        completed,
+        tests: &tests,
+        flaky,
+    });
+}
+
+/// Records the failure of `test`, which ended the soak run, and writes the
+/// summary. Does nothing outside a soak run.
+pub fn abort(test: Option<&'static str>) {
+    if !RUNNING.swap(false, Ordering::AcqRel) {
+        return;
+    }
+    ITERATIONS.fetch_add(1, Ordering::Relaxed);
+    if let Some(test) = test {
+        OUTCOMES
+            .lock()
+            .entry(test)
+            .or_default()
+            .record(TmkStatus::Failed);
+    }
+    write_summary(false);
+}
+
+fn reference_time<T: VtlPlatformTrait>(ctx: &mut T) -> TmkResult<u64> {
+    let vtl = ctx.get_current_vtl()?;
+    ctx.get_vp_register_on_vp(0, HvAllArchRegisterName::TimeRefCount.0, vtl)
+}
+
+/// Runs the tests of `registry` over and over as `config` says, reporting
+/// every iteration and the summary.
+pub fn run<T: VtlPlatformTrait>(registry: &mut Registry<T>, ctx: &mut T, config: &SoakConfig) {
+    if let Some(tests) = &config.tests {
+        registry.retain(|name| tests.iter().any(|t| t == name));
+        for test in tests {
+            if !registry.contains(test) {
+                log::warn!("soak test {} is not in the image", test);
+            }
+        }
+    }
+
+    let deadline = match config.duration {
+        Some(seconds) => match reference_time(ctx) {
+            Ok(now) => Some(now.saturating_add(seconds.saturating_mul(REFERENCE_HZ))),
+            Err(e) => {
+                log::error!("no reference time, ignoring the soak duration: {:?}", e);
+                None
+            }
+        },
+        None => None,
+    };
+    let expired = |ctx: &mut T| match deadline {
+        Some(deadline) => reference_time(ctx).is_ok_and(|now| now >= deadline),
+        None => false,
+    };
+    // Without a usable duration, run once rather than forever.
+    let max_iterations = config.iterations.or(deadline.is_none().then_some(1));
+
+    ITERATIONS.store(0, Ordering::Relaxed);
+    OUTCOMES.lock().clear();
+    RUNNING.store(true, Ordering::Release);
+    loop {
+        let iteration = ITERATIONS.load(Ordering::Relaxed);
+        if max_iterations.is_some_and(|n| iteration >= n) || expired(ctx) {
+            break;
+        }
+        log::info!("soak iteration {}", iteration);
+        let outcomes: Vec<_> = registry
+            .run(ctx)
+            .into_iter()
+            .map(|(test, status)| OutcomeEntry { test, status })
+            .collect();
+        {
+            let mut all = OUTCOMES.lock();
+            for outcome in &outcomes {
+                all.entry(outcome.test).or_default().record(outcome.status);
+            }
+        }
+        crate::tmk_logger::write_record(&SoakIterationRecord {
+            record_type: "soak_iteration",
+            iteration,
+            outcomes: &outcomes,
+        });
+        ITERATIONS.store(iteration + 1, Ordering::Relaxed);
+    }
+    RUNNING.store(false, Ordering::Release);
+    write_summary(true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = parse_config("iterations=10; tests=hv_processor, hv_vp_runtime").unwrap();
+        assert_eq!(config.iterations, Some(10));
+        assert_eq!(config.duration, None);
+        assert_eq!(
+            config.tests,
+            Some(vec!["hv_processor".into(), "hv_vp_runtime".into()])
+        );
+        let config = parse_config("duration=600").unwrap();
+        assert_eq!((config.iterations, config.duration), (None, Some(600)));
+        assert!(parse_config("tests=hv_processor").is_err());
+        assert!(parse_config("iterations=x").is_err());
+        assert!(parse_config("repeat=3").is_err());
+    }
+
+    #[test]
+    fn test_flaky() {
+        let mut outcomes = TestOutcomes::default();
+        outcomes.record(TmkStatus::Passed);
+        outcomes.record(TmkStatus::Passed);
+        assert!(!outcomes.flaky());
+        outcomes.record(TmkStatus::Failed);
+        assert!(outcomes.flaky());
+    }
+}
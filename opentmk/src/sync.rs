@@ -0,0 +1,189 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Synchronization helpers for cross-VP tests.
+
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::ops::Deref;
+use core::ops::DerefMut;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use crate::platform::hyperv::long_spin;
+use crate::tmkdefs::SyncError;
+
+/// A spin lock whose slow path tells the hypervisor when it has spun for
+/// long, as an enlightened guest's does, see [`long_spin`].
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: the lock gives one VP at a time access to the value.
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+// SAFETY: the value moves with the lock.
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    /// Creates an unlocked lock holding `value`.
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Takes the lock, spinning until it is free.
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        if self.try_take() {
+            return SpinLockGuard { lock: self };
+        }
+        let mut spins = 0u32;
+        loop {
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+                spins = spins.saturating_add(1);
+                if long_spin::spun(spins) {
+                    spins = 0;
+                }
+            }
+            if self.try_take() {
+                return SpinLockGuard { lock: self };
+            }
+        }
+    }
+
+    /// Takes the lock if it is free.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        self.try_take().then_some(SpinLockGuard { lock: self })
+    }
+
+    fn try_take(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+}
+
+/// Access to the value of a taken [`SpinLock`], which is released on drop.
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the lock is held.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: the lock is held, and the guard borrowed mutably.
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+/// How [`ShardedCounter::increment`] updates the shared count.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Separate load and store. Concurrent increments are expected to be
+    /// lost.
+    None,
+    /// Separate load and store under a spin lock.
+    Lock,
+    /// Atomic fetch-and-add.
+    Atomic,
+}
+
+/// A counter in its own cache line, so per-VP shards do not contend.
+#[repr(align(64))]
+struct Shard(AtomicU64);
+
+/// A counter incremented concurrently by many VPs.
+///
+/// Every increment bumps the caller's own shard, which only that VP writes,
+/// and the shared count with the requested [`SyncMode`]. The sum of the
+/// shards is the exact number of increments, so comparing it with the
+/// shared count shows whether updates were lost.
+pub struct ShardedCounter {
+    shards: Vec<Shard>,
+    shared: AtomicU64,
+    lock: SpinLock<()>,
+}
+
+impl ShardedCounter {
+    /// Create a counter with one shard per VP.
+    pub fn new(shard_count: usize) -> Self {
+        Self {
+            shards: (0..shard_count).map(|_| Shard(AtomicU64::new(0))).collect(),
+            shared: AtomicU64::new(0),
+            lock: SpinLock::new(()),
+        }
+    }
+
+    /// Increment the counter from the VP owning `shard`.
+    pub fn increment(&self, shard: usize, mode: SyncMode) -> Result<(), SyncError> {
+        self.shards
+            .get(shard)
+            .ok_or(SyncError::InvalidShard(shard))?
+            .0
+            .fetch_add(1, Ordering::Relaxed);
+        match mode {
+            SyncMode::None => {
+                let v = self.shared.load(Ordering::Relaxed);
+                self.shared.store(v + 1, Ordering::Relaxed);
+            }
+            SyncMode::Lock => {
+                let _guard = self.lock.lock();
+                // Relaxed is enough, the lock must provide the ordering.
+                let v = self.shared.load(Ordering::Relaxed);
+                self.shared.store(v + 1, Ordering::Relaxed);
+            }
+            SyncMode::Atomic => {
+                self.shared.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    /// The exact number of increments, summed over the shards.
+    pub fn expected(&self) -> u64 {
+        self.shards
+            .iter()
+            .map(|s| s.0.load(Ordering::Acquire))
+            .sum()
+    }
+
+    /// The shared count.
+    pub fn value(&self) -> u64 {
+        // Taking the lock orders this read after any lock-mode increment.
+        let _guard = self.lock.lock();
+        self.shared.load(Ordering::Acquire)
+    }
+
+    /// Number of increments lost by the shared count.
+    pub fn lost(&self) -> u64 {
+        self.expected().saturating_sub(self.value())
+    }
+
+    /// Reset the shards and the shared count. Must not race with
+    /// [`ShardedCounter::increment`].
+    pub fn reset(&self) {
+        let _guard = self.lock.lock();
+        for shard in &self.shards {
+            shard.0.store(0, Ordering::Release);
+        }
+        self.shared.store(0, Ordering::Release);
+    }
+}
@@ -0,0 +1,72 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use core::alloc::Layout;
+
+use crate::tmk_assert;
+use crate::tmkdefs::TmkError;
+use crate::uefi::alloc::ALLOCATOR;
+
+const LIMIT_4GB: u64 = 1 << 32;
+
+/// Allocates buffers below 4GB for 32-bit DMA, checks their placement and
+/// alignment and that a limit the low pool cannot satisfy fails cleanly.
+pub fn exec() {
+    let pool = ALLOCATOR.low_pool();
+    log::info!("low pool at {:#x}..{:#x}", pool.start, pool.end);
+    tmk_assert!(
+        !pool.is_empty() && pool.end <= LIMIT_4GB,
+        "the low pool should be reserved below 4GB"
+    );
+
+    for (size, align) in [
+        (4096, 4096),
+        (64 * 1024, 4096),
+        (100, 64),
+        (3 * 4096, 2 * 4096),
+    ] {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let ptr = ALLOCATOR.alloc_below(LIMIT_4GB, layout);
+        tmk_assert!(
+            ptr.is_ok(),
+            "allocating below 4GB should succeed",
+            extra = size
+        );
+        let ptr = ptr.unwrap();
+        let gpa = ptr.as_ptr() as u64;
+        tmk_assert!(
+            gpa + size as u64 <= LIMIT_4GB,
+            "the buffer should end below 4GB",
+            extra = gpa
+        );
+        tmk_assert!(
+            gpa % align as u64 == 0,
+            "the buffer should be aligned",
+            extra = gpa
+        );
+
+        // SAFETY: the buffer was just allocated with `layout` and is only
+        // accessed here.
+        let buf = unsafe { core::slice::from_raw_parts_mut(ptr.as_ptr(), size) };
+        buf.iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+        tmk_assert!(
+            buf.iter().enumerate().all(|(i, b)| *b == i as u8),
+            "the buffer should be usable"
+        );
+        // SAFETY: the buffer came from `alloc_below` with `layout`.
+        unsafe { ALLOCATOR.dealloc_below(ptr, layout) };
+    }
+
+    let layout = Layout::from_size_align(2 * 4096, 4096).unwrap();
+    let r = ALLOCATOR.alloc_below(pool.start + 4096, layout);
+    tmk_assert!(
+        matches!(r, Err(TmkError::AllocationFailed)),
+        "a limit below the pool should fail the allocation"
+    );
+
+    let r = ALLOCATOR.alloc_below(LIMIT_4GB, Layout::from_size_align(0, 1).unwrap());
+    tmk_assert!(
+        matches!(r, Err(TmkError::InvalidParameter)),
+        "an empty allocation should be rejected"
+    );
+}
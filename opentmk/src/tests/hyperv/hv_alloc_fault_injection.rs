@@ -0,0 +1,61 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use hvdef::Vtl;
+
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VtlPlatformTrait;
+use crate::platform::hyperv::synic::Synic;
+use crate::tmk_assert;
+use crate::tmkdefs::TmkError;
+use crate::uefi::alloc::ALLOCATOR;
+use crate::uefi::alloc::AllocFailure;
+
+/// Checks that stack and SynIC page allocation failures are reported as
+/// [`TmkError::AllocationFailed`] instead of ending the run.
+///
+/// Channel creation is not covered: `Channel::new` allocates through
+/// `Arc::new`, which has no fallible form in stable Rust.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    let vp_count = ctx.get_vp_count();
+    tmk_assert!(vp_count.is_ok(), "get_vp_count should succeed");
+    let vp_count = vp_count.unwrap();
+
+    // A VP stack does not fit in a single page budget. The VP index is out
+    // of range so that nothing is started should the allocation succeed.
+    let (r, failures) = ALLOCATOR.with_injected_failures(AllocFailure::AfterBytes(4096), || {
+        ctx.enable_vp_vtl_with_default_context(vp_count, Vtl::Vtl1)
+    });
+    tmk_assert!(
+        matches!(r, Err(TmkError::AllocationFailed)),
+        "VP stack allocation failure should be reported"
+    );
+    tmk_assert!(failures == 1, "exactly the stack allocation should fail");
+
+    // Fail the SIEFP after the SIMP was allocated, so that the cleanup of
+    // the partially allocated pages runs too.
+    let (r, failures) =
+        ALLOCATOR.with_injected_failures(AllocFailure::EveryNth(2), || Synic::enable().err());
+    tmk_assert!(
+        r == Some(TmkError::AllocationFailed),
+        "SIEFP allocation failure should be reported"
+    );
+    tmk_assert!(failures == 1, "exactly the SIEFP allocation should fail");
+
+    let (r, failures) =
+        ALLOCATOR.with_injected_failures(AllocFailure::EveryNth(1), || Synic::enable().err());
+    tmk_assert!(
+        r == Some(TmkError::AllocationFailed),
+        "SIMP allocation failure should be reported"
+    );
+    tmk_assert!(failures == 2, "both SynIC page allocations should fail");
+
+    let page = vec![0u8; 4096];
+    tmk_assert!(
+        page.len() == 4096,
+        "allocations should succeed once failures are disarmed"
+    );
+}
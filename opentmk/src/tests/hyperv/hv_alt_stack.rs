@@ -0,0 +1,41 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::vec::Vec;
+
+use crate::arch::stack::run_on_stack;
+use crate::tmk_assert;
+
+const STACK_SIZE: usize = 64 * 1024;
+
+/// Runs a closure on a heap-allocated stack and checks that its locals live
+/// in that buffer and that execution returns to the original stack.
+pub fn exec() {
+    let mut stack: Vec<u8> = vec![0; STACK_SIZE];
+    let range = stack.as_ptr() as u64..stack.as_ptr() as u64 + STACK_SIZE as u64;
+
+    let outer = 0u64;
+    let outer_addr = &outer as *const u64 as u64;
+    let mut local_addr = 0u64;
+    let mut sum = 0u64;
+    run_on_stack(&mut stack, || {
+        let values = [1u64, 2, 3, 4];
+        local_addr = core::hint::black_box(&values) as *const _ as u64;
+        sum = values.iter().sum();
+    });
+
+    tmk_assert!(sum == 10, "closure should run to completion");
+    tmk_assert!(
+        range.contains(&local_addr),
+        "closure locals should live on the alternate stack"
+    );
+    tmk_assert!(
+        !range.contains(&outer_addr),
+        "caller locals should stay on the original stack"
+    );
+    let after = 0u64;
+    tmk_assert!(
+        !range.contains(&(core::hint::black_box(&after) as *const u64 as u64)),
+        "execution should return to the original stack"
+    );
+}
@@ -0,0 +1,25 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use crate::tmk_assert;
+use crate::uefi::alloc::ALLOCATOR;
+use crate::uefi::memory_map;
+use crate::uefi::results_file;
+
+/// Registered as needing boot services: checks that the registry ran it
+/// before exiting them, and that they still serve file I/O by writing the
+/// results file.
+pub fn exec() {
+    tmk_assert!(
+        !ALLOCATOR.boot_services_exited(),
+        "boot services should still be running"
+    );
+    tmk_assert!(
+        memory_map::regions().is_empty(),
+        "the final memory map should not be captured yet"
+    );
+    tmk_assert!(
+        results_file::flush(),
+        "the results file should be written through boot services"
+    );
+}
@@ -0,0 +1,192 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use hvdef::Vtl;
+use serde::Serialize;
+
+use crate::affinity;
+use crate::arch::cycles;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+use crate::platform::nested;
+use crate::tmk_assert;
+use crate::tmk_skip;
+
+/// Increments made by each VP of a pair.
+const ITERATIONS: u64 = 1_000_000;
+/// A pair whose shared line costs this many times the cheapest pair's is
+/// reported as an outlier.
+const OUTLIER_FACTOR: u64 = 2;
+
+/// Two counters in the same cache line.
+#[repr(C, align(64))]
+struct Adjacent([AtomicU64; 2]);
+
+/// A counter alone in its cache line, and the adjacent one, as the spatial
+/// prefetcher pulls lines in pairs.
+#[repr(C, align(128))]
+struct Padded(AtomicU64);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Layout {
+    Adjacent,
+    Padded,
+}
+
+struct Counters {
+    adjacent: Adjacent,
+    padded: [Padded; 2],
+    /// VPs ready to start, so that both hammer their counter at once.
+    ready: AtomicU32,
+}
+
+impl Counters {
+    fn counter(&self, layout: Layout, side: usize) -> &AtomicU64 {
+        match layout {
+            Layout::Adjacent => &self.adjacent.0[side],
+            Layout::Padded => &self.padded[side].0,
+        }
+    }
+
+    /// Waits for the other VP, then increments the counter of `side` and
+    /// returns the cycles it took.
+    fn hammer(&self, layout: Layout, side: usize) -> u64 {
+        let counter = self.counter(layout, side);
+        self.ready.fetch_add(1, Ordering::AcqRel);
+        while self.ready.load(Ordering::Acquire) < 2 {
+            core::hint::spin_loop();
+        }
+        let start = cycles::read();
+        for _ in 0..ITERATIONS {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        cycles::read() - start
+    }
+}
+
+#[derive(Serialize)]
+struct BounceRecord {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    vps: [u32; 2],
+    layout: Layout,
+    iterations: u64,
+    /// Cycles per increment on each VP of the pair.
+    cycles_per_op: [u64; 2],
+    ns_per_op: Option<[u64; 2]>,
+}
+
+/// Has VP 0 and `vp` increment their counter, laid out as `layout`, at
+/// the same time, reports the cycles per increment on each and returns the
+/// larger.
+fn measure<T>(ctx: &mut T, vp: u32, layout: Layout, tsc_hz: Option<u64>) -> u64
+where
+    T: VirtualProcessorPlatformTrait<T>,
+{
+    let counters = Arc::new(Counters {
+        adjacent: Adjacent([AtomicU64::new(0), AtomicU64::new(0)]),
+        padded: [Padded(AtomicU64::new(0)), Padded(AtomicU64::new(0))],
+        ready: AtomicU32::new(0),
+    });
+    let remote = counters.clone();
+    let (token, result) = VpExecToken::new(vp, Vtl::Vtl0)
+        .command_with_result(move |_ctx: &mut T| remote.hammer(layout, 1));
+    let r = ctx.start_on_vp(token);
+    tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+    let local = counters.hammer(layout, 0);
+    let remote = result.recv();
+    tmk_assert!(
+        remote.is_ok(),
+        "the other VP should complete its increments"
+    );
+    let remote = remote.unwrap();
+
+    let counts = [0, 1].map(|side| counters.counter(layout, side).load(Ordering::Relaxed));
+    tmk_assert!(
+        counts == [ITERATIONS; 2],
+        "no increment should be lost",
+        extra = counts
+    );
+
+    let cycles_per_op = [local / ITERATIONS, remote / ITERATIONS];
+    let ns_per_op = tsc_hz
+        .filter(|&hz| hz != 0)
+        .map(|hz| cycles_per_op.map(|c| (c as u128 * 1_000_000_000 / hz as u128) as u64));
+    log::info!(
+        "VP0/VP{} {:?}: {:?} cycles per increment",
+        vp,
+        layout,
+        cycles_per_op
+    );
+    crate::tmk_logger::write_record(&BounceRecord {
+        record_type: "cache_line_bounce",
+        vps: [0, vp],
+        layout,
+        iterations: ITERATIONS,
+        cycles_per_op,
+        ns_per_op,
+    });
+    cycles_per_op[0].max(cycles_per_op[1])
+}
+
+/// Measures the cost of false sharing between VP 0 and every other selected
+/// VP: both increment counters in the same cache line, then in separate
+/// lines, and the cycles per increment of each are reported as
+/// `cache_line_bounce` records. Pairs far costlier than the others hint
+/// that their VPs sit on different host NUMA nodes or share a core, which
+/// the guest's topology does not show.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    let vp_count = ctx.get_vp_count();
+    tmk_assert!(vp_count.is_ok(), "get_vp_count should succeed");
+    // VP 0 may not be selected, but always takes one side of each pair.
+    let vps: Vec<u32> = affinity::selected_vps(vp_count.unwrap())
+        .into_iter()
+        .filter(|&vp| vp != 0)
+        .collect();
+    if vps.is_empty() {
+        tmk_skip!("at least two VPs are needed");
+    }
+    let tsc_hz = cycles::frequency();
+
+    let mut bounces = Vec::new();
+    for &vp in &vps {
+        let shared = measure(ctx, vp, Layout::Adjacent, tsc_hz);
+        let padded = measure(ctx, vp, Layout::Padded, tsc_hz);
+        log::info!(
+            "VP0/VP{}: false sharing costs {} cycles per increment, {} without",
+            vp,
+            shared,
+            padded
+        );
+        bounces.push((vp, shared));
+    }
+
+    // The host topology behind nested VPs is too far removed for outliers
+    // to point at it.
+    if crate::platform::is_nested() {
+        nested::note_adapted("outlier pairs not reported");
+        return;
+    }
+    let cheapest = bounces.iter().map(|&(_, c)| c).min().unwrap_or(0).max(1);
+    for &(vp, cycles) in &bounces {
+        if cycles > cheapest * OUTLIER_FACTOR {
+            log::warn!(
+                "VP0/VP{}: bouncing a line costs {} cycles, {}x the cheapest pair",
+                vp,
+                cycles,
+                cycles / cheapest
+            );
+        }
+    }
+}
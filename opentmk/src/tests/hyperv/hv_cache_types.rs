@@ -0,0 +1,190 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::alloc::alloc_zeroed;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::ops::Range;
+
+use hvdef::Vtl;
+use nostd_spin_channel::Channel;
+
+use crate::arch::cache;
+use crate::arch::cache::MemoryType;
+use crate::arch::cache::Mtrrs;
+use crate::arch::paging;
+use crate::context::InterruptPlatformTrait;
+use crate::context::SecureInterceptPlatformTrait;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+use crate::platform::hyperv::mmio_stub;
+use crate::platform::hyperv::mmio_stub::MmioRegion;
+use crate::platform::hyperv::vtl_access::Access;
+use crate::tests::hyperv::test_helpers::vtl0_access_allowed;
+use crate::tmk_assert;
+use crate::tmk_setup;
+
+const INTERCEPT_VECTOR: u8 = 0x30;
+const PAGE_SIZE: u64 = 4096;
+
+/// The memory types the test pages are mapped with, one page each.
+const TYPES: [MemoryType; 4] = [
+    MemoryType::WriteBack,
+    MemoryType::WriteThrough,
+    MemoryType::WriteCombining,
+    MemoryType::Uncacheable,
+];
+
+const VTL0_PATTERN: u64 = 0x5654_4c30_0000_0000;
+const VTL1_PATTERN: u64 = 0x5654_4c31_0000_0000;
+
+/// Maps a page with each of the WB, WT, WC and UC memory types and checks,
+/// for each, that data written by one VTL is visible to the other and that
+/// VTL1 protections intercept VTL0 reads and writes the same way.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: InterruptPlatformTrait
+        + SecureInterceptPlatformTrait
+        + VtlPlatformTrait
+        + VirtualProcessorPlatformTrait<T>,
+{
+    let mtrrs = Mtrrs::read();
+    log::info!(
+        "pat {:#x}, mtrrs enabled {} default type {} with {} ranges",
+        cache::read_pat(),
+        mtrrs.enabled,
+        mtrrs.default_type,
+        mtrrs.ranges.len()
+    );
+
+    let r = ctx.setup_interrupt_handler();
+    tmk_assert!(r.is_ok(), "setup_interrupt_handler should succeed");
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
+
+    let size = TYPES.len() as u64 * PAGE_SIZE;
+    let layout = Layout::from_size_align(size as usize, PAGE_SIZE as usize).unwrap();
+    // SAFETY: the layout has a non-zero size. The buffer is never freed,
+    // its pages keep the memory types they were given.
+    let base = unsafe { alloc_zeroed(layout) } as u64;
+    tmk_assert!(base != 0, "the buffer should be allocated");
+    let pages: Vec<u64> = (0..TYPES.len() as u64)
+        .map(|i| base + i * PAGE_SIZE)
+        .collect();
+
+    let last_pat_entry = cache::pat_entry(7);
+    let mut restore = Vec::new();
+    for (&page, &ty) in pages.iter().zip(&TYPES) {
+        let r = cache::set_page_cache_type(page, ty);
+        tmk_assert!(
+            r.is_ok(),
+            "set_page_cache_type should succeed",
+            extra = page
+        );
+        restore.push(r.unwrap());
+        log::info!(
+            "page {:#x} mapped {:?}, mtrr type {:?}",
+            page,
+            ty,
+            mtrrs.type_of(page)
+        );
+        // SAFETY: the page belongs to the buffer allocated above.
+        unsafe { core::ptr::write_volatile(page as *mut u64, VTL0_PATTERN | page) };
+    }
+
+    // VTL1 shares the page tables but has a PAT of its own, so it may access
+    // the pages with other memory types; the data must be coherent anyway.
+    let (tx, rx) = Channel::new().split();
+    let shared = pages.clone();
+    let r = ctx.start_on_vp(VpExecToken::new(0, Vtl::Vtl1).command(move |ctx: &mut T| {
+        let seen: Vec<bool> = shared
+            .iter()
+            .map(|&page| {
+                // SAFETY: the page belongs to the buffer VTL0 allocated.
+                unsafe {
+                    let seen = core::ptr::read_volatile(page as *const u64) == VTL0_PATTERN | page;
+                    core::ptr::write_volatile(page as *mut u64, VTL1_PATTERN | page);
+                    seen
+                }
+            })
+            .collect();
+
+        let r = ctx.setup_secure_intercept(INTERCEPT_VECTOR);
+        tmk_assert!(r.is_ok(), "setup_secure_intercept should succeed");
+        let r = ctx.set_interrupt_idx(INTERCEPT_VECTOR, mmio_stub::handle_intercept);
+        tmk_assert!(r.is_ok(), "set_interrupt_idx should succeed");
+        _ = tx.send(seen);
+        ctx.switch_to_low_vtl();
+    }));
+    tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+    let seen = rx.recv();
+    tmk_assert!(
+        seen.as_ref().is_ok_and(|s| s.iter().all(|&s| s)),
+        "VTL1 should see what VTL0 wrote",
+        extra = seen.ok()
+    );
+    for &page in &pages {
+        // SAFETY: the page belongs to the buffer allocated above.
+        let value = unsafe { core::ptr::read_volatile(page as *const u64) };
+        tmk_assert!(
+            value == VTL1_PATTERN | page,
+            "VTL0 should see what VTL1 wrote",
+            extra = page
+        );
+    }
+
+    let range = Range {
+        start: base,
+        end: base + size,
+    };
+    mmio_stub::install(MmioRegion::new(range.clone()));
+    let protected = range.clone();
+    let r = ctx.start_on_vp(VpExecToken::new(0, Vtl::Vtl1).command(move |ctx: &mut T| {
+        let r = ctx.setup_vtl_protection();
+        tmk_assert!(r.is_ok(), "setup_vtl_protection should succeed");
+        let r = ctx.apply_vtl_protection_for_memory(protected, Vtl::Vtl1);
+        tmk_assert!(r.is_ok(), "apply_vtl_protection_for_memory should succeed");
+        ctx.switch_to_low_vtl();
+    }));
+    tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+
+    for (&page, &ty) in pages.iter().zip(&TYPES) {
+        for access in Access::ALL {
+            let allowed = vtl0_access_allowed(ctx, page, access);
+            log::info!(
+                "{:?} {:?} of protected page allowed: {:?}",
+                ty,
+                access,
+                allowed
+            );
+            tmk_assert!(
+                allowed == Ok(false),
+                "VTL0 access to a protected page should be intercepted",
+                extra = page
+            );
+        }
+    }
+    let (emulated, unhandled) = mmio_stub::stats();
+    mmio_stub::uninstall();
+    tmk_assert!(
+        emulated == 2 * TYPES.len() as u64 && unhandled == 0,
+        "every intercepted access should be served",
+        extra = (emulated, unhandled)
+    );
+
+    let r = ctx.start_on_vp(VpExecToken::new(0, Vtl::Vtl1).command(move |ctx: &mut T| {
+        let r = ctx.remove_vtl_protection_for_memory(range, Vtl::Vtl1);
+        tmk_assert!(r.is_ok(), "remove_vtl_protection_for_memory should succeed");
+        ctx.switch_to_low_vtl();
+    }));
+    tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+
+    for (&page, &index) in pages.iter().zip(&restore) {
+        let r = paging::set_page_pat_index(page, index);
+        tmk_assert!(r.is_ok(), "restoring the page memory type should succeed");
+    }
+    if let Some(ty) = last_pat_entry {
+        let r = cache::set_pat_entry(7, ty);
+        tmk_assert!(r.is_ok(), "restoring the PAT should succeed");
+    }
+}
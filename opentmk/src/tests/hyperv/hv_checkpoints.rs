@@ -0,0 +1,57 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::format;
+use alloc::vec;
+
+use hvdef::Vtl;
+
+use crate::affinity;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+use crate::tmk_assert;
+use crate::tmk_assert::Checkpoint;
+
+/// Passes checkpoints on the BSP and on another selected VP, see
+/// [`affinity::selected_vps`], and checks that they are listed in order
+/// with the VP that reached each, as a failure dump would report them.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    tmk_assert::checkpoint("before the command");
+    let mut expected = vec![Checkpoint {
+        vp_index: 0,
+        label: "before the command",
+    }];
+
+    let vp_count = ctx.get_vp_count();
+    tmk_assert!(vp_count.is_ok(), "get_vp_count should succeed");
+    let ap = affinity::selected_vps(vp_count.unwrap())
+        .into_iter()
+        .find(|&vp| vp != 0);
+    if let Some(ap) = ap {
+        let (token, result) = VpExecToken::new(ap, Vtl::Vtl0)
+            .command_with_result(|_: &mut T| tmk_assert::checkpoint("in the command"));
+        let r = ctx.start_on_vp(token);
+        tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+        tmk_assert!(result.recv().is_ok(), "the command should run");
+        expected.push(Checkpoint {
+            vp_index: ap,
+            label: "in the command",
+        });
+    }
+
+    tmk_assert::checkpoint("after the command");
+    expected.push(Checkpoint {
+        vp_index: 0,
+        label: "after the command",
+    });
+    let checkpoints = tmk_assert::checkpoints();
+    tmk_assert!(
+        checkpoints == expected,
+        "the checkpoints should be listed in order with their VP",
+        extra = format!("{:?}", checkpoints)
+    );
+}
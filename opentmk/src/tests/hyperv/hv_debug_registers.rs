@@ -0,0 +1,193 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use hvdef::HvAllArchRegisterName;
+use hvdef::HvRegisterVsmCapabilities;
+use hvdef::HvX64RegisterName;
+use hvdef::Vtl;
+use serde::Serialize;
+
+use crate::arch::debug;
+use crate::arch::debug::Breakpoint;
+use crate::arch::debug::DebugRegisters;
+use crate::arch::debug::Length;
+use crate::context::InterruptPlatformTrait;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+use crate::tmk_assert;
+use crate::tmk_setup;
+
+/// Addresses VTL0 loads in DR0-DR3, never enabled.
+const VTL0_ADDRESSES: [u64; 4] = [0x1000, 0x2008, 0x3010, 0x4018];
+/// DR7 of VTL0: R/W and LEN fields set, no breakpoint enabled.
+const VTL0_DR7: u64 = debug::DR7_DISABLED | 0x00d9_0000;
+/// DR7 of VTL1, different from VTL0's.
+const VTL1_DR7: u64 = debug::DR7_DISABLED | 0x0500_0000;
+/// DR6 with no debug condition reported.
+const DR6_CLEAR: u64 = 0xffff_0ff0;
+/// DR6 of VTL0: B0 and B2 set, which no #DB reports as the breakpoints
+/// are disabled.
+const VTL0_DR6: u64 = DR6_CLEAR | 0b0101;
+
+static WATCHED: AtomicU64 = AtomicU64::new(0);
+
+#[inline(never)]
+fn breakpoint_target() -> u64 {
+    core::hint::black_box(42)
+}
+
+#[derive(Serialize)]
+struct DebugRegistersRecord {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    dr6_shared: bool,
+    vtl0: DebugRegisters,
+    vtl1: DebugRegisters,
+    /// DR7 of VTL0 as the hypervisor reports it to VTL1.
+    vtl0_dr7_reported: u64,
+}
+
+/// Checks that write and execution breakpoints raise a #DB reporting the
+/// right breakpoint and RIP, then that the debug registers survive VTL
+/// switches as the VSM specification lays out: DR0-DR3 are shared between
+/// VTLs, DR6 too if VsmCapabilities says so, while DR7 is private to each
+/// and kept across switches.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: InterruptPlatformTrait + VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    let r = ctx.setup_interrupt_handler();
+    tmk_assert!(r.is_ok(), "setup_interrupt_handler should succeed");
+
+    let address = WATCHED.as_ptr() as u64;
+    let r = debug::set_breakpoint(0, Breakpoint::write(address, Length::Qword));
+    tmk_assert!(r.is_ok(), "setting a write breakpoint should succeed");
+    WATCHED.store(1, Ordering::Relaxed);
+    let hit = debug::take_hit();
+    tmk_assert!(
+        hit.is_some_and(|hit| hit.slots == 0b1 && hit.rip != 0),
+        "a write to the watched location should hit DR0",
+        extra = hit
+    );
+
+    let target = breakpoint_target as usize as u64;
+    let r = debug::set_breakpoint(1, Breakpoint::execute(target));
+    tmk_assert!(r.is_ok(), "setting an execution breakpoint should succeed");
+    let value = breakpoint_target();
+    let hit = debug::take_hit();
+    tmk_assert!(
+        value == 42 && hit.is_some_and(|hit| hit.slots == 0b10 && hit.rip == target),
+        "calling the target should hit DR1 at its first instruction",
+        extra = hit
+    );
+    for slot in 0..2 {
+        let r = debug::clear_breakpoint(slot);
+        tmk_assert!(r.is_ok(), "clearing a breakpoint should succeed");
+    }
+    WATCHED.store(2, Ordering::Relaxed);
+    tmk_assert!(
+        debug::take_hit().is_none(),
+        "no breakpoint should hit once cleared"
+    );
+
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
+    let caps = ctx.get_vp_register_with_vtl(HvAllArchRegisterName::VsmCapabilities.0, Vtl::Vtl0);
+    tmk_assert!(caps.is_ok(), "reading VsmCapabilities should succeed");
+    let dr6_shared = HvRegisterVsmCapabilities::from(caps.unwrap()).dr6_shared();
+
+    // SAFETY: DR7 enables no breakpoint, the addresses are never used.
+    let r = unsafe {
+        VTL0_ADDRESSES
+            .iter()
+            .enumerate()
+            .try_for_each(|(i, &address)| debug::write_dr(i as u8, address))
+            .and_then(|()| debug::write_dr(7, VTL0_DR7))
+            .and_then(|()| debug::write_dr(6, VTL0_DR6))
+    };
+    tmk_assert!(r.is_ok(), "writing the VTL0 debug registers should succeed");
+    let vtl0 = DebugRegisters::read();
+
+    let (token, result) = VpExecToken::new(0, Vtl::Vtl1).command_with_result(move |ctx: &mut T| {
+        let vtl1 = DebugRegisters::read();
+        let reported = ctx.get_vp_register_with_vtl(HvX64RegisterName::Dr7.0, Vtl::Vtl0);
+        // SAFETY: DR7 enables no breakpoint.
+        let written = unsafe { debug::write_dr(7, VTL1_DR7) };
+        (vtl1, reported, written, debug::read_dr(7))
+    });
+    let r = ctx.start_on_vp(token);
+    tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+    let result = result.recv();
+    tmk_assert!(result.is_ok(), "VTL1 should report its debug registers");
+    let (vtl1, reported, written, readback) = result.unwrap();
+    tmk_assert!(
+        reported.is_ok() && written.is_ok(),
+        "VTL1 should read the VTL0 DR7 and write its own"
+    );
+    let reported = reported.unwrap();
+    let after = DebugRegisters::read();
+
+    let (token, result) =
+        VpExecToken::new(0, Vtl::Vtl1).command_with_result(|_: &mut T| debug::read_dr(7));
+    let r = ctx.start_on_vp(token);
+    tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+    let kept = result.recv();
+    tmk_assert!(kept.is_ok(), "VTL1 should report its DR7 again");
+    let kept = kept.unwrap();
+
+    crate::tmk_logger::write_record(&DebugRegistersRecord {
+        record_type: "debug_registers",
+        dr6_shared,
+        vtl0,
+        vtl1,
+        vtl0_dr7_reported: reported,
+    });
+    tmk_assert!(
+        vtl0.address == VTL0_ADDRESSES && vtl0.dr6 == VTL0_DR6 && vtl0.dr7 == VTL0_DR7,
+        "VTL0 should read back its debug registers",
+        extra = vtl0
+    );
+    tmk_assert!(
+        vtl1.address == VTL0_ADDRESSES,
+        "VTL1 should observe the DR0-DR3 VTL0 loaded",
+        extra = vtl1
+    );
+    tmk_assert!(
+        vtl1.dr7 != VTL0_DR7,
+        "VTL1 should not observe the DR7 VTL0 loaded",
+        extra = vtl1
+    );
+    tmk_assert!(
+        (vtl1.dr6 == VTL0_DR6) == dr6_shared,
+        "VTL1 should observe the DR6 VTL0 loaded if and only if DR6 is shared",
+        extra = vtl1
+    );
+    tmk_assert!(
+        reported == VTL0_DR7,
+        "the hypervisor should report the DR7 VTL0 loaded",
+        extra = reported
+    );
+    tmk_assert!(
+        readback == Ok(VTL1_DR7),
+        "VTL1 should read back the DR7 it wrote",
+        extra = format!("{:?}", readback)
+    );
+    tmk_assert!(
+        kept == Ok(VTL1_DR7),
+        "VTL1 should keep its DR7 across the switch",
+        extra = format!("{:?}", kept)
+    );
+    tmk_assert!(
+        after.address == VTL0_ADDRESSES && after.dr7 == VTL0_DR7,
+        "VTL0 should keep its DR7 across the switch",
+        extra = after
+    );
+
+    // SAFETY: DR7 disables every breakpoint.
+    let r = unsafe { debug::write_dr(7, debug::DR7_DISABLED) };
+    tmk_assert!(r.is_ok(), "clearing DR7 should succeed");
+    debug::write_dr6(DR6_CLEAR);
+}
@@ -0,0 +1,114 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use hvdef::HvX64RegisterName;
+use hvdef::Vtl;
+use x86_64::instructions::tables::sgdt;
+use x86_64::instructions::tables::sidt;
+
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+use crate::platform::hyperv::descriptor_table::DescriptorTable;
+use crate::platform::hyperv::descriptor_table::IDT_ENTRY_SIZE;
+use crate::tmk_assert;
+use crate::tmk_setup;
+use crate::tmkdefs::TmkResult;
+
+/// Returns the IDTR loaded in the current VTL.
+fn loaded_idt() -> DescriptorTable {
+    let idt = sidt();
+    DescriptorTable {
+        base: idt.base.as_u64(),
+        limit: idt.limit,
+    }
+}
+
+/// Has VTL1 write `table` to the VTL0 IDTR of VP 0, and returns the value
+/// it reads back.
+fn set_vtl0_idt<T>(ctx: &mut T, table: DescriptorTable) -> TmkResult<DescriptorTable>
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    let idtr = HvX64RegisterName::Idtr.0;
+    let (token, result) = VpExecToken::new(0, Vtl::Vtl1).command_with_result(
+        move |ctx: &mut T| -> TmkResult<DescriptorTable> {
+            ctx.set_vp_descriptor_table(0, idtr, table, Vtl::Vtl0)?;
+            ctx.get_vp_descriptor_table(0, idtr, Vtl::Vtl0)
+        },
+    );
+    ctx.start_on_vp(token)?;
+    result.recv()?
+}
+
+/// Checks that the GDTR and IDTR the hypervisor reports for VTL0 are those
+/// VTL0 loaded and are valid tables, then has VTL1 shorten the VTL0 IDT by
+/// a vector and checks that both the hypervisor and VTL0 see the new limit
+/// before restoring it.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
+
+    let gdt = sgdt();
+    let gdt = DescriptorTable {
+        base: gdt.base.as_u64(),
+        limit: gdt.limit,
+    };
+    let reported = ctx.get_vp_descriptor_table(0, HvX64RegisterName::Gdtr.0, Vtl::Vtl0);
+    tmk_assert!(
+        reported == Ok(gdt),
+        "the hypervisor should report the GDTR VTL0 loaded",
+        extra = (gdt, reported.ok())
+    );
+    tmk_assert!(
+        gdt.validate_gdt().is_ok(),
+        "the GDTR should be valid",
+        extra = gdt
+    );
+
+    let idt = loaded_idt();
+    let reported = ctx.get_vp_descriptor_table(0, HvX64RegisterName::Idtr.0, Vtl::Vtl0);
+    tmk_assert!(
+        reported == Ok(idt),
+        "the hypervisor should report the IDTR VTL0 loaded",
+        extra = (idt, reported.ok())
+    );
+    tmk_assert!(
+        idt.validate_idt().is_ok(),
+        "the IDTR should be valid",
+        extra = idt
+    );
+    tmk_assert!(
+        idt.entries(IDT_ENTRY_SIZE) > 1,
+        "the IDT should have a vector to spare",
+        extra = idt
+    );
+
+    // The last vector is never raised, so dropping it is harmless.
+    let shortened = DescriptorTable {
+        limit: idt.limit - IDT_ENTRY_SIZE as u16,
+        ..idt
+    };
+    let r = set_vtl0_idt(ctx, shortened);
+    tmk_assert!(
+        r == Ok(shortened),
+        "the hypervisor should report the IDTR limit VTL1 wrote",
+        extra = (shortened, r.ok())
+    );
+    let observed = loaded_idt();
+    tmk_assert!(
+        observed == shortened,
+        "VTL0 should observe the IDTR limit VTL1 wrote",
+        extra = observed
+    );
+
+    let r = set_vtl0_idt(ctx, idt);
+    tmk_assert!(
+        r == Ok(idt),
+        "restoring the IDTR should succeed",
+        extra = r.ok()
+    );
+    tmk_assert!(loaded_idt() == idt, "VTL0 should observe the restored IDTR");
+}
@@ -0,0 +1,84 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use hvdef::Vtl;
+
+use crate::context::VtlPlatformTrait;
+use crate::devices::dynamic_memory::DmClient;
+use crate::devices::dynamic_memory::DmRequest;
+use crate::devices::dynamic_memory::HotAddPolicy;
+use crate::devices::dynamic_memory::protocol::Capabilities;
+use crate::devices::vmbus::VmbusClient;
+use crate::tmk_assert;
+
+/// Number of pages reported as available and committed in status reports.
+const REPORTED_PAGES: u64 = 0x20000;
+
+/// Drives the guest side of the Dynamic Memory protocol: reports hot-add
+/// and balloon capabilities, then acks the first hot-add request and
+/// services balloon/unballoon requests until it arrives.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait,
+{
+    let vtl = ctx.get_current_vtl();
+    tmk_assert!(vtl.is_ok(), "get_current_vtl should succeed");
+    tmk_assert!(vtl.unwrap() == Vtl::Vtl0, "dm test should run in VTL0");
+
+    let vmbus = VmbusClient::connect();
+    tmk_assert!(vmbus.is_ok(), "vmbus connect should succeed");
+
+    let dm = DmClient::open(vmbus.unwrap(), HotAddPolicy::AcceptAll);
+    tmk_assert!(dm.is_ok(), "dm channel open should succeed");
+    let mut dm = dm.unwrap();
+
+    let version = dm.negotiate();
+    tmk_assert!(version.is_ok(), "dm version negotiation should succeed");
+
+    let capabilities = Capabilities::new()
+        .with_balloon(true)
+        .with_hot_add(true)
+        .with_hot_add_alignment(7);
+    let r = dm.report_capabilities(capabilities, 0, u64::MAX);
+    tmk_assert!(r.is_ok(), "dm capabilities should be accepted");
+
+    let r = dm.report_status(REPORTED_PAGES, REPORTED_PAGES);
+    tmk_assert!(r.is_ok(), "dm status report should succeed");
+
+    loop {
+        let request = dm.next_request();
+        tmk_assert!(request.is_ok(), "dm request should be received");
+        match request.unwrap() {
+            DmRequest::HotAdd { trans_id, range } => {
+                let accepted = dm.ack_hot_add(trans_id, range);
+                tmk_assert!(accepted.is_ok(), "hot-add ack should succeed");
+                tmk_assert!(
+                    accepted.unwrap() == range.page_count(),
+                    "all hot-added pages should be accepted"
+                );
+                break;
+            }
+            DmRequest::Balloon {
+                trans_id,
+                num_pages,
+            } => {
+                let r = dm.ack_balloon(trans_id, num_pages);
+                tmk_assert!(r.is_ok(), "balloon ack should succeed");
+            }
+            DmRequest::Unballoon { trans_id, ranges } => {
+                let r = dm.ack_unballoon(trans_id, &ranges);
+                tmk_assert!(r.is_ok(), "unballoon ack should succeed");
+            }
+            DmRequest::Other(message_type) => {
+                log::info!("ignoring dm message {}", message_type);
+            }
+        }
+    }
+
+    tmk_assert!(
+        dm.hot_added().len() == 1,
+        "one hot-added range should be recorded"
+    );
+    let r = dm.close();
+    tmk_assert!(r.is_ok(), "dm channel close should succeed");
+}
@@ -0,0 +1,90 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use hvdef::Vtl;
+use nostd_spin_channel::Channel;
+
+use crate::context::SynicEventPlatformTrait;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+use crate::platform::hyperv::synic;
+use crate::platform::hyperv::synic::Synic;
+use crate::tmk_assert;
+use crate::tmk_setup;
+use crate::tmk_skip;
+use crate::tmkdefs::TmkError;
+
+/// SINT VTL1 receives the doorbell on. Polled, so the vector is never
+/// raised.
+const DOORBELL_SINT: u8 = 5;
+const DOORBELL_VECTOR: u8 = 0x32;
+const DOORBELL_FLAG: u16 = 77;
+
+/// Runs `f` in VTL1 on VP0 and returns its result to VTL0.
+fn in_vtl1<T, R>(ctx: &mut T, f: impl FnOnce(&mut T) -> R + Send + 'static) -> R
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+    R: Send + 'static,
+{
+    let (tx, rx) = Channel::new().split();
+    let r = ctx.start_on_vp(VpExecToken::new(0, Vtl::Vtl1).command(move |ctx: &mut T| {
+        _ = tx.send(f(ctx));
+        ctx.switch_to_low_vtl();
+    }));
+    tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+    let r = rx.recv();
+    tmk_assert!(r.is_ok(), "VTL1 should report back");
+    r.unwrap()
+}
+
+/// Rings a doorbell from VTL0 on a SynIC event flag of VTL1, and checks the
+/// flag is delivered on its own, without a message.
+///
+/// Ports and connections cannot be created from inside the partition, so
+/// the flag is signaled with `HvCallSignalEventDirect` rather than
+/// `HvCallSignalEvent`; both set the flag through the same SynIC path.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T> + SynicEventPlatformTrait,
+{
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
+
+    let r = in_vtl1(ctx, |_| {
+        if !synic::capabilities().polling {
+            return Err(TmkError::FeatureUnavailable);
+        }
+        let synic = Synic::enable()?;
+        synic.configure_sint(DOORBELL_SINT, DOORBELL_VECTOR, true)?;
+        _ = synic.take_event_flag(DOORBELL_SINT, DOORBELL_FLAG);
+        Ok(())
+    });
+    if r == Err(TmkError::FeatureUnavailable) {
+        tmk_skip!("SINT polling mode is not available");
+    }
+    tmk_assert!(r.is_ok(), "VTL1 should set up its SynIC");
+
+    let r = ctx.signal_event_direct(0, Vtl::Vtl1, DOORBELL_SINT, DOORBELL_FLAG);
+    if let Err(TmkError::AccessDenied | TmkError::InvalidHypercallCode) = r {
+        tmk_skip!("HvCallSignalEventDirect is not permitted");
+    }
+    tmk_assert!(r == Ok(true), "the first doorbell should set the flag");
+    let r = ctx.signal_event_direct(0, Vtl::Vtl1, DOORBELL_SINT, DOORBELL_FLAG);
+    tmk_assert!(
+        r == Ok(false),
+        "ringing again before VTL1 consumed the flag should not set it anew"
+    );
+
+    let (flag, message, flag_after) = in_vtl1(ctx, |_| {
+        let synic = Synic::current();
+        tmk_assert!(synic.is_some(), "the VTL1 SynIC should still be enabled");
+        let synic = synic.unwrap();
+        let flag = synic.take_event_flag(DOORBELL_SINT, DOORBELL_FLAG);
+        let message = synic.poll_message(DOORBELL_SINT).is_some();
+        let flag_after = synic.take_event_flag(DOORBELL_SINT, DOORBELL_FLAG);
+        (flag, message, flag_after)
+    });
+    tmk_assert!(flag, "VTL1 should observe the doorbell flag");
+    tmk_assert!(!message, "the doorbell should not deliver a message");
+    tmk_assert!(!flag_after, "consuming the flag should clear it");
+}
@@ -0,0 +1,28 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use crate::context::ExtendedHypercallPlatformTrait;
+use crate::platform::hyperv::extended;
+use crate::tmk_assert;
+use crate::tmk_skip;
+
+/// Reports which extended hypercalls the host implements, and checks that
+/// the capability mask is stable across queries.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: ExtendedHypercallPlatformTrait,
+{
+    let caps = extended::probe(ctx);
+    tmk_assert!(caps.is_ok(), "probing extended hypercalls should succeed");
+    let caps = caps.unwrap();
+    extended::write_record(&caps);
+    if !caps.available {
+        tmk_skip!("extended hypercalls are not available");
+    }
+
+    let again = extended::probe(ctx);
+    tmk_assert!(
+        again == Ok(caps),
+        "the extended capability mask should not change between queries"
+    );
+}
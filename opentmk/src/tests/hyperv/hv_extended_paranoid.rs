@@ -0,0 +1,39 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use crate::context::ExtendedHypercallPlatformTrait;
+use crate::platform::hyperv::arch::hypercall::HvCall;
+use crate::platform::hyperv::extended;
+use crate::tmk_assert;
+use crate::tmk_skip;
+
+/// Runs HvExtCallQueryCapabilities in paranoid mode and checks that it
+/// writes no more than its documented output, skipping on hosts that do not
+/// implement it.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: ExtendedHypercallPlatformTrait,
+{
+    let caps = extended::probe(ctx);
+    tmk_assert!(caps.is_ok(), "probing extended hypercalls should succeed");
+    let caps = caps.unwrap();
+    if !caps.supports(hvdef::HypercallCode::HvExtCallQueryCapabilities.0) {
+        tmk_skip!("HvExtCallQueryCapabilities is not available");
+    }
+
+    HvCall::set_paranoid_mode(true);
+    let violations = HvCall::paranoid_violations();
+    let again = extended::probe(ctx);
+    let new_violations = HvCall::paranoid_violations() - violations;
+    HvCall::set_paranoid_mode(false);
+
+    tmk_assert!(
+        again == Ok(caps),
+        "the capability query should succeed in paranoid mode"
+    );
+    tmk_assert!(
+        new_violations == 0,
+        "HvExtCallQueryCapabilities should not write beyond its documented output",
+        extra = new_violations
+    );
+}
@@ -0,0 +1,67 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use hvdef::Vtl;
+use nostd_spin_channel::Channel;
+
+use crate::affinity;
+use crate::arch::features;
+use crate::arch::features::Feature;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+use crate::tmk_assert;
+use crate::tmk_setup;
+
+/// Enables every supported extended-state and supervisor protection feature
+/// on the current VP and checks CR4/XCR0 reflect it.
+fn check_features(vp: u32) {
+    let supported = features::supported();
+    log::info!("VP{} supported features: {:?}", vp, supported);
+
+    let wanted = [Feature::XSAVE, Feature::AVX, Feature::SMEP]
+        .into_iter()
+        .filter(|f| supported.contains(*f))
+        .fold(Feature::NONE, |acc, f| acc | f);
+    let r = features::enable(wanted);
+    tmk_assert!(r.is_ok(), "enabling supported features should succeed");
+    tmk_assert!(
+        features::enabled().contains(wanted),
+        "enabled features should be reflected in CR4/XCR0"
+    );
+
+    if supported.contains(Feature::SMEP) {
+        let r = features::disable(Feature::SMEP);
+        tmk_assert!(r.is_ok(), "disabling SMEP should succeed");
+        tmk_assert!(
+            !features::enabled().contains(Feature::SMEP),
+            "SMEP should be cleared"
+        );
+    }
+}
+
+/// Runs [`check_features`] on each selected VP. VP0 runs the tests rather
+/// than a command loop in VTL0, so it checks its features inline.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
+
+    let vp_count = ctx.get_vp_count();
+    tmk_assert!(vp_count.is_ok(), "get_vp_count should succeed");
+
+    for i in affinity::selected_vps(vp_count.unwrap()) {
+        if i == 0 {
+            check_features(0);
+            continue;
+        }
+        let (tx, rx) = Channel::new().split();
+        let r = ctx.start_on_vp(VpExecToken::new(i, Vtl::Vtl0).command(move |_ctx: &mut T| {
+            check_features(i);
+            _ = tx.send(());
+        }));
+        tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+        _ = rx.recv();
+    }
+}
@@ -0,0 +1,62 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use core::cell::Cell;
+
+use crate::arch::cycles;
+use crate::fiber::Scheduler;
+use crate::tmk_assert;
+
+const MESSAGES: u64 = 32;
+const TICKS: u64 = 64;
+const TICK_CYCLES: u64 = 10_000;
+const TIME_SLICE: u64 = 50_000;
+
+/// Interleaves a mailbox producer, a mailbox consumer and a tick counter on
+/// the current VP and checks they all complete while taking turns.
+pub fn exec() {
+    let mailbox = Cell::new(None);
+    let received = Cell::new(0);
+    let in_order = Cell::new(true);
+    let ticks = Cell::new(0);
+
+    let mut scheduler = Scheduler::new().with_time_slice(TIME_SLICE);
+    scheduler.spawn("producer", |y| {
+        for i in 0..MESSAGES {
+            y.wait_until(|| mailbox.get().is_none());
+            mailbox.set(Some(i));
+        }
+    });
+    scheduler.spawn("consumer", |y| {
+        for i in 0..MESSAGES {
+            y.wait_until(|| mailbox.get().is_some());
+            in_order.set(in_order.get() && mailbox.take() == Some(i));
+            received.set(received.get() + 1);
+        }
+    });
+    scheduler.spawn("ticker", |y| {
+        let mut next = cycles::read() + TICK_CYCLES;
+        while ticks.get() < TICKS {
+            if cycles::read() >= next {
+                ticks.set(ticks.get() + 1);
+                next += TICK_CYCLES;
+            }
+            y.checkpoint();
+        }
+    });
+    let summary = scheduler.run();
+
+    for fiber in &summary {
+        log::info!("fiber {} was resumed {} times", fiber.name, fiber.resumes);
+    }
+    tmk_assert!(
+        received.get() == MESSAGES,
+        "the consumer should receive every message"
+    );
+    tmk_assert!(in_order.get(), "messages should arrive in order");
+    tmk_assert!(ticks.get() == TICKS, "the ticker should count every tick");
+    tmk_assert!(
+        summary.iter().all(|f| f.resumes > 1),
+        "every fiber should have yielded to the others"
+    );
+}
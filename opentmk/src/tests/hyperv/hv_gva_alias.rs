@@ -0,0 +1,230 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::alloc::alloc_zeroed;
+use core::alloc::Layout;
+use core::ops::Range;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use hvdef::HvMessageType;
+use hvdef::HvX64MemoryInterceptMessage;
+use hvdef::Vtl;
+use nostd_spin_channel::Channel;
+
+use crate::arch::env;
+use crate::arch::fault;
+use crate::arch::fault::PF_PROTECTION;
+use crate::arch::fault::PF_WRITE;
+use crate::arch::paging;
+use crate::context::InterruptPlatformTrait;
+use crate::context::SecureInterceptPlatformTrait;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlAccessPlatformTrait;
+use crate::context::VtlPlatformTrait;
+use crate::platform::hyperv::ctx::resync_command_queue;
+use crate::platform::hyperv::mmio_stub::emulate_mov;
+use crate::platform::hyperv::synic;
+use crate::platform::hyperv::vtl_access::Access;
+use crate::tmk_assert;
+use crate::tmk_setup;
+use crate::tmk_skip;
+
+const PAGE_SIZE: usize = 4096;
+/// Offset in the page of the byte accessed.
+const OFFSET: u64 = 0x123;
+const INTERCEPT_VECTOR: u8 = 0x30;
+
+static INTERCEPTS: AtomicU32 = AtomicU32::new(0);
+static INTERCEPTED_GVA: AtomicU64 = AtomicU64::new(0);
+static INTERCEPTED_GPA: AtomicU64 = AtomicU64::new(0);
+
+/// Records the VTL0 write VTL1 protection intercepted and moves VTL0 past
+/// it, dropping the write.
+fn handle_intercept() {
+    let Some(message) = synic::poll_current_message(hvdef::HV_SYNIC_INTERCEPTION_SINT_INDEX) else {
+        return;
+    };
+    if message.header.typ != HvMessageType::HvMessageTypeGpaIntercept {
+        return;
+    }
+    let message = message.as_message::<HvX64MemoryInterceptMessage>();
+    match emulate_mov(message, |_, _, _| Ok(0)) {
+        Ok(_) => {
+            INTERCEPTED_GVA.store(message.guest_virtual_address, Ordering::Relaxed);
+            INTERCEPTED_GPA.store(message.guest_physical_address, Ordering::Relaxed);
+            INTERCEPTS.fetch_add(1, Ordering::Release);
+        }
+        Err(e) => {
+            crate::log_fmt_nostdalloc!(log::Level::Error, "skipping the write failed: {:?}", e);
+        }
+    }
+}
+
+/// Allocates a page; it is never freed, its mappings outlive the test.
+fn page() -> u64 {
+    let layout = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap();
+    // SAFETY: the layout has a non-zero size.
+    let page = unsafe { alloc_zeroed(layout) };
+    tmk_assert!(!page.is_null(), "the page should be allocated");
+    page as u64
+}
+
+/// Maps a page at a second, read-only virtual address and checks that both
+/// addresses translate to it and see the same data, that writes through the
+/// alias fault while they succeed through the original mapping, and that
+/// making the alias writable lets them through. Then has VTL1 make the page
+/// read-only for VTL0 and checks that the change applies to the physical
+/// page, whichever address VTL0 reaches it through: writes through either
+/// are intercepted by VTL1, which drops them, or fault.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: InterruptPlatformTrait
+        + SecureInterceptPlatformTrait
+        + VtlAccessPlatformTrait
+        + VtlPlatformTrait
+        + VirtualProcessorPlatformTrait<T>,
+{
+    // Supervisor writes only fault on read-only pages with CR0.WP set,
+    // which the TMK environment guarantees.
+    if env::clean_env().is_none() {
+        tmk_skip!("the TMK environment was not adopted");
+    }
+    let r = ctx.setup_interrupt_handler();
+    tmk_assert!(r.is_ok(), "setup_interrupt_handler should succeed");
+
+    let gpa = page();
+    let alias = paging::map_alias(page(), gpa, false);
+    tmk_assert!(alias.is_ok(), "map_alias should succeed");
+    let alias = alias.unwrap();
+    let direct = gpa + OFFSET;
+    let aliased = alias.va() + OFFSET;
+
+    let translated = paging::translate(aliased);
+    tmk_assert!(
+        translated == Ok(direct),
+        "the alias should translate to the page",
+        extra = translated.ok()
+    );
+    tmk_assert!(
+        paging::translate(direct) == Ok(direct),
+        "the original mapping should be kept"
+    );
+
+    let r = fault::probe_write(direct, 0x11);
+    tmk_assert!(
+        r.is_ok(),
+        "writing through the original mapping should succeed"
+    );
+    let r = fault::probe_read(aliased);
+    tmk_assert!(
+        r == Ok(0x11),
+        "the alias should read what was written through the original mapping",
+        extra = r.ok()
+    );
+    let r = fault::probe_write(aliased, 0x22);
+    tmk_assert!(
+        r.is_err_and(|f| f.address == aliased
+            && f.error_code & (PF_WRITE | PF_PROTECTION) == PF_WRITE | PF_PROTECTION),
+        "writing through the read-only alias should raise a protection fault",
+        extra = r.err().map(|f| (f.address, f.error_code))
+    );
+    tmk_assert!(
+        fault::probe_read(direct) == Ok(0x11),
+        "the faulting write should leave the page unchanged"
+    );
+
+    let r = alias.set_writable(true);
+    tmk_assert!(r == Ok(false), "the alias should have been read-only");
+    let r = fault::probe_write(aliased, 0x33);
+    tmk_assert!(
+        r.is_ok(),
+        "writing through the writable alias should succeed"
+    );
+    tmk_assert!(
+        fault::probe_read(direct) == Ok(0x33),
+        "the original mapping should read what was written through the alias"
+    );
+
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
+    let protected = Range {
+        start: gpa,
+        end: gpa + PAGE_SIZE as u64,
+    };
+    let (tx, rx) = Channel::new().split();
+    let page_range = protected.clone();
+    let r = ctx.start_on_vp(VpExecToken::new(0, Vtl::Vtl1).command(move |ctx: &mut T| {
+        let r = ctx.setup_secure_intercept(INTERCEPT_VECTOR);
+        tmk_assert!(r.is_ok(), "setup_secure_intercept should succeed");
+        let r = ctx.set_interrupt_idx(INTERCEPT_VECTOR, handle_intercept);
+        tmk_assert!(r.is_ok(), "set_interrupt_idx should succeed");
+        let r = ctx.setup_vtl_protection();
+        tmk_assert!(r.is_ok(), "setup_vtl_protection should succeed");
+        let r = ctx.set_vtl_protection_mask(page_range, Vtl::Vtl1, Access::Read.flags());
+        tmk_assert!(r.is_ok(), "set_vtl_protection_mask should succeed");
+        let r = ctx.check_vtl_access(&[gpa / PAGE_SIZE as u64], Vtl::Vtl0, Access::Write.flags());
+        _ = tx.send(r.map(|checks| checks.iter().all(|check| !check.allowed)));
+        ctx.switch_to_low_vtl();
+    }));
+    tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+    let denied = rx.recv();
+    tmk_assert!(
+        denied == Ok(Ok(true)),
+        "VTL1 should report writes to the page denied to VTL0"
+    );
+    let reads = (fault::probe_read(direct), fault::probe_read(aliased));
+    tmk_assert!(
+        reads == (Ok(0x33), Ok(0x33)),
+        "both addresses should keep reading the page under the VTL protection",
+        extra = (reads.0.ok(), reads.1.ok())
+    );
+    for (va, value) in [(direct, 0x55), (aliased, 0x66)] {
+        // VTL1 runs this after serving the write, if it is intercepted.
+        let r = ctx.queue_command_vp(VpExecToken::new(0, Vtl::Vtl1).command(|ctx: &mut T| {
+            ctx.switch_to_low_vtl();
+        }));
+        tmk_assert!(r.is_ok(), "queue_command_vp should succeed");
+        let intercepts = INTERCEPTS.load(Ordering::Acquire);
+        let r = fault::probe_write(va, value);
+        // Drop the command if the write was not intercepted.
+        resync_command_queue(0);
+        let intercepted = INTERCEPTS.load(Ordering::Acquire) == intercepts + 1
+            && INTERCEPTED_GVA.load(Ordering::Relaxed) == va
+            && INTERCEPTED_GPA.load(Ordering::Relaxed) == direct;
+        let faulted = r.is_err_and(|f| f.address == va);
+        tmk_assert!(
+            intercepted || faulted,
+            "a write through either address should be intercepted or fault under the VTL protection",
+            extra = (va, r.err().map(|f| (f.address, f.error_code)))
+        );
+        tmk_assert!(
+            fault::probe_read(direct) == Ok(0x33),
+            "the page should not change under the VTL protection",
+            extra = va
+        );
+    }
+
+    let r = ctx.start_on_vp(VpExecToken::new(0, Vtl::Vtl1).command(move |ctx: &mut T| {
+        let r = ctx.remove_vtl_protection_for_memory(protected, Vtl::Vtl1);
+        tmk_assert!(r.is_ok(), "remove_vtl_protection_for_memory should succeed");
+        let r = ctx.clear_interrupt_idx(INTERCEPT_VECTOR);
+        tmk_assert!(r.is_ok(), "clear_interrupt_idx should succeed");
+        ctx.switch_to_low_vtl();
+    }));
+    tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+    let r = fault::probe_write(aliased, 0x44);
+    tmk_assert!(
+        r.is_ok() && fault::probe_read(direct) == Ok(0x44),
+        "writes through the alias should reach the page once unprotected"
+    );
+
+    let va = alias.va();
+    let r = alias.remove();
+    tmk_assert!(r.is_ok(), "removing the alias should succeed");
+    tmk_assert!(
+        paging::translate(va) == Ok(va),
+        "the aliased address should map its own page again"
+    );
+}
@@ -0,0 +1,86 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::vec::Vec;
+
+use hvdef::Vtl;
+use nostd_spin_channel::Channel;
+
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+use crate::tmk_assert;
+use crate::tmk_setup;
+use crate::uefi::alloc::ALLOCATOR;
+use crate::uefi::alloc::QuotaScope;
+
+/// Heap VTL1 may hold, with room for the allocations made to run the
+/// command.
+const QUOTA: usize = 256 * 1024;
+
+/// Reserves `size` bytes without ending the run if the allocation fails.
+fn try_reserve(size: usize) -> Option<Vec<u8>> {
+    let mut buffer = Vec::new();
+    buffer.try_reserve_exact(size).ok().map(|()| buffer)
+}
+
+/// Checks that a VTL1 heap quota fails the allocations of VTL1 beyond it
+/// while leaving VTL0 unaffected, that freeing memory gives the room back,
+/// and that a per-VP quota applies whatever the VTL.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
+    let r = ALLOCATOR.set_quota(QuotaScope::Vtl(1), Some(QUOTA));
+    tmk_assert!(r.is_ok(), "setting the VTL1 quota should succeed");
+
+    let (tx, rx) = Channel::new().split();
+    let r = ctx.start_on_vp(VpExecToken::new(0, Vtl::Vtl1).command(move |ctx: &mut T| {
+        let over = try_reserve(QUOTA + 1).is_none();
+        let held = try_reserve(QUOTA / 4 * 3);
+        let beyond = try_reserve(QUOTA / 2).is_none();
+        let held_ok = held.is_some();
+        drop(held);
+        let freed = try_reserve(QUOTA / 2).is_some();
+        _ = tx.send([over, held_ok, beyond, freed]);
+        ctx.switch_to_low_vtl();
+    }));
+    tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+    let r = rx.recv();
+    tmk_assert!(r.is_ok(), "VTL1 should report back");
+    let [over, held, beyond, freed] = r.unwrap();
+    tmk_assert!(over, "an allocation larger than the quota should fail");
+    tmk_assert!(held, "an allocation within the quota should succeed");
+    tmk_assert!(beyond, "an allocation past the quota should fail");
+    tmk_assert!(freed, "freed memory should count against the quota no more");
+
+    let vtl0 = try_reserve(QUOTA * 4);
+    tmk_assert!(
+        vtl0.is_some(),
+        "VTL0 should not be limited by the VTL1 quota"
+    );
+    drop(vtl0);
+
+    let usage = ALLOCATOR.quota_usage();
+    let vtl1 = usage.iter().find(|u| u.scope == QuotaScope::Vtl(1));
+    tmk_assert!(
+        vtl1.is_some_and(|u| u.failures == 2 && u.peak <= QUOTA),
+        "the VTL1 quota usage should be reported",
+        extra = vtl1
+    );
+
+    let vp = ctx.get_current_vp();
+    tmk_assert!(vp.is_ok(), "get_current_vp should succeed");
+    let r = ALLOCATOR.set_quota(QuotaScope::Vp(vp.unwrap()), Some(QUOTA));
+    tmk_assert!(r.is_ok(), "setting the VP quota should succeed");
+    tmk_assert!(
+        try_reserve(QUOTA * 4).is_none(),
+        "the VP quota should apply to VTL0"
+    );
+    ALLOCATOR.clear_quotas();
+    tmk_assert!(
+        try_reserve(QUOTA * 4).is_some(),
+        "clearing the quotas should lift the limits"
+    );
+}
@@ -0,0 +1,71 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use hvdef::HvRegisterVsmCodePageOffsets;
+use hvdef::HvX64RegisterName;
+
+use crate::arch::fault;
+use crate::arch::paging;
+use crate::context::InterruptPlatformTrait;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VtlPlatformTrait;
+use crate::platform::hyperv::hypercall_page;
+use crate::platform::hyperv::hypercall_page::WriteFault;
+use crate::tmk_assert;
+
+/// Checks the hypercall page holds the entries the TLFS describes, with the
+/// hypercall instruction of the processor vendor, and that VTL0 cannot
+/// write it.
+///
+/// The guest mapping of the page is made writable first, so that a fault
+/// on the write comes from the hypervisor protecting its overlay rather
+/// than from the firmware page tables. The write stores the byte already
+/// there, so the page stays usable should it wrongly succeed.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: InterruptPlatformTrait + VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    let r = ctx.setup_interrupt_handler();
+    tmk_assert!(r.is_ok(), "setup_interrupt_handler should succeed");
+
+    let before = hypercall_page::read();
+    // Without VSM the register is not available and the page has no VTL
+    // entries.
+    let vsm_offsets = ctx
+        .get_register(HvX64RegisterName::VsmCodePageOffsets.0)
+        .ok()
+        .map(|value| HvRegisterVsmCodePageOffsets::from(value as u64));
+    let entries =
+        hypercall_page::verify(&before, hypercall_page::expected_instruction(), vsm_offsets);
+
+    let addr = hypercall_page::address();
+    let r = paging::set_page_writable(addr, true);
+    tmk_assert!(
+        r.is_ok(),
+        "making the hypercall page writable should succeed"
+    );
+    let was_writable = r.unwrap();
+    let write = fault::probe_write(addr, before[0]);
+    let r = paging::set_page_writable(addr, was_writable);
+    tmk_assert!(r.is_ok(), "restoring the page access should succeed");
+
+    let write_fault = write.err().map(|f| WriteFault {
+        vector: f.vector,
+        error_code: f.error_code,
+    });
+    let unchanged = hypercall_page::read() == before;
+    hypercall_page::write_record(&entries, write_fault, unchanged);
+
+    for entry in &entries {
+        tmk_assert!(
+            entry.valid,
+            "the hypercall page entry should match the TLFS",
+            extra = entry
+        );
+    }
+    tmk_assert!(
+        write_fault.is_some(),
+        "writing the hypercall page from VTL0 should fault"
+    );
+    tmk_assert!(unchanged, "the hypercall page should not change");
+}
@@ -0,0 +1,35 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use hvdef::HvAllArchRegisterName;
+use hvdef::Vtl;
+
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VtlPlatformTrait;
+use crate::platform::hyperv::arch::hypercall::HvCall;
+use crate::tmk_assert;
+use crate::tmk_setup;
+
+/// Runs a set of register hypercalls in paranoid mode and checks that none
+/// of them writes beyond its documented output.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    HvCall::set_paranoid_mode(true);
+    let violations = HvCall::paranoid_violations();
+
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
+
+    let status = ctx.get_vp_register_with_vtl(HvAllArchRegisterName::VsmVpStatus.0, Vtl::Vtl0);
+    tmk_assert!(status.is_ok(), "reading VsmVpStatus should succeed");
+
+    let caps = ctx.get_vp_register_with_vtl(HvAllArchRegisterName::VsmCapabilities.0, Vtl::Vtl0);
+    tmk_assert!(caps.is_ok(), "reading VsmCapabilities should succeed");
+
+    tmk_assert!(
+        HvCall::paranoid_violations() == violations,
+        "no hypercall should write beyond its documented output"
+    );
+    HvCall::set_paranoid_mode(false);
+}
@@ -0,0 +1,62 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::format;
+
+use hvdef::HypercallCode;
+
+use crate::context::SynicEventPlatformTrait;
+use crate::platform::hyperv::retry;
+use crate::platform::hyperv::retry::RetryPolicy;
+use crate::tmk_assert;
+use crate::tmkdefs::TmkError;
+
+/// A connection ID no port is bound to.
+const UNKNOWN_CONNECTION: u32 = 0x00ff_fffe;
+/// A message type guests may post.
+const MESSAGE_TYPE: u32 = 1;
+
+/// Sets a retry policy for HvCallPostMessage and checks that a status that
+/// is not transient still reaches the caller unchanged and without a retry,
+/// with retries enabled and disabled, and that clearing the policy removes
+/// it.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: SynicEventPlatformTrait,
+{
+    let code = HypercallCode::HvCallPostMessage;
+    let policy = RetryPolicy::new(3).with_backoff(1_000, 4_000);
+    let r = retry::set_policy(code, Some(policy));
+    tmk_assert!(r.is_ok(), "setting a retry policy should succeed");
+    tmk_assert!(
+        retry::policy(code) == Some(policy),
+        "the retry policy should read back"
+    );
+
+    for enabled in [true, false] {
+        retry::set_enabled(enabled);
+        let before = retry::retries();
+        let r = ctx.post_message(UNKNOWN_CONNECTION, MESSAGE_TYPE, &[0; 16]);
+        tmk_assert!(
+            r == Err(TmkError::InvalidConnectionId),
+            format!(
+                "posting to an unknown connection should fail with InvalidConnectionId with retries enabled: {}",
+                enabled
+            ),
+            extra = format!("{:?}", r)
+        );
+        tmk_assert!(
+            retry::retries() == before,
+            "a failure that is not transient should not be retried",
+            extra = retry::retries() - before
+        );
+    }
+    retry::set_enabled(true);
+
+    let r = retry::set_policy(code, None);
+    tmk_assert!(r.is_ok(), "clearing a retry policy should succeed");
+    tmk_assert!(
+        retry::policy(code).is_none(),
+        "the retry policy should be cleared"
+    );
+}
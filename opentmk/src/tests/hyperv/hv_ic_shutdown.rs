@@ -0,0 +1,66 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use hvdef::Vtl;
+use serde::Serialize;
+
+use crate::context::VtlPlatformTrait;
+use crate::devices::ic::IcClient;
+use crate::devices::ic::IcEvent;
+use crate::devices::ic::IcService;
+use crate::devices::vmbus::VmbusClient;
+use crate::tmk_assert;
+
+/// Polls before giving up on the harness sending a shutdown request.
+const SHUTDOWN_POLL_LIMIT: u64 = 10_000_000_000;
+
+/// Tells the harness the shutdown it requested arrived.
+#[derive(Serialize)]
+struct ShutdownRecord {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    restart: bool,
+    reason_code: u32,
+    heartbeats: u64,
+}
+
+/// Offers the heartbeat, shutdown and KVP integration components, answers
+/// the host until the harness requests a shutdown through the shutdown IC,
+/// then closes the channels and reports the request. The VM keeps running
+/// the remaining tests; the harness checks the record and powers the VM
+/// off itself once the run ends.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait,
+{
+    let vtl = ctx.get_current_vtl();
+    tmk_assert!(vtl.is_ok(), "get_current_vtl should succeed");
+    tmk_assert!(vtl.unwrap() == Vtl::Vtl0, "ic test should run in VTL0");
+
+    let vmbus = VmbusClient::connect();
+    tmk_assert!(vmbus.is_ok(), "vmbus connect should succeed");
+
+    let ic = IcClient::open(
+        vmbus.unwrap(),
+        &[IcService::Heartbeat, IcService::Shutdown, IcService::Kvp],
+    );
+    tmk_assert!(ic.is_ok(), "ic channels should open");
+    let mut ic = ic.unwrap();
+
+    let event = ic.wait_for_shutdown(SHUTDOWN_POLL_LIMIT);
+    tmk_assert!(event.is_ok(), "shutdown should be requested");
+    let IcEvent::Shutdown {
+        restart,
+        reason_code,
+        ..
+    } = event.unwrap();
+    crate::tmk_logger::write_record(&ShutdownRecord {
+        record_type: "ic_shutdown",
+        restart,
+        reason_code,
+        heartbeats: ic.heartbeats(),
+    });
+
+    let r = ic.close();
+    tmk_assert!(r.is_ok(), "ic channels should close");
+}
@@ -0,0 +1,148 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use core::arch::global_asm;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use hvdef::HvX64InterceptMessageHeader;
+use hvdef::Vtl;
+
+use crate::context::InterruptPlatformTrait;
+use crate::context::SecureInterceptPlatformTrait;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+use crate::platform::hyperv::intercept;
+use crate::platform::hyperv::intercept::Resume;
+use crate::platform::hyperv::synic;
+use crate::tmk_assert;
+use crate::tmk_setup;
+
+const INTERCEPT_VECTOR: u8 = 0x30;
+
+/// Returns 1 when VTL0 resumes past its WRMSR, and 2 from the recovery
+/// stub, which the handler may send VTL0 to instead. The WRMSR writes the
+/// APIC base back unchanged, should it ever run.
+global_asm!(
+    ".global opentmk_intercept_resume_probe",
+    "opentmk_intercept_resume_probe:",
+    "mov ecx, 0x1B",
+    "rdmsr",
+    "wrmsr",
+    "mov eax, 1",
+    "ret",
+    ".global opentmk_intercept_resume_recovery",
+    "opentmk_intercept_resume_recovery:",
+    "mov eax, 2",
+    "ret",
+);
+
+unsafe extern "sysv64" {
+    fn opentmk_intercept_resume_probe() -> u32;
+    fn opentmk_intercept_resume_recovery() -> u32;
+}
+
+/// Where the handler sends VTL0, zero for past the intercepted instruction.
+static RESUME_AT: AtomicU64 = AtomicU64::new(0);
+/// The RIP the handler set.
+static RESUMED_RIP: AtomicU64 = AtomicU64::new(0);
+static HANDLED: AtomicU32 = AtomicU32::new(0);
+
+fn handle_intercept() {
+    let Some(message) = synic::poll_current_message(hvdef::HV_SYNIC_INTERCEPTION_SINT_INDEX) else {
+        return;
+    };
+    let resume = match RESUME_AT.load(Ordering::Relaxed) {
+        0 => Resume::Next,
+        address => Resume::At(address),
+    };
+    match intercept::resume_vtl0(message.as_message::<HvX64InterceptMessageHeader>(), resume) {
+        Ok(rip) => {
+            RESUMED_RIP.store(rip, Ordering::Relaxed);
+            HANDLED.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(e) => {
+            crate::log_fmt_nostdalloc!(log::Level::Error, "resuming VTL0 failed: {:?}", e);
+        }
+    }
+}
+
+/// Has the VTL1 handler of an intercepted WRMSR move VTL0 past it, then
+/// redirect VTL0 to a recovery stub, checking that VTL0 resumes where the
+/// handler said rather than executing the WRMSR again.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: InterruptPlatformTrait
+        + SecureInterceptPlatformTrait
+        + VtlPlatformTrait
+        + VirtualProcessorPlatformTrait<T>,
+{
+    let r = ctx.setup_interrupt_handler();
+    tmk_assert!(r.is_ok(), "setup_interrupt_handler should succeed");
+
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
+
+    let r = ctx.start_on_vp(VpExecToken::new(0, Vtl::Vtl1).command(move |ctx: &mut T| {
+        let r = ctx.setup_secure_intercept(INTERCEPT_VECTOR);
+        tmk_assert!(r.is_ok(), "setup_secure_intercept should succeed");
+        let r = ctx.set_interrupt_idx(INTERCEPT_VECTOR, handle_intercept);
+        tmk_assert!(r.is_ok(), "set_interrupt_idx should succeed");
+        let r = ctx.set_register(0x000E0000, 0x0000000000001000);
+        tmk_assert!(
+            r.is_ok(),
+            "set_register should succeed to intercept the APIC base"
+        );
+        ctx.switch_to_low_vtl();
+    }));
+    tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+
+    let recovery = opentmk_intercept_resume_recovery as usize as u64;
+    for (resume_at, expected) in [(0, 1), (recovery, 2)] {
+        RESUME_AT.store(resume_at, Ordering::Relaxed);
+        let handled = HANDLED.load(Ordering::Relaxed);
+        _ = ctx.queue_command_vp(VpExecToken::new(0, Vtl::Vtl1).command(|ctx: &mut T| {
+            ctx.switch_to_low_vtl();
+        }));
+
+        // SAFETY: the probe only clobbers registers the sysv64 ABI lets it
+        // clobber, and writes the APIC base back unchanged.
+        let returned = unsafe { opentmk_intercept_resume_probe() };
+
+        tmk_assert!(
+            HANDLED.load(Ordering::Relaxed) == handled + 1,
+            "the handler should resume VTL0 once per intercept",
+            extra = resume_at
+        );
+        let rip = RESUMED_RIP.load(Ordering::Relaxed);
+        tmk_assert!(
+            returned == expected,
+            "VTL0 should resume where the handler said",
+            extra = (returned, rip)
+        );
+        if resume_at != 0 {
+            tmk_assert!(
+                rip == recovery,
+                "the handler should report the recovery stub",
+                extra = rip
+            );
+        }
+    }
+
+    // Later tests write the APIC base themselves, e.g. to enable the APIC:
+    // the intercept is disarmed and its handler unhooked before returning.
+    RESUME_AT.store(0, Ordering::Relaxed);
+    let (token, result) = VpExecToken::new(0, Vtl::Vtl1).command_with_result(|ctx: &mut T| {
+        let disarmed = ctx.set_register(0x000E0000, 0);
+        let unhooked = ctx.clear_interrupt_idx(INTERCEPT_VECTOR);
+        disarmed.and(unhooked)
+    });
+    let r = ctx.start_on_vp(token);
+    tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+    let r = result.recv();
+    tmk_assert!(
+        matches!(r, Ok(Ok(()))),
+        "VTL1 should disarm the intercept and unhook its handler"
+    );
+}
@@ -0,0 +1,177 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use hvdef::Vtl;
+
+use crate::arch::apic;
+use crate::arch::ioapic;
+use crate::arch::ioapic::IoApic;
+use crate::context::InterruptPlatformTrait;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+use crate::devices::pit;
+use crate::devices::pit::Mode;
+use crate::platform::hyperv::synic::reference_time;
+use crate::platform::nested;
+use crate::tmk_assert;
+use crate::tmk_skip;
+use crate::tmkdefs::TmkResult;
+
+/// GSI the PIT interrupts on, IRQ0 being overridden to GSI 2 as on PC
+/// platforms.
+const PIT_GSI: u32 = 2;
+const PIT_CHANNEL: u8 = 0;
+/// Reload of the PIT, a 10ms period.
+const PIT_RELOAD: u16 = 11_932;
+const VECTOR: u8 = 0x52;
+/// Reference time interrupts are counted over, 100ms.
+const WINDOW: u64 = 1_000_000;
+/// Interrupts expected within the window, and the slack allowed around
+/// it.
+const EXPECTED: u64 = 10;
+const SLACK: u64 = 3;
+/// Reference time waited for interrupts still in flight after masking,
+/// two periods.
+const SETTLE: u64 = 200_000;
+/// VP the GSI is routed to after the BSP.
+const TARGET_VP: u32 = 1;
+
+static INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+static LAST_APIC_ID: AtomicU32 = AtomicU32::new(u32::MAX);
+
+fn handler() {
+    LAST_APIC_ID.store(apic::id(), Ordering::Relaxed);
+    INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+    apic::eoi();
+}
+
+fn wait(duration: u64) {
+    let start = reference_time();
+    while reference_time() - start < duration {
+        core::hint::spin_loop();
+    }
+}
+
+/// Returns the interrupts received over [`WINDOW`].
+fn count_interrupts() -> u64 {
+    INTERRUPTS.store(0, Ordering::Relaxed);
+    LAST_APIC_ID.store(u32::MAX, Ordering::Relaxed);
+    wait(WINDOW);
+    INTERRUPTS.load(Ordering::Relaxed)
+}
+
+/// Routes the PIT interrupt to the VP with `apic_id` and checks it
+/// arrives there at the PIT rate.
+fn check_route(ioapic: &IoApic, apic_id: u32, slack: u64) {
+    let r = ioapic.route(PIT_GSI, VECTOR, apic_id);
+    tmk_assert!(r.is_ok(), "routing the PIT GSI should succeed");
+    let entry = ioapic.redirection(PIT_GSI);
+    tmk_assert!(
+        entry.is_ok(),
+        "reading the redirection entry should succeed"
+    );
+    let entry = entry.unwrap();
+    tmk_assert!(
+        entry.vector() == VECTOR && u32::from(entry.destination()) == apic_id && !entry.masked(),
+        "the redirection entry should read back as written",
+        extra = u64::from(entry)
+    );
+
+    let received = count_interrupts();
+    tmk_assert!(
+        received.abs_diff(EXPECTED) <= slack,
+        "the PIT interrupt should arrive at the PIT rate",
+        extra = (apic_id, received)
+    );
+    let last = LAST_APIC_ID.load(Ordering::Relaxed);
+    tmk_assert!(
+        last == apic_id,
+        "the PIT interrupt should arrive on the routed VP",
+        extra = (apic_id, last)
+    );
+}
+
+/// Routes the PIT interrupt through the IOAPIC to a chosen vector on the
+/// BSP and then on another VP, checking that it arrives where it is routed
+/// at the rate the PIT is programmed for, and not at all while masked.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: InterruptPlatformTrait + VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    let ioapic = match IoApic::new(ioapic::DEFAULT_BASE) {
+        Ok(ioapic) => ioapic,
+        Err(_) => {
+            tmk_skip!("no IOAPIC");
+        }
+    };
+    pit::program(PIT_CHANNEL, Mode::RateGenerator, PIT_RELOAD);
+    if pit::read_status(PIT_CHANNEL).mode() != Mode::RateGenerator as u8 {
+        tmk_skip!("no PIT");
+    }
+    log::info!(
+        "IOAPIC {} version {:#x}, {} entries",
+        ioapic.id(),
+        ioapic.version(),
+        ioapic.entries()
+    );
+    let saved = ioapic.redirection(PIT_GSI);
+    tmk_assert!(saved.is_ok(), "the IOAPIC should have an entry for the PIT");
+    let saved = saved.unwrap();
+
+    let r = ctx.setup_interrupt_handler();
+    tmk_assert!(r.is_ok(), "setup_interrupt_handler should succeed");
+    let r = ctx.set_interrupt_idx(VECTOR, handler);
+    tmk_assert!(r.is_ok(), "set_interrupt_idx should succeed");
+    let r = apic::enable();
+    tmk_assert!(r.is_ok(), "enabling the x2APIC should succeed");
+    let pic_masks = ioapic::mask_legacy_pic();
+
+    // Some interrupts must still arrive for the route to count as working.
+    let slack =
+        (SLACK * nested::tolerance_factor("interrupt rate slack widened")).min(EXPECTED - 1);
+    check_route(&ioapic, apic::id(), slack);
+
+    let r = ioapic.set_masked(PIT_GSI, true);
+    tmk_assert!(r.is_ok(), "masking the PIT GSI should succeed");
+    wait(SETTLE);
+    let received = count_interrupts();
+    tmk_assert!(
+        received == 0,
+        "a masked GSI should not be delivered",
+        extra = received
+    );
+
+    let vp_count = ctx.get_vp_count();
+    tmk_assert!(vp_count.is_ok(), "get_vp_count should succeed");
+    if vp_count.unwrap() > TARGET_VP {
+        let (token, result) = VpExecToken::new(TARGET_VP, Vtl::Vtl0).command_with_result(
+            |ctx: &mut T| -> TmkResult<u32> {
+                ctx.setup_interrupt_handler()?;
+                apic::enable()?;
+                Ok(apic::id())
+            },
+        );
+        let r = ctx.start_on_vp(token);
+        tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+        let apic_id = result.recv();
+        tmk_assert!(
+            apic_id.as_ref().is_ok_and(|r| r.is_ok()),
+            "the target VP should enable its APIC"
+        );
+        check_route(&ioapic, apic_id.unwrap().unwrap(), slack);
+    } else {
+        log::info!("single VP, not routing to another VP");
+    }
+
+    // The entry is restored masked: the PIT is left as a one-shot, whose
+    // single edge nothing handles anymore.
+    let r = ioapic.set_redirection(PIT_GSI, saved.with_masked(true));
+    tmk_assert!(r.is_ok(), "restoring the redirection entry should succeed");
+    pit::program(PIT_CHANNEL, Mode::InterruptOnTerminalCount, u16::MAX);
+    ioapic::set_legacy_pic_masks(pic_masks);
+}
@@ -0,0 +1,66 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+
+use crate::context::InterruptPlatformTrait;
+use crate::platform::hyperv::irq_hvcall;
+use crate::tmk_assert;
+use crate::tmkdefs::TmkError;
+
+const TEST_VECTOR: u8 = 0x30;
+
+static HANDLER_RAN: AtomicBool = AtomicBool::new(false);
+static HANDLER_OK: AtomicBool = AtomicBool::new(false);
+
+fn handler() {
+    HANDLER_RAN.store(true, Ordering::Release);
+    let ok = irq_hvcall::in_irq_context()
+        && irq_hvcall::with_irq_hvcall(|hvcall| {
+            let vtl = hvcall.vtl();
+            crate::log_fmt_nostdalloc!(log::Level::Info, "interrupt handler running in {:?}", vtl);
+            // Re-entering the instance must be refused, not aliased.
+            irq_hvcall::with_irq_hvcall(|_| ()) == Err(TmkError::OperationDenied)
+        })
+        .unwrap_or(false);
+    HANDLER_OK.store(ok, Ordering::Release);
+}
+
+/// Issues hypercalls from an interrupt handler through the per-VP interrupt
+/// context HvCall and checks that nesting on it is caught.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: InterruptPlatformTrait,
+{
+    let r = ctx.setup_interrupt_handler();
+    tmk_assert!(r.is_ok(), "setup_interrupt_handler should succeed");
+    let r = ctx.set_interrupt_idx(TEST_VECTOR, handler);
+    tmk_assert!(r.is_ok(), "set_interrupt_idx should succeed");
+
+    tmk_assert!(
+        !irq_hvcall::in_irq_context(),
+        "test should start outside interrupt context"
+    );
+    let violations = irq_hvcall::nesting_violations();
+
+    // SAFETY: the vector has a handler installed above.
+    unsafe { core::arch::asm!("int 0x30") };
+
+    tmk_assert!(
+        HANDLER_RAN.load(Ordering::Acquire),
+        "interrupt handler should run"
+    );
+    tmk_assert!(
+        HANDLER_OK.load(Ordering::Acquire),
+        "handler hypercalls should succeed and nesting should be refused"
+    );
+    tmk_assert!(
+        irq_hvcall::nesting_violations() == violations + 1,
+        "exactly the deliberate nesting should be reported"
+    );
+    tmk_assert!(
+        !irq_hvcall::in_irq_context(),
+        "interrupt context should end with the handler"
+    );
+}
@@ -0,0 +1,72 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use crate::arch::apic;
+use crate::arch::cycles;
+use crate::context::InterruptPlatformTrait;
+use crate::latency::LatencyStats;
+use crate::tmk_assert;
+
+const TEST_VECTOR: u8 = 0x31;
+const ITERATIONS: usize = 1000;
+/// Cycles to wait for the handler before declaring the interrupt lost.
+const DELIVERY_TIMEOUT: u64 = 10_000_000_000;
+
+/// TSC value at handler entry, zero while no interrupt is pending.
+static HANDLER_ENTRY: AtomicU64 = AtomicU64::new(0);
+
+fn handler() {
+    HANDLER_ENTRY.store(cycles::read(), Ordering::Release);
+    apic::eoi();
+}
+
+/// Measures the delay between sending a self IPI and the entry of its
+/// handler, and reports the distribution as a `latency` record.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: InterruptPlatformTrait,
+{
+    let r = ctx.setup_interrupt_handler();
+    tmk_assert!(r.is_ok(), "setup_interrupt_handler should succeed");
+    let r = ctx.set_interrupt_idx(TEST_VECTOR, handler);
+    tmk_assert!(r.is_ok(), "set_interrupt_idx should succeed");
+    let r = apic::enable();
+    tmk_assert!(r.is_ok(), "enabling the x2APIC should succeed");
+
+    let mut stats = LatencyStats::with_capacity(ITERATIONS);
+    let mut delivered = 0;
+    for _ in 0..ITERATIONS {
+        HANDLER_ENTRY.store(0, Ordering::Release);
+        let sent = cycles::read();
+        apic::self_ipi(TEST_VECTOR);
+        let entry = loop {
+            let entry = HANDLER_ENTRY.load(Ordering::Acquire);
+            if entry != 0 || cycles::read().wrapping_sub(sent) > DELIVERY_TIMEOUT {
+                break entry;
+            }
+            core::hint::spin_loop();
+        };
+        // The IPIs after a lost one would likely be lost too, each waiting
+        // for the whole timeout.
+        if entry == 0 {
+            break;
+        }
+        stats.record(entry.wrapping_sub(sent));
+        delivered += 1;
+    }
+    tmk_assert!(
+        delivered == ITERATIONS,
+        "every self IPI should be delivered",
+        extra = delivered
+    );
+
+    let summary = stats.summary(cycles::frequency());
+    crate::latency::write_summary("self_ipi", &summary);
+    tmk_assert!(
+        summary.count == ITERATIONS,
+        "every iteration should record a sample"
+    );
+}
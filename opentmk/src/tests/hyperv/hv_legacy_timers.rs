@@ -0,0 +1,183 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use serde::Serialize;
+
+use crate::devices::pit;
+use crate::devices::pit::Mode;
+use crate::devices::pm_timer;
+use crate::platform::hyperv::synic::reference_time;
+use crate::platform::nested;
+use crate::tmk_assert;
+use crate::tmk_skip;
+
+const CHANNEL: u8 = 0;
+/// Frequency of the partition reference time.
+const REFERENCE_HZ: u64 = 10_000_000;
+/// Reference time each rate is measured over, 100ms.
+const WINDOW: u64 = 1_000_000;
+/// Reload of the periodic modes, a 10ms period.
+const PERIODIC_RELOAD: u16 = 11_932;
+/// Count of the one-shot, the longest the counter holds.
+const ONE_SHOT_COUNT: u16 = u16::MAX;
+/// Reference time waited for the one-shot to reach terminal count.
+const ONE_SHOT_TIMEOUT: u64 = 10_000_000;
+/// Largest deviation of a measured rate from the nominal one.
+const TOLERANCE_PERMILLE: u64 = 20;
+/// Largest deviation of the one-shot, whose end is only seen by polling.
+const ONE_SHOT_TOLERANCE_PERMILLE: u64 = 50;
+
+#[derive(Serialize)]
+struct LegacyTimerRecord {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    timer: &'static str,
+    expected_hz: u64,
+    measured_hz: u64,
+    error_permille: u64,
+}
+
+/// Writes the `legacy_timer` record of a measurement and returns its
+/// deviation from `expected_hz`.
+fn write_record(timer: &'static str, expected_hz: u64, measured_hz: u64) -> u64 {
+    let error_permille = expected_hz.abs_diff(measured_hz) * 1000 / expected_hz;
+    log::info!(
+        "{}: {} Hz, expected {} Hz ({} permille off)",
+        timer,
+        measured_hz,
+        expected_hz,
+        error_permille
+    );
+    crate::tmk_logger::write_record(&LegacyTimerRecord {
+        record_type: "legacy_timer",
+        timer,
+        expected_hz,
+        measured_hz,
+        error_permille,
+    });
+    error_permille
+}
+
+fn rate(ticks: u64, elapsed: u64) -> u64 {
+    (ticks as u128 * REFERENCE_HZ as u128 / elapsed.max(1) as u128) as u64
+}
+
+/// Runs channel 0 in the periodic `mode` for [`WINDOW`] and returns the
+/// rate it counted at, following the count across reloads.
+fn measure_periodic(mode: Mode) -> u64 {
+    pit::program(CHANNEL, mode, PERIODIC_RELOAD);
+    while pit::read_status(CHANNEL).null_count() {
+        core::hint::spin_loop();
+    }
+    let mut previous = pit::read_count(CHANNEL);
+    let start = reference_time();
+    let mut ticks = 0;
+    loop {
+        let count = pit::read_count(CHANNEL);
+        let now = reference_time();
+        ticks += pit::ticks_between(previous, count, PERIODIC_RELOAD, mode);
+        previous = count;
+        if now - start >= WINDOW {
+            return rate(ticks, now - start);
+        }
+    }
+}
+
+/// Runs channel 0 as a one-shot and returns the rate implied by the time
+/// OUT took to rise, `None` if it did not.
+fn measure_one_shot() -> Option<u64> {
+    let start = reference_time();
+    pit::program(CHANNEL, Mode::InterruptOnTerminalCount, ONE_SHOT_COUNT);
+    loop {
+        let now = reference_time();
+        if pit::read_status(CHANNEL).output() {
+            return Some(rate(ONE_SHOT_COUNT.into(), now - start));
+        }
+        if now - start > ONE_SHOT_TIMEOUT {
+            return None;
+        }
+    }
+}
+
+/// Returns the rate the PM timer counted at over [`WINDOW`].
+fn measure_pm_timer(timer: pm_timer::PmTimer) -> u64 {
+    let mut previous = timer.read();
+    let start = reference_time();
+    let mut ticks = 0u64;
+    loop {
+        let count = timer.read();
+        let now = reference_time();
+        ticks += timer.ticks_between(previous, count) as u64;
+        previous = count;
+        if now - start >= WINDOW {
+            return rate(ticks, now - start);
+        }
+    }
+}
+
+fn check_pit(factor: u64) {
+    for mode in [Mode::RateGenerator, Mode::SquareWave] {
+        let measured = measure_periodic(mode);
+        let error = write_record(
+            match mode {
+                Mode::RateGenerator => "pit_rate_generator",
+                _ => "pit_square_wave",
+            },
+            pit::FREQUENCY_HZ,
+            measured,
+        );
+        tmk_assert!(
+            error <= TOLERANCE_PERMILLE * factor,
+            "the PIT should count at its nominal rate",
+            extra = (mode as u8, measured)
+        );
+    }
+
+    let measured = measure_one_shot();
+    tmk_assert!(
+        measured.is_some(),
+        "the PIT one-shot should reach terminal count"
+    );
+    let measured = measured.unwrap();
+    let error = write_record("pit_one_shot", pit::FREQUENCY_HZ, measured);
+    tmk_assert!(
+        error <= ONE_SHOT_TOLERANCE_PERMILLE * factor,
+        "the PIT one-shot should expire on time",
+        extra = measured
+    );
+}
+
+/// Programs PIT channel 0 as a rate generator, a square wave and a
+/// one-shot, and reads the ACPI PM timer, measuring each against the
+/// partition reference time and checking that it counts at its nominal
+/// rate.
+pub fn exec() {
+    // A read-back of a missing PIT floats to all ones.
+    pit::program(CHANNEL, Mode::RateGenerator, PERIODIC_RELOAD);
+    let status = pit::read_status(CHANNEL);
+    let has_pit = status.mode() == Mode::RateGenerator as u8;
+    let timer = pm_timer::get();
+    if !has_pit && timer.is_none() {
+        tmk_skip!("neither a PIT nor an ACPI PM timer is present");
+    }
+
+    let factor = nested::tolerance_factor("timer rate tolerances widened");
+    if has_pit {
+        check_pit(factor);
+    } else {
+        log::info!("no PIT, status {:#x}", u8::from(status));
+    }
+
+    match timer {
+        Some(timer) => {
+            let measured = measure_pm_timer(timer);
+            let error = write_record("pm_timer", pm_timer::FREQUENCY_HZ, measured);
+            tmk_assert!(
+                error <= TOLERANCE_PERMILLE * factor,
+                "the PM timer should count at its nominal rate",
+                extra = measured
+            );
+        }
+        None => log::info!("no ACPI PM timer"),
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::Ordering;
+
+use hvdef::Vtl;
+use serde::Serialize;
+
+use crate::affinity;
+use crate::arch::cycles;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+use crate::platform::hyperv::arch::hypercall::HvCall;
+use crate::platform::hyperv::long_spin;
+use crate::sync::SpinLock;
+use crate::tmk_assert;
+use crate::tmk_skip;
+
+/// Cycles the BSP holds the lock while the APs wait for it.
+const HOLD_CYCLES: u64 = 100_000_000;
+
+#[derive(Serialize)]
+struct LongSpinRecord {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    hints: bool,
+    threshold: Option<u32>,
+    waiters: u32,
+    /// Hints given while the lock was contended.
+    notifications: u64,
+    failures: u64,
+    /// Cycles from releasing the lock to the last AP taking it.
+    handoff_cycles: u64,
+}
+
+/// Holds a [`SpinLock`] on the BSP for [`HOLD_CYCLES`] while every AP of
+/// `aps` waits for it, then releases it and returns the cycles until the
+/// last AP took it in turn.
+fn contend<T>(ctx: &mut T, aps: &[u32]) -> u64
+where
+    T: VirtualProcessorPlatformTrait<T>,
+{
+    let lock = Arc::new(SpinLock::new(0u64));
+    let ready = Arc::new(AtomicU32::new(0));
+    let guard = lock.lock();
+    let (tx, rx) = nostd_spin_channel::Channel::new().split();
+    for &vp in aps {
+        let lock = lock.clone();
+        let ready = ready.clone();
+        let tx = tx.clone();
+        let r = ctx.start_on_vp(
+            VpExecToken::new(vp, Vtl::Vtl0).command(move |_ctx: &mut T| {
+                ready.fetch_add(1, Ordering::AcqRel);
+                *lock.lock() = cycles::read();
+                _ = tx.send(());
+            }),
+        );
+        tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+    }
+    while ready.load(Ordering::Acquire) < aps.len() as u32 {
+        core::hint::spin_loop();
+    }
+    let start = cycles::read();
+    while cycles::read() - start < HOLD_CYCLES {
+        core::hint::spin_loop();
+    }
+    let released = cycles::read();
+    drop(guard);
+    for _ in aps {
+        tmk_assert!(rx.recv().is_ok(), "every AP should take the lock");
+    }
+    let last = *lock.lock();
+    last.saturating_sub(released)
+}
+
+/// Checks that HvCallNotifyLongSpinWait succeeds, then has every selected
+/// AP, see [`affinity::selected_vps`], wait on
+/// a spin lock the BSP holds, with the hints of the slow path enabled and
+/// disabled, and reports the hints given and the cycles the waiters took to
+/// get the lock once released as `long_spin_wait` records. Waiters spinning
+/// past the recommended count must have notified the hypervisor.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    let r = HvCall::notify_long_spin_wait(1);
+    tmk_assert!(
+        r.is_ok(),
+        "HvCallNotifyLongSpinWait should succeed",
+        extra = r.err()
+    );
+
+    let vp_count = ctx.get_vp_count();
+    tmk_assert!(vp_count.is_ok(), "get_vp_count should succeed");
+    let aps: Vec<u32> = affinity::selected_vps(vp_count.unwrap())
+        .into_iter()
+        .filter(|&vp| vp != 0)
+        .collect();
+    if aps.is_empty() {
+        tmk_skip!("at least two selected VPs are needed");
+    }
+    let threshold = long_spin::threshold();
+    log::info!("recommended long spin wait count: {:?}", threshold);
+
+    for hints in [true, false] {
+        long_spin::set_enabled(hints);
+        let notifications = long_spin::notifications();
+        let failures = long_spin::failures();
+        let handoff_cycles = contend(ctx, &aps);
+        let notifications = long_spin::notifications() - notifications;
+        let failures = long_spin::failures() - failures;
+        log::info!(
+            "hints {}: {} given, {} failed, last waiter in {} cycles",
+            if hints { "enabled" } else { "disabled" },
+            notifications,
+            failures,
+            handoff_cycles
+        );
+        crate::tmk_logger::write_record(&LongSpinRecord {
+            record_type: "long_spin_wait",
+            hints,
+            threshold,
+            waiters: aps.len() as u32,
+            notifications,
+            failures,
+            handoff_cycles,
+        });
+        tmk_assert!(
+            failures == 0,
+            "the hypervisor should accept every hint",
+            extra = failures
+        );
+        if !hints {
+            tmk_assert!(
+                notifications == 0,
+                "no hint should be given while disabled",
+                extra = notifications
+            );
+        } else if threshold.is_some_and(|t| (t as u64) < HOLD_CYCLES / 100) {
+            tmk_assert!(
+                notifications > 0,
+                "waiters spinning past the recommended count should notify",
+                extra = threshold
+            );
+        }
+    }
+    long_spin::set_enabled(true);
+}
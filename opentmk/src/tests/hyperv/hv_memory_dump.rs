@@ -0,0 +1,49 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::vec::Vec;
+
+use crate::compress::Compression;
+use crate::tmk_assert;
+use crate::tmk_logger;
+
+/// Pages in the dumped buffer.
+const PAGES: usize = 64;
+const PAGE_SIZE: usize = 4096;
+
+/// Dumps a buffer of mostly zeroed pages, each starting with its index, and
+/// checks that it is split in chunks and, when the harness took
+/// compression, that much less than the buffer crossed the serial port.
+pub fn exec() {
+    let mut memory: Vec<u8> = vec![0; PAGES * PAGE_SIZE];
+    for (i, page) in memory.chunks_mut(PAGE_SIZE).enumerate() {
+        page[..8].copy_from_slice(&(i as u64).to_le_bytes());
+    }
+
+    let stats = tmk_logger::dump_memory("hv_memory_dump", memory.as_ptr() as u64, &memory);
+    log::info!(
+        "dumped {} bytes in {} records, {} bytes sent",
+        stats.len,
+        stats.records,
+        stats.encoded
+    );
+    tmk_assert!(
+        stats.len == memory.len()
+            && stats.records == memory.len().div_ceil(tmk_logger::DUMP_CHUNK_SIZE),
+        "the whole buffer should be dumped in chunks",
+        extra = stats
+    );
+    if tmk_logger::compression() == Compression::Lz4 {
+        tmk_assert!(
+            stats.encoded < stats.len / 10,
+            "zeroed pages should compress",
+            extra = stats
+        );
+    } else {
+        tmk_assert!(
+            stats.encoded == stats.len,
+            "the dump should be sent as is without compression",
+            extra = stats
+        );
+    }
+}
@@ -4,8 +4,10 @@
 use alloc::alloc::alloc;
 use core::alloc::Layout;
 use core::arch::asm;
-use core::cell::RefCell;
 use core::ops::Range;
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering;
 
 use hvdef::Vtl;
 use nostd_spin_channel::Channel;
@@ -17,22 +19,22 @@
 use crate::context::VtlPlatformTrait;
 use crate::create_function_with_restore;
 use crate::tmk_assert;
+use crate::tmk_setup;
 
-static mut HEAP_ALLOC_PTR: RefCell<*mut u8> = RefCell::new(0 as *mut u8);
+/// The buffer VTL1 protects, for the function VTL0 calls to access it.
+static HEAP_ALLOC_PTR: AtomicPtr<u8> = AtomicPtr::new(core::ptr::null_mut());
 
-static mut RETURN_VALUE: u8 = 0;
+static RETURN_VALUE: AtomicU8 = AtomicU8::new(0);
 
 // Without inline the compiler may optimize away the call and the VTL switch may
 // distort the architectural registers
 #[inline(never)]
-#[expect(static_mut_refs)]
-// writing to a static generates a warning. we safely handle RETURN_VALUE so ignoring it here.
 fn violate_heap() {
-    unsafe {
-        let alloc_ptr = *HEAP_ALLOC_PTR.borrow();
-        // after a VTL switch we can't trust the value returned by eax
-        RETURN_VALUE = *(alloc_ptr.add(10));
-    }
+    let alloc_ptr = HEAP_ALLOC_PTR.load(Ordering::Relaxed);
+    // SAFETY: the buffer is allocated by VTL1 and never freed.
+    let value = unsafe { *(alloc_ptr.add(10)) };
+    // after a VTL switch we can't trust the value returned by eax
+    RETURN_VALUE.store(value, Ordering::Relaxed);
 }
 create_function_with_restore!(f_violate_heap, violate_heap);
 
@@ -53,16 +55,15 @@ pub fn exec<T>(ctx: &mut T)
     tmk_assert!(r.is_ok(), "setup_interrupt_handler should succeed");
     log::info!("set intercept handler successfully!");
 
-    let r = ctx.setup_partition_vtl(Vtl::Vtl1);
-    tmk_assert!(r.is_ok(), "setup_partition_vtl should succeed");
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
 
-    let r = ctx.start_on_vp(VpExecToken::new(0, Vtl::Vtl1).command(move |ctx: &mut T| {
+    let (token, buffer) = VpExecToken::new(0, Vtl::Vtl1).command_with_result(move |ctx: &mut T| {
         log::info!("successfully started running VTL1 on vp0.");
         let r = ctx.setup_secure_intercept(0x30);
         tmk_assert!(r.is_ok(), "setup_secure_intercept should succeed");
 
         let r = ctx.set_interrupt_idx(0x30, move || {
-            log::info!("interrupt handled for 0x30!");
+            crate::log_static!(log::Level::Info, "interrupt handled for 0x30!");
         });
         tmk_assert!(r.is_ok(), "set_interrupt_idx should succeed");
 
@@ -72,13 +73,8 @@ pub fn exec<T>(ctx: &mut T)
         let ptr = unsafe { alloc(layout) };
         log::info!("allocated some memory in the heap from vtl1");
 
-        #[expect(static_mut_refs)]
-        // writing to a static generates a warning. we safely handle HEAP_ALLOC_PTR so ignoring it here.
-        unsafe {
-            let mut z = HEAP_ALLOC_PTR.borrow_mut();
-            *z = ptr;
-            *ptr.add(10) = 0xA2;
-        }
+        // SAFETY: the allocation is 1MiB large.
+        unsafe { *ptr.add(10) = 0xA2 };
 
         let size = layout.size();
         let r = ctx.setup_vtl_protection();
@@ -93,11 +89,14 @@ pub fn exec<T>(ctx: &mut T)
         let r = ctx.apply_vtl_protection_for_memory(range, Vtl::Vtl1);
         tmk_assert!(r.is_ok(), "apply_vtl_protection_for_memory should succeed");
 
-        log::info!("moving to vtl0 to attempt to read the heap memory");
-
-        ctx.switch_to_low_vtl();
-    }));
+        log::info!("moving to vtl0 to attempt to access the heap memory");
+        ptr as u64
+    });
+    let r = ctx.start_on_vp(token);
     tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+    let buffer = buffer.recv();
+    tmk_assert!(buffer.is_ok(), "VTL1 should return the protected buffer");
+    HEAP_ALLOC_PTR.store(buffer.unwrap() as *mut u8, Ordering::Relaxed);
 
     let (tx, rx) = Channel::new().split();
 
@@ -127,19 +126,15 @@ pub fn exec<T>(ctx: &mut T)
 
             f_violate_heap();
 
-            #[expect(static_mut_refs)]
-            // reading a reference to a shared static reference generates a error. we safely handle RETURN_VALUE so ignoring it here.
-            // SAFETY: we are reading a static variable that is written to only once.
-            unsafe {
-                log::info!(
-                    "reading mutated heap memory from vtl0(it should not be 0xA2): 0x{:x}",
-                    RETURN_VALUE
-                );
-                tmk_assert!(
-                    RETURN_VALUE != 0xA2,
-                    "heap memory should not be accessible from vtl0"
-                );
-            }
+            let value = RETURN_VALUE.load(Ordering::Relaxed);
+            log::info!(
+                "reading mutated heap memory from vtl0(it should not be 0xA2): 0x{:x}",
+                value
+            );
+            tmk_assert!(
+                value != 0xA2,
+                "heap memory should not be accessible from vtl0"
+            );
 
             _ = tx.send(());
         }),
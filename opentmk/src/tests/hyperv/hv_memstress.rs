@@ -0,0 +1,79 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::vec::Vec;
+
+use hvdef::Vtl;
+
+use crate::affinity;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+use crate::memstress;
+use crate::tmk_assert;
+
+/// Heap usage, in percent, the test drives the allocator to.
+const PRESSURE_PERCENT: usize = 90;
+
+/// Validates that the allocator, hypercalls and commands on the selected
+/// APs, see [`affinity::selected_vps`], keep working while most of the heap
+/// is consumed, and that all the memory can be given back.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    let consumed = memstress::consume(PRESSURE_PERCENT);
+    tmk_assert!(consumed.is_ok(), "memstress consume should succeed");
+    tmk_assert!(
+        memstress::held() == consumed.unwrap(),
+        "memstress should hold all consumed memory"
+    );
+
+    // Hypercalls use pre-allocated pages and must not depend on free heap.
+    let vtl = ctx.get_current_vtl();
+    tmk_assert!(vtl.is_ok(), "get_current_vtl should succeed under pressure");
+    let vp_count = ctx.get_vp_count();
+    tmk_assert!(
+        vp_count.is_ok(),
+        "get_vp_count should succeed under pressure"
+    );
+    let mut unresponsive = Vec::new();
+    for vp in affinity::selected_vps(vp_count.unwrap()) {
+        if vp == 0 {
+            continue;
+        }
+        let (token, result) =
+            VpExecToken::new(vp, Vtl::Vtl0).command_with_result(|ctx: &mut T| ctx.get_current_vp());
+        let r = ctx.start_on_vp(token);
+        tmk_assert!(r.is_ok(), "start_on_vp should succeed under pressure");
+        if !matches!(result.recv(), Ok(Ok(ran_on)) if ran_on == vp) {
+            unresponsive.push(vp);
+        }
+    }
+    tmk_assert!(
+        unresponsive.is_empty(),
+        "every selected AP should run a command under pressure",
+        extra = unresponsive
+    );
+
+    let small = vec![0u8; 4096];
+    tmk_assert!(
+        small.len() == 4096,
+        "small allocation should succeed under pressure"
+    );
+    drop(small);
+
+    let released = memstress::release_all();
+    log::info!("memstress released {} bytes", released);
+    tmk_assert!(
+        memstress::held() == 0,
+        "memstress should release everything"
+    );
+
+    let consumed = memstress::consume(PRESSURE_PERCENT);
+    tmk_assert!(
+        consumed.is_ok(),
+        "memstress consume should succeed again after release"
+    );
+    memstress::release_all();
+}
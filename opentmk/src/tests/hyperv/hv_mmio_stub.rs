@@ -0,0 +1,141 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::alloc::alloc;
+use core::alloc::Layout;
+use core::ops::Range;
+
+use hvdef::Vtl;
+
+use crate::context::InterruptPlatformTrait;
+use crate::context::SecureInterceptPlatformTrait;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+use crate::platform::hyperv::mmio_stub;
+use crate::platform::hyperv::mmio_stub::MmioRegion;
+use crate::platform::hyperv::mmio_stub::MmioRegister;
+use crate::tmk_assert;
+use crate::tmk_setup;
+
+const INTERCEPT_VECTOR: u8 = 0x30;
+const PAGE_SIZE: usize = 4096;
+
+/// Read-only identification register.
+const ID: u64 = 0x0;
+/// Plain read/write register.
+const SCRATCH: u64 = 0x8;
+/// Accumulates the values written to it.
+const DOORBELL: u64 = 0x10;
+/// Increments on every read.
+const COUNTER: u64 = 0x18;
+
+const ID_VALUE: u32 = 0x4f54_4d4b;
+const SCRATCH_PATTERN: u64 = 0x0123_4567_89ab_cdef;
+
+/// Number of accesses VTL0 makes to the region, each needing VTL1 to
+/// return to VTL0 once served.
+const ACCESSES: u64 = 7;
+
+/// Has VTL1 emulate a small device in a page VTL0 cannot access, and checks
+/// that VTL0 loads and stores to it are served by the device registers and
+/// resume after the accessing instruction.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: InterruptPlatformTrait
+        + SecureInterceptPlatformTrait
+        + VtlPlatformTrait
+        + VirtualProcessorPlatformTrait<T>,
+{
+    let r = ctx.setup_interrupt_handler();
+    tmk_assert!(r.is_ok(), "setup_interrupt_handler should succeed");
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
+
+    let layout = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap();
+    // SAFETY: the layout has a non-zero size. The page is never freed, VTL1
+    // keeps it protected for the rest of the run.
+    let page = unsafe { alloc(layout) };
+    tmk_assert!(!page.is_null(), "the device page should be allocated");
+    let range = Range {
+        start: page as u64,
+        end: page as u64 + PAGE_SIZE as u64,
+    };
+
+    mmio_stub::install(
+        MmioRegion::new(range.clone())
+            .register(MmioRegister::new(ID, ID_VALUE.into()).on_write(|_, _| {}))
+            .register(MmioRegister::new(SCRATCH, 0))
+            .register(MmioRegister::new(DOORBELL, 0).on_write(|value, written| *value += written))
+            .register(MmioRegister::new(COUNTER, 0).on_read(|value| {
+                *value += 1;
+                *value
+            })),
+    );
+
+    let protected = range.clone();
+    let r = ctx.start_on_vp(VpExecToken::new(0, Vtl::Vtl1).command(move |ctx: &mut T| {
+        let r = ctx.setup_secure_intercept(INTERCEPT_VECTOR);
+        tmk_assert!(r.is_ok(), "setup_secure_intercept should succeed");
+        let r = ctx.set_interrupt_idx(INTERCEPT_VECTOR, mmio_stub::handle_intercept);
+        tmk_assert!(r.is_ok(), "set_interrupt_idx should succeed");
+        let r = ctx.setup_vtl_protection();
+        tmk_assert!(r.is_ok(), "setup_vtl_protection should succeed");
+        let r = ctx.apply_vtl_protection_for_memory(protected, Vtl::Vtl1);
+        tmk_assert!(r.is_ok(), "apply_vtl_protection_for_memory should succeed");
+        ctx.switch_to_low_vtl();
+    }));
+    tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+
+    // VTL1 runs one of these after serving each access.
+    for _ in 0..ACCESSES {
+        let r = ctx.queue_command_vp(VpExecToken::new(0, Vtl::Vtl1).command(|ctx: &mut T| {
+            ctx.switch_to_low_vtl();
+        }));
+        tmk_assert!(r.is_ok(), "queue_command_vp should succeed");
+    }
+
+    let reg = |offset: u64| (range.start + offset) as *mut u8;
+    // SAFETY: the registers are within the page allocated above and every
+    // access to it is emulated by VTL1.
+    let (id, scratch, first, second) = unsafe {
+        let id = core::ptr::read_volatile(reg(ID).cast::<u32>());
+        core::ptr::write_volatile(reg(SCRATCH).cast::<u64>(), SCRATCH_PATTERN);
+        let scratch = core::ptr::read_volatile(reg(SCRATCH).cast::<u64>());
+        core::ptr::write_volatile(reg(DOORBELL).cast::<u32>(), 3);
+        core::ptr::write_volatile(reg(DOORBELL).cast::<u8>(), 4);
+        let first = core::ptr::read_volatile(reg(COUNTER).cast::<u16>());
+        let second = core::ptr::read_volatile(reg(COUNTER).cast::<u16>());
+        (id, scratch, first, second)
+    };
+
+    let (emulated, unhandled) = mmio_stub::stats();
+    let region = mmio_stub::uninstall();
+    tmk_assert!(region.is_some(), "the region should still be installed");
+    let region = region.unwrap();
+
+    tmk_assert!(id == ID_VALUE, "the ID register should read its value");
+    tmk_assert!(
+        scratch == SCRATCH_PATTERN,
+        "the scratch register should read back the value written"
+    );
+    tmk_assert!(
+        region.get(DOORBELL).map(MmioRegister::value) == Some(7),
+        "the doorbell should see both writes"
+    );
+    tmk_assert!(
+        first == 1 && second == 2,
+        "the counter should count the reads"
+    );
+    tmk_assert!(
+        emulated == ACCESSES && unhandled == 0,
+        "every access should be emulated",
+        extra = (emulated, unhandled)
+    );
+
+    let r = ctx.start_on_vp(VpExecToken::new(0, Vtl::Vtl1).command(move |ctx: &mut T| {
+        let r = ctx.remove_vtl_protection_for_memory(range, Vtl::Vtl1);
+        tmk_assert!(r.is_ok(), "remove_vtl_protection_for_memory should succeed");
+        ctx.switch_to_low_vtl();
+    }));
+    tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+}
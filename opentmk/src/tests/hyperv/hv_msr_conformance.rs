@@ -0,0 +1,124 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::vec::Vec;
+use core::arch::x86_64::__cpuid;
+
+use hvdef::HvIsolationConfiguration;
+
+use crate::arch::msr_conformance;
+use crate::arch::msr_conformance::ExpectedRead;
+use crate::arch::msr_conformance::ExpectedWrite;
+use crate::arch::msr_conformance::MsrExpectation;
+use crate::context::InterruptPlatformTrait;
+use crate::tmk_assert;
+
+/// A synthetic MSR number the TLFS leaves unassigned.
+const UNASSIGNED_SYNTHETIC_MSR: u32 = 0x4000_01ff;
+/// An architectural MSR number no processor implements.
+const UNASSIGNED_ARCH_MSR: u32 = 0x0000_0fff;
+
+const fn entry(
+    msr: u32,
+    name: &'static str,
+    read: ExpectedRead,
+    write: Option<(u64, ExpectedWrite)>,
+) -> MsrExpectation {
+    MsrExpectation {
+        msr,
+        name,
+        read,
+        write,
+    }
+}
+
+/// Expectations shared by every Hyper-V platform. The hypercall interface
+/// is initialized before tests run, so the guest OS ID is set.
+const COMMON: &[MsrExpectation] = &[
+    entry(
+        hvdef::HV_X64_MSR_GUEST_OS_ID,
+        "guest_os_id",
+        ExpectedRead::NonZero,
+        None,
+    ),
+    entry(
+        hvdef::HV_X64_MSR_VP_INDEX,
+        "vp_index",
+        ExpectedRead::Any,
+        Some((0, ExpectedWrite::Fault)),
+    ),
+    entry(
+        hvdef::HV_X64_MSR_TIME_REF_COUNT,
+        "time_ref_count",
+        ExpectedRead::NonZero,
+        Some((0, ExpectedWrite::Fault)),
+    ),
+    entry(
+        hvdef::HV_X64_MSR_GUEST_CRASH_P0,
+        "guest_crash_p0",
+        ExpectedRead::Any,
+        Some((0, ExpectedWrite::Accepted)),
+    ),
+    entry(
+        UNASSIGNED_SYNTHETIC_MSR,
+        "unassigned_synthetic",
+        ExpectedRead::Fault,
+        Some((0, ExpectedWrite::Fault)),
+    ),
+    entry(
+        UNASSIGNED_ARCH_MSR,
+        "unassigned_architectural",
+        ExpectedRead::Fault,
+        Some((0, ExpectedWrite::Fault)),
+    ),
+];
+
+/// Expectations specific to running under a paravisor, which emulates the
+/// crash MSRs itself and reports its crash capabilities on CRASH_CTL reads.
+const PARAVISOR: &[MsrExpectation] = &[entry(
+    hvdef::HV_X64_MSR_GUEST_CRASH_CTL,
+    "guest_crash_ctl",
+    ExpectedRead::NonZero,
+    None,
+)];
+
+fn paravisor_present() -> bool {
+    // SAFETY: CPUID is always available on x86_64.
+    let leaf = unsafe { __cpuid(hvdef::HV_CPUID_FUNCTION_MS_HV_ISOLATION_CONFIGURATION) };
+    HvIsolationConfiguration::from(
+        leaf.eax as u128
+            | (leaf.ebx as u128) << 32
+            | (leaf.ecx as u128) << 64
+            | (leaf.edx as u128) << 96,
+    )
+    .paravisor_present()
+}
+
+/// Probes a table of implemented and unimplemented MSRs with #GP capture
+/// and checks each access against the expectations of the platform.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: InterruptPlatformTrait,
+{
+    let r = ctx.setup_interrupt_handler();
+    tmk_assert!(r.is_ok(), "setup_interrupt_handler should succeed");
+
+    let (platform, extra) = if paravisor_present() {
+        ("paravisor", PARAVISOR)
+    } else {
+        ("hyperv", &[][..])
+    };
+    log::info!("checking MSR behavior against the {} table", platform);
+
+    let table: Vec<MsrExpectation> = COMMON.iter().chain(extra).copied().collect();
+    // SAFETY: the table only writes MSRs whose writes are either rejected
+    // or harmless, and accepted writes are undone.
+    let results = unsafe { msr_conformance::check(&table) };
+    msr_conformance::write_report(platform, &results);
+
+    let failures = results.iter().filter(|r| !r.conforms).count();
+    tmk_assert!(
+        failures == 0,
+        "every MSR should behave as the platform table expects"
+    );
+}
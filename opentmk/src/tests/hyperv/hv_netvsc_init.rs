@@ -0,0 +1,53 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use hvdef::Vtl;
+
+use crate::context::VtlPlatformTrait;
+use crate::devices::netvsc::NetvscClient;
+use crate::devices::vmbus::VmbusClient;
+use crate::tmk_assert;
+
+/// MAC address the harness assigns to the synthetic NIC.
+const EXPECTED_MAC: [u8; 6] = [0x00, 0x15, 0x5d, 0x00, 0x7e, 0x01];
+
+/// Brings up the synthetic NIC's control path: opens the channel, sets up
+/// NVSP, initializes RNDIS and checks the link state and MAC address
+/// reported by the host against what the harness configured.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait,
+{
+    let vtl = ctx.get_current_vtl();
+    tmk_assert!(vtl.is_ok(), "get_current_vtl should succeed");
+    tmk_assert!(vtl.unwrap() == Vtl::Vtl0, "netvsc test should run in VTL0");
+
+    let vmbus = VmbusClient::connect();
+    tmk_assert!(vmbus.is_ok(), "vmbus connect should succeed");
+
+    let netvsc = NetvscClient::open(vmbus.unwrap());
+    tmk_assert!(netvsc.is_ok(), "network channel open should succeed");
+    let mut netvsc = netvsc.unwrap();
+
+    let version = netvsc.initialize();
+    tmk_assert!(version.is_ok(), "nvsp initialization should succeed");
+
+    let device = netvsc.rndis_initialize();
+    tmk_assert!(device.is_ok(), "rndis initialize should succeed");
+    let device = device.unwrap();
+    log::info!("rndis device: {:?}", device);
+    tmk_assert!(device.medium == 0, "nic should be an 802.3 device");
+
+    let link_up = netvsc.link_up();
+    tmk_assert!(link_up.is_ok(), "link state query should succeed");
+    tmk_assert!(link_up.unwrap(), "link should be up");
+
+    let mac = netvsc.mac_address();
+    tmk_assert!(mac.is_ok(), "mac address query should succeed");
+    let mac = mac.unwrap();
+    log::info!("mac address: {:02x?}", mac);
+    tmk_assert!(mac == EXPECTED_MAC, "mac address should match the harness");
+
+    let r = netvsc.close();
+    tmk_assert!(r.is_ok(), "network channel close should succeed");
+}
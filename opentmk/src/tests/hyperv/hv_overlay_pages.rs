@@ -0,0 +1,138 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::alloc::alloc_zeroed;
+use alloc::alloc::dealloc;
+use core::alloc::Layout;
+use core::mem::size_of;
+
+use hvdef::HV_PAGE_SIZE;
+use hvdef::HvRegisterVpAssistPage;
+use hvdef::HvVpAssistPage;
+
+use crate::context::GpaOverlayPlatformTrait;
+use crate::context::MsrPlatformTrait;
+use crate::tmk_assert;
+use crate::tmkdefs::TmkError;
+
+const PAGE_SIZE: usize = HV_PAGE_SIZE as usize;
+/// Pattern of the memory covered by the overlays.
+const UNDERLYING_PATTERN: u8 = 0xa5;
+/// Pattern of the source page mapped over the target.
+const OVERLAY_PATTERN: u8 = 0x5a;
+/// Offset in the VP assist page past every field the hypervisor defines,
+/// so writing a marker there does not change any VP state.
+const ASSIST_MARKER_OFFSET: usize = PAGE_SIZE - 1;
+const _: () = assert!(size_of::<HvVpAssistPage>() <= ASSIST_MARKER_OFFSET);
+
+fn page_holds(page: *const u8, value: u8) -> bool {
+    // SAFETY: callers pass a page allocated by this test.
+    (0..PAGE_SIZE).all(|i| unsafe { page.add(i).read_volatile() } == value)
+}
+
+fn fill_page(page: *mut u8, value: u8) {
+    // SAFETY: callers pass a page allocated by this test.
+    unsafe { core::ptr::write_bytes(page, value, PAGE_SIZE) };
+}
+
+/// Maps a page over another with `HvCallMapGpaPages` where the partition is
+/// permitted to, and checks that the original contents come back after
+/// `HvCallUnmapGpaPages`.
+fn map_gpa_pages_overlay<T>(ctx: &mut T, target: *mut u8, source: *mut u8)
+where
+    T: GpaOverlayPlatformTrait,
+{
+    fill_page(target, UNDERLYING_PATTERN);
+    fill_page(source, OVERLAY_PATTERN);
+
+    let r = ctx.map_gpa_pages(
+        target as u64 / HV_PAGE_SIZE,
+        &[source as u64 / HV_PAGE_SIZE],
+    );
+    match r {
+        Err(TmkError::AccessDenied | TmkError::InvalidHypercallCode) => {
+            log::warn!("HvCallMapGpaPages not permitted ({:?}), skipping", r);
+            return;
+        }
+        _ => tmk_assert!(r.is_ok(), "map_gpa_pages should succeed"),
+    }
+    tmk_assert!(
+        page_holds(target, OVERLAY_PATTERN),
+        "the target should show the source page while mapped"
+    );
+
+    let r = ctx.unmap_gpa_pages(target as u64 / HV_PAGE_SIZE, 1);
+    tmk_assert!(r.is_ok(), "unmap_gpa_pages should succeed");
+    tmk_assert!(
+        page_holds(target, UNDERLYING_PATTERN),
+        "the target contents should be restored after unmap"
+    );
+}
+
+/// Places the VP assist page overlay over `target`, writes a marker through
+/// it, and checks that removing the overlay restores the original contents.
+fn vp_assist_overlay<T>(ctx: &mut T, target: *mut u8)
+where
+    T: MsrPlatformTrait,
+{
+    // SAFETY: the VP assist page MSR is always present under Hyper-V.
+    let r = unsafe { ctx.read_msr(hvdef::HV_X64_MSR_VP_ASSIST_PAGE) };
+    tmk_assert!(r.is_ok(), "reading the VP assist page MSR should succeed");
+    let original = r.unwrap();
+    if HvRegisterVpAssistPage::from(original).enabled() {
+        log::warn!("VP assist page already in use ({:#x}), skipping", original);
+        return;
+    }
+    fill_page(target, UNDERLYING_PATTERN);
+    let overlay = HvRegisterVpAssistPage::new()
+        .with_enabled(true)
+        .with_gpa_page_number(target as u64 / HV_PAGE_SIZE);
+    // SAFETY: the overlay covers a page owned by this test.
+    let r = unsafe { ctx.write_msr(hvdef::HV_X64_MSR_VP_ASSIST_PAGE, overlay.into()) };
+    tmk_assert!(r.is_ok(), "enabling the VP assist page should succeed");
+
+    // SAFETY: the marker offset is within the page and unused by the
+    // hypervisor.
+    unsafe {
+        target
+            .add(ASSIST_MARKER_OFFSET)
+            .write_volatile(OVERLAY_PATTERN)
+    };
+
+    // SAFETY: restoring the value read above.
+    let r = unsafe { ctx.write_msr(hvdef::HV_X64_MSR_VP_ASSIST_PAGE, original) };
+    tmk_assert!(r.is_ok(), "disabling the VP assist page should succeed");
+    tmk_assert!(
+        page_holds(target, UNDERLYING_PATTERN),
+        "the memory under the VP assist page should be restored"
+    );
+}
+
+/// Overlays test pages with `HvCallMapGpaPages` and with the VP assist page
+/// and checks that the memory underneath is untouched once the overlays
+/// are removed. With a paravisor, the VP assist page overlay is emulated by
+/// the paravisor rather than the hypervisor.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: GpaOverlayPlatformTrait + MsrPlatformTrait,
+{
+    let layout = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap();
+    // SAFETY: the layout has a non-zero size.
+    let target = unsafe { alloc_zeroed(layout) };
+    // SAFETY: the layout has a non-zero size.
+    let source = unsafe { alloc_zeroed(layout) };
+    tmk_assert!(
+        !target.is_null() && !source.is_null(),
+        "page allocation should succeed"
+    );
+
+    map_gpa_pages_overlay(ctx, target, source);
+    vp_assist_overlay(ctx, target);
+
+    // SAFETY: the pages were allocated above with this layout and no
+    // overlay covers them anymore.
+    unsafe {
+        dealloc(target, layout);
+        dealloc(source, layout);
+    }
+}
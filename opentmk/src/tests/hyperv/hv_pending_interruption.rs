@@ -0,0 +1,128 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::Ordering;
+
+use hvdef::HvX64PendingInterruptionRegister;
+use hvdef::Vtl;
+
+use crate::context::InterruptPlatformTrait;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+use crate::platform::hyperv::pending_event;
+use crate::tmk_assert;
+use crate::tmk_setup;
+use crate::tmkdefs::TmkResult;
+
+/// The vector of the injected external interrupt.
+const VECTOR: u8 = 0x41;
+/// The vector of the injected exception, #DE, which has no error code and
+/// is never raised by the test itself.
+const EXCEPTION_VECTOR: u8 = 0;
+
+static INTERRUPTS: AtomicU32 = AtomicU32::new(0);
+static EXCEPTIONS: AtomicU32 = AtomicU32::new(0);
+
+fn on_interrupt() {
+    INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+}
+
+fn on_exception() {
+    EXCEPTIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Has VTL1 make `event` pending for VTL0 on `vp`, then withdraw it if
+/// `withdraw` is set, and returns the pending interruption VTL1 read back
+/// after injecting. The event, if left pending, is delivered as VTL0
+/// resumes.
+fn inject_from_vtl1<T>(
+    ctx: &mut T,
+    vp: u32,
+    event: HvX64PendingInterruptionRegister,
+    withdraw: bool,
+) -> HvX64PendingInterruptionRegister
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    let (token, result) =
+        VpExecToken::new(vp, Vtl::Vtl1).command_with_result(move |ctx: &mut T| -> TmkResult<_> {
+            pending_event::inject(ctx, vp, Vtl::Vtl0, event)?;
+            let pending = pending_event::read(ctx, vp, Vtl::Vtl0)?;
+            if withdraw {
+                pending_event::clear(ctx, vp, Vtl::Vtl0)?;
+            }
+            Ok(pending)
+        });
+    let r = ctx.start_on_vp(token);
+    tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+    let r = result.recv();
+    tmk_assert!(r.is_ok(), "VTL1 should report back");
+    let r = r.unwrap();
+    tmk_assert!(
+        r.is_ok(),
+        "VTL1 should set the VTL0 pending interruption",
+        extra = format!("{:?}", r)
+    );
+    r.unwrap()
+}
+
+/// Checks that VTL1 can inject an external interrupt and an exception into
+/// the suspended VTL0 of its VP through the pending interruption register,
+/// that each is delivered exactly once as VTL0 resumes, and that an event
+/// withdrawn before VTL0 resumes is not delivered.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: InterruptPlatformTrait + VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    let r = ctx.setup_interrupt_handler();
+    tmk_assert!(r.is_ok(), "setup_interrupt_handler should succeed");
+    let r = ctx.set_interrupt_idx(VECTOR, on_interrupt);
+    tmk_assert!(r.is_ok(), "set_interrupt_idx should succeed");
+    let r = ctx.set_interrupt_idx(EXCEPTION_VECTOR, on_exception);
+    tmk_assert!(r.is_ok(), "set_interrupt_idx should succeed");
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
+    let vp = ctx.get_current_vp();
+    tmk_assert!(vp.is_ok(), "get_current_vp should succeed");
+    let vp = vp.unwrap();
+    INTERRUPTS.store(0, Ordering::Relaxed);
+    EXCEPTIONS.store(0, Ordering::Relaxed);
+
+    let pending = inject_from_vtl1(ctx, vp, pending_event::interrupt(VECTOR), false);
+    tmk_assert!(
+        pending.interruption_pending() && pending.interruption_vector() == VECTOR.into(),
+        "the injected interrupt should read back as pending",
+        extra = u64::from(pending)
+    );
+    tmk_assert!(
+        INTERRUPTS.load(Ordering::Relaxed) == 1,
+        "the injected interrupt should be delivered once",
+        extra = INTERRUPTS.load(Ordering::Relaxed)
+    );
+    let after = pending_event::read(ctx, vp, Vtl::Vtl0);
+    tmk_assert!(
+        after.is_ok_and(|p| !p.interruption_pending()),
+        "no interruption should be pending once delivered"
+    );
+
+    inject_from_vtl1(ctx, vp, pending_event::interrupt(VECTOR), true);
+    tmk_assert!(
+        INTERRUPTS.load(Ordering::Relaxed) == 1,
+        "a withdrawn interrupt should not be delivered",
+        extra = INTERRUPTS.load(Ordering::Relaxed)
+    );
+
+    let event = pending_event::exception(EXCEPTION_VECTOR, None);
+    let pending = inject_from_vtl1(ctx, vp, event, false);
+    tmk_assert!(
+        u64::from(pending) == u64::from(event),
+        "the injected exception should read back unchanged",
+        extra = u64::from(pending)
+    );
+    tmk_assert!(
+        EXCEPTIONS.load(Ordering::Relaxed) == 1,
+        "the injected exception should be delivered once",
+        extra = EXCEPTIONS.load(Ordering::Relaxed)
+    );
+}
@@ -0,0 +1,54 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use hvdef::HvPartitionPrivilege;
+
+use crate::context::PrivilegePlatformTrait;
+use crate::platform::hyperv::privileges;
+use crate::tmk_assert;
+
+/// Tries a hypercall guarded by each privilege, records the advertised
+/// privileges against the observed behavior, and checks they agree. Also
+/// checks that overriding the privileges only changes what is reported.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: PrivilegePlatformTrait,
+{
+    let reported = ctx.get_privileges();
+    tmk_assert!(reported.is_ok(), "get_privileges should succeed");
+    let reported = reported.unwrap();
+
+    let checks = privileges::check(ctx);
+    tmk_assert!(checks.is_ok(), "checking privileges should succeed");
+    let checks = checks.unwrap();
+    privileges::write_record(reported, &checks);
+    for check in &checks {
+        tmk_assert!(
+            check.consistent(),
+            "a hypercall should be let through exactly when its privilege is advertised",
+            extra = check
+        );
+    }
+
+    ctx.set_privileges(Some(HvPartitionPrivilege::new()));
+    let overridden = ctx.get_privileges();
+    tmk_assert!(
+        overridden.is_ok_and(|p| p == HvPartitionPrivilege::new()),
+        "get_privileges should return the override"
+    );
+    let restricted = privileges::check(ctx);
+    tmk_assert!(
+        restricted.is_ok_and(|restricted| restricted
+            .iter()
+            .zip(&checks)
+            .all(|(r, c)| !r.reported && r.observed == c.observed)),
+        "the override should not change what the hypervisor lets through"
+    );
+
+    ctx.set_privileges(None);
+    let restored = ctx.get_privileges();
+    tmk_assert!(
+        restored.is_ok_and(|p| p == reported),
+        "clearing the override should restore the advertised privileges"
+    );
+}
@@ -4,18 +4,19 @@
 use hvdef::Vtl;
 use nostd_spin_channel::Channel;
 
+use crate::affinity;
 use crate::context::VirtualProcessorPlatformTrait;
 use crate::context::VpExecToken;
 use crate::context::VtlPlatformTrait;
 use crate::tmk_assert;
+use crate::tmk_setup;
 
 /// Executes a series of tests to validate VTL and VP functionalities.
 pub fn exec<T>(ctx: &mut T)
 where
     T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
 {
-    let r = ctx.setup_partition_vtl(Vtl::Vtl1);
-    tmk_assert!(r.is_ok(), "setup_partition_vtl should succeed");
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
 
     let vp_count = ctx.get_vp_count();
     tmk_assert!(vp_count.is_ok(), "get_vp_count should succeed");
@@ -48,7 +49,7 @@ pub fn exec<T>(ctx: &mut T)
         _ = rx.recv();
     }
 
-    for i in 1..vp_count {
+    for i in affinity::selected_vps(vp_count).into_iter().skip(1) {
         // Testing VTL1
         {
             let (tx, rx) = Channel::new().split();
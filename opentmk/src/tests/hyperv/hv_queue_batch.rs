@@ -0,0 +1,107 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use hvdef::Vtl;
+use nostd_spin_channel::Channel;
+use spin::Mutex;
+
+use crate::affinity;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+use crate::tmk_assert;
+use crate::tmk_setup;
+use crate::tmk_skip;
+
+const BATCH_LEN: usize = 64;
+const NOISE_LEN: usize = 512;
+
+const BATCH: u8 = 1;
+const NOISE: u8 = 2;
+
+/// Queues a batch of commands for the first AP selected, see
+/// [`affinity::selected_vps`], from the BSP while the second keeps queuing
+/// single commands for it, and checks the batch ran without any other
+/// command in between.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    let vp_count = ctx.get_vp_count();
+    tmk_assert!(vp_count.is_ok(), "get_vp_count should succeed");
+    let aps: Vec<u32> = affinity::selected_vps(vp_count.unwrap())
+        .into_iter()
+        .filter(|&vp| vp != 0)
+        .collect();
+    let &[target_vp, noise_vp, ..] = aps.as_slice() else {
+        tmk_skip!("needs at least 3 selected VPs");
+    };
+
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
+    for vp in [target_vp, noise_vp] {
+        let r = ctx.start_on_vp(VpExecToken::new(vp, Vtl::Vtl0).command(|_: &mut T| {}));
+        tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+    }
+
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let (tx, rx) = Channel::new().split();
+
+    let noise_order = order.clone();
+    let noise_tx = tx.clone();
+    let r = ctx.start_on_vp(
+        VpExecToken::new(noise_vp, Vtl::Vtl0).command(move |ctx: &mut T| {
+            for i in 0..NOISE_LEN {
+                let order = noise_order.clone();
+                let tx = noise_tx.clone();
+                let r = ctx.queue_command_vp(VpExecToken::new(target_vp, Vtl::Vtl0).command(
+                    move |_: &mut T| {
+                        order.lock().push(NOISE);
+                        if i == NOISE_LEN - 1 {
+                            _ = tx.send(NOISE);
+                        }
+                    },
+                ));
+                tmk_assert!(r.is_ok(), "queue_command_vp should succeed");
+            }
+        }),
+    );
+    tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+
+    let cmds = (0..BATCH_LEN)
+        .map(|i| {
+            let order = order.clone();
+            let tx = tx.clone();
+            Box::new(move |_: &mut T| {
+                order.lock().push(BATCH);
+                if i == BATCH_LEN - 1 {
+                    _ = tx.send(BATCH);
+                }
+            }) as Box<dyn FnOnce(&mut T) + Send>
+        })
+        .collect();
+    let r = ctx.queue_batch(target_vp, Vtl::Vtl0, cmds);
+    tmk_assert!(r.is_ok(), "queue_batch should succeed");
+
+    for _ in 0..2 {
+        tmk_assert!(rx.recv().is_ok(), "both command streams should complete");
+    }
+
+    let order = order.lock();
+    let first = order.iter().position(|&c| c == BATCH);
+    tmk_assert!(first.is_some(), "the batch should have run");
+    let first = first.unwrap();
+    let batch = &order[first..(first + BATCH_LEN).min(order.len())];
+    log::info!(
+        "batch ran at position {} of {} commands",
+        first,
+        order.len()
+    );
+    tmk_assert!(
+        batch.len() == BATCH_LEN && batch.iter().all(|&c| c == BATCH),
+        "no command should run in the middle of the batch"
+    );
+}
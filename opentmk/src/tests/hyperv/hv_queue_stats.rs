@@ -0,0 +1,87 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use hvdef::Vtl;
+use nostd_spin_channel::Channel;
+
+use crate::affinity;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+use crate::platform::hyperv::queue_stats;
+use crate::tmk_assert;
+use crate::tmk_setup;
+use crate::tmk_skip;
+use crate::tmkdefs::TmkError;
+
+/// Commands queued in a row for VTL0.
+const COMMANDS: u64 = 32;
+/// A VP index no queue is registered for.
+const MISSING_VP: u32 = u32::MAX;
+
+/// Queues commands for the first AP selected, see
+/// [`affinity::selected_vps`], some of them for VTL1 so that its
+/// command loop switches VTL twice, and one for a VP without a queue,
+/// checking that the queue counters account for each of them and that no
+/// command was lost besides the refused one.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    let vp_count = ctx.get_vp_count();
+    tmk_assert!(vp_count.is_ok(), "get_vp_count should succeed");
+    let Some(target_vp) = affinity::selected_vps(vp_count.unwrap())
+        .into_iter()
+        .find(|&vp| vp != 0)
+    else {
+        tmk_skip!("needs at least 2 selected VPs");
+    };
+
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
+    let r = ctx.start_on_vp(VpExecToken::new(target_vp, Vtl::Vtl0).command(|_: &mut T| {}));
+    tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+
+    let before = queue_stats::stats();
+    let (tx, rx) = Channel::new().split();
+    for vtl in [Vtl::Vtl0, Vtl::Vtl1, Vtl::Vtl0] {
+        for _ in 0..COMMANDS {
+            let tx = tx.clone();
+            let r =
+                ctx.queue_command_vp(VpExecToken::new(target_vp, vtl).command(move |_: &mut T| {
+                    _ = tx.send(());
+                }));
+            tmk_assert!(
+                r.is_ok(),
+                "queue_command_vp should succeed",
+                extra = vtl as u8
+            );
+        }
+    }
+    for _ in 0..COMMANDS * 3 {
+        tmk_assert!(rx.recv().is_ok(), "every queued command should run");
+    }
+
+    let r = ctx.queue_command_vp(VpExecToken::new(MISSING_VP, Vtl::Vtl0).command(|_: &mut T| {}));
+    tmk_assert!(
+        r == Err(TmkError::InvalidVpIndex),
+        "a command for a VP without a queue should be refused"
+    );
+
+    let added = queue_stats::stats().since(&before);
+    log::info!("command queue counters: {:?}", added);
+    tmk_assert!(
+        added.dequeued >= COMMANDS * 3,
+        "every command run should be counted as dequeued",
+        extra = added
+    );
+    tmk_assert!(
+        added.bounced >= 2,
+        "the command loop should be counted switching to VTL1 and back",
+        extra = added
+    );
+    tmk_assert!(
+        added.rejected == 1 && added.dropped == 0,
+        "only the refused command should be counted as lost",
+        extra = added
+    );
+}
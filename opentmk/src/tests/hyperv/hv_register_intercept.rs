@@ -10,10 +10,15 @@
 use crate::context::VirtualProcessorPlatformTrait;
 use crate::context::VtlPlatformTrait;
 use crate::create_function_with_restore;
+use crate::platform::hyperv::intercept;
 use crate::tmk_assert;
+use crate::tmk_setup;
 
 static FAULT_CALLED: Mutex<bool> = Mutex::new(false);
 
+/// Bytes of `violate_reg_rule` the intercepted WRMSR must lie in.
+const VIOLATION_CODE_SIZE: u64 = 0x40;
+
 // Without inline the compiler may optimize away the call and the VTL switch may
 // distort the architectural registers
 #[inline(never)]
@@ -44,6 +49,7 @@ pub fn exec<T>(ctx: &mut T)
         + VtlPlatformTrait
         + VirtualProcessorPlatformTrait<T>,
 {
+    use hvdef::HvVtlEntryReason;
     use hvdef::Vtl;
 
     use crate::context::VpExecToken;
@@ -57,8 +63,7 @@ pub fn exec<T>(ctx: &mut T)
     tmk_assert!(r.is_ok(), "setup_interrupt_handler should succeed");
     log::info!("set intercept handler successfully!");
 
-    let r = ctx.setup_partition_vtl(Vtl::Vtl1);
-    tmk_assert!(r.is_ok(), "setup_partition_vtl should succeed");
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
 
     let r = ctx.start_on_vp(VpExecToken::new(0, Vtl::Vtl1).command(move |ctx: &mut T| {
         log::info!("successfully started running VTL1 on vp0.");
@@ -66,7 +71,10 @@ pub fn exec<T>(ctx: &mut T)
         tmk_assert!(r.is_ok(), "setup_secure_intercept should succeed");
 
         let r = ctx.set_interrupt_idx(0x30, move || {
-            log::info!("interrupt handled for 0x30!");
+            crate::log_static!(log::Level::Info, "interrupt handled for 0x30!");
+            if intercept::capture_vtl0_context(0x30).is_err() {
+                crate::log_static!(log::Level::Error, "failed to capture the VTL0 context");
+            }
             let mut status = FAULT_CALLED.lock();
             *status = true;
         });
@@ -97,6 +105,24 @@ pub fn exec<T>(ctx: &mut T)
     let fault_called = *FAULT_CALLED.lock();
     tmk_assert!(fault_called, "Secure intercept should be received");
 
+    let reason = ctx.get_vtl_entry_reason(0);
+    tmk_assert!(
+        reason.is_ok_and(|r| r == HvVtlEntryReason::INTERRUPT || r == HvVtlEntryReason::INTERCEPT),
+        "VTL1 should be entered for the intercept rather than by a VTL call",
+        extra = reason.map(|r| r.0).ok()
+    );
+
+    let captured = intercept::take_captured(0);
+    tmk_assert!(captured.is_some(), "the VTL0 context should be captured");
+    let captured = captured.unwrap();
+    intercept::write_intercept_record(0, &captured);
+    let start = violate_reg_rule as usize as u64;
+    tmk_assert!(
+        (start..start + VIOLATION_CODE_SIZE).contains(&captured.rip),
+        "VTL0 should be interrupted at the WRMSR",
+        extra = captured
+    );
+
     log::info!("we are in vtl0 now!");
     log::info!("we reached the end of the test");
 }
@@ -0,0 +1,46 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use crate::handshake;
+use crate::rendezvous;
+use crate::rendezvous::Phases;
+use crate::tmk_assert;
+use crate::tmk_skip;
+use crate::tmkdefs::TmkError;
+
+/// VMs the harness boots for the test.
+const PARTIES: u32 = 2;
+/// Barriers met in a row.
+const PHASES: u32 = 4;
+/// Polls of the serial port without data before giving up on the other VM.
+const SERIAL_POLL_LIMIT: u64 = 1_000_000_000;
+
+/// Meets the other VM of the scenario at a series of barriers, checking
+/// that the harness releases each one, and that invalid barriers are
+/// refused without asking the harness.
+pub fn exec() {
+    if handshake::host_supports(handshake::RENDEZVOUS_CAPABILITY) != Some(true) {
+        tmk_skip!("the harness does not coordinate VMs");
+    }
+
+    let r = rendezvous::barrier("two words", PARTIES, SERIAL_POLL_LIMIT);
+    tmk_assert!(
+        r == Err(TmkError::InvalidParameter),
+        "a barrier name with a space should be refused"
+    );
+    let r = rendezvous::barrier("hv_rendezvous.alone", 1, SERIAL_POLL_LIMIT);
+    tmk_assert!(
+        r == Err(TmkError::InvalidParameter),
+        "a barrier for a single VM should be refused"
+    );
+
+    let mut phases = Phases::new("hv_rendezvous", PARTIES);
+    for phase in 0..PHASES {
+        let r = phases.advance(SERIAL_POLL_LIMIT);
+        tmk_assert!(
+            r.is_ok(),
+            "the harness should release the barrier",
+            extra = phase
+        );
+    }
+}
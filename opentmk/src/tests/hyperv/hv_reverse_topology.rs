@@ -0,0 +1,123 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::vec::Vec;
+
+use hvdef::Vtl;
+use nostd_spin_channel::Channel;
+
+use crate::affinity;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+use crate::platform::hyperv::ctx::active_vtl;
+use crate::tmk_assert;
+use crate::tmk_setup;
+use crate::tmk_skip;
+
+/// VTL0 workloads VTL1 runs on each VP.
+const ROUNDS: u32 = 8;
+/// Polls of a VP's VTL before giving up on it going back to VTL1.
+const SETTLE_POLLS: u64 = 100_000_000;
+
+/// Waits for `vp` to be back in VTL1. Returns whether it was in time.
+fn settles_in_vtl1(vp: u32) -> bool {
+    (0..SETTLE_POLLS).any(|_| {
+        core::hint::spin_loop();
+        active_vtl(vp) == Ok(Vtl::Vtl1)
+    })
+}
+
+/// Runs the command loops in the reverse topology, VTL1 idling on every
+/// selected VP, see [`affinity::selected_vps`], and VTL0 only entered for targeted workloads, and checks from a VTL1
+/// orchestrator on the BSP that the workloads run in VTL0 of the VP they
+/// target, that each VP goes back to VTL1 once its queue is empty, and that
+/// VTL1 commands run without leaving VTL1.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    let vp_count = ctx.get_vp_count();
+    tmk_assert!(vp_count.is_ok(), "get_vp_count should succeed");
+    let aps: Vec<u32> = affinity::selected_vps(vp_count.unwrap())
+        .into_iter()
+        .filter(|&vp| vp != 0)
+        .collect();
+    if aps.is_empty() {
+        tmk_skip!("needs at least 2 selected VPs");
+    }
+
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
+    for &vp in &aps {
+        let r = ctx.start_on_vp(VpExecToken::new(vp, Vtl::Vtl0).command(|_: &mut T| {}));
+        tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+    }
+
+    let r = ctx.set_idle_vtl(Some(Vtl::Vtl1));
+    tmk_assert!(r.is_ok(), "set_idle_vtl should succeed");
+    let idle: Vec<u32> = aps
+        .iter()
+        .copied()
+        .filter(|&vp| !settles_in_vtl1(vp))
+        .collect();
+    tmk_assert!(
+        idle.is_empty(),
+        "every VP should idle in VTL1",
+        extra = idle
+    );
+
+    let (token, result) = VpExecToken::new(0, Vtl::Vtl1).command_with_result(move |ctx: &mut T| {
+        let mut misplaced = Vec::new();
+        let mut stuck = Vec::new();
+        for vp in aps {
+            for _ in 0..ROUNDS {
+                let (tx, rx) = Channel::new().split();
+                let r = ctx.queue_command_vp(VpExecToken::new(vp, Vtl::Vtl0).command(
+                    move |ctx: &mut T| {
+                        _ = tx.send((ctx.get_current_vp(), ctx.get_current_vtl()));
+                    },
+                ));
+                tmk_assert!(r.is_ok(), "queue_command_vp should succeed");
+                let ran = rx.recv();
+                tmk_assert!(ran.is_ok(), "the workload should report back");
+                if ran.unwrap() != (Ok(vp), Ok(Vtl::Vtl0)) {
+                    misplaced.push(vp);
+                }
+                if !settles_in_vtl1(vp) {
+                    stuck.push(vp);
+                }
+            }
+
+            let (tx, rx) = Channel::new().split();
+            let r = ctx.queue_command_vp(VpExecToken::new(vp, Vtl::Vtl1).command(
+                move |ctx: &mut T| {
+                    _ = tx.send(ctx.get_current_vtl());
+                },
+            ));
+            tmk_assert!(r.is_ok(), "queue_command_vp should succeed");
+            let ran = rx.recv();
+            if ran != Ok(Ok(Vtl::Vtl1)) || active_vtl(vp) != Ok(Vtl::Vtl1) {
+                misplaced.push(vp);
+            }
+        }
+        (misplaced, stuck)
+    });
+    let r = ctx.start_on_vp(token);
+    tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+    let result = result.recv();
+    tmk_assert!(result.is_ok(), "the VTL1 orchestrator should report back");
+    let (misplaced, stuck) = result.unwrap();
+
+    let r = ctx.set_idle_vtl(None);
+    tmk_assert!(r.is_ok(), "set_idle_vtl should succeed");
+    tmk_assert!(
+        misplaced.is_empty(),
+        "every command should run on its VP in its VTL",
+        extra = misplaced
+    );
+    tmk_assert!(
+        stuck.is_empty(),
+        "every VP should go back to VTL1 after a VTL0 workload",
+        extra = stuck
+    );
+}
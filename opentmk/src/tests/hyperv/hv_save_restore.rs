@@ -0,0 +1,130 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::vec::Vec;
+
+use hvdef::HvMessageType;
+use hvdef::TimerMessagePayload;
+use zerocopy::FromBytes;
+
+use crate::handshake;
+use crate::host_action;
+use crate::platform::hyperv::save_restore;
+use crate::platform::hyperv::save_restore::GuestState;
+use crate::platform::hyperv::synic;
+use crate::platform::hyperv::synic::Synic;
+use crate::tmk_assert;
+use crate::tmk_skip;
+
+/// Polls of the serial port without data before giving up on the harness
+/// completing the save and restore.
+const SERIAL_POLL_LIMIT: u64 = 1_000_000_000;
+/// Words of the pattern checked across the save and restore.
+const PATTERN_WORDS: usize = 4096;
+/// SINT the pending timer delivers to. Polled, so the vector is never
+/// raised.
+const TIMER_SINT: u8 = 7;
+const TIMER_VECTOR: u8 = 0x34;
+const TIMER: u8 = 0;
+/// Reference time, in 100ns units, from arming the timer to its expiration,
+/// long enough for it to still be pending when the VM is saved.
+const TIMER_DELAY: u64 = 20_000_000;
+/// Reference time waited for the timer message past its expiration, and
+/// then for a duplicate.
+const MESSAGE_TIMEOUT: u64 = 50_000_000;
+const DUPLICATE_WAIT: u64 = 1_000_000;
+
+fn pattern(i: usize) -> u64 {
+    (i as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ 0x5a5a_5a5a_5a5a_5a5a
+}
+
+/// Polls `sint` until `deadline` for a timer message and returns its
+/// payload.
+fn wait_timer_message(synic: &Synic, deadline: u64) -> Option<TimerMessagePayload> {
+    while synic::reference_time() < deadline {
+        if let Some(message) = synic.poll_message(TIMER_SINT) {
+            if message.header.typ != HvMessageType::HvMessageTypeTimerExpired {
+                log::warn!("ignoring message {:?}", message.header.typ);
+                continue;
+            }
+            return TimerMessagePayload::read_from_prefix(&message.payload_buffer)
+                .ok()
+                .map(|(payload, _)| payload);
+        }
+        core::hint::spin_loop();
+    }
+    None
+}
+
+/// Asks the harness to save and restore the VM mid-test, and checks that
+/// the VP state, the counters and memory carry over and that a synthetic
+/// timer pending across the restore fires exactly once.
+pub fn exec() {
+    if handshake::host_supports(handshake::HOST_ACTION_CAPABILITY) == Some(false) {
+        tmk_skip!("the harness does not perform host actions");
+    }
+    let caps = synic::capabilities();
+    if !caps.polling || !caps.timers {
+        tmk_skip!("SINT polling mode or synthetic timers are not available");
+    }
+    let synic = Synic::enable();
+    tmk_assert!(synic.is_ok(), "synic enable should succeed");
+    let synic = synic.unwrap();
+    let r = synic.configure_sint(TIMER_SINT, TIMER_VECTOR, true);
+    tmk_assert!(r.is_ok(), "configuring the timer SINT should succeed");
+
+    let buffer: Vec<u64> = (0..PATTERN_WORDS).map(pattern).collect();
+    let expiration = synic::reference_time() + TIMER_DELAY;
+    let r = synic.arm_timer(TIMER, TIMER_SINT, expiration);
+    tmk_assert!(r.is_ok(), "arming the pending timer should succeed");
+    let before = GuestState::capture();
+
+    let r = host_action::perform("save_restore", "", SERIAL_POLL_LIMIT);
+    tmk_assert!(r.is_ok(), "the harness should save and restore the VM");
+
+    let after = GuestState::capture();
+    let discontinuities = save_restore::compare(&before, &after);
+    save_restore::write_record(&before, &after, &discontinuities);
+    tmk_assert!(
+        discontinuities.is_empty(),
+        "the VP state should carry over the save and restore"
+    );
+
+    // Keep the compiler from assuming the buffer still holds the pattern.
+    let mismatch = core::hint::black_box(&buffer)
+        .iter()
+        .enumerate()
+        .position(|(i, &word)| word != pattern(i));
+    tmk_assert!(
+        mismatch.is_none(),
+        "memory should survive the save and restore",
+        extra = mismatch
+    );
+
+    if after.reference_time >= expiration {
+        log::warn!("the timer expired before the restore completed");
+    }
+    let payload = wait_timer_message(
+        &synic,
+        expiration.max(after.reference_time) + MESSAGE_TIMEOUT,
+    );
+    tmk_assert!(
+        payload.is_some(),
+        "the timer pending across the restore should fire"
+    );
+    let payload = payload.unwrap();
+    tmk_assert!(
+        payload.timer_index == TIMER as u32 && payload.expiration_time == expiration,
+        "the timer message should be for the armed expiration",
+        extra = (payload.timer_index, payload.expiration_time, expiration)
+    );
+    tmk_assert!(
+        payload.delivery_time >= expiration,
+        "the timer should not fire early",
+        extra = (payload.delivery_time, expiration)
+    );
+    let duplicate = wait_timer_message(&synic, synic::reference_time() + DUPLICATE_WAIT);
+    tmk_assert!(duplicate.is_none(), "the timer should fire only once");
+    let r = synic.disarm_timer(TIMER);
+    tmk_assert!(r.is_ok(), "disarming the timer should succeed");
+}
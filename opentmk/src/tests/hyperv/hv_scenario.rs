@@ -0,0 +1,46 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use crate::scenario;
+use crate::scenario::ScenarioPlatform;
+use crate::tmk_assert;
+
+/// Polls of the serial port without data before giving up on the harness
+/// sending a scenario.
+const SERIAL_POLL_LIMIT: u64 = 1_000_000_000;
+
+/// Runs the scenario provided by the harness, from the UEFI variable if set
+/// and from the serial port otherwise.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: ScenarioPlatform,
+{
+    let text = match scenario::take_variable_scenario() {
+        Some(text) => text,
+        None => {
+            log::info!("no scenario variable, reading the scenario from serial");
+            let text = scenario::read_serial_scenario(SERIAL_POLL_LIMIT);
+            tmk_assert!(text.is_ok(), "scenario should be received over serial");
+            text.unwrap()
+        }
+    };
+
+    let lines = scenario::parse::parse(&text);
+    if let Err(e) = &lines {
+        log::error!("scenario line {}: {}", e.line, e.message);
+    }
+    tmk_assert!(lines.is_ok(), "scenario should parse");
+    let lines = lines.unwrap();
+    log::info!("running scenario of {} steps", lines.len());
+
+    let r = scenario::run(ctx, &lines);
+    if let Err(failure) = &r {
+        log::error!(
+            "scenario line {} ({:?}) failed: {}",
+            failure.line,
+            failure.step,
+            failure.error
+        );
+    }
+    tmk_assert!(r.is_ok(), "scenario should complete");
+}
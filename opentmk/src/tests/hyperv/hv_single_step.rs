@@ -0,0 +1,112 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+use crate::arch::single_step;
+use crate::context::InterruptPlatformTrait;
+use crate::tmk_assert;
+
+/// Most RIPs recorded per run.
+const MAX_STEPS: usize = 256;
+/// Offsets of the instructions of `opentmk_step_target`.
+const TARGET_OFFSETS: [u64; 6] = [0, 1, 3, 5, 7, 8];
+
+global_asm! {
+    ".globl opentmk_step_target",
+    "opentmk_step_target:",
+    "push rbx",
+    "xor eax, eax",
+    // Intercepted by the hypervisor, stepped as a single instruction.
+    "cpuid",
+    "inc eax",
+    "pop rbx",
+    "ret",
+}
+
+unsafe extern "sysv64" {
+    fn opentmk_step_target();
+}
+
+static STEPPED: [AtomicU64; MAX_STEPS] = [const { AtomicU64::new(0) }; MAX_STEPS];
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+fn record(rip: u64) -> bool {
+    let i = COUNT.fetch_add(1, Ordering::Relaxed);
+    if let Some(slot) = STEPPED.get(i) {
+        slot.store(rip, Ordering::Relaxed);
+    }
+    true
+}
+
+fn stop(_rip: u64) -> bool {
+    false
+}
+
+fn recorded() -> Vec<u64> {
+    let count = COUNT.swap(0, Ordering::Relaxed).min(MAX_STEPS);
+    STEPPED[..count]
+        .iter()
+        .map(|rip| rip.load(Ordering::Relaxed))
+        .collect()
+}
+
+/// Steps through a short sequence including an intercepted CPUID and checks
+/// that every instruction is reported in order, the intercepted one
+/// resuming at the next, then that stepping stops when disabled or when
+/// the callback asks to.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: InterruptPlatformTrait,
+{
+    let r = ctx.setup_interrupt_handler();
+    tmk_assert!(r.is_ok(), "setup_interrupt_handler should succeed");
+
+    let target = opentmk_step_target as usize as u64;
+    // SAFETY: the target only uses the registers it saves or may clobber.
+    let ((), steps) = single_step::run_stepped(record, || unsafe { opentmk_step_target() });
+    let rips = recorded();
+    tmk_assert!(
+        steps as usize >= TARGET_OFFSETS.len(),
+        "every instruction of the target should be stepped",
+        extra = steps
+    );
+    let start = rips.iter().position(|&rip| rip == target);
+    tmk_assert!(start.is_some(), "the target entry should be stepped");
+    let start = start.unwrap();
+    let observed: Vec<u64> = rips[start..]
+        .iter()
+        .take(TARGET_OFFSETS.len())
+        .map(|rip| rip - target)
+        .collect();
+    tmk_assert!(
+        observed == TARGET_OFFSETS,
+        "the instructions of the target should be stepped in order",
+        extra = observed
+    );
+
+    let mut sum = 0u64;
+    for i in 0..100 {
+        sum = core::hint::black_box(sum + i);
+    }
+    tmk_assert!(
+        single_step::steps() == steps && recorded().is_empty(),
+        "no instruction should be stepped once disabled",
+        extra = sum
+    );
+
+    single_step::enable(stop);
+    for i in 0..100 {
+        sum = core::hint::black_box(sum + i);
+    }
+    let steps = single_step::disable();
+    tmk_assert!(
+        steps == 1,
+        "stepping should stop when the callback asks to",
+        extra = steps
+    );
+}
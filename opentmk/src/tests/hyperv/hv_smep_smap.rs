@@ -0,0 +1,119 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::alloc::alloc_zeroed;
+use alloc::alloc::dealloc;
+use core::alloc::Layout;
+
+use crate::arch::env;
+use crate::arch::fault;
+use crate::arch::fault::PF_INSTRUCTION_FETCH;
+use crate::arch::fault::PF_PROTECTION;
+use crate::arch::fault::PF_WRITE;
+use crate::arch::features;
+use crate::arch::features::Feature;
+use crate::arch::paging;
+use crate::arch::paging::PageAccess;
+use crate::context::InterruptPlatformTrait;
+use crate::tmk_assert;
+use crate::tmk_skip;
+
+const PAGE_SIZE: usize = 4096;
+/// `ret`, so an executable probe returns if the fetch is not blocked.
+const RET_OPCODE: u8 = 0xc3;
+
+/// Maps a page as user accessible and checks that supervisor reads, writes
+/// and instruction fetches to it raise the page faults required by SMAP and
+/// SMEP rather than being silently allowed.
+///
+/// No ring-3 code runs here, only the supervisor side of the protections is
+/// exercised.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: InterruptPlatformTrait,
+{
+    let supported = features::supported();
+    if !supported.contains(Feature::SMEP | Feature::SMAP) {
+        log::warn!("SMEP/SMAP not supported ({:?}), skipping", supported);
+        return;
+    }
+
+    // The page fault error codes are only exact on page tables the TMK
+    // built itself.
+    if env::clean_env().is_none() {
+        tmk_skip!("the TMK environment was not adopted");
+    }
+
+    let r = ctx.setup_interrupt_handler();
+    tmk_assert!(r.is_ok(), "setup_interrupt_handler should succeed");
+
+    let layout = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap();
+    // SAFETY: the layout has a non-zero size.
+    let page = unsafe { alloc_zeroed(layout) };
+    tmk_assert!(!page.is_null(), "user page allocation should succeed");
+    // SAFETY: the page was just allocated.
+    unsafe { page.write_volatile(RET_OPCODE) };
+    let addr = page as u64;
+
+    let r = paging::set_page_access(
+        addr,
+        PageAccess {
+            user: true,
+            executable: true,
+        },
+    );
+    tmk_assert!(r.is_ok(), "mapping the page as user should succeed");
+    let original = r.unwrap();
+
+    // Without SMEP/SMAP the supervisor may touch the user page freely.
+    let r = features::disable(Feature::SMEP | Feature::SMAP);
+    tmk_assert!(r.is_ok(), "disabling SMEP/SMAP should succeed");
+    tmk_assert!(
+        fault::probe_read(addr) == Ok(RET_OPCODE),
+        "supervisor read of a user page should succeed without SMAP"
+    );
+    tmk_assert!(
+        fault::probe_exec(addr).is_ok(),
+        "supervisor execute of a user page should succeed without SMEP"
+    );
+
+    let r = features::enable(Feature::SMEP | Feature::SMAP);
+    tmk_assert!(r.is_ok(), "enabling SMEP/SMAP should succeed");
+
+    let r = fault::probe_read(addr);
+    tmk_assert!(r.is_err(), "SMAP should block supervisor reads");
+    let fault = r.unwrap_err();
+    tmk_assert!(fault.vector == 14, "SMAP read should raise #PF");
+    tmk_assert!(fault.address == addr, "SMAP read should report the address");
+    tmk_assert!(
+        fault.error_code & (PF_PROTECTION | PF_WRITE) == PF_PROTECTION,
+        "SMAP read should be a protection violation on a read"
+    );
+
+    let r = fault::probe_write(addr, 0);
+    tmk_assert!(r.is_err(), "SMAP should block supervisor writes");
+    let fault = r.unwrap_err();
+    tmk_assert!(fault.vector == 14, "SMAP write should raise #PF");
+    tmk_assert!(
+        fault.error_code & (PF_PROTECTION | PF_WRITE) == PF_PROTECTION | PF_WRITE,
+        "SMAP write should be a protection violation on a write"
+    );
+
+    let r = fault::probe_exec(addr);
+    tmk_assert!(r.is_err(), "SMEP should block supervisor execution");
+    let fault = r.unwrap_err();
+    tmk_assert!(fault.vector == 14, "SMEP fetch should raise #PF");
+    tmk_assert!(fault.rip == addr, "SMEP fetch should fault on the target");
+    tmk_assert!(
+        fault.error_code & (PF_PROTECTION | PF_INSTRUCTION_FETCH)
+            == PF_PROTECTION | PF_INSTRUCTION_FETCH,
+        "SMEP fault should be a protection violation on a fetch"
+    );
+
+    let r = features::disable(Feature::SMEP | Feature::SMAP);
+    tmk_assert!(r.is_ok(), "disabling SMEP/SMAP should succeed");
+    let r = paging::set_page_access(addr, original);
+    tmk_assert!(r.is_ok(), "restoring the page access should succeed");
+    // SAFETY: the page was allocated above with this layout.
+    unsafe { dealloc(page, layout) };
+}
@@ -0,0 +1,75 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use hvdef::Vtl;
+
+use crate::context::VtlPlatformTrait;
+use crate::devices::storvsc::ScsiAddress;
+use crate::devices::storvsc::StorvscClient;
+use crate::devices::vmbus::VmbusClient;
+use crate::tmk_assert;
+
+/// The LUN the harness attaches its test disk image at.
+const TEST_DISK: ScsiAddress = ScsiAddress {
+    path_id: 0,
+    target_id: 0,
+    lun: 0,
+};
+/// Logical block size of the test disk image.
+const BLOCK_SIZE: usize = 512;
+
+/// Contents of the first block of the test disk image: every byte holds its
+/// offset, truncated to 8 bits.
+fn expected_block() -> impl Iterator<Item = u8> {
+    (0..BLOCK_SIZE).map(|i| i as u8)
+}
+
+/// Initializes the synthetic SCSI channel, identifies the test disk with
+/// INQUIRY and checks that READ(16) of LBA 0 returns the contents of the
+/// harness disk image.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait,
+{
+    let vtl = ctx.get_current_vtl();
+    tmk_assert!(vtl.is_ok(), "get_current_vtl should succeed");
+    tmk_assert!(vtl.unwrap() == Vtl::Vtl0, "storvsc test should run in VTL0");
+
+    let vmbus = VmbusClient::connect();
+    tmk_assert!(vmbus.is_ok(), "vmbus connect should succeed");
+
+    let storvsc = StorvscClient::open(vmbus.unwrap());
+    tmk_assert!(storvsc.is_ok(), "scsi channel open should succeed");
+    let mut storvsc = storvsc.unwrap();
+
+    let version = storvsc.initialize();
+    tmk_assert!(
+        version.is_ok(),
+        "scsi channel initialization should succeed"
+    );
+
+    let inquiry = storvsc.inquiry(TEST_DISK);
+    tmk_assert!(inquiry.is_ok(), "inquiry should succeed");
+    let inquiry = inquiry.unwrap();
+    log::info!(
+        "test disk: {} {}",
+        core::str::from_utf8(&inquiry.vendor_id).unwrap_or("?"),
+        core::str::from_utf8(&inquiry.product_id).unwrap_or("?")
+    );
+    tmk_assert!(
+        inquiry.peripheral & 0x1f == 0,
+        "test disk should be a direct access block device"
+    );
+
+    let data = storvsc.read16(TEST_DISK, 0, 1, BLOCK_SIZE);
+    tmk_assert!(data.is_ok(), "read(16) of lba 0 should succeed");
+    let data = data.unwrap();
+    tmk_assert!(data.len() == BLOCK_SIZE, "read should return a full block");
+    tmk_assert!(
+        data.iter().copied().eq(expected_block()),
+        "lba 0 should match the harness disk image"
+    );
+
+    let r = storvsc.close();
+    tmk_assert!(r.is_ok(), "scsi channel close should succeed");
+}
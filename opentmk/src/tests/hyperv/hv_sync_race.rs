@@ -0,0 +1,87 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use hvdef::Vtl;
+use nostd_spin_channel::Channel;
+
+use crate::affinity;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+use crate::sync::ShardedCounter;
+use crate::sync::SyncMode;
+use crate::tmk_assert;
+use crate::tmk_setup;
+
+/// Increments done by each command.
+const ITERATIONS: u64 = 100_000;
+
+/// Has every selected AP, see [`affinity::selected_vps`], increment a
+/// shared counter from both VTL1 and VTL0 while the BSP does the same from
+/// VTL0, once per [`SyncMode`]. Locked and atomic increments must all be
+/// accounted for; unsynchronized ones are only reported, as losing them is
+/// expected.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
+
+    let vp_count = ctx.get_vp_count();
+    tmk_assert!(vp_count.is_ok(), "get_vp_count should succeed");
+    let vp_count = vp_count.unwrap();
+    let aps: Vec<u32> = affinity::selected_vps(vp_count)
+        .into_iter()
+        .filter(|&vp| vp != 0)
+        .collect();
+
+    for mode in [SyncMode::None, SyncMode::Lock, SyncMode::Atomic] {
+        let counter = Arc::new(ShardedCounter::new(vp_count as usize));
+        let (tx, rx) = Channel::new().split();
+
+        for &i in &aps {
+            for vtl in [Vtl::Vtl1, Vtl::Vtl0] {
+                let counter = counter.clone();
+                let tx = tx.clone();
+                let r = ctx.start_on_vp(VpExecToken::new(i, vtl).command(move |_ctx: &mut T| {
+                    let r = (0..ITERATIONS).try_for_each(|_| counter.increment(i as usize, mode));
+                    _ = tx.send(r);
+                }));
+                tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+            }
+        }
+
+        let r = (0..ITERATIONS).try_for_each(|_| counter.increment(0, mode));
+        tmk_assert!(r.is_ok(), "increments from the BSP should succeed");
+        for _ in 0..2 * aps.len() {
+            let r = rx.recv();
+            tmk_assert!(
+                matches!(r, Ok(Ok(()))),
+                "increments from the APs should succeed"
+            );
+        }
+
+        let expected = counter.expected();
+        let value = counter.value();
+        log::info!(
+            "{:?}: expected {} increments, counted {}, lost {}",
+            mode,
+            expected,
+            value,
+            counter.lost()
+        );
+        tmk_assert!(
+            expected == ITERATIONS * (2 * aps.len() as u64 + 1),
+            "every increment should be recorded in its shard"
+        );
+        if mode != SyncMode::None {
+            tmk_assert!(
+                value == expected,
+                format!("{:?} increments should not be lost", mode)
+            );
+        }
+    }
+}
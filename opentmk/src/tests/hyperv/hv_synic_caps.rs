@@ -0,0 +1,49 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use hvdef::Vtl;
+
+use crate::context::VtlPlatformTrait;
+use crate::platform::hyperv::synic;
+use crate::platform::hyperv::synic::Synic;
+use crate::tmk_assert;
+
+/// Vector used for the probed SINT, never delivered since it is polled or
+/// left idle.
+const TEST_VECTOR: u8 = 0x31;
+
+/// Checks the advertised SynIC capabilities against what the SINT MSRs
+/// accept: the last SINT is usable and keeps polling mode only when it is
+/// advertised.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait,
+{
+    let vtl = ctx.get_current_vtl();
+    tmk_assert!(vtl.is_ok(), "get_current_vtl should succeed");
+    tmk_assert!(vtl.unwrap() == Vtl::Vtl0, "synic test should run in VTL0");
+
+    let caps = synic::capabilities();
+    log::info!("synic capabilities: {:?}", caps);
+    tmk_assert!(caps.version != 0, "synic MSRs should be accessible");
+
+    let synic = Synic::enable();
+    tmk_assert!(synic.is_ok(), "synic enable should succeed");
+    let synic = synic.unwrap();
+
+    let last = hvdef::NUM_SINTS as u8 - 1;
+    let r = synic.configure_sint(last, TEST_VECTOR, caps.polling);
+    tmk_assert!(r.is_ok(), "configuring the last SINT should succeed");
+
+    let sint = synic.sint(last);
+    tmk_assert!(sint.is_ok(), "reading the last SINT should succeed");
+    let sint = sint.unwrap();
+    tmk_assert!(
+        sint.vector() == TEST_VECTOR && !sint.masked(),
+        "SINT should read back as configured"
+    );
+    tmk_assert!(
+        sint.polling() == caps.polling,
+        "SINT polling mode should stick only when advertised"
+    );
+}
@@ -0,0 +1,169 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::format;
+use alloc::vec::Vec;
+
+use hvdef::Vtl;
+
+use crate::affinity;
+use crate::context::SynicEventPlatformTrait;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::platform::hyperv::synic;
+use crate::platform::hyperv::synic::EVENT_FLAGS_PER_SINT;
+use crate::platform::hyperv::synic::EventConnections;
+use crate::platform::hyperv::synic::Synic;
+use crate::tmk_assert;
+use crate::tmk_skip;
+use crate::tmkdefs::TmkError;
+
+/// Vector of SINT 0; every SINT is polled, so none is raised.
+const VECTOR_BASE: u8 = 0x40;
+/// SINT whose event flags are all handed out.
+const EXHAUSTED_SINT: u8 = 6;
+/// A connection ID no port is bound to.
+const UNKNOWN_CONNECTION: u32 = 0x00ff_fffe;
+/// A message type guests may post.
+const MESSAGE_TYPE: u32 = 1;
+
+/// Configures every SINT of VP0, hands out every event flag of one of them
+/// and signals them all, then checks that going past each limit fails with
+/// the error the TLFS documents rather than corrupting state: a flag number
+/// out of range, a SINT number out of range on each VP
+/// [`affinity::selected_vps`] selects, a VP that does not exist, an unknown
+/// connection and a message larger than a message slot. Handing out one
+/// flag too many is refused by the TMK's own [`EventConnections`]
+/// bookkeeping, not the hypervisor.
+///
+/// The SINTs are restored before any check can fail, so that a failure
+/// does not leave them configured for the tests that follow.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: SynicEventPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    let caps = synic::capabilities();
+    if caps.version == 0 || !caps.polling {
+        tmk_skip!("the SynIC or SINT polling mode is not available");
+    }
+    let synic = Synic::current().map_or_else(Synic::enable, Ok);
+    tmk_assert!(synic.is_ok(), "enabling the SynIC should succeed");
+    let synic = synic.unwrap();
+    let vp_count = ctx.get_vp_count();
+    tmk_assert!(vp_count.is_ok(), "get_vp_count should succeed");
+    let vp_count = vp_count.unwrap();
+
+    let snapshot = synic.configure_all(VECTOR_BASE, true);
+    tmk_assert!(snapshot.is_ok(), "configuring every SINT should succeed");
+    let snapshot = snapshot.unwrap();
+    let misconfigured: Vec<u8> = (0..hvdef::NUM_SINTS as u8)
+        .filter(|&sint| {
+            !synic
+                .sint(sint)
+                .is_ok_and(|c| !c.masked() && c.polling() && c.vector() == VECTOR_BASE + sint)
+        })
+        .collect();
+
+    let mut connections = EventConnections::new(0, Vtl::Vtl0);
+    let mut connected = Vec::new();
+    let last = loop {
+        match connections.connect(EXHAUSTED_SINT) {
+            Ok(connection) => connected.push(connection),
+            Err(e) => break e,
+        }
+    };
+    let bookkeeping_ok =
+        connected.len() == EVENT_FLAGS_PER_SINT && last == TmkError::InsufficientBuffers;
+    if !bookkeeping_ok {
+        synic.restore_sints(&snapshot);
+    }
+    tmk_assert!(
+        bookkeeping_ok,
+        "the event flag bookkeeping should refuse a connection past the last flag",
+        extra = connected.len()
+    );
+
+    let r = connected[0].signal(ctx);
+    if let Err(TmkError::AccessDenied | TmkError::InvalidHypercallCode) = r {
+        synic.restore_sints(&snapshot);
+        tmk_skip!("HvCallSignalEventDirect is not permitted");
+    }
+    let newly_set = core::iter::once(r)
+        .chain(connected[1..].iter().map(|c| c.signal(ctx)))
+        .filter(|r| *r == Ok(true))
+        .count();
+    let delivered = connected
+        .iter()
+        .filter(|c| synic.take_event_flag(c.sint, c.flag))
+        .count();
+    connections.release_all();
+
+    let past_last_flag =
+        ctx.signal_event_direct(0, Vtl::Vtl0, EXHAUSTED_SINT, EVENT_FLAGS_PER_SINT as u16);
+    let past_last_sint: Vec<u32> = affinity::selected_vps(vp_count)
+        .into_iter()
+        .filter(|&vp| {
+            ctx.signal_event_direct(vp, Vtl::Vtl0, hvdef::NUM_SINTS as u8, 0)
+                != Err(TmkError::InvalidParameter)
+        })
+        .collect();
+    let missing_vp = ctx.signal_event_direct(vp_count, Vtl::Vtl0, EXHAUSTED_SINT, 0);
+    let unknown_signal = ctx.signal_event(UNKNOWN_CONNECTION, 0);
+    let unknown_post = ctx.post_message(UNKNOWN_CONNECTION, MESSAGE_TYPE, &[0; 16]);
+    let oversized = ctx.post_message_unchecked(
+        UNKNOWN_CONNECTION,
+        MESSAGE_TYPE,
+        hvdef::HV_MESSAGE_PAYLOAD_SIZE as u32 + 1,
+    );
+
+    synic.restore_sints(&snapshot);
+    tmk_assert!(
+        synic.snapshot_sints() == snapshot,
+        "every SINT should be restored"
+    );
+
+    tmk_assert!(
+        misconfigured.is_empty(),
+        "every SINT should read back as configured",
+        extra = misconfigured
+    );
+    tmk_assert!(
+        newly_set == EVENT_FLAGS_PER_SINT,
+        "every event flag of the SINT should be set",
+        extra = newly_set
+    );
+    tmk_assert!(
+        delivered == EVENT_FLAGS_PER_SINT,
+        "every event flag of the SINT should be observed",
+        extra = delivered
+    );
+    tmk_assert!(
+        past_last_flag == Err(TmkError::InvalidParameter),
+        "signaling a flag past the last should fail with InvalidParameter",
+        extra = format!("{:?}", past_last_flag)
+    );
+    tmk_assert!(
+        past_last_sint.is_empty(),
+        "signaling a SINT past the last should fail with InvalidParameter on every selected VP",
+        extra = past_last_sint
+    );
+    tmk_assert!(
+        missing_vp == Err(TmkError::InvalidVpIndex),
+        "signaling a VP that does not exist should fail with InvalidVpIndex",
+        extra = format!("{:?}", missing_vp)
+    );
+    tmk_assert!(
+        unknown_signal == Err(TmkError::InvalidConnectionId),
+        "signaling an unknown connection should fail with InvalidConnectionId",
+        extra = format!("{:?}", unknown_signal)
+    );
+    tmk_assert!(
+        unknown_post == Err(TmkError::InvalidConnectionId),
+        "posting to an unknown connection should fail with InvalidConnectionId",
+        extra = format!("{:?}", unknown_post)
+    );
+    tmk_assert!(
+        oversized == Err(TmkError::InvalidParameter),
+        "the hypervisor should refuse a message larger than a message slot with InvalidParameter",
+        extra = format!("{:?}", oversized)
+    );
+}
@@ -0,0 +1,63 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use crate::platform::hyperv::message_stress;
+use crate::platform::hyperv::message_stress::StressConfig;
+use crate::platform::hyperv::synic;
+use crate::platform::hyperv::synic::Synic;
+use crate::tmk_assert;
+use crate::tmk_skip;
+
+/// SINT flooded by the test. Polled, so the vector is never raised.
+const STRESS_SINT: u8 = 6;
+const STRESS_VECTOR: u8 = 0x33;
+
+/// Floods a SINT with synthetic timer messages while delaying each
+/// end-of-message, and checks that queued messages are announced with
+/// `message_pending` and delivered exactly once.
+pub fn exec() {
+    let caps = synic::capabilities();
+    if !caps.polling || !caps.timers {
+        tmk_skip!("SINT polling mode or synthetic timers are not available");
+    }
+
+    let synic = Synic::enable();
+    tmk_assert!(synic.is_ok(), "synic enable should succeed");
+    let synic = synic.unwrap();
+    let r = synic.configure_sint(STRESS_SINT, STRESS_VECTOR, true);
+    tmk_assert!(r.is_ok(), "configuring the stress SINT should succeed");
+
+    let config = StressConfig {
+        sint: STRESS_SINT,
+        rounds: 64,
+        timers: hvdef::NUM_TIMERS as u8,
+        // 10us between expirations, 100us before each EOM.
+        spacing: 100,
+        eom_delay: 1_000,
+    };
+    let stats = message_stress::run(&synic, &config);
+    tmk_assert!(stats.is_ok(), "the stress run should complete");
+    let stats = stats.unwrap();
+    message_stress::write_record(&config, &stats);
+
+    tmk_assert!(
+        stats.lost == 0 && stats.delivered == stats.expected,
+        "every expiration should be delivered",
+        extra = stats
+    );
+    tmk_assert!(
+        stats.duplicates == 0 && stats.unexpected == 0,
+        "no expiration should be delivered twice",
+        extra = stats
+    );
+    tmk_assert!(
+        stats.pending_missing == 0,
+        "a message with others queued behind it should have message_pending set",
+        extra = stats
+    );
+    tmk_assert!(
+        stats.released == stats.eoms,
+        "every EOM should release the message queued behind the slot",
+        extra = stats
+    );
+}
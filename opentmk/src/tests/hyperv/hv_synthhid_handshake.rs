@@ -0,0 +1,67 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use hvdef::Vtl;
+
+use crate::context::VtlPlatformTrait;
+use crate::devices::synthhid::SynthHidClient;
+use crate::devices::synthhid::protocol::HID_DESCRIPTOR_TYPE;
+use crate::devices::synthhid::protocol::REPORT_DESCRIPTOR_TYPE;
+use crate::devices::vmbus::VmbusClient;
+use crate::tmk_assert;
+
+/// Report descriptor prefix selecting the Generic Desktop usage page.
+const USAGE_PAGE_GENERIC_DESKTOP: [u8; 2] = [0x05, 0x01];
+
+/// Performs the synthetic HID version handshake and checks that the device
+/// info announced afterwards carries a well formed HID descriptor and the
+/// report descriptor it describes.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait,
+{
+    let vtl = ctx.get_current_vtl();
+    tmk_assert!(vtl.is_ok(), "get_current_vtl should succeed");
+    tmk_assert!(
+        vtl.unwrap() == Vtl::Vtl0,
+        "synthhid test should run in VTL0"
+    );
+
+    let vmbus = VmbusClient::connect();
+    tmk_assert!(vmbus.is_ok(), "vmbus connect should succeed");
+
+    let hid = SynthHidClient::open(vmbus.unwrap());
+    tmk_assert!(hid.is_ok(), "hid channel open should succeed");
+    let mut hid = hid.unwrap();
+
+    let version = hid.negotiate();
+    tmk_assert!(version.is_ok(), "hid version negotiation should succeed");
+
+    let device = hid.device_info();
+    tmk_assert!(device.is_ok(), "hid device info should be received");
+    let device = device.unwrap();
+    log::info!(
+        "hid device {:04x}:{:04x} version {:#x}, {} byte report descriptor",
+        device.vendor_id,
+        device.product_id,
+        device.version,
+        device.report_descriptor.len()
+    );
+    tmk_assert!(
+        device.descriptor.descriptor_type == HID_DESCRIPTOR_TYPE,
+        "descriptor should be a hid descriptor"
+    );
+    tmk_assert!(
+        device.descriptor.class_descriptor_type == REPORT_DESCRIPTOR_TYPE,
+        "hid descriptor should describe a report descriptor"
+    );
+    tmk_assert!(
+        device
+            .report_descriptor
+            .starts_with(&USAGE_PAGE_GENERIC_DESKTOP),
+        "report descriptor should start with the generic desktop usage page"
+    );
+
+    let r = hid.close();
+    tmk_assert!(r.is_ok(), "hid channel close should succeed");
+}
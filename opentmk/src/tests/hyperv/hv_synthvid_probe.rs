@@ -0,0 +1,94 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use hvdef::Vtl;
+use serde::Serialize;
+
+use crate::context::VtlPlatformTrait;
+use crate::devices::synthvid;
+use crate::devices::synthvid::SynthVidClient;
+use crate::devices::vmbus::VmbusClient;
+use crate::tmk_assert;
+use crate::tmk_skip;
+
+const WIDTH: u32 = 1024;
+const HEIGHT: u32 = 768;
+
+/// Colors of the vertical bars of the test pattern, as XRGB.
+const BARS: [u32; 8] = [
+    0x00ffffff, 0x00ffff00, 0x0000ffff, 0x0000ff00, 0x00ff00ff, 0x00ff0000, 0x000000ff, 0x00000000,
+];
+
+/// Tells the harness the test pattern is on screen.
+#[derive(Serialize)]
+struct ScreenshotRecord {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    test: &'static str,
+    width: u32,
+    height: u32,
+}
+
+/// Negotiates the synthetic video protocol, places the VRAM where the
+/// harness said, sets a mode and draws eight vertical color bars for the
+/// harness to compare against a reference screenshot.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait,
+{
+    let vtl = ctx.get_current_vtl();
+    tmk_assert!(vtl.is_ok(), "get_current_vtl should succeed");
+    tmk_assert!(
+        vtl.unwrap() == Vtl::Vtl0,
+        "synthvid test should run in VTL0"
+    );
+
+    let Some(vram_gpa) = synthvid::vram_gpa() else {
+        tmk_skip!("the harness named no VRAM location");
+    };
+
+    let vmbus = VmbusClient::connect();
+    tmk_assert!(vmbus.is_ok(), "vmbus connect should succeed");
+
+    let video = SynthVidClient::open(vmbus.unwrap());
+    tmk_assert!(video.is_ok(), "video channel open should succeed");
+    let mut video = video.unwrap();
+
+    let version = video.negotiate();
+    tmk_assert!(version.is_ok(), "video version negotiation should succeed");
+
+    let vram_size = video.vram_size();
+    let r = video.set_vram_location(vram_gpa, vram_size);
+    tmk_assert!(r.is_ok(), "vram location should be acked");
+
+    let mode = video.set_mode(WIDTH, HEIGHT);
+    tmk_assert!(mode.is_ok(), "mode set should be acked");
+
+    let bar_width = WIDTH / BARS.len() as u32;
+    let mut failed_pixels = 0u32;
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let color = BARS[(x / bar_width) as usize];
+            if video.put_pixel(x, y, color).is_err() {
+                failed_pixels += 1;
+            }
+        }
+    }
+    tmk_assert!(
+        failed_pixels == 0,
+        "every pixel write should succeed",
+        extra = failed_pixels
+    );
+    let r = video.mark_dirty();
+    tmk_assert!(r.is_ok(), "dirty notification should succeed");
+
+    crate::tmk_logger::write_record(&ScreenshotRecord {
+        record_type: "screenshot",
+        test: "hv_synthvid_probe",
+        width: WIDTH,
+        height: HEIGHT,
+    });
+
+    let r = video.close();
+    tmk_assert!(r.is_ok(), "video channel close should succeed");
+}
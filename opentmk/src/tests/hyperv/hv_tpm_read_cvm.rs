@@ -13,6 +13,7 @@
 use crate::context::VtlPlatformTrait;
 use crate::devices::tpm::{TpmDevice, TpmUtil};
 use crate::tmk_assert;
+use crate::tmk_setup;
 
 /// Executes a series of tests to validate TPM read violation in a Hyper-V environment.
 pub fn exec<T>(ctx: &mut T)
@@ -51,8 +52,7 @@ pub fn exec<T>(ctx: &mut T)
     let r = ctx.setup_interrupt_handler();
     tmk_assert!(r.is_ok(), "setup_interrupt_handler should succeed");
     log::info!("set intercept handler successfully!");
-    let r = ctx.setup_partition_vtl(Vtl::Vtl1);
-    tmk_assert!(r.is_ok(), "setup_partition_vtl should succeed");
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
 
     let command_range = Range {
         start: tpm_gpa as u64,
@@ -71,7 +71,7 @@ pub fn exec<T>(ctx: &mut T)
     }));
 
     let r = ctx.set_interrupt_idx(18, || {
-        log::warn!("successfully intercepted interrupt 18");
+        crate::log_static!(log::Level::Warn, "successfully intercepted interrupt 18");
         panic!("MC should cause a system abort");
     });
     tmk_assert!(r.is_ok(), "set_interrupt_idx should succeed");
@@ -0,0 +1,40 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use crate::tmk_assert;
+use crate::tmk_skip;
+use crate::uefi::memory_map;
+
+/// Validates the memory map the firmware handed over when boot services
+/// were exited, and the RAM it describes against the size the harness
+/// configured.
+pub fn exec() {
+    let regions = memory_map::regions();
+    if regions.is_empty() {
+        tmk_skip!("no memory map was captured");
+    }
+    let expected = memory_map::memory_size();
+    if expected.is_none() {
+        log::warn!(
+            "{} is not set, not checking the RAM size",
+            memory_map::MEMORY_SIZE_VARIABLE
+        );
+    }
+    for region in &regions {
+        log::debug!(
+            "{:#x}..{:#x} {:?} {:?}",
+            region.start,
+            region.end(),
+            region.ty,
+            region.attributes
+        );
+    }
+
+    let violations = memory_map::validate(&regions, expected);
+    memory_map::write_record(&regions, expected, &violations);
+    tmk_assert!(
+        violations.is_empty(),
+        "the memory map should be consistent",
+        extra = violations
+    );
+}
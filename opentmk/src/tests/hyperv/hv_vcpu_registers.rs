@@ -0,0 +1,129 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::vec::Vec;
+
+use hvdef::Vtl;
+
+use crate::affinity;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+use crate::platform::hyperv::ctx::vp_started;
+use crate::platform::hyperv::vcpu::ARG_REGISTERS;
+use crate::platform::hyperv::vcpu::PC_REGISTER;
+use crate::platform::hyperv::vcpu::SP_REGISTER;
+use crate::platform::hyperv::vcpu::VirtualProcessor;
+use crate::tmk_assert;
+use crate::tmk_setup;
+use crate::tmk_skip;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// VP whose VTL1 drives the registers of the others.
+const DRIVER_VP: u32 = 0;
+
+fn pattern(vp: u32, i: usize) -> u64 {
+    0x5eed_0000_0000_0000 | (u64::from(vp) << 16) | i as u64
+}
+
+/// What VTL1 read back from a VP.
+struct Readback {
+    entry_point: u64,
+    stack_pointer: u64,
+    args: Vec<u64>,
+    too_many: TmkResult<()>,
+}
+
+/// Points VTL0 of `vp` at a pattern entry point, stack and arguments
+/// through the arch-neutral [`VirtualProcessor`], reads them back and the
+/// fault information, then restores the registers.
+fn drive<T: VtlPlatformTrait>(ctx: &mut T, vp: u32) -> TmkResult<Readback> {
+    let registers: Vec<u32> = [PC_REGISTER, SP_REGISTER]
+        .into_iter()
+        .chain(ARG_REGISTERS.iter().copied())
+        .collect();
+    let originals = registers
+        .iter()
+        .map(|&register| ctx.get_vp_register_on_vp(vp, register, Vtl::Vtl0))
+        .collect::<TmkResult<Vec<_>>>()?;
+    let args: Vec<u64> = (0..ARG_REGISTERS.len()).map(|i| pattern(vp, i)).collect();
+
+    let mut vcpu = VirtualProcessor::new(ctx, vp, Vtl::Vtl0);
+    vcpu.set_entry_point(pattern(vp, 0x100))?;
+    vcpu.set_stack(pattern(vp, 0x200))?;
+    let too_many = vcpu.set_args(&[0; 16][..ARG_REGISTERS.len() + 1]);
+    vcpu.set_args(&args)?;
+    let readback = Readback {
+        entry_point: vcpu.entry_point()?,
+        stack_pointer: vcpu.stack_pointer()?,
+        args: ARG_REGISTERS
+            .iter()
+            .map(|&register| ctx.get_vp_register_on_vp(vp, register, Vtl::Vtl0))
+            .collect::<TmkResult<Vec<_>>>()?,
+        too_many,
+    };
+    VirtualProcessor::new(ctx, vp, Vtl::Vtl0).fault_info()?;
+
+    registers
+        .iter()
+        .zip(originals)
+        .try_for_each(|(&register, value)| {
+            ctx.set_vp_register_on_vp(vp, register, value, Vtl::Vtl0)
+        })?;
+    Ok(readback)
+}
+
+/// Has VTL1 on [`DRIVER_VP`] drive the VTL0 registers of each other
+/// selected VP through the arch-neutral [`VirtualProcessor`]: the entry
+/// point, stack and argument registers are loaded and read back, then
+/// restored, and the fault information read. The general purpose
+/// registers are shared between the VTLs of a VP, so only VPs that were not
+/// started, and run nothing, are driven. Nothing in the test is specific
+/// to an architecture.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    let vp_count = ctx.get_vp_count();
+    tmk_assert!(vp_count.is_ok(), "get_vp_count should succeed");
+    let targets: Vec<u32> = affinity::selected_vps(vp_count.unwrap())
+        .into_iter()
+        .filter(|&vp| vp != DRIVER_VP && !vp_started(vp))
+        .collect();
+    if targets.is_empty() {
+        tmk_skip!("needs a selected VP that was not started");
+    }
+
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
+    for vp in targets {
+        let (token, result) = VpExecToken::new(DRIVER_VP, Vtl::Vtl1)
+            .command_with_result(move |ctx: &mut T| drive(ctx, vp));
+        let r = ctx.start_on_vp(token);
+        tmk_assert!(r.is_ok(), "start_on_vp should succeed", extra = vp);
+        let result = result.recv();
+        tmk_assert!(
+            result.as_ref().is_ok_and(|r| r.is_ok()),
+            "VTL1 should access the VTL0 registers",
+            extra = vp
+        );
+        let readback = result.unwrap().unwrap();
+        tmk_assert!(
+            readback.entry_point == pattern(vp, 0x100)
+                && readback.stack_pointer == pattern(vp, 0x200),
+            "the entry point and stack should read back as set",
+            extra = (vp, readback.entry_point, readback.stack_pointer)
+        );
+        tmk_assert!(
+            readback.too_many == Err(TmkError::InvalidParameter),
+            "loading more arguments than registers should be refused",
+            extra = vp
+        );
+        let expected: Vec<u64> = (0..ARG_REGISTERS.len()).map(|i| pattern(vp, i)).collect();
+        tmk_assert!(
+            readback.args == expected,
+            "the argument registers should read back as loaded",
+            extra = vp
+        );
+    }
+}
@@ -0,0 +1,80 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::alloc::alloc_zeroed;
+use core::alloc::Layout;
+
+use hvdef::HV_PAGE_SIZE;
+
+use crate::devices::vmbus::VmbusClient;
+use crate::devices::vmbus::gpadl::Gpadl;
+use crate::devices::vmbus::gpadl::GpadlMessages;
+use crate::devices::vmbus::protocol;
+use crate::devices::vmbus::protocol::GpaRange;
+use crate::tmk_assert;
+use crate::tmk_skip;
+
+/// Large enough for the page list to need body messages.
+const BUFFER_PAGES: usize = 64;
+
+/// Creates and tears down a GPADL spanning several messages on the first
+/// offered channel, then checks that the host refuses a GPADL whose range
+/// claims more pages than it lists.
+pub fn exec() {
+    let vmbus = VmbusClient::connect();
+    tmk_assert!(vmbus.is_ok(), "vmbus connect should succeed");
+    let mut vmbus = vmbus.unwrap();
+    let Some(offer) = vmbus.offers().first().copied() else {
+        tmk_skip!("no vmbus channel offered");
+    };
+
+    let layout = Layout::from_size_align(BUFFER_PAGES * HV_PAGE_SIZE as usize, 4096).unwrap();
+    // SAFETY: the layout has a non-zero size. The buffer is shared with the
+    // host, so it is never freed.
+    let base = unsafe { alloc_zeroed(layout) };
+    tmk_assert!(!base.is_null(), "the buffer should be allocated");
+
+    let gpadl = Gpadl::new().range(base as u64, layout.size());
+    let messages = GpadlMessages::new(offer.channel_id, 0, &gpadl);
+    tmk_assert!(messages.is_ok(), "the gpadl should fit in its header");
+    log::info!(
+        "gpadl takes {} body messages",
+        messages.unwrap().bodies.len()
+    );
+    let gpadl_id = vmbus.create_gpadl(offer.channel_id, &gpadl);
+    tmk_assert!(gpadl_id.is_ok(), "creating the gpadl should succeed");
+    let gpadl_id = gpadl_id.unwrap();
+    tmk_assert!(
+        vmbus.live_gpadls().contains(&(offer.channel_id, gpadl_id)),
+        "the gpadl should be tracked"
+    );
+
+    let r = vmbus.teardown_gpadl(offer.channel_id, gpadl_id);
+    tmk_assert!(r.is_ok(), "tearing down the gpadl should succeed");
+    tmk_assert!(
+        vmbus.live_gpadls().is_empty(),
+        "no gpadl should be left after the teardown"
+    );
+
+    let malformed = Gpadl::new().raw_range(
+        GpaRange {
+            len: 4 * HV_PAGE_SIZE as u32,
+            offset: 0,
+        },
+        &[base as u64 / HV_PAGE_SIZE],
+    );
+    let gpadl_id = vmbus.allocate_gpadl_id();
+    let r = GpadlMessages::new(offer.channel_id, gpadl_id, &malformed)
+        .and_then(|messages| vmbus.send_gpadl_messages(&messages));
+    tmk_assert!(r.is_ok(), "sending the malformed gpadl should succeed");
+    let status = vmbus.wait_gpadl_created(gpadl_id);
+    log::info!("malformed gpadl answered with {:x?}", status);
+    tmk_assert!(
+        status.is_ok_and(|s| s != protocol::STATUS_SUCCESS),
+        "the host should refuse the malformed gpadl"
+    );
+    tmk_assert!(
+        vmbus.live_gpadls().is_empty(),
+        "the refused gpadl should not be tracked"
+    );
+}
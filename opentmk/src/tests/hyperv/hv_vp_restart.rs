@@ -0,0 +1,75 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use hvdef::Vtl;
+use nostd_spin_channel::Channel;
+
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+use crate::tmk_assert;
+use crate::tmk_setup;
+
+/// Number of polls of the faulted VP set before giving up.
+const FAULT_POLL_LIMIT: u64 = 100_000_000;
+
+/// Faults a command on VP1 on purpose, restarts the VP from the BSP and
+/// checks that it executes commands again.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
+
+    ctx.set_crash_isolation(true);
+
+    // Bring VP1 up and make sure it runs commands.
+    {
+        let (tx, rx) = Channel::new().split();
+        let r = ctx.start_on_vp(VpExecToken::new(1, Vtl::Vtl0).command(move |_ctx: &mut T| {
+            _ = tx.send(());
+        }));
+        tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+        _ = rx.recv();
+    }
+
+    let r = ctx.queue_command_vp(VpExecToken::new(1, Vtl::Vtl0).command(|_ctx: &mut T| {
+        panic!("intentional fault on VP1");
+    }));
+    tmk_assert!(r.is_ok(), "faulting command should be queued");
+
+    let mut faulted = false;
+    for _ in 0..FAULT_POLL_LIMIT {
+        if ctx.get_faulted_vps().is_ok_and(|vps| vps.contains(&1)) {
+            faulted = true;
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    tmk_assert!(faulted, "VP1 should be reported as faulted");
+
+    let r = ctx.restart_vp(1, Vtl::Vtl0);
+    tmk_assert!(r.is_ok(), "restart_vp should succeed");
+
+    let faulted = ctx.get_faulted_vps();
+    tmk_assert!(
+        faulted.is_ok_and(|vps| vps.is_empty()),
+        "no VP should be faulted after the restart"
+    );
+
+    // The restarted VP must pick up new commands.
+    {
+        let (tx, rx) = Channel::new().split();
+        let r = ctx.start_on_vp(VpExecToken::new(1, Vtl::Vtl0).command(move |ctx: &mut T| {
+            _ = tx.send(ctx.get_current_vp());
+        }));
+        tmk_assert!(r.is_ok(), "start_on_vp should succeed after restart");
+        let vp = rx.recv();
+        tmk_assert!(
+            vp.is_ok_and(|vp| vp == Ok(1)),
+            "restarted VP1 should run commands"
+        );
+    }
+
+    ctx.set_crash_isolation(false);
+}
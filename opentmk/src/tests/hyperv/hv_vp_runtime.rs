@@ -0,0 +1,107 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use hvdef::Vtl;
+
+use crate::affinity;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+use crate::platform::hyperv::hv_processor;
+use crate::platform::hyperv::hv_processor::VtlRuntime;
+use crate::tmk_assert;
+use crate::tmk_setup;
+use crate::tmk_skip;
+use crate::tmkdefs::TmkResult;
+
+/// Reference time to spin for in each VTL, in 100ns units.
+const SPIN_TIME: u64 = 500_000;
+/// Share of the spin the spinning VP should at least run, in thousandths.
+/// Loose, the host may run other work on the same processor.
+const MIN_SHARE_PERMILLE: u64 = 100;
+/// Slack allowed above the elapsed time, for the skew between reading the
+/// run time and the reference time.
+const MAX_SHARE_PERMILLE: u64 = 1100;
+
+/// Spins on VP `vp` in the current VTL for [`SPIN_TIME`] past the
+/// reference time `start`, returning the reference time it stopped at.
+fn spin<T>(ctx: &mut T, vp: u32, start: u64) -> TmkResult<u64>
+where
+    T: VtlPlatformTrait,
+{
+    loop {
+        let now = hv_processor::sample(ctx, [vp])?[0].reference;
+        if now - start >= SPIN_TIME {
+            return Ok(now);
+        }
+    }
+}
+
+/// Spins on the current VP, first in VTL0 then in VTL1, and checks the run
+/// time the hypervisor accounts for it, overall and to each VTL, reporting
+/// the run time of every VP over the spin.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    let vp = ctx.get_current_vp();
+    tmk_assert!(vp.is_ok(), "get_current_vp should succeed");
+    let vp = vp.unwrap();
+    let vp_count = ctx.get_vp_count();
+    tmk_assert!(vp_count.is_ok(), "get_vp_count should succeed");
+    let vps = affinity::selected_vps(vp_count.unwrap());
+
+    let before = match hv_processor::sample(ctx, vps.iter().copied()) {
+        Ok(before) => before,
+        Err(e) => {
+            log::info!("reading the VP run time failed: {:?}", e);
+            tmk_skip!("the VP run time is not available");
+        }
+    };
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
+    let meter = VtlRuntime::start(ctx);
+    tmk_assert!(meter.is_ok(), "starting the VTL accounting should succeed");
+    let mut meter = meter.unwrap();
+
+    let start = before.iter().find(|s| s.vp == vp).map(|s| s.reference);
+    tmk_assert!(start.is_some(), "the current VP should be sampled");
+    let r = spin(ctx, vp, start.unwrap()).and_then(|end| meter.switch(ctx).map(|()| end));
+    tmk_assert!(
+        r.is_ok(),
+        "spinning in VTL0 should succeed",
+        extra = format!("{:?}", r)
+    );
+    let vtl0_end = r.unwrap();
+
+    let (token, result) =
+        VpExecToken::new(vp, Vtl::Vtl1).command_with_result(move |ctx: &mut T| {
+            spin(ctx, vp, vtl0_end).and_then(|_| meter.switch(ctx).map(|()| meter))
+        });
+    let r = ctx.start_on_vp(token);
+    tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+    let meter = result.recv();
+    tmk_assert!(
+        meter.as_ref().is_ok_and(|m| m.is_ok()),
+        "spinning in VTL1 should succeed"
+    );
+    let meter = meter.unwrap().unwrap();
+
+    let after = hv_processor::sample(ctx, vps.iter().copied());
+    tmk_assert!(after.is_ok(), "sampling the VPs again should succeed");
+    let deltas = hv_processor::runtime_deltas(&before, &after.unwrap());
+    hv_processor::write_record("spin", &deltas);
+
+    let own = deltas.iter().find(|d| d.vp == vp);
+    tmk_assert!(
+        own.is_some_and(|d| d.share_permille <= MAX_SHARE_PERMILLE)
+            && !hv_processor::starved(&deltas, MIN_SHARE_PERMILLE).contains(&vp),
+        "the spinning VP should have run for at least a tenth of the spin",
+        extra = own
+    );
+    let per_vtl = meter.per_vtl();
+    tmk_assert!(
+        per_vtl[0] > 0 && per_vtl[1] > 0 && per_vtl[2] == 0,
+        "the run time should be charged to VTL0 and VTL1",
+        extra = per_vtl
+    );
+}
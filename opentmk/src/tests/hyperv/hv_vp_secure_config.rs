@@ -0,0 +1,51 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use hvdef::Vtl;
+use nostd_spin_channel::Channel;
+
+use crate::affinity;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+use crate::tmk_assert;
+use crate::tmk_setup;
+
+/// Validates reading and writing the VTL0 secure configuration from VTL1 on
+/// every VP.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
+
+    let vp_count = ctx.get_vp_count();
+    tmk_assert!(vp_count.is_ok(), "get_vp_count should succeed");
+
+    for i in affinity::selected_vps(vp_count.unwrap()) {
+        let (tx, rx) = Channel::new().split();
+        let r = ctx.start_on_vp(VpExecToken::new(i, Vtl::Vtl1).command(move |ctx: &mut T| {
+            let config = ctx.get_vp_secure_config(Vtl::Vtl0);
+            tmk_assert!(config.is_ok(), "reading VTL0 secure config should succeed");
+            let config = config.unwrap();
+            log::info!("VP{} VTL0 secure config: {:?}", i, config);
+            tmk_assert!(
+                !config.mbec_enabled(),
+                "MBEC should be off when not enabled for the partition"
+            );
+
+            let r = ctx.set_vp_secure_config(Vtl::Vtl0, config.with_tlb_locked(true));
+            tmk_assert!(r.is_ok(), "locking the VTL0 TLB should read back");
+
+            let r = ctx.set_vp_secure_config(Vtl::Vtl0, config);
+            tmk_assert!(r.is_ok(), "restoring VTL0 secure config should succeed");
+
+            _ = tx.send(());
+            if i == 0 {
+                ctx.switch_to_low_vtl();
+            }
+        }));
+        tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+        _ = rx.recv();
+    }
+}
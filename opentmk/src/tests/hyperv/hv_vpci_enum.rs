@@ -0,0 +1,81 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use hvdef::Vtl;
+
+use crate::context::VtlPlatformTrait;
+use crate::devices::vmbus::VmbusClient;
+use crate::devices::vpci;
+use crate::devices::vpci::VpciClient;
+use crate::tmk_assert;
+use crate::tmk_skip;
+
+/// Offset of the BARs of the assigned device from the config window, at
+/// the start of the MMIO space the harness named.
+const BAR_OFFSET: u64 = 0x100_0000;
+
+/// Enumerates the devices assigned over VPCI, checks that the IDs reported
+/// over the channel match the device's config space, and maps the BARs of
+/// the first device in the MMIO space the harness named in
+/// [`vpci::MMIO_VARIABLE`].
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait,
+{
+    let vtl = ctx.get_current_vtl();
+    tmk_assert!(vtl.is_ok(), "get_current_vtl should succeed");
+    tmk_assert!(vtl.unwrap() == Vtl::Vtl0, "vpci test should run in VTL0");
+
+    let Some(config_window) = vpci::mmio_gpa() else {
+        tmk_skip!("the harness named no MMIO space for VPCI");
+    };
+
+    let vmbus = VmbusClient::connect();
+    tmk_assert!(vmbus.is_ok(), "vmbus connect should succeed");
+
+    let vpci = VpciClient::open(vmbus.unwrap(), config_window);
+    tmk_assert!(vpci.is_ok(), "vpci channel open should succeed");
+    let mut vpci = vpci.unwrap();
+
+    let version = vpci.negotiate();
+    tmk_assert!(version.is_ok(), "vpci version negotiation should succeed");
+
+    let devices = vpci.enter_d0().map(|d| d.to_vec());
+    tmk_assert!(devices.is_ok(), "vpci d0 entry should succeed");
+    let devices = devices.unwrap();
+    tmk_assert!(
+        !devices.is_empty(),
+        "at least one device should be assigned"
+    );
+
+    for device in &devices {
+        let ids = vpci.read_config(device.slot, 0);
+        tmk_assert!(
+            ids as u16 == device.pnp_id.vendor_id,
+            "config space vendor id should match bus relations"
+        );
+        tmk_assert!(
+            (ids >> 16) as u16 == device.pnp_id.device_id,
+            "config space device id should match bus relations"
+        );
+    }
+
+    let slot = devices[0].slot;
+    let bars = vpci.map_bars(slot, config_window + BAR_OFFSET);
+    tmk_assert!(bars.is_ok(), "mapping the bars should succeed");
+    for bar in bars.unwrap() {
+        tmk_assert!(
+            bar.address % bar.size == 0,
+            "bars should be naturally aligned"
+        );
+        let offset = 0x10 + 4 * bar.index as u16;
+        let readback = vpci.read_config(slot, offset) & !0xf;
+        tmk_assert!(
+            readback == bar.address as u32 & !0xf,
+            "bar should read back the assigned address"
+        );
+    }
+
+    let r = vpci.close();
+    tmk_assert!(r.is_ok(), "vpci channel close should succeed");
+}
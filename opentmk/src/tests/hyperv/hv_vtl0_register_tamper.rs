@@ -0,0 +1,104 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::alloc::alloc;
+use alloc::alloc::dealloc;
+use core::alloc::Layout;
+
+use hvdef::HvX64RegisterName;
+use hvdef::Vtl;
+
+use crate::affinity;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VtlPlatformTrait;
+use crate::tests::hyperv::test_helpers::tamper_vtl0_register;
+use crate::tmk_assert;
+use crate::tmk_setup;
+
+/// Value VTL1 writes to DR0, a canonical address that is never used since
+/// DR7 leaves the breakpoint disabled.
+const DR0_PATTERN: u64 = 0xffff_8000_dead_0000;
+
+const PAGE_SIZE: usize = 4096;
+/// The flag bits of CR3 below the address of the top level table.
+const CR3_FLAGS: u64 = 0xfff;
+
+fn page_layout() -> Layout {
+    Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap()
+}
+
+/// Copies the top level page table `cr3` points to into a new page and
+/// returns `cr3` pointing to the copy instead, which maps the same memory.
+/// Returns `cr3` unchanged if no page could be allocated.
+fn copy_top_level_table(cr3: u64) -> u64 {
+    // SAFETY: the layout has a non-zero size.
+    let copy = unsafe { alloc(page_layout()) };
+    if copy.is_null() {
+        return cr3;
+    }
+    // SAFETY: the page tables are identity mapped, and the copy is a page
+    // just allocated.
+    unsafe { core::ptr::copy_nonoverlapping((cr3 & !CR3_FLAGS) as *const u8, copy, PAGE_SIZE) };
+    copy as u64 | (cr3 & CR3_FLAGS)
+}
+
+/// Validates that VTL1 can modify the VTL0 registers of a suspended VP,
+/// that VTL0 observes the modification, and that VTL0 cannot modify VTL1
+/// registers in turn.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
+
+    let vp_count = ctx.get_vp_count();
+    tmk_assert!(vp_count.is_ok(), "get_vp_count should succeed");
+
+    let dr0 = HvX64RegisterName::Dr0.0;
+    let cr3 = HvX64RegisterName::Cr3.0;
+    for vp in affinity::selected_vps(vp_count.unwrap()) {
+        let r = tamper_vtl0_register(ctx, vp, dr0, move |_| DR0_PATTERN + vp as u64);
+        tmk_assert!(r.is_ok(), "VTL1 should be able to set VTL0 DR0");
+        let (old, new) = r.unwrap();
+
+        let observed = ctx.get_vp_register_on_vp(vp, dr0, Vtl::Vtl0);
+        tmk_assert!(observed.is_ok(), "VTL0 should be able to read DR0");
+        let observed = observed.unwrap();
+        tmk_assert!(
+            observed == new,
+            "VTL0 should observe the DR0 written by VTL1",
+            extra = serde_json::json!({
+                "vp": vp,
+                "original": old,
+                "written": new,
+                "observed": observed,
+            })
+        );
+
+        let r = ctx.set_vp_register_on_vp(vp, dr0, old, Vtl::Vtl0);
+        tmk_assert!(r.is_ok(), "VTL0 should be able to restore DR0");
+
+        // Pointing CR3 at a copy of the top level table changes the value
+        // without changing what VTL0 sees mapped.
+        let r = tamper_vtl0_register(ctx, vp, cr3, copy_top_level_table);
+        tmk_assert!(r.is_ok(), "VTL1 should be able to set VTL0 CR3");
+        let (old, new) = r.unwrap();
+        tmk_assert!(
+            new != old,
+            "a copy of the top level page table should be allocated"
+        );
+        let observed = ctx.get_vp_register_on_vp(vp, cr3, Vtl::Vtl0);
+        tmk_assert!(
+            observed == Ok(new),
+            format!("VP{} VTL0 should observe the CR3 written by VTL1", vp)
+        );
+        let r = ctx.set_vp_register_on_vp(vp, cr3, old, Vtl::Vtl0);
+        tmk_assert!(r.is_ok(), "VTL0 should be able to restore CR3");
+        // SAFETY: the copy was allocated with the same layout, and no VP
+        // uses it since CR3 was restored.
+        unsafe { dealloc((new & !CR3_FLAGS) as *mut u8, page_layout()) };
+
+        let r = ctx.set_vp_register_on_vp(vp, dr0, DR0_PATTERN, Vtl::Vtl1);
+        tmk_assert!(r.is_err(), "VTL0 should not be able to set VTL1 DR0");
+    }
+}
@@ -0,0 +1,147 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::alloc::alloc;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::ops::Range;
+
+use hvdef::HvMapGpaFlags;
+use hvdef::Vtl;
+use nostd_spin_channel::Channel;
+
+use crate::context::InterruptPlatformTrait;
+use crate::context::SecureInterceptPlatformTrait;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlAccessPlatformTrait;
+use crate::context::VtlPlatformTrait;
+use crate::platform::hyperv::mmio_stub;
+use crate::platform::hyperv::mmio_stub::MmioRegion;
+use crate::platform::hyperv::vtl_access::Access;
+use crate::platform::hyperv::vtl_access::AccessCheck;
+use crate::platform::hyperv::vtl_access::AccessComparison;
+use crate::platform::hyperv::vtl_access::write_record;
+use crate::tests::hyperv::test_helpers::vtl0_access_allowed;
+use crate::tmk_assert;
+use crate::tmk_setup;
+
+const INTERCEPT_VECTOR: u8 = 0x30;
+const PAGE_SIZE: u64 = 4096;
+
+/// The protections applied to each page of the buffer, in order.
+const PROTECTIONS: [HvMapGpaFlags; 4] = [
+    hvdef::HV_MAP_GPA_PERMISSIONS_NONE,
+    HvMapGpaFlags::new().with_readable(true),
+    HvMapGpaFlags::new().with_readable(true).with_writable(true),
+    hvdef::HV_MAP_GPA_PERMISSIONS_ALL,
+];
+
+/// Applies a different VTL protection to each page of a buffer and checks
+/// that, for reads and writes, the protections applied, the access
+/// `HvCallCheckSparseGpaPageVtlAccess` reports for VTL0 and whether a VTL0
+/// access is actually intercepted all agree.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: InterruptPlatformTrait
+        + SecureInterceptPlatformTrait
+        + VtlAccessPlatformTrait
+        + VtlPlatformTrait
+        + VirtualProcessorPlatformTrait<T>,
+{
+    let r = ctx.setup_interrupt_handler();
+    tmk_assert!(r.is_ok(), "setup_interrupt_handler should succeed");
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
+
+    let size = PROTECTIONS.len() as u64 * PAGE_SIZE;
+    let layout = Layout::from_size_align(size as usize, PAGE_SIZE as usize).unwrap();
+    // SAFETY: the layout has a non-zero size. The buffer is never freed, it
+    // is protected while VTL0 runs.
+    let base = unsafe { alloc(layout) } as u64;
+    tmk_assert!(base != 0, "the buffer should be allocated");
+    let range = Range {
+        start: base,
+        end: base + size,
+    };
+    let gpns: Vec<u64> = range
+        .clone()
+        .step_by(PAGE_SIZE as usize)
+        .map(|gpa| gpa / PAGE_SIZE)
+        .collect();
+
+    // Accesses VTL1 intercepts read as zero and drop writes.
+    mmio_stub::install(MmioRegion::new(range.clone()));
+
+    let (tx, rx) = Channel::new().split();
+    let checked = gpns.clone();
+    let r = ctx.start_on_vp(VpExecToken::new(0, Vtl::Vtl1).command(move |ctx: &mut T| {
+        let r = ctx.setup_secure_intercept(INTERCEPT_VECTOR);
+        tmk_assert!(r.is_ok(), "setup_secure_intercept should succeed");
+        let r = ctx.set_interrupt_idx(INTERCEPT_VECTOR, mmio_stub::handle_intercept);
+        tmk_assert!(r.is_ok(), "set_interrupt_idx should succeed");
+        let r = ctx.setup_vtl_protection();
+        tmk_assert!(r.is_ok(), "setup_vtl_protection should succeed");
+
+        for (&gpn, &flags) in checked.iter().zip(&PROTECTIONS) {
+            let page = gpn * PAGE_SIZE..(gpn + 1) * PAGE_SIZE;
+            let r = ctx.set_vtl_protection_mask(page, Vtl::Vtl1, flags);
+            tmk_assert!(r.is_ok(), "set_vtl_protection_mask should succeed");
+        }
+
+        let mut reports: Vec<(Access, Vec<AccessCheck>)> = Vec::new();
+        for access in Access::ALL {
+            let r = ctx.check_vtl_access(&checked, Vtl::Vtl0, access.flags());
+            tmk_assert!(r.is_ok(), "check_vtl_access should succeed");
+            let checks = r.unwrap();
+            tmk_assert!(
+                checks.len() == checked.len(),
+                "every page should be checked",
+                extra = checks.len()
+            );
+            reports.push((access, checks));
+        }
+        _ = tx.send(reports);
+        ctx.switch_to_low_vtl();
+    }));
+    tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+    let reports = rx.recv();
+    tmk_assert!(reports.is_ok(), "VTL1 should report the checks");
+    let reports = reports.unwrap();
+
+    let mut comparisons = Vec::new();
+    for (access, checks) in &reports {
+        for (check, &flags) in checks.iter().zip(&PROTECTIONS) {
+            let observed = vtl0_access_allowed(ctx, check.gpn * PAGE_SIZE, *access);
+            tmk_assert!(observed.is_ok(), "the access should be made");
+            comparisons.push(AccessComparison {
+                gpn: check.gpn,
+                access: *access,
+                applied: access.is_granted(flags),
+                reported: check.allowed,
+                observed: observed.unwrap(),
+            });
+        }
+    }
+
+    let (_, unhandled) = mmio_stub::stats();
+    mmio_stub::uninstall();
+    let r = ctx.start_on_vp(VpExecToken::new(0, Vtl::Vtl1).command(move |ctx: &mut T| {
+        let r = ctx.remove_vtl_protection_for_memory(range, Vtl::Vtl1);
+        tmk_assert!(r.is_ok(), "remove_vtl_protection_for_memory should succeed");
+        ctx.switch_to_low_vtl();
+    }));
+    tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+
+    write_record(&comparisons);
+    tmk_assert!(
+        unhandled == 0,
+        "every intercepted access should be served",
+        extra = unhandled
+    );
+    let inconsistent: Vec<_> = comparisons.iter().filter(|c| !c.consistent()).collect();
+    tmk_assert!(
+        inconsistent.is_empty(),
+        "applied, reported and observed access should agree",
+        extra = inconsistent
+    );
+}
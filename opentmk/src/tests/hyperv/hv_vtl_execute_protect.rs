@@ -0,0 +1,156 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::vec::Vec;
+
+use hvdef::HvMapGpaFlags;
+use hvdef::Vtl;
+use nostd_spin_channel::Channel;
+
+use crate::arch::env;
+use crate::arch::fault;
+use crate::arch::paging;
+use crate::arch::paging::PageAccess;
+use crate::context::InterruptPlatformTrait;
+use crate::context::SecureInterceptPlatformTrait;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlAccessPlatformTrait;
+use crate::context::VtlPlatformTrait;
+use crate::platform::hyperv::exec_probe;
+use crate::platform::hyperv::exec_probe::CodePage;
+use crate::platform::hyperv::vtl_access::Access;
+use crate::platform::hyperv::vtl_access::AccessComparison;
+use crate::platform::hyperv::vtl_access::write_record;
+use crate::tests::hyperv::test_helpers::vtl0_access_allowed;
+use crate::tmk_assert;
+use crate::tmk_setup;
+use crate::tmk_skip;
+
+const INTERCEPT_VECTOR: u8 = 0x30;
+
+/// The protections applied to the code page, in order: execution withheld,
+/// then granted. MBEC is not enabled for the partition, so the kernel
+/// execute permission alone decides.
+const PROTECTIONS: [HvMapGpaFlags; 2] = [
+    HvMapGpaFlags::new().with_readable(true).with_writable(true),
+    hvdef::HV_MAP_GPA_PERMISSIONS_ALL,
+];
+
+/// Checks that VTL0 cannot run code in a page VTL1 withholds execute
+/// permission from, and can once it is granted.
+///
+/// The guest page tables are checked first: with the page marked
+/// non-executable, the call raises a page fault in VTL0 itself. With the
+/// page executable, VTL1 applies each protection, asks the hypervisor
+/// whether VTL0 may execute the page and has VTL0 call the function on it,
+/// and the three views are compared.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: InterruptPlatformTrait
+        + SecureInterceptPlatformTrait
+        + VtlAccessPlatformTrait
+        + VtlPlatformTrait
+        + VirtualProcessorPlatformTrait<T>,
+{
+    // Execute-disable must be on, whatever the firmware left in EFER.
+    if env::clean_env().is_none() {
+        tmk_skip!("the TMK environment was not adopted");
+    }
+    let r = ctx.setup_interrupt_handler();
+    tmk_assert!(r.is_ok(), "setup_interrupt_handler should succeed");
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
+
+    let page = CodePage::new();
+    tmk_assert!(page.is_ok(), "the code page should be set up");
+    let page = page.unwrap();
+    tmk_assert!(
+        page.call() == exec_probe::MAGIC,
+        "the code page should run before it is protected"
+    );
+
+    let nx = PageAccess {
+        user: false,
+        executable: false,
+    };
+    let r = paging::set_page_access(page.address(), nx);
+    tmk_assert!(r.is_ok(), "making the page non-executable should succeed");
+    let probe = fault::probe_exec(page.ret_address());
+    let r = paging::set_page_access(page.address(), r.unwrap());
+    tmk_assert!(r.is_ok(), "restoring the page access should succeed");
+    let error_code = probe.err().map(|f| f.error_code);
+    tmk_assert!(
+        error_code.is_some_and(|e| e & fault::PF_INSTRUCTION_FETCH != 0),
+        "executing a non-executable page should raise an instruction fetch fault",
+        extra = error_code
+    );
+
+    exec_probe::reset();
+    let range = page.range();
+    let gpn = range.start / hvdef::HV_PAGE_SIZE;
+    let r = ctx.start_on_vp(VpExecToken::new(0, Vtl::Vtl1).command(move |ctx: &mut T| {
+        let r = ctx.setup_secure_intercept(INTERCEPT_VECTOR);
+        tmk_assert!(r.is_ok(), "setup_secure_intercept should succeed");
+        let r = ctx.set_interrupt_idx(INTERCEPT_VECTOR, exec_probe::handle_intercept);
+        tmk_assert!(r.is_ok(), "set_interrupt_idx should succeed");
+        let r = ctx.setup_vtl_protection();
+        tmk_assert!(r.is_ok(), "setup_vtl_protection should succeed");
+        ctx.switch_to_low_vtl();
+    }));
+    tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+
+    let mut comparisons = Vec::new();
+    for flags in PROTECTIONS {
+        let (tx, rx) = Channel::new().split();
+        let protected = range.clone();
+        let r = ctx.start_on_vp(VpExecToken::new(0, Vtl::Vtl1).command(move |ctx: &mut T| {
+            let r = ctx.set_vtl_protection_mask(protected, Vtl::Vtl1, flags);
+            tmk_assert!(r.is_ok(), "set_vtl_protection_mask should succeed");
+            let r = ctx.check_vtl_access(&[gpn], Vtl::Vtl0, Access::Execute.flags());
+            tmk_assert!(r.is_ok(), "check_vtl_access should succeed");
+            _ = tx.send(r.unwrap().first().map(|c| c.allowed));
+            ctx.switch_to_low_vtl();
+        }));
+        tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+        let reported = rx.recv();
+        tmk_assert!(reported.is_ok(), "VTL1 should report the check");
+        let reported = reported.unwrap();
+        tmk_assert!(reported.is_some(), "the page should be checked");
+
+        let observed = vtl0_access_allowed(ctx, page.address(), Access::Execute);
+        tmk_assert!(observed.is_ok(), "the call should be made");
+        comparisons.push(AccessComparison {
+            gpn,
+            access: Access::Execute,
+            applied: Access::Execute.is_granted(flags),
+            reported: reported.unwrap(),
+            observed: observed.unwrap(),
+        });
+    }
+
+    let r = ctx.start_on_vp(VpExecToken::new(0, Vtl::Vtl1).command(move |ctx: &mut T| {
+        let r = ctx.remove_vtl_protection_for_memory(range, Vtl::Vtl1);
+        tmk_assert!(r.is_ok(), "remove_vtl_protection_for_memory should succeed");
+        ctx.switch_to_low_vtl();
+    }));
+    tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+
+    write_record(&comparisons);
+    let (intercepted, unhandled) = exec_probe::stats();
+    tmk_assert!(
+        unhandled == 0,
+        "every intercepted call should be served",
+        extra = unhandled
+    );
+    tmk_assert!(
+        intercepted == 1,
+        "only the call without execute permission should be intercepted",
+        extra = intercepted
+    );
+    let inconsistent: Vec<_> = comparisons.iter().filter(|c| !c.consistent()).collect();
+    tmk_assert!(
+        inconsistent.is_empty(),
+        "applied, reported and observed execute access should agree",
+        extra = inconsistent
+    );
+}
@@ -0,0 +1,132 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::alloc::alloc;
+use alloc::alloc::dealloc;
+use core::alloc::Layout;
+use core::ops::Range;
+
+use hvdef::Vtl;
+use nostd_spin_channel::Channel;
+use serde::Serialize;
+
+use crate::arch::cycles;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+use crate::platform::nested;
+use crate::tmk_assert;
+use crate::tmk_setup;
+
+const PAGE_SIZE: usize = 4096;
+/// Largest range measured nested, where each protection change also
+/// updates the page tables of the level below.
+const NESTED_MAX_RANGE_SIZE: u64 = 64 << 20;
+/// Largest buffer requested from the heap, half of the capped heap, which
+/// bounds the largest range measured.
+const MAX_BUFFER_SIZE: usize = 256 << 20;
+/// Each range is this many times larger than the previous one.
+const RANGE_GROWTH: u64 = 4;
+
+#[derive(Serialize)]
+struct ThroughputRecord {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    bytes: u64,
+    pages: u64,
+    /// Cycles spent in the rep hypercalls.
+    cycles: u64,
+    pages_per_second: Option<u64>,
+}
+
+fn write_record(bytes: u64, cycles: u64, tsc_hz: Option<u64>) {
+    let pages = bytes / PAGE_SIZE as u64;
+    let pages_per_second = match tsc_hz {
+        Some(hz) if cycles != 0 => Some((pages as u128 * hz as u128 / cycles as u128) as u64),
+        _ => None,
+    };
+    log::info!(
+        "protected {} pages in {} cycles, {:?} pages/s",
+        pages,
+        cycles,
+        pages_per_second
+    );
+    crate::tmk_logger::write_record(&ThroughputRecord {
+        record_type: "vtl_protect_throughput",
+        bytes,
+        pages,
+        cycles,
+        pages_per_second,
+    });
+}
+
+/// Allocate the largest page aligned buffer up to [`MAX_BUFFER_SIZE`].
+fn allocate_buffer() -> Option<(*mut u8, Layout)> {
+    let mut size = MAX_BUFFER_SIZE;
+    while size >= PAGE_SIZE {
+        let layout = Layout::from_size_align(size, PAGE_SIZE).ok()?;
+        // SAFETY: the layout has a non-zero size.
+        let ptr = unsafe { alloc(layout) };
+        if !ptr.is_null() {
+            return Some((ptr, layout));
+        }
+        size /= 2;
+    }
+    None
+}
+
+/// Measures how fast VTL1 can apply VTL protections to progressively larger
+/// ranges, from a page up to the largest buffer the heap provides, at most
+/// [`MAX_BUFFER_SIZE`], or [`NESTED_MAX_RANGE_SIZE`] when nested, through
+/// the ModifyVtlProtectionMask rep hypercalls, and reports the throughput
+/// of each as a `vtl_protect_throughput` record.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
+
+    let buffer = allocate_buffer();
+    tmk_assert!(buffer.is_some(), "a buffer to protect should be allocated");
+    let (ptr, layout) = buffer.unwrap();
+    let base = ptr as u64;
+    let max_range_size = if crate::platform::is_nested() {
+        nested::note_adapted("largest protected range reduced to 64MiB");
+        NESTED_MAX_RANGE_SIZE.min(layout.size() as u64)
+    } else {
+        layout.size() as u64
+    };
+
+    let (tx, rx) = Channel::new().split();
+    let r = ctx.start_on_vp(VpExecToken::new(0, Vtl::Vtl1).command(move |ctx: &mut T| {
+        let r = ctx.setup_vtl_protection();
+        tmk_assert!(r.is_ok(), "setup_vtl_protection should succeed");
+
+        let tsc_hz = cycles::frequency();
+        let mut size = PAGE_SIZE as u64;
+        while size <= max_range_size {
+            let range = Range {
+                start: base,
+                end: base + size,
+            };
+            let start = cycles::read();
+            let r = ctx.apply_vtl_protection_for_memory(range.clone(), Vtl::Vtl1);
+            let elapsed = cycles::read().wrapping_sub(start);
+            tmk_assert!(r.is_ok(), "apply_vtl_protection_for_memory should succeed");
+
+            let r = ctx.remove_vtl_protection_for_memory(range, Vtl::Vtl1);
+            tmk_assert!(r.is_ok(), "remove_vtl_protection_for_memory should succeed");
+            write_record(size, elapsed, tsc_hz);
+            size *= RANGE_GROWTH;
+        }
+
+        _ = tx.send(());
+        ctx.switch_to_low_vtl();
+    }));
+    tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+    _ = rx.recv();
+
+    // SAFETY: the buffer was allocated with `layout` and every protection
+    // applied to it was removed.
+    unsafe { dealloc(ptr, layout) };
+}
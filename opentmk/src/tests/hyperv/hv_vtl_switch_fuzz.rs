@@ -0,0 +1,59 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use hvdef::HvAllArchRegisterName;
+use hvdef::Vtl;
+
+use crate::affinity;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VtlPlatformTrait;
+use crate::tmk_assert;
+use crate::tmk_setup;
+use crate::vtl_fuzz;
+use crate::vtl_fuzz::FuzzConfig;
+
+/// Reference time the VPs fuzz for, 10s.
+const DURATION: u64 = 100_000_000;
+
+/// Has every selected VP make random VTL calls, hypercalls and accesses to
+/// a range shared across VPs and VTLs at once, and checks that none of
+/// them sees the state of its VTLs or of the range corrupted.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T> + 'static,
+{
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
+
+    let vp_count = ctx.get_vp_count();
+    tmk_assert!(vp_count.is_ok(), "get_vp_count should succeed");
+    let vps = affinity::selected_vps(vp_count.unwrap());
+
+    // The seed is taken from the clock so that runs differ; it is logged
+    // and recorded for a failing run to be replayed.
+    let seed = ctx.get_vp_register_with_vtl(HvAllArchRegisterName::TimeRefCount.0, Vtl::Vtl0);
+    tmk_assert!(seed.is_ok(), "reading the reference time should succeed");
+    let config = FuzzConfig {
+        seed: seed.unwrap(),
+        duration: DURATION,
+        words_per_vp: 512,
+        check_interval: 256,
+    };
+
+    let stats = vtl_fuzz::run(ctx, &vps, &config);
+    tmk_assert!(stats.is_ok(), "the fuzz run should complete");
+    let stats = stats.unwrap();
+    vtl_fuzz::write_records(&config, &stats);
+
+    for stats in &stats {
+        tmk_assert!(
+            stats.vtl_calls != 0 && stats.checks != 0,
+            "every VP should have made VTL calls and checks",
+            extra = (stats.vp, stats.vtl_calls, stats.checks)
+        );
+        tmk_assert!(
+            stats.violations == 0,
+            format!("VP{} should find no invariant broken", stats.vp),
+            extra = (config.seed, stats.first_violation)
+        );
+    }
+}
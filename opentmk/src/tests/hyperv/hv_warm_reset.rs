@@ -0,0 +1,65 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use crate::handshake;
+use crate::tmk_assert;
+use crate::tmk_skip;
+use crate::uefi::alloc::ALLOCATOR;
+use crate::uefi::warm_reset;
+use crate::uefi::warm_reset::PhaseMarker;
+
+const TEST: &str = "hv_warm_reset";
+
+/// Registered as needing boot services, and only run when the harness
+/// follows the TMK across a reset. On the first boot, leaves a phase marker
+/// on the boot volume and asks the firmware for a warm reset; on the next
+/// one, checks that the reset brought the TMK back with the marker intact,
+/// and removes it so that a later run starts over.
+pub fn exec() {
+    if handshake::host_supports(handshake::WARM_RESET_CAPABILITY) != Some(true) {
+        tmk_skip!("the harness does not follow warm resets");
+    }
+    tmk_assert!(
+        !ALLOCATOR.boot_services_exited(),
+        "boot services should still be running"
+    );
+
+    let Some(marker) = warm_reset::read_marker().filter(|m| m.test == TEST) else {
+        let marker = PhaseMarker {
+            test: TEST.into(),
+            phase: 1,
+            nonce: crate::arch::cycles::read(),
+        };
+        log::info!("phase 1: resetting with nonce {:#x}", marker.nonce);
+        warm_reset::reset(&marker);
+        tmk_assert!(false, "the warm reset should not return");
+        return;
+    };
+
+    log::info!(
+        "phase 2: resumed after phase {} with nonce {:#x}",
+        marker.phase,
+        marker.nonce
+    );
+    let expected = warm_reset::expected_nonce();
+    // Removed first, so that the next run starts over with phase 1 even if
+    // a check below fails.
+    tmk_assert!(
+        warm_reset::clear_marker(),
+        "the phase marker should be removed"
+    );
+    tmk_assert!(
+        marker.phase == 1,
+        "the reset should come after phase 1",
+        extra = marker.phase
+    );
+    tmk_assert!(
+        expected == Some(marker.nonce),
+        "the phase marker should be read back as written",
+        extra = (marker.nonce, expected)
+    );
+    tmk_assert!(
+        warm_reset::read_marker().is_none() && warm_reset::expected_nonce().is_none(),
+        "no phase marker should be left for the next boot"
+    );
+}
@@ -0,0 +1,153 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use alloc::alloc::alloc;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::ops::Range;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use hvdef::Vtl;
+
+use crate::context::InterruptPlatformTrait;
+use crate::context::SecureInterceptPlatformTrait;
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+use crate::platform::hyperv::watch;
+use crate::platform::hyperv::watch::WatchHit;
+use crate::tmk_assert;
+use crate::tmk_setup;
+
+const INTERCEPT_VECTOR: u8 = 0x30;
+const PAGE_SIZE: usize = 4096;
+
+/// Offset of the watched slots in the page.
+const WATCHED: u64 = 0x100;
+/// Size of the watched slots.
+const WATCHED_SIZE: u64 = 0x10;
+/// Offset of a slot in the same page that is not watched.
+const UNWATCHED: u64 = 0x200;
+
+const PATTERN: u64 = 0x5a5a_1234_dead_beef;
+
+/// Number of accesses VTL0 makes to the page, each needing VTL1 to return
+/// to VTL0 once stepped.
+const ACCESSES: u64 = 4;
+
+static CALLBACKS: AtomicU64 = AtomicU64::new(0);
+
+fn on_access(_hit: &WatchHit) {
+    CALLBACKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Has VTL1 watch a few slots of a page, then checks that the VTL0 loads
+/// and stores to them are recorded with their size, direction and RIP and
+/// take effect, that accesses to the rest of the page are stepped without
+/// being recorded, and that the watch stays armed throughout.
+pub fn exec<T>(ctx: &mut T)
+where
+    T: InterruptPlatformTrait
+        + SecureInterceptPlatformTrait
+        + VtlPlatformTrait
+        + VirtualProcessorPlatformTrait<T>,
+{
+    let r = ctx.setup_interrupt_handler();
+    tmk_assert!(r.is_ok(), "setup_interrupt_handler should succeed");
+    tmk_setup!("vsm", ctx.setup_partition_vtl(Vtl::Vtl1));
+
+    let layout = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap();
+    // SAFETY: the layout has a non-zero size. The page is never freed.
+    let page = unsafe { alloc(layout) };
+    tmk_assert!(!page.is_null(), "the watched page should be allocated");
+    let base = page as u64;
+    let range = Range {
+        start: base + WATCHED,
+        end: base + WATCHED + WATCHED_SIZE,
+    };
+
+    let watched = range.clone();
+    let r = ctx.start_on_vp(VpExecToken::new(0, Vtl::Vtl1).command(move |ctx: &mut T| {
+        let r = ctx.setup_secure_intercept(INTERCEPT_VECTOR);
+        tmk_assert!(r.is_ok(), "setup_secure_intercept should succeed");
+        let r = ctx.set_interrupt_idx(INTERCEPT_VECTOR, watch::handle_intercept);
+        tmk_assert!(r.is_ok(), "set_interrupt_idx should succeed");
+        let r = ctx.setup_vtl_protection();
+        tmk_assert!(r.is_ok(), "setup_vtl_protection should succeed");
+        let r = watch::watch(ctx, watched, on_access);
+        tmk_assert!(r.is_ok(), "watch should succeed");
+        ctx.switch_to_low_vtl();
+    }));
+    tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+
+    // VTL1 runs one of these after stepping each access.
+    for _ in 0..ACCESSES {
+        let r = ctx.queue_command_vp(VpExecToken::new(0, Vtl::Vtl1).command(|ctx: &mut T| {
+            ctx.switch_to_low_vtl();
+        }));
+        tmk_assert!(r.is_ok(), "queue_command_vp should succeed");
+    }
+
+    let slot = |offset: u64| (base + offset) as *mut u8;
+    // SAFETY: the slots are within the page allocated above, every access
+    // to which VTL1 steps.
+    let read = unsafe {
+        core::ptr::write_volatile(slot(WATCHED).cast::<u64>(), PATTERN);
+        core::ptr::write_volatile(slot(UNWATCHED).cast::<u32>(), 7);
+        let read = core::ptr::read_volatile(slot(WATCHED).cast::<u64>());
+        core::ptr::write_volatile(slot(WATCHED + WATCHED_SIZE - 1), 0xa5);
+        read
+    };
+
+    let stats = watch::stats();
+    let (token, hits) =
+        VpExecToken::new(0, Vtl::Vtl1).command_with_result(move |ctx: &mut T| watch::unwatch(ctx));
+    let r = ctx.start_on_vp(token);
+    tmk_assert!(r.is_ok(), "start_on_vp should succeed");
+    let hits = hits.recv();
+    tmk_assert!(
+        matches!(hits, Ok(Ok(_))),
+        "unwatch should return the accesses"
+    );
+    let hits = hits.unwrap().unwrap();
+    watch::write_hit_records(&hits);
+
+    tmk_assert!(read == PATTERN, "the load should read the value stored");
+    // SAFETY: VTL1 gave the page back to VTL0.
+    let (unwatched, last) = unsafe {
+        (
+            core::ptr::read_volatile(slot(UNWATCHED).cast::<u32>()),
+            core::ptr::read_volatile(slot(WATCHED + WATCHED_SIZE - 1)),
+        )
+    };
+    tmk_assert!(
+        unwatched == 7 && last == 0xa5,
+        "the stores should have reached memory",
+        extra = (unwatched, last)
+    );
+    tmk_assert!(
+        stats.hits == 3 && stats.passed == 1 && stats.disarmed == 0,
+        "the accesses to the slots should be recorded and the others passed",
+        extra = stats
+    );
+    tmk_assert!(
+        CALLBACKS.load(Ordering::Relaxed) == 3,
+        "on_access should be called on each recorded access"
+    );
+    let expected: [(u64, u8, bool); 3] = [
+        (range.start, 8, true),
+        (range.start, 8, false),
+        (range.end - 1, 1, true),
+    ];
+    let observed: Vec<_> = hits.iter().map(|h| (h.gpa, h.size, h.write)).collect();
+    tmk_assert!(
+        observed == expected,
+        "the accesses should be recorded in order",
+        extra = observed
+    );
+    tmk_assert!(
+        hits.iter().all(|h| h.vp_index == 0 && h.rip != 0),
+        "the accesses should be recorded with the VP and RIP making them"
+    );
+}
@@ -1,19 +1,151 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
+#[cfg(target_os = "uefi")]
+pub mod hv_alloc_below;
+#[cfg(target_os = "uefi")]
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_alloc_fault_injection;
+pub mod hv_alt_stack;
+#[cfg(target_os = "uefi")]
+pub mod hv_boot_services;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_cache_line_bounce;
+#[cfg(nightly)]
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_cache_types;
+pub mod hv_checkpoints;
+#[cfg(nightly)]
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_debug_registers;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_descriptor_tables;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_dm_hot_add;
 pub mod hv_error_vp_start;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_event_doorbell;
+pub mod hv_extended_hypercalls;
+pub mod hv_extended_paranoid;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_features;
+pub mod hv_fibers;
+#[cfg(nightly)]
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_gva_alias;
+#[cfg(target_os = "uefi")]
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_heap_quota;
+#[cfg(nightly)]
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_hypercall_page;
+pub mod hv_hypercall_paranoid;
+pub mod hv_hypercall_retry;
+#[cfg(target_os = "uefi")]
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_ic_shutdown;
+#[cfg(nightly)]
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_intercept_resume;
+#[cfg(nightly)]
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_ioapic_routing;
+#[cfg(nightly)]
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_irq_hvcall;
+#[cfg(nightly)]
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_irq_latency;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_legacy_timers;
+pub mod hv_long_spin_wait;
+pub mod hv_memory_dump;
 #[cfg(nightly)]
 pub mod hv_memory_protect_read;
 #[cfg(nightly)]
 pub mod hv_memory_protect_write;
+#[cfg(target_os = "uefi")]
+pub mod hv_memstress;
+#[cfg(nightly)]
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_mmio_stub;
+#[cfg(nightly)]
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_msr_conformance;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_netvsc_init;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_overlay_pages;
+#[cfg(nightly)]
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_pending_interruption;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_privilege_matrix;
 pub mod hv_processor;
+pub mod hv_queue_batch;
+pub mod hv_queue_stats;
 #[cfg(nightly)]
 #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
 pub mod hv_register_intercept;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_rendezvous;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_reverse_topology;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_save_restore;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_scenario;
+#[cfg(nightly)]
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_single_step;
+#[cfg(nightly)]
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_smep_smap;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_storvsc_read;
+pub mod hv_sync_race;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_synic_caps;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_synic_exhaust;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_synic_message_stress;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_synthhid_handshake;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_synthvid_probe;
 #[cfg(nightly)]
 #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
 pub mod hv_tpm_read_cvm;
 #[cfg(nightly)]
 #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
 pub mod hv_tpm_write_cvm;
+#[cfg(target_os = "uefi")]
+pub mod hv_uefi_memory_map;
+pub mod hv_vcpu_registers;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_vmbus_gpadl;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_vp_restart;
+pub mod hv_vp_runtime;
+pub mod hv_vp_secure_config;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_vpci_enum;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_vtl0_register_tamper;
+#[cfg(nightly)]
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_vtl_access_check;
+#[cfg(nightly)]
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_vtl_execute_protect;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_vtl_protect_throughput;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_vtl_switch_fuzz;
+#[cfg(target_os = "uefi")]
+pub mod hv_warm_reset;
+#[cfg(nightly)]
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub mod hv_watchpoint;
 pub mod test_helpers;
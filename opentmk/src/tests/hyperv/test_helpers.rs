@@ -1,6 +1,21 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
+use hvdef::Vtl;
+use nostd_spin_channel::Channel;
+
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+use crate::platform::hyperv::exec_probe;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+use crate::platform::hyperv::mmio_stub;
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+use crate::platform::hyperv::vtl_access::Access;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
 #[macro_export]
 /// Generates a function that calls the given symbol saving and restoring general purpose registers around the call.
 macro_rules! create_function_with_restore {
@@ -47,3 +62,78 @@ fn $func_name() {
         }
     };
 }
+
+/// Has VTL1 on `vp_index` replace the VTL0 register `register_index` of
+/// that VP with `tamper(current value)` while VTL0 is suspended, then
+/// returns to the caller in VTL0.
+///
+/// Returns the value before and after tampering, for the caller to check
+/// what VTL0 observes and to restore the register. VTL1 must be enabled for
+/// the partition.
+pub fn tamper_vtl0_register<T>(
+    ctx: &mut T,
+    vp_index: u32,
+    register_index: u32,
+    tamper: impl FnOnce(u64) -> u64 + Send + 'static,
+) -> TmkResult<(u64, u64)>
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    let caller = ctx.get_current_vp()?;
+    let (tx, rx) = Channel::new().split();
+    ctx.start_on_vp(
+        VpExecToken::new(vp_index, Vtl::Vtl1).command(move |ctx: &mut T| {
+            let r: TmkResult<(u64, u64)> = (|| {
+                let old = ctx.get_vp_register_on_vp(vp_index, register_index, Vtl::Vtl0)?;
+                let new = tamper(old);
+                ctx.set_vp_register_on_vp(vp_index, register_index, new, Vtl::Vtl0)?;
+                Ok((old, new))
+            })();
+            _ = tx.send(r);
+            if vp_index == caller {
+                ctx.switch_to_low_vtl();
+            }
+        }),
+    )?;
+    rx.recv().map_err(|_| TmkError::OperationFailed)?
+}
+
+/// Makes `access` to `gpa` from VTL0 on VP 0 and returns whether it went
+/// through without being intercepted by VTL1.
+///
+/// For reads and writes, VTL1 must serve the intercepts with
+/// [`mmio_stub::handle_intercept`] and an installed region covering `gpa`;
+/// intercepted reads return zero unless the region says otherwise. For
+/// execution, `gpa` must be the function of an
+/// [`exec_probe::CodePage`] and VTL1 must serve the intercepts with
+/// [`exec_probe::handle_intercept`].
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub fn vtl0_access_allowed<T>(ctx: &mut T, gpa: u64, access: Access) -> TmkResult<bool>
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    // VTL1 runs this after serving the access, if it is intercepted.
+    ctx.queue_command_vp(VpExecToken::new(0, Vtl::Vtl1).command(|ctx: &mut T| {
+        ctx.switch_to_low_vtl();
+    }))?;
+
+    let intercepts = || mmio_stub::stats().0 + exec_probe::stats().0;
+    let before = intercepts();
+    // SAFETY: the caller provides a page it owns, a code page for
+    // execution, and intercepted accesses are emulated by VTL1.
+    unsafe {
+        match access {
+            Access::Read => {
+                core::ptr::read_volatile(gpa as *const u64);
+            }
+            Access::Write => core::ptr::write_volatile(gpa as *mut u64, 0),
+            Access::Execute => {
+                exec_probe::call(gpa);
+            }
+        }
+    }
+    let after = intercepts();
+    // Drop the command if the access was not intercepted.
+    crate::platform::hyperv::ctx::resync_command_queue(0);
+    Ok(after == before)
+}
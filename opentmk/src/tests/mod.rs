@@ -2,15 +2,323 @@
 // Licensed under the MIT License.
 
 //! Test modules driving OpenTMK tests.
+//!
+//! The tests built into an image are chosen at compile time with the
+//! `OPENTMK_TESTS` environment variable, a comma separated list of test
+//! names without spaces, e.g.
+//! `OPENTMK_TESTS=hv_vp_restart cargo build -p opentmk`. Tests that are not
+//! selected are never referenced, so neither they nor the subsystems only
+//! they use end up in the image. Naming a test that does not exist in the
+//! current configuration fails the build.
 
-// only one test is run at a time so there is dead code in other tests
+// only the selected tests are run so there is dead code in other tests
 #![expect(dead_code)]
 use crate::platform::hyperv::ctx::HvTestCtx;
+use crate::tests::registry::Registry;
+use crate::tests::registry::TestCase;
 mod hyperv;
+pub mod registry;
+
+/// Tests built into the image when `OPENTMK_TESTS` is not set.
+const DEFAULT_TESTS: &str = "hv_processor";
+
+/// Comma separated names of the tests built into the image.
+pub const SELECTED_TESTS: &str = match option_env!("OPENTMK_TESTS") {
+    Some(tests) => tests,
+    None => DEFAULT_TESTS,
+};
+
+/// Returns whether the entry `list[start..end]` of a test list is `name`.
+const fn entry_is(list: &[u8], start: usize, end: usize, name: &[u8]) -> bool {
+    if end - start != name.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < name.len() {
+        if list[start + i] != name[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Returns the end of the entry of `list` starting at `start`.
+const fn entry_end(list: &[u8], start: usize) -> usize {
+    let mut end = start;
+    while end < list.len() && list[end] != b',' {
+        end += 1;
+    }
+    end
+}
+
+/// Returns whether the comma separated `list` contains `name`.
+const fn is_selected(list: &str, name: &str) -> bool {
+    let list = list.as_bytes();
+    let mut start = 0;
+    while start < list.len() {
+        let end = entry_end(list, start);
+        if entry_is(list, start, end, name.as_bytes()) {
+            return true;
+        }
+        start = end + 1;
+    }
+    false
+}
+
+/// Returns whether every entry of the comma separated `list` is in `known`.
+const fn all_known(list: &str, known: &[&str]) -> bool {
+    let list = list.as_bytes();
+    let mut start = 0;
+    while start < list.len() {
+        let end = entry_end(list, start);
+        let mut found = false;
+        let mut i = 0;
+        while i < known.len() {
+            if entry_is(list, start, end, known[i].as_bytes()) {
+                found = true;
+            }
+            i += 1;
+        }
+        if !found {
+            return false;
+        }
+        start = end + 1;
+    }
+    true
+}
+
+/// Registers the tests of the catalog that are part of [`SELECTED_TESTS`].
+///
+/// Each entry is the test name, which is also its module under `hyperv`,
+/// optionally followed by the function to run and by [`TestCase`] builder
+/// calls, and may carry `cfg` attributes.
+macro_rules! register_selected {
+    ($registry:ident; $($(#[$attr:meta])* $name:ident $(=> $run:expr)? $(, $modifier:ident($arg:expr))*;)*) => {
+        const KNOWN: &[&str] = &[$($(#[$attr])* stringify!($name),)*];
+        const _: () = assert!(
+            all_known(SELECTED_TESTS, KNOWN),
+            "OPENTMK_TESTS names a test that is not available in this configuration"
+        );
+        $(
+            $(#[$attr])*
+            {
+                const SELECTED: bool = is_selected(SELECTED_TESTS, stringify!($name));
+                if SELECTED {
+                    $registry.register(
+                        TestCase::new(
+                            stringify!($name),
+                            register_selected!(@run $name $(=> $run)?),
+                        )
+                        $(.$modifier($arg))*,
+                    );
+                }
+            }
+        )*
+    };
+    (@run $name:ident => $run:expr) => {
+        $run
+    };
+    (@run $name:ident) => {
+        hyperv::$name::exec
+    };
+}
 
 /// Runs all the tests.
 pub fn run_test() {
+    let mut registry = Registry::new();
+    register_selected! {
+        registry;
+        #[cfg(target_os = "uefi")]
+        hv_alloc_below => |_| hyperv::hv_alloc_below::exec();
+        #[cfg(target_os = "uefi")]
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_alloc_fault_injection;
+        hv_alt_stack => |_| hyperv::hv_alt_stack::exec();
+        #[cfg(target_os = "uefi")]
+        hv_boot_services => |_| hyperv::hv_boot_services::exec(), boot_services(true);
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_cache_line_bounce;
+        #[cfg(nightly)]
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_cache_types;
+        hv_checkpoints;
+        #[cfg(nightly)]
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_debug_registers;
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_descriptor_tables;
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_dm_hot_add;
+        hv_error_vp_start;
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_event_doorbell;
+        hv_extended_hypercalls;
+        hv_extended_paranoid;
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_features;
+        hv_fibers => |_| hyperv::hv_fibers::exec();
+        #[cfg(nightly)]
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_gva_alias;
+        #[cfg(target_os = "uefi")]
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_heap_quota;
+        #[cfg(nightly)]
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_hypercall_page;
+        hv_hypercall_paranoid;
+        hv_hypercall_retry;
+        #[cfg(target_os = "uefi")]
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_ic_shutdown;
+        #[cfg(nightly)]
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_intercept_resume;
+        #[cfg(nightly)]
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_ioapic_routing;
+        #[cfg(nightly)]
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_irq_hvcall;
+        #[cfg(nightly)]
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_irq_latency;
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_legacy_timers => |_| hyperv::hv_legacy_timers::exec();
+        hv_long_spin_wait;
+        hv_memory_dump => |_| hyperv::hv_memory_dump::exec();
+        #[cfg(nightly)]
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_memory_protect_read;
+        #[cfg(nightly)]
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_memory_protect_write;
+        #[cfg(target_os = "uefi")]
+        hv_memstress;
+        #[cfg(nightly)]
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_mmio_stub;
+        #[cfg(nightly)]
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_msr_conformance;
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_netvsc_init;
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_overlay_pages;
+        #[cfg(nightly)]
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_pending_interruption;
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_privilege_matrix;
+        hv_processor, provides(&["vtl1_enabled"]);
+        hv_queue_batch;
+        hv_queue_stats;
+        #[cfg(nightly)]
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_register_intercept;
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_rendezvous => |_| hyperv::hv_rendezvous::exec();
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_reverse_topology;
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_save_restore => |_| hyperv::hv_save_restore::exec();
+        #[cfg(nightly)]
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_scenario;
+        #[cfg(nightly)]
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_single_step;
+        #[cfg(nightly)]
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_smep_smap;
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_storvsc_read;
+        hv_sync_race;
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_synic_caps;
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_synic_exhaust;
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_synic_message_stress => |_| hyperv::hv_synic_message_stress::exec();
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_synthhid_handshake;
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_synthvid_probe;
+        #[cfg(nightly)]
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_tpm_read_cvm;
+        #[cfg(nightly)]
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_tpm_write_cvm;
+        #[cfg(target_os = "uefi")]
+        hv_uefi_memory_map => |_| hyperv::hv_uefi_memory_map::exec();
+        hv_vcpu_registers;
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_vmbus_gpadl => |_| hyperv::hv_vmbus_gpadl::exec();
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_vp_restart;
+        hv_vp_runtime;
+        hv_vp_secure_config;
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_vpci_enum;
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_vtl0_register_tamper;
+        #[cfg(nightly)]
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_vtl_access_check;
+        #[cfg(nightly)]
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_vtl_execute_protect;
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_vtl_protect_throughput;
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_vtl_switch_fuzz;
+        #[cfg(target_os = "uefi")]
+        hv_warm_reset => |_| hyperv::hv_warm_reset::exec(), boot_services(true);
+        #[cfg(nightly)]
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        hv_watchpoint;
+    }
+
+    // Unless the harness asked for the tests needing them, boot services
+    // are gone before the VPs are set up, so that the firmware is out of
+    // the way of every test.
+    registry.keep_boot_services(
+        crate::handshake::host_supports(crate::handshake::BOOT_SERVICES_CAPABILITY) == Some(true),
+    );
+    #[cfg(target_os = "uefi")]
+    if registry.needs_boot_services() {
+        crate::uefi::init::disable_watchdog();
+    } else {
+        crate::uefi::init::exit_boot_services();
+    }
     let mut ctx = HvTestCtx::new();
     ctx.init(hvdef::Vtl::Vtl0).expect("failed to init on BSP");
-    hyperv::hv_processor::exec(&mut ctx);
+    if let Err(e) = crate::affinity::write_vp_map(&mut ctx) {
+        log::error!("failed to report the VP map: {:?}", e);
+    }
+    match crate::soak::config() {
+        Some(config) => crate::soak::run(&mut registry, &mut ctx, &config),
+        None => {
+            for (name, status) in registry.run(&mut ctx) {
+                log::info!("{}: {:?}", name, status);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selection() {
+        assert!(is_selected("hv_processor", "hv_processor"));
+        assert!(is_selected("hv_features,hv_processor", "hv_processor"));
+        assert!(!is_selected("hv_processor_x", "hv_processor"));
+        assert!(!is_selected("", "hv_processor"));
+        assert!(all_known("a,b", &["b", "a", "c"]));
+        assert!(!all_known("a,d", &["a", "b"]));
+    }
 }
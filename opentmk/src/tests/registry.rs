@@ -0,0 +1,605 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Test registry with dependency ordering.
+//!
+//! Tests declare the capabilities they `provide` (e.g. `vtl1_enabled`),
+//! the capabilities they `require`, and tests they must run `after`. The
+//! registry runs them in dependency order and skips a test whose required
+//! capability was not provided, emitting a `test_skip` record for it.
+//! `after` only orders tests, it does not make one depend on the other
+//! passing.
+//!
+//! A test may end early with an outcome of its own: `tmk_skip!` reports it
+//! skipped, e.g. on a platform without the feature it covers, and a failing
+//! `tmk_xfail!` check reports it as an expected failure, in a `test_xfail`
+//! record, for areas known to be broken. Neither provides its
+//! capabilities, and both are counted apart from the failures.
+//!
+//! A setup step run through `tmk_setup!` that fails because the host does
+//! not offer a feature, e.g. enabling VTL1 without VSM, ends the test as
+//! blocked on the feature, in a `test_blocked` record carrying the error.
+//! The tests requiring a capability the blocked test provides are reported
+//! blocked as well, on the same feature and error, so that a run on such a
+//! host reports what could not be covered rather than failing.
+//!
+//! Tests run after UEFI boot services were exited, for a deterministic
+//! environment, unless they declare they need `boot_services`, e.g. for
+//! file I/O or variables. Those only run when the harness asks for them,
+//! see [`Registry::keep_boot_services`], and are skipped otherwise, so
+//! that the firmware stays out of the way of every other run. They run
+//! first, as far as their dependencies allow, and the registry exits boot
+//! services right before the first test that does not need them; a run of
+//! such tests only keeps boot services to the end. A test needing boot
+//! services that can only run after they were exited is skipped.
+//!
+//! A failing `tmk_assert!` panics and ends the run. The panic handler calls
+//! [`abort_run`] so that the tests that never ran are still reported.
+
+use alloc::collections::btree_map::BTreeMap;
+use alloc::collections::btree_set::BTreeSet;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+
+use serde::Serialize;
+use spin::Mutex;
+
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkStatus;
+
+/// A registered test.
+pub struct TestCase<T> {
+    name: &'static str,
+    run: fn(&mut T),
+    requires: &'static [&'static str],
+    after: &'static [&'static str],
+    provides: &'static [&'static str],
+    boot_services: bool,
+}
+
+impl<T> TestCase<T> {
+    /// Create a test without dependencies.
+    pub fn new(name: &'static str, run: fn(&mut T)) -> Self {
+        Self {
+            name,
+            run,
+            requires: &[],
+            after: &[],
+            provides: &[],
+            boot_services: false,
+        }
+    }
+
+    /// Capabilities that must have been provided by a passing test.
+    pub fn requires(mut self, capabilities: &'static [&'static str]) -> Self {
+        self.requires = capabilities;
+        self
+    }
+
+    /// Tests that must run before this one, whether they pass or not.
+    pub fn after(mut self, tests: &'static [&'static str]) -> Self {
+        self.after = tests;
+        self
+    }
+
+    /// Capabilities this test provides when it passes.
+    pub fn provides(mut self, capabilities: &'static [&'static str]) -> Self {
+        self.provides = capabilities;
+        self
+    }
+
+    /// Whether the test needs UEFI boot services to still be running.
+    pub fn boot_services(mut self, needed: bool) -> Self {
+        self.boot_services = needed;
+        self
+    }
+}
+
+#[derive(Serialize)]
+struct TestSkipRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    test: &'a str,
+    reason: &'a str,
+}
+
+fn write_skip(test: &'static str, reason: &str) {
+    log::warn!("skipping {}: {}", test, reason);
+    crate::tmk_logger::write_record(&TestSkipRecord {
+        record_type: "test_skip",
+        test,
+        reason,
+    });
+    #[cfg(target_os = "uefi")]
+    crate::uefi::results_file::record_outcome(test, TmkStatus::Skipped);
+}
+
+#[derive(Serialize)]
+struct TestXfailRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    test: &'a str,
+    reason: &'a str,
+}
+
+fn write_xfail(test: &'static str, reason: &str) {
+    log::warn!("{} failed as expected: {}", test, reason);
+    crate::tmk_logger::write_record(&TestXfailRecord {
+        record_type: "test_xfail",
+        test,
+        reason,
+    });
+    #[cfg(target_os = "uefi")]
+    crate::uefi::results_file::record_outcome(test, TmkStatus::ExpectedFailure);
+}
+
+/// A feature setup failed for, blocking the tests that need it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Blocker {
+    feature: &'static str,
+    error: TmkError,
+    /// The test whose setup failed.
+    origin: &'static str,
+}
+
+#[derive(Serialize)]
+struct TestBlockedRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    test: &'a str,
+    feature: &'a str,
+    error: String,
+    origin: &'a str,
+}
+
+fn write_blocked(test: &'static str, blocker: &Blocker) {
+    log::warn!(
+        "{} blocked on {}: {:?} in {}",
+        test,
+        blocker.feature,
+        blocker.error,
+        blocker.origin
+    );
+    crate::tmk_logger::write_record(&TestBlockedRecord {
+        record_type: "test_blocked",
+        test,
+        feature: blocker.feature,
+        error: format!("{:?}", blocker.error),
+        origin: blocker.origin,
+    });
+    #[cfg(target_os = "uefi")]
+    crate::uefi::results_file::record_outcome(test, TmkStatus::Blocked);
+}
+
+/// Tests still to run, kept for [`abort_run`].
+struct RunState {
+    available: BTreeSet<&'static str>,
+    /// Capabilities withheld by blocked tests, and what blocked them.
+    blocked: BTreeMap<&'static str, Blocker>,
+    pending: Vec<(&'static str, &'static [&'static str])>,
+}
+
+impl RunState {
+    /// Reports `test` as unable to run for lack of `missing`: blocked if
+    /// the provider was, skipped otherwise.
+    fn report_missing(&self, test: &'static str, missing: &str) -> TmkStatus {
+        match self.blocked.get(missing) {
+            Some(blocker) => {
+                write_blocked(test, blocker);
+                TmkStatus::Blocked
+            }
+            None => {
+                write_skip(test, &format!("prerequisite {} unavailable", missing));
+                TmkStatus::Skipped
+            }
+        }
+    }
+}
+
+static RUN_STATE: Mutex<Option<RunState>> = Mutex::new(None);
+/// The outcome the running test ended with early, and why.
+static OUTCOME_REQUESTED: Mutex<Option<(TmkStatus, &'static str)>> = Mutex::new(None);
+/// What the running test was blocked on, see [`block`].
+static BLOCKER: Mutex<Option<Blocker>> = Mutex::new(None);
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static CURRENT_TEST: Mutex<Option<&'static str>> = Mutex::new(None);
+
+/// Marks the running test as skipped, e.g. because the platform lacks a
+/// feature. The test should return right after calling this; the
+/// capabilities it provides are withheld.
+pub fn skip(reason: &'static str) {
+    *OUTCOME_REQUESTED.lock() = Some((TmkStatus::Skipped, reason));
+}
+
+/// Marks the running test as an expected failure, because a check known
+/// not to hold yet failed. The test should return right after calling
+/// this; the capabilities it provides are withheld.
+pub fn expect_failure(reason: &'static str) {
+    *OUTCOME_REQUESTED.lock() = Some((TmkStatus::ExpectedFailure, reason));
+}
+
+/// Marks the running test as blocked on `feature`, which setup failed to
+/// enable with `error` because the host does not offer it. The test should
+/// return right after calling this; the capabilities it provides are
+/// withheld, and the tests requiring them are blocked as well.
+pub fn block(feature: &'static str, error: TmkError) {
+    *BLOCKER.lock() = Some(Blocker {
+        feature,
+        error,
+        origin: current_test().unwrap_or("setup"),
+    });
+    *OUTCOME_REQUESTED.lock() = Some((TmkStatus::Blocked, feature));
+}
+
+/// Returns whether UEFI boot services were exited.
+fn boot_services_exited() -> bool {
+    #[cfg(target_os = "uefi")]
+    return crate::uefi::alloc::ALLOCATOR.boot_services_exited();
+    #[cfg(not(target_os = "uefi"))]
+    return true;
+}
+
+/// Returns the name of the running test, if any.
+pub fn current_test() -> Option<&'static str> {
+    *CURRENT_TEST.lock()
+}
+
+/// Reports the tests a panic prevented from running. Dependents of
+/// unavailable capabilities are reported as such, the others as aborted.
+/// Ends a soak run, see [`crate::soak::abort`].
+pub fn abort_run() {
+    crate::soak::abort(current_test());
+    if !RUNNING.swap(false, Ordering::AcqRel) {
+        return;
+    }
+    let Some(state) = RUN_STATE.lock().take() else {
+        return;
+    };
+    for &(name, requires) in &state.pending {
+        match requires.iter().find(|c| !state.available.contains(*c)) {
+            Some(missing) => _ = state.report_missing(name, missing),
+            None => write_skip(name, "run aborted"),
+        }
+    }
+}
+
+/// An ordered list of tests.
+pub struct Registry<T> {
+    tests: Vec<TestCase<T>>,
+    keep_boot_services: bool,
+}
+
+impl<T> Default for Registry<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Registry<T> {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        Self {
+            tests: Vec::new(),
+            keep_boot_services: false,
+        }
+    }
+
+    /// Add a test. Registration order breaks ties between tests that do not
+    /// depend on each other.
+    pub fn register(&mut self, test: TestCase<T>) {
+        self.tests.push(test);
+    }
+
+    /// Keep only the tests whose name `keep` accepts.
+    pub fn retain(&mut self, keep: impl Fn(&str) -> bool) {
+        self.tests.retain(|test| keep(test.name));
+    }
+
+    /// Whether a test called `name` is registered.
+    pub fn contains(&self, name: &str) -> bool {
+        self.tests.iter().any(|test| test.name == name)
+    }
+
+    /// Whether the tests needing boot services may run, keeping boot
+    /// services up until they did. Off by default, the tests are then
+    /// skipped.
+    pub fn keep_boot_services(&mut self, keep: bool) {
+        self.keep_boot_services = keep;
+    }
+
+    /// Whether a test that may run needs boot services, which must then be
+    /// kept until the registry runs.
+    pub fn needs_boot_services(&self) -> bool {
+        self.keep_boot_services && self.tests.iter().any(|test| test.boot_services)
+    }
+
+    /// Order the tests so that every test runs after the tests named in
+    /// `after` and after every provider of the capabilities it requires,
+    /// the tests needing boot services as early as that allows.
+    ///
+    /// Returns the indices of the ordered tests and the names of the tests
+    /// caught in a dependency cycle, which cannot run.
+    fn plan(&self) -> (Vec<usize>, Vec<&'static str>) {
+        let count = self.tests.len();
+        let mut predecessors: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); count];
+        for (i, test) in self.tests.iter().enumerate() {
+            for (j, other) in self.tests.iter().enumerate() {
+                if i != j
+                    && (test.after.contains(&other.name)
+                        || test.requires.iter().any(|c| other.provides.contains(c)))
+                {
+                    predecessors[i].insert(j);
+                }
+            }
+            for name in test.after {
+                if !self.tests.iter().any(|t| t.name == *name) {
+                    log::warn!("{} runs after unknown test {}", test.name, name);
+                }
+            }
+        }
+
+        let mut order = Vec::with_capacity(count);
+        let mut placed = vec![false; count];
+        let ready =
+            |placed: &[bool], i: usize| !placed[i] && predecessors[i].iter().all(|&p| placed[p]);
+        while let Some(next) = (0..count)
+            .find(|&i| self.tests[i].boot_services && ready(&placed, i))
+            .or_else(|| (0..count).find(|&i| ready(&placed, i)))
+        {
+            placed[next] = true;
+            order.push(next);
+        }
+        let cyclic = (0..count)
+            .filter(|&i| !placed[i])
+            .map(|i| self.tests[i].name)
+            .collect();
+        (order, cyclic)
+    }
+
+    /// Run the tests in dependency order and return their outcomes, in
+    /// the order they were considered.
+    ///
+    /// A failed test ends the run, so no outcome is ever
+    /// [`TmkStatus::Failed`]; the failure is reported by [`abort_run`].
+    pub fn run(&self, ctx: &mut T) -> Vec<(&'static str, TmkStatus)> {
+        let (order, cyclic) = self.plan();
+        let mut outcomes = Vec::with_capacity(self.tests.len());
+        for name in cyclic {
+            write_skip(name, "dependency cycle");
+            outcomes.push((name, TmkStatus::Skipped));
+        }
+
+        *RUN_STATE.lock() = Some(RunState {
+            available: BTreeSet::new(),
+            blocked: BTreeMap::new(),
+            pending: order
+                .iter()
+                .map(|&i| (self.tests[i].name, self.tests[i].requires))
+                .collect(),
+        });
+        RUNNING.store(true, Ordering::Release);
+
+        for i in order {
+            let test = &self.tests[i];
+            let unavailable = {
+                let mut state = RUN_STATE.lock();
+                let state = state.as_mut().unwrap();
+                state.pending.remove(0);
+                test.requires
+                    .iter()
+                    .find(|c| !state.available.contains(*c))
+                    .map(|missing| state.report_missing(test.name, missing))
+            };
+            if let Some(status) = unavailable {
+                outcomes.push((test.name, status));
+                continue;
+            }
+            if test.boot_services && !self.keep_boot_services {
+                write_skip(test.name, "the harness did not ask to keep boot services");
+                outcomes.push((test.name, TmkStatus::Skipped));
+                continue;
+            }
+            if test.boot_services && boot_services_exited() {
+                write_skip(test.name, "boot services were exited");
+                outcomes.push((test.name, TmkStatus::Skipped));
+                continue;
+            }
+            #[cfg(target_os = "uefi")]
+            if !test.boot_services {
+                crate::uefi::init::exit_boot_services();
+            }
+
+            log::info!("running {}", test.name);
+            *OUTCOME_REQUESTED.lock() = None;
+            *BLOCKER.lock() = None;
+            *CURRENT_TEST.lock() = Some(test.name);
+            crate::platform::hyperv::trace::reset();
+            crate::platform::hyperv::retry::reset();
+            let queue_before = crate::platform::hyperv::queue_stats::stats();
+            crate::tmk_assert::clear_checkpoints();
+            #[cfg(target_os = "uefi")]
+            crate::uefi::alloc::ALLOCATOR.clear_quotas();
+            #[cfg(nightly)]
+            #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+            crate::arch::interrupt_watch::reset();
+            (test.run)(ctx);
+            *CURRENT_TEST.lock() = None;
+
+            if let Some((status, reason)) = OUTCOME_REQUESTED.lock().take() {
+                match status {
+                    TmkStatus::ExpectedFailure => write_xfail(test.name, reason),
+                    TmkStatus::Blocked => {
+                        let blocker = BLOCKER.lock().take().unwrap();
+                        write_blocked(test.name, &blocker);
+                        if let Some(state) = RUN_STATE.lock().as_mut() {
+                            state
+                                .blocked
+                                .extend(test.provides.iter().map(|&c| (c, blocker)));
+                        }
+                    }
+                    _ => write_skip(test.name, reason),
+                }
+                outcomes.push((test.name, status));
+                continue;
+            }
+            crate::platform::hyperv::trace::write_test_end(test.name);
+            crate::platform::hyperv::queue_stats::write_record(
+                test.name,
+                crate::platform::hyperv::queue_stats::stats().since(&queue_before),
+            );
+            #[cfg(target_os = "uefi")]
+            crate::uefi::alloc::ALLOCATOR.write_stats_record(test.name);
+            #[cfg(target_os = "uefi")]
+            crate::uefi::alloc::ALLOCATOR.write_irq_alloc_record(test.name);
+            #[cfg(nightly)]
+            #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+            crate::arch::interrupt_watch::write_record(test.name);
+            #[cfg(target_os = "uefi")]
+            crate::uefi::results_file::record_outcome(test.name, TmkStatus::Passed);
+            outcomes.push((test.name, TmkStatus::Passed));
+            if let Some(state) = RUN_STATE.lock().as_mut() {
+                state.available.extend(test.provides);
+            }
+        }
+
+        RUNNING.store(false, Ordering::Release);
+        *RUN_STATE.lock() = None;
+        outcomes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nop(_: &mut ()) {}
+
+    fn names(registry: &Registry<()>, order: &[usize]) -> Vec<&'static str> {
+        order.iter().map(|&i| registry.tests[i].name).collect()
+    }
+
+    #[test]
+    fn test_plan_orders_dependencies() {
+        let mut registry = Registry::new();
+        registry.register(TestCase::new("protect", nop).requires(&["vtl1_enabled"]));
+        registry.register(TestCase::new("intercept", nop).after(&["synic_basic"]));
+        registry.register(TestCase::new("synic_basic", nop));
+        registry.register(TestCase::new("vtl1", nop).provides(&["vtl1_enabled"]));
+        let (order, cyclic) = registry.plan();
+        assert_eq!(
+            names(&registry, &order),
+            ["synic_basic", "intercept", "vtl1", "protect"]
+        );
+        assert!(cyclic.is_empty());
+    }
+
+    #[test]
+    fn test_missing_capability_of_blocked_test() {
+        let blocker = Blocker {
+            feature: "vsm",
+            error: TmkError::AccessDenied,
+            origin: "vtl1",
+        };
+        let state = RunState {
+            available: BTreeSet::new(),
+            blocked: [("vtl1_enabled", blocker)].into(),
+            pending: Vec::new(),
+        };
+        let output = crate::tmk_logger::capture_output(|| {
+            assert_eq!(
+                state.report_missing("protect", "vtl1_enabled"),
+                TmkStatus::Blocked
+            );
+            assert_eq!(state.report_missing("timers", "synic"), TmkStatus::Skipped);
+        });
+        let lines = crate::tmk_logger::json_lines(&output);
+        let blocked: Vec<_> = lines
+            .iter()
+            .filter(|line| line["type"] == "test_blocked")
+            .collect();
+        assert_eq!(blocked.len(), 1);
+        assert_eq!(blocked[0]["test"], "protect");
+        assert_eq!(blocked[0]["feature"], "vsm");
+        assert_eq!(blocked[0]["error"], "AccessDenied");
+        assert_eq!(blocked[0]["origin"], "vtl1");
+        assert!(
+            lines
+                .iter()
+                .any(|line| line["type"] == "test_skip" && line["test"] == "timers")
+        );
+    }
+
+    #[test]
+    fn test_plan_runs_boot_services_tests_first() {
+        let mut registry = Registry::new();
+        registry.register(TestCase::new("vtl1", nop).provides(&["vtl1_enabled"]));
+        registry.register(TestCase::new("files", nop).boot_services(true));
+        registry.register(
+            TestCase::new("variables", nop)
+                .boot_services(true)
+                .requires(&["vtl1_enabled"]),
+        );
+        registry.register(TestCase::new("protect", nop));
+        assert!(!registry.needs_boot_services());
+        registry.keep_boot_services(true);
+        assert!(registry.needs_boot_services());
+        let (order, _) = registry.plan();
+        assert_eq!(
+            names(&registry, &order),
+            ["files", "vtl1", "variables", "protect"]
+        );
+    }
+
+    #[test]
+    fn test_plan_reports_cycles() {
+        let mut registry = Registry::new();
+        registry.register(TestCase::new("a", nop).after(&["b"]));
+        registry.register(TestCase::new("b", nop).after(&["a"]));
+        registry.register(TestCase::new("c", nop));
+        let (order, cyclic) = registry.plan();
+        assert_eq!(names(&registry, &order), ["c"]);
+        assert_eq!(cyclic, ["a", "b"]);
+    }
+
+    fn known_broken(_: &mut ()) {
+        crate::tmk_xfail!(1 + 1 == 3, "known broken");
+    }
+
+    fn since_fixed(_: &mut ()) {
+        crate::tmk_xfail!(1 + 1 == 2, "since fixed");
+    }
+
+    #[test]
+    fn test_run_reports_expected_failures() {
+        let mut registry = Registry::new();
+        registry.register(TestCase::new("broken", known_broken).provides(&["broken_feature"]));
+        registry.register(TestCase::new("fixed", since_fixed).provides(&["fixed_feature"]));
+        registry.register(TestCase::new("uses_broken", nop).requires(&["broken_feature"]));
+        registry.register(TestCase::new("uses_fixed", nop).requires(&["fixed_feature"]));
+        let mut outcomes = Vec::new();
+        let output = crate::tmk_logger::capture_output(|| outcomes = registry.run(&mut ()));
+        assert_eq!(
+            outcomes,
+            [
+                ("broken", TmkStatus::ExpectedFailure),
+                ("fixed", TmkStatus::Passed),
+                ("uses_broken", TmkStatus::Skipped),
+                ("uses_fixed", TmkStatus::Passed),
+            ]
+        );
+
+        let lines = crate::tmk_logger::json_lines(&output);
+        let xfails: Vec<_> = lines
+            .iter()
+            .filter(|line| line["type"] == "test_xfail")
+            .collect();
+        assert_eq!(xfails.len(), 1);
+        assert_eq!(xfails[0]["test"], "broken");
+        assert_eq!(xfails[0]["reason"], "known broken");
+        assert!(lines.iter().any(|line| line["type"] == "xfail"
+            && line["testname"] == "since fixed"
+            && line["assertion_result"] == true));
+    }
+}
@@ -4,16 +4,55 @@
 //! Assertion handling and logging in JSON format.
 //! This module provides a custom assertion macro `tmk_assert!` that logs assertion results in
 //! JSON format. It also includes utility functions for formatting and writing log messages.
+//!
+//! Structured context can be attached to an assertion with
+//! `tmk_assert!(cond, "message", extra = serde_json::json!({...}))`, which
+//! lands in the `extra` field of the record instead of the message string.
+//!
+//! When the dump on failure mode is on, a failing assertion writes an
+//! `assert_failure_dump` record before panicking. It carries the control
+//! registers of the VP that failed, the last hypercalls it traced and the
+//! checkpoints the test passed through with [`checkpoint`]. The mode is
+//! enabled at build time with the `OPENTMK_DUMP_ON_FAILURE` environment
+//! variable.
+//!
+//! `tmk_skip!(reason)` ends the test as skipped. `tmk_xfail!(cond, reason)`
+//! checks a condition known not to hold yet, writing an `xfail` record; if
+//! it fails, the test ends as an expected failure rather than panicking,
+//! and if it unexpectedly holds the test carries on with a warning.
 
 use alloc::string::String;
-use core::fmt::Write;
+use alloc::vec::Vec;
 
 use serde::Serialize;
+use spin::Mutex;
+
+use crate::platform::hyperv::ctx::HvTestCtx;
+use crate::platform::hyperv::trace;
+use crate::platform::hyperv::trace::TraceEntry;
+
+/// Number of traced hypercalls included in a failure dump.
+const DUMP_HYPERCALLS: usize = 32;
+/// Number of checkpoints kept for a failure dump; older ones are dropped.
+const MAX_CHECKPOINTS: usize = 64;
+
+const DUMP_ON_FAILURE: bool = option_env!("OPENTMK_DUMP_ON_FAILURE").is_some();
+static CHECKPOINTS: Mutex<Vec<Checkpoint>> = Mutex::new(Vec::new());
+
+/// A point a test reached, recorded with [`checkpoint`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Checkpoint {
+    /// The VP that reached it.
+    pub vp_index: u32,
+    /// What the test was doing.
+    pub label: &'static str,
+}
 
 #[derive(Serialize)]
-struct AssertJson<'a, T>
+struct AssertJson<'a, T, E>
 where
     T: Serialize,
+    E: Serialize,
 {
     #[serde(rename = "type")]
     type_: &'a str,
@@ -22,11 +61,14 @@ struct AssertJson<'a, T>
     line: String,
     assertion_result: bool,
     testname: &'a T,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    extra: Option<&'a E>,
 }
 
-impl<'a, T> AssertJson<'a, T>
+impl<'a, T, E> AssertJson<'a, T, E>
 where
     T: Serialize,
+    E: Serialize,
 {
     fn new(
         type_: &'a str,
@@ -35,6 +77,7 @@ fn new(
         line: String,
         assertion_result: bool,
         testname: &'a T,
+        extra: Option<&'a E>,
     ) -> Self {
         Self {
             type_,
@@ -43,21 +86,48 @@ fn new(
             line,
             assertion_result,
             testname,
+            extra,
         }
     }
 }
 
-pub(crate) fn format_assert_json_string<T>(
+pub(crate) fn format_assert_json_string<T, E>(
     s: &str,
     terminate_new_line: bool,
     line: String,
     assert_result: bool,
     testname: &T,
+    extra: Option<&E>,
 ) -> String
 where
     T: Serialize,
+    E: Serialize,
 {
-    let assert_json = AssertJson::new("assert", "WARN", s, line, assert_result, testname);
+    format_json_string(
+        "assert",
+        s,
+        terminate_new_line,
+        line,
+        assert_result,
+        testname,
+        extra,
+    )
+}
+
+fn format_json_string<T, E>(
+    type_: &str,
+    s: &str,
+    terminate_new_line: bool,
+    line: String,
+    assert_result: bool,
+    testname: &T,
+    extra: Option<&E>,
+) -> String
+where
+    T: Serialize,
+    E: Serialize,
+{
+    let assert_json = AssertJson::new(type_, "WARN", s, line, assert_result, testname, extra);
 
     let mut out = serde_json::to_string(&assert_json).expect("Failed to serialize assert JSON");
     if terminate_new_line {
@@ -67,25 +137,241 @@ pub(crate) fn format_assert_json_string<T>(
 }
 
 pub(crate) fn write_str(s: &str) {
-    _ = crate::tmk_logger::LOGGER.get_writer().write_str(s);
+    crate::tmk_logger::write_output(s);
+}
+
+/// Records that the current VP reached `label`, to be reported if an
+/// assertion fails later in the test.
+pub fn checkpoint(label: &'static str) {
+    let vp_index = HvTestCtx::get_vp_idx();
+    #[cfg(target_os = "uefi")]
+    crate::uefi::results_page::checkpoint(vp_index, label);
+    push_checkpoint(Checkpoint { vp_index, label });
+}
+
+fn push_checkpoint(checkpoint: Checkpoint) {
+    let mut checkpoints = CHECKPOINTS.lock();
+    if checkpoints.len() == MAX_CHECKPOINTS {
+        checkpoints.remove(0);
+    }
+    checkpoints.push(checkpoint);
+}
+
+/// Returns the checkpoints passed since the start of the test, oldest
+/// first.
+pub fn checkpoints() -> Vec<Checkpoint> {
+    CHECKPOINTS.lock().clone()
+}
+
+/// Forgets the checkpoints of the previous test.
+pub(crate) fn clear_checkpoints() {
+    CHECKPOINTS.lock().clear();
+}
+
+#[derive(Serialize)]
+struct FailureDumpRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    test: Option<&'static str>,
+    message: &'a str,
+    vp_index: u32,
+    registers: Vec<(&'static str, u64)>,
+    hypercalls: &'a [TraceEntry],
+    checkpoints: Vec<Checkpoint>,
+}
+
+/// Called by `tmk_assert!` when an assertion fails, before panicking.
+/// Records the failure for the results file and writes the
+/// `assert_failure_dump` record if the mode is on.
+#[doc(hidden)]
+pub fn on_failure(message: &str) {
+    #[cfg(target_os = "uefi")]
+    crate::uefi::results_file::record_failure(crate::tests::registry::current_test(), message);
+    if !DUMP_ON_FAILURE {
+        return;
+    }
+    let hypercalls = trace::recent();
+    let hypercalls = &hypercalls[hypercalls.len().saturating_sub(DUMP_HYPERCALLS)..];
+    // Do not deadlock if the assertion failed while the list was locked.
+    let checkpoints = CHECKPOINTS.try_lock().map_or_else(Vec::new, |c| c.clone());
+    crate::tmk_logger::write_record(&FailureDumpRecord {
+        record_type: "assert_failure_dump",
+        test: crate::tests::registry::current_test(),
+        message,
+        vp_index: HvTestCtx::get_vp_idx(),
+        registers: crate::arch::regs::snapshot(),
+        hypercalls,
+        checkpoints,
+    });
+}
+
+/// Called by `tmk_xfail!`. Writes the `xfail` record and, if the check
+/// failed as expected, marks the test as an expected failure.
+#[doc(hidden)]
+pub fn on_xfail(expn: &str, line: String, result: bool, reason: &'static str) {
+    let js = format_json_string("xfail", expn, true, line, result, &reason, None::<&()>);
+    write_str(&js);
+    if result {
+        log::warn!("expected failure passed: {}", reason);
+    } else {
+        crate::tests::registry::expect_failure(reason);
+    }
+}
+
+#[macro_export]
+/// Ends the running test as skipped, for `reason`.
+macro_rules! tmk_skip {
+    ($reason:expr) => {{
+        $crate::tests::registry::skip($reason);
+        return;
+    }};
+}
+
+#[macro_export]
+/// Runs a setup step enabling `feature`, e.g. `"vsm"`, and evaluates to its
+/// value. If the step fails because the host does not offer the feature,
+/// the test ends as blocked on it, with the error; any other error fails
+/// the test.
+macro_rules! tmk_setup {
+    ($feature:expr, $setup:expr) => {
+        match $setup {
+            Ok(value) => value,
+            Err(e) if $crate::tmkdefs::TmkError::is_unsupported(&e) => {
+                $crate::tests::registry::block($feature, e);
+                return;
+            }
+            Err(e) => {
+                $crate::tmk_assert!(
+                    false,
+                    concat!(stringify!($setup), " should succeed"),
+                    extra = format!("{:?}", e)
+                );
+                unreachable!()
+            }
+        }
+    };
+}
+
+#[macro_export]
+/// Checks a condition known not to hold yet. If it is false, the test ends
+/// as an expected failure for `reason` instead of failing; if it is true,
+/// the test goes on and a warning notes that the check now passes.
+macro_rules! tmk_xfail {
+    ($condition:expr, $reason:expr) => {{
+        let result: bool = $condition;
+        $crate::tmk_assert::on_xfail(
+            stringify!($condition),
+            format!("{}:{}", core::file!(), line!()),
+            result,
+            $reason,
+        );
+        if !result {
+            return;
+        }
+    }};
 }
 
 #[macro_export]
 /// Asserts that a condition is true, logging the result in JSON format.
 /// If the condition is false, it panics with the provided message.
+///
+/// An optional `extra = value` argument attaches any serializable value,
+/// typically a `serde_json::json!` object, to the record.
 macro_rules! tmk_assert {
-    ($condition:expr, $message:expr) => {{
+    (@impl $condition:expr, $message:expr, $extra:expr) => {{
         let file = core::file!();
         let line = line!();
         let file_line = format!("{}:{}", file, line);
         let expn = stringify!($condition);
         let result: bool = $condition;
+        let extra = $extra;
         let js = $crate::tmk_assert::format_assert_json_string(
-            &expn, true, file_line, result, &$message,
+            &expn,
+            true,
+            file_line,
+            result,
+            &$message,
+            extra.as_ref(),
         );
         $crate::tmk_assert::write_str(&js);
         if !result {
+            $crate::tmk_assert::on_failure(&$message);
             panic!("Assertion failed: {}", $message);
         }
     }};
+    ($condition:expr, $message:expr) => {
+        $crate::tmk_assert!(@impl $condition, $message, Option::<()>::None)
+    };
+    ($condition:expr, $message:expr, extra = $extra:expr) => {
+        $crate::tmk_assert!(@impl $condition, $message, Some($extra))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extra_payload() {
+        let with_extra = format_assert_json_string(
+            "x == 1",
+            false,
+            "file.rs:1".into(),
+            true,
+            &"message",
+            Some(&serde_json::json!({ "vp": 1 })),
+        );
+        assert!(with_extra.ends_with(r#""testname":"message","extra":{"vp":1}}"#));
+
+        let without_extra = format_assert_json_string(
+            "x == 1",
+            false,
+            "file.rs:1".into(),
+            true,
+            &"message",
+            None::<&()>,
+        );
+        assert!(!without_extra.contains("extra"));
+    }
+
+    #[test]
+    fn test_xfail_record() {
+        let js = format_json_string(
+            "xfail",
+            "x == 1",
+            false,
+            "file.rs:1".into(),
+            false,
+            &"known issue",
+            None::<&()>,
+        );
+        assert!(js.starts_with(r#"{"type":"xfail","#));
+        assert!(js.contains(r#""assertion_result":false"#));
+    }
+
+    #[test]
+    fn test_checkpoints_are_capped() {
+        clear_checkpoints();
+        for _ in 0..MAX_CHECKPOINTS {
+            push_checkpoint(Checkpoint {
+                vp_index: 0,
+                label: "old",
+            });
+        }
+        push_checkpoint(Checkpoint {
+            vp_index: 1,
+            label: "new",
+        });
+        let checkpoints = CHECKPOINTS.lock();
+        assert_eq!(checkpoints.len(), MAX_CHECKPOINTS);
+        assert_eq!(
+            checkpoints.last(),
+            Some(&Checkpoint {
+                vp_index: 1,
+                label: "new"
+            })
+        );
+        drop(checkpoints);
+        clear_checkpoints();
+    }
 }
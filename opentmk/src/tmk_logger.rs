@@ -4,26 +4,61 @@
 //! Logger implementation for OpenTMK.
 //! This module provides a logger that formats log messages as JSON and writes them to a specified output
 //! such as a serial port.
+//!
+//! High-volume runs may switch log entries and records to CBOR frames with
+//! the same schema, see [`LogFormat`] and [`negotiate_format`]. Harnesses
+//! taking [`COMPRESSION_OPTION`] get large frames and memory dumps, see
+//! [`dump_memory`], as LZ4 blocks.
+//!
+//! The `log` macros format into heap strings, which must not happen in
+//! interrupt or fault context. Handlers use [`log_static!`] and
+//! [`log_fmt_nostdalloc!`] instead, which render into a fixed per-VP buffer
+//! and drop the line rather than wait if the output is busy.
+//!
+//! Around a boundary such as `ExitBootServices`, across which the firmware
+//! console goes away and the firmware may touch the serial port,
+//! [`begin_boundary`] flushes the output and writes a `log_boundary`
+//! record, then holds back what is logged until [`end_boundary`] writes it
+//! out after a second record, so that the harness can tell a gap in the
+//! output from lost records.
+//!
+//! Log entries are filtered by level per module, e.g. `synic=debug,
+//! hypercall=warn`, so that a subsystem can be traced for a single failing
+//! test without drowning the serial link. The harness sets the filters with
+//! [`LOG_LEVELS_COMMAND`], during the handshake or whenever the TMK reads
+//! from the serial port, and tests with [`set_log_levels`].
+//!
+//! The logger writes through a [`LogOutput`], the serial port on target. In
+//! host unit tests it is a buffer instead, so that the output can be checked
+//! without booting a VM.
 
 use alloc::borrow::ToOwned;
+use alloc::collections::VecDeque;
 use alloc::fmt::format;
 use alloc::string::String;
 use alloc::string::ToString;
+use alloc::vec::Vec;
 use core::fmt::Write;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
 
 use log::SetLoggerError;
 use serde::Serialize;
+use serde::Serializer;
 use spin::Mutex;
 use spin::MutexGuard;
 
 #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
 use crate::arch::serial::InstrIoAccess;
-#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
 use crate::arch::serial::Serial;
-#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
-use crate::arch::serial::SerialPort;
-#[cfg(target_arch = "aarch64")] // xtask-fmt allow-target-arch sys-crate
-use minimal_rt::arch::Serial;
+use crate::arch::serial::SerialConfig;
+use crate::compress;
+use crate::compress::Compression;
+
+use crate::platform::hyperv::ctx::HvTestCtx;
+use crate::tmkdefs::MAX_VPS;
+use crate::tmkdefs::vp_slot;
 
 #[derive(Serialize)]
 struct LogEntry {
@@ -60,6 +95,710 @@ pub(crate) fn format_log_string_to_json(
     out
 }
 
+/// Format of the log entries and records on the log output.
+///
+/// Asserts and the lines of the allocation-free log path stay JSON lines in
+/// either format; they are rare, and the latter cannot allocate a frame.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One JSON object per line, the default.
+    Json,
+    /// A CBOR frame per entry, see [`FRAME_START`].
+    Cbor,
+}
+
+impl LogFormat {
+    /// The name of the format in the handshake.
+    pub fn name(self) -> &'static str {
+        match self {
+            LogFormat::Json => "json",
+            LogFormat::Cbor => "cbor",
+        }
+    }
+
+    /// Returns the format called `name` in the handshake.
+    pub fn from_name(name: &str) -> Option<Self> {
+        [LogFormat::Json, LogFormat::Cbor]
+            .into_iter()
+            .find(|format| format.name() == name)
+    }
+}
+
+/// Byte starting a binary frame, followed by the length of the payload as a
+/// little endian `u32` and the payload. It is the ASCII record separator,
+/// which JSON lines never hold unescaped, so the host can tell frames from
+/// the lines that stay JSON.
+pub const FRAME_START: u8 = 0x1e;
+/// Byte starting a compressed frame, the ASCII group separator. It is
+/// followed by the [`Compression`] tag, the length of the compressed
+/// payload and that of the payload once decompressed, both as little endian
+/// `u32`, then the compressed payload; integrity checking adds the same
+/// fields as to other frames.
+pub const COMPRESSED_FRAME_START: u8 = 0x1d;
+/// Frame payloads this large are compressed, if the harness took
+/// compression and it makes them smaller.
+const COMPRESS_THRESHOLD: usize = 1024;
+/// Command the harness answers the format offer with, followed by the name
+/// of the format and optionally [`INTEGRITY_OPTION`].
+pub const LOG_FORMAT_COMMAND: &str = "log_format";
+/// Option of the format reply turning on integrity checking: every line and
+/// frame carries a sequence number and a CRC32, see [`seal_line`] and
+/// [`seal_frame`], so that the host can detect dropped or corrupted bytes,
+/// and asks for the records it missed with [`RETRANSMIT_COMMAND`].
+pub const INTEGRITY_OPTION: &str = "integrity";
+/// Option of the format reply turning on the LZ4 compression of large
+/// frames, see [`COMPRESSED_FRAME_START`], and of memory dumps.
+pub const COMPRESSION_OPTION: &str = "lz4";
+/// Bytes of memory per `memory_dump` record.
+pub const DUMP_CHUNK_SIZE: usize = 64 * 1024;
+/// Command the harness asks for retransmission with, followed by the first
+/// sequence number it is missing.
+pub const RETRANSMIT_COMMAND: &str = "log_retransmit";
+/// Sealed records kept for retransmission.
+const RETRANSMIT_RECORDS: usize = 512;
+/// Command the harness sets the log level filters with, followed by them
+/// as [`parse_log_levels`] reads them.
+pub const LOG_LEVELS_COMMAND: &str = "log_levels";
+/// Level of the modules no filter names.
+const DEFAULT_LEVEL: log::LevelFilter = log::LevelFilter::Debug;
+
+static CBOR_OUTPUT: AtomicBool = AtomicBool::new(false);
+static INTEGRITY: AtomicBool = AtomicBool::new(false);
+static LZ4: AtomicBool = AtomicBool::new(false);
+/// Sequence number of the next sealed line or frame, only taken with the
+/// writer locked.
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(0);
+static RETRANSMIT_RING: Mutex<VecDeque<(u64, Sealed)>> = Mutex::new(VecDeque::new());
+/// Lines and frame payloads held back while crossing a boundary, see
+/// [`begin_boundary`]. `None` outside of one.
+static HELD: Mutex<Option<Held>> = Mutex::new(None);
+/// Whether a boundary is being crossed, for [`log_nostdalloc`] to tell
+/// without contending on [`HELD`].
+static IN_BOUNDARY: AtomicBool = AtomicBool::new(false);
+/// The log level filters, see [`set_log_levels`].
+static LOG_LEVELS: Mutex<Vec<LevelDirective>> = Mutex::new(Vec::new());
+/// Lines and frames held back across a boundary; more are dropped.
+const HELD_RECORDS: usize = 64;
+
+/// Returns the format of the log output.
+pub fn log_format() -> LogFormat {
+    if CBOR_OUTPUT.load(Ordering::Relaxed) {
+        LogFormat::Cbor
+    } else {
+        LogFormat::Json
+    }
+}
+
+/// Returns how large frames and memory dumps are compressed.
+pub fn compression() -> Compression {
+    if LZ4.load(Ordering::Relaxed) {
+        Compression::Lz4
+    } else {
+        Compression::None
+    }
+}
+
+/// The harness' answer to the format offer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FormatReply {
+    /// The format picked.
+    pub format: LogFormat,
+    /// Whether integrity checking was asked for.
+    pub integrity: bool,
+    /// The compression asked for.
+    pub compression: Compression,
+}
+
+/// Parses the harness' answer to the format offer. The options follow the
+/// format in any order, each at most once.
+pub fn parse_format_reply(line: &str) -> Option<FormatReply> {
+    let mut words = line.split_whitespace();
+    if words.next()? != LOG_FORMAT_COMMAND {
+        return None;
+    }
+    let mut reply = FormatReply {
+        format: LogFormat::from_name(words.next()?)?,
+        integrity: false,
+        compression: Compression::None,
+    };
+    for option in words {
+        match option {
+            INTEGRITY_OPTION if !reply.integrity => reply.integrity = true,
+            COMPRESSION_OPTION if reply.compression == Compression::None => {
+                reply.compression = Compression::Lz4
+            }
+            _ => return None,
+        }
+    }
+    Some(reply)
+}
+
+#[derive(Serialize)]
+struct LogFormatOfferRecord {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    formats: [&'static str; 2],
+    options: [&'static str; 2],
+}
+
+/// Offers the CBOR format and integrity checking to the harness in a
+/// `log_format_offer` record and switches to what it answers with.
+/// Harnesses that do not answer within `max_polls` polls of the serial port
+/// without data, or answer `json`, e.g. when asked to keep the text log,
+/// get JSON lines. Harness commands received before the reply are served.
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub fn negotiate_format(max_polls: u64) {
+    write_record(&LogFormatOfferRecord {
+        record_type: "log_format_offer",
+        formats: [LogFormat::Json.name(), LogFormat::Cbor.name()],
+        options: [INTEGRITY_OPTION, COMPRESSION_OPTION],
+    });
+    let serial = Serial::selected();
+    let reply = loop {
+        match serial.read_line(max_polls) {
+            Ok(line) => match parse_format_reply(&line) {
+                Some(reply) => break reply,
+                // Harness commands, e.g. log levels, may precede the reply.
+                None if handle_host_line(&line) => {}
+                None => log::warn!("ignoring serial line {:?}", line),
+            },
+            Err(_) => {
+                break FormatReply {
+                    format: LogFormat::Json,
+                    integrity: false,
+                    compression: Compression::None,
+                };
+            }
+        }
+    };
+    CBOR_OUTPUT.store(reply.format == LogFormat::Cbor, Ordering::Relaxed);
+    INTEGRITY.store(reply.integrity, Ordering::Relaxed);
+    LZ4.store(reply.compression == Compression::Lz4, Ordering::Relaxed);
+    log::info!(
+        "log format: {}, integrity checking {}, compression {:?}",
+        reply.format.name(),
+        if reply.integrity { "on" } else { "off" },
+        reply.compression
+    );
+}
+
+/// A JSON line or a frame as written to the log output.
+enum Sealed {
+    Line(String),
+    Frame(Vec<u8>),
+}
+
+impl Sealed {
+    fn write_to(&self, writer: &mut OutputWriter) {
+        match self {
+            Sealed::Line(line) => _ = writer.write_str(line),
+            Sealed::Frame(frame) => writer.write_bytes(frame),
+        }
+    }
+}
+
+/// Adds the sequence number and CRC of integrity checking to `line`, a JSON
+/// object and its line ending. They are spliced in as the last fields, the
+/// CRC32 covering the line up to `,"crc":`, which only the trailer holds
+/// unescaped.
+pub fn seal_line(line: &str, seq: u64) -> String {
+    let body = line
+        .trim_end()
+        .strip_suffix('}')
+        .expect("log lines are JSON objects");
+    let separator = if body.ends_with('{') { "" } else { "," };
+    let mut out = format!("{}{}\"seq\":{}", body, separator, seq);
+    let crc = crc32fast::hash(out.as_bytes());
+    _ = write!(out, ",\"crc\":{}}}\n", crc);
+    out
+}
+
+/// Builds the frame of `payload`. With integrity checking, the sequence
+/// number follows the length as a little endian `u64`, and the CRC32 of the
+/// sequence number and payload ends the frame as a little endian `u32`.
+pub fn seal_frame(payload: &[u8], seq: Option<u64>) -> Vec<u8> {
+    let mut header = [FRAME_START, 0, 0, 0, 0];
+    header[1..].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+    seal(&header, payload, seq)
+}
+
+/// Builds the compressed frame of `compressed`, a payload of `len` bytes
+/// compressed with `compression`, see [`COMPRESSED_FRAME_START`].
+pub fn seal_compressed_frame(
+    compression: Compression,
+    compressed: &[u8],
+    len: usize,
+    seq: Option<u64>,
+) -> Vec<u8> {
+    let mut header = [0; 10];
+    header[0] = COMPRESSED_FRAME_START;
+    header[1] = compression.tag();
+    header[2..6].copy_from_slice(&(compressed.len() as u32).to_le_bytes());
+    header[6..].copy_from_slice(&(len as u32).to_le_bytes());
+    seal(&header, compressed, seq)
+}
+
+/// Builds the frame of `payload`, compressed if it is large and compressing
+/// it with what the harness took makes it smaller.
+fn build_frame(payload: &[u8], seq: Option<u64>) -> Vec<u8> {
+    let compression = compression();
+    if payload.len() >= COMPRESS_THRESHOLD
+        && let Some(compressed) = compress::compress(compression, payload)
+    {
+        return seal_compressed_frame(compression, &compressed, payload.len(), seq);
+    }
+    seal_frame(payload, seq)
+}
+
+/// Builds a frame from its `header`, the sequence number if any, `payload`
+/// and the CRC32 of the last two.
+fn seal(header: &[u8], payload: &[u8], seq: Option<u64>) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(header.len() + payload.len() + 12);
+    frame.extend_from_slice(header);
+    let checked = frame.len();
+    if let Some(seq) = seq {
+        frame.extend_from_slice(&seq.to_le_bytes());
+    }
+    frame.extend_from_slice(payload);
+    if seq.is_some() {
+        let crc = crc32fast::hash(&frame[checked..]);
+        frame.extend_from_slice(&crc.to_le_bytes());
+    }
+    frame
+}
+
+/// What was logged while crossing a boundary.
+struct Held {
+    boundary: &'static str,
+    /// Room for [`HELD_RECORDS`] entries is reserved up front so that the
+    /// list never grows; each entry is still copied to the heap.
+    entries: Vec<(Option<String>, Option<Vec<u8>>)>,
+    dropped: usize,
+}
+
+#[derive(Serialize)]
+struct LogBoundaryRecord {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    boundary: &'static str,
+    phase: &'static str,
+    /// Lines and frames held back across the boundary and written after
+    /// the record ending it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    held: Option<usize>,
+    /// Lines and frames logged across the boundary that did not fit.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dropped: Option<usize>,
+}
+
+/// Writes a `log_boundary` record starting `boundary`, waits for the output
+/// to drain, and holds back what is logged until [`end_boundary`].
+pub(crate) fn begin_boundary(boundary: &'static str) {
+    write_record(&LogBoundaryRecord {
+        record_type: "log_boundary",
+        boundary,
+        phase: "begin",
+        held: None,
+        dropped: None,
+    });
+    LOGGER.get_writer().flush();
+    IN_BOUNDARY.store(true, Ordering::Release);
+    *HELD.lock() = Some(Held {
+        boundary,
+        entries: Vec::with_capacity(HELD_RECORDS),
+        dropped: 0,
+    });
+}
+
+/// Ends the boundary started by [`begin_boundary`]: writes a `log_boundary`
+/// record ending it, then what was held back, in order.
+pub(crate) fn end_boundary() {
+    let Some(held) = HELD.lock().take() else {
+        return;
+    };
+    IN_BOUNDARY.store(false, Ordering::Release);
+    write_record(&LogBoundaryRecord {
+        record_type: "log_boundary",
+        boundary: held.boundary,
+        phase: "end",
+        held: Some(held.entries.len()),
+        dropped: Some(held.dropped),
+    });
+    for (line, payload) in &held.entries {
+        emit(line.as_deref(), payload.as_deref());
+    }
+}
+
+/// Holds back a line or frame payload if crossing a boundary. Returns
+/// whether it was taken.
+fn hold(line: Option<&str>, payload: Option<&[u8]>) -> bool {
+    let mut held = HELD.lock();
+    let Some(held) = held.as_mut() else {
+        return false;
+    };
+    if held.entries.len() < HELD_RECORDS {
+        held.entries
+            .push((line.map(ToOwned::to_owned), payload.map(ToOwned::to_owned)));
+    } else {
+        held.dropped += 1;
+    }
+    true
+}
+
+/// Writes a JSON line or a frame payload to the log output, sealed and kept
+/// for retransmission if integrity checking is on.
+fn emit(line: Option<&str>, payload: Option<&[u8]>) {
+    if hold(line, payload) {
+        return;
+    }
+    let mut writer = LOGGER.get_writer();
+    // The sequence numbers are taken under the writer lock so that they
+    // reach the host in order.
+    let seq = INTEGRITY
+        .load(Ordering::Relaxed)
+        .then(|| NEXT_SEQ.fetch_add(1, Ordering::Relaxed));
+    let sealed = match (line, payload) {
+        (Some(line), _) => match seq {
+            Some(seq) => Sealed::Line(seal_line(line, seq)),
+            None => {
+                _ = writer.write_str(line);
+                return;
+            }
+        },
+        (None, Some(payload)) => Sealed::Frame(build_frame(payload, seq)),
+        (None, None) => return,
+    };
+    sealed.write_to(&mut writer);
+    if let Some(seq) = seq {
+        let mut ring = RETRANSMIT_RING.lock();
+        if ring.len() == RETRANSMIT_RECORDS {
+            ring.pop_front();
+        }
+        ring.push_back((seq, sealed));
+    }
+}
+
+/// Writes `value` to the log output in the current format. The console
+/// mirror always gets JSON.
+fn write_serialized<T: Serialize>(value: &T) {
+    if log_format() == LogFormat::Json {
+        let mut out = serde_json::to_string(value).expect("failed to serialize record");
+        out.push('\n');
+        write_output(&out);
+        return;
+    }
+    emit(
+        None,
+        Some(&crate::cbor::to_vec(value).expect("failed to encode record")),
+    );
+    if let Some(mirror) = *CONSOLE_MIRROR.lock() {
+        let mut out = serde_json::to_string(value).expect("failed to serialize record");
+        out.push('\n');
+        mirror(&out);
+    }
+}
+
+/// Writes a structured record to the log output, as a single JSON line or a
+/// CBOR frame depending on the [`LogFormat`].
+///
+/// The record is expected to carry its own `type` field so the host side can
+/// tell it apart from log and assert entries.
+pub(crate) fn write_record<T: Serialize>(record: &T) {
+    write_serialized(record);
+}
+
+/// Bytes of memory, as a CBOR byte string.
+struct Bytes<'a>(&'a [u8]);
+
+impl Serialize for Bytes<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+/// The memory of a `memory_dump` record: hex digits in JSON lines, a byte
+/// string in CBOR frames.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum DumpData<'a> {
+    Hex(String),
+    Bytes(Bytes<'a>),
+}
+
+#[derive(Serialize)]
+struct MemoryDumpRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    label: &'a str,
+    /// Address of the first byte of the chunk.
+    address: u64,
+    /// Bytes of memory in the chunk, once decompressed.
+    len: usize,
+    compression: Compression,
+    data: DumpData<'a>,
+}
+
+/// What [`dump_memory`] sent.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct DumpStats {
+    /// `memory_dump` records written.
+    pub records: usize,
+    /// Bytes of memory dumped.
+    pub len: usize,
+    /// Bytes of memory sent, once compressed.
+    pub encoded: usize,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        _ = write!(out, "{:02x}", byte);
+    }
+    out
+}
+
+/// Writes `data`, the memory at `address`, in `memory_dump` records of at
+/// most [`DUMP_CHUNK_SIZE`] bytes labeled `label`. Each chunk is compressed
+/// if the harness took compression and that makes it smaller, and its
+/// record tells the [`Compression`] used.
+pub fn dump_memory(label: &str, address: u64, data: &[u8]) -> DumpStats {
+    let compression = compression();
+    let mut stats = DumpStats::default();
+    for (i, chunk) in data.chunks(DUMP_CHUNK_SIZE).enumerate() {
+        let compressed = compress::compress(compression, chunk);
+        let (compression, encoded) = match &compressed {
+            Some(compressed) => (compression, compressed.as_slice()),
+            None => (Compression::None, chunk),
+        };
+        write_record(&MemoryDumpRecord {
+            record_type: "memory_dump",
+            label,
+            address: address + (i * DUMP_CHUNK_SIZE) as u64,
+            len: chunk.len(),
+            compression,
+            data: match log_format() {
+                LogFormat::Json => DumpData::Hex(hex(encoded)),
+                LogFormat::Cbor => DumpData::Bytes(Bytes(encoded)),
+            },
+        });
+        stats.records += 1;
+        stats.len += chunk.len();
+        stats.encoded += encoded.len();
+    }
+    stats
+}
+
+/// Returns the first sequence number asked for if `line` is a
+/// retransmission request.
+pub fn parse_retransmit(line: &str) -> Option<u64> {
+    let mut words = line.split_whitespace();
+    if words.next()? != RETRANSMIT_COMMAND {
+        return None;
+    }
+    let from = words.next()?.parse().ok()?;
+    words.next().is_none().then_some(from)
+}
+
+#[derive(Serialize)]
+struct LogRetransmitRecord {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    from: u64,
+    count: usize,
+    /// The oldest sequence number still held, later than `from` if the
+    /// records in between were dropped from the ring.
+    oldest: Option<u64>,
+}
+
+/// Writes again the records from sequence number `from` on that are still
+/// held, then a `log_retransmit` record summing up what was sent.
+pub fn retransmit(from: u64) {
+    let (count, oldest) = {
+        let mut writer = LOGGER.get_writer();
+        let ring = RETRANSMIT_RING.lock();
+        let mut count = 0;
+        for (_, sealed) in ring.iter().filter(|(seq, _)| *seq >= from) {
+            sealed.write_to(&mut writer);
+            count += 1;
+        }
+        (count, ring.front().map(|(seq, _)| *seq))
+    };
+    write_record(&LogRetransmitRecord {
+        record_type: "log_retransmit",
+        from,
+        count,
+        oldest,
+    });
+}
+
+/// Serves a retransmission request or sets the log level filters if
+/// `line`, received from the harness, is one of those commands. Returns
+/// whether it was.
+pub fn handle_host_line(line: &str) -> bool {
+    if let Some(from) = parse_retransmit(line) {
+        retransmit(from);
+        return true;
+    }
+    match line.trim().split_once(' ') {
+        Some((LOG_LEVELS_COMMAND, spec)) => {
+            match parse_log_levels(spec) {
+                Some(directives) => set_log_levels(directives),
+                None => log::error!("ignoring invalid log levels {:?}", spec),
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+/// A log level filter, for the modules of `module` or, without one, for
+/// the modules no other filter names.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LevelDirective {
+    /// A module path such as `synic` or `hyperv::synic`, matching every
+    /// module whose path contains it as whole segments.
+    pub module: Option<String>,
+    /// The most verbose level written.
+    pub level: log::LevelFilter,
+}
+
+/// Parses log level filters: a comma separated list of `module=level`, and
+/// optionally a bare `level` for the modules not listed, e.g.
+/// `info,synic=debug,hypercall=warn`.
+pub fn parse_log_levels(spec: &str) -> Option<Vec<LevelDirective>> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty())
+        .map(|directive| match directive.split_once('=') {
+            Some((module, level)) => {
+                let module = module.trim();
+                if module.is_empty() {
+                    return None;
+                }
+                Some(LevelDirective {
+                    module: Some(module.to_owned()),
+                    level: level.trim().parse().ok()?,
+                })
+            }
+            None => Some(LevelDirective {
+                module: None,
+                level: directive.parse().ok()?,
+            }),
+        })
+        .collect()
+}
+
+/// Whether `module` names `target`, or one of its parents, as whole path
+/// segments.
+fn module_matches(target: &str, module: &str) -> bool {
+    target.match_indices(module).any(|(i, _)| {
+        let rest = &target[i + module.len()..];
+        (i == 0 || target[..i].ends_with("::")) && (rest.is_empty() || rest.starts_with("::"))
+    })
+}
+
+/// Returns the level log entries of `target`, a module path, are filtered
+/// at: that of the longest filter matching it, the last one if several
+/// match equally.
+fn level_for(directives: &[LevelDirective], target: &str) -> log::LevelFilter {
+    let default = directives
+        .iter()
+        .rev()
+        .find(|d| d.module.is_none())
+        .map_or(DEFAULT_LEVEL, |d| d.level);
+    directives
+        .iter()
+        .filter_map(|d| Some((d.module.as_deref()?, d.level)))
+        .filter(|(module, _)| module_matches(target, module))
+        .max_by_key(|(module, _)| module.len())
+        .map_or(default, |(_, level)| level)
+}
+
+/// Replaces the log level filters. Log entries of modules no filter names
+/// are written up to the level of the bare filter, debug without one.
+///
+/// The allocation-free log path is not filtered.
+pub fn set_log_levels(directives: Vec<LevelDirective>) {
+    let max = directives
+        .iter()
+        .map(|d| d.level)
+        .chain(
+            directives
+                .iter()
+                .all(|d| d.module.is_some())
+                .then_some(DEFAULT_LEVEL),
+        )
+        .max()
+        .unwrap_or(DEFAULT_LEVEL);
+    let summary = directives
+        .iter()
+        .map(|d| match &d.module {
+            Some(module) => format!("{}={}", module, d.level),
+            None => d.level.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    *LOG_LEVELS.lock() = directives;
+    log::set_max_level(max);
+    log::info!("log levels: {}", summary);
+}
+
+/// Returns the log level filters, e.g. to restore them after
+/// [`set_log_levels`].
+pub fn log_levels() -> Vec<LevelDirective> {
+    LOG_LEVELS.lock().clone()
+}
+
+/// Serves retransmission requests until the VM is torn down, once the run
+/// ended.
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+pub fn serve_retransmits() -> ! {
+    let serial = Serial::selected();
+    loop {
+        if let Ok(line) = serial.read_line(u64::MAX) {
+            if !handle_host_line(&line) {
+                log::warn!("ignoring serial line {:?}", line);
+            }
+        }
+    }
+}
+
+/// Moves the log output and the harness commands to the serial port of
+/// `config`, once what was written to the current one has drained.
+pub fn select_serial(config: SerialConfig) {
+    let previous = crate::arch::serial::config();
+    crate::arch::serial::set_config(config);
+    #[cfg(not(test))]
+    {
+        let mut writer = LOGGER.get_writer();
+        writer.flush();
+        writer.reconfigure(config);
+    }
+    log::info!("serial output moved from {:?} to {:?}", previous, config);
+}
+
+/// Optional second output every line is mirrored to, see
+/// [`set_console_mirror`].
+static CONSOLE_MIRROR: Mutex<Option<fn(&str)>> = Mutex::new(None);
+
+/// Sets or clears the function all output is mirrored to in addition to the
+/// logger's writer.
+///
+/// Used to show output on the firmware console while it is available, so
+/// that failures are visible even if the serial port is misconfigured.
+pub(crate) fn set_console_mirror(mirror: Option<fn(&str)>) {
+    *CONSOLE_MIRROR.lock() = mirror;
+}
+
+/// Writes `s`, a JSON line, to the log output and, if set, the console
+/// mirror.
+pub(crate) fn write_output(s: &str) {
+    emit(Some(s), None);
+    if let Some(mirror) = *CONSOLE_MIRROR.lock() {
+        mirror(s);
+    }
+}
+
 /// A logger that writes log messages to a provided writer, such as a serial port.
 pub struct TmkLogger<T> {
     writer: T,
@@ -84,42 +823,726 @@ pub fn get_writer(&self) -> MutexGuard<'_, T>
     {
         self.writer.lock()
     }
+
+    /// Returns a lock guard to the underlying writer, or `None` if it is in
+    /// use.
+    pub fn try_get_writer(&self) -> Option<MutexGuard<'_, T>>
+    where
+        T: Write + Send,
+    {
+        self.writer.try_lock()
+    }
 }
 
 impl<T> log::Log for TmkLogger<Mutex<T>>
 where
     T: Write + Send,
 {
-    fn enabled(&self, _metadata: &log::Metadata<'_>) -> bool {
-        true
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        metadata.level() <= level_for(&LOG_LEVELS.lock(), metadata.target())
     }
 
     fn log(&self, record: &log::Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
         let str = format(*record.args());
         let line = format!(
             "{}:{}",
             record.file().unwrap_or_default(),
             record.line().unwrap_or_default()
         );
+        if log_format() == LogFormat::Cbor {
+            write_serialized(&LogEntry::new(record.level(), &str, &line));
+            return;
+        }
         let str = format_log_string_to_json(&str, &line, true, record.level());
-        _ = self.writer.lock().write_str(str.as_str());
+        write_output(&str);
     }
 
     fn flush(&self) {}
 }
 
+/// Size of the per-VP buffer of the allocation-free log path, including the
+/// JSON framing.
+const NOSTDALLOC_BUFFER_SIZE: usize = 512;
+/// Room kept after the message for the source location.
+const NOSTDALLOC_LOCATION_SIZE: usize = 96;
+/// Closing of a log line, always written in full.
+const NOSTDALLOC_END: &str = "\"}\n";
+/// Room kept for the fields of integrity checking, see [`seal_line`].
+const NOSTDALLOC_SEAL_SIZE: usize = 48;
+
+/// A fixed-size text buffer that truncates, on a character boundary, what
+/// does not fit below its current limit.
+pub struct FixedBuf<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    limit: usize,
+    truncated: bool,
+}
+
+impl<const N: usize> FixedBuf<N> {
+    /// Create an empty buffer.
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            limit: N,
+            truncated: false,
+        }
+    }
+
+    /// Empty the buffer and lift the limit.
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.limit = N;
+        self.truncated = false;
+    }
+
+    /// Returns the buffered text.
+    pub fn as_str(&self) -> &str {
+        // SAFETY: only whole characters of `&str`s are ever copied in.
+        unsafe { core::str::from_utf8_unchecked(&self.buf[..self.len]) }
+    }
+
+    /// Returns true if text was dropped since the last [`FixedBuf::clear`].
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    fn set_limit(&mut self, limit: usize) {
+        self.limit = limit.min(N);
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+
+    /// Append `s` only if it fits entirely.
+    fn push_whole(&mut self, s: &str) -> core::fmt::Result {
+        if self.len + s.len() > self.limit {
+            self.truncated = true;
+            return Err(core::fmt::Error);
+        }
+        self.buf[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+        self.len += s.len();
+        Ok(())
+    }
+}
+
+impl<const N: usize> Default for FixedBuf<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Write for FixedBuf<N> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let room = self.limit.saturating_sub(self.len);
+        let mut take = s.len().min(room);
+        while !s.is_char_boundary(take) {
+            take -= 1;
+        }
+        self.buf[self.len..self.len + take].copy_from_slice(&s.as_bytes()[..take]);
+        self.len += take;
+        if take < s.len() {
+            self.truncated = true;
+        }
+        Ok(())
+    }
+}
+
+/// Escapes what is written through it as the contents of a JSON string.
+/// Characters and escape sequences are written whole, and the first one that
+/// does not fit ends the write, so that truncation cannot leave a dangling
+/// backslash.
+struct JsonEscape<'a, const N: usize>(&'a mut FixedBuf<N>);
+
+impl<const N: usize> Write for JsonEscape<'_, N> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        const HEX: &[u8; 16] = b"0123456789abcdef";
+        for c in s.chars() {
+            match c {
+                '"' => self.0.push_whole("\\\"")?,
+                '\\' => self.0.push_whole("\\\\")?,
+                '\n' => self.0.push_whole("\\n")?,
+                c if (c as u32) < 0x20 => {
+                    let escape = [
+                        b'\\',
+                        b'u',
+                        b'0',
+                        b'0',
+                        HEX[(c as usize) >> 4],
+                        HEX[(c as usize) & 0xf],
+                    ];
+                    // The escape is ASCII.
+                    self.0.push_whole(core::str::from_utf8(&escape).unwrap())?;
+                }
+                c => self.0.push_whole(c.encode_utf8(&mut [0; 4]))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+static NOSTDALLOC_BUFFERS: [Mutex<FixedBuf<NOSTDALLOC_BUFFER_SIZE>>; MAX_VPS] =
+    [const { Mutex::new(FixedBuf::new()) }; MAX_VPS];
+static NOSTDALLOC_DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Writes a log line without allocating, for interrupt and fault handlers.
+///
+/// The line is rendered into a buffer of the current VP, with the message
+/// truncated to fit, and dropped if the buffer or the output is already in
+/// use, e.g. by the code the handler interrupted, or while a boundary is
+/// crossed, see [`begin_boundary`], as holding it back would allocate.
+/// Use through [`log_static!`] or [`log_fmt_nostdalloc!`].
+pub fn log_nostdalloc(
+    level: log::Level,
+    file: &'static str,
+    line: u32,
+    args: core::fmt::Arguments<'_>,
+) {
+    // Only VPs past the buffers, which the test context never starts, have
+    // no buffer; their lines are dropped.
+    let Ok(vp_index) = vp_slot(HvTestCtx::get_vp_idx()) else {
+        NOSTDALLOC_DROPPED.fetch_add(1, Ordering::Relaxed);
+        return;
+    };
+    // Holding the line back would allocate.
+    if IN_BOUNDARY.load(Ordering::Acquire) {
+        NOSTDALLOC_DROPPED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    let Some(mut buf) = NOSTDALLOC_BUFFERS[vp_index].try_lock() else {
+        NOSTDALLOC_DROPPED.fetch_add(1, Ordering::Relaxed);
+        return;
+    };
+    buf.clear();
+    _ = write!(
+        buf,
+        "{{\"type\":\"log\",\"level\":\"{}\",\"message\":\"",
+        level.as_str()
+    );
+    let end = NOSTDALLOC_BUFFER_SIZE - NOSTDALLOC_SEAL_SIZE - NOSTDALLOC_END.len();
+    buf.set_limit(end - NOSTDALLOC_LOCATION_SIZE);
+    _ = JsonEscape(&mut *buf).write_fmt(args);
+    buf.set_limit(end);
+    _ = buf.write_str("\",\"line\":\"");
+    _ = write!(JsonEscape(&mut *buf), "{}:{}", file, line);
+    buf.set_limit(NOSTDALLOC_BUFFER_SIZE);
+    _ = buf.write_str(NOSTDALLOC_END);
+
+    match LOGGER.try_get_writer() {
+        Some(mut writer) => {
+            // Sealed as by `seal_line`, but not kept for retransmission,
+            // which would allocate.
+            if INTEGRITY.load(Ordering::Relaxed) {
+                let seq = NEXT_SEQ.fetch_add(1, Ordering::Relaxed);
+                buf.truncate(buf.as_str().len() - "}\n".len());
+                _ = write!(buf, ",\"seq\":{}", seq);
+                let crc = crc32fast::hash(buf.as_str().as_bytes());
+                _ = write!(buf, ",\"crc\":{}}}\n", crc);
+            }
+            _ = writer.write_str(buf.as_str());
+        }
+        None => {
+            NOSTDALLOC_DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Returns the number of lines the allocation-free log path dropped since
+/// boot because its buffer or the output was busy.
+pub fn nostdalloc_dropped() -> u64 {
+    NOSTDALLOC_DROPPED.load(Ordering::Relaxed)
+}
+
+#[macro_export]
+/// Logs a static message without allocating. Safe in interrupt and fault
+/// handlers, see [`log_nostdalloc`](crate::tmk_logger::log_nostdalloc).
+macro_rules! log_static {
+    ($level:expr, $message:expr) => {
+        $crate::tmk_logger::log_nostdalloc(
+            $level,
+            core::file!(),
+            core::line!(),
+            format_args!("{}", $message),
+        )
+    };
+}
+
+#[macro_export]
+/// Logs a formatted message without allocating, truncating it to a fixed
+/// size. Safe in interrupt and fault handlers, see
+/// [`log_nostdalloc`](crate::tmk_logger::log_nostdalloc).
+macro_rules! log_fmt_nostdalloc {
+    ($level:expr, $($arg:tt)+) => {
+        $crate::tmk_logger::log_nostdalloc(
+            $level,
+            core::file!(),
+            core::line!(),
+            format_args!($($arg)+),
+        )
+    };
+}
+
+/// A device the logger writes to.
+pub trait LogOutput: Write + Send {
+    /// Writes `bytes` as they are, for the frames of the CBOR format.
+    fn write_bytes(&mut self, bytes: &[u8]);
+
+    /// Waits until what was written has left the device.
+    fn flush(&mut self) {}
+}
+
 #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
-type SerialPortWriter = Serial<InstrIoAccess>;
-#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
-/// The global logger instance for x86_64 architecture, using COM2 serial port.
-pub static LOGGER: TmkLogger<Mutex<SerialPortWriter>> =
-    TmkLogger::new(SerialPortWriter::new(SerialPort::COM2, InstrIoAccess));
+impl LogOutput for Serial<InstrIoAccess> {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        Serial::write_bytes(self, bytes);
+    }
+
+    fn flush(&mut self) {
+        Serial::flush(self);
+    }
+}
 
 #[cfg(target_arch = "aarch64")] // xtask-fmt allow-target-arch sys-crate
-/// The global logger instance for aarch64 architecture, using the default serial implementation.
-pub static LOGGER: TmkLogger<Mutex<Serial>> = TmkLogger::new(Serial {});
+impl LogOutput for Serial {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        Serial::write_bytes(self, bytes);
+    }
+
+    fn flush(&mut self) {
+        Serial::flush(self);
+    }
+}
+
+/// Output of the logger in host unit tests, collecting what is written.
+#[cfg(test)]
+pub struct CaptureOutput(Vec<u8>);
+
+#[cfg(test)]
+impl CaptureOutput {
+    /// Creates an empty output.
+    pub const fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    /// Returns what was written since the last call.
+    pub fn take(&mut self) -> Vec<u8> {
+        core::mem::take(&mut self.0)
+    }
+}
+
+#[cfg(test)]
+impl Default for CaptureOutput {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+impl Write for CaptureOutput {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.0.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl LogOutput for CaptureOutput {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+}
+
+/// Serializes the host unit tests looking at the log output.
+#[cfg(test)]
+static CAPTURE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Runs `f` and returns what it wrote to the log output. Tests running
+/// alongside may write too, so callers look for their own lines.
+#[cfg(test)]
+pub(crate) fn capture_output(f: impl FnOnce()) -> Vec<u8> {
+    capture_output_as(LogFormat::Json, f)
+}
+
+/// Runs `f` with the log output in `format` and returns what it wrote, as
+/// [`capture_output`] does. The format is switched back to JSON after.
+#[cfg(test)]
+fn capture_output_as(format: LogFormat, f: impl FnOnce()) -> Vec<u8> {
+    let _guard = CAPTURE_LOCK.lock();
+    CBOR_OUTPUT.store(format == LogFormat::Cbor, Ordering::Relaxed);
+    LOGGER.get_writer().take();
+    f();
+    let output = LOGGER.get_writer().take();
+    CBOR_OUTPUT.store(false, Ordering::Relaxed);
+    output
+}
+
+/// Parses the JSON lines of captured output.
+#[cfg(test)]
+pub(crate) fn json_lines(output: &[u8]) -> Vec<serde_json::Value> {
+    core::str::from_utf8(output)
+        .expect("JSON output is text")
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("each line is a JSON object"))
+        .collect()
+}
+
+#[cfg(all(not(test), target_arch = "x86_64"))] // xtask-fmt allow-target-arch sys-crate
+type OutputWriter = Serial<InstrIoAccess>;
+#[cfg(all(not(test), target_arch = "x86_64"))] // xtask-fmt allow-target-arch sys-crate
+/// The global logger instance for x86_64 architecture, on COM2 until
+/// [`select_serial`] picks another serial port.
+pub static LOGGER: TmkLogger<Mutex<OutputWriter>> = TmkLogger::new(OutputWriter::with_config(
+    SerialConfig::DEFAULT,
+    InstrIoAccess,
+));
+
+#[cfg(all(not(test), target_arch = "aarch64"))] // xtask-fmt allow-target-arch sys-crate
+type OutputWriter = Serial;
+#[cfg(all(not(test), target_arch = "aarch64"))] // xtask-fmt allow-target-arch sys-crate
+/// The global logger instance for aarch64 architecture, on the PL011
+/// Hyper-V emulates until [`select_serial`] picks another UART.
+pub static LOGGER: TmkLogger<Mutex<OutputWriter>> =
+    TmkLogger::new(OutputWriter::with_config(SerialConfig::DEFAULT));
+
+#[cfg(test)]
+type OutputWriter = CaptureOutput;
+#[cfg(test)]
+/// The global logger instance of host unit tests, capturing the output.
+pub static LOGGER: TmkLogger<Mutex<OutputWriter>> = TmkLogger::new(CaptureOutput::new());
 
 /// Initializes the global logger.
 pub fn init() -> Result<(), SetLoggerError> {
-    log::set_logger(&LOGGER).map(|()| log::set_max_level(log::LevelFilter::Debug))
+    // The firmware leaves the COM ports set up, but not necessarily the
+    // PL011.
+    #[cfg(all(not(test), target_arch = "aarch64"))] // xtask-fmt allow-target-arch sys-crate
+    LOGGER.get_writer().init();
+    log::set_logger(&LOGGER).map(|()| log::set_max_level(DEFAULT_LEVEL))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_buf_escape_truncation() {
+        let mut buf = FixedBuf::<8>::new();
+        _ = write!(JsonEscape(&mut buf), "a\"b\u{1}\"");
+        assert_eq!(buf.as_str(), "a\\\"b");
+        assert!(buf.truncated());
+
+        buf.clear();
+        _ = buf.write_str("ab\u{e9}\u{e9}\u{e9}\u{e9}");
+        assert_eq!(buf.as_str(), "ab\u{e9}\u{e9}\u{e9}");
+        assert!(buf.truncated());
+    }
+
+    #[test]
+    fn test_parse_format_reply() {
+        assert_eq!(
+            parse_format_reply("log_format cbor"),
+            Some(FormatReply {
+                format: LogFormat::Cbor,
+                integrity: false,
+                compression: Compression::None,
+            })
+        );
+        assert_eq!(
+            parse_format_reply(" log_format json integrity "),
+            Some(FormatReply {
+                format: LogFormat::Json,
+                integrity: true,
+                compression: Compression::None,
+            })
+        );
+        assert_eq!(
+            parse_format_reply("log_format cbor lz4 integrity"),
+            Some(FormatReply {
+                format: LogFormat::Cbor,
+                integrity: true,
+                compression: Compression::Lz4,
+            })
+        );
+        assert_eq!(parse_format_reply("log_format xml"), None);
+        assert_eq!(parse_format_reply("log_format cbor json"), None);
+        assert_eq!(parse_format_reply("log_format cbor lz4 lz4"), None);
+        assert_eq!(parse_format_reply("end"), None);
+    }
+    fn find<'a>(lines: &'a [serde_json::Value], record_type: &str) -> &'a serde_json::Value {
+        lines
+            .iter()
+            .find(|line| line["type"] == record_type)
+            .expect("record written")
+    }
+
+    #[test]
+    fn test_log_and_record_lines() {
+        let output = capture_output(|| {
+            log::Log::log(
+                &LOGGER,
+                &log::Record::builder()
+                    .args(format_args!("value \"{}\"", 7))
+                    .level(log::Level::Warn)
+                    .file(Some("file.rs"))
+                    .line(Some(3))
+                    .build(),
+            );
+            write_record(&LogRetransmitRecord {
+                record_type: "log_retransmit",
+                from: 4,
+                count: 2,
+                oldest: None,
+            });
+        });
+        let lines = json_lines(&output);
+        let log = lines
+            .iter()
+            .find(|line| line["line"] == "file.rs:3")
+            .unwrap();
+        assert_eq!(
+            *log,
+            serde_json::json!({
+                "type": "log",
+                "level": "WARN",
+                "message": "value \"7\"",
+                "line": "file.rs:3",
+            })
+        );
+        assert_eq!(
+            *find(&lines, "log_retransmit"),
+            serde_json::json!({
+                "type": "log_retransmit",
+                "from": 4,
+                "count": 2,
+                "oldest": null,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_log_levels() {
+        let directives = parse_log_levels(" info, synic=debug,hyperv::arch=warn ,").unwrap();
+        assert_eq!(
+            directives,
+            [
+                LevelDirective {
+                    module: None,
+                    level: log::LevelFilter::Info,
+                },
+                LevelDirective {
+                    module: Some("synic".into()),
+                    level: log::LevelFilter::Debug,
+                },
+                LevelDirective {
+                    module: Some("hyperv::arch".into()),
+                    level: log::LevelFilter::Warn,
+                },
+            ]
+        );
+        assert_eq!(parse_log_levels("synic=loud"), None);
+        assert_eq!(parse_log_levels("=debug"), None);
+        assert_eq!(parse_log_levels(""), Some(Vec::new()));
+    }
+
+    #[test]
+    fn test_level_for() {
+        let directives = parse_log_levels("info,synic=trace,hyperv=warn,hyperv::arch=off").unwrap();
+        let level = |target| level_for(&directives, target);
+        assert_eq!(
+            level("opentmk::platform::hyperv::synic"),
+            log::LevelFilter::Trace
+        );
+        assert_eq!(
+            level("opentmk::platform::hyperv::ctx"),
+            log::LevelFilter::Warn
+        );
+        assert_eq!(
+            level("opentmk::platform::hyperv::arch::hypercall"),
+            log::LevelFilter::Off
+        );
+        assert_eq!(
+            level("opentmk::platform::hypervisor"),
+            log::LevelFilter::Info
+        );
+        assert_eq!(level_for(&[], "opentmk"), DEFAULT_LEVEL);
+    }
+
+    #[test]
+    fn test_log_levels_filter() {
+        let log = |target, level| {
+            log::Log::log(
+                &LOGGER,
+                &log::Record::builder()
+                    .args(format_args!("from {}", target))
+                    .level(level)
+                    .target(target)
+                    .build(),
+            )
+        };
+        let output = capture_output(|| {
+            let saved = log_levels();
+            assert!(handle_host_line("log_levels warn,synic=debug"));
+            log("opentmk::platform::hyperv::synic", log::Level::Debug);
+            log("opentmk::platform::hyperv::ctx", log::Level::Info);
+            log("opentmk::platform::hyperv::ctx", log::Level::Warn);
+            set_log_levels(saved);
+        });
+        let messages: Vec<_> = json_lines(&output)
+            .into_iter()
+            .filter_map(|line| {
+                line["message"]
+                    .as_str()?
+                    .strip_prefix("from ")
+                    .map(String::from)
+            })
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                "opentmk::platform::hyperv::synic",
+                "opentmk::platform::hyperv::ctx"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nostdalloc_line() {
+        let output = capture_output(|| {
+            log_nostdalloc(
+                log::Level::Error,
+                "handler.rs",
+                9,
+                format_args!("vector {}\n\"{}\"", 14, "\u{1}"),
+            )
+        });
+        let lines = json_lines(&output);
+        let log = lines
+            .iter()
+            .find(|line| line["line"] == "handler.rs:9")
+            .unwrap();
+        assert_eq!(log["level"], "ERROR");
+        assert_eq!(log["message"], "vector 14\n\"\u{1}\"");
+    }
+
+    #[test]
+    fn test_seal_line() {
+        let sealed = seal_line("{\"type\":\"log\"}\n", 5);
+        assert!(sealed.ends_with("}\n"));
+        let (checked, _) = sealed.split_once(",\"crc\":").unwrap();
+        let value: serde_json::Value = serde_json::from_str(&sealed).unwrap();
+        assert_eq!(value["type"], "log");
+        assert_eq!(value["seq"], 5);
+        assert_eq!(value["crc"], crc32fast::hash(checked.as_bytes()));
+
+        let empty: serde_json::Value = serde_json::from_str(&seal_line("{}", 6)).unwrap();
+        assert_eq!(empty["seq"], 6);
+    }
+
+    #[test]
+    fn test_seal_frame() {
+        let payload = [0xa1, 0x61, 0x61, 0x01];
+        let frame = seal_frame(&payload, None);
+        assert_eq!(frame[0], FRAME_START);
+        assert_eq!(frame[1..5], 4u32.to_le_bytes());
+        assert_eq!(frame[5..], payload);
+
+        let frame = seal_frame(&payload, Some(9));
+        assert_eq!(frame.len(), 1 + 4 + 8 + payload.len() + 4);
+        assert_eq!(frame[1..5], 4u32.to_le_bytes());
+        assert_eq!(frame[5..13], 9u64.to_le_bytes());
+        assert_eq!(frame[13..17], payload);
+        let crc = crc32fast::hash(&frame[5..17]);
+        assert_eq!(frame[17..], crc.to_le_bytes());
+    }
+
+    #[test]
+    fn test_seal_compressed_frame() {
+        let compressed = [0x1f, 0x00, 0x01, 0x00];
+        let frame = seal_compressed_frame(Compression::Lz4, &compressed, 20, Some(3));
+        assert_eq!(frame[0], COMPRESSED_FRAME_START);
+        assert_eq!(frame[1], Compression::Lz4.tag());
+        assert_eq!(frame[2..6], 4u32.to_le_bytes());
+        assert_eq!(frame[6..10], 20u32.to_le_bytes());
+        assert_eq!(frame[10..18], 3u64.to_le_bytes());
+        assert_eq!(frame[18..22], compressed);
+        let crc = crc32fast::hash(&frame[10..22]);
+        assert_eq!(frame[22..], crc.to_le_bytes());
+    }
+
+    #[test]
+    fn test_dump_memory() {
+        let mut memory = vec![0u8; DUMP_CHUNK_SIZE + 10];
+        memory[10] = 0xab;
+        let mut stats = DumpStats::default();
+        let output = capture_output(|| {
+            LZ4.store(true, Ordering::Relaxed);
+            stats = dump_memory("page", 0x1000, &memory);
+            LZ4.store(false, Ordering::Relaxed);
+        });
+        assert_eq!(stats.records, 2);
+        assert_eq!(stats.len, memory.len());
+        assert!(stats.encoded < memory.len() / 10);
+        let lines = json_lines(&output);
+        let dumps: Vec<_> = lines
+            .iter()
+            .filter(|line| line["type"] == "memory_dump")
+            .collect();
+        assert_eq!(dumps.len(), 2);
+        assert_eq!(dumps[0]["label"], "page");
+        assert_eq!(dumps[0]["compression"], "lz4");
+        assert_eq!(dumps[0]["len"], DUMP_CHUNK_SIZE);
+        assert_eq!(dumps[1]["address"], 0x1000 + DUMP_CHUNK_SIZE as u64);
+        assert_eq!(dumps[1]["len"], 10);
+        // Too short to hold a match.
+        assert_eq!(dumps[1]["compression"], "none");
+        assert_eq!(dumps[1]["data"], "00".repeat(10));
+    }
+
+    #[test]
+    fn test_cbor_frames() {
+        let record = LogRetransmitRecord {
+            record_type: "log_retransmit",
+            from: 0,
+            count: 0,
+            oldest: Some(1),
+        };
+        let output = capture_output_as(LogFormat::Cbor, || write_record(&record));
+        let frame = seal_frame(&crate::cbor::to_vec(&record).unwrap(), None);
+        assert!(output.windows(frame.len()).any(|window| window == frame));
+    }
+
+    #[test]
+    fn test_boundary_holds_output() {
+        let mut during = Vec::new();
+        let output = capture_output(|| {
+            begin_boundary("test");
+            write_output("{\"type\":\"held\"}\n");
+            during = LOGGER.get_writer().take();
+            end_boundary();
+        });
+        let during = json_lines(&during);
+        assert_eq!(find(&during, "log_boundary")["phase"], "begin");
+        assert!(during.iter().all(|line| line["type"] != "held"));
+
+        let lines = json_lines(&output);
+        let end = lines
+            .iter()
+            .position(|line| line["type"] == "log_boundary")
+            .unwrap();
+        assert_eq!(
+            lines[end],
+            serde_json::json!({
+                "type": "log_boundary",
+                "boundary": "test",
+                "phase": "end",
+                "held": 1,
+                "dropped": 0,
+            })
+        );
+        assert!(lines[end..].iter().any(|line| line["type"] == "held"));
+    }
 }
@@ -1,9 +1,13 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
-//! TMK error definitions and result type alias.
+//! TMK error and status definitions and result type alias.
 //!
+//! [`TmkError`] is the error type of the platform and device APIs. Subsystems
+//! with failures of their own, such as bring-up or the synchronization
+//! helpers, have dedicated error types that convert into it.
 
+use serde::Serialize;
 use thiserror::Error;
 
 /// Primary error type produced by TMK operations.
@@ -229,3 +233,95 @@ pub enum TmkError {
 
 /// Result type alias for TMK operations using `TmkError`.
 pub type TmkResult<T> = Result<T, TmkError>;
+
+/// Number of VPs the per-VP tables of the TMK hold. VP indexes are APIC
+/// IDs, which fit in a byte. The test context refuses to run on or start a
+/// VP past it, so the index of the current VP always has a slot.
+pub const MAX_VPS: usize = 256;
+
+/// Returns the slot of VP `vp_index` in a per-VP table, failing with
+/// [`TmkError::InvalidVpIndex`] for a VP past [`MAX_VPS`].
+pub fn vp_slot(vp_index: u32) -> TmkResult<usize> {
+    let slot = vp_index as usize;
+    if slot < MAX_VPS {
+        Ok(slot)
+    } else {
+        Err(TmkError::InvalidVpIndex)
+    }
+}
+
+/// Outcome of a test.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TmkStatus {
+    /// The test ran to completion.
+    Passed,
+    /// An assertion of the test failed.
+    Failed,
+    /// The test did not run, or gave up because a prerequisite is missing.
+    Skipped,
+    /// A check of the test known not to hold yet failed, see
+    /// `tmk_xfail!`.
+    ExpectedFailure,
+    /// A setup step the test relies on failed because the host does not
+    /// offer the feature, e.g. VSM, see `tmk_setup!`.
+    Blocked,
+}
+
+/// Errors returned by the cross-VP synchronization helpers.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Error)]
+pub enum SyncError {
+    /// Returned when a VP uses a shard index the helper was not created
+    /// with.
+    #[error("shard {0} out of range")]
+    InvalidShard(usize),
+}
+
+/// Errors returned while bringing up the TMK, before tests run.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Error)]
+pub enum BootError {
+    /// Returned when the heap could not be allocated.
+    #[error("heap allocation failed")]
+    HeapAllocationFailed,
+    /// Returned when the logger could not be installed.
+    #[error("logger initialization failed")]
+    LoggerInitFailed,
+    /// Returned when a firmware variable could not be read or written.
+    #[error("firmware variable {0} access failed")]
+    VariableAccessFailed(&'static str),
+}
+
+impl TmkError {
+    /// Whether the error means the host does not offer the operation, by
+    /// policy or for lack of support, rather than that it went wrong.
+    pub fn is_unsupported(&self) -> bool {
+        matches!(
+            self,
+            TmkError::AccessDenied
+                | TmkError::OperationDenied
+                | TmkError::FeatureUnavailable
+                | TmkError::ProcessorFeatureNotSupported
+                | TmkError::InvalidHypercallCode
+                | TmkError::NotImplemented
+        )
+    }
+}
+
+impl From<SyncError> for TmkError {
+    fn from(e: SyncError) -> Self {
+        match e {
+            SyncError::InvalidShard(_) => TmkError::InvalidParameter,
+        }
+    }
+}
+
+impl From<BootError> for TmkError {
+    fn from(e: BootError) -> Self {
+        match e {
+            BootError::HeapAllocationFailed => TmkError::AllocationFailed,
+            BootError::LoggerInitFailed | BootError::VariableAccessFailed(_) => {
+                TmkError::OperationFailed
+            }
+        }
+    }
+}
@@ -2,46 +2,391 @@
 // Licensed under the MIT License.
 
 use core::alloc::GlobalAlloc;
+use core::alloc::Layout;
 use core::cell::RefCell;
+use core::ops::Range;
+use core::ptr::NonNull;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
 
+use alloc::vec::Vec;
+use hvdef::Vtl;
 use linked_list_allocator::LockedHeap;
+use serde::Serialize;
 use spin::Mutex;
 use uefi::allocator::Allocator;
 use uefi::boot::AllocateType;
 use uefi::boot::MemoryType;
 use uefi::boot::{self};
 
+use crate::platform::hyperv::ctx::HvTestCtx;
+use crate::platform::hyperv::ctx::active_vtl;
+use crate::platform::hyperv::irq_hvcall::in_irq_context;
+use crate::tmkdefs::BootError;
+use crate::tmkdefs::MAX_VPS;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
 pub const SIZE_1MB: usize = 1024 * 1024;
 const PAGE_SIZE: usize = 4096;
+/// Size of the pool [`MemoryAllocator::alloc_below`] allocates from once
+/// boot services are exited.
+pub const LOW_POOL_SIZE: usize = 16 * SIZE_1MB;
+/// Address the pool ends below, so that it can back 32-bit DMA.
+pub const LOW_POOL_LIMIT: u64 = 1 << 32;
+/// Number of VTLs a quota can be set for.
+const QUOTA_VTLS: usize = 2;
+/// Owner of allocations made while no quota was set.
+const UNTRACKED: u32 = u32::MAX;
+/// Allocations from interrupt context whose callers are kept per test.
+const IRQ_ALLOC_HITS: usize = 16;
+/// Return addresses kept for each of them, innermost first.
+const IRQ_ALLOC_FRAMES: usize = 6;
+/// Farthest above the stack pointer a frame is followed.
+const MAX_STACK_WALK: u64 = 1 << 20;
 
 #[global_allocator]
 pub static ALLOCATOR: MemoryAllocator = MemoryAllocator {
     use_locked_heap: Mutex::new(RefCell::new(false)),
     locked_heap: LockedHeap::empty(),
     uefi_allocator: Allocator {},
+    fault_injection: Mutex::new(FaultInjection {
+        mode: None,
+        allocations: 0,
+        bytes: 0,
+        failures: 0,
+    }),
+    low_pool: LockedHeap::empty(),
+    low_pool_range: Mutex::new(0..0),
+    boot_services_exited: AtomicBool::new(false),
+    heap_range: Mutex::new(0..0),
+    quotas: Quotas {
+        tracking: AtomicBool::new(false),
+        vtls: [const { Quota::new() }; QUOTA_VTLS],
+        vps: [const { Quota::new() }; MAX_VPS],
+    },
+    irq_audit: IrqAudit {
+        enabled: option_env!("OPENTMK_IRQ_ALLOC_AUDIT").is_some(),
+        total: AtomicU64::new(0),
+        hits: Mutex::new(IrqAllocHits {
+            hits: [IrqAlloc {
+                vp_index: 0,
+                size: 0,
+                callers: [0; IRQ_ALLOC_FRAMES],
+            }; IRQ_ALLOC_HITS],
+            len: 0,
+        }),
+    },
 };
 
+/// Snapshot of the capped heap usage, in bytes.
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+/// When injected allocation failures happen, see
+/// [`MemoryAllocator::with_injected_failures`].
+#[derive(Copy, Clone, Debug)]
+pub enum AllocFailure {
+    /// Fail every Nth allocation, counting from the first one made after
+    /// the failures were armed.
+    EveryNth(usize),
+    /// Fail every allocation once this many bytes were allocated since the
+    /// failures were armed.
+    AfterBytes(usize),
+}
+
+struct FaultInjection {
+    mode: Option<AllocFailure>,
+    allocations: usize,
+    bytes: usize,
+    failures: usize,
+}
+
+impl FaultInjection {
+    fn should_fail(&mut self, size: usize) -> bool {
+        let fail = match self.mode {
+            None => return false,
+            Some(AllocFailure::EveryNth(n)) => {
+                self.allocations += 1;
+                n != 0 && self.allocations % n == 0
+            }
+            Some(AllocFailure::AfterBytes(budget)) => self.bytes + size > budget,
+        };
+        if fail {
+            self.failures += 1;
+        } else {
+            self.bytes += size;
+        }
+        fail
+    }
+}
+
+/// An allocation made from interrupt context, see
+/// [`MemoryAllocator::write_irq_alloc_record`].
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct IrqAlloc {
+    pub vp_index: u32,
+    pub size: usize,
+    /// Return addresses of the allocating code, innermost first, zero past
+    /// the outermost frame found.
+    pub callers: [u64; IRQ_ALLOC_FRAMES],
+}
+
+struct IrqAllocHits {
+    hits: [IrqAlloc; IRQ_ALLOC_HITS],
+    len: usize,
+}
+
+/// Auditing of allocations made from interrupt context, which may find the
+/// heap locked by the code they interrupted.
+struct IrqAudit {
+    /// Set at build time with the `OPENTMK_IRQ_ALLOC_AUDIT` environment
+    /// variable.
+    enabled: bool,
+    /// Allocations from interrupt context since the last report.
+    total: AtomicU64,
+    hits: Mutex<IrqAllocHits>,
+}
+
+impl IrqAudit {
+    fn check(&self, size: usize) {
+        if !self.enabled || !in_irq_context() {
+            return;
+        }
+        self.total.fetch_add(1, Ordering::Relaxed);
+        let mut hit = IrqAlloc {
+            vp_index: HvTestCtx::get_vp_idx(),
+            size,
+            callers: [0; IRQ_ALLOC_FRAMES],
+        };
+        walk_frames(&mut hit.callers);
+        crate::log_fmt_nostdalloc!(
+            log::Level::Warn,
+            "allocation of {} bytes in interrupt context from {:x?}",
+            size,
+            hit.callers
+        );
+        // Never wait in interrupt context, the hit is only counted if the
+        // list is busy.
+        if let Some(mut hits) = self.hits.try_lock()
+            && hits.len < IRQ_ALLOC_HITS
+        {
+            let len = hits.len;
+            hits.hits[len] = hit;
+            hits.len += 1;
+        }
+    }
+}
+
+/// Fills `callers` with the return addresses found by following the frame
+/// pointers from the caller of this function, stopping at a frame that
+/// does not lie above the previous one on the current stack.
+#[inline(never)]
+fn walk_frames(callers: &mut [u64]) {
+    let low = crate::arch::stack::stack_pointer();
+    let high = low.saturating_add(MAX_STACK_WALK);
+    let mut fp = crate::arch::stack::frame_pointer();
+    // The first frame is that of the allocator itself.
+    for depth in 0..=callers.len() {
+        if fp < low || fp >= high - 16 || !fp.is_multiple_of(8) {
+            return;
+        }
+        // SAFETY: the frame lies within the current stack, which is mapped,
+        // and frame pointers are kept in every build.
+        let (next, ret) = unsafe {
+            (
+                (fp as *const u64).read_volatile(),
+                (fp as *const u64).add(1).read_volatile(),
+            )
+        };
+        if depth > 0 {
+            callers[depth - 1] = ret;
+        }
+        if next <= fp {
+            return;
+        }
+        fp = next;
+    }
+}
+
+/// Which allocations a quota limits.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "scope", content = "index", rename_all = "snake_case")]
+pub enum QuotaScope {
+    /// Allocations made in the VTL, on any VP.
+    Vtl(u8),
+    /// Allocations made on the VP, in any VTL.
+    Vp(u32),
+}
+
+/// A quota and the usage it limits, in bytes.
+struct Quota {
+    /// `usize::MAX` if there is no limit.
+    limit: AtomicUsize,
+    used: AtomicUsize,
+    peak: AtomicUsize,
+    failures: AtomicUsize,
+}
+
+impl Quota {
+    const fn new() -> Self {
+        Self {
+            limit: AtomicUsize::new(usize::MAX),
+            used: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            failures: AtomicUsize::new(0),
+        }
+    }
+
+    fn charge(&self, size: usize) -> bool {
+        let used = self.used.fetch_add(size, Ordering::AcqRel) + size;
+        if used > self.limit.load(Ordering::Acquire) {
+            self.used.fetch_sub(size, Ordering::AcqRel);
+            self.failures.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        self.peak.fetch_max(used, Ordering::Relaxed);
+        true
+    }
+
+    fn credit(&self, size: usize) {
+        self.used.fetch_sub(size, Ordering::AcqRel);
+    }
+}
+
+/// Usage of a quota, as reported in the `allocator_stats` record.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct QuotaUsage {
+    /// The allocations limited.
+    #[serde(flatten)]
+    pub scope: QuotaScope,
+    /// The limit, `None` if only usage is tracked.
+    pub limit: Option<usize>,
+    /// Bytes allocated and not freed yet.
+    pub used: usize,
+    /// Highest usage since the quotas were last cleared.
+    pub peak: usize,
+    /// Allocations failed for exceeding the limit.
+    pub failures: usize,
+}
+
+/// Per-VTL and per-VP quotas of the capped heap.
+///
+/// Allocations are charged to the VP and VTL that made them, found from the
+/// APIC ID and [`active_vtl`](crate::platform::hyperv::ctx::active_vtl),
+/// and credited back to them when freed, whichever VP frees them. Only
+/// allocations made while a quota is set are counted.
+struct Quotas {
+    tracking: AtomicBool,
+    vtls: [Quota; QUOTA_VTLS],
+    vps: [Quota; MAX_VPS],
+}
+
+impl Quotas {
+    fn quota(&self, scope: QuotaScope) -> Option<&Quota> {
+        match scope {
+            QuotaScope::Vtl(vtl) => self.vtls.get(vtl as usize),
+            QuotaScope::Vp(vp) => self.vps.get(vp as usize),
+        }
+    }
+
+    fn owner_quotas(&self, owner: u32) -> (Option<&Quota>, Option<&Quota>) {
+        (
+            self.quota(QuotaScope::Vtl(owner as u8)),
+            self.quota(QuotaScope::Vp(owner >> 8)),
+        )
+    }
+
+    /// Charges `size` bytes to the current VP and VTL and returns the owner
+    /// to record, or `None` if that exceeds a quota.
+    fn charge(&self, size: usize) -> Option<u32> {
+        if !self.tracking.load(Ordering::Acquire) {
+            return Some(UNTRACKED);
+        }
+        let vp = HvTestCtx::get_vp_idx();
+        // The current VP always has a slot.
+        let vtl = active_vtl(vp).unwrap_or(Vtl::Vtl0);
+        let owner = vp << 8 | vtl as u32;
+        let (vtl, vp) = self.owner_quotas(owner);
+        if vtl.is_some_and(|q| !q.charge(size)) {
+            return None;
+        }
+        if vp.is_some_and(|q| !q.charge(size)) {
+            if let Some(q) = vtl {
+                q.credit(size);
+            }
+            return None;
+        }
+        Some(owner)
+    }
+
+    fn credit(&self, owner: u32, size: usize) {
+        if owner == UNTRACKED {
+            return;
+        }
+        let (vtl, vp) = self.owner_quotas(owner);
+        for q in [vtl, vp].into_iter().flatten() {
+            q.credit(size);
+        }
+    }
+}
+
 pub struct MemoryAllocator {
     use_locked_heap: Mutex<RefCell<bool>>,
     locked_heap: LockedHeap,
     uefi_allocator: Allocator,
+    fault_injection: Mutex<FaultInjection>,
+    low_pool: LockedHeap,
+    low_pool_range: Mutex<Range<u64>>,
+    boot_services_exited: AtomicBool,
+    heap_range: Mutex<Range<usize>>,
+    quotas: Quotas,
+    irq_audit: IrqAudit,
+}
+
+/// The layout of a capped heap allocation for `layout`, with room after it
+/// for the owner it is charged to, and the offset of the owner. The owner
+/// takes the padding up to its own alignment, so that aligned allocations
+/// grow by no more than its size.
+fn with_owner(layout: Layout) -> Option<(Layout, usize)> {
+    let offset = layout.size().checked_next_multiple_of(align_of::<u32>())?;
+    let outer = Layout::from_size_align(
+        offset.checked_add(size_of::<u32>())?,
+        layout.align().max(align_of::<u32>()),
+    )
+    .ok()?;
+    Some((outer, offset))
 }
 
 // SAFETY: The methods of GlobalAlloc are unsafe because the caller must ensure the safety
 unsafe impl GlobalAlloc for MemoryAllocator {
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        self.irq_audit.check(layout.size());
+        if self.fault_injection.lock().should_fail(layout.size()) {
+            return core::ptr::null_mut();
+        }
         // SAFETY: caller must ensure layout is valid
-        unsafe { self.get_allocator().alloc(layout) }
+        unsafe { self.allocate(layout, false) }
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
         // SAFETY: caller must ensure ptr and layout are valid
-        unsafe { self.get_allocator().dealloc(ptr, layout) };
+        unsafe { self.release(ptr, layout) };
     }
 
     unsafe fn alloc_zeroed(&self, layout: core::alloc::Layout) -> *mut u8 {
+        self.irq_audit.check(layout.size());
+        if self.fault_injection.lock().should_fail(layout.size()) {
+            return core::ptr::null_mut();
+        }
         // SAFETY: caller must ensure layout is valid
-        unsafe { self.get_allocator().alloc_zeroed(layout) }
+        unsafe { self.allocate(layout, true) }
     }
 
     unsafe fn realloc(
@@ -50,49 +395,372 @@ unsafe fn realloc(
         layout: core::alloc::Layout,
         new_size: usize,
     ) -> *mut u8 {
-        // SAFETY: caller must ensure ptr is valid for layout
-        unsafe { self.get_allocator().realloc(ptr, layout, new_size) }
+        self.irq_audit.check(new_size);
+        if self
+            .fault_injection
+            .lock()
+            .should_fail(new_size.saturating_sub(layout.size()))
+        {
+            return core::ptr::null_mut();
+        }
+        if !self.in_capped_heap(ptr) {
+            // SAFETY: caller must ensure ptr is valid for layout
+            return unsafe { self.uefi_allocator.realloc(ptr, layout, new_size) };
+        }
+        let Ok(new_layout) = Layout::from_size_align(new_size, layout.align()) else {
+            return core::ptr::null_mut();
+        };
+        // SAFETY: the caller guarantees `new_size` is non-zero.
+        let new = unsafe { self.allocate(new_layout, false) };
+        if !new.is_null() {
+            // SAFETY: both blocks are valid for the smaller of the sizes and
+            // distinct, and the old one is freed once copied.
+            unsafe {
+                core::ptr::copy_nonoverlapping(ptr, new, layout.size().min(new_size));
+                self.release(ptr, layout);
+            }
+        }
+        new
     }
 }
 
 impl MemoryAllocator {
-    pub fn switch_to_capped_heap(&self, size: usize) -> bool {
+    pub fn switch_to_capped_heap(&self, size: usize) -> Result<(), BootError> {
         let pages = ((SIZE_1MB * size) / 4096) + 1;
         let size = pages * 4096;
-        let mem: Result<core::ptr::NonNull<u8>, uefi::Error> = boot::allocate_pages(
+        let ptr = boot::allocate_pages(
             AllocateType::AnyPages,
             MemoryType::BOOT_SERVICES_DATA,
             pages,
-        );
-        if mem.is_err() {
-            return false;
-        }
-        let ptr = mem.unwrap().as_ptr();
+        )
+        .map_err(|_| BootError::HeapAllocationFailed)?
+        .as_ptr();
         // SAFETY: its safe to init a locked heap at this point, we know memory allocated is valid
         unsafe { self.locked_heap.lock().init(ptr, size) };
+        *self.heap_range.lock() = ptr as usize..ptr as usize + size;
         *self.use_locked_heap.lock().borrow_mut() = true;
-        true
+        Ok(())
     }
 
-    #[expect(dead_code)]
-    pub fn get_page_aligned_memory(&self, size: usize) -> *mut u8 {
-        let pages = ((SIZE_1MB * size) / PAGE_SIZE) + 1;
-        let mem: Result<core::ptr::NonNull<u8>, uefi::Error> = boot::allocate_pages(
-            AllocateType::AnyPages,
+    fn in_capped_heap(&self, ptr: *mut u8) -> bool {
+        self.heap_range.lock().contains(&(ptr as usize))
+    }
+
+    /// Allocates from the capped heap, charging the quotas, or from UEFI
+    /// before the heap is set up.
+    ///
+    /// # Safety
+    ///
+    /// `layout` must have a non-zero size.
+    unsafe fn allocate(&self, layout: Layout, zeroed: bool) -> *mut u8 {
+        if !*self.use_locked_heap.lock().borrow() {
+            // SAFETY: guaranteed by the caller.
+            return unsafe {
+                if zeroed {
+                    self.uefi_allocator.alloc_zeroed(layout)
+                } else {
+                    self.uefi_allocator.alloc(layout)
+                }
+            };
+        }
+        let Some((outer, offset)) = with_owner(layout) else {
+            return core::ptr::null_mut();
+        };
+        let Some(owner) = self.quotas.charge(layout.size()) else {
+            crate::log_static!(log::Level::Warn, "heap quota exceeded");
+            return core::ptr::null_mut();
+        };
+        // SAFETY: the outer layout has a non-zero size.
+        let base = unsafe {
+            if zeroed {
+                self.locked_heap.alloc_zeroed(outer)
+            } else {
+                self.locked_heap.alloc(outer)
+            }
+        };
+        if base.is_null() {
+            self.quotas.credit(owner, layout.size());
+            return base;
+        }
+        // SAFETY: the owner lies `offset` bytes into the allocation, past
+        // the memory handed out and aligned for it.
+        unsafe { base.add(offset).cast::<u32>().write(owner) };
+        base
+    }
+
+    /// Frees memory returned by [`Self::allocate`].
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by [`Self::allocate`] with `layout` and
+    /// not freed since.
+    unsafe fn release(&self, ptr: *mut u8, layout: Layout) {
+        if !self.in_capped_heap(ptr) {
+            // SAFETY: guaranteed by the caller, the memory came from UEFI.
+            unsafe { self.uefi_allocator.dealloc(ptr, layout) };
+            return;
+        }
+        let (outer, offset) = with_owner(layout).expect("layout was allocated with an owner");
+        // SAFETY: guaranteed by the caller, see `allocate` for the owner.
+        unsafe {
+            self.quotas
+                .credit(ptr.add(offset).cast::<u32>().read(), layout.size());
+            self.locked_heap.dealloc(ptr, outer);
+        }
+    }
+
+    /// Limits the heap memory allocations in `scope` may hold to `limit`
+    /// bytes, or lifts the limit with `None`. Allocations beyond the limit
+    /// fail, which ends the run unless made through a fallible API such as
+    /// `Vec::try_reserve`.
+    ///
+    /// Quotas only apply to the capped heap and are cleared before each
+    /// test.
+    pub fn set_quota(&self, scope: QuotaScope, limit: Option<usize>) -> TmkResult<()> {
+        let quota = self.quotas.quota(scope).ok_or(TmkError::InvalidParameter)?;
+        quota
+            .limit
+            .store(limit.unwrap_or(usize::MAX), Ordering::Release);
+        self.quotas.tracking.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Lifts every quota and stops counting new allocations.
+    pub fn clear_quotas(&self) {
+        self.quotas.tracking.store(false, Ordering::Release);
+        for quota in self.quotas.vtls.iter().chain(&self.quotas.vps) {
+            quota.limit.store(usize::MAX, Ordering::Release);
+            quota
+                .peak
+                .store(quota.used.load(Ordering::Acquire), Ordering::Relaxed);
+            quota.failures.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the usage of the quotas that are set or still have memory
+    /// charged to them.
+    pub fn quota_usage(&self) -> Vec<QuotaUsage> {
+        let vtls = (0..QUOTA_VTLS as u8).map(QuotaScope::Vtl);
+        let vps = (0..MAX_VPS as u32).map(QuotaScope::Vp);
+        vtls.chain(vps)
+            .filter_map(|scope| {
+                let quota = self.quotas.quota(scope)?;
+                let limit = quota.limit.load(Ordering::Acquire);
+                let used = quota.used.load(Ordering::Acquire);
+                (limit != usize::MAX || used != 0).then(|| QuotaUsage {
+                    scope,
+                    limit: (limit != usize::MAX).then_some(limit),
+                    used,
+                    peak: quota.peak.load(Ordering::Relaxed),
+                    failures: quota.failures.load(Ordering::Relaxed),
+                })
+            })
+            .collect()
+    }
+
+    /// Writes the `allocator_stats` record, with the heap and quota usage,
+    /// at the end of `test`.
+    pub fn write_stats_record(&self, test: &str) {
+        #[derive(Serialize)]
+        struct AllocatorStatsRecord<'a> {
+            #[serde(rename = "type")]
+            record_type: &'static str,
+            test: &'a str,
+            heap: Option<HeapStats>,
+            quotas: Vec<QuotaUsage>,
+        }
+
+        crate::tmk_logger::write_record(&AllocatorStatsRecord {
+            record_type: "allocator_stats",
+            test,
+            heap: self.heap_stats(),
+            quotas: self.quota_usage(),
+        });
+    }
+
+    /// Writes an `irq_alloc` record with the allocations made from
+    /// interrupt context during `test`, if there were any, and forgets
+    /// them.
+    ///
+    /// Allocations from interrupt context are only audited, each logged
+    /// with its callers, in builds made with the `OPENTMK_IRQ_ALLOC_AUDIT`
+    /// environment variable set.
+    pub fn write_irq_alloc_record(&self, test: &str) {
+        #[derive(Serialize)]
+        struct IrqAllocRecord<'a> {
+            #[serde(rename = "type")]
+            record_type: &'static str,
+            test: &'a str,
+            /// Allocations made, including those not kept in `hits`.
+            total: u64,
+            hits: &'a [IrqAlloc],
+        }
+
+        let total = self.irq_audit.total.swap(0, Ordering::Relaxed);
+        if total == 0 {
+            return;
+        }
+        let hits = {
+            let mut hits = self.irq_audit.hits.lock();
+            let kept = hits.hits;
+            let len = core::mem::take(&mut hits.len);
+            (kept, len)
+        };
+        crate::tmk_logger::write_record(&IrqAllocRecord {
+            record_type: "irq_alloc",
+            test,
+            total,
+            hits: &hits.0[..hits.1],
+        });
+    }
+
+    /// Returns the size and usage of the capped heap, or `None` while the
+    /// UEFI allocator is still in use.
+    pub fn heap_stats(&self) -> Option<HeapStats> {
+        if !*self.use_locked_heap.lock().borrow() {
+            return None;
+        }
+        let heap = self.locked_heap.lock();
+        Some(HeapStats {
+            size: heap.size(),
+            used: heap.used(),
+            free: heap.free(),
+        })
+    }
+
+    /// Run `f` with allocation failures injected as described by `failure`,
+    /// and return its result along with the number of allocations that
+    /// were failed.
+    ///
+    /// Used to check that allocation failures are reported as errors. The
+    /// failures apply to every VP, and a failed allocation through an
+    /// infallible API such as `Box::new`, including the formatting done by
+    /// `log`, ends the run, so `f` should only exercise fallible paths.
+    pub fn with_injected_failures<R>(
+        &self,
+        failure: AllocFailure,
+        f: impl FnOnce() -> R,
+    ) -> (R, usize) {
+        *self.fault_injection.lock() = FaultInjection {
+            mode: Some(failure),
+            allocations: 0,
+            bytes: 0,
+            failures: 0,
+        };
+        let r = f();
+        let mut injection = self.fault_injection.lock();
+        injection.mode = None;
+        (r, injection.failures)
+    }
+
+    /// Reserves `size` bytes ending at or below `limit` for
+    /// [`Self::alloc_below`] to allocate from once boot services are exited.
+    pub fn reserve_low_pool(&self, size: usize, limit: u64) -> Result<(), BootError> {
+        let pages = size.div_ceil(PAGE_SIZE);
+        let ptr = boot::allocate_pages(
+            AllocateType::MaxAddress(limit - 1),
             MemoryType::BOOT_SERVICES_DATA,
             pages,
-        );
-        if mem.is_err() {
-            return core::ptr::null_mut();
+        )
+        .map_err(|_| BootError::HeapAllocationFailed)?
+        .as_ptr();
+        // SAFETY: the pages were just allocated and are only used by the pool.
+        unsafe { self.low_pool.lock().init(ptr, pages * PAGE_SIZE) };
+        *self.low_pool_range.lock() = ptr as u64..ptr as u64 + (pages * PAGE_SIZE) as u64;
+        Ok(())
+    }
+
+    /// Returns the range of the pool reserved with
+    /// [`Self::reserve_low_pool`], empty if there is none.
+    pub fn low_pool(&self) -> Range<u64> {
+        self.low_pool_range.lock().clone()
+    }
+
+    /// Records that boot services were exited, from which point
+    /// [`Self::alloc_below`] allocates from the low pool.
+    pub fn set_boot_services_exited(&self) {
+        self.boot_services_exited.store(true, Ordering::Release);
+    }
+
+    /// Returns whether boot services were exited.
+    pub fn boot_services_exited(&self) -> bool {
+        self.boot_services_exited.load(Ordering::Acquire)
+    }
+
+    /// Allocates memory for `layout` ending at or below `limit_gpa`.
+    ///
+    /// Memory is identity mapped, so the allocation is physically
+    /// contiguous and its address is its GPA. While boot services are up the
+    /// pages come from UEFI, with `layout` aligned to at most a page;
+    /// afterwards they come from the low pool, which fails the allocation if
+    /// the pool does not lie below `limit_gpa`. Free the memory with
+    /// [`Self::dealloc_below`].
+    pub fn alloc_below(&self, limit_gpa: u64, layout: Layout) -> TmkResult<NonNull<u8>> {
+        if layout.size() == 0 {
+            return Err(TmkError::InvalidParameter);
         }
-        mem.unwrap().as_ptr()
+        if !self.boot_services_exited.load(Ordering::Acquire) {
+            if layout.align() > PAGE_SIZE {
+                return Err(TmkError::InvalidAlignment);
+            }
+            let max_address = limit_gpa.checked_sub(1).ok_or(TmkError::AllocationFailed)?;
+            return boot::allocate_pages(
+                AllocateType::MaxAddress(max_address),
+                MemoryType::BOOT_SERVICES_DATA,
+                layout.size().div_ceil(PAGE_SIZE),
+            )
+            .map_err(|_| TmkError::AllocationFailed);
+        }
+
+        let mut pool = self.low_pool.lock();
+        let ptr = pool
+            .allocate_first_fit(layout)
+            .map_err(|_| TmkError::AllocationFailed)?;
+        if ptr.as_ptr() as u64 + layout.size() as u64 > limit_gpa {
+            // SAFETY: the memory was just allocated from the pool with
+            // `layout`.
+            unsafe { pool.deallocate(ptr, layout) };
+            return Err(TmkError::AllocationFailed);
+        }
+        Ok(ptr)
     }
 
-    fn get_allocator(&self) -> &dyn GlobalAlloc {
-        if *self.use_locked_heap.lock().borrow() {
-            &self.locked_heap
+    /// Frees memory returned by [`Self::alloc_below`].
+    ///
+    /// Pages allocated from UEFI can only be returned while boot services
+    /// are up; afterwards they are leaked.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by [`Self::alloc_below`] with `layout`
+    /// and not freed since.
+    pub unsafe fn dealloc_below(&self, ptr: NonNull<u8>, layout: Layout) {
+        if self.low_pool_range.lock().contains(&(ptr.as_ptr() as u64)) {
+            // SAFETY: guaranteed by the caller.
+            unsafe { self.low_pool.lock().deallocate(ptr, layout) };
+        } else if !self.boot_services_exited.load(Ordering::Acquire) {
+            // SAFETY: guaranteed by the caller, the pages came from UEFI.
+            let r = unsafe { boot::free_pages(ptr, layout.size().div_ceil(PAGE_SIZE)) };
+            if r.is_err() {
+                log::warn!("failed to free pages at {:p}", ptr);
+            }
         } else {
-            &self.uefi_allocator
+            log::warn!(
+                "leaking {} bytes at {:p} allocated before exiting boot services",
+                layout.size(),
+                ptr
+            );
         }
     }
+
+    #[expect(dead_code)]
+    pub fn get_page_aligned_memory(&self, size: usize) -> Result<NonNull<u8>, BootError> {
+        let pages = ((SIZE_1MB * size) / PAGE_SIZE) + 1;
+        boot::allocate_pages(
+            AllocateType::AnyPages,
+            MemoryType::BOOT_SERVICES_DATA,
+            pages,
+        )
+        .map_err(|_| BootError::HeapAllocationFailed)
+    }
 }
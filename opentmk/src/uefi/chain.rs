@@ -0,0 +1,363 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Chained EFI applications.
+//!
+//! The harness may list EFI applications in [`CHAIN_VARIABLE`] for the TMK
+//! to run before its own tests, e.g. the `guest_test_uefi` suite, so that a
+//! composite run takes a single boot. Each is loaded from the volume the TMK
+//! was loaded from and started while boot services are still available.
+//!
+//! While an application runs, the console output of the system table is
+//! replaced by a proxy that passes text on to the firmware console and
+//! forwards every line to the TMK log as a `chained_output` record carrying
+//! the image and the nesting depth. Lines that are JSON objects, such as the
+//! records of another TMK, are embedded as structured values. A
+//! `chain_result` record per image aggregates its exit status and output.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ptr::NonNull;
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::Ordering;
+
+use serde::Serialize;
+use spin::Mutex;
+use uefi::CString16;
+use uefi::Status;
+use uefi::boot::LoadImageSource;
+use uefi::fs::FileSystem;
+use uefi::fs::PathBuf;
+use uefi_raw::Boolean;
+use uefi_raw::Char16;
+use uefi_raw::protocol::console::SimpleTextOutputProtocol;
+
+/// Name of the UEFI variable listing the applications to run, as paths on
+/// the boot volume separated by `;` or new lines.
+pub const CHAIN_VARIABLE: &str = "OpenTmkChain";
+/// Vendor GUID of [`CHAIN_VARIABLE`], shared with the scenario variable.
+pub const CHAIN_VARIABLE_VENDOR: uefi::Guid = crate::scenario::SCENARIO_VARIABLE_VENDOR;
+
+/// Nesting depth of the output of applications the TMK starts.
+const DEPTH: u32 = 1;
+
+/// The application whose output is being forwarded.
+struct Capture {
+    image: String,
+    line: String,
+    lines: u64,
+    records: u64,
+}
+
+static CAPTURE: Mutex<Option<Capture>> = Mutex::new(None);
+/// The console output the proxy stands in for.
+static ORIGINAL: AtomicPtr<SimpleTextOutputProtocol> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Splits the value of [`CHAIN_VARIABLE`] into image paths.
+pub fn parse_chain(text: &str) -> Vec<&str> {
+    text.split([';', '\n'])
+        .map(str::trim)
+        .filter(|path| !path.is_empty())
+        .collect()
+}
+
+#[derive(Serialize)]
+struct ChainedOutputRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    image: &'a str,
+    depth: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    record: Option<serde_json::Value>,
+}
+
+/// Writes a line printed by the application.
+fn forward_line(capture: &mut Capture) {
+    let line = core::mem::take(&mut capture.line);
+    let record = serde_json::from_str::<serde_json::Value>(&line)
+        .ok()
+        .filter(|value| value.is_object());
+    capture.lines += 1;
+    if record.is_some() {
+        capture.records += 1;
+    }
+    crate::tmk_logger::write_record(&ChainedOutputRecord {
+        record_type: "chained_output",
+        image: &capture.image,
+        depth: DEPTH,
+        line: record.is_none().then_some(line.as_str()),
+        record,
+    });
+}
+
+/// Appends the text at `string`, null terminated UCS-2, to the current line.
+///
+/// # Safety
+///
+/// `string` must point to a null terminated string.
+unsafe fn capture_text(string: *const Char16) {
+    let mut guard = CAPTURE.lock();
+    let Some(capture) = guard.as_mut() else {
+        return;
+    };
+    let mut len = 0;
+    // SAFETY: the caller guarantees the string is null terminated.
+    while unsafe { *string.add(len) } != 0 {
+        len += 1;
+    }
+    // SAFETY: the `len` characters before the terminator were read above.
+    let units = unsafe { core::slice::from_raw_parts(string, len) };
+    for c in char::decode_utf16(units.iter().copied()) {
+        match c.unwrap_or(char::REPLACEMENT_CHARACTER) {
+            '\r' => {}
+            '\n' => forward_line(capture),
+            c => capture.line.push(c),
+        }
+    }
+}
+
+fn original() -> *mut SimpleTextOutputProtocol {
+    ORIGINAL.load(Ordering::Acquire)
+}
+
+// The proxy functions call the firmware with its own protocol instance, as
+// the firmware finds its private data from it.
+
+unsafe extern "efiapi" fn proxy_reset(
+    _this: *mut SimpleTextOutputProtocol,
+    extended: Boolean,
+) -> Status {
+    let original = original();
+    // SAFETY: calling the firmware console the proxy stands in for.
+    unsafe { ((*original).reset)(original, extended) }
+}
+
+unsafe extern "efiapi" fn proxy_output_string(
+    _this: *mut SimpleTextOutputProtocol,
+    string: *const Char16,
+) -> Status {
+    // SAFETY: the application passes a null terminated string.
+    unsafe { capture_text(string) };
+    let original = original();
+    // SAFETY: calling the firmware console the proxy stands in for.
+    unsafe { ((*original).output_string)(original, string) }
+}
+
+unsafe extern "efiapi" fn proxy_test_string(
+    _this: *mut SimpleTextOutputProtocol,
+    string: *const Char16,
+) -> Status {
+    let original = original();
+    // SAFETY: calling the firmware console the proxy stands in for.
+    unsafe { ((*original).test_string)(original, string) }
+}
+
+unsafe extern "efiapi" fn proxy_query_mode(
+    _this: *mut SimpleTextOutputProtocol,
+    mode: usize,
+    columns: *mut usize,
+    rows: *mut usize,
+) -> Status {
+    let original = original();
+    // SAFETY: calling the firmware console the proxy stands in for.
+    unsafe { ((*original).query_mode)(original, mode, columns, rows) }
+}
+
+unsafe extern "efiapi" fn proxy_set_mode(
+    _this: *mut SimpleTextOutputProtocol,
+    mode: usize,
+) -> Status {
+    let original = original();
+    // SAFETY: calling the firmware console the proxy stands in for.
+    unsafe { ((*original).set_mode)(original, mode) }
+}
+
+unsafe extern "efiapi" fn proxy_set_attribute(
+    _this: *mut SimpleTextOutputProtocol,
+    attribute: usize,
+) -> Status {
+    let original = original();
+    // SAFETY: calling the firmware console the proxy stands in for.
+    unsafe { ((*original).set_attribute)(original, attribute) }
+}
+
+unsafe extern "efiapi" fn proxy_clear_screen(_this: *mut SimpleTextOutputProtocol) -> Status {
+    let original = original();
+    // SAFETY: calling the firmware console the proxy stands in for.
+    unsafe { ((*original).clear_screen)(original) }
+}
+
+unsafe extern "efiapi" fn proxy_set_cursor_position(
+    _this: *mut SimpleTextOutputProtocol,
+    column: usize,
+    row: usize,
+) -> Status {
+    let original = original();
+    // SAFETY: calling the firmware console the proxy stands in for.
+    unsafe { ((*original).set_cursor_position)(original, column, row) }
+}
+
+unsafe extern "efiapi" fn proxy_enable_cursor(
+    _this: *mut SimpleTextOutputProtocol,
+    visible: Boolean,
+) -> Status {
+    let original = original();
+    // SAFETY: calling the firmware console the proxy stands in for.
+    unsafe { ((*original).enable_cursor)(original, visible) }
+}
+
+/// Forwards the console output of the system table to the TMK log until
+/// dropped.
+struct Redirect {
+    original: NonNull<SimpleTextOutputProtocol>,
+    _proxy: Box<SimpleTextOutputProtocol>,
+}
+
+impl Redirect {
+    fn install(image: &str) -> Option<Self> {
+        let table = uefi::table::system_table_raw()?;
+        // SAFETY: boot services are active, so the system table is valid and
+        // nothing else changes it while the TMK runs.
+        let original = NonNull::new(unsafe { (*table.as_ptr()).stdout })?;
+        let mut proxy = Box::new(SimpleTextOutputProtocol {
+            reset: proxy_reset,
+            output_string: proxy_output_string,
+            test_string: proxy_test_string,
+            query_mode: proxy_query_mode,
+            set_mode: proxy_set_mode,
+            set_attribute: proxy_set_attribute,
+            clear_screen: proxy_clear_screen,
+            set_cursor_position: proxy_set_cursor_position,
+            enable_cursor: proxy_enable_cursor,
+            // SAFETY: the original protocol is valid, see above.
+            mode: unsafe { (*original.as_ptr()).mode },
+        });
+        ORIGINAL.store(original.as_ptr(), Ordering::Release);
+        *CAPTURE.lock() = Some(Capture {
+            image: image.into(),
+            line: String::new(),
+            lines: 0,
+            records: 0,
+        });
+        // SAFETY: the proxy outlives its installation, as it is only dropped
+        // after the original is restored. Applications do not check the
+        // table CRC, so it is not updated.
+        unsafe { (*table.as_ptr()).stdout = &mut *proxy };
+        Some(Self {
+            original,
+            _proxy: proxy,
+        })
+    }
+
+    /// Restores the console output and returns what was captured.
+    fn finish(self) -> Capture {
+        let mut capture = CAPTURE.lock().take().expect("capture is installed");
+        if !capture.line.is_empty() {
+            forward_line(&mut capture);
+        }
+        capture
+    }
+}
+
+impl Drop for Redirect {
+    fn drop(&mut self) {
+        if let Some(table) = uefi::table::system_table_raw() {
+            // SAFETY: putting back the console output replaced in `install`.
+            unsafe { (*table.as_ptr()).stdout = self.original.as_ptr() };
+        }
+        ORIGINAL.store(core::ptr::null_mut(), Ordering::Release);
+    }
+}
+
+/// The outcome of a chained application.
+#[derive(Clone, Debug, Serialize)]
+pub struct ChainResult {
+    /// The path of the image.
+    pub image: String,
+    /// The status the application exited with, or the load failure.
+    pub status: usize,
+    /// Whether the application ran and exited successfully.
+    pub success: bool,
+    /// Lines the application printed.
+    pub lines: u64,
+    /// Lines that were JSON records.
+    pub records: u64,
+}
+
+#[derive(Serialize)]
+struct ChainResultRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    depth: u32,
+    #[serde(flatten)]
+    result: &'a ChainResult,
+}
+
+fn load(path: &str) -> uefi::Result<uefi::Handle> {
+    let path = CString16::try_from(path).map_err(|_| Status::INVALID_PARAMETER)?;
+    let volume = uefi::boot::get_image_file_system(uefi::boot::image_handle())?;
+    let buffer = FileSystem::new(volume)
+        .read(PathBuf::from(path))
+        .map_err(|e| {
+            log::error!("failed to read chained image: {:?}", e);
+            Status::NOT_FOUND
+        })?;
+    uefi::boot::load_image(
+        uefi::boot::image_handle(),
+        LoadImageSource::FromBuffer {
+            buffer: &buffer,
+            file_path: None,
+        },
+    )
+}
+
+/// Runs the application at `path` on the boot volume and returns its
+/// outcome. Must be called with boot services active and without the log
+/// mirrored to the console, which would feed the log back into itself.
+pub fn run_image(path: &str) -> ChainResult {
+    log::info!("starting chained image {}", path);
+    let (status, capture) = match load(path) {
+        Ok(handle) => match Redirect::install(path) {
+            Some(redirect) => {
+                let status = match uefi::boot::start_image(handle) {
+                    Ok(()) => Status::SUCCESS,
+                    Err(e) => e.status(),
+                };
+                (status, Some(redirect.finish()))
+            }
+            None => (Status::UNSUPPORTED, None),
+        },
+        Err(e) => (e.status(), None),
+    };
+    let result = ChainResult {
+        image: path.into(),
+        status: status.0,
+        success: status == Status::SUCCESS,
+        lines: capture.as_ref().map_or(0, |c| c.lines),
+        records: capture.as_ref().map_or(0, |c| c.records),
+    };
+    log::info!("chained image {} exited: {:?}", path, status);
+    crate::tmk_logger::write_record(&ChainResultRecord {
+        record_type: "chain_result",
+        depth: DEPTH,
+        result: &result,
+    });
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_chain() {
+        assert_eq!(
+            parse_chain(" \\EFI\\a.efi;\\EFI\\b.efi\n\n \\c.efi ;"),
+            ["\\EFI\\a.efi", "\\EFI\\b.efi", "\\c.efi"]
+        );
+        assert!(parse_chain(" ; \n").is_empty());
+    }
+}
@@ -1,34 +1,328 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
+use alloc::string::String;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+
 use uefi::CStr16;
 use uefi::Status;
 use uefi::boot::MemoryType;
-use uefi::boot::exit_boot_services;
 use uefi::guid;
 
 use super::alloc::ALLOCATOR;
+use super::alloc::LOW_POOL_LIMIT;
+use super::alloc::LOW_POOL_SIZE;
+use super::chain;
+use super::memory_map;
+use super::results_page;
+use crate::tmkdefs::BootError;
 
 const EFI_GUID: uefi::Guid = guid!("610b9e98-c6f6-47f8-8b47-2d2da0d52a91");
+const ACPI2_GUID: uefi::Guid = guid!("8868e871-e4f1-11d3-bc22-0080c73c8881");
 const OS_LOADER_INDICATIONS: &str = "OsLoaderIndications";
 
-fn enable_uefi_vtl_protection() {
+/// Name of the UEFI variable turning the console mirror `on` or `off`, on
+/// by default.
+pub const CONSOLE_MIRROR_VARIABLE: &str = "OpenTmkConsoleMirror";
+/// Vendor GUID of [`CONSOLE_MIRROR_VARIABLE`], shared with the scenario
+/// variable.
+pub const CONSOLE_MIRROR_VARIABLE_VENDOR: uefi::Guid = crate::scenario::SCENARIO_VARIABLE_VENDOR;
+
+/// Whether log output is mirrored to the UEFI console until boot services
+/// are exited, for when nothing shows up on the serial port.
+static MIRROR_TO_CONSOLE: AtomicBool = AtomicBool::new(true);
+
+/// Enables or disables mirroring log output to the UEFI console as
+/// [`CONSOLE_MIRROR_VARIABLE`] says. The mirror always stops when boot
+/// services are exited.
+fn load_console_mirror() {
+    let Some(text) = read_text_variable(
+        CONSOLE_MIRROR_VARIABLE,
+        CONSOLE_MIRROR_VARIABLE_VENDOR,
+        MAX_CONSOLE_MIRROR_SIZE,
+    ) else {
+        return;
+    };
+    match text.trim() {
+        "on" => MIRROR_TO_CONSOLE.store(true, Ordering::Relaxed),
+        "off" => MIRROR_TO_CONSOLE.store(false, Ordering::Relaxed),
+        _ => log::error!("ignoring invalid console mirror setting {:?}", text),
+    }
+}
+
+fn console_mirror(s: &str) {
+    uefi::print!("{}", s);
+}
+
+/// Largest scenario accepted from [`crate::scenario::SCENARIO_VARIABLE`].
+const MAX_SCENARIO_SIZE: usize = 16 * 1024;
+/// Largest VP selection accepted from [`crate::affinity::VP_SET_VARIABLE`].
+const MAX_VP_SET_SIZE: usize = 1024;
+/// Largest RAM size accepted from [`super::memory_map::MEMORY_SIZE_VARIABLE`].
+const MAX_MEMORY_SIZE_SIZE: usize = 32;
+/// Largest list of images accepted from [`super::chain::CHAIN_VARIABLE`].
+const MAX_CHAIN_SIZE: usize = 1024;
+/// Largest soak configuration accepted from [`crate::soak::SOAK_VARIABLE`].
+const MAX_SOAK_SIZE: usize = 1024;
+/// Largest serial configuration accepted from
+/// [`crate::arch::serial::SERIAL_VARIABLE`].
+const MAX_SERIAL_SIZE: usize = 64;
+/// Largest baseline accepted from
+/// [`crate::golden_env::GOLDEN_ENV_VARIABLE`].
+const MAX_GOLDEN_ENV_SIZE: usize = 16 * 1024;
+/// Largest GPA accepted from [`super::results_page::RESULTS_PAGE_VARIABLE`].
+const MAX_RESULTS_PAGE_SIZE: usize = 32;
+/// Largest GPA accepted from [`crate::devices::synthvid::VRAM_VARIABLE`].
+const MAX_VRAM_SIZE: usize = 32;
+/// Largest GPA accepted from [`crate::devices::vpci::MMIO_VARIABLE`].
+const MAX_VPCI_MMIO_SIZE: usize = 32;
+/// Largest setting accepted from [`CONSOLE_MIRROR_VARIABLE`].
+const MAX_CONSOLE_MIRROR_SIZE: usize = 8;
+/// Polls of the serial port without data before the log format offer is
+/// taken as unanswered, short so that harnesses unaware of it barely wait.
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+const LOG_FORMAT_POLLS: u64 = 100_000;
+/// Polls of the serial port without data before the handshake is taken as
+/// unanswered.
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+const HANDSHAKE_POLLS: u64 = 100_000;
+/// Watchdog code of the TMK, above the range reserved for the firmware.
+const WATCHDOG_CODE: u64 = 0x10000;
+/// Largest chaos configuration accepted.
+#[cfg(feature = "chaos")]
+const MAX_CHAOS_SIZE: usize = 64;
+
+/// Reads the text of the UEFI variable `name`, up to `max_size` bytes.
+/// Returns `None` if the variable is not set or cannot be read.
+fn read_text_variable(name: &str, vendor: uefi::Guid, max_size: usize) -> Option<String> {
+    let mut name_buf = [0u16; 32];
+    let name16 = CStr16::from_str_with_buf(name, &mut name_buf).ok()?;
+    let mut buf = vec![0u8; max_size];
+    let vendor = uefi::runtime::VariableVendor(vendor);
+    match uefi::runtime::get_variable(name16, &vendor, &mut buf) {
+        Ok((data, _)) => match core::str::from_utf8(data) {
+            Ok(text) => {
+                log::info!("{} variable found, {} bytes", name, text.len());
+                Some(text.into())
+            }
+            Err(_) => {
+                log::error!("{} variable is not valid UTF-8", name);
+                None
+            }
+        },
+        Err(e) if e.status() == Status::NOT_FOUND => None,
+        Err(e) => {
+            log::error!("failed to read {} variable: {:?}", name, e.status());
+            None
+        }
+    }
+}
+
+/// Stashes the scenario, VP selection, RAM size, soak and chaos
+/// configuration the harness may have left in UEFI variables, as the
+/// variables can't be read once boot services are gone, and reserves the
+/// results page it may have named.
+fn load_harness_variables() {
+    if let Some(text) = read_text_variable(
+        crate::scenario::SCENARIO_VARIABLE,
+        crate::scenario::SCENARIO_VARIABLE_VENDOR,
+        MAX_SCENARIO_SIZE,
+    ) {
+        crate::scenario::set_variable_scenario(text);
+    }
+    if let Some(text) = read_text_variable(
+        crate::affinity::VP_SET_VARIABLE,
+        crate::affinity::VP_SET_VARIABLE_VENDOR,
+        MAX_VP_SET_SIZE,
+    ) {
+        match crate::affinity::parse_vp_set(&text) {
+            Ok(set) => crate::affinity::set_vp_set(set),
+            Err(_) => log::error!("ignoring invalid VP selection {:?}", text),
+        }
+    }
+    if let Some(text) = read_text_variable(
+        memory_map::MEMORY_SIZE_VARIABLE,
+        memory_map::MEMORY_SIZE_VARIABLE_VENDOR,
+        MAX_MEMORY_SIZE_SIZE,
+    ) {
+        match memory_map::parse_memory_size(&text) {
+            Ok(size) => memory_map::set_memory_size(size),
+            Err(_) => log::error!("ignoring invalid RAM size {:?}", text),
+        }
+    }
+    if let Some(text) = read_text_variable(
+        crate::soak::SOAK_VARIABLE,
+        crate::soak::SOAK_VARIABLE_VENDOR,
+        MAX_SOAK_SIZE,
+    ) {
+        match crate::soak::parse_config(&text) {
+            Ok(config) => crate::soak::set_config(config),
+            Err(_) => log::error!("ignoring invalid soak configuration {:?}", text),
+        }
+    }
+    if let Some(text) = read_text_variable(
+        crate::golden_env::GOLDEN_ENV_VARIABLE,
+        crate::golden_env::GOLDEN_ENV_VARIABLE_VENDOR,
+        MAX_GOLDEN_ENV_SIZE,
+    ) {
+        match crate::golden_env::parse_baseline(&text) {
+            Ok(baseline) => crate::golden_env::set_baseline(baseline),
+            Err(_) => log::error!("ignoring invalid golden environment {:?}", text),
+        }
+    }
+    if let Some(text) = read_text_variable(
+        results_page::RESULTS_PAGE_VARIABLE,
+        results_page::RESULTS_PAGE_VARIABLE_VENDOR,
+        MAX_RESULTS_PAGE_SIZE,
+    ) {
+        match results_page::parse_gpa(&text) {
+            // The failure is logged, the run goes on without the page.
+            Ok(gpa) => _ = results_page::reserve(gpa),
+            Err(_) => log::error!("ignoring invalid results page {:?}", text),
+        }
+    }
+    if let Some(text) = read_text_variable(
+        crate::devices::synthvid::VRAM_VARIABLE,
+        crate::devices::synthvid::VRAM_VARIABLE_VENDOR,
+        MAX_VRAM_SIZE,
+    ) {
+        match results_page::parse_gpa(&text) {
+            Ok(gpa) => crate::devices::synthvid::set_vram_gpa(gpa),
+            Err(_) => log::error!("ignoring invalid VRAM location {:?}", text),
+        }
+    }
+    if let Some(text) = read_text_variable(
+        crate::devices::vpci::MMIO_VARIABLE,
+        crate::devices::vpci::MMIO_VARIABLE_VENDOR,
+        MAX_VPCI_MMIO_SIZE,
+    ) {
+        match results_page::parse_gpa(&text) {
+            Ok(gpa) => crate::devices::vpci::set_mmio_gpa(gpa),
+            Err(_) => log::error!("ignoring invalid VPCI MMIO location {:?}", text),
+        }
+    }
+    #[cfg(feature = "chaos")]
+    {
+        if let Some(text) = read_text_variable(
+            crate::chaos::CHAOS_VARIABLE,
+            crate::chaos::CHAOS_VARIABLE_VENDOR,
+            MAX_CHAOS_SIZE,
+        ) {
+            match crate::chaos::parse_config(&text) {
+                Ok((seed, max)) => crate::chaos::configure(seed, max),
+                Err(_) => log::error!("ignoring invalid chaos configuration {:?}", text),
+            }
+        }
+        crate::chaos::log_config();
+    }
+}
+
+/// Runs the applications the harness listed in [`chain::CHAIN_VARIABLE`],
+/// which needs boot services.
+fn run_chained_images() {
+    let Some(text) = read_text_variable(
+        chain::CHAIN_VARIABLE,
+        chain::CHAIN_VARIABLE_VENDOR,
+        MAX_CHAIN_SIZE,
+    ) else {
+        return;
+    };
+    // The console output of the applications is forwarded to the log, so
+    // mirroring the log to the console would feed it back into itself.
+    crate::tmk_logger::set_console_mirror(None);
+    let paths = chain::parse_chain(&text);
+    let succeeded = paths
+        .iter()
+        .filter(|path| chain::run_image(path).success)
+        .count();
+    log::info!("{} of {} chained images succeeded", succeeded, paths.len());
+    if MIRROR_TO_CONSOLE.load(Ordering::Relaxed) {
+        crate::tmk_logger::set_console_mirror(Some(console_mirror));
+    }
+}
+
+/// Returns the address of the ACPI 2.0 RSDP from the configuration table.
+fn acpi_rsdp() -> Option<u64> {
+    let rsdp = uefi::system::with_config_table(|entries| {
+        entries
+            .iter()
+            .find(|entry| entry.guid == ACPI2_GUID)
+            .map(|entry| entry.address as u64)
+    });
+    if rsdp.is_none() {
+        log::warn!("no ACPI 2.0 RSDP");
+    }
+    rsdp
+}
+
+/// Locates the ACPI PM timer while the configuration table pointing at
+/// the ACPI tables is at hand.
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+fn locate_pm_timer(rsdp: Option<u64>) {
+    if let Some(rsdp) = rsdp {
+        // SAFETY: the firmware provided the RSDP, and the ACPI tables are
+        // identity mapped.
+        _ = unsafe { crate::devices::pm_timer::locate(rsdp) };
+    }
+}
+
+/// Moves the log to the serial port the harness asked for in
+/// [`crate::arch::serial::SERIAL_VARIABLE`], named or the one the ACPI SPCR
+/// or DBG2 table describes, if it differs from the default.
+fn select_serial_port(rsdp: Option<u64>) {
+    use crate::arch::serial;
+
+    let Some(text) = read_text_variable(
+        serial::SERIAL_VARIABLE,
+        serial::SERIAL_VARIABLE_VENDOR,
+        MAX_SERIAL_SIZE,
+    ) else {
+        return;
+    };
+    let config = if text.trim() == serial::ACPI_SERIAL {
+        // SAFETY: the firmware provided the RSDP, and the ACPI tables are
+        // identity mapped.
+        let uart = rsdp.and_then(|rsdp| unsafe { crate::devices::acpi::find_debug_uart(rsdp) });
+        let config = uart.as_ref().and_then(serial::config_from_acpi);
+        if config.is_none() {
+            log::warn!("no supported debug UART in the ACPI tables: {:?}", uart);
+        }
+        config
+    } else {
+        serial::parse_config(&text)
+            .inspect_err(|_| log::error!("ignoring invalid serial configuration {:?}", text))
+            .ok()
+    };
+    if let Some(config) = config
+        && config != serial::config()
+    {
+        crate::tmk_logger::select_serial(config);
+    }
+}
+
+/// Sets the bit of `OsLoaderIndications` asking the firmware to keep its
+/// VTL protections, which it acts on when boot services are exited.
+fn enable_uefi_vtl_protection() -> Result<(), BootError> {
     let mut buf = vec![0u8; 1024];
     let mut str_buff = vec![0u16; 1024];
+    let access_failed = BootError::VariableAccessFailed(OS_LOADER_INDICATIONS);
     let os_loader_indications_key =
-        CStr16::from_str_with_buf(OS_LOADER_INDICATIONS, str_buff.as_mut_slice()).unwrap();
+        CStr16::from_str_with_buf(OS_LOADER_INDICATIONS, str_buff.as_mut_slice())
+            .map_err(|_| access_failed)?;
 
-    let os_loader_indications_result = uefi::runtime::get_variable(
+    let (os_loader_indications, attributes) = uefi::runtime::get_variable(
         os_loader_indications_key,
         &uefi::runtime::VariableVendor(EFI_GUID),
         buf.as_mut(),
     )
-    .expect("Failed to get OsLoaderIndications");
+    .map_err(|_| access_failed)?;
 
     let mut os_loader_indications = u32::from_le_bytes(
-        os_loader_indications_result.0[0..4]
-            .try_into()
-            .expect("error in output"),
+        os_loader_indications
+            .get(0..4)
+            .and_then(|b| b.try_into().ok())
+            .ok_or(access_failed)?,
     );
     os_loader_indications |= 0x1u32;
 
@@ -37,28 +331,92 @@ fn enable_uefi_vtl_protection() {
     uefi::runtime::set_variable(
         os_loader_indications_key,
         &uefi::runtime::VariableVendor(EFI_GUID),
-        os_loader_indications_result.1,
+        attributes,
         &os_loader_indications,
     )
-    .expect("Failed to set OsLoaderIndications");
+    .map_err(|_| access_failed)?;
 
     let _os_loader_indications_result = uefi::runtime::get_variable(
         os_loader_indications_key,
         &uefi::runtime::VariableVendor(EFI_GUID),
         buf.as_mut(),
     )
-    .expect("Failed to get OsLoaderIndications");
+    .map_err(|_| access_failed)?;
+    Ok(())
+}
 
-    // SAFETY: its safe to exit boot services here
-    let _memory_map = unsafe { exit_boot_services(Some(MemoryType::BOOT_SERVICES_DATA)) };
+/// Disables the UEFI watchdog, which would otherwise reset the VM five
+/// minutes after the TMK was started while boot services are kept.
+pub fn disable_watchdog() {
+    if let Err(e) = uefi::boot::set_watchdog_timer(0, WATCHDOG_CODE, None) {
+        log::warn!("failed to disable the watchdog: {:?}", e.status());
+    }
 }
 
-pub fn init() -> Result<(), Status> {
-    let r: bool = ALLOCATOR.switch_to_capped_heap(512);
-    if !r {
-        return Err(Status::ABORTED);
+/// Exits boot services, unless that was done already. The run header, the
+/// harness variables and the chained images were dealt with by [`init`];
+/// the console mirror stops and the results file is written one last time.
+/// The host environment is then checked against the golden one, see
+/// [`crate::golden_env`], and the TMK switches to an environment of its
+/// own, see [`crate::arch::env`].
+///
+/// The test registry calls this before the first test that does not keep
+/// boot services, see [`crate::tests::registry::TestCase::boot_services`].
+pub fn exit_boot_services() {
+    if ALLOCATOR.boot_services_exited() {
+        return;
     }
-    crate::tmk_logger::init().map_err(|_| Status::NOT_READY)?;
-    enable_uefi_vtl_protection();
-    Ok(())
+    // The console and the boot volume go away with boot services.
+    crate::tmk_logger::set_console_mirror(None);
+    super::results_file::flush();
+    crate::tmk_logger::begin_boundary("exit_boot_services");
+    // SAFETY: nothing uses boot services past this point: the console
+    // mirror was removed and the results file stops being written.
+    let memory_map =
+        unsafe { uefi::boot::exit_boot_services(Some(MemoryType::BOOT_SERVICES_DATA)) };
+    ALLOCATOR.set_boot_services_exited();
+    memory_map::capture(&memory_map);
+    crate::tmk_logger::end_boundary();
+    // Drift of the host configuration is reported ahead of the tests run
+    // without boot services. The MSRs are probed with #GP recovery, which
+    // needs the TMK's IDT, only loaded once the firmware is out of the way.
+    // The TMK environment is adopted once the firmware's is checked, and
+    // kept by every test run from here on.
+    #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+    {
+        #[cfg(nightly)]
+        crate::arch::interrupt::init();
+        crate::golden_env::check();
+        if let Err(e) = crate::arch::env::adopt_clean_state() {
+            log::error!("failed to adopt the TMK environment: {:?}", e);
+        }
+    }
+}
+
+/// Brings the TMK up with boot services still running: the allocator and
+/// the log work in both phases, and [`exit_boot_services`] is left to the
+/// test registry, so that tests needing UEFI services can run first.
+pub fn init() -> Result<(), BootError> {
+    ALLOCATOR.switch_to_capped_heap(512)?;
+    ALLOCATOR.reserve_low_pool(LOW_POOL_SIZE, LOW_POOL_LIMIT)?;
+    crate::tmk_logger::init().map_err(|_| BootError::LoggerInitFailed)?;
+    load_console_mirror();
+    if MIRROR_TO_CONSOLE.load(Ordering::Relaxed) {
+        crate::tmk_logger::set_console_mirror(Some(console_mirror));
+    }
+    load_harness_variables();
+    let rsdp = acpi_rsdp();
+    select_serial_port(rsdp);
+    #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+    locate_pm_timer(rsdp);
+    // The manifest stays the first record, ahead of chained output.
+    crate::manifest::write_run_header();
+    #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+    crate::tmk_logger::negotiate_format(LOG_FORMAT_POLLS);
+    #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+    crate::handshake::negotiate(HANDSHAKE_POLLS);
+    #[cfg(not(target_arch = "x86_64"))] // xtask-fmt allow-target-arch sys-crate
+    crate::handshake::write_record();
+    run_chained_images();
+    enable_uefi_vtl_protection()
 }
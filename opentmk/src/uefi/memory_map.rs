@@ -0,0 +1,290 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! UEFI memory map validation.
+//!
+//! The memory map the firmware returns when boot services are exited is
+//! kept by [`capture`], as it is the last one the firmware produces.
+//! [`validate`] checks its invariants: entries are page aligned, non-empty
+//! and do not overlap, only known attribute bits are set, runtime regions
+//! and only those carry the runtime attribute and are aligned as the UEFI
+//! specification requires, and the RAM described matches the size the
+//! harness configured in [`MEMORY_SIZE_VARIABLE`].
+
+use alloc::vec::Vec;
+
+use serde::Serialize;
+use spin::Mutex;
+use uefi::boot::MemoryAttribute;
+use uefi::boot::MemoryType;
+use uefi::mem::memory_map::MemoryMap;
+
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// Name of the UEFI variable holding the RAM size of the VM in bytes, in
+/// decimal or `0x` prefixed hexadecimal.
+pub const MEMORY_SIZE_VARIABLE: &str = "OpenTmkMemorySize";
+/// Vendor GUID of [`MEMORY_SIZE_VARIABLE`], shared with the scenario variable.
+pub const MEMORY_SIZE_VARIABLE_VENDOR: uefi::Guid = crate::scenario::SCENARIO_VARIABLE_VENDOR;
+
+const PAGE_SIZE: u64 = 4096;
+/// Alignment of runtime regions, from the UEFI specification.
+#[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+const RUNTIME_ALIGNMENT: u64 = PAGE_SIZE;
+#[cfg(target_arch = "aarch64")] // xtask-fmt allow-target-arch sys-crate
+const RUNTIME_ALIGNMENT: u64 = 64 * 1024;
+/// Share of the configured RAM the map may leave out, for the pages the
+/// loader keeps for itself, as a divisor.
+const RAM_TOLERANCE_DIVISOR: u64 = 32;
+
+static MEMORY_MAP: Mutex<Vec<Region>> = Mutex::new(Vec::new());
+static MEMORY_SIZE: Mutex<Option<u64>> = Mutex::new(None);
+
+/// A memory map entry.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    /// The memory type.
+    pub ty: MemoryType,
+    /// First byte of the region.
+    pub start: u64,
+    /// Number of 4KB pages.
+    pub pages: u64,
+    /// The attributes.
+    pub attributes: MemoryAttribute,
+}
+
+impl Region {
+    /// One past the last byte of the region.
+    pub fn end(&self) -> u64 {
+        self.start
+            .saturating_add(self.pages.saturating_mul(PAGE_SIZE))
+    }
+
+    fn is_runtime_type(&self) -> bool {
+        matches!(
+            self.ty,
+            MemoryType::RUNTIME_SERVICES_CODE
+                | MemoryType::RUNTIME_SERVICES_DATA
+                | MemoryType::PAL_CODE
+        )
+    }
+
+    /// Whether the region is backed by RAM rather than by a device.
+    fn is_ram(&self) -> bool {
+        !matches!(
+            self.ty,
+            MemoryType::MMIO | MemoryType::MMIO_PORT_SPACE | MemoryType::UNUSABLE
+        )
+    }
+}
+
+/// A broken invariant.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Violation {
+    /// The entry at `start` is not page aligned.
+    Unaligned { start: u64 },
+    /// The entry at `start` is empty.
+    Empty { start: u64 },
+    /// The entry at `start` overlaps the previous one, which ends at
+    /// `previous_end`.
+    Overlap { start: u64, previous_end: u64 },
+    /// The entry at `start` has attribute bits UEFI does not define.
+    UnknownAttributes { start: u64, attributes: u64 },
+    /// The entry at `start` has the runtime attribute without being a
+    /// runtime type, or the other way round.
+    RuntimeMismatch { start: u64, ty: u32 },
+    /// The runtime entry at `start` is not aligned to the runtime
+    /// alignment.
+    RuntimeUnaligned { start: u64 },
+    /// The map describes `found` bytes of RAM where `expected` were
+    /// configured.
+    RamSize { found: u64, expected: u64 },
+}
+
+/// Keeps the entries of `map`, the map returned when boot services were
+/// exited.
+pub(crate) fn capture(map: &impl MemoryMap) {
+    *MEMORY_MAP.lock() = map
+        .entries()
+        .map(|d| Region {
+            ty: d.ty,
+            start: d.phys_start,
+            pages: d.page_count,
+            attributes: d.att,
+        })
+        .collect();
+}
+
+/// Returns the captured memory map, sorted by address.
+pub fn regions() -> Vec<Region> {
+    let mut regions = MEMORY_MAP.lock().clone();
+    regions.sort_by_key(|r| r.start);
+    regions
+}
+
+/// Parses the RAM size found in [`MEMORY_SIZE_VARIABLE`].
+pub fn parse_memory_size(text: &str) -> TmkResult<u64> {
+    let text = text.trim();
+    let size = match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    size.ok()
+        .filter(|&size| size != 0)
+        .ok_or(TmkError::InvalidParameter)
+}
+
+/// Records the RAM size found in [`MEMORY_SIZE_VARIABLE`].
+pub(crate) fn set_memory_size(size: u64) {
+    *MEMORY_SIZE.lock() = Some(size);
+}
+
+/// Returns the RAM size the harness configured, if it did.
+pub fn memory_size() -> Option<u64> {
+    *MEMORY_SIZE.lock()
+}
+
+/// Returns the number of bytes of RAM described by `regions`.
+pub fn ram_size(regions: &[Region]) -> u64 {
+    regions
+        .iter()
+        .filter(|r| r.is_ram())
+        .map(|r| r.pages * PAGE_SIZE)
+        .sum()
+}
+
+/// Checks the invariants of `regions`, sorted by address, and the RAM they
+/// describe against `expected_ram` if known.
+pub fn validate(regions: &[Region], expected_ram: Option<u64>) -> Vec<Violation> {
+    let known = MemoryAttribute::all().bits();
+    let mut violations = Vec::new();
+    let mut previous_end = 0;
+    for (i, region) in regions.iter().enumerate() {
+        let start = region.start;
+        if start % PAGE_SIZE != 0 {
+            violations.push(Violation::Unaligned { start });
+        }
+        if region.pages == 0 {
+            violations.push(Violation::Empty { start });
+        }
+        if i != 0 && start < previous_end {
+            violations.push(Violation::Overlap {
+                start,
+                previous_end,
+            });
+        }
+        previous_end = previous_end.max(region.end());
+
+        let attributes = region.attributes.bits();
+        if attributes & !known != 0 {
+            violations.push(Violation::UnknownAttributes { start, attributes });
+        }
+        // Device regions may be mapped for runtime services as well.
+        let runtime = region.attributes.contains(MemoryAttribute::RUNTIME);
+        if region.is_runtime_type() != (runtime && region.is_ram()) {
+            violations.push(Violation::RuntimeMismatch {
+                start,
+                ty: region.ty.0,
+            });
+        }
+        if region.is_runtime_type()
+            && (start % RUNTIME_ALIGNMENT != 0 || region.end() % RUNTIME_ALIGNMENT != 0)
+        {
+            violations.push(Violation::RuntimeUnaligned { start });
+        }
+    }
+
+    if let Some(expected) = expected_ram {
+        let found = ram_size(regions);
+        if found > expected || expected - found > expected / RAM_TOLERANCE_DIVISOR {
+            violations.push(Violation::RamSize { found, expected });
+        }
+    }
+    violations
+}
+
+#[derive(Serialize)]
+struct MemoryMapRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    regions: usize,
+    ram_size: u64,
+    expected_ram_size: Option<u64>,
+    violations: &'a [Violation],
+}
+
+/// Writes the `uefi_memory_map` record summarizing a validation.
+pub fn write_record(regions: &[Region], expected_ram: Option<u64>, violations: &[Violation]) {
+    crate::tmk_logger::write_record(&MemoryMapRecord {
+        record_type: "uefi_memory_map",
+        regions: regions.len(),
+        ram_size: ram_size(regions),
+        expected_ram_size: expected_ram,
+        violations,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(ty: MemoryType, start: u64, pages: u64, attributes: MemoryAttribute) -> Region {
+        Region {
+            ty,
+            start,
+            pages,
+            attributes,
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let wb = MemoryAttribute::WRITE_BACK;
+        let rt = wb | MemoryAttribute::RUNTIME;
+        let mut regions = vec![
+            region(MemoryType::CONVENTIONAL, 0, 0x100, wb),
+            region(MemoryType::RUNTIME_SERVICES_DATA, 0x100000, 0x10, rt),
+            region(MemoryType::LOADER_DATA, 0x110000, 0xf0, wb),
+            region(
+                MemoryType::MMIO,
+                0xf000_0000,
+                0x10,
+                MemoryAttribute::RUNTIME,
+            ),
+        ];
+        assert_eq!(validate(&regions, Some(0x200000)), []);
+        assert_eq!(
+            validate(&regions, Some(0x400000)),
+            [Violation::RamSize {
+                found: 0x200000,
+                expected: 0x400000
+            }]
+        );
+
+        regions[2].start = 0x10f000;
+        regions[1].attributes = wb;
+        assert_eq!(
+            validate(&regions, None),
+            [
+                Violation::RuntimeMismatch {
+                    start: 0x100000,
+                    ty: MemoryType::RUNTIME_SERVICES_DATA.0
+                },
+                Violation::Overlap {
+                    start: 0x10f000,
+                    previous_end: 0x110000
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_memory_size() {
+        assert_eq!(parse_memory_size("1073741824"), Ok(1 << 30));
+        assert_eq!(parse_memory_size(" 0x40000000\n"), Ok(1 << 30));
+        assert!(parse_memory_size("0").is_err());
+        assert!(parse_memory_size("1G").is_err());
+    }
+}
@@ -1,9 +1,14 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
-mod alloc;
+pub(crate) mod alloc;
+pub mod chain;
 pub mod init;
+pub mod memory_map;
+pub mod results_file;
+pub mod results_page;
 mod rt;
+pub mod warm_reset;
 
 use init::init;
 use uefi::Status;
@@ -18,7 +23,13 @@ fn uefi_main() -> Status {
 
     log::warn!("TEST_START");
     crate::tests::run_test();
+    results_file::finish();
+    results_file::flush();
     log::warn!("TEST_END");
+    // The harness may still ask for the records it missed.
+    #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+    crate::tmk_logger::serve_retransmits();
+    #[cfg(not(target_arch = "x86_64"))] // xtask-fmt allow-target-arch sys-crate
     loop {
         core::hint::spin_loop();
     }
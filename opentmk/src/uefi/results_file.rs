@@ -0,0 +1,210 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Results file on the EFI System Partition.
+//!
+//! The outcome of every test and the message of every failed assertion are
+//! collected here as the run goes, and [`flush`] writes them as a JSON
+//! document to [`RESULTS_FILE`] on the volume the TMK was loaded from,
+//! through the Simple File System protocol, for harnesses that mount the
+//! VHD after the run.
+//!
+//! The protocol is gone with boot services, so the file only covers the
+//! part of the run before they are exited: it is written once just before,
+//! marked incomplete, and again if the run ends while they are still up,
+//! e.g. on a failure during initialization or in a run of tests needing
+//! boot services only. Past that point [`flush`] writes nothing, and the
+//! outcome of the tests that follow is only carried by the serial records
+//! and, if the harness asked for one, the results page, see
+//! [`super::results_page`], which the collected results keep up to date.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use serde::Serialize;
+use spin::Mutex;
+use uefi::CString16;
+use uefi::fs::FileSystem;
+use uefi::fs::PathBuf;
+
+use super::alloc::ALLOCATOR;
+use crate::manifest::RunManifest;
+use crate::platform::hyperv::queue_stats;
+use crate::platform::hyperv::queue_stats::QueueStats;
+use crate::tmkdefs::TmkStatus;
+
+/// Path of the results file on the boot volume.
+pub const RESULTS_FILE: &str = "\\opentmk_results.json";
+
+/// The outcome of a test.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct TestOutcome {
+    /// The test.
+    pub test: &'static str,
+    /// Its outcome.
+    pub status: TmkStatus,
+}
+
+/// A failed assertion.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Failure {
+    /// The test running when the assertion failed, if any.
+    pub test: Option<&'static str>,
+    /// The message of the assertion.
+    pub message: String,
+}
+
+/// Number of tests per outcome.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Summary {
+    /// Tests that passed.
+    pub passed: usize,
+    /// Tests that failed.
+    pub failed: usize,
+    /// Tests that were skipped.
+    pub skipped: usize,
+    /// Tests that failed as expected.
+    pub expected_failures: usize,
+    /// Tests blocked on a feature the host does not offer.
+    pub blocked: usize,
+}
+
+struct Results {
+    complete: bool,
+    tests: Vec<TestOutcome>,
+    failures: Vec<Failure>,
+}
+
+impl Results {
+    /// Mirrors the summary to the results page, if the harness asked for
+    /// one.
+    fn publish(&self) {
+        super::results_page::update(&summarize(&self.tests), self.complete);
+    }
+}
+
+static RESULTS: Mutex<Results> = Mutex::new(Results {
+    complete: false,
+    tests: Vec::new(),
+    failures: Vec::new(),
+});
+
+/// Counts the tests of `tests` per outcome.
+pub fn summarize(tests: &[TestOutcome]) -> Summary {
+    let mut summary = Summary::default();
+    for outcome in tests {
+        match outcome.status {
+            TmkStatus::Passed => summary.passed += 1,
+            TmkStatus::Failed => summary.failed += 1,
+            TmkStatus::Skipped => summary.skipped += 1,
+            TmkStatus::ExpectedFailure => summary.expected_failures += 1,
+            TmkStatus::Blocked => summary.blocked += 1,
+        }
+    }
+    summary
+}
+
+/// Records the outcome of `test`.
+pub fn record_outcome(test: &'static str, status: TmkStatus) {
+    let mut results = RESULTS.lock();
+    results.tests.push(TestOutcome { test, status });
+    results.publish();
+}
+
+/// Records a failed assertion, and the failure of `test` if one was
+/// running.
+pub fn record_failure(test: Option<&'static str>, message: &str) {
+    let mut results = RESULTS.lock();
+    if let Some(test) = test {
+        results.tests.push(TestOutcome {
+            test,
+            status: TmkStatus::Failed,
+        });
+    }
+    results.failures.push(Failure {
+        test,
+        message: message.into(),
+    });
+    results.publish();
+}
+
+/// Marks the run as complete: every test was considered.
+pub fn finish() {
+    let mut results = RESULTS.lock();
+    results.complete = true;
+    results.publish();
+}
+
+#[derive(Serialize)]
+struct ResultsDocument<'a> {
+    manifest: RunManifest,
+    complete: bool,
+    summary: Summary,
+    command_queue: QueueStats,
+    tests: &'a [TestOutcome],
+    failures: &'a [Failure],
+}
+
+/// Returns the results collected so far as a JSON document.
+pub fn contents() -> Vec<u8> {
+    let results = RESULTS.lock();
+    serde_json::to_vec_pretty(&ResultsDocument {
+        manifest: RunManifest::current(),
+        complete: results.complete,
+        summary: summarize(&results.tests),
+        command_queue: queue_stats::stats(),
+        tests: &results.tests,
+        failures: &results.failures,
+    })
+    .expect("results serialize")
+}
+
+/// Writes the results collected so far to [`RESULTS_FILE`], replacing it.
+/// Returns whether the file was written; once boot services were exited
+/// it no longer can be.
+pub fn flush() -> bool {
+    if ALLOCATOR.boot_services_exited() {
+        return false;
+    }
+    let contents = contents();
+    let written = uefi::boot::get_image_file_system(uefi::boot::image_handle())
+        .map_err(|e| log::error!("failed to open the boot volume: {:?}", e.status()))
+        .and_then(|volume| {
+            let path = CString16::try_from(RESULTS_FILE).map_err(|_| ())?;
+            FileSystem::new(volume)
+                .write(PathBuf::from(path), &contents)
+                .map_err(|e| log::error!("failed to write {}: {:?}", RESULTS_FILE, e))
+        });
+    if written.is_ok() {
+        log::info!("wrote {} bytes to {}", contents.len(), RESULTS_FILE);
+    }
+    written.is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summarize() {
+        let outcome = |test, status| TestOutcome { test, status };
+        let tests = [
+            outcome("a", TmkStatus::Passed),
+            outcome("b", TmkStatus::Skipped),
+            outcome("c", TmkStatus::Failed),
+            outcome("d", TmkStatus::Passed),
+            outcome("e", TmkStatus::ExpectedFailure),
+            outcome("f", TmkStatus::Blocked),
+        ];
+        assert_eq!(
+            summarize(&tests),
+            Summary {
+                passed: 2,
+                failed: 1,
+                skipped: 1,
+                expected_failures: 1,
+                blocked: 1,
+            }
+        );
+    }
+}
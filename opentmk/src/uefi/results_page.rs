@@ -0,0 +1,228 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Results page at a GPA the host reads.
+//!
+//! When the harness names a page in [`RESULTS_PAGE_VARIABLE`], the TMK
+//! reserves it while boot services are up and keeps a [`ResultsPage`] in it
+//! for the whole run: the number of tests per outcome, whether the run is
+//! complete and the last checkpoint reached. The host can then read the
+//! outcome straight from guest memory, even when the serial output was
+//! lost, and for the tests run after boot services were exited, which the
+//! results file does not cover.
+//!
+//! The page is updated as a sequence lock: `sequence` is odd while the
+//! other fields are written and even once they are consistent, so a reader
+//! retries when it reads an odd value, or a different one after reading
+//! the fields.
+
+use core::mem::offset_of;
+
+use spin::Mutex;
+use uefi::boot::AllocateType;
+use uefi::boot::MemoryType;
+
+use super::results_file::Summary;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// Name of the UEFI variable holding the GPA of the results page, in
+/// decimal or `0x` prefixed hexadecimal.
+pub const RESULTS_PAGE_VARIABLE: &str = "OpenTmkResultsPage";
+/// Vendor GUID of [`RESULTS_PAGE_VARIABLE`], shared with the scenario
+/// variable.
+pub const RESULTS_PAGE_VARIABLE_VENDOR: uefi::Guid = crate::scenario::SCENARIO_VARIABLE_VENDOR;
+
+/// `OTMKRSLT`, little endian.
+pub const RESULTS_PAGE_MAGIC: u64 = u64::from_le_bytes(*b"OTMKRSLT");
+/// Version of the [`ResultsPage`] layout.
+pub const RESULTS_PAGE_VERSION: u32 = 2;
+/// Set in [`ResultsPage::flags`] once every test was considered.
+pub const FLAG_COMPLETE: u32 = 1 << 0;
+/// Bytes of a checkpoint label kept, the rest is cut.
+pub const CHECKPOINT_LABEL_SIZE: usize = 64;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Layout of the results page; fields the TMK does not write are zero.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ResultsPage {
+    /// [`RESULTS_PAGE_MAGIC`].
+    pub magic: u64,
+    /// [`RESULTS_PAGE_VERSION`].
+    pub version: u32,
+    /// [`FLAG_COMPLETE`].
+    pub flags: u32,
+    /// Odd while the page is being updated.
+    pub sequence: u64,
+    /// Tests that passed.
+    pub passed: u32,
+    /// Tests that failed.
+    pub failed: u32,
+    /// Tests that were skipped.
+    pub skipped: u32,
+    /// Tests that failed as expected.
+    pub expected_failures: u32,
+    /// VP that reached the last checkpoint.
+    pub checkpoint_vp: u32,
+    /// Length of the label of the last checkpoint, zero if none was
+    /// reached.
+    pub checkpoint_len: u32,
+    /// The label of the last checkpoint, NUL padded.
+    pub checkpoint: [u8; CHECKPOINT_LABEL_SIZE],
+    /// Tests blocked on a feature the host does not offer, since version 2.
+    pub blocked: u32,
+}
+
+const _: () = assert!(size_of::<ResultsPage>() <= PAGE_SIZE as usize);
+const _: () = assert!(offset_of!(ResultsPage, sequence) == 16);
+
+/// The reserved page and what was last written to it.
+struct State {
+    page: *mut ResultsPage,
+    contents: ResultsPage,
+}
+
+// SAFETY: the page is only accessed with the lock held.
+unsafe impl Send for State {}
+
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+/// Parses a GPA named by the harness, such as the one found in
+/// [`RESULTS_PAGE_VARIABLE`]; it must be page aligned and not zero.
+pub fn parse_gpa(text: &str) -> TmkResult<u64> {
+    let text = text.trim();
+    let gpa = match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    let gpa = gpa
+        .ok()
+        .filter(|&gpa| gpa != 0)
+        .ok_or(TmkError::InvalidParameter)?;
+    if gpa % PAGE_SIZE != 0 {
+        return Err(TmkError::InvalidAlignment);
+    }
+    Ok(gpa)
+}
+
+/// Reserves the page at `gpa` so that nothing else lands in it, and starts
+/// reporting to it. Needs boot services.
+pub(crate) fn reserve(gpa: u64) -> TmkResult<()> {
+    let page = uefi::boot::allocate_pages(AllocateType::Address(gpa), MemoryType::RESERVED, 1)
+        .map_err(|e| {
+            log::error!(
+                "failed to reserve the results page at {:#x}: {:?}",
+                gpa,
+                e.status()
+            );
+            TmkError::AllocationFailed
+        })?;
+    let mut state = State {
+        page: page.as_ptr().cast(),
+        contents: ResultsPage {
+            magic: RESULTS_PAGE_MAGIC,
+            version: RESULTS_PAGE_VERSION,
+            ..Default::default()
+        },
+    };
+    // SAFETY: the page was just allocated, and the memory is identity
+    // mapped.
+    unsafe { page.as_ptr().write_bytes(0, PAGE_SIZE as usize) };
+    state.write();
+    *STATE.lock() = Some(state);
+    log::info!("reporting results to the page at {:#x}", gpa);
+    Ok(())
+}
+
+impl State {
+    /// Writes `contents` to the page, bumping the sequence around it.
+    fn write(&mut self) {
+        let sequence = self.contents.sequence;
+        let page = self.page;
+        // SAFETY: the page is reserved for the whole run and identity
+        // mapped; it is only written with the lock held.
+        unsafe {
+            (&raw mut (*page).sequence).write_volatile(sequence | 1);
+            core::sync::atomic::fence(core::sync::atomic::Ordering::Release);
+            page.write_volatile(ResultsPage {
+                sequence: sequence | 1,
+                ..self.contents
+            });
+            core::sync::atomic::fence(core::sync::atomic::Ordering::Release);
+            self.contents.sequence = (sequence | 1) + 1;
+            (&raw mut (*page).sequence).write_volatile(self.contents.sequence);
+        }
+    }
+}
+
+/// Publishes the number of tests per outcome, and whether the run is
+/// complete.
+pub(crate) fn update(summary: &Summary, complete: bool) {
+    let mut state = STATE.lock();
+    let Some(state) = state.as_mut() else {
+        return;
+    };
+    let contents = &mut state.contents;
+    contents.passed = summary.passed as u32;
+    contents.failed = summary.failed as u32;
+    contents.skipped = summary.skipped as u32;
+    contents.expected_failures = summary.expected_failures as u32;
+    contents.blocked = summary.blocked as u32;
+    if complete {
+        contents.flags |= FLAG_COMPLETE;
+    }
+    state.write();
+}
+
+/// Publishes that `vp_index` reached the checkpoint `label`.
+pub(crate) fn checkpoint(vp_index: u32, label: &str) {
+    let mut state = STATE.lock();
+    let Some(state) = state.as_mut() else {
+        return;
+    };
+    let (checkpoint, len) = encode_label(label);
+    state.contents.checkpoint_vp = vp_index;
+    state.contents.checkpoint = checkpoint;
+    state.contents.checkpoint_len = len as u32;
+    state.write();
+}
+
+/// Returns `label` NUL padded to [`CHECKPOINT_LABEL_SIZE`] bytes and the
+/// number of bytes kept, cut on a character boundary.
+fn encode_label(label: &str) -> ([u8; CHECKPOINT_LABEL_SIZE], usize) {
+    let mut len = label.len().min(CHECKPOINT_LABEL_SIZE);
+    while !label.is_char_boundary(len) {
+        len -= 1;
+    }
+    let mut bytes = [0; CHECKPOINT_LABEL_SIZE];
+    bytes[..len].copy_from_slice(&label.as_bytes()[..len]);
+    (bytes, len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_gpa() {
+        assert_eq!(parse_gpa("0x1000"), Ok(0x1000));
+        assert_eq!(parse_gpa(" 8192\n"), Ok(0x2000));
+        assert_eq!(parse_gpa("0x1008"), Err(TmkError::InvalidAlignment));
+        assert_eq!(parse_gpa("0"), Err(TmkError::InvalidParameter));
+        assert_eq!(parse_gpa("page"), Err(TmkError::InvalidParameter));
+    }
+
+    #[test]
+    fn test_encode_label() {
+        let (bytes, len) = encode_label("setup");
+        assert_eq!(len, 5);
+        assert_eq!(&bytes[..6], b"setup\0");
+
+        let long = format!("a{}", "é".repeat(CHECKPOINT_LABEL_SIZE / 2));
+        let (bytes, len) = encode_label(&long);
+        assert_eq!(len, CHECKPOINT_LABEL_SIZE - 1);
+        assert!(core::str::from_utf8(&bytes[..len]).is_ok());
+    }
+}
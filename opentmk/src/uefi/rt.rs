@@ -5,6 +5,21 @@
 #[panic_handler]
 fn panic_handler(panic: &core::panic::PanicInfo<'_>) -> ! {
     log::error!("Panic at runtime: {}", panic);
+    if let Some(vp_index) = crate::platform::hyperv::ctx::park_faulted_vp() {
+        log::error!("VP{} faulted, parking it until it is restarted", vp_index);
+        // Halt with interrupts disabled, so that nothing runs while the BSP
+        // redirects the VP to a fresh context.
+        #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+        x86_64::instructions::interrupts::disable();
+        loop {
+            #[cfg(target_arch = "x86_64")] // xtask-fmt allow-target-arch sys-crate
+            x86_64::instructions::hlt();
+            #[cfg(not(target_arch = "x86_64"))] // xtask-fmt allow-target-arch sys-crate
+            core::hint::spin_loop();
+        }
+    }
+    crate::tests::registry::abort_run();
+    crate::uefi::results_file::flush();
     log::warn!("TEST_END");
     loop {}
 }
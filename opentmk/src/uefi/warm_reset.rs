@@ -0,0 +1,284 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Runs that go on across a guest initiated warm reset.
+//!
+//! A test validating how the host handles a reset the guest asks for needs
+//! to find, on the next boot, where it was before it. [`reset`] writes a
+//! [`PhaseMarker`] to [`PHASE_FILE`] on the volume the TMK was loaded from,
+//! flushes the results file and asks the firmware for a warm reset; on the
+//! next boot [`read_marker`] finds the marker and the test goes on with its
+//! next phase, then removes it with [`clear_marker`].
+//!
+//! A reset ends the run as the harness sees it unless it expects it, so
+//! tests using it only run when the harness announced
+//! [`crate::handshake::WARM_RESET_CAPABILITY`]. The marker is kept on the
+//! boot volume, so they must also run with boot services, see
+//! [`crate::tests::registry::TestCase::boot_services`].
+//! It is a `;` separated list of settings, e.g.
+//! `test=hv_warm_reset;phase=1;nonce=0x1d2f`, small enough for a harness to
+//! check or remove after the run.
+//!
+//! The nonce of the marker is also kept in [`NONCE_VARIABLE`], a
+//! non-volatile UEFI variable, so that the next boot can check the marker
+//! it reads back against a copy that survived the reset on its own, see
+//! [`expected_nonce`].
+//!
+//! A `warm_reset` record is written before the reset, and another one when
+//! a marker is found, so the harness can tell the reset was asked for and
+//! keep reading the serial output of the next boot.
+
+use alloc::string::String;
+use alloc::string::ToString;
+use core::fmt;
+
+use serde::Serialize;
+use uefi::CStr16;
+use uefi::CString16;
+use uefi::Status;
+use uefi::fs::FileSystem;
+use uefi::fs::PathBuf;
+use uefi::runtime::ResetType;
+use uefi::runtime::VariableAttributes;
+use uefi::runtime::VariableVendor;
+
+use super::alloc::ALLOCATOR;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// Path of the phase marker on the boot volume.
+pub const PHASE_FILE: &str = "\\opentmk_phase.txt";
+/// Name of the UEFI variable holding the nonce of the marker, as 8 little
+/// endian bytes.
+pub const NONCE_VARIABLE: &str = "OpenTmkPhaseNonce";
+/// Vendor GUID of [`NONCE_VARIABLE`], shared with the scenario variable.
+pub const NONCE_VARIABLE_VENDOR: uefi::Guid = crate::scenario::SCENARIO_VARIABLE_VENDOR;
+
+/// Where a test was when it asked for a warm reset.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PhaseMarker {
+    /// The test that asked for the reset.
+    pub test: String,
+    /// The phase the test completed before the reset.
+    pub phase: u32,
+    /// A value of the test's choosing, to check the marker read back is
+    /// the one written.
+    pub nonce: u64,
+}
+
+/// Parses a marker in the format of the module documentation.
+pub fn parse_marker(text: &str) -> TmkResult<PhaseMarker> {
+    let mut test = None;
+    let mut phase = None;
+    let mut nonce = None;
+    for setting in text.split(';').map(str::trim).filter(|s| !s.is_empty()) {
+        let (key, value) = setting.split_once('=').ok_or(TmkError::InvalidParameter)?;
+        let value = value.trim();
+        match key.trim() {
+            "test" => test = Some(String::from(value)),
+            "phase" => phase = Some(value.parse().map_err(|_| TmkError::InvalidParameter)?),
+            "nonce" => {
+                let hex = value.strip_prefix("0x").ok_or(TmkError::InvalidParameter)?;
+                nonce = Some(u64::from_str_radix(hex, 16).map_err(|_| TmkError::InvalidParameter)?)
+            }
+            _ => return Err(TmkError::InvalidParameter),
+        }
+    }
+    match (test, phase, nonce) {
+        (Some(test), Some(phase), Some(nonce)) if !test.is_empty() => {
+            Ok(PhaseMarker { test, phase, nonce })
+        }
+        _ => Err(TmkError::InvalidParameter),
+    }
+}
+
+/// Writes the marker in the format [`parse_marker`] reads.
+impl fmt::Display for PhaseMarker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "test={};phase={};nonce={:#x}",
+            self.test, self.phase, self.nonce
+        )
+    }
+}
+
+fn phase_file() -> Option<(FileSystem, PathBuf)> {
+    if ALLOCATOR.boot_services_exited() {
+        log::error!("{} needs boot services", PHASE_FILE);
+        return None;
+    }
+    let volume = uefi::boot::get_image_file_system(uefi::boot::image_handle())
+        .map_err(|e| log::error!("failed to open the boot volume: {:?}", e.status()))
+        .ok()?;
+    let path = CString16::try_from(PHASE_FILE).ok()?;
+    Some((FileSystem::new(volume), PathBuf::from(path)))
+}
+
+/// Returns the marker left by a test before the last warm reset, if any.
+/// A marker that cannot be read or parsed is logged and ignored.
+pub fn read_marker() -> Option<PhaseMarker> {
+    let (mut fs, path) = phase_file()?;
+    if !fs.try_exists(&path).unwrap_or(false) {
+        return None;
+    }
+    let contents = fs
+        .read(&path)
+        .map_err(|e| log::error!("failed to read {}: {:?}", PHASE_FILE, e))
+        .ok()?;
+    let text = core::str::from_utf8(&contents).unwrap_or_default();
+    match parse_marker(text) {
+        Ok(marker) => {
+            write_record("resumed", &marker);
+            Some(marker)
+        }
+        Err(_) => {
+            log::error!("ignoring invalid phase marker {:?}", text);
+            None
+        }
+    }
+}
+
+/// Calls `f` with the name and vendor of [`NONCE_VARIABLE`].
+fn with_nonce_variable<R>(f: impl FnOnce(&CStr16, &VariableVendor) -> R) -> Option<R> {
+    let mut name_buf = [0u16; 32];
+    let name = CStr16::from_str_with_buf(NONCE_VARIABLE, &mut name_buf).ok()?;
+    Some(f(name, &VariableVendor(NONCE_VARIABLE_VENDOR)))
+}
+
+/// Returns the nonce kept in [`NONCE_VARIABLE`] before the last warm reset,
+/// if any.
+pub fn expected_nonce() -> Option<u64> {
+    let mut buf = [0u8; 8];
+    let data = with_nonce_variable(|name, vendor| {
+        uefi::runtime::get_variable(name, vendor, &mut buf)
+            .map(|(data, _)| data.len())
+            .map_err(|e| e.status())
+    })?;
+    match data {
+        Ok(8) => Some(u64::from_le_bytes(buf)),
+        Ok(len) => {
+            log::error!("{} variable has {} bytes, not 8", NONCE_VARIABLE, len);
+            None
+        }
+        Err(Status::NOT_FOUND) => None,
+        Err(status) => {
+            log::error!("failed to read {} variable: {:?}", NONCE_VARIABLE, status);
+            None
+        }
+    }
+}
+
+/// Removes the marker and its nonce, so that the next boot starts over.
+/// Returns whether neither is left.
+pub fn clear_marker() -> bool {
+    let variable_cleared = with_nonce_variable(
+        |name, vendor| match uefi::runtime::delete_variable(name, vendor) {
+            Ok(()) => true,
+            Err(e) if e.status() == Status::NOT_FOUND => true,
+            Err(e) => {
+                log::error!(
+                    "failed to remove {} variable: {:?}",
+                    NONCE_VARIABLE,
+                    e.status()
+                );
+                false
+            }
+        },
+    )
+    .unwrap_or(false);
+    let Some((mut fs, path)) = phase_file() else {
+        return false;
+    };
+    if !fs.try_exists(&path).unwrap_or(false) {
+        return variable_cleared;
+    }
+    fs.remove_file(&path)
+        .map_err(|e| log::error!("failed to remove {}: {:?}", PHASE_FILE, e))
+        .is_ok()
+        && variable_cleared
+}
+
+#[derive(Serialize)]
+struct WarmResetRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    event: &'static str,
+    #[serde(flatten)]
+    marker: &'a PhaseMarker,
+}
+
+fn write_record(event: &'static str, marker: &PhaseMarker) {
+    crate::tmk_logger::write_record(&WarmResetRecord {
+        record_type: "warm_reset",
+        event,
+        marker,
+    });
+}
+
+/// Writes `marker` to [`PHASE_FILE`] and its nonce to [`NONCE_VARIABLE`],
+/// flushes the results file and asks the firmware for a warm reset.
+/// Returns only if the marker could not be written, in which case no reset
+/// is asked for.
+pub fn reset(marker: &PhaseMarker) {
+    let Some((mut fs, path)) = phase_file() else {
+        return;
+    };
+    let attributes = VariableAttributes::NON_VOLATILE
+        | VariableAttributes::BOOTSERVICE_ACCESS
+        | VariableAttributes::RUNTIME_ACCESS;
+    let nonce = marker.nonce.to_le_bytes();
+    let stored = with_nonce_variable(|name, vendor| {
+        uefi::runtime::set_variable(name, vendor, attributes, &nonce)
+    });
+    match stored {
+        Some(Ok(())) => {}
+        Some(Err(e)) => {
+            log::error!(
+                "failed to write {} variable: {:?}",
+                NONCE_VARIABLE,
+                e.status()
+            );
+            return;
+        }
+        None => return,
+    }
+    if let Err(e) = fs.write(&path, marker.to_string()) {
+        log::error!("failed to write {}: {:?}", PHASE_FILE, e);
+        return;
+    }
+    super::results_file::flush();
+    write_record("requested", marker);
+    log::info!("warm reset after phase {} of {}", marker.phase, marker.test);
+    uefi::runtime::reset(ResetType::WARM, Status::SUCCESS, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_marker() {
+        let marker = PhaseMarker {
+            test: "hv_warm_reset".into(),
+            phase: 1,
+            nonce: 0x1d2f_0000_beef,
+        };
+        assert_eq!(
+            marker.to_string(),
+            "test=hv_warm_reset;phase=1;nonce=0x1d2f0000beef"
+        );
+        assert_eq!(parse_marker(&marker.to_string()), Ok(marker.clone()));
+        assert_eq!(
+            parse_marker(" nonce = 0x1d2f0000beef ; phase=1; test=hv_warm_reset;\n"),
+            Ok(marker)
+        );
+
+        assert!(parse_marker("").is_err());
+        assert!(parse_marker("test=hv_warm_reset;phase=1").is_err());
+        assert!(parse_marker("test=;phase=1;nonce=0x1").is_err());
+        assert!(parse_marker("test=a;phase=1;nonce=17").is_err());
+        assert!(parse_marker("test=a;phase=-1;nonce=0x1").is_err());
+        assert!(parse_marker("test=a;phase=1;nonce=0x1;boot=2").is_err());
+    }
+}
@@ -0,0 +1,494 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Concurrent VTL switch fuzzing.
+//!
+//! [`run`] has every selected VP draw a random sequence of operations for a
+//! fixed duration: VTL calls into VTL1 and back, hypercalls, and reads and
+//! writes of a range shared by all VPs and both VTLs. The words of the range
+//! are dealt out to the VPs in turn, so that neighbouring words, and the
+//! cache lines holding them, belong to different VPs; each word is only
+//! written by its owner, from either VTL.
+//!
+//! Every VP checks, as it goes and in a full sweep every
+//! [`FuzzConfig::check_interval`] operations, that:
+//!
+//! - it is in the VTL it expects and still the same VP across each switch,
+//!   with a value held across the VTL call unchanged, and VTL1 reports
+//!   being entered by a VTL call;
+//! - each word it owns reads back the last value it wrote, whichever VTL
+//!   wrote it;
+//! - each word it does not own is untouched or tagged with its owner;
+//! - its VP index register reads back its index, from VTL0 and from VTL1.
+//!
+//! The operations of a VP are drawn from the seed and the VP index, so a
+//! failing sequence is replayed by reusing the logged seed, although the
+//! interleaving across VPs is not.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use hvdef::HvAllArchRegisterName;
+use hvdef::HvVtlEntryReason;
+use hvdef::Vtl;
+use serde::Serialize;
+
+use crate::context::VirtualProcessorPlatformTrait;
+use crate::context::VpExecToken;
+use crate::context::VtlPlatformTrait;
+use crate::tmkdefs::TmkError;
+use crate::tmkdefs::TmkResult;
+
+/// Bits of a word holding the sequence number of the write, the ones above
+/// holding the owner.
+const SEQUENCE_BITS: u32 = 48;
+/// Most words written by one VTL call.
+const MAX_VTL1_WRITES: u64 = 8;
+
+/// How a fuzz run is driven.
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct FuzzConfig {
+    /// Seed the operations of every VP are drawn from.
+    pub seed: u64,
+    /// Reference time, in 100ns units, the run lasts.
+    pub duration: u64,
+    /// Words of the shared range owned by each VP.
+    pub words_per_vp: usize,
+    /// Operations between full invariant checks.
+    pub check_interval: u64,
+}
+
+/// An invariant found broken.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Violation {
+    /// The operation of the VP it was found at.
+    pub operation: u64,
+    /// What was broken.
+    pub kind: &'static str,
+    /// The value expected.
+    pub expected: u64,
+    /// The value found instead.
+    pub found: u64,
+}
+
+/// What a VP did during a fuzz run.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FuzzStats {
+    /// The VP index.
+    pub vp: u32,
+    /// Operations performed.
+    pub operations: u64,
+    /// VTL calls made, each followed by a VTL return.
+    pub vtl_calls: u64,
+    /// Hypercalls made outside of the VTL calls.
+    pub hypercalls: u64,
+    /// Words of the shared range written, from either VTL.
+    pub writes: u64,
+    /// Words of the shared range read outside of the full checks.
+    pub reads: u64,
+    /// Full invariant checks made.
+    pub checks: u64,
+    /// Invariants found broken.
+    pub violations: u64,
+    /// The first violation found, the later ones often following from it.
+    pub first_violation: Option<Violation>,
+}
+
+impl FuzzStats {
+    fn violation(&mut self, kind: &'static str, expected: u64, found: u64) {
+        log::error!(
+            "vtl fuzz: VP{} op {}: {} (expected {:#x}, found {:#x})",
+            self.vp,
+            self.operations,
+            kind,
+            expected,
+            found
+        );
+        self.violations += 1;
+        self.first_violation.get_or_insert(Violation {
+            operation: self.operations,
+            kind,
+            expected,
+            found,
+        });
+    }
+}
+
+/// SplitMix64 generator.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64, vp: u32) -> Self {
+        let mut rng = Rng(seed ^ ((vp as u64) << 32));
+        rng.next();
+        rng
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut x = self.0;
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        x ^ (x >> 31)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/// The range shared by the VPs, word `i` belonging to the VP in slot
+/// `i % vps`.
+struct SharedRange {
+    words: Vec<AtomicU64>,
+    vps: usize,
+}
+
+impl SharedRange {
+    fn owner(&self, index: usize) -> usize {
+        index % self.vps
+    }
+
+    fn index(&self, slot: usize, word: usize) -> usize {
+        word * self.vps + slot
+    }
+}
+
+/// Returns the value written by the VP in `slot` as its `sequence`th write.
+fn tag(slot: usize, sequence: u64) -> u64 {
+    (slot as u64 + 1) << SEQUENCE_BITS | sequence & ((1 << SEQUENCE_BITS) - 1)
+}
+
+fn owner_of(value: u64) -> Option<usize> {
+    (value != 0).then(|| (value >> SEQUENCE_BITS) as usize - 1)
+}
+
+/// The state of one VP of the run.
+struct Fuzzer {
+    vp: u32,
+    slot: usize,
+    shared: Arc<SharedRange>,
+    /// The last value written to each word owned by the VP.
+    mirror: Vec<u64>,
+    sequence: u64,
+    rng: Rng,
+    stats: FuzzStats,
+}
+
+impl Fuzzer {
+    fn next_write(&mut self) -> (usize, u64) {
+        let word = self.rng.below(self.mirror.len() as u64) as usize;
+        self.sequence += 1;
+        let value = tag(self.slot, self.sequence);
+        self.mirror[word] = value;
+        (word, value)
+    }
+
+    fn check_own(&mut self, word: usize) {
+        let found = self.shared.words[self.shared.index(self.slot, word)].load(Ordering::Acquire);
+        if found != self.mirror[word] {
+            self.stats
+                .violation("own word changed", self.mirror[word], found);
+        }
+    }
+
+    fn check_word(&mut self, index: usize) {
+        let owner = self.shared.owner(index);
+        if owner == self.slot {
+            self.check_own(index / self.shared.vps);
+            return;
+        }
+        let found = self.shared.words[index].load(Ordering::Acquire);
+        if owner_of(found).is_some_and(|o| o != owner) {
+            self.stats
+                .violation("word written by another VP", owner as u64, found);
+        }
+    }
+
+    fn check_all(&mut self) {
+        self.stats.checks += 1;
+        for index in 0..self.shared.words.len() {
+            self.check_word(index);
+        }
+    }
+
+    fn check_vp_index<T: VtlPlatformTrait>(&mut self, ctx: &mut T) {
+        self.stats.hypercalls += 1;
+        match ctx.get_vp_register_with_vtl(HvAllArchRegisterName::VpIndex.0, Vtl::Vtl0) {
+            Ok(index) if index == self.vp as u64 => {}
+            Ok(index) => self
+                .stats
+                .violation("VP index register", self.vp as u64, index),
+            Err(_) => self.stats.violation("VP index register read failed", 0, 0),
+        }
+    }
+
+    fn check_vtl<T: VtlPlatformTrait>(&mut self, ctx: &T, expected: Vtl) {
+        match ctx.get_current_vtl() {
+            Ok(vtl) if vtl == expected => {}
+            Ok(vtl) => self
+                .stats
+                .violation("wrong VTL", expected as u64, vtl as u64),
+            Err(_) => self
+                .stats
+                .violation("current VTL unknown", expected as u64, 0),
+        }
+    }
+
+    /// Calls into VTL1, which writes a few words and checks its view of the
+    /// VP, and checks the VP is back as it left after the return.
+    fn vtl_call<T>(&mut self, ctx: &mut T)
+    where
+        T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T> + 'static,
+    {
+        self.stats.vtl_calls += 1;
+        let count = 1 + self.rng.below(MAX_VTL1_WRITES);
+        let writes: Vec<_> = (0..count)
+            .map(|_| {
+                let (word, value) = self.next_write();
+                (self.shared.index(self.slot, word), value)
+            })
+            .collect();
+        let shared = self.shared.clone();
+        let vp = self.vp;
+        let canary = self.rng.next();
+        let (token, result) = VpExecToken::new(vp, Vtl::Vtl1)
+            .command_with_result(move |ctx: &mut T| in_vtl1(ctx, vp, &shared, &writes));
+        let held = core::hint::black_box(canary);
+        let r = ctx.start_on_vp(token);
+        if r.is_err() {
+            self.stats.violation("VTL call failed", 0, 0);
+            return;
+        }
+        match result.recv() {
+            Ok(Ok(())) => {}
+            Ok(Err((kind, expected, found))) => self.stats.violation(kind, expected, found),
+            Err(_) => self.stats.violation("VTL1 command lost", 0, 0),
+        }
+        self.check_vtl(ctx, Vtl::Vtl0);
+        match ctx.get_current_vp() {
+            Ok(current) if current == vp => {}
+            current => self.stats.violation(
+                "wrong VP after the VTL return",
+                vp as u64,
+                current.unwrap_or(u32::MAX) as u64,
+            ),
+        }
+        if held != canary {
+            self.stats
+                .violation("value changed across the VTL call", canary, held);
+        }
+        self.stats.writes += count;
+    }
+
+    fn step<T>(&mut self, ctx: &mut T)
+    where
+        T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T> + 'static,
+    {
+        match self.rng.below(4) {
+            0 => self.vtl_call(ctx),
+            1 => self.check_vp_index(ctx),
+            2 => {
+                let (word, value) = self.next_write();
+                self.shared.words[self.shared.index(self.slot, word)]
+                    .store(value, Ordering::Release);
+                self.stats.writes += 1;
+            }
+            _ => {
+                let index = self.rng.below(self.shared.words.len() as u64) as usize;
+                self.check_word(index);
+                self.stats.reads += 1;
+            }
+        }
+        self.stats.operations += 1;
+    }
+}
+
+/// What a VTL call checks and does in VTL1: a broken invariant is returned
+/// to VTL0 as the kind, expected and found values of the violation.
+fn in_vtl1<T>(
+    ctx: &mut T,
+    vp: u32,
+    shared: &SharedRange,
+    writes: &[(usize, u64)],
+) -> Result<(), (&'static str, u64, u64)>
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T>,
+{
+    let vtl = ctx.get_current_vtl();
+    if !matches!(vtl, Ok(Vtl::Vtl1)) {
+        return Err(("not in VTL1", 1, vtl.map_or(u64::MAX, |v| v as u64)));
+    }
+    let current = ctx.get_current_vp();
+    if current != Ok(vp) {
+        return Err((
+            "wrong VP in VTL1",
+            vp as u64,
+            current.unwrap_or(u32::MAX) as u64,
+        ));
+    }
+    // The first entry of the VP into VTL1 has no recorded reason.
+    match ctx.get_vtl_entry_reason(vp) {
+        Ok(HvVtlEntryReason::VTL_CALL) | Err(TmkError::NoData) => {}
+        reason => {
+            return Err((
+                "VTL1 entered other than by a VTL call",
+                HvVtlEntryReason::VTL_CALL.0 as u64,
+                reason.map_or(u64::MAX, |r| r.0 as u64),
+            ));
+        }
+    }
+    for &(index, value) in writes {
+        shared.words[index].store(value, Ordering::Release);
+    }
+    match ctx.get_vp_register_on_vp(vp, HvAllArchRegisterName::VpIndex.0, Vtl::Vtl0) {
+        Ok(index) if index == vp as u64 => Ok(()),
+        Ok(index) => Err(("VTL0 VP index register from VTL1", vp as u64, index)),
+        Err(_) => Err(("VTL0 register read from VTL1 failed", 0, 0)),
+    }
+}
+
+fn reference_time<T: VtlPlatformTrait>(ctx: &mut T) -> TmkResult<u64> {
+    ctx.get_vp_register_with_vtl(HvAllArchRegisterName::TimeRefCount.0, Vtl::Vtl0)
+}
+
+/// Runs the operations of the VP in `slot` until `deadline`.
+fn fuzz_vp<T>(
+    ctx: &mut T,
+    slot: usize,
+    shared: Arc<SharedRange>,
+    config: FuzzConfig,
+    deadline: u64,
+) -> FuzzStats
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T> + 'static,
+{
+    let vp = ctx.get_current_vp().unwrap_or(u32::MAX);
+    let mut fuzzer = Fuzzer {
+        vp,
+        slot,
+        mirror: vec![0; config.words_per_vp],
+        shared,
+        sequence: 0,
+        rng: Rng::new(config.seed, vp),
+        stats: FuzzStats {
+            vp,
+            ..Default::default()
+        },
+    };
+    fuzzer.check_vtl(ctx, Vtl::Vtl0);
+    loop {
+        for _ in 0..config.check_interval {
+            fuzzer.step(ctx);
+        }
+        fuzzer.check_all();
+        match reference_time(ctx) {
+            Ok(now) if now < deadline => {}
+            Ok(_) => break,
+            Err(_) => {
+                fuzzer.stats.violation("reference time read failed", 0, 0);
+                break;
+            }
+        }
+    }
+    fuzzer.stats
+}
+
+/// Fuzzes VTL switches concurrently on `vps`, the first of which must be
+/// the VP running the caller, in VTL0. VTL1 must be enabled on all of them.
+/// Returns what each VP did, in the order of `vps`.
+pub fn run<T>(ctx: &mut T, vps: &[u32], config: &FuzzConfig) -> TmkResult<Vec<FuzzStats>>
+where
+    T: VtlPlatformTrait + VirtualProcessorPlatformTrait<T> + 'static,
+{
+    if vps.first() != Some(&ctx.get_current_vp()?)
+        || config.words_per_vp == 0
+        || config.check_interval == 0
+    {
+        return Err(TmkError::InvalidParameter);
+    }
+    let shared = Arc::new(SharedRange {
+        words: (0..vps.len() * config.words_per_vp)
+            .map(|_| AtomicU64::new(0))
+            .collect(),
+        vps: vps.len(),
+    });
+    let deadline = reference_time(ctx)? + config.duration;
+    log::info!(
+        "vtl fuzz: seed {:#x} on VPs {:?} for {} reference time units",
+        config.seed,
+        vps,
+        config.duration
+    );
+
+    let mut results = Vec::new();
+    for (slot, &vp) in vps.iter().enumerate().skip(1) {
+        let shared = shared.clone();
+        let config = *config;
+        let (token, result) = VpExecToken::new(vp, Vtl::Vtl0)
+            .command_with_result(move |ctx: &mut T| fuzz_vp(ctx, slot, shared, config, deadline));
+        ctx.start_on_vp(token)?;
+        results.push(result);
+    }
+
+    let mut stats = Vec::with_capacity(vps.len());
+    stats.push(fuzz_vp(ctx, 0, shared, *config, deadline));
+    for result in results {
+        stats.push(result.recv()?);
+    }
+    Ok(stats)
+}
+
+#[derive(Serialize)]
+struct FuzzRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    config: &'a FuzzConfig,
+    #[serde(flatten)]
+    stats: &'a FuzzStats,
+}
+
+/// Writes a `vtl_fuzz` record of what each VP did.
+pub fn write_records(config: &FuzzConfig, stats: &[FuzzStats]) {
+    for stats in stats {
+        log::info!(
+            "vtl fuzz: VP{}: {} operations, {} VTL calls, {} violations",
+            stats.vp,
+            stats.operations,
+            stats.vtl_calls,
+            stats.violations
+        );
+        crate::tmk_logger::write_record(&FuzzRecord {
+            record_type: "vtl_fuzz",
+            config,
+            stats,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rng_per_vp() {
+        let mut a = Rng::new(42, 0);
+        let mut b = Rng::new(42, 0);
+        let mut c = Rng::new(42, 1);
+        let a: Vec<_> = (0..4).map(|_| a.next()).collect();
+        let b: Vec<_> = (0..4).map(|_| b.next()).collect();
+        let c: Vec<_> = (0..4).map(|_| c.next()).collect();
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn test_tag() {
+        assert_eq!(owner_of(0), None);
+        assert_eq!(owner_of(tag(0, 1)), Some(0));
+        assert_eq!(owner_of(tag(3, u64::MAX)), Some(3));
+        assert_ne!(tag(3, 1), tag(3, 2));
+    }
+}
@@ -296,6 +296,8 @@ pub enum HypercallCode: u16 {
         HvCallFlushVirtualAddressSpaceEx = 0x0013,
         HvCallFlushVirtualAddressListEx = 0x0014,
         HvCallSendSyntheticClusterIpiEx = 0x0015,
+        HvCallMapGpaPages = 0x004b,
+        HvCallUnmapGpaPages = 0x004c,
         HvCallInstallIntercept = 0x004d,
         HvCallGetVpRegisters = 0x0050,
         HvCallSetVpRegisters = 0x0051,
@@ -1591,6 +1593,24 @@ pub struct EnableVpVtlArm64 {
         pub vp_vtl_context: InitialVpContextArm64,
     }
 
+    #[repr(C)]
+    #[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+    pub struct MapGpaPages {
+        pub target_partition_id: u64,
+        pub target_gpa_base: u64,
+        pub map_flags: HvMapGpaFlags,
+        pub padding: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+    pub struct UnmapGpaPages {
+        pub target_partition_id: u64,
+        pub target_gpa_base: u64,
+        pub unmap_flags: u32,
+        pub padding: u32,
+    }
+
     #[repr(C)]
     #[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
     pub struct ModifyVtlProtectionMask {